@@ -4,10 +4,12 @@
 
 package org.mozilla.places
 
+import com.sun.jna.Callback
 import com.sun.jna.Library
 import com.sun.jna.Native
 import com.sun.jna.Pointer
 import com.sun.jna.PointerType
+import com.sun.jna.Structure
 import java.lang.reflect.Proxy
 
 internal interface LibPlacesFFI : Library {
@@ -30,13 +32,48 @@ internal interface LibPlacesFFI : Library {
     // String will work but either force us to leak them, or cause us to corrupt the heap (when we
     // free them).
 
-    /** Create a new places connection */
-    fun places_connection_new(
+    /**
+     * Create a new places API, which owns the database at `db_path`. A
+     * `max_url_length` or `max_title_length` of 0 uses the default.
+     */
+    fun places_api_new(
             db_path: String,
             encryption_key: String?,
+            max_url_length: Int,
+            max_title_length: Int,
+            out_err: RustError.ByReference
+    ): RawPlacesApi?
+
+    /** Open a connection of type `conn_type` (see [ConnectionType]) from `api` */
+    fun places_connection_new(
+            api: RawPlacesApi,
+            conn_type: Byte,
             out_err: RustError.ByReference
     ): RawPlacesConnection?
 
+    /** Re-encrypt the database with `new_key`, using the read-write connection `conn` */
+    fun places_rekey(
+            conn: RawPlacesConnection,
+            new_key: String,
+            out_err: RustError.ByReference
+    )
+
+    /**
+     * Prune tombstones, and vacuum the read-write connection `conn`. Visits older than
+     * `history_retention_days` are expired too, unless it's 0.
+     */
+    fun places_run_maintenance(
+            conn: RawPlacesConnection,
+            history_retention_days: Int,
+            out_err: RustError.ByReference
+    )
+
+    /** Returns 1 if the database was corrupt, and replaced with an empty one, or 0 if not */
+    fun places_api_was_recovered(api: RawPlacesApi): Byte
+
+    /** Interrupt any queries running on read-only connections from `api` */
+    fun places_api_interrupt_readers(api: RawPlacesApi)
+
     /** Returns JSON string, which you need to free with places_destroy_string */
     fun places_note_observation(
             conn: RawPlacesConnection,
@@ -45,12 +82,91 @@ internal interface LibPlacesFFI : Library {
     )
 
     /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_search_history(
+            conn: RawPlacesConnection,
+            query: String,
+            limit: Int,
+            offset: Int,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_highlights(
+            conn: RawPlacesConnection,
+            limit: Int,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns 1 if the site was pinned, or 0 if it was already pinned. */
+    fun places_pin_site(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    ): Byte
+
+    /** Returns 1 if the site was unpinned, or 0 if it wasn't pinned. */
+    fun places_unpin_site(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    ): Byte
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_pinned_sites(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_top_sites(
+            conn: RawPlacesConnection,
+            limit: Int,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_set_page_title(
+            conn: RawPlacesConnection,
+            url: String,
+            title: String,
+            out_err: RustError.ByReference
+    )
+
+    /**
+     * Returns a `MsgTypes.SearchResultList` protocol buffer, which you need to free with
+     * places_destroy_bytebuffer
+     */
     fun places_query_autocomplete(
             conn: RawPlacesConnection,
             search: String,
             limit: Int,
             out_err: RustError.ByReference
-    ): Pointer?
+    ): RustBuffer.ByValue
+
+    /** Start a private browsing session. Free with places_private_browsing_destroy */
+    fun places_private_browsing_new(out_err: RustError.ByReference): RawPrivateBrowsingSession?
+
+    /** Like `places_note_observation`, but for a visit in a private tab */
+    fun places_private_note_observation(
+            session: RawPrivateBrowsingSession,
+            json_observation: String,
+            out_err: RustError.ByReference
+    )
+
+    /**
+     * Like `places_query_autocomplete`, but also includes the matches from the private
+     * browsing session. Returns a `MsgTypes.SearchResultList` protocol buffer, which you need
+     * to free with places_destroy_bytebuffer
+     */
+    fun places_private_query_autocomplete(
+            session: RawPrivateBrowsingSession,
+            conn: RawPlacesConnection,
+            search: String,
+            limit: Int,
+            out_err: RustError.ByReference
+    ): RustBuffer.ByValue
+
+    /** Forget everything noted in the private browsing session so far */
+    fun places_private_browsing_clear(session: RawPrivateBrowsingSession, out_err: RustError.ByReference)
 
     fun places_get_visited(
             conn: RawPlacesConnection,
@@ -58,6 +174,18 @@ internal interface LibPlacesFFI : Library {
             out_err: RustError.ByReference
     ): Pointer?
 
+    /**
+     * Returns a bitset of which of `urls` were visited, which you need to free with
+     * places_destroy_bytebuffer. `urls` holds each URL as a big-endian int byte length
+     * followed by its UTF-8 bytes.
+     */
+    fun places_get_visited_bitset(
+            conn: RawPlacesConnection,
+            urls: ByteArray,
+            urls_len: Int,
+            out_err: RustError.ByReference
+    ): RustBuffer.ByValue
+
     fun places_get_visited_urls_in_range(
             conn: RawPlacesConnection,
             start: Long,
@@ -66,11 +194,114 @@ internal interface LibPlacesFFI : Library {
             out_err: RustError.ByReference
     ): Pointer?
 
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_visit_page(
+            conn: RawPlacesConnection,
+            bound: Long,
+            offset: Long,
+            count: Long,
+            excluded_types: Int,
+            dedupe: Byte,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /**
+     * Returns JSON string, which you need to free with places_destroy_string, or null if the
+     * page doesn't exist.
+     */
+    fun places_get_page_info(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_note_history_metadata_observation(
+            conn: RawPlacesConnection,
+            json_observation: String,
+            out_err: RustError.ByReference
+    )
+
+    /**
+     * Returns JSON string, which you need to free with places_destroy_string, or null if there's
+     * no metadata for the page.
+     */
+    fun places_get_latest_history_metadata_for_url(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_history_metadata_since(
+            conn: RawPlacesConnection,
+            since: Long,
+            document_type: Int,
+            limit: Int,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_query_history_metadata(
+            conn: RawPlacesConnection,
+            query: String,
+            limit: Int,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Start forwarding rust logs to `callback`. Free with places_log_adapter_destroy */
+    fun places_log_adapter_create(
+            callback: RawLogCallback,
+            out_err: RustError.ByReference
+    ): RawLogAdapter?
+
+    /** Only forward logs at `level` (an android.util.Log priority) or above */
+    fun places_log_adapter_set_max_level(level: Int, out_err: RustError.ByReference)
+
+    fun places_log_adapter_destroy(adapter: RawLogAdapter)
+
     /** Destroy strings returned from libplaces_ffi calls. */
     fun places_destroy_string(s: Pointer)
 
+    /** Destroy buffers returned from libplaces_ffi calls. */
+    fun places_destroy_bytebuffer(b: RustBuffer.ByValue)
+
     /** Destroy connection created using `places_connection_new` */
     fun places_connection_destroy(obj: RawPlacesConnection)
+
+    /** Destroy api created using `places_api_new` */
+    fun places_api_destroy(obj: RawPlacesApi)
+
+    /** Destroy a session created using `places_private_browsing_new`, forgetting its visits */
+    fun places_private_browsing_destroy(obj: RawPrivateBrowsingSession)
+}
+
+class RawPlacesApi : PointerType()
+
+/**
+ * A `ffi_support::ByteBuffer`, which is returned by value. It must be freed with
+ * `places_destroy_bytebuffer`.
+ */
+open class RustBuffer : Structure() {
+    @JvmField var len: Long = 0
+    @JvmField var data: Pointer? = null
+
+    class ByValue : RustBuffer(), Structure.ByValue
+
+    override fun getFieldOrder(): List<String> {
+        return listOf("len", "data")
+    }
+
+    fun getByteArray(): ByteArray {
+        return this.data?.getByteArray(0, this.len.toInt()) ?: ByteArray(0)
+    }
 }
 
 class RawPlacesConnection : PointerType()
+
+class RawPrivateBrowsingSession : PointerType()
+
+class RawLogAdapter : PointerType()
+
+internal interface RawLogCallback : Callback {
+    fun invoke(level: Int, tag: String?, message: String)
+}
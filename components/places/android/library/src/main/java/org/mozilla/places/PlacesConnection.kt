@@ -5,8 +5,8 @@
 package org.mozilla.places
 
 import com.sun.jna.Pointer
+import java.nio.ByteBuffer
 import org.json.JSONArray
-import org.json.JSONException
 import org.json.JSONObject
 
 /**
@@ -15,14 +15,42 @@ import org.json.JSONObject
  * @param path an absolute path to a file that will be used for the internal database.
  * @param encryption_key an optional key used for encrypting/decrypting data stored in the internal
  *  database. If omitted, data will be stored in plaintext.
+ * @param maxUrlLength the length, in bytes, of the longest URL to store. If omitted, or 0, uses
+ *  the default.
+ * @param maxTitleLength the number of characters to truncate page titles to. If omitted, or 0,
+ *  uses the default.
+ * @throws DatabaseCorruptedException if the database was corrupt, and couldn't be replaced. If it
+ *  could, it's replaced with an empty one, and [wasRecovered] is true.
  */
-class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI, AutoCloseable {
+class PlacesConnection(
+    path: String,
+    encryption_key: String? = null,
+    maxUrlLength: Int = 0,
+    maxTitleLength: Int = 0
+) : PlacesAPI, AutoCloseable {
+    private var api: RawPlacesApi?
     private var db: RawPlacesConnection?
 
+    /**
+     * Whether the database was corrupt, and was moved aside and replaced with an empty one when
+     * it was opened, so that the app can tell the user their history was lost.
+     */
+    val wasRecovered: Boolean
+
     init {
-        db = rustCall { error ->
-            LibPlacesFFI.INSTANCE.places_connection_new(path, encryption_key, error)
+        api = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_api_new(path, encryption_key, maxUrlLength, maxTitleLength, error)
+        }
+        db = try {
+            rustCall { error ->
+                LibPlacesFFI.INSTANCE.places_connection_new(
+                        this.api!!, ConnectionType.READ_WRITE.type, error)
+            }
+        } catch (e: PlacesException) {
+            LibPlacesFFI.INSTANCE.places_api_destroy(this.api!!)
+            throw e
         }
+        wasRecovered = LibPlacesFFI.INSTANCE.places_api_was_recovered(this.api!!).toInt() != 0
     }
 
     @Synchronized
@@ -32,6 +60,37 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         if (db != null) {
             LibPlacesFFI.INSTANCE.places_connection_destroy(db)
         }
+        val api = this.api
+        this.api = null
+        if (api != null) {
+            LibPlacesFFI.INSTANCE.places_api_destroy(api)
+        }
+    }
+
+    /**
+     * Re-encrypt the database with [newKey]. The database must have been opened with an
+     * encryption key.
+     *
+     * @throws InvalidKeyException if the database couldn't be decrypted.
+     */
+    fun rekey(newKey: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_rekey(this.db!!, newKey, error)
+        }
+    }
+
+    /**
+     * Prune tombstones, and vacuum the database if it's fragmented. This can take a while, so it
+     * should be run in the background when the app is idle, no more than about once a day. The
+     * connection must be read-write.
+     *
+     * @param historyRetentionDays if not 0, visits older than this many days are deleted, along
+     *  with pages that are only in history because of them. History is kept forever by default.
+     */
+    fun runMaintenance(historyRetentionDays: Int = 0) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_run_maintenance(this.db!!, historyRetentionDays, error)
+        }
     }
 
     override fun noteObservation(data: VisitObservation) {
@@ -41,28 +100,123 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         }
     }
 
-    override fun queryAutocomplete(query: String, limit: Int): List<SearchResult> {
+    override fun setPageTitle(url: String, title: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_set_page_title(this.db!!, url, title, error)
+        }
+    }
+
+    override fun searchHistory(query: String, limit: Int, offset: Int): List<HistorySearchResult> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_search_history(this.db!!, query, limit, offset, error)
+        }
+        return HistorySearchResult.fromJSONArray(json)
+    }
+
+    override fun getHighlights(limit: Int): List<Highlight> {
         val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_highlights(this.db!!, limit, error)
+        }
+        return Highlight.fromJSONArray(json)
+    }
+
+    override fun pinSite(url: String): Boolean {
+        val pinned = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_pin_site(this.db!!, url, error)
+        }
+        return pinned.toInt() != 0
+    }
+
+    override fun unpinSite(url: String): Boolean {
+        val unpinned = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unpin_site(this.db!!, url, error)
+        }
+        return unpinned.toInt() != 0
+    }
+
+    override fun getPinnedSites(): List<TopSite> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_pinned_sites(this.db!!, error)
+        }
+        return TopSite.fromJSONArray(json)
+    }
+
+    override fun getTopSites(limit: Int): List<TopSite> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_top_sites(this.db!!, limit, error)
+        }
+        return TopSite.fromJSONArray(json)
+    }
+
+    override fun queryAutocomplete(query: String, limit: Int): List<SearchResult> {
+        val buf = rustCall { error ->
             LibPlacesFFI.INSTANCE.places_query_autocomplete(this.db!!, query, limit, error)
         }
-        return SearchResult.fromJSONArray(json)
+        return searchResultsFromBuffer(buf)
+    }
+
+    /**
+     * Start a private browsing session, whose visits are kept in memory, and searched along
+     * with this connection's. See [PrivateBrowsingSession].
+     */
+    fun newPrivateBrowsingSession(): PrivateBrowsingSession {
+        val session = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_private_browsing_new(error)
+        }
+        return PrivateBrowsingSession(this, session!!)
+    }
+
+    internal fun privateNoteObservation(session: RawPrivateBrowsingSession, data: VisitObservation) {
+        val json = data.toJSON().toString()
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_private_note_observation(session, json, error)
+        }
+    }
+
+    internal fun privateQueryAutocomplete(
+        session: RawPrivateBrowsingSession,
+        query: String,
+        limit: Int
+    ): List<SearchResult> {
+        val buf = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_private_query_autocomplete(session, this.db!!, query, limit, error)
+        }
+        return searchResultsFromBuffer(buf)
+    }
+
+    internal fun privateClear(session: RawPrivateBrowsingSession) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_private_browsing_clear(session, error)
+        }
+    }
+
+    private fun searchResultsFromBuffer(buf: RustBuffer.ByValue): List<SearchResult> {
+        try {
+            val list = MsgTypes.SearchResultList.parseFrom(buf.getByteArray())
+            return list.resultsList.map { SearchResult.fromMessage(it) }
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(buf)
+        }
     }
 
     override fun getVisited(urls: List<String>): List<Boolean> {
-        val urlsToJson = JSONArray()
-        for (url in urls) {
-            urlsToJson.put(url)
+        // Passing the URLs as length-prefixed strings, and getting a bitset back, is much
+        // faster than JSON for pages with lots of links.
+        val encoded = urls.map { it.toByteArray(Charsets.UTF_8) }
+        val buf = ByteBuffer.allocate(encoded.sumBy { it.size + 4 })
+        for (url in encoded) {
+            buf.putInt(url.size)
+            buf.put(url)
         }
-        val urlStr = urls.toString()
-        val visitedStr = rustCallForString { error ->
-            LibPlacesFFI.INSTANCE.places_get_visited(this.db!!, urlStr, error)
+        val bits = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_get_visited_bitset(this.db!!, buf.array(), buf.capacity(), error)
         }
-        val visited = JSONArray(visitedStr)
-        val result = mutableListOf<Boolean>()
-        for (index in 0 until visited.length()) {
-            result.add(visited.getBoolean(index))
+        try {
+            val bytes = bits.getByteArray()
+            return urls.indices.map { i -> (bytes[i / 8].toInt() and (1 shl (i % 8))) != 0 }
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(bits)
         }
-        return result
     }
 
     override fun getVisitedUrlsInRange(start: Long, end: Long, includeRemote: Boolean): List<String> {
@@ -79,6 +233,75 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         return result
     }
 
+    override fun getVisitPage(
+            bound: Long,
+            offset: Long,
+            count: Long,
+            excludeTypes: List<VisitType>,
+            dedupe: Boolean
+    ): VisitPage {
+        var excluded = 0
+        for (type in excludeTypes) {
+            if (type.type > 0) {
+                excluded = excluded or (1 shl type.type)
+            }
+        }
+        val json = rustCallForString { error ->
+            val dedupeArg: Byte = if (dedupe) { 1 } else { 0 }
+            LibPlacesFFI.INSTANCE.places_get_visit_page(
+                    this.db!!, bound, offset, count, excluded, dedupeArg, error)
+        }
+        return VisitPage.fromJSON(JSONObject(json))
+    }
+
+    override fun getPageInfo(url: String): PageInfo? {
+        val cstring = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_get_page_info(this.db!!, url, error)
+        } ?: return null
+        try {
+            return PageInfo.fromJSON(JSONObject(cstring.getString(0, "utf8")))
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_string(cstring)
+        }
+    }
+
+    override fun noteHistoryMetadataObservation(data: HistoryMetadataObservation) {
+        val json = data.toJSON().toString()
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_note_history_metadata_observation(this.db!!, json, error)
+        }
+    }
+
+    override fun getLatestHistoryMetadataForUrl(url: String): HistoryMetadata? {
+        val cstring = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_get_latest_history_metadata_for_url(this.db!!, url, error)
+        } ?: return null
+        try {
+            return HistoryMetadata.fromJSON(JSONObject(cstring.getString(0, "utf8")))
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_string(cstring)
+        }
+    }
+
+    override fun getHistoryMetadataSince(
+            since: Long,
+            limit: Int,
+            documentType: DocumentType?
+    ): List<HistoryMetadata> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_history_metadata_since(
+                    this.db!!, since, documentType?.type ?: -1, limit, error)
+        }
+        return HistoryMetadata.fromJSONArray(json)
+    }
+
+    override fun queryHistoryMetadata(query: String, limit: Int): List<HistoryMetadata> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_query_history_metadata(this.db!!, query, limit, error)
+        }
+        return HistoryMetadata.fromJSONArray(json)
+    }
+
     private inline fun <U> rustCall(callback: (RustError.ByReference) -> U): U {
         synchronized(this) {
             val e = RustError.ByReference()
@@ -103,6 +326,51 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
     }
 }
 
+/**
+ * Pages visited in private tabs, which are never written to disk, but show up in
+ * [queryAutocomplete] until the session is cleared or closed. Create one with
+ * [PlacesConnection.newPrivateBrowsingSession], and close it when the last private tab is
+ * closed.
+ */
+class PrivateBrowsingSession internal constructor(
+    private val conn: PlacesConnection,
+    private var session: RawPrivateBrowsingSession?
+) : AutoCloseable {
+    /**
+     * Record a visit in a private tab. See [PlacesAPI.noteObservation].
+     */
+    fun noteObservation(data: VisitObservation) {
+        conn.privateNoteObservation(this.session!!, data)
+    }
+
+    /**
+     * Like [PlacesAPI.queryAutocomplete], but also includes pages visited in this session,
+     * before the ones from the connection.
+     */
+    fun queryAutocomplete(query: String, limit: Int): List<SearchResult> {
+        return conn.privateQueryAutocomplete(this.session!!, query, limit)
+    }
+
+    /**
+     * Forget everything visited in this session so far.
+     */
+    fun clear() {
+        conn.privateClear(this.session!!)
+    }
+
+    /**
+     * End the session, forgetting everything visited in it.
+     */
+    @Synchronized
+    override fun close() {
+        val session = this.session
+        this.session = null
+        if (session != null) {
+            LibPlacesFFI.INSTANCE.places_private_browsing_destroy(session)
+        }
+    }
+}
+
 /**
  * An API for interacting with Places.
  */
@@ -112,6 +380,11 @@ interface PlacesAPI {
      */
     fun noteObservation(data: VisitObservation)
 
+    /**
+     * Set the title of the page at [url], without recording a visit to it.
+     */
+    fun setPageTitle(url: String, title: String)
+
     /**
      * A way to search the internal database tailored for autocompletion purposes.
      *
@@ -121,6 +394,54 @@ interface PlacesAPI {
      */
     fun queryAutocomplete(query: String, limit: Int): List<SearchResult>
 
+    /**
+     * Search the titles and URLs of visited pages, for the history screen's search box.
+     * Unlike [queryAutocomplete], results are ordered by how well they match, and then by
+     * how recently they were visited.
+     *
+     * @param query the words to search for. Every word must match.
+     * @param limit a maximum number of results to retrieve.
+     * @param offset the number of results to skip, to fetch further pages.
+     */
+    fun searchHistory(query: String, limit: Int, offset: Int = 0): List<HistorySearchResult>
+
+    /**
+     * Returns recently visited pages for the home screen's highlights, best first. Pages
+     * which were visited often and recently score highest, and there's at most one page
+     * for each host.
+     *
+     * @param limit a maximum number of results to retrieve.
+     */
+    fun getHighlights(limit: Int): List<Highlight>
+
+    /**
+     * Pins a site to the top of the top sites, after the sites already pinned. Pinned sites
+     * stay pinned even if their history is deleted.
+     *
+     * @return true if the site was pinned, or false if it was already pinned.
+     */
+    fun pinSite(url: String): Boolean
+
+    /**
+     * Unpins a site.
+     *
+     * @return true if the site was unpinned, or false if it wasn't pinned.
+     */
+    fun unpinSite(url: String): Boolean
+
+    /**
+     * Returns the pinned sites, in the order they were pinned.
+     */
+    fun getPinnedSites(): List<TopSite>
+
+    /**
+     * Returns the sites for the new tab page: the pinned sites, followed by the most frecent
+     * pages that aren't pinned.
+     *
+     * @param limit a maximum number of results to retrieve, including pinned sites.
+     */
+    fun getTopSites(limit: Int): List<TopSite>
+
     /**
      * Maps a list of page URLs to a list of booleans indicating if each URL was visited.
      * @param urls a list of page URLs about which "visited" information is being requested.
@@ -138,12 +459,89 @@ interface PlacesAPI {
      *  is (roughly) considered remote if it didn't originate on the current device.
      */
     fun getVisitedUrlsInRange(start: Long, end: Long = Long.MAX_VALUE, includeRemote: Boolean = true): List<String>
+
+    /**
+     * Returns a page of the history timeline, most recent visit first, for infinite scrolling.
+     *
+     * @param bound fetch visits at or before this time, in milliseconds. Use the current time for
+     *  the first page, and [VisitPage.bound] for the following ones.
+     * @param offset the number of visits at exactly [bound] to skip. Use 0 for the first page, and
+     *  [VisitPage.offset] for the following ones.
+     * @param count the maximum number of visits to return.
+     * @param excludeTypes visits with these types are skipped.
+     * @param dedupe if true, consecutive visits to the same URL are collapsed into one, so a page
+     *  may contain fewer than [count] visits even if there are more available.
+     */
+    fun getVisitPage(
+            bound: Long,
+            offset: Long = 0,
+            count: Long,
+            excludeTypes: List<VisitType> = listOf(),
+            dedupe: Boolean = false
+    ): VisitPage
+
+    /**
+     * Returns what we know about the page at [url], for a "page info" screen, or null if it's
+     * not in history or bookmarks.
+     */
+    fun getPageInfo(url: String): PageInfo?
+
+    /**
+     * Records how long the user viewed a page, or what kind of page it is. Observations made
+     * within 30 minutes of each other are added up. Pages which aren't in history are ignored,
+     * so note the visit first.
+     */
+    fun noteHistoryMetadataObservation(data: HistoryMetadataObservation)
+
+    /**
+     * Returns the most recent metadata for the page at [url], or null if there isn't any.
+     */
+    fun getLatestHistoryMetadataForUrl(url: String): HistoryMetadata?
+
+    /**
+     * Returns metadata updated since [since], most recent first. Pass [DocumentType.MEDIA] as
+     * [documentType] for a "recently watched" list.
+     *
+     * @param since unix timestamp in milliseconds.
+     * @param limit a maximum number of results to retrieve.
+     * @param documentType only return metadata for this type of page, or any type if null.
+     */
+    fun getHistoryMetadataSince(since: Long, limit: Int, documentType: DocumentType? = null): List<HistoryMetadata>
+
+    /**
+     * Returns the pages whose URL or title contains [query], the ones viewed for longest first.
+     * Each page's metadata is added up into a single result.
+     */
+    fun queryHistoryMetadata(query: String, limit: Int): List<HistoryMetadata>
+}
+
+/**
+ * The kinds of connections which can be opened to a places database. These must match
+ * `places::ConnectionType` in the Rust code.
+ */
+@SuppressWarnings("MagicNumber")
+enum class ConnectionType(val type: Byte) {
+    READ_ONLY(1),
+    READ_WRITE(2),
+    SYNC(3),
 }
 
 open class PlacesException(msg: String): Exception(msg)
 open class InternalPanic(msg: String): PlacesException(msg)
 open class UrlParseFailed(msg: String): PlacesException(msg)
 open class InvalidPlaceInfo(msg: String): PlacesException(msg)
+/**
+ * The query was interrupted, and can be retried.
+ */
+open class OperationInterrupted(msg: String): PlacesException(msg)
+/**
+ * Either the database file isn't a database, or it's not encrypted with the key it was opened with.
+ */
+open class InvalidKeyException(msg: String): PlacesException(msg)
+/**
+ * The database was corrupt, and couldn't be moved aside and replaced with an empty one.
+ */
+open class DatabaseCorruptedException(msg: String): PlacesException(msg)
 
 @SuppressWarnings("MagicNumber")
 enum class VisitType(val type: Int) {
@@ -208,26 +606,84 @@ data class SearchResult(
     // Skipping `reasons` for now...
 ) {
     companion object {
-        fun fromJSON(jsonObject: JSONObject): SearchResult {
-            fun stringOrNull(key: String): String? {
-                return try {
-                    jsonObject.getString(key)
-                } catch (e: JSONException) {
-                    null
-                }
+        internal fun fromMessage(msg: MsgTypes.SearchResultMessage): SearchResult {
+            return SearchResult(
+                searchString = msg.searchString,
+                url = msg.url,
+                title = msg.title,
+                frecency = msg.frecency,
+                iconUrl = if (msg.hasIconUrl()) { msg.iconUrl } else { null }
+            )
+        }
+    }
+}
+
+data class VisitInfo(
+    val url: String,
+    val title: String?,
+    /** Milliseconds */
+    val visitTime: Long,
+    val visitType: Int,
+    val isHidden: Boolean
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): VisitInfo {
+            return VisitInfo(
+                url = jsonObject.getString("url"),
+                title = if (jsonObject.isNull("title")) { null } else { jsonObject.getString("title") },
+                visitTime = jsonObject.getLong("visit_date"),
+                visitType = jsonObject.getInt("visit_type"),
+                isHidden = jsonObject.getBoolean("is_hidden")
+            )
+        }
+    }
+}
+
+data class VisitPage(
+    val visits: List<VisitInfo>,
+    /** Pass this back to [PlacesAPI.getVisitPage] to fetch the next page. */
+    val bound: Long,
+    /** Pass this back to [PlacesAPI.getVisitPage] to fetch the next page. */
+    val offset: Long
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): VisitPage {
+            val array = jsonObject.getJSONArray("visits")
+            val visits: MutableList<VisitInfo> = mutableListOf()
+            for (index in 0 until array.length()) {
+                visits.add(VisitInfo.fromJSON(array.getJSONObject(index)))
             }
+            return VisitPage(
+                visits = visits,
+                bound = jsonObject.getLong("bound"),
+                offset = jsonObject.getLong("offset")
+            )
+        }
+    }
+}
 
-            return SearchResult(
-                searchString = jsonObject.getString("search_string"),
+data class HistorySearchResult(
+    val url: String,
+    val title: String?,
+    /** Milliseconds */
+    val lastVisitTime: Long,
+    val visitCount: Int,
+    /** How well the page matched. Higher is better. */
+    val score: Int
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): HistorySearchResult {
+            return HistorySearchResult(
                 url = jsonObject.getString("url"),
-                title = jsonObject.getString("title"),
-                frecency = jsonObject.getLong("frecency"),
-                iconUrl = stringOrNull("icon_url")
+                title = if (jsonObject.isNull("title")) { null } else { jsonObject.getString("title") },
+                lastVisitTime = jsonObject.getLong("last_visit_date"),
+                visitCount = jsonObject.getInt("visit_count"),
+                score = jsonObject.getInt("score")
             )
         }
 
-        fun fromJSONArray(jsonArrayText: String): List<SearchResult> {
-            val result: MutableList<SearchResult> = mutableListOf()
+        fun fromJSONArray(jsonArrayText: String): List<HistorySearchResult> {
+            val result: MutableList<HistorySearchResult> = mutableListOf()
             val array = JSONArray(jsonArrayText)
             for (index in 0 until array.length()) {
                 result.add(fromJSON(array.getJSONObject(index)))
@@ -236,3 +692,165 @@ data class SearchResult(
         }
     }
 }
+
+data class Highlight(
+    val url: String,
+    val title: String?,
+    /** Milliseconds */
+    val lastVisitTime: Long,
+    val visitCount: Int,
+    /** Higher is better. Only meaningful compared to other highlights. */
+    val score: Double
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): Highlight {
+            return Highlight(
+                url = jsonObject.getString("url"),
+                title = if (jsonObject.isNull("title")) { null } else { jsonObject.getString("title") },
+                lastVisitTime = jsonObject.getLong("last_visit_date"),
+                visitCount = jsonObject.getInt("visit_count"),
+                score = jsonObject.getDouble("score")
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<Highlight> {
+            val result: MutableList<Highlight> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
+
+data class TopSite(
+    val url: String,
+    val title: String?,
+    val pinned: Boolean
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): TopSite {
+            return TopSite(
+                url = jsonObject.getString("url"),
+                title = if (jsonObject.isNull("title")) { null } else { jsonObject.getString("title") },
+                pinned = jsonObject.getBoolean("pinned")
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<TopSite> {
+            val result: MutableList<TopSite> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
+
+/**
+ * The kinds of pages we record metadata for. These must match `places::DocumentType` in the Rust
+ * code.
+ */
+@SuppressWarnings("MagicNumber")
+enum class DocumentType(val type: Int) {
+    REGULAR(0),
+    /** Video or audio. */
+    MEDIA(1);
+
+    companion object {
+        fun fromType(type: Int): DocumentType {
+            return values().firstOrNull { it.type == type } ?: REGULAR
+        }
+    }
+}
+
+/**
+ * Something seen about a page the user is viewing. Null fields record nothing.
+ */
+data class HistoryMetadataObservation(
+    val url: String,
+    /** How long the page was viewed since the last observation, in milliseconds. */
+    val viewTime: Int? = null,
+    val documentType: DocumentType? = null,
+    /** Milliseconds. Defaults to now. */
+    val at: Long? = null
+) {
+    fun toJSON(): JSONObject {
+        val o = JSONObject()
+        o.put("url", this.url)
+        this.viewTime?.let { o.put("view_time", it) }
+        this.documentType?.let { o.put("document_type", it.type) }
+        this.at?.let { o.put("at", it) }
+        return o
+    }
+}
+
+data class HistoryMetadata(
+    val url: String,
+    val title: String?,
+    /** Milliseconds */
+    val createdAt: Long,
+    /** Milliseconds */
+    val updatedAt: Long,
+    /** Milliseconds */
+    val totalViewTime: Long,
+    val documentType: DocumentType
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): HistoryMetadata {
+            return HistoryMetadata(
+                url = jsonObject.getString("url"),
+                title = if (jsonObject.isNull("title")) { null } else { jsonObject.getString("title") },
+                createdAt = jsonObject.getLong("created_at"),
+                updatedAt = jsonObject.getLong("updated_at"),
+                totalViewTime = jsonObject.getLong("total_view_time"),
+                documentType = DocumentType.fromType(jsonObject.getInt("document_type"))
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<HistoryMetadata> {
+            val result: MutableList<HistoryMetadata> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
+
+data class PageInfo(
+    val url: String,
+    val guid: String,
+    /** Empty if the page has no title. */
+    val title: String,
+    val isHidden: Boolean,
+    /** The number of times the page was typed into the URL bar. */
+    val typedCount: Int,
+    val frecency: Int,
+    val localVisitCount: Int,
+    val remoteVisitCount: Int,
+    /** Milliseconds, or 0 if the page hasn't been visited locally. */
+    val lastLocalVisitTime: Long,
+    /** Milliseconds, or 0 if the page hasn't been visited on another device. */
+    val lastRemoteVisitTime: Long
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): PageInfo {
+            return PageInfo(
+                url = jsonObject.getString("url"),
+                guid = jsonObject.getString("guid"),
+                title = jsonObject.getString("title"),
+                isHidden = jsonObject.getBoolean("hidden"),
+                typedCount = jsonObject.getInt("typed"),
+                frecency = jsonObject.getInt("frecency"),
+                localVisitCount = jsonObject.getInt("visit_count_local"),
+                remoteVisitCount = jsonObject.getInt("visit_count_remote"),
+                lastLocalVisitTime = jsonObject.getLong("last_visit_date_local"),
+                lastRemoteVisitTime = jsonObject.getLong("last_visit_date_remote")
+            )
+        }
+    }
+}
@@ -48,8 +48,13 @@ open class RustError : Structure() {
         }
         val message = this.consumeErrorMessage();
         when (code) {
-            1 -> return InvalidPlaceInfo(message)
-            2 -> return UrlParseFailed(message)
+            // Codes shared between components.
+            3 -> return OperationInterrupted(message)
+            5 -> return InvalidKeyException(message)
+            // Codes specific to places.
+            301 -> return InvalidPlaceInfo(message)
+            302 -> return UrlParseFailed(message)
+            303 -> return DatabaseCorruptedException(message)
             -1 -> return InternalPanic(message)
             else -> return PlacesException(message)
         }
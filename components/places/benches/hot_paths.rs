@@ -0,0 +1,73 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Benchmarks for the storage paths apps hit most often. Run with
+// `cargo bench -p places`; criterion compares each run with the last one,
+// and reports regressions.
+
+#[macro_use]
+extern crate criterion;
+extern crate places;
+extern crate rand;
+extern crate url;
+
+mod synthetic;
+
+use criterion::Criterion;
+use url::Url;
+
+use places::api::matcher::{search_frecent, SearchParams};
+use places::{storage, PlacesDb, VisitObservation, VisitTransition};
+use synthetic::SyntheticOptions;
+
+fn synthetic_db(num_pages: usize) -> (PlacesDb, Vec<Url>) {
+    let db = PlacesDb::open_in_memory(None).unwrap();
+    let urls = synthetic::fill_db(&db, &SyntheticOptions { num_pages, ..Default::default() }).unwrap();
+    (db, urls)
+}
+
+fn bench_apply_observation(c: &mut Criterion) {
+    c.bench_function("apply_observation", |b| {
+        let db = PlacesDb::open_in_memory(None).unwrap();
+        let mut i = 0;
+        b.iter(|| {
+            // A mix of new pages and revisits.
+            i += 1;
+            let url = Url::parse(&format!("https://example.com/{}/page.html", i % 1000)).unwrap();
+            places::apply_observation(&db, VisitObservation::new(url)
+                .with_visit_type(VisitTransition::Link)).unwrap()
+        });
+    });
+}
+
+fn bench_get_visited(c: &mut Criterion) {
+    let (db, visited) = synthetic_db(10_000);
+    let unvisited = synthetic::unvisited_urls(5_000);
+    c.bench_function_over_inputs("get_visited", move |b, &&count| {
+        // Half of them visited, like a page of search results might be.
+        let urls = visited.iter().take(count / 2)
+            .chain(unvisited.iter().take(count - count / 2))
+            .cloned()
+            .collect::<Vec<_>>();
+        b.iter(|| storage::get_visited(&db, &urls).unwrap());
+    }, &[1_000, 10_000]);
+}
+
+fn bench_search_frecent(c: &mut Criterion) {
+    let (db, _) = synthetic_db(50_000);
+    c.bench_function_over_inputs("search_frecent", move |b, &&query| {
+        b.iter(|| search_frecent(&db, SearchParams {
+            search_string: query.to_owned(),
+            limit: 10,
+        }).unwrap());
+    }, &["m", "moz", "mozilla firefox", "https://rust", "nothing matches this"]);
+}
+
+criterion_group! {
+    name = benches;
+    // Fewer samples than the default, since the databases are slow to fill.
+    config = Criterion::default().sample_size(20);
+    targets = bench_apply_observation, bench_get_visited, bench_search_frecent
+}
+criterion_main!(benches);
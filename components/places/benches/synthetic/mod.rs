@@ -0,0 +1,99 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Fills a places database with made-up history, for the benchmarks and the
+// `generate_db` example. The same seed always gives the same pages and
+// visits (with dates relative to now), so numbers from different runs can
+// be compared.
+
+// Not everything that includes this module uses all of it.
+#![allow(dead_code)]
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use url::Url;
+
+use places::storage::{self, BulkPage};
+use places::{PlacesDb, Result, Timestamp, VisitTransition};
+
+const WORDS: &[&str] = &[
+    "apple", "banana", "cherry", "mozilla", "firefox", "rust", "sync", "places",
+    "history", "bookmark", "search", "weather", "news", "recipe", "travel",
+    "music", "video", "sports", "finance", "science", "garden", "kitchen",
+];
+
+const TLDS: &[&str] = &["com", "org", "net", "io", "co.uk", "de"];
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How much history to generate.
+#[derive(Debug, Clone, Copy)]
+pub struct SyntheticOptions {
+    pub num_pages: usize,
+    /// Pages are spread over this many hosts, so that some hosts have lots
+    /// of pages, like they do in real history.
+    pub num_hosts: usize,
+    /// Each page gets between 1 and this many visits.
+    pub max_visits_per_page: usize,
+    /// Visits are spread over this many days before now.
+    pub days: u64,
+    pub seed: u8,
+}
+
+impl Default for SyntheticOptions {
+    fn default() -> Self {
+        SyntheticOptions {
+            num_pages: 1000,
+            num_hosts: 200,
+            max_visits_per_page: 10,
+            days: 90,
+            seed: 1,
+        }
+    }
+}
+
+// The `i`th page's url. Pages on the same host share a word, so searching
+// for it matches lots of pages.
+fn page_url(rng: &mut StdRng, options: &SyntheticOptions, i: usize) -> Url {
+    let host = rng.gen_range(0, options.num_hosts);
+    let tld = TLDS[host % TLDS.len()];
+    let word = WORDS[host % WORDS.len()];
+    let path = rng.choose(WORDS).unwrap();
+    Url::parse(&format!("https://{}{}.{}/{}/{}.html", word, host, tld, path, i)).unwrap()
+}
+
+/// Adds `options.num_pages` pages to `db`, and returns their urls.
+pub fn fill_db(db: &PlacesDb, options: &SyntheticOptions) -> Result<Vec<Url>> {
+    let mut rng = StdRng::from_seed([options.seed; 32]);
+    let now = Timestamp::now().0;
+    let mut urls = Vec::with_capacity(options.num_pages);
+    let mut pages: Vec<BulkPage> = Vec::with_capacity(options.num_pages);
+    for i in 0..options.num_pages {
+        let url = page_url(&mut rng, options, i);
+        let title = format!("{} {}", rng.choose(WORDS).unwrap(), rng.choose(WORDS).unwrap());
+        let num_visits = rng.gen_range(1, options.max_visits_per_page + 1);
+        let visits = (0..num_visits).map(|_| {
+            let date = Timestamp(now - rng.gen_range(0, options.days * DAY_MS));
+            // Mostly links, with some typed and bookmarked visits, which
+            // count for more in frecency.
+            let visit_type = match rng.gen_range(0, 10) {
+                0 => VisitTransition::Typed,
+                1 => VisitTransition::Bookmark,
+                _ => VisitTransition::Link,
+            };
+            (date, visit_type, true)
+        }).collect();
+        urls.push(url.clone());
+        pages.push((url, Some(title), visits));
+    }
+    storage::insert_pages_bulk(db, &pages)?;
+    Ok(urls)
+}
+
+/// Returns `count` urls which `fill_db` didn't add.
+pub fn unvisited_urls(count: usize) -> Vec<Url> {
+    (0..count)
+        .map(|i| Url::parse(&format!("https://unvisited.example.com/{}", i)).unwrap())
+        .collect()
+}
@@ -0,0 +1,10 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate prost_build;
+
+fn main() {
+    // Generates `msg_types.rs` in `OUT_DIR`, which `lib.rs` includes.
+    prost_build::compile_protos(&["src/places_msg_types.proto"], &["src/"]).unwrap();
+}
@@ -154,14 +154,19 @@ impl LegacyPlace {
                 .with_at(places::Timestamp((v.date / 1000) as u64))
                 .with_title(self.title.clone())
                 .with_is_remote(rand::random::<f64>() < options.remote_probability);
-            places::storage::apply_observation_direct(conn, obs)?;
+            places::storage::apply_observation_direct(
+                conn,
+                &places::UrlPolicy::default(),
+                &places::frecency::FrecencySettings::default(),
+                obs,
+            )?;
         };
         Ok(())
     }
 }
 
 fn import_places(
-    new: &mut places::PlacesDb,
+    new: &places::PlacesDb,
     old_path: PathBuf,
     options: ImportPlacesOptions
 ) -> Result<()> {
@@ -209,7 +214,9 @@ fn import_places(
     let mut current_place = LegacyPlace { id: -1, .. LegacyPlace::default() };
     let mut place_counter = 0;
 
-    let tx = new.db.transaction()?;
+    // Commit periodically, so that we don't hold a single huge transaction
+    // open for the entire import.
+    let mut tx = new.begin_chunked_transaction(std::time::Duration::from_secs(1))?;
 
     print!("Processing {} / {} places (approx.)", place_counter, place_count);
     let _ = std::io::stdout().flush();
@@ -230,6 +237,7 @@ fn import_places(
         let _ = std::io::stdout().flush();
         if current_place.id != -1 {
             current_place.insert(tx.conn(), &options)?;
+            tx.maybe_commit()?;
         }
         current_place = LegacyPlace::from_row(&row);
     }
@@ -746,7 +754,7 @@ fn main() -> Result<()> {
     let db_path = matches.value_of("database_path").unwrap_or("./new-places.db");
     let encryption_key = matches.value_of("encryption_key");
 
-    let mut conn = places::PlacesDb::open(&db_path, encryption_key)?;
+    let conn = places::PlacesDb::open(&db_path, encryption_key)?;
 
     if let Some(import_places_arg) = matches.value_of("import_places") {
         let options = ImportPlacesOptions {
@@ -784,7 +792,7 @@ fn main() -> Result<()> {
         let temp_places = dir.path().join("places-tmp.sqlite");
 
         fs::copy(&import_source, &temp_places)?;
-        import_places(&mut conn, temp_places, options)?;
+        import_places(&conn, temp_places, options)?;
     }
 
     if let Some(observations_json) = matches.value_of("import_observations") {
@@ -795,7 +803,7 @@ fn main() -> Result<()> {
         let mut counter = 0;
         for obs in observations {
             let visit = obs.into_visit()?;
-            places::apply_observation(&mut conn, visit)?;
+            places::apply_observation(&conn, visit)?;
             counter += 1;
             if (counter % 1000) == 0 {
                 trace!("Importing observations {} / {}", counter, num_observations);
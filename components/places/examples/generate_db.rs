@@ -0,0 +1,27 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Writes a places database full of made-up history, the same as the
+// benchmarks use, for profiling or trying out queries. Run with
+// `cargo run --release --example generate_db <path> [num_pages]`.
+
+extern crate places;
+extern crate rand;
+extern crate url;
+
+#[path = "../benches/synthetic/mod.rs"]
+mod synthetic;
+
+use places::PlacesDb;
+use synthetic::SyntheticOptions;
+
+fn main() -> places::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().expect("Usage: generate_db <path> [num_pages]");
+    let num_pages = args.next().and_then(|n| n.parse().ok()).unwrap_or(50_000usize);
+    let db = PlacesDb::open(&path, None)?;
+    let urls = synthetic::fill_db(&db, &SyntheticOptions { num_pages, ..Default::default() })?;
+    println!("Added {} pages to {}", urls.len(), path);
+    Ok(())
+}
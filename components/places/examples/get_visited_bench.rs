@@ -0,0 +1,62 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Compares the cost of answering "which of these links were visited?" for a
+// link-heavy page, with the bools encoded as JSON (`places_get_visited`) and
+// as a bitset (`places_get_visited_bitset`). Run with
+// `cargo run --release --example get_visited_bench [num_links]`.
+
+extern crate places;
+extern crate serde_json;
+extern crate url;
+
+use std::time::{Duration, Instant};
+
+use places::{storage, PlacesDb, VisitObservation, VisitTransition};
+use url::Url;
+
+const ITERATIONS: u32 = 100;
+
+fn time(name: &str, mut f: impl FnMut() -> usize) {
+    let start = Instant::now();
+    let mut bytes = 0;
+    for _ in 0..ITERATIONS {
+        bytes = f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{:>8}: {:>8.3}ms per call, {} bytes returned", name, millis(per_iter), bytes);
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+fn main() -> places::Result<()> {
+    let num_links = std::env::args().nth(1).and_then(|n| n.parse().ok()).unwrap_or(1000usize);
+    let db = PlacesDb::open_in_memory(None)?;
+    let links = (0..num_links)
+        .map(|i| format!("https://www.example.com/{}/page.html?q={}", i % 50, i))
+        .collect::<Vec<_>>();
+    // Visit every third link, so there's a mix of visited and unvisited.
+    for link in links.iter().step_by(3) {
+        places::apply_observation(&db, VisitObservation::new(Url::parse(link)?)
+            .with_visit_type(VisitTransition::Link))?;
+    }
+    println!("Checking {} links, {} visited", num_links, (num_links + 2) / 3);
+
+    time("json", || {
+        // What `places_get_visited` does, minus the FFI.
+        let json = serde_json::to_string(&links).unwrap();
+        let strings: Vec<String> = serde_json::from_str(&json).unwrap();
+        let urls = strings.iter().map(|s| Url::parse(s).unwrap()).collect::<Vec<_>>();
+        let visited = storage::get_visited(&db, &urls).unwrap();
+        serde_json::to_string(&visited).unwrap().len()
+    });
+
+    time("bitset", || {
+        let urls = links.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        storage::get_visited_bitset(&db, &urls).unwrap().len()
+    });
+    Ok(())
+}
@@ -10,56 +10,179 @@ extern crate url;
 #[macro_use]
 extern crate log;
 
-#[cfg(target_os = "android")]
-extern crate android_logger;
-
 #[macro_use]
 extern crate ffi_support;
+#[macro_use]
+extern crate rc_log;
 
 use std::os::raw::c_char;
-use places::{storage, PlacesDb};
-use ffi_support::{call_with_result, ExternError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use places::{history_metadata, pinned_sites, storage, ConnectionType, PlacesApi, PlacesDb, PrivateBrowsingStore, UrlPolicy};
+use places::msg_types::SearchResultList;
+use ffi_support::{call_with_result, ByteBuffer, ExternError};
 
 use places::api::matcher::{
     search_frecent,
     SearchParams,
 };
 
-fn logging_init() {
-    #[cfg(target_os = "android")]
-    {
-        android_logger::init_once(
-            android_logger::Filter::default().with_min_level(log::Level::Trace),
-            Some("libplaces_ffi"));
-        debug!("Android logging should be hooked up!")
+define_log_adapter_ffi!(
+    places_log_adapter_create,
+    places_log_adapter_set_max_level,
+    places_log_adapter_destroy
+);
+
+/// The handle for a `PlacesApi` we give out over the FFI. It's shared with
+/// the connections opened from it, so that they can be returned to it when
+/// they're destroyed, regardless of which is destroyed first.
+pub struct PlacesApiHandle {
+    api: Arc<PlacesApi>,
+}
+
+implement_into_ffi_by_pointer!(PlacesApiHandle);
+
+/// The handle for a connection we give out over the FFI. Calls may come in
+/// from any thread (for example, observations from the main thread and
+/// queries from a background one), so the connection is guarded by a mutex.
+pub struct PlacesConnection {
+    api: Arc<PlacesApi>,
+    // Only `None` while we're being dropped.
+    db: Option<Mutex<PlacesDb>>,
+}
+
+impl PlacesConnection {
+    fn lock(&self) -> MutexGuard<PlacesDb> {
+        // A panic while holding the lock will have rolled back any transaction
+        // that was in progress, so the connection is still usable.
+        let db = self.db.as_ref().expect("Connection used after being closed");
+        db.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for PlacesConnection {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            let db = db.into_inner().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = self.api.close_connection(db) {
+                warn!("Failed to close connection: {}", e);
+            }
+        }
     }
 }
 
+implement_into_ffi_by_pointer!(PlacesConnection);
+
 // XXX I'm completely punting on error handling until we have time to refactor. I'd rather not
 // add more ffi error copypasta in the meantime.
 
-/// Instantiate a places connection. Returned connection must be freed with
-/// `places_connection_destroy`. Returns null and logs on errors (for now).
+/// Instantiate a places API, which owns the database at `db_path`. URLs
+/// longer than `max_url_length` bytes aren't stored, and titles are truncated
+/// to `max_title_length` characters; 0 means the default for either. Returned
+/// API must be freed with `places_api_destroy`. Returns null and logs on
+/// errors (for now).
 #[no_mangle]
-pub unsafe extern "C" fn places_connection_new(
+pub unsafe extern "C" fn places_api_new(
     db_path: *const c_char,
     encryption_key: *const c_char,
+    max_url_length: u32,
+    max_title_length: u32,
     error: &mut ExternError,
-) -> *mut PlacesDb {
-    trace!("places_connection_new");
-    logging_init();
+) -> *mut PlacesApiHandle {
+    trace!("places_api_new");
     call_with_result(error, || {
         let path = ffi_support::rust_string_from_c(db_path);
         let key = ffi_support::opt_rust_string_from_c(encryption_key);
-        PlacesDb::open(path, key.as_ref().map(|v| v.as_str()))
+        let mut policy = UrlPolicy::default();
+        if max_url_length > 0 {
+            policy = policy.with_max_url_length(max_url_length as usize);
+        }
+        if max_title_length > 0 {
+            policy = policy.with_max_title_length(max_title_length as usize);
+        }
+        let api = PlacesApi::new_with_url_policy(path, key.as_ref().map(|v| v.as_str()), policy)?;
+        Ok(PlacesApiHandle { api: Arc::new(api) })
+    })
+}
+
+/// Open a connection to the database owned by `api`. `conn_type` is one of
+/// the values of `places::ConnectionType`. Returned connection must be freed
+/// with `places_connection_destroy`. Returns null and sets `error` if
+/// `conn_type` isn't a valid connection type, or the connection can't be
+/// opened.
+#[no_mangle]
+pub extern "C" fn places_connection_new(
+    api: &PlacesApiHandle,
+    conn_type: u8,
+    error: &mut ExternError,
+) -> *mut PlacesConnection {
+    trace!("places_connection_new");
+    call_with_result(error, || -> places::Result<PlacesConnection> {
+        let conn_type = ConnectionType::from_primitive(conn_type)
+            .ok_or(places::ErrorKind::InvalidConnectionType(conn_type))?;
+        let db = api.api.open_connection(conn_type)?;
+        Ok(PlacesConnection { api: Arc::clone(&api.api), db: Some(Mutex::new(db)) })
+    })
+}
+
+/// Re-encrypt the database with `new_key`. `conn` must be a read-write
+/// connection, and the database must already be encrypted. Other connections
+/// need to be reopened afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn places_rekey(
+    conn: &PlacesConnection,
+    new_key: *const c_char,
+    error: &mut ExternError,
+) {
+    trace!("places_rekey");
+    call_with_result(error, || {
+        let new_key = ffi_support::rust_str_from_c(new_key);
+        conn.api.rekey(&conn.lock(), new_key)
     })
 }
 
+/// Runs periodic maintenance on the database, which prunes old tombstones,
+/// and vacuums and checkpoints the database. If `history_retention_days` isn't
+/// 0, visits older than that many days are expired too. `conn` must be a
+/// read-write connection. See `places::maintenance` for details.
+#[no_mangle]
+pub extern "C" fn places_run_maintenance(
+    conn: &PlacesConnection,
+    history_retention_days: u32,
+    error: &mut ExternError,
+) {
+    trace!("places_run_maintenance");
+    call_with_result(error, || {
+        let history_retention = match history_retention_days {
+            0 => None,
+            days => Some(Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+        };
+        places::maintenance::run_maintenance(&conn.lock(), places::Timestamp::now(), history_retention)
+    })
+}
+
+/// Returns 1 if the database was corrupt when `api` opened it, and was
+/// replaced with an empty one, or 0 if it wasn't.
+#[no_mangle]
+pub extern "C" fn places_api_was_recovered(api: &PlacesApiHandle) -> u8 {
+    trace!("places_api_was_recovered");
+    api.api.was_recovered() as u8
+}
+
+/// Interrupt any queries running on read-only connections opened from `api`,
+/// which will fail with an error. Used to cancel autocomplete searches the
+/// user is no longer waiting for.
+#[no_mangle]
+pub extern "C" fn places_api_interrupt_readers(api: &PlacesApiHandle) {
+    trace!("places_api_interrupt_readers");
+    api.api.interrupt_readers();
+}
+
 /// Add an observation to the database. The observation is a VisitObservation represented as JSON.
 /// Errors are logged.
 #[no_mangle]
 pub unsafe extern "C" fn places_note_observation(
-    conn: &mut PlacesDb,
+    conn: &PlacesConnection,
     json_observation: *const c_char,
     error: &mut ExternError,
 ) {
@@ -67,31 +190,114 @@ pub unsafe extern "C" fn places_note_observation(
     call_with_result(error, || {
         let json = ffi_support::rust_str_from_c(json_observation);
         let visit: places::VisitObservation = serde_json::from_str(&json)?;
-        places::api::apply_observation(conn, visit)
+        places::api::apply_observation(&conn.lock(), visit)
     })
 }
 
-/// Execute a query, returning a `Vec<SearchResult>` as a JSON string. Returned string must be freed
-/// using `places_destroy_string`. Returns null and logs on errors (for now).
+/// Sets the title of a page, without recording a visit.
+#[no_mangle]
+pub unsafe extern "C" fn places_set_page_title(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    title: *const c_char,
+    error: &mut ExternError,
+) {
+    trace!("places_set_page_title");
+    call_with_result(error, || -> places::Result<()> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        storage::set_page_title(&conn.lock(), &url, ffi_support::rust_str_from_c(title))
+    })
+}
+
+/// Execute a query, returning the matches as a `msg_types::SearchResultList`
+/// protocol buffer, which is parsed much faster than JSON for a query that's
+/// run on every keystroke. The returned buffer must be freed with
+/// `places_destroy_bytebuffer`.
 #[no_mangle]
 pub unsafe extern "C" fn places_query_autocomplete(
-    conn: &PlacesDb,
+    conn: &PlacesConnection,
     search: *const c_char,
     limit: u32,
     error: &mut ExternError,
-) -> *mut c_char {
+) -> ByteBuffer {
     trace!("places_query_autocomplete");
+    call_with_result(error, || -> places::Result<SearchResultList> {
+        Ok(search_frecent(&conn.lock(), SearchParams {
+            search_string: ffi_support::rust_string_from_c(search),
+            limit,
+        })?.into())
+    })
+}
+
+/// The pages and visits from private tabs, which are kept in memory until
+/// the private browsing session ends (see `places::PrivateBrowsingStore`).
+pub struct PrivateBrowsingSession {
+    store: Mutex<PrivateBrowsingStore>,
+}
+
+implement_into_ffi_by_pointer!(PrivateBrowsingSession);
+
+/// Start a private browsing session. Returned session must be freed with
+/// `places_private_browsing_destroy`, which forgets everything noted in it.
+#[no_mangle]
+pub extern "C" fn places_private_browsing_new(error: &mut ExternError) -> *mut PrivateBrowsingSession {
+    trace!("places_private_browsing_new");
+    call_with_result(error, || -> places::Result<PrivateBrowsingSession> {
+        Ok(PrivateBrowsingSession { store: Mutex::new(PrivateBrowsingStore::new()?) })
+    })
+}
+
+/// Like `places_note_observation`, but for a visit in a private tab.
+#[no_mangle]
+pub unsafe extern "C" fn places_private_note_observation(
+    session: &PrivateBrowsingSession,
+    json_observation: *const c_char,
+    error: &mut ExternError,
+) {
+    trace!("places_private_note_observation");
     call_with_result(error, || {
-        search_frecent(conn, SearchParams {
+        let json = ffi_support::rust_str_from_c(json_observation);
+        let visit: places::VisitObservation = serde_json::from_str(&json)?;
+        let store = session.store.lock().unwrap_or_else(|e| e.into_inner());
+        store.note_observation(visit)
+    })
+}
+
+/// Like `places_query_autocomplete`, but also includes matches from the
+/// private browsing session.
+#[no_mangle]
+pub unsafe extern "C" fn places_private_query_autocomplete(
+    session: &PrivateBrowsingSession,
+    conn: &PlacesConnection,
+    search: *const c_char,
+    limit: u32,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    trace!("places_private_query_autocomplete");
+    call_with_result(error, || -> places::Result<SearchResultList> {
+        let store = session.store.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(store.search_frecent(&conn.lock(), SearchParams {
             search_string: ffi_support::rust_string_from_c(search),
             limit,
-        })
+        })?.into())
+    })
+}
+
+/// Forget everything noted in the session so far.
+#[no_mangle]
+pub extern "C" fn places_private_browsing_clear(
+    session: &PrivateBrowsingSession,
+    error: &mut ExternError,
+) {
+    trace!("places_private_browsing_clear");
+    call_with_result(error, || {
+        session.store.lock().unwrap_or_else(|e| e.into_inner()).clear()
     })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn places_get_visited(
-    conn: &PlacesDb,
+    conn: &PlacesConnection,
     urls_json: *const c_char,
     error: &mut ExternError,
 ) -> *mut c_char {
@@ -106,15 +312,40 @@ pub unsafe extern "C" fn places_get_visited(
             .collect::<Result<Vec<_>, _>>()?;
         // We need to call `to_string` manually because primitives (e.g. bool) don't implement
         // `ffi_support::IntoFfiJsonTag` (Not clear if they should, needs more thought).
-        let visited = storage::get_visited(conn, &urls)?;
+        let visited = storage::get_visited(&conn.lock(), &urls)?;
         Ok(serde_json::to_string(&visited)?)
     })
 }
 
+/// Like `places_get_visited`, but faster for large batches, such as marking
+/// the visited links on a page. `urls` points to `urls_len` bytes holding
+/// each URL as a big-endian `i32` byte length followed by its UTF-8 bytes.
+/// Returns a bitset where bit `i % 8` of byte `i / 8` is set if the `i`th URL
+/// was visited, which must be freed with `places_destroy_bytebuffer`. URLs
+/// which don't parse are reported as unvisited.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_visited_bitset(
+    conn: &PlacesConnection,
+    urls: *const u8,
+    urls_len: i32,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    trace!("places_get_visited_bitset");
+    call_with_result(error, || -> places::Result<ByteBuffer> {
+        let buf = if urls.is_null() || urls_len <= 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(urls, urls_len as usize)
+        };
+        let urls = places::ffi::strings_from_buffer(buf)?;
+        let visited = storage::get_visited_bitset(&conn.lock(), &urls)?;
+        Ok(ByteBuffer::from_vec(visited))
+    })
+}
 
 #[no_mangle]
 pub extern "C" fn places_get_visited_urls_in_range(
-    conn: &PlacesDb,
+    conn: &PlacesConnection,
     start: i64,
     end: i64,
     include_remote: u8, // JNA has issues with bools...
@@ -123,7 +354,7 @@ pub extern "C" fn places_get_visited_urls_in_range(
     trace!("places_get_visited_in_range");
     call_with_result(error, || -> places::Result<String> {
         let visited = storage::get_visited_urls(
-            conn,
+            &conn.lock(),
             // Probably should allow into()...
             places::Timestamp(start.max(0) as u64),
             places::Timestamp(end.max(0) as u64),
@@ -133,6 +364,223 @@ pub extern "C" fn places_get_visited_urls_in_range(
     })
 }
 
+/// Fetch a page of the history timeline, as a JSON `HistoryVisitPage`. See
+/// `places::storage::get_visits_paginated` for how `bound` and `offset` work.
+/// `excluded_types` is a `VisitTransitionSet` of visit types to skip, where
+/// bit `n` is set to exclude visits with a transition type of `n`.
+#[no_mangle]
+pub extern "C" fn places_get_visit_page(
+    conn: &PlacesConnection,
+    bound: i64,
+    offset: i64,
+    count: i64,
+    excluded_types: i32,
+    dedupe: u8,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_visit_page");
+    call_with_result(error, || {
+        storage::get_visits_paginated(
+            &*conn.lock(),
+            places::Timestamp(bound.max(0) as u64),
+            offset.max(0) as u32,
+            count.max(0) as u32,
+            places::VisitTransitionSet::from_ffi(excluded_types),
+            dedupe != 0,
+        )
+    })
+}
+
+/// Search the titles and urls of visited pages, for the history screen.
+/// Returns a `Vec<HistorySearchResult>` as a JSON string, which must be freed
+/// using `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_search_history(
+    conn: &PlacesConnection,
+    query: *const c_char,
+    limit: u32,
+    offset: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_search_history");
+    call_with_result(error, || {
+        storage::search_history(&*conn.lock(), ffi_support::rust_str_from_c(query), limit, offset)
+    })
+}
+
+/// Returns up to `limit` recently visited pages for the home screen's
+/// highlights, best first, as a JSON array of `Highlight`s, which must be
+/// freed using `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_highlights(
+    conn: &PlacesConnection,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_highlights");
+    call_with_result(error, || storage::get_highlights(&*conn.lock(), limit))
+}
+
+/// Pins `url` to the top sites, after the sites already pinned. Returns 1 if
+/// it was pinned, or 0 if it was already pinned.
+#[no_mangle]
+pub unsafe extern "C" fn places_pin_site(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    error: &mut ExternError,
+) -> u8 {
+    trace!("places_pin_site");
+    call_with_result(error, || -> places::Result<bool> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        pinned_sites::pin_site(&conn.lock(), &url)
+    })
+}
+
+/// Unpins `url`. Returns 1 if it was unpinned, or 0 if it wasn't pinned.
+#[no_mangle]
+pub unsafe extern "C" fn places_unpin_site(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    error: &mut ExternError,
+) -> u8 {
+    trace!("places_unpin_site");
+    call_with_result(error, || -> places::Result<bool> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        pinned_sites::unpin_site(&conn.lock(), &url)
+    })
+}
+
+/// Returns the pinned sites, in the order they were pinned, as a JSON array
+/// of `TopSite`s, which must be freed using `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_get_pinned_sites(
+    conn: &PlacesConnection,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_pinned_sites");
+    call_with_result(error, || pinned_sites::get_pinned_sites(&*conn.lock()))
+}
+
+/// Returns up to `limit` sites for the new tab page, pinned sites first, as
+/// a JSON array of `TopSite`s, which must be freed using
+/// `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_get_top_sites(
+    conn: &PlacesConnection,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_top_sites");
+    call_with_result(error, || pinned_sites::get_top_sites(&*conn.lock(), limit))
+}
+
+/// Returns everything we know about the page for `url` (its title, visit
+/// counts and last visit dates) as a JSON `PageInfo`, or null if it's not in
+/// history or bookmarks.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_page_info(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_page_info");
+    call_with_result(error, || -> places::Result<Option<places::PageInfo>> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        storage::get_page_info(&*conn.lock(), &url)
+    })
+}
+
+/// Notes how long the user viewed a page, or what kind of page it was. The
+/// observation is a `places::HistoryMetadataObservation` represented as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn places_note_history_metadata_observation(
+    conn: &PlacesConnection,
+    json_observation: *const c_char,
+    error: &mut ExternError,
+) {
+    trace!("places_note_history_metadata_observation");
+    call_with_result(error, || -> places::Result<()> {
+        let json = ffi_support::rust_str_from_c(json_observation);
+        let observation: places::HistoryMetadataObservation = serde_json::from_str(&json)?;
+        history_metadata::note_history_metadata_observation(&conn.lock(), observation)
+    })
+}
+
+/// Returns the most recent metadata for `url` as a JSON `HistoryMetadata`,
+/// or null if there isn't any.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_latest_history_metadata_for_url(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_latest_history_metadata_for_url");
+    call_with_result(error, || -> places::Result<Option<places::HistoryMetadata>> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        history_metadata::get_latest_history_metadata_for_url(&*conn.lock(), &url)
+    })
+}
+
+/// Returns up to `limit` metadata windows updated at or after `since`, most
+/// recent first, as a JSON array of `HistoryMetadata`. `document_type` is a
+/// `places::DocumentType`, or -1 (or any other unknown value) for any type.
+#[no_mangle]
+pub extern "C" fn places_get_history_metadata_since(
+    conn: &PlacesConnection,
+    since: i64,
+    document_type: i32,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_history_metadata_since");
+    call_with_result(error, || {
+        let document_type = if document_type >= 0 && document_type <= 255 {
+            places::DocumentType::from_primitive(document_type as u8)
+        } else {
+            None
+        };
+        history_metadata::get_history_metadata_since(
+            &*conn.lock(),
+            places::Timestamp(since.max(0) as u64),
+            document_type,
+            limit,
+        )
+    })
+}
+
+/// Returns up to `limit` pages matching `query`, the ones viewed longest
+/// first, as a JSON array of `HistoryMetadata`.
+#[no_mangle]
+pub unsafe extern "C" fn places_query_history_metadata(
+    conn: &PlacesConnection,
+    query: *const c_char,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_query_history_metadata");
+    call_with_result(error, || {
+        history_metadata::query_history_metadata(&*conn.lock(), ffi_support::rust_str_from_c(query), limit)
+    })
+}
+
+/// Explain how the frecency of `url` was calculated, as a JSON
+/// `FrecencyDetails`, or null if the page doesn't exist. This is for
+/// debugging ranking, and the format may change at any time.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_frecency_details(
+    conn: &PlacesConnection,
+    url: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_frecency_details");
+    call_with_result(error, || -> places::Result<Option<places::frecency::FrecencyDetails>> {
+        let url = url::Url::parse(ffi_support::rust_str_from_c(url))?;
+        storage::get_frecency_details(&conn.lock(), &url)
+    })
+}
 
 define_string_destructor!(places_destroy_string);
-define_box_destructor!(PlacesDb, places_connection_destroy);
+define_bytebuffer_destructor!(places_destroy_bytebuffer);
+define_box_destructor!(PlacesConnection, places_connection_destroy);
+define_box_destructor!(PlacesApiHandle, places_api_destroy);
+define_box_destructor!(PrivateBrowsingSession, places_private_browsing_destroy);
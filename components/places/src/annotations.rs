@@ -0,0 +1,434 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Annotations are typed name/value pairs attached to a page or a visit, for
+//! features which need to remember more about them than history does: where
+//! a download was saved, whether a page was last read in reader view, and so
+//! on. Like desktop, the names are stored once in `moz_anno_attributes`, and
+//! each annotation says when it should expire (see `expire_annotations`).
+
+use rusqlite::Row;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Result as RusqliteResult;
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use sql_support::ConnExt;
+use storage::{self, RowId};
+use types::Timestamp;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// The value of an annotation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnoValue {
+    Integer(i64),
+    Double(f64),
+    Text(String),
+}
+
+impl AnnoValue {
+    fn anno_type(&self) -> AnnoType {
+        match *self {
+            AnnoValue::Integer(_) => AnnoType::Int64,
+            AnnoValue::Double(_) => AnnoType::Double,
+            AnnoValue::Text(_) => AnnoType::String,
+        }
+    }
+
+    fn from_row(row: &Row) -> Result<Self> {
+        let anno_type: AnnoType = row.get_checked("type")?;
+        Ok(match anno_type {
+            AnnoType::Int32 | AnnoType::Int64 => AnnoValue::Integer(row.get_checked("content")?),
+            AnnoType::Double => AnnoValue::Double(row.get_checked("content")?),
+            AnnoType::String => AnnoValue::Text(row.get_checked("content")?),
+        })
+    }
+}
+
+impl ToSql for AnnoValue {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        match *self {
+            AnnoValue::Integer(ref i) => i.to_sql(),
+            AnnoValue::Double(ref d) => d.to_sql(),
+            AnnoValue::Text(ref s) => s.to_sql(),
+        }
+    }
+}
+
+// NOTE: These are the same values desktop uses for `moz_annos.type`. We
+// never write `Int32`, but read it as an integer.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum AnnoType {
+    Int32 = 1,
+    Double = 2,
+    String = 3,
+    Int64 = 5,
+}
+
+impl ToSql for AnnoType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for AnnoType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match u8::column_result(value)? {
+            1 => Ok(AnnoType::Int32),
+            2 => Ok(AnnoType::Double),
+            3 => Ok(AnnoType::String),
+            5 => Ok(AnnoType::Int64),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// When an annotation should be removed by `expire_annotations`. The values
+/// are the same as desktop's `EXPIRE_*` constants. The dated policies count
+/// from when the annotation was last set.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AnnoExpiration {
+    /// Kept until its page or visit is removed.
+    Never = 4,
+    /// Removed once its page has no visits left, for example after the
+    /// user clears their history. Visit annotations are always removed with
+    /// their visit, so this is the same as `Never` for them.
+    WithHistory = 5,
+    /// Removed after 7 days.
+    Days = 6,
+    /// Removed after 30 days.
+    Weeks = 7,
+    /// Removed after 180 days.
+    Months = 8,
+}
+
+impl AnnoExpiration {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            4 => Some(AnnoExpiration::Never),
+            5 => Some(AnnoExpiration::WithHistory),
+            6 => Some(AnnoExpiration::Days),
+            7 => Some(AnnoExpiration::Weeks),
+            8 => Some(AnnoExpiration::Months),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for AnnoExpiration {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for AnnoExpiration {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        AnnoExpiration::from_primitive(u8::column_result(value)?)
+            .ok_or_else(|| FromSqlError::InvalidType)
+    }
+}
+
+/// A page with an annotation, as returned by `get_pages_with_annotation`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageAnnotation {
+    pub url: Url,
+    pub value: AnnoValue,
+    pub expiration: AnnoExpiration,
+    pub last_modified: Timestamp,
+}
+
+impl PageAnnotation {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url: String = row.get_checked("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            value: AnnoValue::from_row(row)?,
+            expiration: row.get_checked("expiration")?,
+            last_modified: row.get_checked("lastModified")?,
+        })
+    }
+}
+
+/// Sets the annotation `name` on the page for `url`, replacing any value it
+/// already had, and adding the page (without any visits) if it doesn't
+/// exist. URLs which the URL policy rejects are ignored.
+pub fn set_page_annotation(
+    db: &PlacesDb,
+    url: &Url,
+    name: &str,
+    value: &AnnoValue,
+    expiration: AnnoExpiration,
+) -> Result<()> {
+    if !db.url_policy().can_add_url(url) {
+        debug!("Ignoring annotation for a URL rejected by the URL policy");
+        return Ok(());
+    }
+    let tx = db.begin_transaction()?;
+    let page_id = storage::get_or_insert_page_id(&tx, db.url_policy(), url)?;
+    set_page_annotation_by_id(&tx, page_id, name, value, expiration)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn set_page_annotation_by_id(
+    db: &impl ConnExt,
+    page_id: RowId,
+    name: &str,
+    value: &AnnoValue,
+    expiration: AnnoExpiration,
+) -> Result<()> {
+    let attribute_id = get_or_insert_attribute_id(db, name)?;
+    // Preserve `dateAdded` if we're replacing an existing annotation.
+    let sql = "
+        INSERT OR REPLACE INTO moz_annos
+            (place_id, anno_attribute_id, content, type, expiration, dateAdded, lastModified)
+        VALUES (:page_id, :attribute_id, :content, :type, :expiration,
+                COALESCE((SELECT dateAdded FROM moz_annos
+                          WHERE place_id = :page_id AND anno_attribute_id = :attribute_id), :now),
+                :now)";
+    db.execute_named_cached(sql, &[
+        (":page_id", &page_id),
+        (":attribute_id", &attribute_id),
+        (":content", value),
+        (":type", &value.anno_type()),
+        (":expiration", &expiration),
+        (":now", &Timestamp::now()),
+    ])?;
+    Ok(())
+}
+
+/// Returns the value of the annotation `name` on the page for `url`, or
+/// `None` if the page doesn't have it.
+pub fn get_page_annotation(db: &PlacesDb, url: &Url, name: &str) -> Result<Option<AnnoValue>> {
+    let page_id = match storage::find_page_id(db, url)? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    Ok(db.try_query_row("
+        SELECT a.content, a.type
+        FROM moz_annos a
+        JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+        WHERE a.place_id = :page_id AND n.name = :name",
+        &[(":page_id", &page_id), (":name", &name)],
+        AnnoValue::from_row,
+        true)?)
+}
+
+/// Removes the annotation `name` from the page for `url`. Returns false if
+/// the page didn't have it.
+pub fn remove_page_annotation(db: &PlacesDb, url: &Url, name: &str) -> Result<bool> {
+    let page_id = match storage::find_page_id(db, url)? {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    let removed = db.execute_named_cached("
+        DELETE FROM moz_annos
+        WHERE place_id = :page_id
+          AND anno_attribute_id = (SELECT id FROM moz_anno_attributes WHERE name = :name)",
+        &[(":page_id", &page_id), (":name", &name)])?;
+    Ok(removed != 0)
+}
+
+/// Returns every page with the annotation `name`, most recently annotated
+/// first.
+pub fn get_pages_with_annotation(db: &PlacesDb, name: &str) -> Result<Vec<PageAnnotation>> {
+    let mut stmt = db.prepare_cached("
+        SELECT h.url, a.content, a.type, a.expiration, a.lastModified
+        FROM moz_annos a
+        JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+        JOIN moz_places h ON h.id = a.place_id
+        WHERE n.name = :name
+        ORDER BY a.lastModified DESC")?;
+    let rows = stmt.query_and_then_named(&[(":name", &name)], PageAnnotation::from_row)?;
+    rows.collect()
+}
+
+/// Sets the annotation `name` on the visit `visit_id`, replacing any value it
+/// already had. Returns false if there's no such visit.
+pub fn set_visit_annotation(
+    db: &PlacesDb,
+    visit_id: RowId,
+    name: &str,
+    value: &AnnoValue,
+    expiration: AnnoExpiration,
+) -> Result<bool> {
+    let tx = db.begin_transaction()?;
+    let attribute_id = get_or_insert_attribute_id(&tx, name)?;
+    let sql = "
+        INSERT OR REPLACE INTO moz_visit_annos
+            (visit_id, anno_attribute_id, content, type, expiration, dateAdded, lastModified)
+        SELECT v.id, :attribute_id, :content, :type, :expiration,
+               COALESCE((SELECT dateAdded FROM moz_visit_annos
+                         WHERE visit_id = v.id AND anno_attribute_id = :attribute_id), :now),
+               :now
+        FROM moz_historyvisits v
+        WHERE v.id = :visit_id";
+    let inserted = tx.execute_named_cached(sql, &[
+        (":visit_id", &visit_id),
+        (":attribute_id", &attribute_id),
+        (":content", value),
+        (":type", &value.anno_type()),
+        (":expiration", &expiration),
+        (":now", &Timestamp::now()),
+    ])?;
+    tx.commit()?;
+    Ok(inserted != 0)
+}
+
+/// Returns the value of the annotation `name` on the visit `visit_id`, or
+/// `None` if the visit doesn't have it.
+pub fn get_visit_annotation(db: &PlacesDb, visit_id: RowId, name: &str) -> Result<Option<AnnoValue>> {
+    Ok(db.try_query_row("
+        SELECT a.content, a.type
+        FROM moz_visit_annos a
+        JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+        WHERE a.visit_id = :visit_id AND n.name = :name",
+        &[(":visit_id", &visit_id), (":name", &name)],
+        AnnoValue::from_row,
+        true)?)
+}
+
+/// Removes the annotations whose expiration policy says they should be gone
+/// by `now`, and any names no longer used by an annotation. Returns the
+/// number of annotations removed.
+pub fn expire_annotations(db: &PlacesDb, now: Timestamp) -> Result<usize> {
+    let cutoff = |days: u64| Timestamp(now.0.saturating_sub(days * DAY_MS));
+    let (days, weeks, months) = (cutoff(7), cutoff(30), cutoff(180));
+    let dated = "
+        (expiration = :days AND lastModified < :days_cutoff)
+        OR (expiration = :weeks AND lastModified < :weeks_cutoff)
+        OR (expiration = :months AND lastModified < :months_cutoff)";
+    let params: &[(&str, &ToSql)] = &[
+        (":days", &AnnoExpiration::Days),
+        (":days_cutoff", &days),
+        (":weeks", &AnnoExpiration::Weeks),
+        (":weeks_cutoff", &weeks),
+        (":months", &AnnoExpiration::Months),
+        (":months_cutoff", &months),
+    ];
+
+    let tx = db.begin_transaction()?;
+    let mut removed = tx.execute_named(&format!("
+        DELETE FROM moz_annos
+        WHERE {}
+           OR (expiration = {with_history} AND NOT EXISTS (
+                 SELECT 1 FROM moz_historyvisits v
+                 WHERE v.place_id = moz_annos.place_id))",
+        dated,
+        with_history = AnnoExpiration::WithHistory as u8), params)?;
+    removed += tx.execute_named(&format!("DELETE FROM moz_visit_annos WHERE {}", dated), params)?;
+    tx.execute_batch("
+        DELETE FROM moz_anno_attributes
+        WHERE id NOT IN (SELECT anno_attribute_id FROM moz_annos)
+          AND id NOT IN (SELECT anno_attribute_id FROM moz_visit_annos)")?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+fn get_or_insert_attribute_id(db: &impl ConnExt, name: &str) -> Result<RowId> {
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_anno_attributes(name) VALUES (:name)",
+        &[(":name", &name)])?;
+    Ok(db.query_row_and_then_named(
+        "SELECT id FROM moz_anno_attributes WHERE name = :name",
+        &[(":name", &name)],
+        |row| row.get_checked(0),
+        true)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use types::VisitTransition;
+
+    const READER_ANNO: &str = "test/readerMode";
+
+    #[test]
+    fn test_page_annotations() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let visited = Url::parse("https://www.example.com/article").unwrap();
+        let unvisited = Url::parse("https://www.example.com/later").unwrap();
+        storage::apply_observation(&conn, VisitObservation::new(visited.clone())
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit");
+
+        assert_eq!(get_page_annotation(&conn, &visited, READER_ANNO).unwrap(), None);
+        set_page_annotation(&conn, &visited, READER_ANNO, &AnnoValue::Integer(1), AnnoExpiration::Never).unwrap();
+        // Annotating a page we don't know about adds it.
+        set_page_annotation(&conn, &unvisited, READER_ANNO,
+                            &AnnoValue::Text("scrolled".into()), AnnoExpiration::Never).unwrap();
+        assert_eq!(get_page_annotation(&conn, &visited, READER_ANNO).unwrap(), Some(AnnoValue::Integer(1)));
+        assert_eq!(get_page_annotation(&conn, &unvisited, READER_ANNO).unwrap(),
+                   Some(AnnoValue::Text("scrolled".into())));
+
+        // Setting it again replaces the value.
+        set_page_annotation(&conn, &visited, READER_ANNO, &AnnoValue::Double(0.5), AnnoExpiration::Days).unwrap();
+        let pages = get_pages_with_annotation(&conn, READER_ANNO).unwrap();
+        assert_eq!(pages.len(), 2);
+        let page = pages.iter().find(|p| p.url == visited).expect("should be annotated");
+        assert_eq!(page.value, AnnoValue::Double(0.5));
+        assert_eq!(page.expiration, AnnoExpiration::Days);
+        assert!(get_pages_with_annotation(&conn, "test/other").unwrap().is_empty());
+
+        assert!(remove_page_annotation(&conn, &unvisited, READER_ANNO).unwrap());
+        assert!(!remove_page_annotation(&conn, &unvisited, READER_ANNO).unwrap());
+        assert_eq!(get_page_annotation(&conn, &unvisited, READER_ANNO).unwrap(), None);
+    }
+
+    #[test]
+    fn test_visit_annotations() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let visit_id = storage::apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit").expect("should get a rowid");
+
+        assert!(set_visit_annotation(&conn, visit_id, READER_ANNO, &AnnoValue::Integer(1), AnnoExpiration::Never).unwrap());
+        assert!(!set_visit_annotation(&conn, RowId(9999), READER_ANNO, &AnnoValue::Integer(1), AnnoExpiration::Never).unwrap());
+        assert_eq!(get_visit_annotation(&conn, visit_id, READER_ANNO).unwrap(), Some(AnnoValue::Integer(1)));
+
+        // Removing the visit removes its annotations.
+        conn.execute_named("DELETE FROM moz_historyvisits WHERE id = :id", &[(":id", &visit_id)]).unwrap();
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_visit_annos").unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_expire_annotations() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let visited = Url::parse("https://www.example.com/visited").unwrap();
+        let unvisited = Url::parse("https://www.example.com/unvisited").unwrap();
+        let dated = Url::parse("https://www.example.com/dated").unwrap();
+        storage::apply_observation(&conn, VisitObservation::new(visited.clone())
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit");
+        for url in &[&visited, &unvisited] {
+            set_page_annotation(&conn, url, "test/history", &AnnoValue::Integer(1), AnnoExpiration::WithHistory).unwrap();
+        }
+        set_page_annotation(&conn, &dated, "test/days", &AnnoValue::Integer(1), AnnoExpiration::Days).unwrap();
+        set_page_annotation(&conn, &dated, "test/months", &AnnoValue::Integer(1), AnnoExpiration::Months).unwrap();
+        set_page_annotation(&conn, &dated, "test/never", &AnnoValue::Integer(1), AnnoExpiration::Never).unwrap();
+
+        // Only the unvisited page loses its `WithHistory` annotation now...
+        assert_eq!(expire_annotations(&conn, Timestamp::now()).unwrap(), 1);
+        assert!(get_page_annotation(&conn, &visited, "test/history").unwrap().is_some());
+        assert!(get_page_annotation(&conn, &unvisited, "test/history").unwrap().is_none());
+
+        // ...but the dated ones go once enough time has passed.
+        let in_a_month = Timestamp(Timestamp::now().0 + 31 * DAY_MS);
+        assert_eq!(expire_annotations(&conn, in_a_month).unwrap(), 1);
+        assert!(get_page_annotation(&conn, &dated, "test/days").unwrap().is_none());
+        assert!(get_page_annotation(&conn, &dated, "test/months").unwrap().is_some());
+        assert!(get_page_annotation(&conn, &dated, "test/never").unwrap().is_some());
+
+        let unused: i64 = conn.query_one(
+            "SELECT COUNT(*) FROM moz_anno_attributes WHERE name = 'test/days'").unwrap();
+        assert_eq!(unused, 0);
+    }
+}
@@ -12,11 +12,6 @@ use observation::{VisitObservation};
 
 // This module can become, roughly: PlacesUtils.history()
 
-// functions used internally.
-fn can_add_url(_url: &Url) -> Result<bool> {
-    Ok(true)
-}
-
 // eg: PlacesUtils.history.insert({url: "http", title: ..., visits: [{date: ...}]})
 
 // Structs representing place and visit infos for this API.
@@ -42,7 +37,7 @@ pub struct AddableVisit {
 }
 
 // insert a visit a'la PlacesUtils.history.insert()
-pub fn insert(conn: &mut PlacesDb, place: AddablePlaceInfo) -> Result<()> {
+pub fn insert(conn: &PlacesDb, place: AddablePlaceInfo) -> Result<()> {
     for v in place.visits {
         let obs = VisitObservation::new(place.url.clone())
                   .with_visit_type(v.transition)
@@ -137,7 +132,7 @@ pub enum RedirectSourceType {
 // of using various browser-specific heuristics to compute the VisitTransition
 // we assume the caller has already done this and passed the correct transition
 // flags in.
-pub fn visit_uri(conn: &mut PlacesDb,
+pub fn visit_uri(conn: &PlacesDb,
                  url: &Url,
                  last_url: Option<Url>,
                  // To be more honest, this would *not* take a VisitTransition,
@@ -148,7 +143,7 @@ pub fn visit_uri(conn: &mut PlacesDb,
                  is_error_page: bool,
                 ) -> Result<()> {
     // Silently return if URI is something we shouldn't add to DB.
-    if !can_add_url(&url)? {
+    if !conn.url_policy().can_add_url(&url) {
         return Ok(());
     };
     // Do not save a reloaded uri if we have visited the same URI recently.
@@ -7,6 +7,7 @@ use url::Url;
 use url_serde;
 use db::PlacesDb;
 use error::Result;
+use icons::PAGE_ICON_URL_SQL;
 
 pub use match_impl::{MatchBehavior, SearchBehavior};
 
@@ -148,6 +149,12 @@ pub struct SearchResult {
     pub reasons: Vec<MatchReason>,
 }
 
+fn icon_url_from_row(row: &rusqlite::Row) -> rusqlite::Result<Option<Url>> {
+    // An icon we can't parse isn't worth failing the search for.
+    let icon_url = row.get_checked::<_, Option<String>>("icon_url")?;
+    Ok(icon_url.and_then(|url| Url::parse(&url).ok()))
+}
+
 impl SearchResult {
     /// Default search behaviors from Desktop: HISTORY, BOOKMARK, OPENPAGE, SEARCHES.
     /// Default match behavior: MATCH_BOUNDARY_ANYWHERE.
@@ -177,7 +184,7 @@ impl SearchResult {
             search_string,
             url,
             title,
-            icon_url: None,
+            icon_url: icon_url_from_row(row)?,
             frecency,
             reasons,
         })
@@ -205,7 +212,7 @@ impl SearchResult {
             search_string,
             url,
             title,
-            icon_url: None,
+            icon_url: icon_url_from_row(row)?,
             frecency,
             reasons,
         })
@@ -223,7 +230,7 @@ impl SearchResult {
             search_string,
             url,
             title: display_url,
-            icon_url: None,
+            icon_url: icon_url_from_row(row)?,
             frecency,
             reasons: vec![MatchReason::Origin],
         })
@@ -247,7 +254,7 @@ impl SearchResult {
             search_string,
             url,
             title: display_url,
-            icon_url: None,
+            icon_url: icon_url_from_row(row)?,
             frecency,
             reasons,
         })
@@ -272,7 +279,11 @@ impl<'query, 'conn> OriginOrUrl<'query, 'conn> {
                        moz_origins.host || '/' AS displayURL,
                        frecency,
                        id,
-                       :searchString AS searchString
+                       :searchString AS searchString,
+                       (SELECT icon_url FROM moz_icons
+                        WHERE root
+                          AND icon_url_hash = hash(IFNULL(:prefix, prefix) || moz_origins.host || '/favicon.ico')
+                        LIMIT 1) AS icon_url
                 FROM (
                   SELECT host,
                          TOTAL(frecency) AS host_frecency
@@ -302,13 +313,14 @@ impl<'query, 'conn> OriginOrUrl<'query, 'conn> {
             }
         } else if self.query.contains(|c| c == '/' || c == ':' || c == '?') {
             let (host, stripped_url) = split_after_host_and_port(self.query);
-            let mut stmt = self.conn.db.prepare("
+            let mut stmt = self.conn.db.prepare(&format!("
                 SELECT h.url as url,
                        :strippedURL AS displayURL,
                        h.frecency as frecency,
                        h.foreign_count > 0 AS bookmarked,
                        h.id as id,
-                       :searchString AS searchString
+                       :searchString AS searchString,
+                       {icon_url}
                 FROM moz_places h
                 JOIN moz_origins o ON o.id = h.origin_id
                 WHERE o.rev_host = reverse_host(:host)
@@ -321,7 +333,8 @@ impl<'query, 'conn> OriginOrUrl<'query, 'conn> {
                        h.frecency as frecency,
                        h.foreign_count > 0 AS bookmarked,
                        h.id as id,
-                       :searchString AS searchString
+                       :searchString AS searchString,
+                       {icon_url}
                 FROM moz_places h
                 JOIN moz_origins o ON o.id = h.origin_id
                 WHERE o.rev_host = reverse_host(:host) || 'www.'
@@ -330,7 +343,7 @@ impl<'query, 'conn> OriginOrUrl<'query, 'conn> {
                       AND strip_prefix_and_userinfo(h.url) BETWEEN 'www.' || :strippedURL AND 'www.' || :strippedURL || X'FFFF'
                 ORDER BY h.frecency DESC, h.id DESC
                 LIMIT 1
-            ")?;
+            ", icon_url = PAGE_ICON_URL_SQL))?;
             let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
                 (":searchString", &self.query),
                 (":strippedURL", &stripped_url),
@@ -381,7 +394,7 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
     }
 
     pub fn search(&self) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare("
+        let mut stmt = self.conn.db.prepare(&format!("
             SELECT h.url as url,
                    h.title as title,
                    EXISTS(SELECT 1 FROM moz_bookmarks
@@ -397,7 +410,8 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
                    h.id as id,
                    NULL AS open_count,
                    h.frecency as frecency,
-                   :searchString AS searchString
+                   :searchString AS searchString,
+                   {icon_url}
             FROM (
               SELECT ROUND(MAX(use_count) * (1 + (input = :searchString)), 1) AS rank,
                      place_id
@@ -412,7 +426,7 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
                                      NULL, :matchBehavior, :searchBehavior)
             ORDER BY rank DESC, h.frecency DESC
             LIMIT :maxResults
-        ")?;
+        ", icon_url = PAGE_ICON_URL_SQL))?;
         let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
@@ -462,7 +476,7 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
     }
 
     pub fn search(&self) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare("
+        let mut stmt = self.conn.db.prepare(&format!("
             SELECT h.url, h.title,
                    (SELECT title FROM moz_bookmarks
                     WHERE fk = h.id AND
@@ -473,7 +487,8 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
                    h.visit_count_local + h.visit_count_remote AS visit_count,
                    h.typed as typed,
                    h.id as id,
-                   NULL AS open_count, h.frecency, :searchString AS searchString
+                   NULL AS open_count, h.frecency, :searchString AS searchString,
+                   {icon_url}
             FROM moz_places h
             WHERE h.frecency > 0
               AND AUTOCOMPLETE_MATCH(:searchString, h.url,
@@ -484,7 +499,7 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
               AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0)
             ORDER BY h.frecency DESC, h.id DESC
             LIMIT :maxResults
-        ")?;
+        ", icon_url = PAGE_ICON_URL_SQL))?;
         let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
@@ -505,6 +520,7 @@ mod tests {
     use observation::{VisitObservation};
     use storage::{apply_observation};
     use types::{Timestamp, VisitTransition};
+    use icons;
 
     #[test]
     fn split() {
@@ -523,7 +539,7 @@ mod tests {
 
     #[test]
     fn search() {
-        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
 
         let url = Url::parse("http://example.com/123").unwrap();
         let visit = VisitObservation::new(url.clone())
@@ -531,7 +547,7 @@ mod tests {
                    .with_visit_type(VisitTransition::Typed)
                    .with_at(Timestamp::now());
 
-        apply_observation(&mut conn, visit).expect("Should apply visit");
+        apply_observation(&conn, visit).expect("Should apply visit");
 
         let by_origin = search_frecent(&conn, SearchParams {
             search_string: "example.com".into(),
@@ -559,4 +575,36 @@ mod tests {
         }).expect("Should search by adaptive input history");
         println!("Matches by adaptive input history: {:?}", by_adaptive);
     }
+
+    #[test]
+    fn search_icons() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/page").unwrap();
+        let icon = Url::parse("https://www.example.com/icon.png").unwrap();
+        let root = Url::parse("https://www.example.com/favicon.ico").unwrap();
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_title("Example page".to_string())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now())).expect("Should apply visit");
+        let expires = Timestamp(Timestamp::now().0 + 1000);
+        icons::set_icon_for_page(&conn, &url, &root, 16, None, expires).expect("Should set root icon");
+
+        // The page falls back to its origin's icon, which is also used for
+        // the origin itself...
+        let matches = search_frecent(&conn, SearchParams {
+            search_string: "www.example.com".into(),
+            limit: 10,
+        }).expect("Should search by origin");
+        assert!(!matches.is_empty());
+        assert!(matches.iter().all(|m| m.icon_url == Some(root.clone())));
+
+        // ...until it has one of its own.
+        icons::set_icon_for_page(&conn, &url, &icon, 32, None, expires).expect("Should set icon");
+        let matches = search_frecent(&conn, SearchParams {
+            search_string: "Example page".into(),
+            limit: 10,
+        }).expect("Should search by title");
+        let page_match = matches.iter().find(|m| m.url == url).expect("Should match page");
+        assert_eq!(page_match.icon_url, Some(icon));
+    }
 }
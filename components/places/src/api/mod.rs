@@ -4,12 +4,13 @@
 
 pub mod history;
 pub mod matcher;
+pub mod places_api;
 use db::PlacesDb;
 use error::{Result};
 use observation::{VisitObservation};
 use storage;
 
-pub fn apply_observation(conn: &mut PlacesDb, visit_obs: VisitObservation) -> Result<()> {
+pub fn apply_observation(conn: &PlacesDb, visit_obs: VisitObservation) -> Result<()> {
     storage::apply_observation(conn, visit_obs)?;
     Ok(())
 }
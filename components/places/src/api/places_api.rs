@@ -0,0 +1,395 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rusqlite;
+
+use db::{ConnectionType, PlacesDb, SqlInterruptHandle};
+use sql_support::ConnExt;
+use error::*;
+use types::Timestamp;
+use url_policy::UrlPolicy;
+
+/// The entry point for consumers of this crate (including the FFI). It owns
+/// the location of the database and its encryption key, and hands out
+/// connections to it.
+///
+/// Any number of read-only connections may be open at once, but there's
+/// only ever one read-write connection, and one sync connection. Connections
+/// which can write must be returned with `close_connection` once they're no
+/// longer needed, so that they can be handed out again.
+pub struct PlacesApi {
+    db_name: PathBuf,
+    // Changed by `rekey`.
+    encryption_key: Mutex<Option<String>>,
+    // Set on every connection we open.
+    url_policy: UrlPolicy,
+    // Opened when the API is created (which also creates or upgrades the
+    // schema), and taken while it's in use.
+    write_connection: Mutex<Option<PlacesDb>>,
+    sync_connection_open: AtomicBool,
+    // Handles for the read-only connections we've given out, which are
+    // usually running autocomplete queries that may need to be cancelled.
+    read_interrupts: Mutex<Vec<Weak<SqlInterruptHandle>>>,
+    // Whether the database was corrupt, and replaced when we opened it.
+    recovered: bool,
+}
+
+impl PlacesApi {
+    /// Opens the database at `db_name`, creating or upgrading it if needed.
+    ///
+    /// If the database is corrupt, either because it can't be opened or
+    /// because `PRAGMA quick_check` finds a problem, it's moved aside (with
+    /// its journal), to a file named like
+    /// `places.sqlite.corrupt-<milliseconds since epoch>`, and a new one is
+    /// created in its place. `was_recovered` then returns true, so that the
+    /// app can tell the user their history was lost. We only fail with
+    /// `DatabaseCorrupted` if we couldn't move it aside. If the database is
+    /// encrypted, we can't tell a corrupt database from a wrong key, so only
+    /// errors that can't be caused by the key are treated as corruption.
+    pub fn new(db_name: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
+        Self::new_with_url_policy(db_name, encryption_key, UrlPolicy::default())
+    }
+
+    /// Like `new`, but every connection uses `url_policy`, instead of the
+    /// default, to decide which URLs to store, and how long their titles may
+    /// be.
+    pub fn new_with_url_policy(
+        db_name: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        url_policy: UrlPolicy,
+    ) -> Result<Self> {
+        let db_name = db_name.as_ref().to_path_buf();
+        let (mut write_connection, recovered) = match open_checked(&db_name, encryption_key) {
+            Ok(db) => (db, false),
+            Err(ref e) if is_corrupt(e, encryption_key.is_some()) => {
+                error!("Places database is corrupt: {}", e);
+                match move_aside(&db_name) {
+                    Ok(moved_to) => warn!("Moved the corrupt database to {:?}", moved_to),
+                    Err(e) => {
+                        error!("Couldn't move the corrupt database aside: {}", e);
+                        return Err(ErrorKind::DatabaseCorrupted.into());
+                    }
+                }
+                let db = PlacesDb::open_with_type(&db_name, encryption_key, ConnectionType::ReadWrite)?;
+                (db, true)
+            }
+            Err(e) => return Err(e),
+        };
+        write_connection.set_url_policy(url_policy.clone());
+        Ok(Self {
+            db_name,
+            encryption_key: Mutex::new(encryption_key.map(|k| k.to_owned())),
+            url_policy,
+            write_connection: Mutex::new(Some(write_connection)),
+            sync_connection_open: AtomicBool::new(false),
+            read_interrupts: Mutex::new(Vec::new()),
+            recovered,
+        })
+    }
+
+    /// Whether the database was corrupt when we opened it, and was replaced
+    /// with an empty one.
+    pub fn was_recovered(&self) -> bool {
+        self.recovered
+    }
+
+    /// Opens a connection of the given type. Fails with
+    /// `ConnectionAlreadyOpen` if a read-write or sync connection is
+    /// requested while one is already in use.
+    pub fn open_connection(&self, conn_type: ConnectionType) -> Result<PlacesDb> {
+        match conn_type {
+            ConnectionType::ReadOnly => {
+                let db = self.open(conn_type)?;
+                let mut interrupts = lock(&self.read_interrupts);
+                interrupts.retain(|handle| handle.upgrade().is_some());
+                interrupts.push(Arc::downgrade(&db.new_interrupt_handle()));
+                Ok(db)
+            }
+            ConnectionType::ReadWrite => {
+                match lock(&self.write_connection).take() {
+                    Some(db) => Ok(db),
+                    None => Err(ErrorKind::ConnectionAlreadyOpen.into()),
+                }
+            }
+            ConnectionType::Sync => {
+                if self.sync_connection_open.swap(true, Ordering::SeqCst) {
+                    return Err(ErrorKind::ConnectionAlreadyOpen.into());
+                }
+                self.open(conn_type).map_err(|e| {
+                    self.sync_connection_open.store(false, Ordering::SeqCst);
+                    e
+                })
+            }
+        }
+    }
+
+    /// Returns a connection from `open_connection`. Read-only connections are
+    /// just closed, but read-write and sync connections become available to
+    /// be opened again.
+    pub fn close_connection(&self, db: PlacesDb) -> Result<()> {
+        match db.conn_type() {
+            ConnectionType::ReadOnly => {}
+            ConnectionType::ReadWrite => {
+                let mut write_connection = lock(&self.write_connection);
+                if write_connection.is_some() {
+                    // Someone's closing a connection from a different API.
+                    return Err(ErrorKind::WrongApiForClose.into());
+                }
+                *write_connection = Some(db);
+            }
+            ConnectionType::Sync => {
+                if !self.sync_connection_open.swap(false, Ordering::SeqCst) {
+                    return Err(ErrorKind::WrongApiForClose.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Interrupts the queries running on every read-only connection that's
+    /// still open. Writers are left alone, so that we never abandon
+    /// something the user asked us to save.
+    pub fn interrupt_readers(&self) {
+        let mut interrupts = lock(&self.read_interrupts);
+        interrupts.retain(|handle| match handle.upgrade() {
+            Some(handle) => {
+                handle.interrupt();
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Re-encrypts the database with `new_key`, using `db`, which must be the
+    /// read-write connection from this API. Connections opened afterwards use
+    /// the new key, but any other connections which are already open will
+    /// need to be closed and opened again. See `PlacesDb::rekey`.
+    pub fn rekey(&self, db: &PlacesDb, new_key: &str) -> Result<()> {
+        if db.conn_type() != ConnectionType::ReadWrite {
+            return Err(ErrorKind::CannotRekey("Not the read-write connection".into()).into());
+        }
+        let mut encryption_key = lock(&self.encryption_key);
+        if encryption_key.is_none() {
+            return Err(ErrorKind::CannotRekey("The database isn't encrypted".into()).into());
+        }
+        db.rekey(new_key)?;
+        *encryption_key = Some(new_key.to_owned());
+        Ok(())
+    }
+
+    fn open(&self, conn_type: ConnectionType) -> Result<PlacesDb> {
+        let encryption_key = lock(&self.encryption_key);
+        let mut db = PlacesDb::open_with_type(
+            &self.db_name,
+            encryption_key.as_ref().map(|k| k.as_str()),
+            conn_type,
+        )?;
+        db.set_url_policy(self.url_policy.clone());
+        Ok(db)
+    }
+}
+
+// Opens the read-write connection, and checks that the database isn't
+// corrupt. Opening only reads the header and schema, so a database with
+// damaged pages or indexes would otherwise open fine, and fail later.
+fn open_checked(db_name: &Path, encryption_key: Option<&str>) -> Result<PlacesDb> {
+    let db = PlacesDb::open_with_type(db_name, encryption_key, ConnectionType::ReadWrite)?;
+    // `quick_check` returns a single "ok" row, or a row for each problem.
+    let result: String = db.query_one("PRAGMA quick_check")?;
+    if result != "ok" {
+        error!("Places database failed the integrity check: {}", result);
+        return Err(ErrorKind::DatabaseCorrupted.into());
+    }
+    Ok(db)
+}
+
+// Whether `e`, from opening the database, means that it's corrupt. For an
+// encrypted database, SQLCipher reports a wrong key as `NotADatabase`, and
+// throwing away a database that was fine would be much worse than failing.
+fn is_corrupt(e: &Error, encrypted: bool) -> bool {
+    match e.kind() {
+        ErrorKind::DatabaseCorrupted => true,
+        ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => match err.code {
+            rusqlite::ErrorCode::DatabaseCorrupt => true,
+            rusqlite::ErrorCode::NotADatabase => !encrypted,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Renames the database at `path`, and its rollback journal, WAL and shared
+// memory files if it has them, so that a new database can be created there.
+// The old journal or WAL can't be left behind, since SQLite would try to
+// apply it to the new database.
+// Returns the database's new path.
+fn move_aside(path: &Path) -> io::Result<PathBuf> {
+    let mut moved_to = path.as_os_str().to_owned();
+    moved_to.push(format!(".corrupt-{}", Timestamp::now().0));
+    for suffix in &["", "-journal", "-wal", "-shm"] {
+        let mut from = path.as_os_str().to_owned();
+        from.push(suffix);
+        let mut to: OsString = moved_to.clone();
+        to.push(suffix);
+        match fs::rename(&from, &to) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !suffix.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(PathBuf::from(moved_to))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    // Nothing we guard can be left in an inconsistent state by a panic.
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = PlacesApi::new(dir.path().join("places.sqlite"), None).unwrap();
+
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        assert!(api.open_connection(ConnectionType::ReadWrite).is_err());
+        writer.execute_batch("INSERT INTO moz_meta(key, value) VALUES('test', 1)").unwrap();
+
+        let reader = api.open_connection(ConnectionType::ReadOnly).unwrap();
+        let count: i64 = reader.query_row(
+            "SELECT COUNT(*) FROM moz_meta WHERE key = 'test'", &[], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        assert!(reader.execute_batch("DELETE FROM moz_meta").is_err());
+
+        let sync = api.open_connection(ConnectionType::Sync).unwrap();
+        assert!(api.open_connection(ConnectionType::Sync).is_err());
+
+        api.close_connection(writer).unwrap();
+        api.close_connection(sync).unwrap();
+        api.close_connection(reader).unwrap();
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        let sync = api.open_connection(ConnectionType::Sync).unwrap();
+
+        // Connections can't be returned to an API they didn't come from.
+        let other_dir = tempfile::tempdir().unwrap();
+        let other = PlacesApi::new(other_dir.path().join("places.sqlite"), None).unwrap();
+        assert!(other.close_connection(writer).is_err());
+        assert!(other.close_connection(sync).is_err());
+    }
+
+    #[test]
+    fn test_url_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = UrlPolicy::new().block_scheme("x-internal").with_max_title_length(10);
+        let api = PlacesApi::new_with_url_policy(
+            dir.path().join("places.sqlite"), None, policy.clone()).unwrap();
+
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        assert_eq!(writer.url_policy(), &policy);
+        let reader = api.open_connection(ConnectionType::ReadOnly).unwrap();
+        assert_eq!(reader.url_policy(), &policy);
+        let sync = api.open_connection(ConnectionType::Sync).unwrap();
+        assert_eq!(sync.url_policy(), &policy);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = PlacesApi::new(dir.path().join("places.sqlite"), Some("secret")).unwrap();
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        let reader = api.open_connection(ConnectionType::ReadOnly).unwrap();
+        assert!(api.rekey(&reader, "new secret").is_err());
+        api.rekey(&writer, "new secret").unwrap();
+        // New connections use the new key.
+        api.open_connection(ConnectionType::ReadOnly).unwrap();
+
+        let plain_dir = tempfile::tempdir().unwrap();
+        let plain = PlacesApi::new(plain_dir.path().join("places.sqlite"), None).unwrap();
+        let writer = plain.open_connection(ConnectionType::ReadWrite).unwrap();
+        assert!(plain.rekey(&writer, "secret").is_err());
+    }
+
+    #[test]
+    fn test_corrupt_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        let garbage = "This isn't a database. ".repeat(200);
+        fs::write(&path, &garbage).unwrap();
+        fs::write(dir.path().join("places.sqlite-wal"), "Nor is this.").unwrap();
+        // A journal that starts with a zero byte isn't hot, so SQLite leaves
+        // it alone when we try to open the database.
+        fs::write(dir.path().join("places.sqlite-journal"), "\0Nor this.").unwrap();
+
+        // We can't tell this from a wrong key, so we should leave it alone.
+        let err = PlacesApi::new(&path, Some("secret")).err().expect("should fail to open");
+        assert!(!is_corrupt(&err, true));
+        assert_eq!(fs::read_to_string(&path).unwrap(), garbage);
+
+        let api = PlacesApi::new(&path, None).expect("should replace the corrupt database");
+        assert!(api.was_recovered());
+        let mut moved: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("places.sqlite.corrupt-"))
+            .collect();
+        moved.sort();
+        assert_eq!(moved.len(), 3);
+        assert!(moved[1].ends_with("-journal"));
+        assert!(moved[2].ends_with("-wal"));
+        assert!(!dir.path().join("places.sqlite-journal").exists());
+        assert_eq!(fs::read_to_string(dir.path().join(&moved[0])).unwrap(), garbage);
+
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        writer.execute_batch("INSERT INTO moz_meta(key, value) VALUES('test', 1)").unwrap();
+        api.close_connection(writer).unwrap();
+        drop(api);
+
+        let api = PlacesApi::new(&path, None).expect("should open the new database");
+        assert!(!api.was_recovered());
+    }
+
+    #[test]
+    fn test_corrupt_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let api = PlacesApi::new(&path, None).unwrap();
+            let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+            let tx = writer.begin_transaction().unwrap();
+            for i in 0..2000 {
+                tx.execute_named_cached(
+                    "INSERT INTO moz_meta(key, value) VALUES(:key, :value)",
+                    &[(":key", &format!("test-{}", i)), (":value", &"x".repeat(100))],
+                ).unwrap();
+            }
+            tx.commit().unwrap();
+            api.close_connection(writer).unwrap();
+        }
+
+        // Scribble over the last page. The schema is still readable, so
+        // opening the database works, but `quick_check` should notice.
+        let len = fs::metadata(&path).unwrap().len();
+        let mut bytes = fs::read(&path).unwrap();
+        for b in &mut bytes[(len - 32768 + 100) as usize..(len - 32768 + 1100) as usize] {
+            *b = 0xff;
+        }
+        fs::write(&path, &bytes).unwrap();
+
+        let api = PlacesApi::new(&path, None).expect("should replace the corrupt database");
+        assert!(api.was_recovered());
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        let count: i64 = writer.query_one("SELECT COUNT(*) FROM moz_meta WHERE key LIKE 'test-%'").unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,571 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+
+use error::*;
+use types_support::Guid;
+use super::tree::{Kind, Node, Tree};
+
+/// Which side's value (title, URL, etc) a merged item takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueState {
+    /// The item didn't change on either side.
+    Unchanged,
+    Local,
+    Remote,
+}
+
+/// What we need to do with a merged item, taking into account both its value
+/// and its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeState {
+    /// Nothing changed.
+    Unchanged,
+    /// The local item is correct, but needs to be uploaded.
+    Local,
+    /// The remote item is correct, and needs to be applied locally.
+    Remote,
+    /// The local value wins, but the merged children differ from both sides,
+    /// so it needs to be applied locally and uploaded.
+    LocalWithNewStructure,
+    /// The remote value wins, but the merged children differ from the remote
+    /// children, so it needs to be applied locally and uploaded.
+    RemoteWithNewStructure,
+}
+
+impl MergeState {
+    pub fn should_apply(self) -> bool {
+        match self {
+            MergeState::Remote |
+            MergeState::LocalWithNewStructure |
+            MergeState::RemoteWithNewStructure => true,
+            MergeState::Unchanged | MergeState::Local => false,
+        }
+    }
+
+    pub fn should_upload(self) -> bool {
+        match self {
+            MergeState::Local |
+            MergeState::LocalWithNewStructure |
+            MergeState::RemoteWithNewStructure => true,
+            MergeState::Unchanged | MergeState::Remote => false,
+        }
+    }
+}
+
+/// An item in the merged tree.
+#[derive(Debug)]
+pub struct MergedNode {
+    pub guid: Guid,
+    pub kind: Kind,
+    pub value_state: ValueState,
+    /// The item's parent in the local and remote trees, if it exists there.
+    pub local_parent: Option<Guid>,
+    pub remote_parent: Option<Guid>,
+    pub merged_children: Vec<MergedNode>,
+    local_children: Option<Vec<Guid>>,
+    remote_children: Option<Vec<Guid>>,
+}
+
+impl MergedNode {
+    fn new(value_state: ValueState, local: Option<Node>, remote: Option<Node>) -> MergedNode {
+        let (guid, kind) = {
+            let winner = match (value_state, local, remote) {
+                (ValueState::Local, Some(node), _) | (_, _, Some(node)) | (_, Some(node), None) => node,
+                (_, None, None) => unreachable!("Merged items must exist on at least one side"),
+            };
+            (winner.guid().clone(), winner.item().kind)
+        };
+        MergedNode {
+            guid,
+            kind,
+            value_state,
+            local_parent: local.and_then(|n| n.parent()).map(|p| p.guid().clone()),
+            remote_parent: remote.and_then(|n| n.parent()).map(|p| p.guid().clone()),
+            merged_children: Vec::new(),
+            local_children: local.map(|n| n.child_guids()),
+            remote_children: remote.map(|n| n.child_guids()),
+        }
+    }
+
+    #[inline]
+    pub fn exists_locally(&self) -> bool {
+        self.local_children.is_some()
+    }
+
+    #[inline]
+    pub fn exists_remotely(&self) -> bool {
+        self.remote_children.is_some()
+    }
+
+    pub fn merge_state(&self) -> MergeState {
+        let merged: Vec<&Guid> = self.merged_children.iter().map(|c| &c.guid).collect();
+        let differs = |children: &Option<Vec<Guid>>| children.as_ref().map_or(false, |children|
+            children.len() != merged.len() || children.iter().zip(&merged).any(|(a, b)| a != *b));
+        let new_local_structure = differs(&self.local_children);
+        let new_remote_structure = differs(&self.remote_children);
+        match self.value_state {
+            ValueState::Local if new_local_structure => MergeState::LocalWithNewStructure,
+            ValueState::Local => MergeState::Local,
+            ValueState::Remote if new_remote_structure => MergeState::RemoteWithNewStructure,
+            ValueState::Remote => MergeState::Remote,
+            ValueState::Unchanged => match (new_local_structure, new_remote_structure) {
+                (false, false) => MergeState::Unchanged,
+                (true, false) => MergeState::Remote,
+                (false, true) => MergeState::Local,
+                (true, true) => MergeState::LocalWithNewStructure,
+            },
+        }
+    }
+
+    /// Whether the item needs to be written to the local tree, given the
+    /// GUID of its parent in the merged tree (`None` for the root).
+    pub fn needs_apply(&self, parent: Option<&Guid>) -> bool {
+        !self.exists_locally() || self.merge_state().should_apply() ||
+            self.local_parent.as_ref() != parent
+    }
+
+    /// Whether the item needs to be uploaded, given the GUID of its parent in
+    /// the merged tree (`None` for the root).
+    pub fn needs_upload(&self, parent: Option<&Guid>) -> bool {
+        !self.exists_remotely() || self.merge_state().should_upload() ||
+            self.remote_parent.as_ref() != parent
+    }
+
+    fn find_mut(&mut self, guid: &Guid) -> Option<&mut MergedNode> {
+        if self.guid == *guid {
+            return Some(self);
+        }
+        self.merged_children.iter_mut().filter_map(|child| child.find_mut(guid)).next()
+    }
+}
+
+/// The result of a merge.
+#[derive(Debug)]
+pub struct MergedRoot {
+    pub node: MergedNode,
+    /// Items which were deleted remotely, and should be deleted locally.
+    pub delete_locally: HashSet<Guid>,
+    /// Items which were deleted locally, and need tombstones uploaded.
+    pub delete_remotely: HashSet<Guid>,
+}
+
+impl MergedRoot {
+    /// Returns every item in the merged tree except the root, in pre-order,
+    /// along with its parent and its position in that parent.
+    pub fn descendants(&self) -> Vec<(&MergedNode, &MergedNode, usize)> {
+        fn accumulate<'a>(node: &'a MergedNode, results: &mut Vec<(&'a MergedNode, &'a MergedNode, usize)>) {
+            for (position, child) in node.merged_children.iter().enumerate() {
+                results.push((child, node, position));
+                accumulate(child, results);
+            }
+        }
+        let mut results = Vec::new();
+        accumulate(&self.node, &mut results);
+        results
+    }
+}
+
+/// Merges a local and remote bookmark tree, in the style of desktop's
+/// "dogear" merger.
+///
+/// Items are matched by GUID. Values are taken from whichever side changed
+/// (or changed most recently, if both did). Folder contents are merged rather
+/// than replaced: we walk the children of the side which changed most
+/// recently first, then add anything new from the other side. An item which
+/// moved on both sides ends up in the parent which changed most recently.
+///
+/// Deleting an item which changed on the other side revives it. Deleting a
+/// folder deletes its unchanged descendants, but descendants which were added
+/// or changed on the other side are moved to the closest surviving ancestor,
+/// so we never lose a bookmark because someone deleted its folder.
+pub struct Merger<'t> {
+    local_tree: &'t Tree,
+    remote_tree: &'t Tree,
+    merged_guids: HashSet<Guid>,
+    delete_locally: HashSet<Guid>,
+    delete_remotely: HashSet<Guid>,
+}
+
+impl<'t> Merger<'t> {
+    pub fn new(local_tree: &'t Tree, remote_tree: &'t Tree) -> Merger<'t> {
+        Merger {
+            local_tree,
+            remote_tree,
+            merged_guids: HashSet::new(),
+            delete_locally: HashSet::new(),
+            delete_remotely: HashSet::new(),
+        }
+    }
+
+    pub fn merge(mut self) -> Result<MergedRoot> {
+        let local_root = self.local_tree.root();
+        let remote_root = self.remote_tree.root();
+        if local_root.guid() != remote_root.guid() {
+            return Err(ErrorKind::InvalidBookmarkTree(format!(
+                "Local root {} doesn't match remote root {}", local_root.guid(), remote_root.guid())).into());
+        }
+        let mut root = self.two_way_merge(local_root, remote_root)?;
+        self.relocate_unmerged(&mut root)?;
+        Ok(MergedRoot {
+            node: root,
+            delete_locally: self.delete_locally,
+            delete_remotely: self.delete_remotely,
+        })
+    }
+
+    fn two_way_merge(&mut self, local: Node<'t>, remote: Node<'t>) -> Result<MergedNode> {
+        if local.item().kind != remote.item().kind {
+            return Err(ErrorKind::InvalidBookmarkTree(format!(
+                "Item {} is a {:?} locally, but a {:?} remotely",
+                local.guid(), local.item().kind, remote.item().kind)).into());
+        }
+        self.merged_guids.insert(local.guid().clone());
+        let value_state = resolve_value(local, remote);
+        let mut merged = MergedNode::new(value_state, Some(local), Some(remote));
+        if value_state == ValueState::Local {
+            for child in local.children() {
+                self.merge_local_child(&mut merged, child)?;
+            }
+            for child in remote.children() {
+                self.merge_remote_child(&mut merged, child)?;
+            }
+        } else {
+            for child in remote.children() {
+                self.merge_remote_child(&mut merged, child)?;
+            }
+            for child in local.children() {
+                self.merge_local_child(&mut merged, child)?;
+            }
+        }
+        Ok(merged)
+    }
+
+    fn merge_local_only(&mut self, local: Node<'t>) -> Result<MergedNode> {
+        self.merged_guids.insert(local.guid().clone());
+        let mut merged = MergedNode::new(ValueState::Local, Some(local), None);
+        for child in local.children() {
+            self.merge_local_child(&mut merged, child)?;
+        }
+        Ok(merged)
+    }
+
+    fn merge_remote_only(&mut self, remote: Node<'t>) -> Result<MergedNode> {
+        self.merged_guids.insert(remote.guid().clone());
+        let mut merged = MergedNode::new(ValueState::Remote, None, Some(remote));
+        for child in remote.children() {
+            self.merge_remote_child(&mut merged, child)?;
+        }
+        Ok(merged)
+    }
+
+    /// Merges `remote_child` into `merged`, unless it belongs somewhere else.
+    fn merge_remote_child(&mut self, merged: &mut MergedNode, remote_child: Node<'t>) -> Result<()> {
+        let guid = remote_child.guid();
+        if self.merged_guids.contains(guid) || self.delete_remotely.contains(guid) {
+            return Ok(());
+        }
+        if self.local_tree.is_deleted(guid) && !remote_child.item().needs_merge {
+            // Deleted locally, and unchanged remotely, so delete it.
+            self.delete_remotely.insert(guid.clone());
+            self.delete_remote_descendants(remote_child);
+            return Ok(());
+        }
+        match self.local_tree.node_for_guid(guid) {
+            None => {
+                let child = self.merge_remote_only(remote_child)?;
+                merged.merged_children.push(child);
+            }
+            Some(local_child) => {
+                let local_parent = local_child.parent().expect("Only the root has no parent");
+                let remote_parent = remote_child.parent().expect("Only the root has no parent");
+                if *local_parent.guid() == merged.guid || self.prefer_remote_parent(local_parent, remote_parent) {
+                    let child = self.two_way_merge(local_child, remote_child)?;
+                    merged.merged_children.push(child);
+                }
+                // Otherwise, it'll be merged into its local parent.
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges `local_child` into `merged`, unless it belongs somewhere else.
+    fn merge_local_child(&mut self, merged: &mut MergedNode, local_child: Node<'t>) -> Result<()> {
+        let guid = local_child.guid();
+        if self.merged_guids.contains(guid) || self.delete_locally.contains(guid) {
+            return Ok(());
+        }
+        if self.remote_tree.is_deleted(guid) && !local_child.item().needs_merge {
+            // Deleted remotely, and unchanged locally.
+            self.delete_locally.insert(guid.clone());
+            self.delete_local_descendants(local_child);
+            return Ok(());
+        }
+        match self.remote_tree.node_for_guid(guid) {
+            None => {
+                let child = self.merge_local_only(local_child)?;
+                merged.merged_children.push(child);
+            }
+            Some(remote_child) => {
+                let local_parent = local_child.parent().expect("Only the root has no parent");
+                let remote_parent = remote_child.parent().expect("Only the root has no parent");
+                if *remote_parent.guid() == merged.guid || !self.prefer_remote_parent(local_parent, remote_parent) {
+                    let child = self.two_way_merge(local_child, remote_child)?;
+                    merged.merged_children.push(child);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// For an item which is in different folders locally and remotely,
+    /// decides which folder it ends up in.
+    fn prefer_remote_parent(&self, local_parent: Node<'t>, remote_parent: Node<'t>) -> bool {
+        // If one of the parents is going away, the item survives in the other.
+        if self.local_tree.is_deleted(remote_parent.guid()) && !remote_parent.item().needs_merge {
+            return false;
+        }
+        if self.remote_tree.is_deleted(local_parent.guid()) && !local_parent.item().needs_merge {
+            return true;
+        }
+        let (local, remote) = (local_parent.item(), remote_parent.item());
+        match (local.needs_merge, remote.needs_merge) {
+            (true, false) => false,
+            (false, _) => true,
+            (true, true) => remote.age <= local.age,
+        }
+    }
+
+    fn delete_remote_descendants(&mut self, node: Node<'t>) {
+        for child in node.children() {
+            // Items which exist locally are merged from the local tree, and
+            // items which changed remotely are relocated.
+            if self.merged_guids.contains(child.guid()) ||
+               self.local_tree.node_for_guid(child.guid()).is_some() ||
+               child.item().needs_merge {
+                continue;
+            }
+            self.delete_remotely.insert(child.guid().clone());
+            self.delete_remote_descendants(child);
+        }
+    }
+
+    fn delete_local_descendants(&mut self, node: Node<'t>) {
+        for child in node.children() {
+            if self.merged_guids.contains(child.guid()) ||
+               self.remote_tree.node_for_guid(child.guid()).is_some() ||
+               child.item().needs_merge {
+                continue;
+            }
+            self.delete_locally.insert(child.guid().clone());
+            self.delete_local_descendants(child);
+        }
+    }
+
+    /// Moves items which weren't merged (because their parents were deleted)
+    /// into their closest merged ancestor.
+    fn relocate_unmerged(&mut self, root: &mut MergedNode) -> Result<()> {
+        let mut unmerged: Vec<(usize, Guid)> = self.local_tree.guids()
+            .chain(self.remote_tree.guids())
+            .filter(|guid| !self.merged_guids.contains(*guid) &&
+                           !self.delete_locally.contains(*guid) &&
+                           !self.delete_remotely.contains(*guid))
+            .map(|guid| (self.level(guid), guid.clone()))
+            .collect();
+        // Parents before children, so that children of relocated folders stay
+        // in their folder.
+        unmerged.sort();
+        unmerged.dedup();
+        for (_, guid) in unmerged {
+            if self.merged_guids.contains(&guid) {
+                continue;
+            }
+            let local = self.local_tree.node_for_guid(&guid);
+            let remote = self.remote_tree.node_for_guid(&guid);
+            let parent_guid = self.closest_merged_ancestor(local.or(remote).expect("Item exists"));
+            debug!("Moving {} into {}, since its parent was deleted", guid, parent_guid);
+            let child = match (local, remote) {
+                (Some(local), Some(remote)) => self.two_way_merge(local, remote)?,
+                (Some(local), None) => self.merge_local_only(local)?,
+                (None, Some(remote)) => self.merge_remote_only(remote)?,
+                (None, None) => unreachable!(),
+            };
+            root.find_mut(&parent_guid).expect("Ancestor was merged").merged_children.push(child);
+        }
+        Ok(())
+    }
+
+    fn level(&self, guid: &Guid) -> usize {
+        self.local_tree.node_for_guid(guid)
+            .or_else(|| self.remote_tree.node_for_guid(guid))
+            .map_or(0, |node| node.level())
+    }
+
+    fn closest_merged_ancestor(&self, node: Node<'t>) -> Guid {
+        let mut parent = node.parent();
+        while let Some(p) = parent {
+            if self.merged_guids.contains(p.guid()) && p.item().kind.is_folder() {
+                return p.guid().clone();
+            }
+            parent = p.parent();
+        }
+        // The root is always merged first.
+        self.local_tree.root().guid().clone()
+    }
+}
+
+fn resolve_value(local: Node, remote: Node) -> ValueState {
+    let (local, remote) = (local.item(), remote.item());
+    match (local.needs_merge, remote.needs_merge) {
+        (false, false) => ValueState::Unchanged,
+        (true, false) => ValueState::Local,
+        (false, true) => ValueState::Remote,
+        (true, true) => if local.age < remote.age { ValueState::Local } else { ValueState::Remote },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tree::{Item, TreeBuilder};
+
+    // (guid, parent, kind, needs_merge, age)
+    type Spec<'a> = (&'a str, &'a str, Kind, bool, i64);
+
+    fn build_tree(specs: &[Spec], deleted: &[&str]) -> Tree {
+        let mut builder = TreeBuilder::new(Item::new(Guid::new("root"), Kind::Folder));
+        for (position, &(guid, parent, kind, needs_merge, age)) in specs.iter().enumerate() {
+            builder
+                .item(Item { guid: Guid::new(guid), kind, age, needs_merge })
+                .parent_of(Guid::new(guid), Guid::new(parent), position as i64);
+        }
+        for guid in deleted {
+            builder.deleted(Guid::new(guid));
+        }
+        builder.build().unwrap()
+    }
+
+    fn children(root: &MergedRoot, guid: &str) -> Vec<String> {
+        fn find<'a>(node: &'a MergedNode, guid: &str) -> Option<&'a MergedNode> {
+            if node.guid == guid {
+                return Some(node);
+            }
+            node.merged_children.iter().filter_map(|c| find(c, guid)).next()
+        }
+        find(&root.node, guid).expect("merged").merged_children.iter()
+            .map(|c| c.guid.to_string()).collect()
+    }
+
+    fn state(root: &MergedRoot, guid: &str) -> MergeState {
+        root.descendants().into_iter()
+            .find(|&(node, _, _)| node.guid == guid)
+            .map(|(node, _, _)| node.merge_state())
+            .expect("merged")
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let specs = [
+            ("menu", "root", Kind::Folder, false, 0),
+            ("bmkA", "menu", Kind::Bookmark, false, 0),
+        ];
+        let local = build_tree(&specs, &[]);
+        let remote = build_tree(&specs, &[]);
+        let merged = Merger::new(&local, &remote).merge().unwrap();
+        assert_eq!(children(&merged, "menu"), vec!["bmkA"]);
+        for (node, parent, _) in merged.descendants() {
+            assert_eq!(node.merge_state(), MergeState::Unchanged);
+            assert!(!node.needs_apply(Some(&parent.guid)));
+            assert!(!node.needs_upload(Some(&parent.guid)));
+        }
+        assert!(merged.delete_locally.is_empty());
+        assert!(merged.delete_remotely.is_empty());
+    }
+
+    #[test]
+    fn test_new_items_on_both_sides() {
+        let local = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 10),
+            ("bmkA", "menu", Kind::Bookmark, false, 0),
+            ("bmkL", "menu", Kind::Bookmark, true, 10),
+        ], &[]);
+        let remote = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 5),
+            ("bmkR", "menu", Kind::Bookmark, true, 5),
+            ("bmkA", "menu", Kind::Bookmark, false, 0),
+        ], &[]);
+        let merged = Merger::new(&local, &remote).merge().unwrap();
+        // The remote menu is newer, so its order wins, and new local items
+        // go at the end.
+        assert_eq!(children(&merged, "menu"), vec!["bmkR", "bmkA", "bmkL"]);
+        assert_eq!(state(&merged, "menu"), MergeState::RemoteWithNewStructure);
+        assert_eq!(state(&merged, "bmkR"), MergeState::Remote);
+        assert_eq!(state(&merged, "bmkL"), MergeState::Local);
+        assert_eq!(state(&merged, "bmkA"), MergeState::Unchanged);
+    }
+
+    #[test]
+    fn test_move_conflict() {
+        let local = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 10),
+            ("toolbar", "root", Kind::Folder, true, 10),
+            ("unfiled", "root", Kind::Folder, false, 0),
+            ("bmkA", "toolbar", Kind::Bookmark, true, 10),
+            ("bmkB", "toolbar", Kind::Bookmark, true, 1),
+        ], &[]);
+        let remote = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 5),
+            ("toolbar", "root", Kind::Folder, false, 0),
+            ("unfiled", "root", Kind::Folder, true, 5),
+            ("bmkB", "menu", Kind::Bookmark, false, 0),
+            ("bmkA", "unfiled", Kind::Bookmark, true, 5),
+        ], &[]);
+        let merged = Merger::new(&local, &remote).merge().unwrap();
+        // A is in the toolbar locally, and in unfiled remotely, which changed
+        // more recently. Similarly, B is in the toolbar locally, but the
+        // remote menu changed more recently, so it ends up there.
+        assert_eq!(children(&merged, "unfiled"), vec!["bmkA"]);
+        assert_eq!(children(&merged, "menu"), vec!["bmkB"]);
+        assert!(children(&merged, "toolbar").is_empty());
+        assert_eq!(state(&merged, "toolbar"), MergeState::LocalWithNewStructure);
+    }
+
+    #[test]
+    fn test_deletions() {
+        let local = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 10),
+            ("bmkA", "menu", Kind::Bookmark, false, 0),
+            ("bmkC", "menu", Kind::Bookmark, true, 10),
+        ], &["bmkB", "folderD"]);
+        let remote = build_tree(&[
+            ("menu", "root", Kind::Folder, true, 5),
+            ("bmkB", "menu", Kind::Bookmark, true, 5),
+            ("folderD", "menu", Kind::Folder, false, 0),
+            ("bmkE", "folderD", Kind::Bookmark, true, 5),
+            ("bmkF", "folderD", Kind::Bookmark, false, 0),
+        ], &["bmkA", "bmkC"]);
+        let merged = Merger::new(&local, &remote).merge().unwrap();
+        // A was deleted remotely and unchanged locally, so it's deleted. C
+        // changed locally, so it's revived, as is B, which changed remotely.
+        // D was deleted locally, so it's deleted along with F, but E is new,
+        // so it moves to the menu.
+        assert_eq!(children(&merged, "menu"), vec!["bmkB", "bmkC", "bmkE"]);
+        assert_eq!(merged.delete_locally,
+                   vec![Guid::new("bmkA")].into_iter().collect::<HashSet<_>>());
+        assert_eq!(merged.delete_remotely,
+                   vec![Guid::new("folderD"), Guid::new("bmkF")].into_iter().collect::<HashSet<_>>());
+        let (bmk_e, parent, _) = merged.descendants().into_iter()
+            .find(|&(node, _, _)| node.guid == "bmkE").unwrap();
+        assert!(bmk_e.needs_apply(Some(&parent.guid)));
+        assert!(bmk_e.needs_upload(Some(&parent.guid)));
+    }
+
+    #[test]
+    fn test_mismatched_kinds() {
+        let local = build_tree(&[("menu", "root", Kind::Folder, false, 0)], &[]);
+        let remote = build_tree(&[("menu", "root", Kind::Bookmark, false, 0)], &[]);
+        assert!(Merger::new(&local, &remote).merge().is_err());
+    }
+}
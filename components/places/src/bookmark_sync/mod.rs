@@ -0,0 +1,13 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncs bookmarks, by merging the local and remote trees rather than just
+//! applying whichever record is newer. See `merge::Merger` for the details.
+
+pub mod record;
+pub mod tree;
+pub mod merge;
+mod store;
+
+pub use bookmark_sync::store::BookmarksStore;
@@ -0,0 +1,123 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use types_support::Guid;
+use super::tree::Kind;
+
+pub const ROOT_GUID: &str = "root________";
+pub const MENU_GUID: &str = "menu________";
+pub const TOOLBAR_GUID: &str = "toolbar_____";
+pub const UNFILED_GUID: &str = "unfiled_____";
+pub const MOBILE_GUID: &str = "mobile______";
+
+// The roots have different IDs on the server than they do locally.
+const ROOT_SYNC_IDS: &[(&str, &str)] = &[
+    ("places", ROOT_GUID),
+    ("menu", MENU_GUID),
+    ("toolbar", TOOLBAR_GUID),
+    ("unfiled", UNFILED_GUID),
+    ("mobile", MOBILE_GUID),
+];
+
+/// Converts a record ID from the server to a local GUID.
+pub fn guid_from_sync_id(id: &str) -> Guid {
+    ROOT_SYNC_IDS.iter()
+        .find(|&&(sync_id, _)| sync_id == id)
+        .map_or_else(|| Guid::new(id), |&(_, guid)| Guid::new(guid))
+}
+
+/// Converts a local GUID to a record ID for the server.
+pub fn sync_id_from_guid(guid: &Guid) -> String {
+    ROOT_SYNC_IDS.iter()
+        .find(|&&(_, root_guid)| guid.as_str() == root_guid)
+        .map_or_else(|| guid.to_string(), |&(sync_id, _)| sync_id.to_string())
+}
+
+/// The position of a root in the places root, for remote roots which aren't
+/// listed in a "places" record (which the server usually doesn't have).
+pub fn root_position(guid: &Guid) -> Option<i64> {
+    [MENU_GUID, TOOLBAR_GUID, UNFILED_GUID, MOBILE_GUID].iter()
+        .position(|&root| guid.as_str() == root)
+        .map(|p| p as i64)
+}
+
+/// A record in the bookmarks collection. Every kind of item uses the same
+/// struct, with the fields that don't apply to it left empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookmarkRecord {
+    pub id: String,
+
+    #[serde(rename = "type")]
+    pub kind: Kind,
+
+    #[serde(rename = "parentid", default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(rename = "parentName", default, skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(rename = "bmkUri", default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// Record IDs of a folder's children, in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<String>>,
+
+    #[serde(rename = "dateAdded", default, skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_sync_ids() {
+        assert_eq!(guid_from_sync_id("menu"), MENU_GUID);
+        assert_eq!(guid_from_sync_id("bookmarkAAAA"), "bookmarkAAAA");
+        assert_eq!(sync_id_from_guid(&Guid::new(ROOT_GUID)), "places");
+        assert_eq!(sync_id_from_guid(&Guid::new("bookmarkAAAA")), "bookmarkAAAA");
+        assert_eq!(root_position(&Guid::new(UNFILED_GUID)), Some(2));
+        assert_eq!(root_position(&Guid::new(ROOT_GUID)), None);
+    }
+
+    #[test]
+    fn test_record_json() {
+        let record: BookmarkRecord = serde_json::from_str(r#"{
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "menu",
+            "title": "A folder",
+            "children": ["bookmarkBBBB"]
+        }"#).unwrap();
+        assert_eq!(record.kind, Kind::Folder);
+        assert_eq!(record.parent_id, Some("menu".to_string()));
+        assert_eq!(record.children, Some(vec!["bookmarkBBBB".to_string()]));
+        assert_eq!(record.url, None);
+
+        let json = serde_json::to_value(BookmarkRecord {
+            id: "bookmarkBBBB".into(),
+            kind: Kind::Bookmark,
+            parent_id: Some("folderAAAAAA".into()),
+            parent_name: None,
+            title: Some("A bookmark".into()),
+            url: Some("https://example.com/".into()),
+            children: None,
+            date_added: Some(1000),
+        }).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(r#"{
+            "id": "bookmarkBBBB",
+            "type": "bookmark",
+            "parentid": "folderAAAAAA",
+            "title": "A bookmark",
+            "bmkUri": "https://example.com/",
+            "dateAdded": 1000
+        }"#).unwrap();
+        assert_eq!(json, expected);
+    }
+}
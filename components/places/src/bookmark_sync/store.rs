@@ -0,0 +1,733 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::result;
+
+use failure;
+use rusqlite::Row;
+use rusqlite::types::{FromSql, ToSql};
+use sql_support::ConnExt;
+use sync::{CollectionRequest, IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp, Store};
+use sync::telemetry;
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use storage;
+use types::{SyncStatus, Timestamp};
+use types_support::Guid;
+use super::merge::{MergedNode, MergedRoot, Merger, ValueState};
+use super::record::{self, BookmarkRecord, MENU_GUID, MOBILE_GUID, ROOT_GUID, TOOLBAR_GUID, UNFILED_GUID};
+use super::tree::{Item, Kind, Tree, TreeBuilder};
+
+const COLLECTION_NAME: &str = "bookmarks";
+const LAST_SYNC_META_KEY: &str = "bookmarks_last_sync_time";
+
+// Values of `moz_bookmarks.type`. These are the same as desktop.
+const BOOKMARK_TYPE_BOOKMARK: i64 = 1;
+const BOOKMARK_TYPE_FOLDER: i64 = 2;
+const BOOKMARK_TYPE_SEPARATOR: i64 = 3;
+
+/// Syncs the bookmarks collection, by merging the incoming records with the
+/// local tree. See `Merger` for how conflicts are resolved.
+///
+/// Incoming records are staged in a mirror of the server (`moz_bookmarks_synced`),
+/// since we need the complete remote tree to merge, but only download the
+/// records which changed since the last sync.
+pub struct BookmarksStore<'a> {
+    db: &'a PlacesDb,
+    // The records we returned from `apply_incoming`, with their change
+    // counters as of when we fetched them, so that we can add them to the
+    // mirror once they've been uploaded.
+    outgoing: RefCell<HashMap<String, (Payload, i64)>>,
+}
+
+impl<'a> BookmarksStore<'a> {
+    pub fn new(db: &'a PlacesDb) -> BookmarksStore<'a> {
+        BookmarksStore { db, outgoing: RefCell::new(HashMap::new()) }
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.db.begin_transaction()?;
+        for &(ref payload, modified) in &inbound.changes {
+            if !self.stage_payload(payload, modified, true)? {
+                telem.failed += 1;
+            }
+        }
+        let local_tree = self.fetch_local_tree(Timestamp::now())?;
+        let remote_tree = self.fetch_remote_tree(inbound.timestamp)?;
+        let merged = Merger::new(&local_tree, &remote_tree).merge()?;
+        self.apply_merged(&merged)?;
+        let outgoing = self.fetch_outgoing(&merged, &local_tree, &remote_tree, inbound.timestamp)?;
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    /// Stores a record from the server (or one we've just uploaded) in the
+    /// mirror. Returns false if the record is invalid, and so was ignored.
+    fn stage_payload(&self, payload: &Payload, modified: ServerTimestamp, needs_merge: bool) -> Result<bool> {
+        let guid = record::guid_from_sync_id(payload.id());
+        let modified_ms = modified.as_millis() as i64;
+        self.db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+            &[(":guid", &guid)])?;
+        if payload.is_tombstone() {
+            self.db.execute_named_cached("
+                REPLACE INTO moz_bookmarks_synced(guid, serverModified, needsMerge, isDeleted)
+                VALUES(:guid, :serverModified, :needsMerge, 1)",
+                &[(":guid", &guid), (":serverModified", &modified_ms), (":needsMerge", &needs_merge)])?;
+            return Ok(true);
+        }
+        let record: BookmarkRecord = match payload.clone().into_record() {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Ignoring invalid bookmark record {}: {}", payload.id(), e);
+                return Ok(false);
+            }
+        };
+        let parent_guid = record.parent_id.as_ref().map(|id| record::guid_from_sync_id(id));
+        self.db.execute_named_cached("
+            REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge,
+                                              isDeleted, kind, dateAdded, title, url)
+            VALUES(:guid, :parentGuid, :serverModified, :needsMerge,
+                   0, :kind, :dateAdded, :title, :url)",
+            &[
+                (":guid", &guid),
+                (":parentGuid", &parent_guid),
+                (":serverModified", &modified_ms),
+                (":needsMerge", &needs_merge),
+                (":kind", &(record.kind as i64)),
+                (":dateAdded", &record.date_added.unwrap_or(0)),
+                (":title", &record.title),
+                (":url", &record.url),
+            ])?;
+        if let Some(children) = &record.children {
+            for (position, child_id) in children.iter().enumerate() {
+                self.db.execute_named_cached("
+                    INSERT OR IGNORE INTO moz_bookmarks_synced_structure(guid, parentGuid, position)
+                    VALUES(:guid, :parentGuid, :position)",
+                    &[
+                        (":guid", &record::guid_from_sync_id(child_id)),
+                        (":parentGuid", &guid),
+                        (":position", &(position as i64)),
+                    ])?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn fetch_local_tree(&self, now: Timestamp) -> Result<Tree> {
+        let mut builder = TreeBuilder::new(Item::new(Guid::new(ROOT_GUID), Kind::Folder))
+            .orphans_to(Guid::new(UNFILED_GUID));
+        let mut stmt = self.db.prepare("
+            SELECT b.guid, p.guid AS parentGuid, b.type, b.position,
+                   b.lastModified, b.syncChangeCounter, h.url
+            FROM moz_bookmarks b
+            LEFT JOIN moz_bookmarks p ON p.id = b.parent
+            LEFT JOIN moz_places h ON h.id = b.fk
+            WHERE b.guid <> :root")?;
+        let rows = stmt.query_and_then_named(&[(":root", &ROOT_GUID)], |row| -> Result<_> {
+            let type_: i64 = row.get_checked("type")?;
+            let url: Option<String> = row.get_checked("url")?;
+            let kind = match type_ {
+                BOOKMARK_TYPE_BOOKMARK if url.as_ref().map_or(false, |u| u.starts_with("place:")) => Kind::Query,
+                BOOKMARK_TYPE_BOOKMARK => Kind::Bookmark,
+                BOOKMARK_TYPE_FOLDER => Kind::Folder,
+                BOOKMARK_TYPE_SEPARATOR => Kind::Separator,
+                _ => return Err(ErrorKind::InvalidBookmarkTree(
+                    format!("Unknown bookmark type {}", type_)).into()),
+            };
+            let last_modified: Timestamp = row.get_checked("lastModified")?;
+            let item = Item {
+                guid: row.get_checked("guid")?,
+                kind,
+                age: (now.0 as i64 - last_modified.0 as i64).max(0),
+                needs_merge: row.get_checked::<_, i64>("syncChangeCounter")? > 0,
+            };
+            let parent: Option<Guid> = row.get_checked("parentGuid")?;
+            Ok((item, parent, row.get_checked::<_, i64>("position")?))
+        })?;
+        for row in rows {
+            let (item, parent, position) = row?;
+            if let Some(parent) = parent {
+                builder.parent_of(item.guid.clone(), parent, position);
+            }
+            builder.item(item);
+        }
+        for guid in self.query_guids("SELECT guid FROM moz_bookmarks_deleted")? {
+            builder.deleted(guid);
+        }
+        builder.build()
+    }
+
+    fn fetch_remote_tree(&self, server_now: ServerTimestamp) -> Result<Tree> {
+        let now_ms = server_now.as_millis() as i64;
+        let mut builder = TreeBuilder::new(Item::new(Guid::new(ROOT_GUID), Kind::Folder))
+            .orphans_to(Guid::new(UNFILED_GUID));
+
+        // The parent listed in the folder's `children` wins over the item's
+        // `parentid`, so add the structure first.
+        let mut stmt = self.db.prepare(
+            "SELECT guid, parentGuid, position FROM moz_bookmarks_synced_structure")?;
+        let rows = stmt.query_and_then_named(&[], |row| -> Result<_> {
+            Ok((row.get_checked::<_, Guid>("guid")?,
+                row.get_checked::<_, Guid>("parentGuid")?,
+                row.get_checked::<_, i64>("position")?))
+        })?;
+        for row in rows {
+            let (guid, parent, position) = row?;
+            builder.parent_of(guid, parent, position);
+        }
+
+        let mut stmt = self.db.prepare("
+            SELECT guid, parentGuid, serverModified, needsMerge, kind, url
+            FROM moz_bookmarks_synced
+            WHERE NOT isDeleted AND guid <> :root")?;
+        let rows = stmt.query_and_then_named(&[(":root", &ROOT_GUID)], |row| -> Result<_> {
+            let guid: Guid = row.get_checked("guid")?;
+            let kind = Kind::from_u8(row.get_checked::<_, i64>("kind")? as u8);
+            let age = (now_ms - row.get_checked::<_, i64>("serverModified")?).max(0);
+            let needs_merge: bool = row.get_checked("needsMerge")?;
+            let item = kind.map(|kind| Item { guid: guid.clone(), kind, age, needs_merge });
+            let url: Option<String> = row.get_checked("url")?;
+            Ok((guid, item, url, row.get_checked::<_, Option<Guid>>("parentGuid")?))
+        })?;
+        for row in rows {
+            let (guid, item, url, parent) = row?;
+            let item = match item {
+                Some(item) => item,
+                None => {
+                    warn!("Ignoring remote bookmark {} with unknown kind", guid);
+                    continue;
+                }
+            };
+            if item.kind.has_url() && url.as_ref().map_or(true, |u| Url::parse(u).is_err()) {
+                warn!("Ignoring remote bookmark {} with invalid URL", guid);
+                continue;
+            }
+            if let Some(parent) = parent {
+                let position = record::root_position(&guid).unwrap_or(i64::max_value());
+                builder.parent_of(guid, parent, position);
+            }
+            builder.item(item);
+        }
+        for guid in self.query_guids("SELECT guid FROM moz_bookmarks_synced WHERE isDeleted")? {
+            builder.deleted(guid);
+        }
+        builder.build()
+    }
+
+    fn query_guids(&self, sql: &str) -> Result<Vec<Guid>> {
+        let mut stmt = self.db.prepare(sql)?;
+        let rows = stmt.query_and_then_named(&[], |row| -> Result<Guid> {
+            Ok(row.get_checked(0)?)
+        })?;
+        rows.collect()
+    }
+
+    /// Writes the merged tree to `moz_bookmarks`.
+    fn apply_merged(&self, merged: &MergedRoot) -> Result<()> {
+        let now = Timestamp::now();
+        // Descendants are in pre-order, so parents are always written before
+        // their children.
+        for (node, parent, position) in merged.descendants() {
+            let needs_upload = node.needs_upload(Some(&parent.guid));
+            if node.value_state == ValueState::Remote || !node.exists_locally() {
+                self.apply_remote_value(node, parent, position, needs_upload, now)?;
+            } else if node.needs_apply(Some(&parent.guid)) || parent.merge_state().should_apply() {
+                self.db.execute_named_cached("
+                    UPDATE moz_bookmarks SET
+                        parent = (SELECT id FROM moz_bookmarks WHERE guid = :parentGuid),
+                        position = :position
+                    WHERE guid = :guid",
+                    &[
+                        (":parentGuid", &parent.guid),
+                        (":position", &(position as i64)),
+                        (":guid", &node.guid),
+                    ])?;
+            }
+            if needs_upload {
+                self.db.execute_named_cached("
+                    UPDATE moz_bookmarks SET syncChangeCounter = MAX(syncChangeCounter, 1)
+                    WHERE guid = :guid",
+                    &[(":guid", &node.guid)])?;
+            }
+        }
+        for guid in &merged.delete_locally {
+            self.db.execute_named_cached(
+                "DELETE FROM moz_bookmarks WHERE guid = :guid",
+                &[(":guid", guid)])?;
+        }
+        self.db.execute_all(&[
+            // Items we revived don't need tombstones any more.
+            "DELETE FROM moz_bookmarks_deleted WHERE guid IN (SELECT guid FROM moz_bookmarks)",
+            "UPDATE moz_bookmarks_synced SET needsMerge = 0",
+        ])?;
+        Ok(())
+    }
+
+    fn apply_remote_value(
+        &self,
+        node: &MergedNode,
+        parent: &MergedNode,
+        position: usize,
+        needs_upload: bool,
+        now: Timestamp,
+    ) -> Result<()> {
+        let (title, url, date_added) = self.db.query_row_and_then_named(
+            "SELECT title, url, dateAdded FROM moz_bookmarks_synced WHERE guid = :guid",
+            &[(":guid", &node.guid)],
+            |row| -> Result<(Option<String>, Option<String>, i64)> {
+                Ok((row.get_checked("title")?, row.get_checked("url")?, row.get_checked("dateAdded")?))
+            },
+            true)?;
+        let place_id = match url {
+            Some(ref url) if node.kind.has_url() => Some(storage::get_or_insert_page_id(self.db, self.db.url_policy(), &Url::parse(url)?)?),
+            _ => None,
+        };
+        let type_ = match node.kind {
+            Kind::Bookmark | Kind::Query => BOOKMARK_TYPE_BOOKMARK,
+            Kind::Folder | Kind::Livemark => BOOKMARK_TYPE_FOLDER,
+            Kind::Separator => BOOKMARK_TYPE_SEPARATOR,
+        };
+        let date_added = if date_added > 0 { Timestamp(date_added as u64) } else { now };
+        let change_counter: i64 = if needs_upload { 1 } else { 0 };
+        let params: &[(&str, &ToSql)] = &[
+            (":guid", &node.guid),
+            (":type", &type_),
+            (":fk", &place_id),
+            (":title", &title),
+            (":dateAdded", &date_added),
+            (":now", &now),
+            (":parentGuid", &parent.guid),
+            (":position", &(position as i64)),
+            (":syncStatus", &SyncStatus::Normal),
+            (":syncChangeCounter", &change_counter),
+        ];
+        if node.exists_locally() {
+            self.db.execute_named_cached("
+                UPDATE moz_bookmarks SET
+                    type = :type, fk = :fk, title = :title, dateAdded = :dateAdded,
+                    lastModified = :now,
+                    parent = (SELECT id FROM moz_bookmarks WHERE guid = :parentGuid),
+                    position = :position, syncStatus = :syncStatus,
+                    syncChangeCounter = :syncChangeCounter
+                WHERE guid = :guid", params)?;
+        } else {
+            self.db.execute_named_cached("
+                INSERT INTO moz_bookmarks(guid, type, fk, title, dateAdded, lastModified,
+                                          parent, position, syncStatus, syncChangeCounter)
+                VALUES(:guid, :type, :fk, :title, :dateAdded, :now,
+                       (SELECT id FROM moz_bookmarks WHERE guid = :parentGuid),
+                       :position, :syncStatus, :syncChangeCounter)", params)?;
+        }
+        Ok(())
+    }
+
+    fn fetch_outgoing(
+        &self,
+        merged: &MergedRoot,
+        local_tree: &Tree,
+        remote_tree: &Tree,
+        timestamp: ServerTimestamp,
+    ) -> Result<OutgoingChangeset> {
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), timestamp);
+        let mut counters = HashMap::new();
+        for (node, parent, _) in merged.descendants() {
+            if node.needs_upload(Some(&parent.guid)) {
+                let (payload, counter) = self.fetch_outgoing_record(node, parent)?;
+                counters.insert(payload.id.clone(), counter);
+                outgoing.changes.push(payload);
+            }
+        }
+        let tombstones = merged.delete_remotely.iter()
+            .chain(local_tree.deletions())
+            .filter(|guid| !remote_tree.is_deleted(*guid) && local_tree.node_for_guid(*guid).is_none());
+        let mut seen: HashSet<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        for guid in tombstones {
+            let id = record::sync_id_from_guid(guid);
+            if seen.insert(id.clone()) {
+                outgoing.changes.push(Payload::new_tombstone(id));
+            }
+        }
+
+        let mut pending = self.outgoing.borrow_mut();
+        pending.clear();
+        for payload in &outgoing.changes {
+            // Tombstones don't have a row, so there's no counter to reset.
+            let counter = counters.get(&payload.id).cloned().unwrap_or(0);
+            pending.insert(payload.id.clone(), (payload.clone(), counter));
+        }
+        Ok(outgoing)
+    }
+
+    /// Returns the record for `node`, and its current change counter.
+    fn fetch_outgoing_record(&self, node: &MergedNode, parent: &MergedNode) -> Result<(Payload, i64)> {
+        let (title, url, date_added, parent_title, counter) = self.db.query_row_and_then_named("
+            SELECT b.title, h.url, b.dateAdded, b.syncChangeCounter,
+                   (SELECT title FROM moz_bookmarks WHERE guid = :parentGuid) AS parentTitle
+            FROM moz_bookmarks b
+            LEFT JOIN moz_places h ON h.id = b.fk
+            WHERE b.guid = :guid",
+            &[(":guid", &node.guid), (":parentGuid", &parent.guid)],
+            |row| -> Result<(Option<String>, Option<String>, i64, Option<String>, i64)> {
+                Ok((row.get_checked("title")?, row.get_checked("url")?,
+                    row.get_checked("dateAdded")?, row.get_checked("parentTitle")?,
+                    row.get_checked("syncChangeCounter")?))
+            },
+            true)?;
+        let record = BookmarkRecord {
+            id: record::sync_id_from_guid(&node.guid),
+            kind: node.kind,
+            parent_id: Some(record::sync_id_from_guid(&parent.guid)),
+            parent_name: parent_title,
+            title: if node.kind == Kind::Separator { None } else { title },
+            url: if node.kind.has_url() { url } else { None },
+            children: if node.kind.is_folder() {
+                Some(node.merged_children.iter().map(|c| record::sync_id_from_guid(&c.guid)).collect())
+            } else {
+                None
+            },
+            date_added: if date_added > 0 { Some(date_added) } else { None },
+        };
+        Ok((Payload::from_record(record)?, counter))
+    }
+
+    fn mark_as_synchronized(&self, ids: &[String], new_timestamp: ServerTimestamp) -> Result<()> {
+        let tx = self.db.begin_transaction()?;
+        let mut pending = self.outgoing.borrow_mut();
+        for id in ids {
+            let (payload, counter) = match pending.remove(id) {
+                Some(record) => record,
+                None => continue,
+            };
+            let guid = record::guid_from_sync_id(id);
+            self.stage_payload(&payload, new_timestamp, false)?;
+            if payload.is_tombstone() {
+                self.db.execute_named_cached(
+                    "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+                    &[(":guid", &guid)])?;
+            } else {
+                // Only subtract the changes we uploaded, so that anything
+                // changed since we fetched the record is uploaded next time.
+                self.db.execute_named_cached("
+                    UPDATE moz_bookmarks SET
+                        syncChangeCounter = MAX(syncChangeCounter - :counter, 0),
+                        syncStatus = :syncStatus
+                    WHERE guid = :guid",
+                    &[(":counter", &counter), (":syncStatus", &SyncStatus::Normal), (":guid", &guid)])?;
+            }
+        }
+        self.put_meta(LAST_SYNC_META_KEY, &(new_timestamp.as_millis() as i64))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Forgets everything we know about the server, so that the next sync
+    /// merges everything as if it's the first.
+    pub fn reset(&self) -> Result<()> {
+        let tx = self.db.begin_transaction()?;
+        self.reset_sync_metadata()?;
+        tx.commit()?;
+        self.outgoing.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Deletes every bookmark except the roots, without recording tombstones,
+    /// and forgets everything we know about the server, so that the next
+    /// sync replaces our bookmarks with the server's.
+    pub fn wipe(&self) -> Result<()> {
+        let tx = self.db.begin_transaction()?;
+        self.reset_sync_metadata()?;
+        self.db.execute(&format!("
+            DELETE FROM moz_bookmarks
+            WHERE guid NOT IN ('{}', '{}', '{}', '{}', '{}')",
+            ROOT_GUID, MENU_GUID, TOOLBAR_GUID, UNFILED_GUID, MOBILE_GUID), &[])?;
+        tx.commit()?;
+        self.outgoing.borrow_mut().clear();
+        Ok(())
+    }
+
+    // Must be called in a transaction.
+    fn reset_sync_metadata(&self) -> Result<()> {
+        self.db.execute_all(&[
+            "DELETE FROM moz_bookmarks_synced_structure",
+            "DELETE FROM moz_bookmarks_synced",
+            "DELETE FROM moz_bookmarks_deleted",
+            &format!("UPDATE moz_bookmarks SET syncChangeCounter = 1, syncStatus = {}", SyncStatus::New as u8),
+        ])?;
+        self.db.execute_named_cached(
+            "DELETE FROM moz_meta WHERE key = :key",
+            &[(":key", &LAST_SYNC_META_KEY)])?;
+        Ok(())
+    }
+
+    fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
+        self.db.execute_named_cached(
+            "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
+            &[(":key", &key as &ToSql), (":value", value)])?;
+        Ok(())
+    }
+
+    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.db.try_query_row(
+            "SELECT value FROM moz_meta WHERE key = :key",
+            &[(":key", &key as &ToSql)],
+            |row: &Row| -> Result<T> { Ok(row.get_checked(0)?) },
+            true)?)
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(self.get_meta::<i64>(LAST_SYNC_META_KEY)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
+    }
+}
+
+impl<'a> Store for BookmarksStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self.do_apply_incoming(inbound, telem)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> result::Result<(), failure::Error> {
+        Ok(self.mark_as_synchronized(records_synced, new_timestamp)?)
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        let since = self.get_last_sync()?.unwrap_or_default();
+        Ok(CollectionRequest::new(COLLECTION_NAME).full().newer_than(since))
+    }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        Ok(BookmarksStore::reset(self)?)
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        Ok(BookmarksStore::wipe(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger;
+    use serde_json;
+
+    fn payload(json: &str) -> (Payload, ServerTimestamp) {
+        (Payload::from_json(serde_json::from_str(json).unwrap()).unwrap(), ServerTimestamp(1000.0))
+    }
+
+    fn local_children(db: &PlacesDb, parent: &str) -> Vec<String> {
+        let mut stmt = db.prepare("
+            SELECT b.guid FROM moz_bookmarks b
+            JOIN moz_bookmarks p ON p.id = b.parent
+            WHERE p.guid = :parent
+            ORDER BY b.position").unwrap();
+        let rows = stmt.query_and_then_named(&[(":parent", &parent)], |row| -> Result<String> {
+            Ok(row.get_checked(0)?)
+        }).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_first_sync() {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let store = BookmarksStore::new(&db);
+
+        let mut incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(1000.0));
+        incoming.changes.push(payload(r#"{
+            "id": "menu", "type": "folder", "parentid": "places", "title": "menu",
+            "children": ["folderAAAAAA"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "unfiled", "type": "folder", "parentid": "places", "title": "unfiled",
+            "children": []
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "folderAAAAAA", "type": "folder", "parentid": "menu", "title": "A",
+            "children": ["bookmarkBBBB"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkBBBB", "type": "bookmark", "parentid": "folderAAAAAA",
+            "title": "B", "bmkUri": "https://example.com/b"
+        }"#));
+        // An orphan, whose parent we haven't seen.
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkCCCC", "type": "bookmark", "parentid": "folderDDDDDD",
+            "title": "C", "bmkUri": "https://example.com/c"
+        }"#));
+        // A record we don't understand, which we should skip.
+        incoming.changes.push(payload(r#"{
+            "id": "unknownEEEEE", "type": "microsummary", "parentid": "menu", "title": "E"
+        }"#));
+
+        let mut telem = telemetry::EngineIncoming::default();
+        let outgoing = store.apply_incoming(incoming, &mut telem).expect("should apply");
+        assert_eq!(telem.failed, 1);
+        assert_eq!(local_children(&db, "menu________"), vec!["folderAAAAAA"]);
+        assert_eq!(local_children(&db, "folderAAAAAA"), vec!["bookmarkBBBB"]);
+        assert_eq!(local_children(&db, "unfiled_____"), vec!["bookmarkCCCC"]);
+
+        let foreign_count: i64 = db.query_one(
+            "SELECT foreign_count FROM moz_places WHERE url = 'https://example.com/b'").unwrap();
+        assert_eq!(foreign_count, 1);
+
+        // We need to upload the roots the server doesn't have, and unfiled,
+        // since we moved the orphan into it.
+        let mut ids: Vec<&str> = outgoing.changes.iter().map(|p| p.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["mobile", "toolbar", "unfiled"]);
+
+        let synced: Vec<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        store.sync_finished(ServerTimestamp(2000.0), &synced).expect("should finish");
+        let counter: i64 = db.query_one(
+            "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = 'unfiled_____'").unwrap();
+        assert_eq!(counter, 0);
+        assert_eq!(store.get_last_sync().unwrap(), Some(ServerTimestamp(2000.0)));
+
+        // Nothing changed, so the next sync shouldn't upload anything.
+        let incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(2000.0));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty());
+
+        // A remote deletion of the bookmark deletes it locally.
+        let mut incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(3000.0));
+        incoming.changes.push(payload(r#"{"id": "bookmarkBBBB", "deleted": true}"#));
+        incoming.changes.push(payload(r#"{
+            "id": "folderAAAAAA", "type": "folder", "parentid": "menu", "title": "A",
+            "children": []
+        }"#));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+        assert!(local_children(&db, "folderAAAAAA").is_empty());
+        assert!(outgoing.changes.is_empty());
+    }
+
+    #[test]
+    fn test_changes_during_sync() {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let store = BookmarksStore::new(&db);
+
+        let incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(1000.0));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+        let mut ids: Vec<&str> = outgoing.changes.iter().map(|p| p.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["menu", "mobile", "toolbar", "unfiled"]);
+
+        // The menu changes while we're uploading...
+        db.execute_batch("
+            UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+            WHERE guid = 'menu________'").unwrap();
+        let synced: Vec<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        store.sync_finished(ServerTimestamp(2000.0), &synced).expect("should finish");
+
+        // ...so it still needs uploading, but the other roots don't.
+        let counter: i64 = db.query_one(
+            "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = 'menu________'").unwrap();
+        assert_eq!(counter, 1);
+        let incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(2000.0));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+        let ids: Vec<&str> = outgoing.changes.iter().map(|p| p.id()).collect();
+        assert_eq!(ids, vec!["menu"]);
+    }
+
+    #[test]
+    fn test_conflicting_changes() {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let store = BookmarksStore::new(&db);
+
+        let mut incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(1000.0));
+        incoming.changes.push(payload(r#"{
+            "id": "menu", "type": "folder", "parentid": "places", "title": "menu",
+            "children": ["folderAAAAAA"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "unfiled", "type": "folder", "parentid": "places", "title": "unfiled",
+            "children": []
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "folderAAAAAA", "type": "folder", "parentid": "menu", "title": "A",
+            "children": ["bookmarkBBBB", "bookmarkCCCC"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkBBBB", "type": "bookmark", "parentid": "folderAAAAAA",
+            "title": "B", "bmkUri": "https://example.com/b"
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkCCCC", "type": "bookmark", "parentid": "folderAAAAAA",
+            "title": "C", "bmkUri": "https://example.com/c"
+        }"#));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+        let synced: Vec<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        store.sync_finished(ServerTimestamp(2000.0), &synced).expect("should finish");
+
+        // Locally, C moves from A to the toolbar...
+        db.execute_batch("
+            UPDATE moz_bookmarks SET
+                parent = (SELECT id FROM moz_bookmarks WHERE guid = 'toolbar_____'),
+                position = 0
+            WHERE guid = 'bookmarkCCCC';
+            UPDATE moz_bookmarks SET syncChangeCounter = 1, lastModified = 1
+            WHERE guid IN ('bookmarkCCCC', 'folderAAAAAA', 'toolbar_____')").unwrap();
+
+        // ...while, more recently, another device moves it to unfiled,
+        // renames A, and adds D to it.
+        let mut incoming = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(3000.0));
+        incoming.changes.push(payload(r#"{
+            "id": "unfiled", "type": "folder", "parentid": "places", "title": "unfiled",
+            "children": ["bookmarkCCCC"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "folderAAAAAA", "type": "folder", "parentid": "menu", "title": "A2",
+            "children": ["bookmarkBBBB", "bookmarkDDDD"]
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkCCCC", "type": "bookmark", "parentid": "unfiled",
+            "title": "C", "bmkUri": "https://example.com/c"
+        }"#));
+        incoming.changes.push(payload(r#"{
+            "id": "bookmarkDDDD", "type": "bookmark", "parentid": "folderAAAAAA",
+            "title": "D", "bmkUri": "https://example.com/d"
+        }"#));
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::default())
+            .expect("should apply");
+
+        // The newer move wins, and A keeps the remote title and children.
+        assert_eq!(local_children(&db, "unfiled_____"), vec!["bookmarkCCCC"]);
+        assert_eq!(local_children(&db, "folderAAAAAA"), vec!["bookmarkBBBB", "bookmarkDDDD"]);
+        assert!(local_children(&db, "toolbar_____").is_empty());
+        let title: String = db.query_one(
+            "SELECT title FROM moz_bookmarks WHERE guid = 'folderAAAAAA'").unwrap();
+        assert_eq!(title, "A2");
+
+        // The toolbar changed locally, so we upload it again, without C.
+        // Everything else already matches the server.
+        let ids: Vec<&str> = outgoing.changes.iter().map(|p| p.id()).collect();
+        assert_eq!(ids, vec!["toolbar"]);
+    }
+}
@@ -0,0 +1,437 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use error::*;
+use types_support::Guid;
+
+/// The kind of a bookmark item. The discriminants are what we store in the
+/// `kind` column of the mirror, and the serialized names are the `type` of a
+/// bookmark record on the server.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Bookmark = 1,
+    Query = 2,
+    Folder = 3,
+    Livemark = 4,
+    Separator = 5,
+}
+
+impl Kind {
+    pub fn from_u8(v: u8) -> Option<Kind> {
+        match v {
+            1 => Some(Kind::Bookmark),
+            2 => Some(Kind::Query),
+            3 => Some(Kind::Folder),
+            4 => Some(Kind::Livemark),
+            5 => Some(Kind::Separator),
+            _ => None,
+        }
+    }
+
+    /// Only folders have children. Livemarks are folders locally, but their
+    /// children aren't synced, so we treat them as leaves.
+    #[inline]
+    pub fn is_folder(self) -> bool {
+        self == Kind::Folder
+    }
+
+    #[inline]
+    pub fn has_url(self) -> bool {
+        self == Kind::Bookmark || self == Kind::Query
+    }
+}
+
+/// An item in a bookmark tree, with just enough information to merge its
+/// structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub guid: Guid,
+    pub kind: Kind,
+    /// How long ago the item was last changed, in milliseconds, relative to
+    /// the start of the merge. When both sides change an item, the one with
+    /// the smaller age wins.
+    pub age: i64,
+    /// Whether the item changed since we last synced.
+    pub needs_merge: bool,
+}
+
+impl Item {
+    pub fn new(guid: Guid, kind: Kind) -> Item {
+        Item { guid, kind, age: 0, needs_merge: false }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    item: Item,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// A complete bookmark tree (local or remote), along with the GUIDs of items
+/// deleted from it since the last sync.
+#[derive(Debug)]
+pub struct Tree {
+    entries: Vec<Entry>,
+    index_by_guid: HashMap<Guid, usize>,
+    deleted: HashSet<Guid>,
+}
+
+impl Tree {
+    pub fn with_root(root: Item) -> Tree {
+        let mut index_by_guid = HashMap::new();
+        index_by_guid.insert(root.guid.clone(), 0);
+        Tree {
+            entries: vec![Entry { item: root, parent: None, children: Vec::new() }],
+            index_by_guid,
+            deleted: HashSet::new(),
+        }
+    }
+
+    /// Add `item` as the last child of the folder `parent_guid`, which must
+    /// already be in the tree.
+    pub fn insert(&mut self, parent_guid: &Guid, item: Item) -> Result<()> {
+        if self.index_by_guid.contains_key(&item.guid) {
+            return Err(ErrorKind::InvalidBookmarkTree(
+                format!("Item {} appears more than once", item.guid)).into());
+        }
+        let parent = match self.index_by_guid.get(parent_guid) {
+            Some(&index) => index,
+            None => return Err(ErrorKind::InvalidBookmarkTree(
+                format!("Missing parent {} for {}", parent_guid, item.guid)).into()),
+        };
+        if !self.entries[parent].item.kind.is_folder() {
+            return Err(ErrorKind::InvalidBookmarkTree(
+                format!("Parent {} of {} isn't a folder", parent_guid, item.guid)).into());
+        }
+        let index = self.entries.len();
+        self.index_by_guid.insert(item.guid.clone(), index);
+        self.entries.push(Entry { item, parent: Some(parent), children: Vec::new() });
+        self.entries[parent].children.push(index);
+        Ok(())
+    }
+
+    /// Record that `guid` was deleted.
+    pub fn note_deleted(&mut self, guid: Guid) {
+        self.deleted.insert(guid);
+    }
+
+    #[inline]
+    pub fn root(&self) -> Node {
+        Node { tree: self, index: 0 }
+    }
+
+    pub fn node_for_guid(&self, guid: &Guid) -> Option<Node> {
+        self.index_by_guid.get(guid).map(|&index| Node { tree: self, index })
+    }
+
+    /// Returns true if `guid` was deleted. An item which was deleted and then
+    /// recreated with the same GUID isn't considered deleted.
+    pub fn is_deleted(&self, guid: &Guid) -> bool {
+        self.deleted.contains(guid) && !self.index_by_guid.contains_key(guid)
+    }
+
+    pub fn deletions<'t>(&'t self) -> impl Iterator<Item = &'t Guid> + 't {
+        self.deleted.iter().filter(move |guid| !self.index_by_guid.contains_key(*guid))
+    }
+
+    pub fn guids<'t>(&'t self) -> impl Iterator<Item = &'t Guid> + 't {
+        self.entries.iter().map(|e| &e.item.guid)
+    }
+}
+
+/// A reference to an item in a `Tree`, which can be used to walk the tree.
+#[derive(Clone, Copy)]
+pub struct Node<'t> {
+    tree: &'t Tree,
+    index: usize,
+}
+
+impl<'t> Node<'t> {
+    #[inline]
+    pub fn item(&self) -> &'t Item {
+        &self.tree.entries[self.index].item
+    }
+
+    #[inline]
+    pub fn guid(&self) -> &'t Guid {
+        &self.item().guid
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'t>> + 't {
+        let tree: &'t Tree = self.tree;
+        tree.entries[self.index].children.iter().map(move |&index| Node { tree, index })
+    }
+
+    pub fn child_guids(&self) -> Vec<Guid> {
+        self.children().map(|child| child.guid().clone()).collect()
+    }
+
+    pub fn parent(&self) -> Option<Node<'t>> {
+        let tree: &'t Tree = self.tree;
+        tree.entries[self.index].parent.map(|index| Node { tree, index })
+    }
+
+    #[inline]
+    pub fn is_root(&self) -> bool {
+        self.index == 0
+    }
+
+    /// The number of ancestors the node has. The root's level is 0.
+    pub fn level(&self) -> usize {
+        let mut level = 0;
+        let mut parent = self.parent();
+        while let Some(p) = parent {
+            level += 1;
+            parent = p.parent();
+        }
+        level
+    }
+}
+
+impl<'t> fmt::Debug for Node<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node({}, {:?})", self.guid(), self.item().kind)
+    }
+}
+
+/// Builds a `Tree` from items and parent-child relationships given in any
+/// order, as we get them from the database or the server.
+///
+/// Unlike `Tree::insert`, this doesn't fail on inconsistent structure. Items
+/// whose parent is missing or isn't a folder, and items in parent-child
+/// cycles, are "orphans", and are moved into the orphan folder (usually
+/// unfiled) so that they're still merged.
+pub struct TreeBuilder {
+    root: Item,
+    orphans_to: Option<Guid>,
+    items: Vec<Item>,
+    parents: HashMap<Guid, (Guid, i64)>,
+    deleted: Vec<Guid>,
+}
+
+impl TreeBuilder {
+    pub fn new(root: Item) -> TreeBuilder {
+        TreeBuilder {
+            root,
+            orphans_to: None,
+            items: Vec::new(),
+            parents: HashMap::new(),
+            deleted: Vec::new(),
+        }
+    }
+
+    /// Move orphans to the folder `guid`. If this isn't set, or the folder
+    /// doesn't exist, orphans go in the root.
+    pub fn orphans_to(mut self, guid: Guid) -> TreeBuilder {
+        self.orphans_to = Some(guid);
+        self
+    }
+
+    pub fn item(&mut self, item: Item) -> &mut TreeBuilder {
+        self.items.push(item);
+        self
+    }
+
+    /// Record that `child` is at `position` in `parent`. If there are
+    /// several parents for the same child, the first one wins.
+    pub fn parent_of(&mut self, child: Guid, parent: Guid, position: i64) -> &mut TreeBuilder {
+        self.parents.entry(child).or_insert((parent, position));
+        self
+    }
+
+    pub fn deleted(&mut self, guid: Guid) -> &mut TreeBuilder {
+        self.deleted.push(guid);
+        self
+    }
+
+    pub fn build(self) -> Result<Tree> {
+        let TreeBuilder { root, orphans_to, items, mut parents, deleted } = self;
+
+        let mut kinds: HashMap<Guid, Kind> = HashMap::with_capacity(items.len() + 1);
+        kinds.insert(root.guid.clone(), root.kind);
+        let mut items_by_guid: HashMap<Guid, Item> = HashMap::with_capacity(items.len());
+        for item in items {
+            if item.guid == root.guid || kinds.contains_key(&item.guid) {
+                warn!("Ignoring duplicate bookmark item {}", item.guid);
+                continue;
+            }
+            kinds.insert(item.guid.clone(), item.kind);
+            items_by_guid.insert(item.guid.clone(), item);
+        }
+
+        let orphan_parent = match orphans_to {
+            Some(ref guid) if kinds.get(guid).map_or(false, |k| k.is_folder()) => guid.clone(),
+            _ => root.guid.clone(),
+        };
+
+        // Sort items into their parents, noting the ones without a valid
+        // parent. We sort guids so that the result doesn't depend on hash
+        // order.
+        let mut guids: Vec<Guid> = items_by_guid.keys().cloned().collect();
+        guids.sort();
+        let mut children: HashMap<Guid, Vec<(i64, Guid)>> = HashMap::new();
+        let mut orphans = Vec::new();
+        for guid in &guids {
+            let valid_parent = match parents.get(guid) {
+                Some(&(ref parent, _)) => parent != guid && kinds.get(parent).map_or(false, |k| k.is_folder()),
+                None => false,
+            };
+            if valid_parent {
+                let (parent, position) = parents[guid].clone();
+                children.entry(parent).or_insert_with(Vec::new).push((position, guid.clone()));
+            } else {
+                orphans.push(guid.clone());
+            }
+        }
+
+        // Anything we can't reach from the root is either in an orphaned
+        // subtree, or in a cycle. Reparent the tops of orphaned subtrees
+        // first, then break any cycles that are left.
+        let mut reached = HashSet::new();
+        mark_reached(&root.guid, &children, &mut reached);
+        for guid in orphans {
+            if reached.contains(&guid) {
+                continue;
+            }
+            warn!("Moving orphaned bookmark {} to {}", guid, orphan_parent);
+            reparent(&guid, &orphan_parent, &mut parents, &mut children);
+            mark_reached(&guid, &children, &mut reached);
+        }
+        for guid in &guids {
+            if reached.contains(guid) {
+                continue;
+            }
+            // Walk up until we find the item whose parent closes the cycle,
+            // and move that one.
+            let mut path = HashSet::new();
+            let mut current = guid.clone();
+            loop {
+                path.insert(current.clone());
+                let parent = parents[&current].0.clone();
+                if path.contains(&parent) {
+                    break;
+                }
+                current = parent;
+            }
+            warn!("Moving bookmark {} out of a cycle to {}", current, orphan_parent);
+            reparent(&current, &orphan_parent, &mut parents, &mut children);
+            mark_reached(&current, &children, &mut reached);
+        }
+
+        // Everything is reachable now, so build the tree breadth first.
+        let mut tree = Tree::with_root(root.clone());
+        let mut queue = VecDeque::new();
+        queue.push_back(root.guid);
+        while let Some(parent) = queue.pop_front() {
+            let mut kids = match children.remove(&parent) {
+                Some(kids) => kids,
+                None => continue,
+            };
+            kids.sort();
+            for (_, guid) in kids {
+                let item = items_by_guid.remove(&guid).expect("Each item is only inserted once");
+                tree.insert(&parent, item)?;
+                queue.push_back(guid);
+            }
+        }
+        for guid in deleted {
+            tree.note_deleted(guid);
+        }
+        Ok(tree)
+    }
+}
+
+fn mark_reached(guid: &Guid, children: &HashMap<Guid, Vec<(i64, Guid)>>, reached: &mut HashSet<Guid>) {
+    let mut stack = vec![guid.clone()];
+    while let Some(guid) = stack.pop() {
+        if !reached.insert(guid.clone()) {
+            continue;
+        }
+        if let Some(kids) = children.get(&guid) {
+            stack.extend(kids.iter().map(|&(_, ref child)| child.clone()));
+        }
+    }
+}
+
+fn reparent(
+    guid: &Guid,
+    new_parent: &Guid,
+    parents: &mut HashMap<Guid, (Guid, i64)>,
+    children: &mut HashMap<Guid, Vec<(i64, Guid)>>,
+) {
+    if let Some((old_parent, _)) = parents.remove(guid) {
+        if let Some(kids) = children.get_mut(&old_parent) {
+            kids.retain(|&(_, ref child)| child != guid);
+        }
+    }
+    // Orphans go after the existing children, in the order we find them.
+    let kids = children.entry(new_parent.clone()).or_insert_with(Vec::new);
+    let position = kids.iter().map(|&(position, _)| position).max().map_or(0, |p| p.saturating_add(1));
+    kids.push((position, guid.clone()));
+    parents.insert(guid.clone(), (new_parent.clone(), position));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guid(s: &str) -> Guid {
+        Guid::new(s)
+    }
+
+    #[test]
+    fn test_builder_orphans_and_cycles() {
+        let mut builder = TreeBuilder::new(Item::new(guid("root"), Kind::Folder))
+            .orphans_to(guid("unfiled"));
+        builder
+            .item(Item::new(guid("menu"), Kind::Folder))
+            .item(Item::new(guid("unfiled"), Kind::Folder))
+            .item(Item::new(guid("bmkA"), Kind::Bookmark))
+            .item(Item::new(guid("bmkB"), Kind::Bookmark))
+            .item(Item::new(guid("bmkC"), Kind::Bookmark))
+            .item(Item::new(guid("folderD"), Kind::Folder))
+            .item(Item::new(guid("folderE"), Kind::Folder))
+            .item(Item::new(guid("bmkF"), Kind::Bookmark))
+            .parent_of(guid("menu"), guid("root"), 0)
+            .parent_of(guid("unfiled"), guid("root"), 1)
+            .parent_of(guid("bmkB"), guid("menu"), 1)
+            .parent_of(guid("bmkA"), guid("menu"), 0)
+            // Missing parent.
+            .parent_of(guid("bmkC"), guid("nonexistent"), 0)
+            // D and E are in a cycle, and F is in E.
+            .parent_of(guid("folderD"), guid("folderE"), 0)
+            .parent_of(guid("folderE"), guid("folderD"), 0)
+            .parent_of(guid("bmkF"), guid("folderE"), 1)
+            .deleted(guid("bmkG"));
+        let tree = builder.build().unwrap();
+
+        assert_eq!(tree.root().child_guids(), vec![guid("menu"), guid("unfiled")]);
+        let menu = tree.node_for_guid(&guid("menu")).unwrap();
+        assert_eq!(menu.child_guids(), vec![guid("bmkA"), guid("bmkB")]);
+        let unfiled = tree.node_for_guid(&guid("unfiled")).unwrap();
+        assert_eq!(unfiled.child_guids(), vec![guid("bmkC"), guid("folderD")]);
+        let d = tree.node_for_guid(&guid("folderD")).unwrap();
+        assert_eq!(d.child_guids(), vec![guid("folderE")]);
+        let f = tree.node_for_guid(&guid("bmkF")).unwrap();
+        assert_eq!(f.parent().unwrap().guid(), &guid("folderE"));
+        assert_eq!(f.level(), 4);
+        assert!(tree.is_deleted(&guid("bmkG")));
+        assert!(!tree.is_deleted(&guid("bmkA")));
+    }
+
+    #[test]
+    fn test_insert_errors() {
+        let mut tree = Tree::with_root(Item::new(guid("root"), Kind::Folder));
+        tree.insert(&guid("root"), Item::new(guid("bmkA"), Kind::Bookmark)).unwrap();
+        assert!(tree.insert(&guid("root"), Item::new(guid("bmkA"), Kind::Bookmark)).is_err());
+        assert!(tree.insert(&guid("bmkA"), Item::new(guid("bmkB"), Kind::Bookmark)).is_err());
+        assert!(tree.insert(&guid("nope"), Item::new(guid("bmkB"), Kind::Bookmark)).is_err());
+    }
+}
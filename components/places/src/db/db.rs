@@ -7,26 +7,171 @@
 // We should work out how to split this into a library we can reuse.
 
 use super::schema;
+use super::tx::PlacesTransaction;
+use url_policy::UrlPolicy;
+use frecency::FrecencySettings;
 use error::*;
 use hash;
-use rusqlite::{self, Connection};
+use types::Timestamp;
+use rusqlite::{self, Connection, OpenFlags};
 use sql_support::{self, ConnExt};
 use std::path::Path;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 
 use api::matcher::{split_after_prefix, split_after_host_and_port};
 use match_impl::{AutocompleteMatch, MatchBehavior, SearchBehavior};
 
 pub const MAX_VARIABLE_NUMBER: usize = 999;
 
+/// What a connection is going to be used for. Only `ReadWrite` and `Sync`
+/// connections may write to the database, and there should only be one of
+/// each open at a time (see `PlacesApi`).
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionType {
+    ReadOnly = 1,
+    ReadWrite = 2,
+    Sync = 3,
+}
+
+impl ConnectionType {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            1 => Some(ConnectionType::ReadOnly),
+            2 => Some(ConnectionType::ReadWrite),
+            3 => Some(ConnectionType::Sync),
+            _ => None,
+        }
+    }
+
+    /// The flags `PlacesDb::open_with_type` opens connections of this type
+    /// with, for callers of `open_with_config` which want to add to them.
+    pub fn open_flags(self) -> OpenFlags {
+        let access = match self {
+            ConnectionType::ReadOnly => OpenFlags::SQLITE_OPEN_READ_ONLY,
+            ConnectionType::ReadWrite | ConnectionType::Sync =>
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        };
+        // Each connection is only used by one thread at a time (the FFI
+        // wraps them in a mutex), so we don't need SQLite's locking too.
+        access | OpenFlags::SQLITE_OPEN_NO_MUTEX
+    }
+}
+
+/// How SQLite journals writes. See https://www.sqlite.org/pragma.html#pragma_journal_mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Memory,
+    /// Lets readers and a writer use the database at the same time, which
+    /// we need for autocomplete to keep working while we're syncing.
+    Wal,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+        }
+    }
+}
+
+/// How often SQLite waits for writes to reach the disk. See
+/// https://www.sqlite.org/pragma.html#pragma_synchronous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    /// In WAL mode, a crash can lose the last few transactions, but can't
+    /// corrupt the database.
+    Normal,
+    Full,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+        }
+    }
+}
+
+/// The pragmas set on each connection when it's opened. We always set all
+/// of them, rather than relying on SQLite's defaults (which depend on how
+/// it was compiled), and the defaults here are tuned for mobile devices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PragmaConfig {
+    /// The page size for new databases, in bytes. Defaults to 32KiB, which
+    /// was taken from Desktop Firefox, and helps autocomplete-style queries.
+    /// SQLite's default of 1KiB is too small, according to the SQLCipher docs.
+    pub page_size: u32,
+    /// The size of the page cache, in KiB. Defaults to 6MiB, the same as
+    /// `promiseLargeCacheDBConnection` in Desktop's PlacesUtils.
+    pub cache_size_kib: u32,
+    /// Only set by connections which can write, since it's stored in the
+    /// database. Defaults to WAL.
+    pub journal_mode: JournalMode,
+    /// Defaults to `Normal`.
+    pub synchronous: Synchronous,
+    /// Whether SQLite enforces `REFERENCES` constraints. Defaults to false,
+    /// since the schema relies on triggers to clean up instead. The schema's
+    /// `ON DELETE` actions agree with the triggers, so deleting history works
+    /// either way.
+    pub foreign_keys: bool,
+    /// Keep temporary tables and indices in memory. Defaults to true, which
+    /// is required on Android, since there's no tmp partition (see
+    /// https://github.com/mozilla/mentat/issues/505).
+    pub temp_store_memory: bool,
+}
+
+impl Default for PragmaConfig {
+    fn default() -> Self {
+        PragmaConfig {
+            page_size: 32768,
+            cache_size_kib: 6144,
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            foreign_keys: false,
+            temp_store_memory: true,
+        }
+    }
+}
+
+/// A handle which can be used to interrupt whatever query is running on a
+/// connection, from any thread. Interrupted queries fail with
+/// `SQLITE_INTERRUPT`, and the connection can be used again afterwards.
+pub struct SqlInterruptHandle {
+    handle: rusqlite::InterruptHandle,
+}
+
+impl SqlInterruptHandle {
+    pub fn interrupt(&self) {
+        self.handle.interrupt();
+    }
+}
+
 pub struct PlacesDb {
     pub db: Connection,
+    url_policy: UrlPolicy,
+    frecency_settings: FrecencySettings,
+    conn_type: ConnectionType,
+    interrupt_handle: Arc<SqlInterruptHandle>,
 }
 
 impl PlacesDb {
-    pub fn with_connection(db: Connection, encryption_key: Option<&str>) -> Result<Self> {
-        const PAGE_SIZE: u32 = 32768;
-
+    pub fn with_connection(
+        db: Connection,
+        encryption_key: Option<&str>,
+        conn_type: ConnectionType,
+        config: &PragmaConfig,
+    ) -> Result<Self> {
         // `encryption_pragmas` is both for `PRAGMA key` and for `PRAGMA page_size` / `PRAGMA
         // cipher_page_size` (Even though nominally page_size has nothing to do with encryption, we
         // need to set `PRAGMA cipher_page_size` for encrypted databases, and `PRAGMA page_size` for
@@ -36,54 +181,185 @@ impl PlacesDb {
         // requiring a data migration, so this must be done somewhat carefully. This restriction
         // *only* exists for encrypted DBs, and unencrypted ones (even unencrypted databases using
         // sqlcipher), don't have this limitation.
-        //
-        // The value we use (`PAGE_SIZE`) was taken from Desktop Firefox, and seems necessary to
-        // help ensure good performance on autocomplete-style queries. The default value is 1024,
-        // which the SQLcipher docs themselves say is too small and should be changed.
         let encryption_pragmas = if let Some(key) = encryption_key {
             format!("
                 PRAGMA key = '{key}';
                 PRAGMA cipher_page_size = {page_size};
             ",
                 key = sql_support::escape_string_for_pragma(key),
-                page_size = PAGE_SIZE,
+                page_size = config.page_size,
             )
         } else {
-            format!("PRAGMA page_size = {};", PAGE_SIZE)
+            format!("PRAGMA page_size = {};", config.page_size)
         };
 
+        // Note that SQLite uses a negative `cache_size` to indicate that it's
+        // in units of KiB.
         let initial_pragmas = format!("
             {}
-
-            -- `temp_store = 2` is required on Android to force the DB to keep temp
-            -- files in memory, since on Android there's no tmp partition. See
-            -- https://github.com/mozilla/mentat/issues/505. Ideally we'd only
-            -- do this on Android, and/or allow caller to configure it.
-            PRAGMA temp_store = 2;
-
-            -- 6MiB, same as the value used for `promiseLargeCacheDBConnection` in PlacesUtils,
-            -- which is used to improve query performance for autocomplete-style queries (by
-            -- UnifiedComplete). Note that SQLite uses a negative value for this pragma to indicate
-            -- that it's in units of KiB.
-            PRAGMA cache_size = -6144;
+            PRAGMA temp_store = {};
+            PRAGMA cache_size = -{};
+            PRAGMA synchronous = {};
+            PRAGMA foreign_keys = OFF;
         ",
             encryption_pragmas,
+            if config.temp_store_memory { "MEMORY" } else { "DEFAULT" },
+            config.cache_size_kib,
+            config.synchronous.as_str(),
         );
 
         db.execute_batch(&initial_pragmas)?;
+        // SQLCipher doesn't check the key until we read something, so do that
+        // now, to fail with `NotADatabase` if it's wrong before we've set
+        // anything else up.
+        db.query_row("SELECT count(*) FROM sqlite_master", &[], |_| ())?;
+        // The journal mode is stored in the database, so read-only
+        // connections use whatever the read-write one set.
+        if conn_type != ConnectionType::ReadOnly {
+            db.execute_batch(&format!("PRAGMA journal_mode = {};", config.journal_mode.as_str()))?;
+        }
         define_functions(&db)?;
-        let mut res = Self { db };
-        schema::init(&mut res)?;
+        let interrupt_handle = Arc::new(SqlInterruptHandle {
+            handle: db.get_interrupt_handle(),
+        });
+        let res = Self {
+            db,
+            url_policy: UrlPolicy::default(),
+            frecency_settings: FrecencySettings::default(),
+            conn_type,
+            interrupt_handle,
+        };
+        // Read-only connections can't create or upgrade the schema, so
+        // `PlacesApi` makes sure a read-write connection has done it first.
+        if conn_type != ConnectionType::ReadOnly {
+            schema::init(&res)?;
+        }
+        // Only enabled once the schema is up to date, since upgrading it can
+        // drop and recreate tables, which would cascade with them enabled.
+        if config.foreign_keys {
+            res.db.execute_batch("PRAGMA foreign_keys = ON")?;
+        }
 
         Ok(res)
     }
 
     pub fn open(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
-        Ok(Self::with_connection(Connection::open(path)?, encryption_key)?)
+        Self::open_with_type(path, encryption_key, ConnectionType::ReadWrite)
+    }
+
+    pub fn open_with_type(
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        conn_type: ConnectionType,
+    ) -> Result<Self> {
+        Self::open_with_config(
+            path,
+            encryption_key,
+            conn_type,
+            conn_type.open_flags(),
+            &PragmaConfig::default(),
+        )
+    }
+
+    /// Opens a connection with the given SQLite `flags` (usually based on
+    /// `conn_type.open_flags()`) and pragmas, instead of the defaults.
+    pub fn open_with_config(
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        conn_type: ConnectionType,
+        flags: OpenFlags,
+        config: &PragmaConfig,
+    ) -> Result<Self> {
+        let db = Connection::open_with_flags(path, flags)?;
+        Ok(Self::with_connection(db, encryption_key, conn_type, config)?)
     }
 
     pub fn open_in_memory(encryption_key: Option<&str>) -> Result<Self> {
-        Ok(Self::with_connection(Connection::open_in_memory()?, encryption_key)?)
+        Ok(Self::with_connection(
+            Connection::open_in_memory()?,
+            encryption_key,
+            ConnectionType::ReadWrite,
+            &PragmaConfig::default(),
+        )?)
+    }
+
+    /// Re-encrypts the database with `new_key`. The database must already be
+    /// encrypted: SQLCipher can't encrypt or decrypt a database in place.
+    /// Other connections to the database need to be reopened with the new key
+    /// afterwards.
+    pub fn rekey(&self, new_key: &str) -> Result<()> {
+        if self.conn_type == ConnectionType::ReadOnly {
+            return Err(ErrorKind::CannotRekey("The connection is read-only".into()).into());
+        }
+        self.db.execute_batch(&format!(
+            "PRAGMA rekey = '{}';",
+            sql_support::escape_string_for_pragma(new_key),
+        ))?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file, to reclaim the space left by deleted
+    /// pages and defragment it. This rewrites the whole database, so it's
+    /// slow, and should only be done during idle maintenance.
+    pub fn vacuum(&self) -> Result<()> {
+        self.db.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Copies the changes in the write-ahead log into the database, and
+    /// truncates the log, which otherwise only happens automatically once it
+    /// reaches 1000 pages. Does nothing if the database isn't in WAL mode.
+    /// If other connections are reading, some of the log may be left behind.
+    pub fn checkpoint(&self) -> Result<()> {
+        let busy: bool = self.db.query_row(
+            "PRAGMA wal_checkpoint(TRUNCATE)", &[], |row| row.get(0))?;
+        if busy {
+            warn!("Couldn't finish checkpointing; the database is in use");
+        }
+        Ok(())
+    }
+
+    pub fn conn_type(&self) -> ConnectionType {
+        self.conn_type
+    }
+
+    /// Returns a handle which can interrupt queries on this connection from
+    /// another thread. See `SqlInterruptHandle`.
+    pub fn new_interrupt_handle(&self) -> Arc<SqlInterruptHandle> {
+        Arc::clone(&self.interrupt_handle)
+    }
+
+    /// The policy deciding which URLs we store. See `UrlPolicy`.
+    pub fn url_policy(&self) -> &UrlPolicy {
+        &self.url_policy
+    }
+
+    pub fn set_url_policy(&mut self, policy: UrlPolicy) {
+        self.url_policy = policy;
+    }
+
+    /// The settings used to calculate frecency, and to decide which page a
+    /// typed visit counts towards. See `FrecencySettings`.
+    pub fn frecency_settings(&self) -> &FrecencySettings {
+        &self.frecency_settings
+    }
+
+    pub fn set_frecency_settings(&mut self, settings: FrecencySettings) {
+        self.frecency_settings = settings;
+    }
+
+    /// Begin a transaction. It's rolled back if dropped without being
+    /// committed, and nested scopes can be created with `savepoint()`.
+    pub fn begin_transaction(&self) -> Result<PlacesTransaction> {
+        PlacesTransaction::new(&self.db, None)
+    }
+
+    /// Begin a transaction in "chunked" mode, where calls to `maybe_commit()`
+    /// will commit and start a new transaction once `commit_every` has
+    /// elapsed. This is intended for long-running operations which can safely
+    /// be committed part way through.
+    pub fn begin_chunked_transaction(&self, commit_every: Duration) -> Result<PlacesTransaction> {
+        PlacesTransaction::new(&self.db, Some(commit_every))
     }
 }
 
@@ -91,9 +367,15 @@ impl Drop for PlacesDb {
     fn drop(&mut self) {
         // In line with both the recommendations from SQLite and the behavior of places in
         // Database.cpp, we run `PRAGMA optimize` before closing the connection.
-        self.db
-            .execute_batch("PRAGMA optimize(0x02);")
-            .expect("PRAGMA optimize should always succeed!");
+        // It may need to run `ANALYZE`, which read-only connections can't do.
+        // It can also fail if the database is corrupt, which `PlacesApi`
+        // recovers from, so we mustn't panic.
+        if self.conn_type == ConnectionType::ReadOnly {
+            return;
+        }
+        if let Err(e) = self.db.execute_batch("PRAGMA optimize(0x02);") {
+            warn!("Failed to optimize the database before closing it: {}", e);
+        }
     }
 }
 
@@ -169,6 +451,10 @@ fn define_functions(c: &Connection) -> Result<()> {
         };
         Ok(matcher.invoke())
     })?;
+    // The current time, in milliseconds, for triggers. Not deterministic.
+    c.create_scalar_function("now", 0, false, move |_ctx| {
+        Ok(Timestamp::now().0 as i64)
+    })?;
     c.create_scalar_function("hash", -1, true, move |ctx| {
         Ok(match ctx.len() {
             1 => {
@@ -202,6 +488,7 @@ fn define_functions(c: &Connection) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile;
 
     #[test]
     fn test_open() {
@@ -217,4 +504,132 @@ mod tests {
         let rev_host: String = conn.db.query_row("SELECT reverse_host('')", &[], |row| row.get(0)).unwrap();
         assert_eq!(rev_host, ".");
     }
+
+    fn is_invalid_key_error(err: &Error) -> bool {
+        match err.kind() {
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) =>
+                err.code == rusqlite::ErrorCode::NotADatabase,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = PlacesDb::open(&path, Some("secret")).unwrap();
+            conn.execute_batch("INSERT INTO moz_meta(key, value) VALUES('test', 1)").unwrap();
+        }
+        let err = PlacesDb::open(&path, Some("wrong")).err().expect("should fail with the wrong key");
+        assert!(is_invalid_key_error(&err), "{:?}", err);
+        let err = PlacesDb::open_with_type(&path, None, ConnectionType::ReadOnly)
+            .err().expect("should fail without a key");
+        assert!(is_invalid_key_error(&err), "{:?}", err);
+
+        {
+            let conn = PlacesDb::open(&path, Some("secret")).unwrap();
+            conn.rekey("new secret").unwrap();
+        }
+        assert!(PlacesDb::open(&path, Some("secret")).is_err());
+        let conn = PlacesDb::open_with_type(&path, Some("new secret"), ConnectionType::ReadOnly).unwrap();
+        let value: i64 = conn.query_one("SELECT value FROM moz_meta WHERE key = 'test'").unwrap();
+        assert_eq!(value, 1);
+        assert!(conn.rekey("another secret").is_err());
+    }
+
+    fn pragma<T: rusqlite::types::FromSql>(conn: &PlacesDb, name: &str) -> T {
+        conn.query_one(&format!("PRAGMA {}", name)).unwrap()
+    }
+
+    #[test]
+    fn test_pragmas() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = PlacesDb::open(&path, None).unwrap();
+            assert_eq!(pragma::<String>(&conn, "journal_mode").to_lowercase(), "wal");
+            assert_eq!(pragma::<i64>(&conn, "synchronous"), 1);
+            assert_eq!(pragma::<i64>(&conn, "foreign_keys"), 0);
+            assert_eq!(pragma::<i64>(&conn, "temp_store"), 2);
+            assert_eq!(pragma::<i64>(&conn, "cache_size"), -6144);
+            assert_eq!(pragma::<i64>(&conn, "page_size"), 32768);
+
+            conn.execute_batch("INSERT INTO moz_meta(key, value) VALUES('test', 1)").unwrap();
+            conn.checkpoint().unwrap();
+            conn.vacuum().unwrap();
+        }
+
+        let config = PragmaConfig {
+            cache_size_kib: 1024,
+            journal_mode: JournalMode::Delete,
+            synchronous: Synchronous::Full,
+            foreign_keys: true,
+            ..PragmaConfig::default()
+        };
+        let conn = PlacesDb::open_with_config(
+            &path,
+            None,
+            ConnectionType::ReadWrite,
+            ConnectionType::ReadWrite.open_flags(),
+            &config,
+        ).unwrap();
+        assert_eq!(pragma::<String>(&conn, "journal_mode").to_lowercase(), "delete");
+        assert_eq!(pragma::<i64>(&conn, "synchronous"), 2);
+        assert_eq!(pragma::<i64>(&conn, "foreign_keys"), 1);
+        assert_eq!(pragma::<i64>(&conn, "cache_size"), -1024);
+        let value: i64 = conn.query_one("SELECT value FROM moz_meta WHERE key = 'test'").unwrap();
+        assert_eq!(value, 1);
+        // Checkpointing isn't an error outside of WAL mode.
+        conn.checkpoint().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_historyvisits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            // Put back the v11 table, without an `ON DELETE` action for
+            // `from_visit`.
+            let conn = PlacesDb::open(&path, None).unwrap();
+            let sql: String = conn.query_one(
+                "SELECT sql FROM sqlite_master WHERE name = 'moz_historyvisits'").unwrap();
+            let old_sql = sql.replace(" ON DELETE SET NULL", "");
+            assert_ne!(sql, old_sql);
+            conn.execute_batch(&format!(
+                "DROP TABLE moz_historyvisits;
+                 {};
+                 INSERT INTO moz_places(id, guid, url, url_hash)
+                 VALUES(1, 'page________', 'https://example.com/', hash('https://example.com/'));
+                 INSERT INTO moz_historyvisits(id, is_local, from_visit, place_id, visit_date, visit_type)
+                 VALUES(1, 1, NULL, 1, 1000, 1), (2, 1, 1, 1, 2000, 1);
+                 PRAGMA user_version = 11;", old_sql)).unwrap();
+        }
+
+        let conn = PlacesDb::open(&path, None).unwrap();
+        let sql: String = conn.query_one(
+            "SELECT sql FROM sqlite_master WHERE name = 'moz_historyvisits'").unwrap();
+        assert!(sql.contains("REFERENCES moz_historyvisits(id) ON DELETE SET NULL"));
+        let from_visit: i64 = conn.query_one(
+            "SELECT from_visit FROM moz_historyvisits WHERE id = 2").unwrap();
+        assert_eq!(from_visit, 1);
+        let indexes: i64 = conn.query_one(
+            "SELECT COUNT(*) FROM sqlite_master
+             WHERE type = 'index' AND tbl_name = 'moz_historyvisits'").unwrap();
+        assert_eq!(indexes, 4);
+    }
+
+    #[test]
+    fn test_optimize_fails_on_drop() {
+        let conn = PlacesDb::open_in_memory(None).unwrap();
+        // Using an index on a table that's never been analyzed makes
+        // `PRAGMA optimize` run `ANALYZE`, which can't write to the database
+        // with `query_only` on.
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places WHERE url_hash = 0").unwrap();
+        assert_eq!(count, 0);
+        conn.execute_batch("PRAGMA query_only = ON").unwrap();
+        assert!(conn.execute_batch("PRAGMA optimize(0x02)").is_err());
+        // Closing the connection should only log the error.
+        drop(conn);
+    }
 }
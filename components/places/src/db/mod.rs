@@ -4,6 +4,8 @@
 
 // We don't want 'db.rs' as a sub-module. We could move the contents here? Or something else?
 pub mod db;
-pub use db::db::PlacesDb;
+pub use db::db::{PlacesDb, ConnectionType, JournalMode, PragmaConfig, SqlInterruptHandle, Synchronous};
+pub mod tx;
+pub use db::tx::PlacesTransaction;
 
 mod schema;
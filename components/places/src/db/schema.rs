@@ -8,18 +8,23 @@
 // db.rs.
 
 use db::PlacesDb;
+use host;
 use sql_support::ConnExt;
+use types::SyncStatus;
+use url::Url;
 
 use error::*;
 
-const VERSION: i64 = 1;
+const VERSION: i64 = 12;
 
 const CREATE_TABLE_PLACES_SQL: &str =
     "CREATE TABLE IF NOT EXISTS moz_places (
         id INTEGER PRIMARY KEY,
         url LONGVARCHAR NOT NULL,
         title LONGVARCHAR,
-        -- note - desktop has rev_host here - that's now in moz_origin.
+        -- Added in v4. The reversed, canonical host, with a trailing period.
+        -- See `host::rev_host`.
+        rev_host LONGVARCHAR,
         visit_count_local INTEGER NOT NULL DEFAULT 0,
         visit_count_remote INTEGER NOT NULL DEFAULT 0,
         hidden INTEGER DEFAULT 0 NOT NULL,
@@ -36,6 +41,11 @@ const CREATE_TABLE_PLACES_SQL: &str =
         -- origin_id would ideally be NOT NULL, but we use a trigger to keep
         -- it up to date, so do perform the initial insert with a null.
         origin_id INTEGER,
+        -- Added in v7. A `SyncStatus`, and the number of local changes since
+        -- the page was last uploaded, like `syncStatus` and
+        -- `syncChangeCounter` in `moz_bookmarks`. New pages need uploading.
+        sync_status INTEGER NOT NULL DEFAULT 1,
+        sync_change_counter INTEGER NOT NULL DEFAULT 1,
 
         FOREIGN KEY(origin_id) REFERENCES moz_origins(id) ON DELETE CASCADE
     )";
@@ -52,7 +62,7 @@ const CREATE_TABLE_HISTORYVISITS_SQL: &str =
         -- session INTEGER, -- XXX - what is 'session'? Appears unused.
 
         FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
-        FOREIGN KEY(from_visit) REFERENCES moz_historyvisits(id)
+        FOREIGN KEY(from_visit) REFERENCES moz_historyvisits(id) ON DELETE SET NULL
     )";
 
 const CREATE_TABLE_INPUTHISTORY_SQL: &str =
@@ -65,24 +75,191 @@ const CREATE_TABLE_INPUTHISTORY_SQL: &str =
         FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
     )";
 
-// XXX - TODO - moz_annos
-// XXX - TODO - moz_anno_attributes
+// Added in v2, with `type` and `expiration` added in v5. See the
+// `annotations` module. `type` and `expiration` use the same values as
+// desktop, and default to a string that never expires, which is what the
+// annotations added before v5 were.
+const CREATE_TABLE_ANNO_ATTRIBUTES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_anno_attributes (
+        id INTEGER PRIMARY KEY,
+        name VARCHAR(32) UNIQUE NOT NULL
+    )";
+
+const CREATE_TABLE_ANNOS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_annos (
+        id INTEGER PRIMARY KEY,
+        place_id INTEGER NOT NULL,
+        anno_attribute_id INTEGER NOT NULL,
+        content LONGVARCHAR,
+        dateAdded INTEGER DEFAULT 0,
+        lastModified INTEGER DEFAULT 0,
+        type INTEGER NOT NULL DEFAULT 3,
+        expiration INTEGER NOT NULL DEFAULT 4,
+
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
+        FOREIGN KEY(anno_attribute_id) REFERENCES moz_anno_attributes(id) ON DELETE CASCADE
+    )";
+
+// Added in v5. Desktop doesn't have these.
+const CREATE_TABLE_VISIT_ANNOS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_visit_annos (
+        id INTEGER PRIMARY KEY,
+        visit_id INTEGER NOT NULL,
+        anno_attribute_id INTEGER NOT NULL,
+        content LONGVARCHAR,
+        dateAdded INTEGER DEFAULT 0,
+        lastModified INTEGER DEFAULT 0,
+        type INTEGER NOT NULL DEFAULT 3,
+        expiration INTEGER NOT NULL DEFAULT 4,
+
+        FOREIGN KEY(visit_id) REFERENCES moz_historyvisits(id) ON DELETE CASCADE,
+        FOREIGN KEY(anno_attribute_id) REFERENCES moz_anno_attributes(id) ON DELETE CASCADE
+    )";
+
 // XXX - TODO - moz_items_annos
-// XXX - TODO - moz_bookmarks
-// XXX - TODO - moz_bookmarks_deleted
 
-// TODO: This isn't the complete `moz_bookmarks` definition, just enough to
-// test autocomplete.
+// Added in v6. These are the same as desktop's favicons.sqlite, except that
+// we keep them in the same database as everything else, and key icons by
+// `icon_url_hash` instead of desktop's `fixed_icon_url_hash`. See the `icons`
+// module.
+const CREATE_TABLE_ICONS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_icons (
+        id INTEGER PRIMARY KEY,
+        icon_url TEXT NOT NULL,
+        icon_url_hash INTEGER NOT NULL,
+        width INTEGER NOT NULL DEFAULT 0,
+        root INTEGER NOT NULL DEFAULT 0,
+        expire_ms INTEGER NOT NULL DEFAULT 0,
+        data BLOB
+    )";
+
+const CREATE_TABLE_PAGES_W_ICONS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_pages_w_icons (
+        id INTEGER PRIMARY KEY,
+        page_url TEXT NOT NULL,
+        page_url_hash INTEGER NOT NULL
+    )";
+
+const CREATE_TABLE_ICONS_TO_PAGES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_icons_to_pages (
+        page_id INTEGER NOT NULL,
+        icon_id INTEGER NOT NULL,
+
+        PRIMARY KEY (page_id, icon_id),
+        FOREIGN KEY (page_id) REFERENCES moz_pages_w_icons ON DELETE CASCADE,
+        FOREIGN KEY (icon_id) REFERENCES moz_icons ON DELETE CASCADE
+    ) WITHOUT ROWID";
+
+// Added in v8. Tombstones for pages which sync has uploaded, and which have
+// since been removed locally, so the next sync can delete them on the server.
+const CREATE_TABLE_PLACES_TOMBSTONES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_places_tombstones (
+        guid TEXT PRIMARY KEY,
+        time_deleted INTEGER NOT NULL
+    ) WITHOUT ROWID";
+
+// Added in v9. Tombstones for visits which sync may have uploaded, and which
+// have been removed from pages we've kept. A page's visit tombstones are
+// removed with the page, since its own tombstone covers them.
+const CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_historyvisit_tombstones (
+        place_id INTEGER NOT NULL,
+        visit_date INTEGER NOT NULL,
+
+        PRIMARY KEY(place_id, visit_date),
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+    ) WITHOUT ROWID";
+
+// Added in v10. How long the user viewed a page, and what kind of page it
+// was, aggregated in windows. See the `history_metadata` module.
+const CREATE_TABLE_PLACES_METADATA_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_places_metadata (
+        id INTEGER PRIMARY KEY,
+        place_id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        total_view_time INTEGER NOT NULL DEFAULT 0,
+        document_type INTEGER NOT NULL DEFAULT 0,
+
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+    )";
+
+// Added in v11. The sites the user pinned to the top of their top sites, in
+// the order they pinned them. These are keyed by URL, rather than by page, so
+// that pinned sites outlive their history.
+const CREATE_TABLE_PINNED_SITES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_pinned_sites (
+        id INTEGER PRIMARY KEY,
+        url LONGVARCHAR NOT NULL UNIQUE,
+        url_hash INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        pinned_at INTEGER NOT NULL
+    )";
+
+// Replaced the placeholder table in v3. `type`, `syncStatus` and the root
+// GUIDs use the same values as desktop.
 const CREATE_TABLE_BOOKMARKS_SQL: &str =
-    "CREATE TABLE moz_bookmarks (
+    "CREATE TABLE IF NOT EXISTS moz_bookmarks (
         id INTEGER PRIMARY KEY,
-        fk INTEGER,
+        fk INTEGER DEFAULT NULL, -- place_id
+        type INTEGER NOT NULL,
+        parent INTEGER,
+        position INTEGER NOT NULL,
         title TEXT,
+        dateAdded INTEGER NOT NULL DEFAULT 0,
         lastModified INTEGER NOT NULL DEFAULT 0,
+        guid TEXT NOT NULL UNIQUE,
+        syncStatus INTEGER NOT NULL DEFAULT 0,
+        syncChangeCounter INTEGER NOT NULL DEFAULT 1,
+
+        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT,
+        FOREIGN KEY(parent) REFERENCES moz_bookmarks(id) ON DELETE CASCADE
+    )";
 
-        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT
+// Tombstones for locally deleted bookmarks which need to be uploaded.
+const CREATE_TABLE_BOOKMARKS_DELETED_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_bookmarks_deleted (
+        guid TEXT PRIMARY KEY,
+        dateRemoved INTEGER NOT NULL
+    ) WITHOUT ROWID";
+
+// The bookmark sync mirror: the last known state of every item on the server,
+// with GUIDs mapped to local ones (so "menu" is stored as "menu________").
+// `kind` is a `bookmark_sync::Kind`, and `needsMerge` is set for items we've
+// downloaded but not yet merged.
+const CREATE_TABLE_BOOKMARKS_SYNCED_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_bookmarks_synced (
+        id INTEGER PRIMARY KEY,
+        guid TEXT UNIQUE NOT NULL,
+        parentGuid TEXT,
+        serverModified INTEGER NOT NULL DEFAULT 0,
+        needsMerge INTEGER NOT NULL DEFAULT 0,
+        isDeleted INTEGER NOT NULL DEFAULT 0,
+        kind INTEGER NOT NULL DEFAULT -1,
+        dateAdded INTEGER NOT NULL DEFAULT 0,
+        title TEXT,
+        url TEXT
     )";
 
+// The children of each folder in the mirror, as listed in the folder record.
+const CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_bookmarks_synced_structure (
+        guid TEXT NOT NULL,
+        parentGuid TEXT NOT NULL REFERENCES moz_bookmarks_synced(guid) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        PRIMARY KEY(parentGuid, guid)
+    ) WITHOUT ROWID";
+
+// The roots every bookmark tree has. The mobile root is a child of the places
+// root, as on desktop, rather than hidden in the left pane.
+const CREATE_BOOKMARK_ROOTS_SQL: &str = "
+    INSERT OR IGNORE INTO moz_bookmarks(id, type, parent, position, title, guid, syncStatus, syncChangeCounter)
+    VALUES (1, 2, NULL, 0, 'root', 'root________', 1, 1),
+           (2, 2, 1, 0, 'menu', 'menu________', 1, 1),
+           (3, 2, 1, 1, 'toolbar', 'toolbar_____', 1, 1),
+           (4, 2, 1, 2, 'unfiled', 'unfiled_____', 1, 1),
+           (5, 2, 1, 3, 'mobile', 'mobile______', 1, 1)
+";
 
 // Note: desktop has/had a 'keywords' table, but we intentionally do not.
 
@@ -110,11 +287,16 @@ const CREATE_TRIGGER_AFTER_INSERT_ON_PLACES: &str = "
                              host = get_host_and_port(NEW.url) AND
                              rev_host = reverse_host(get_host_and_port(NEW.url)))
         WHERE id = NEW.id;
+
+        -- Sync can bring back a page we deleted.
+        DELETE FROM moz_places_tombstones WHERE guid = NEW.guid;
     END
 ";
 
 // Triggers which update visit_count and last_visit_date based on historyvisits
-// table changes.
+// table changes. The last visit dates are 0, not NULL, once a page has no
+// visits of that kind left. Removing a visit is also a change sync needs to upload; adding
+// one only is if it's local, which `apply_observation` takes care of.
 const EXCLUDED_VISIT_TYPES: &str = "0, 4, 7, 8, 9"; // stolen from desktop
 
 lazy_static! {
@@ -130,25 +312,78 @@ lazy_static! {
                 last_visit_date_remote = MAX(last_visit_date_remote,
                                              CASE WHEN NEW.is_local THEN 0 ELSE NEW.visit_date END)
             WHERE id = NEW.place_id;
+            DELETE FROM moz_historyvisit_tombstones
+            WHERE place_id = NEW.place_id AND visit_date = NEW.visit_date;
         END", excluded = EXCLUDED_VISIT_TYPES);
 
     static ref CREATE_TRIGGER_HISTORYVISITS_AFTERDELETE: String = format!("
         CREATE TEMP TRIGGER moz_historyvisits_afterdelete_trigger
         AFTER DELETE ON moz_historyvisits FOR EACH ROW
         BEGIN
+            -- The page changed, so that sync uploads it without the visit.
             UPDATE moz_places SET
+                sync_change_counter = sync_change_counter + 1,
                 visit_count_local = visit_count_local - (OLD.visit_type NOT IN ({excluded}) AND OLD.is_local),
                 visit_count_remote = visit_count_remote - (OLD.visit_type NOT IN ({excluded}) AND NOT(OLD.is_local)),
-                last_visit_date_local = (SELECT visit_date FROM moz_historyvisits
-                                         WHERE place_id = OLD.place_id AND is_local
-                                         ORDER BY visit_date DESC LIMIT 1),
-                last_visit_date_remote = (SELECT visit_date FROM moz_historyvisits
-                                          WHERE place_id = OLD.place_id AND NOT(is_local)
-                                          ORDER BY visit_date DESC LIMIT 1)
+                last_visit_date_local = IFNULL((SELECT visit_date FROM moz_historyvisits
+                                                WHERE place_id = OLD.place_id AND is_local
+                                                ORDER BY visit_date DESC LIMIT 1), 0),
+                last_visit_date_remote = IFNULL((SELECT visit_date FROM moz_historyvisits
+                                                 WHERE place_id = OLD.place_id AND NOT(is_local)
+                                                 ORDER BY visit_date DESC LIMIT 1), 0)
             WHERE id = OLD.place_id;
-        END", excluded = EXCLUDED_VISIT_TYPES);
+            DELETE FROM moz_visit_annos WHERE visit_id = OLD.id;
+            -- Visits of pages which haven't been synced can't be on the server.
+            INSERT OR IGNORE INTO moz_historyvisit_tombstones(place_id, visit_date)
+            SELECT id, OLD.visit_date FROM moz_places
+            WHERE id = OLD.place_id AND sync_status = {normal};
+        END", excluded = EXCLUDED_VISIT_TYPES, normal = SyncStatus::Normal as u8);
+
+    // Foreign keys are off by default, so annotations, visit tombstones and
+    // metadata need to be removed with their pages by hand. Every way of deleting a page
+    // goes through here, so this is also where we record its tombstone.
+    static ref CREATE_TRIGGER_PLACES_AFTERDELETE: String = format!("
+        CREATE TEMP TRIGGER moz_places_afterdelete_trigger
+        AFTER DELETE ON moz_places FOR EACH ROW
+        BEGIN
+            DELETE FROM moz_annos WHERE place_id = OLD.id;
+            DELETE FROM moz_historyvisit_tombstones WHERE place_id = OLD.id;
+            DELETE FROM moz_places_metadata WHERE place_id = OLD.id;
+            INSERT OR IGNORE INTO moz_places_tombstones(guid, time_deleted)
+            SELECT OLD.guid, now() WHERE OLD.sync_status = {normal};
+        END", normal = SyncStatus::Normal as u8);
 }
 
+// Keep `foreign_count` up to date, so we don't expire bookmarked pages.
+const CREATE_TRIGGER_BOOKMARKS_AFTERINSERT: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterinsert_trigger
+    AFTER INSERT ON moz_bookmarks FOR EACH ROW WHEN NEW.fk NOT NULL
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+    END
+";
+
+const CREATE_TRIGGER_BOOKMARKS_AFTERDELETE: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterdelete_trigger
+    AFTER DELETE ON moz_bookmarks FOR EACH ROW WHEN OLD.fk NOT NULL
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+    END
+";
+
+const CREATE_TRIGGER_BOOKMARKS_AFTERUPDATE_FK: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterupdate_fk_trigger
+    AFTER UPDATE OF fk ON moz_bookmarks FOR EACH ROW WHEN OLD.fk IS NOT NEW.fk
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+    END
+";
+
 // XXX - TODO - lots of desktop temp tables - but it's not clear they make sense here yet?
 
 // XXX - TODO - lots of favicon related tables - but it's not clear they make sense here yet?
@@ -165,7 +400,7 @@ const CREATE_TABLE_META_SQL: &str =
 // See https://searchfox.org/mozilla-central/source/toolkit/components/places/nsPlacesIndexes.h
 const CREATE_IDX_MOZ_PLACES_URL_HASH: &str = "CREATE INDEX url_hashindex ON moz_places(url_hash)";
 
-// const CREATE_IDX_MOZ_PLACES_REVHOST: &str = "CREATE INDEX hostindex ON moz_places(rev_host)";
+const CREATE_IDX_MOZ_PLACES_REVHOST: &str = "CREATE INDEX IF NOT EXISTS hostindex ON moz_places(rev_host)";
 
 const CREATE_IDX_MOZ_PLACES_VISITCOUNT_LOCAL: &str = "CREATE INDEX visitcountlocal ON moz_places(visit_count_local)";
 const CREATE_IDX_MOZ_PLACES_VISITCOUNT_REMOTE: &str = "CREATE INDEX visitcountremote ON moz_places(visit_count_remote)";
@@ -186,12 +421,23 @@ const CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE: &str = "CREATE INDEX dateindex ON
 
 const CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL: &str = "CREATE INDEX islocalindex ON moz_historyvisits(is_local)";
 
+const CREATE_IDX_MOZ_ANNOS_PLACEATTRIBUTE: &str = "CREATE UNIQUE INDEX IF NOT EXISTS moz_annos_placeattributeindex ON moz_annos(place_id, anno_attribute_id)";
 
-// const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str = "CREATE INDEX itemindex ON moz_bookmarks(fk, type)";
-// const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX parentindex ON moz_bookmarks(parent, position)";
-const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str = "CREATE INDEX itemlastmodifiedindex ON moz_bookmarks(fk, lastModified)";
+const CREATE_IDX_MOZ_ICONS_ICONURLHASH: &str = "CREATE INDEX IF NOT EXISTS moz_icons_iconurlhashindex ON moz_icons(icon_url_hash)";
+
+const CREATE_IDX_MOZ_PAGES_W_ICONS_URLHASH: &str = "CREATE INDEX IF NOT EXISTS moz_pages_w_icons_urlhashindex ON moz_pages_w_icons(page_url_hash)";
+
+const CREATE_IDX_MOZ_PLACES_METADATA_PLACEUPDATED: &str = "CREATE INDEX IF NOT EXISTS moz_places_metadata_placeupdatedindex ON moz_places_metadata(place_id, updated_at)";
+const CREATE_IDX_MOZ_PLACES_METADATA_UPDATED: &str = "CREATE INDEX IF NOT EXISTS moz_places_metadata_updatedindex ON moz_places_metadata(updated_at)";
+
+const CREATE_IDX_MOZ_VISIT_ANNOS_VISITATTRIBUTE: &str = "CREATE UNIQUE INDEX IF NOT EXISTS moz_visit_annos_visitattributeindex ON moz_visit_annos(visit_id, anno_attribute_id)";
+
+
+const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str = "CREATE INDEX IF NOT EXISTS itemindex ON moz_bookmarks(fk, type)";
+const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX IF NOT EXISTS parentindex ON moz_bookmarks(parent, position)";
+const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str = "CREATE INDEX IF NOT EXISTS itemlastmodifiedindex ON moz_bookmarks(fk, lastModified)";
 // const CREATE_IDX_MOZ_BOOKMARKS_DATEADDED: &str = "CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded)";
-// const CREATE_IDX_MOZ_BOOKMARKS_GUID: &str = "CREATE UNIQUE INDEX guid_uniqueindex ON moz_bookmarks(guid)";
+const CREATE_IDX_MOZ_BOOKMARKS_SYNCED_STRUCTURE_GUID: &str = "CREATE INDEX IF NOT EXISTS syncedstructureguidindex ON moz_bookmarks_synced_structure(guid)";
 
 // Keys in the moz_meta table.
 // pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &'static str = "origin_frecency_count";
@@ -202,9 +448,8 @@ const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str = "CREATE INDEX itemlastm
 pub fn init(db: &PlacesDb) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
     if user_version == 0 {
-        return create(db);
-    }
-    if user_version != VERSION {
+        create(db)?;
+    } else if user_version != VERSION {
         if user_version < VERSION {
             upgrade(db, user_version)?;
         } else {
@@ -213,17 +458,151 @@ pub fn init(db: &PlacesDb) -> Result<()> {
                   user_version, VERSION)
         }
     }
+    // Temp triggers only live as long as the connection, so they need to be
+    // created every time we open the database, not just when creating it.
+    create_temp_triggers(db)?;
     Ok(())
 }
 
-// https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(_db: &PlacesDb, from: i64) -> Result<()> {
+fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
     debug!("Upgrading schema from {} to {}", from, VERSION);
     if from == VERSION {
         return Ok(());
     }
-    // hrmph - do something here?
-    panic!("sorry, no upgrades yet - delete your db!");
+    assert_ne!(from, 0,
+        "Upgrading from user_version = 0 should already be handled (in `init`)");
+    if from < 2 {
+        // Annotations were added in v2, for downloads.
+        db.execute_all(&[
+            CREATE_TABLE_ANNO_ATTRIBUTES_SQL,
+            CREATE_TABLE_ANNOS_SQL,
+            CREATE_IDX_MOZ_ANNOS_PLACEATTRIBUTE,
+        ])?;
+    }
+    if from < 3 {
+        // Before v3, moz_bookmarks was a placeholder which nothing wrote to,
+        // so we replace it rather than migrating it.
+        db.execute_all(&[
+            "DROP TABLE IF EXISTS moz_bookmarks",
+            CREATE_TABLE_BOOKMARKS_SQL,
+            CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+            CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+            CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
+            CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+            CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
+            CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
+            CREATE_IDX_MOZ_BOOKMARKS_SYNCED_STRUCTURE_GUID,
+            CREATE_BOOKMARK_ROOTS_SQL,
+        ])?;
+    }
+    if from < 4 {
+        db.execute_all(&[
+            "ALTER TABLE moz_places ADD COLUMN rev_host LONGVARCHAR",
+            CREATE_IDX_MOZ_PLACES_REVHOST,
+        ])?;
+        populate_rev_hosts(db)?;
+    }
+    if from < 5 {
+        // The annotations table created by the v2 upgrade above already has
+        // the new columns.
+        if from >= 2 {
+            db.execute_all(&[
+                "ALTER TABLE moz_annos ADD COLUMN type INTEGER NOT NULL DEFAULT 3",
+                "ALTER TABLE moz_annos ADD COLUMN expiration INTEGER NOT NULL DEFAULT 4",
+            ])?;
+        }
+        db.execute_all(&[
+            CREATE_TABLE_VISIT_ANNOS_SQL,
+            CREATE_IDX_MOZ_VISIT_ANNOS_VISITATTRIBUTE,
+        ])?;
+    }
+    if from < 6 {
+        db.execute_all(&[
+            CREATE_TABLE_ICONS_SQL,
+            CREATE_TABLE_PAGES_W_ICONS_SQL,
+            CREATE_TABLE_ICONS_TO_PAGES_SQL,
+            CREATE_IDX_MOZ_ICONS_ICONURLHASH,
+            CREATE_IDX_MOZ_PAGES_W_ICONS_URLHASH,
+        ])?;
+    }
+    if from < 7 {
+        // Existing pages have never been synced, so the defaults are right.
+        db.execute_all(&[
+            "ALTER TABLE moz_places ADD COLUMN sync_status INTEGER NOT NULL DEFAULT 1",
+            "ALTER TABLE moz_places ADD COLUMN sync_change_counter INTEGER NOT NULL DEFAULT 1",
+        ])?;
+    }
+    if from < 8 {
+        db.execute_all(&[CREATE_TABLE_PLACES_TOMBSTONES_SQL])?;
+    }
+    if from < 9 {
+        db.execute_all(&[CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL])?;
+    }
+    if from < 10 {
+        db.execute_all(&[
+            CREATE_TABLE_PLACES_METADATA_SQL,
+            CREATE_IDX_MOZ_PLACES_METADATA_PLACEUPDATED,
+            CREATE_IDX_MOZ_PLACES_METADATA_UPDATED,
+        ])?;
+    }
+    if from < 11 {
+        db.execute_all(&[CREATE_TABLE_PINNED_SITES_SQL])?;
+    }
+    if from < 12 {
+        rebuild_historyvisits(db)?;
+    }
+    db.execute_all(&[
+        &format!("PRAGMA user_version = {version}", version = VERSION),
+    ])?;
+    Ok(())
+}
+
+// Before v12, deleting a visit that another visit came from failed if
+// foreign keys were enabled, since `from_visit` had no `ON DELETE` action.
+// SQLite can't change a constraint in place, so we copy the visits into a new
+// table with the right one. This runs before the connection enables foreign
+// keys, so dropping the old table doesn't cascade to `moz_visit_annos`, and
+// before the temp triggers exist.
+fn rebuild_historyvisits(db: &PlacesDb) -> Result<()> {
+    let create_new_table = CREATE_TABLE_HISTORYVISITS_SQL.replacen(
+        "CREATE TABLE moz_historyvisits",
+        "CREATE TABLE moz_historyvisits_new",
+        1,
+    );
+    let tx = db.unchecked_transaction()?;
+    tx.execute_all(&[
+        &create_new_table,
+        "INSERT INTO moz_historyvisits_new(id, is_local, from_visit, place_id, visit_date, visit_type)
+         SELECT id, is_local, from_visit, place_id, visit_date, visit_type FROM moz_historyvisits",
+        "DROP TABLE moz_historyvisits",
+        "ALTER TABLE moz_historyvisits_new RENAME TO moz_historyvisits",
+        CREATE_IDX_MOZ_HISTORYVISITS_PLACEDATE,
+        CREATE_IDX_MOZ_HISTORYVISITS_FROMVISIT,
+        CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE,
+        CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL,
+    ])?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Fills in `rev_host` for the pages added before v4. Pages with urls we
+// can't parse are left with a NULL `rev_host`, which just means they won't
+// be found by host.
+fn populate_rev_hosts(db: &PlacesDb) -> Result<()> {
+    let pages = {
+        let mut stmt = db.prepare("SELECT id, url FROM moz_places")?;
+        let rows = stmt.query_map(&[], |row| (row.get::<_, i64>(0), row.get::<_, String>(1)))?;
+        let pages: Vec<(i64, String)> = rows.collect::<::rusqlite::Result<_>>()?;
+        pages
+    };
+    for (id, url) in pages {
+        if let Ok(url) = Url::parse(&url) {
+            db.execute_named_cached(
+                "UPDATE moz_places SET rev_host = :rev_host WHERE id = :id",
+                &[(":rev_host", &host::rev_host(&host::canonicalize_url(&url))), (":id", &id)])?;
+        }
+    }
+    Ok(())
 }
 
 pub fn create(db: &PlacesDb) -> Result<()> {
@@ -233,9 +612,23 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_TABLE_HISTORYVISITS_SQL,
         CREATE_TABLE_INPUTHISTORY_SQL,
         CREATE_TABLE_BOOKMARKS_SQL,
+        CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
         CREATE_TABLE_ORIGINS_SQL,
         CREATE_TABLE_META_SQL,
+        CREATE_TABLE_ANNO_ATTRIBUTES_SQL,
+        CREATE_TABLE_ANNOS_SQL,
+        CREATE_TABLE_VISIT_ANNOS_SQL,
+        CREATE_TABLE_ICONS_SQL,
+        CREATE_TABLE_PAGES_W_ICONS_SQL,
+        CREATE_TABLE_ICONS_TO_PAGES_SQL,
+        CREATE_TABLE_PLACES_TOMBSTONES_SQL,
+        CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL,
+        CREATE_TABLE_PLACES_METADATA_SQL,
+        CREATE_TABLE_PINNED_SITES_SQL,
         CREATE_IDX_MOZ_PLACES_URL_HASH,
+        CREATE_IDX_MOZ_PLACES_REVHOST,
         CREATE_IDX_MOZ_PLACES_VISITCOUNT_LOCAL,
         CREATE_IDX_MOZ_PLACES_VISITCOUNT_REMOTE,
         CREATE_IDX_MOZ_PLACES_FRECENCY,
@@ -247,17 +640,33 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_IDX_MOZ_HISTORYVISITS_FROMVISIT,
         CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE,
         CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL,
+        CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+        CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
         CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
+        CREATE_IDX_MOZ_BOOKMARKS_SYNCED_STRUCTURE_GUID,
+        CREATE_IDX_MOZ_ANNOS_PLACEATTRIBUTE,
+        CREATE_IDX_MOZ_VISIT_ANNOS_VISITATTRIBUTE,
+        CREATE_IDX_MOZ_ICONS_ICONURLHASH,
+        CREATE_IDX_MOZ_PAGES_W_ICONS_URLHASH,
+        CREATE_IDX_MOZ_PLACES_METADATA_PLACEUPDATED,
+        CREATE_IDX_MOZ_PLACES_METADATA_UPDATED,
+        CREATE_BOOKMARK_ROOTS_SQL,
         &format!("PRAGMA user_version = {version}",
                  version = VERSION),
     ])?;
+    Ok(())
+}
 
+fn create_temp_triggers(db: &PlacesDb) -> Result<()> {
     debug!("Creating temp tables and triggers");
     db.execute_all(&[
         CREATE_TRIGGER_AFTER_INSERT_ON_PLACES,
+        &CREATE_TRIGGER_PLACES_AFTERDELETE,
         &CREATE_TRIGGER_HISTORYVISITS_AFTERINSERT,
         &CREATE_TRIGGER_HISTORYVISITS_AFTERDELETE,
+        CREATE_TRIGGER_BOOKMARKS_AFTERINSERT,
+        CREATE_TRIGGER_BOOKMARKS_AFTERDELETE,
+        CREATE_TRIGGER_BOOKMARKS_AFTERUPDATE_FK,
     ])?;
-
     Ok(())
 }
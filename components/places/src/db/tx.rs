@@ -0,0 +1,203 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use error::*;
+use rusqlite::Connection;
+use sql_support::ConnExt;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+enum Scope {
+    /// The outermost scope, which is a real transaction. If `commit_every` is
+    /// set, `maybe_commit` will commit and begin a new transaction once that
+    /// much time has passed since `started`.
+    Transaction {
+        commit_every: Option<Duration>,
+        started: Instant,
+    },
+    /// A nested scope, implemented with a SAVEPOINT.
+    Savepoint {
+        name: String,
+    },
+}
+
+/// A transaction (or nested savepoint) on a `PlacesDb`, started with
+/// `PlacesDb::begin_transaction`. Like the rusqlite `Transaction`, it is
+/// rolled back if dropped without calling `commit`.
+///
+/// Unlike the rusqlite `Transaction`, this only needs a shared reference to
+/// the connection, and supports committing in chunks, which is useful for
+/// long-running operations (imports, applying incoming sync records, etc)
+/// that we don't want to hold a single write transaction open for.
+pub struct PlacesTransaction<'conn> {
+    conn: &'conn Connection,
+    scope: Scope,
+    depth: usize,
+    finished: bool,
+}
+
+impl<'conn> PlacesTransaction<'conn> {
+    pub(crate) fn new(conn: &'conn Connection, commit_every: Option<Duration>) -> Result<Self> {
+        conn.execute_batch("BEGIN DEFERRED")?;
+        Ok(Self {
+            conn,
+            scope: Scope::Transaction { commit_every, started: Instant::now() },
+            depth: 0,
+            finished: false,
+        })
+    }
+
+    /// Begin a nested scope inside this one. Committing the nested scope only
+    /// makes its changes part of this scope - they'll still be discarded if
+    /// this scope is rolled back.
+    pub fn savepoint(&mut self) -> Result<PlacesTransaction> {
+        let depth = self.depth + 1;
+        let name = format!("places_sp_{}", depth);
+        self.conn.execute_batch(&format!("SAVEPOINT {}", name))?;
+        Ok(PlacesTransaction {
+            conn: self.conn,
+            scope: Scope::Savepoint { name },
+            depth,
+            finished: false,
+        })
+    }
+
+    /// If this transaction was started in chunked mode and the chunk time has
+    /// elapsed, commit what we have so far and begin a new transaction.
+    /// Otherwise, does nothing. Returns true if we committed.
+    ///
+    /// Note that this can't be called while a savepoint is active (the borrow
+    /// checker prevents it), and is a no-op on a savepoint itself.
+    pub fn maybe_commit(&mut self) -> Result<bool> {
+        if let Scope::Transaction { commit_every: Some(every), ref mut started } = self.scope {
+            if started.elapsed() >= every {
+                self.conn.execute_batch("COMMIT; BEGIN DEFERRED")?;
+                *started = Instant::now();
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Consumes and commits the transaction, or releases the savepoint.
+    pub fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        let sql = match &self.scope {
+            Scope::Transaction { .. } => "COMMIT".to_string(),
+            Scope::Savepoint { name } => format!("RELEASE {}", name),
+        };
+        self.conn.execute_batch(&sql)?;
+        Ok(())
+    }
+
+    /// Consumes and rolls back the transaction or savepoint.
+    pub fn rollback(mut self) -> Result<()> {
+        self.rollback_()
+    }
+
+    fn rollback_(&mut self) -> Result<()> {
+        self.finished = true;
+        let sql = match &self.scope {
+            Scope::Transaction { .. } => "ROLLBACK".to_string(),
+            // `ROLLBACK TO` leaves the savepoint on the stack, so we need to
+            // release it too.
+            Scope::Savepoint { name } => format!("ROLLBACK TO {name}; RELEASE {name}", name = name),
+        };
+        self.conn.execute_batch(&sql)?;
+        Ok(())
+    }
+}
+
+impl<'conn> Drop for PlacesTransaction<'conn> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = self.rollback_() {
+            warn!("Error rolling back a dropped places transaction: {}", e);
+        }
+    }
+}
+
+impl<'conn> ConnExt for PlacesTransaction<'conn> {
+    #[inline]
+    fn conn(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl<'conn> Deref for PlacesTransaction<'conn> {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::PlacesDb;
+
+    fn count(db: &impl ConnExt) -> i64 {
+        db.query_one("SELECT COUNT(*) FROM moz_places").unwrap()
+    }
+
+    fn insert(db: &impl ConnExt, url: &str) {
+        db.execute_named_cached(
+            "INSERT INTO moz_places (guid, url, url_hash) VALUES (:guid, :url, hash(:url))",
+            &[(":guid", &url[url.len() - 12..].to_string()), (":url", &url)],
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_nested_savepoints() {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        {
+            let mut tx = db.begin_transaction().unwrap();
+            insert(&tx, "http://example.com/aaaaaaaaaaaa");
+            {
+                let mut sp = tx.savepoint().unwrap();
+                insert(&sp, "http://example.com/bbbbbbbbbbbb");
+                {
+                    let sp2 = sp.savepoint().unwrap();
+                    insert(&sp2, "http://example.com/cccccccccccc");
+                    sp2.rollback().unwrap();
+                }
+                assert_eq!(count(&sp), 2);
+                sp.commit().unwrap();
+            }
+            {
+                let sp = tx.savepoint().unwrap();
+                insert(&sp, "http://example.com/dddddddddddd");
+                // dropped, so rolled back.
+            }
+            tx.commit().unwrap();
+        }
+        assert_eq!(count(&db), 2);
+
+        {
+            let tx = db.begin_transaction().unwrap();
+            insert(&tx, "http://example.com/eeeeeeeeeeee");
+        }
+        assert_eq!(count(&db), 2);
+    }
+
+    #[test]
+    fn test_chunked_commit() {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        {
+            let mut tx = db.begin_chunked_transaction(Duration::from_millis(0)).unwrap();
+            insert(&tx, "http://example.com/aaaaaaaaaaaa");
+            assert!(tx.maybe_commit().unwrap());
+            insert(&tx, "http://example.com/bbbbbbbbbbbb");
+            // Dropping the transaction only rolls back the second chunk.
+        }
+        assert_eq!(count(&db), 1);
+
+        let mut tx = db.begin_transaction().unwrap();
+        assert!(!tx.maybe_commit().unwrap());
+        tx.commit().unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Merging pages whose urls are different spellings of the same url. We
+//! canonicalize urls as we add pages, but databases imported from elsewhere
+//! often have, say, both `https://example.com` and `https://EXAMPLE.com:443/#`,
+//! which would otherwise show up twice in history and the awesomebar.
+//!
+//! A url's canonical form (see `canonical_url`) is what the `url` crate
+//! parses it as, which adds a slash to empty paths, removes default ports and
+//! lowercases hosts, with the host then canonicalized by
+//! `host::canonicalize_url`. Empty fragments are removed too, but other
+//! fragments are kept, since single-page apps use them for different pages.
+//! A trailing slash on a path that isn't empty is also kept, since servers
+//! can and do treat `/foo` and `/foo/` as different pages.
+//!
+//! Pages which share a canonical url are merged into one: the page which
+//! already has that url if there is one, or the oldest otherwise. It gets
+//! the visits, bookmarks, annotations, metadata and input history of the
+//! others, which are then deleted. Since they're deleted in the usual way,
+//! the ones which have been synced leave tombstones, so that other devices
+//! merge them too.
+
+use std::collections::HashMap;
+
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use frecency;
+use host;
+use sql_support::ConnExt;
+
+/// Returns the canonical form of `url`, which two urls for the same page
+/// have in common.
+pub fn canonical_url(url: &Url) -> Url {
+    let mut canonical = host::canonicalize_url(url).into_owned();
+    if canonical.fragment() == Some("") {
+        canonical.set_fragment(None);
+    }
+    canonical
+}
+
+/// Merges pages with the same canonical url, and gives every page its
+/// canonical url. Returns how many pages were removed. Pages with urls we
+/// can't parse are left alone.
+pub fn dedupe_pages(db: &PlacesDb) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    // The canonical url of every page which needs changing, and the ids and
+    // urls of its pages, oldest first.
+    let mut pages: HashMap<String, Vec<(i64, String)>> = HashMap::new();
+    {
+        let mut stmt = tx.prepare("SELECT id, url FROM moz_places ORDER BY id")?;
+        let rows = stmt.query_map(&[], |row| (row.get::<_, i64>(0), row.get::<_, String>(1)))?;
+        for row in rows {
+            let (id, url) = row?;
+            let canonical = match Url::parse(&url) {
+                Ok(parsed) => canonical_url(&parsed).into_string(),
+                Err(_) => continue,
+            };
+            pages.entry(canonical).or_insert_with(Vec::new).push((id, url));
+        }
+    }
+    let mut removed = 0;
+    for (canonical, mut group) in pages {
+        if group.len() == 1 && group[0].1 == canonical {
+            continue;
+        }
+        // Prefer the page which already has the canonical url, so we don't
+        // need to change it on other devices.
+        let keep_index = group.iter().position(|&(_, ref url)| *url == canonical).unwrap_or(0);
+        let (keep_id, keep_url) = group.remove(keep_index);
+        for (dupe_id, dupe_url) in &group {
+            merge_page(&tx, keep_id, *dupe_id)?;
+            rename_icon_page(&tx, dupe_url, &canonical)?;
+            removed += 1;
+        }
+        if keep_url != canonical {
+            set_page_url(&tx, keep_id, &canonical)?;
+            rename_icon_page(&tx, &keep_url, &canonical)?;
+        }
+        let frecency = frecency::calculate_frecency(&tx, db.frecency_settings(), keep_id, None)?;
+        tx.execute_named_cached(
+            "UPDATE moz_places SET frecency = :frecency WHERE id = :id",
+            &[(":frecency", &frecency), (":id", &keep_id)])?;
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+// Moves everything that refers to the page `dupe_id` to `keep_id`, merges
+// the page's columns into it, and deletes it.
+fn merge_page(db: &impl ConnExt, keep_id: i64, dupe_id: i64) -> Result<()> {
+    // The visit triggers only handle inserts and deletes, so we update the
+    // counts and dates ourselves once the visits are moved.
+    db.execute_named_cached("
+        UPDATE moz_places SET
+            title = IFNULL(title, (SELECT title FROM moz_places WHERE id = :dupe_id)),
+            hidden = MIN(hidden, (SELECT hidden FROM moz_places WHERE id = :dupe_id)),
+            typed = typed + (SELECT typed FROM moz_places WHERE id = :dupe_id),
+            visit_count_local = visit_count_local +
+                (SELECT visit_count_local FROM moz_places WHERE id = :dupe_id),
+            visit_count_remote = visit_count_remote +
+                (SELECT visit_count_remote FROM moz_places WHERE id = :dupe_id),
+            last_visit_date_local = MAX(last_visit_date_local,
+                (SELECT last_visit_date_local FROM moz_places WHERE id = :dupe_id)),
+            last_visit_date_remote = MAX(last_visit_date_remote,
+                (SELECT last_visit_date_remote FROM moz_places WHERE id = :dupe_id)),
+            sync_change_counter = sync_change_counter + 1
+        WHERE id = :keep_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    db.execute_named_cached(
+        "UPDATE moz_historyvisits SET place_id = :keep_id WHERE place_id = :dupe_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    db.execute_named_cached(
+        "UPDATE moz_places_metadata SET place_id = :keep_id WHERE place_id = :dupe_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    // The bookmark triggers move `foreign_count` along with the bookmarks.
+    // Their urls change, so they need uploading too.
+    db.execute_named_cached("
+        UPDATE moz_bookmarks SET
+            fk = :keep_id,
+            syncChangeCounter = syncChangeCounter + 1
+        WHERE fk = :dupe_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    // Where both pages have the same annotation or input, we keep ours. The
+    // page's trigger removes any annotations left behind, but not its input
+    // history.
+    db.execute_named_cached(
+        "UPDATE OR IGNORE moz_annos SET place_id = :keep_id WHERE place_id = :dupe_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    db.execute_named_cached(
+        "UPDATE OR IGNORE moz_inputhistory SET place_id = :keep_id WHERE place_id = :dupe_id",
+        &[(":keep_id", &keep_id), (":dupe_id", &dupe_id)])?;
+    db.execute_named_cached(
+        "DELETE FROM moz_inputhistory WHERE place_id = :dupe_id",
+        &[(":dupe_id", &dupe_id)])?;
+    db.execute_named_cached(
+        "DELETE FROM moz_places WHERE id = :dupe_id",
+        &[(":dupe_id", &dupe_id)])?;
+    Ok(())
+}
+
+// Changes the url of a page, which might also move it to a different origin.
+// The origin is only set by the insert trigger, so we do it ourselves.
+fn set_page_url(db: &impl ConnExt, id: i64, url: &str) -> Result<()> {
+    let rev_host = Url::parse(url).ok().map(|u| host::rev_host(&u));
+    db.execute_named_cached("
+        INSERT OR IGNORE INTO moz_origins(prefix, host, rev_host, frecency)
+        VALUES(get_prefix(:url), get_host_and_port(:url), reverse_host(get_host_and_port(:url)),
+               (SELECT frecency FROM moz_places WHERE id = :id))",
+        &[(":url", &url), (":id", &id)])?;
+    db.execute_named_cached("
+        UPDATE moz_places SET
+            url = :url,
+            url_hash = hash(:url),
+            rev_host = :rev_host,
+            origin_id = (SELECT id FROM moz_origins
+                         WHERE prefix = get_prefix(:url) AND
+                               host = get_host_and_port(:url)),
+            sync_change_counter = sync_change_counter + 1
+        WHERE id = :id",
+        &[(":url", &url), (":rev_host", &rev_host), (":id", &id)])?;
+    Ok(())
+}
+
+// Icons are stored by page url, so they need following when it changes.
+fn rename_icon_page(db: &impl ConnExt, old_url: &str, new_url: &str) -> Result<()> {
+    db.execute_named_cached("
+        UPDATE moz_pages_w_icons SET
+            page_url = :new_url,
+            page_url_hash = hash(:new_url)
+        WHERE page_url_hash = hash(:old_url) AND page_url = :old_url",
+        &[(":old_url", &old_url), (":new_url", &new_url)])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use storage::{apply_observation, get_page_info};
+    use types::{Timestamp, VisitTransition};
+
+    // Adds a page with a visit, bypassing the url canonicalization that
+    // `apply_observation` does, like an import might.
+    fn insert_raw_page(conn: &PlacesDb, guid: &str, url: &str, title: Option<&str>, date: Timestamp) -> i64 {
+        conn.execute_named_cached("
+            INSERT INTO moz_places(guid, url, url_hash, title)
+            VALUES (:guid, :url, hash(:url), :title)",
+            &[(":guid", &guid), (":url", &url), (":title", &title)]).expect("should insert page");
+        let id = conn.last_insert_rowid();
+        conn.execute_named_cached("
+            INSERT INTO moz_historyvisits(is_local, place_id, visit_date, visit_type)
+            VALUES (1, :id, :date, 1)",
+            &[(":id", &id), (":date", &date)]).expect("should insert visit");
+        id
+    }
+
+    #[test]
+    fn test_canonical_url() {
+        for &(url, canonical) in &[
+            ("https://EXAMPLE.com:443", "https://example.com/"),
+            ("https://example.com/#", "https://example.com/"),
+            ("https://example.com/#top", "https://example.com/#top"),
+            ("https://example.com/foo/", "https://example.com/foo/"),
+            ("http://example.com:8080/", "http://example.com:8080/"),
+            ("foo://EXAMPLE.com/bar", "foo://example.com/bar"),
+        ] {
+            assert_eq!(canonical_url(&Url::parse(url).unwrap()).as_str(), canonical);
+        }
+    }
+
+    #[test]
+    fn test_dedupe_pages() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        let canonical = Url::parse("https://example.com/").unwrap();
+        apply_observation(&conn, VisitObservation::new(canonical.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(now.0 - 3000))).expect("should apply");
+        let dupe_id = insert_raw_page(&conn, "dupeAAAAAAAA", "https://EXAMPLE.com:443", Some("Example"),
+                                      Timestamp(now.0 - 2000));
+        insert_raw_page(&conn, "fragmentAAAA", "https://example.com/#", None, Timestamp(now.0 - 1000));
+        // This one just needs its url fixing.
+        insert_raw_page(&conn, "renameAAAAAA", "https://Example.org:443/#", None, now);
+        conn.execute_named_cached("
+            INSERT INTO moz_bookmarks(fk, type, parent, position, guid)
+            VALUES (:id, 1, 5, 0, 'bookmarkAAAA')",
+            &[(":id", &dupe_id)]).expect("should insert bookmark");
+        conn.execute_batch("UPDATE moz_places SET sync_status = 2 WHERE guid = 'dupeAAAAAAAA'").unwrap();
+
+        assert_eq!(dedupe_pages(&conn).expect("should dedupe"), 2);
+        assert_eq!(dedupe_pages(&conn).expect("should dedupe again"), 0);
+
+        let page = get_page_info(&conn, &canonical).unwrap().expect("should keep the page");
+        assert_eq!(page.title, "Example");
+        assert_eq!(page.visit_count_local, 3);
+        assert_eq!(page.last_visit_date_local, Timestamp(now.0 - 1000));
+        assert!(page.frecency > 0);
+        let (count, foreign_count): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), SUM(foreign_count) FROM moz_places",
+            &[], |row| (row.get(0), row.get(1))).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(foreign_count, 1);
+        let bookmarked: String = conn.query_one("
+            SELECT h.url FROM moz_bookmarks b JOIN moz_places h ON h.id = b.fk
+            WHERE b.guid = 'bookmarkAAAA'").unwrap();
+        assert_eq!(bookmarked, "https://example.com/");
+
+        let renamed = get_page_info(&conn, &Url::parse("https://example.org/").unwrap()).unwrap()
+            .expect("should rename the page");
+        assert_eq!(renamed.guid, "renameAAAAAA");
+
+        // Only the synced page needs a tombstone.
+        let tombstone: String = conn.query_one("SELECT guid FROM moz_places_tombstones").unwrap();
+        assert_eq!(tombstone, "dupeAAAAAAAA");
+    }
+}
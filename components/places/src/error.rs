@@ -10,9 +10,27 @@ use std::boxed::Box;
 use rusqlite;
 use serde_json;
 use url;
+use sync;
+use error_support::{self, GetErrorCode};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Codes for errors specific to places, allocated from
+/// `error_support::PLACES_BASE`. See `GetErrorCode`.
+pub mod error_codes {
+    use error_support::PLACES_BASE;
+
+    /// The PlaceInfo we were given is invalid. (TODO: do we want to expose this as multiple
+    /// error codes?)
+    pub const INVALID_PLACE_INFO: i32 = PLACES_BASE + 1;
+
+    /// A URL was provided that we failed to parse
+    pub const URL_PARSE_ERROR: i32 = PLACES_BASE + 2;
+
+    /// The database was corrupt, and we couldn't replace it.
+    pub const DATABASE_CORRUPTED: i32 = PLACES_BASE + 3;
+}
+
 #[derive(Debug)]
 pub struct Error(Box<Context<ErrorKind>>);
 
@@ -42,6 +60,30 @@ impl Error {
     }
 }
 
+impl GetErrorCode for Error {
+    fn error_code(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::InvalidPlaceInfo(_) => error_codes::INVALID_PLACE_INFO,
+            ErrorKind::UrlParseError(_) => error_codes::URL_PARSE_ERROR,
+            ErrorKind::DatabaseCorrupted => error_codes::DATABASE_CORRUPTED,
+            ErrorKind::SyncAdapterError(e) => e.error_code(),
+            // We can't destructure `err` without bringing in the libsqlite3_sys crate
+            // (and I'd really rather not) so we can't put this in the match.
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::NotADatabase => {
+                error_support::INVALID_KEY
+            }
+            // Queries on read-only connections are interrupted when they're
+            // superseded, see `PlacesApi::interrupt_readers`.
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::OperationInterrupted => {
+                error_support::INTERRUPTED
+            }
+            _ => error_support::UNEXPECTED,
+        }
+    }
+}
+
 impl From<ErrorKind> for Error {
     #[inline]
     fn from(kind: ErrorKind) -> Error {
@@ -57,14 +99,17 @@ impl From<Context<ErrorKind>> for Error {
 }
 
 // Note: If you add new error types that should be returned to consumers on the other side of the
-// FFI, update `get_code` in `ffi.rs`
+// FFI, update `error_code` above
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Invalid place info: {}", _0)]
     InvalidPlaceInfo(InvalidPlaceInfo),
 
-//    #[fail(display = "Error synchronizing: {}", _0)]
-//    SyncAdapterError(#[fail(cause)] sync::Error),
+    #[fail(display = "Error synchronizing: {}", _0)]
+    SyncAdapterError(#[fail(cause)] sync::Error),
+
+    #[fail(display = "Invalid bookmark tree: {}", _0)]
+    InvalidBookmarkTree(String),
 
     #[fail(display = "Error parsing JSON data: {}", _0)]
     JsonError(#[fail(cause)] serde_json::Error),
@@ -74,6 +119,26 @@ pub enum ErrorKind {
 
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "A connection of this type is already open")]
+    ConnectionAlreadyOpen,
+
+    #[fail(display = "This connection was not opened by this API")]
+    WrongApiForClose,
+
+    #[fail(display = "Can't change the encryption key: {}", _0)]
+    CannotRekey(String),
+
+    #[fail(display = "Invalid buffer passed over the FFI: {}", _0)]
+    InvalidFfiBuffer(&'static str),
+
+    #[fail(display = "Invalid connection type: {}", _0)]
+    InvalidConnectionType(u8),
+
+    /// Returned by `PlacesApi::new` if the database is corrupt, and we
+    /// couldn't move it aside to replace it with an empty one.
+    #[fail(display = "The database is corrupt")]
+    DatabaseCorrupted,
 }
 
 macro_rules! impl_from_error {
@@ -95,7 +160,7 @@ macro_rules! impl_from_error {
 }
 
 impl_from_error! {
-//    (SyncAdapterError, sync::Error),
+    (SyncAdapterError, sync::Error),
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
@@ -106,5 +171,8 @@ impl_from_error! {
 pub enum InvalidPlaceInfo {
     #[fail(display = "No url specified")]
     NoUrl,
+
+    #[fail(display = "URL is too long")]
+    UrlTooLong,
 }
 
@@ -7,47 +7,141 @@
 // This module implement the traits that make the FFI code easier to manage.
 
 use ffi_support::{ErrorCode, ExternError};
-use api::matcher::SearchResult;
+use api::matcher::{MatchReason, SearchResult};
 use db::PlacesDb;
-use error::{Error, ErrorKind};
+use storage::{Highlight, HistorySearchResult, HistoryVisitPage, PageInfo};
+use frecency::FrecencyDetails;
+use history_metadata::HistoryMetadata;
+use pinned_sites::TopSite;
+use msg_types;
+use error::{Error, ErrorKind, Result};
+use error_support::{self, GetErrorCode};
+use std::str;
 
-pub mod error_codes {
-    // Note: 0 (success) and -1 (panic) are reserved by ffi_support
-
-    /// An unexpected error occurred which likely cannot be meaningfully handled
-    /// by the application.
-    pub const UNEXPECTED: i32 = 1;
+impl From<Error> for ExternError {
+    fn from(e: Error) -> ExternError {
+        let code = e.error_code();
+        if code == error_support::UNEXPECTED {
+            error!("Unexpected error: {:?}", e.kind());
+        } else {
+            error!("Returning error code {}: {}", code, e);
+        }
+        ExternError::new_error(ErrorCode::new(code), e.to_string())
+    }
+}
 
-    /// The PlaceInfo we were given is invalid. (TODO: do we want to expose this as multiple
-    /// error codes?)
-    pub const INVALID_PLACE_INFO: i32 = 2;
+implement_into_ffi_by_pointer!(PlacesDb);
+implement_into_ffi_by_json!(HistoryVisitPage);
+implement_into_ffi_by_json!(HistorySearchResult);
+implement_into_ffi_by_json!(Highlight);
+implement_into_ffi_by_json!(FrecencyDetails);
+implement_into_ffi_by_json!(PageInfo);
+implement_into_ffi_by_json!(HistoryMetadata);
+implement_into_ffi_by_json!(TopSite);
+implement_into_ffi_by_protobuf!(msg_types::SearchResultList);
 
-    /// A URL was provided that we failed to parse
-    pub const URL_PARSE_ERROR: i32 = 3;
+impl From<SearchResult> for msg_types::SearchResultMessage {
+    fn from(result: SearchResult) -> Self {
+        let mut tags = None;
+        let reasons = result.reasons.into_iter().map(|reason| match reason {
+            MatchReason::Keyword => msg_types::SearchResultReason::Keyword,
+            MatchReason::Origin => msg_types::SearchResultReason::Origin,
+            MatchReason::Url => msg_types::SearchResultReason::Url,
+            MatchReason::PreviousUse => msg_types::SearchResultReason::PreviousUse,
+            MatchReason::Bookmark => msg_types::SearchResultReason::Bookmark,
+            MatchReason::Tags(t) => {
+                tags = Some(t);
+                msg_types::SearchResultReason::Tags
+            }
+        }).map(|reason| reason as i32).collect();
+        msg_types::SearchResultMessage {
+            search_string: result.search_string,
+            url: result.url.into_string(),
+            title: result.title,
+            icon_url: result.icon_url.map(|u| u.into_string()),
+            frecency: result.frecency,
+            reasons,
+            tags,
+        }
+    }
 }
 
-fn get_code(err: &Error) -> ErrorCode {
-    match err.kind() {
-        ErrorKind::InvalidPlaceInfo(info) => {
-            error!("Invalid place info: {}", info);
-            ErrorCode::new(error_codes::INVALID_PLACE_INFO)
+impl From<Vec<SearchResult>> for msg_types::SearchResultList {
+    fn from(results: Vec<SearchResult>) -> Self {
+        msg_types::SearchResultList {
+            results: results.into_iter().map(Into::into).collect(),
         }
-        ErrorKind::UrlParseError(e) => {
-            error!("URL parse error: {}", e);
-            ErrorCode::new(error_codes::URL_PARSE_ERROR)
+    }
+}
+
+/// Splits a buffer of strings passed over the FFI, where each string is a
+/// big-endian `i32` byte length followed by that many bytes of UTF-8. This is
+/// what a `java.nio.ByteBuffer` writes with `putInt` and `put`, and avoids
+/// building and parsing a JSON array for large batches.
+pub fn strings_from_buffer(mut buf: &[u8]) -> Result<Vec<&str>> {
+    let mut strings = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            return Err(ErrorKind::InvalidFfiBuffer("truncated length").into());
         }
-        err => {
-            error!("Unexpected error: {:?}", err);
-            ErrorCode::new(error_codes::UNEXPECTED)
+        let len = ((buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32) as usize;
+        buf = &buf[4..];
+        if buf.len() < len {
+            return Err(ErrorKind::InvalidFfiBuffer("truncated string").into());
         }
+        let (s, rest) = buf.split_at(len);
+        strings.push(str::from_utf8(s).map_err(|_| ErrorKind::InvalidFfiBuffer("invalid UTF-8"))?);
+        buf = rest;
     }
+    Ok(strings)
 }
 
-impl From<Error> for ExternError {
-    fn from(e: Error) -> ExternError {
-        ExternError::new_error(get_code(&e), e.to_string())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strings_from_buffer() {
+        let buf = [
+            0, 0, 0, 3, b'a', b'b', b'c',
+            0, 0, 0, 0,
+            0, 0, 0, 1, b'd',
+        ];
+        assert_eq!(strings_from_buffer(&buf).unwrap(), vec!["abc", "", "d"]);
+        assert!(strings_from_buffer(&[]).unwrap().is_empty());
+        assert!(strings_from_buffer(&buf[..5]).is_err());
+        assert!(strings_from_buffer(&buf[..2]).is_err());
+        assert!(strings_from_buffer(&[0, 0, 0, 1, 0xff]).is_err());
     }
-}
 
-implement_into_ffi_by_pointer!(PlacesDb);
-implement_into_ffi_by_json!(SearchResult);
+    #[test]
+    fn test_search_result_list() {
+        use prost::Message;
+        use url::Url;
+
+        let results = vec![SearchResult {
+            search_string: "moz".into(),
+            url: Url::parse("https://www.mozilla.org/").unwrap(),
+            title: "Mozilla".into(),
+            icon_url: None,
+            frecency: 1234,
+            reasons: vec![MatchReason::Origin, MatchReason::Tags("web".into())],
+        }];
+        let list: msg_types::SearchResultList = results.into();
+        let mut bytes = Vec::new();
+        list.encode(&mut bytes).unwrap();
+
+        let decoded = msg_types::SearchResultList::decode(&bytes[..]).unwrap();
+        assert_eq!(decoded.results.len(), 1);
+        let result = &decoded.results[0];
+        assert_eq!(result.url, "https://www.mozilla.org/");
+        assert_eq!(result.title, "Mozilla");
+        assert_eq!(result.icon_url, None);
+        assert_eq!(result.frecency, 1234);
+        assert_eq!(result.reasons, vec![
+            msg_types::SearchResultReason::Origin as i32,
+            msg_types::SearchResultReason::Tags as i32,
+        ]);
+        assert_eq!(result.tags, Some("web".to_string()));
+    }
+}
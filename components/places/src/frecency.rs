@@ -39,6 +39,11 @@ pub struct FrecencySettings {
     pub unvisited_bookmark_bonus: i32,  // from "places.frecency.unvisitedBookmarkBonus"
     pub unvisited_typed_bonus: i32,     // from "places.frecency.unvisitedTypedBonus"
     pub reload_visit_bonus: i32,        // from "places.frecency.reloadVisitBonus"
+    /// If true, a typed visit which ends in a redirect counts as typing the
+    /// redirect target instead of the source, like desktop does. The source
+    /// is hidden, so this is what lets the target get the typed bonus in
+    /// autocomplete.
+    pub typed_redirect_boosts_target: bool,
 }
 
 pub const DEFAULT_FRECENCY_SETTINGS: FrecencySettings = FrecencySettings {
@@ -66,6 +71,7 @@ pub const DEFAULT_FRECENCY_SETTINGS: FrecencySettings = FrecencySettings {
     unvisited_bookmark_bonus: 140,
     unvisited_typed_bonus: 200,
     reload_visit_bonus: 0,
+    typed_redirect_boosts_target: true,
 };
 
 impl Default for FrecencySettings {
@@ -85,8 +91,8 @@ impl FrecencySettings {
             (Some(VisitTransition::Link), _) => self.link_visit_bonus,
             (Some(VisitTransition::Embed), _) => self.embed_visit_bonus,
             (Some(VisitTransition::FramedLink), _) => self.framed_link_visit_bonus,
-            (Some(VisitTransition::RedirectPermanent), _) => self.temporary_redirect_visit_bonus,
-            (Some(VisitTransition::RedirectTemporary), _) => self.permanent_redirect_visit_bonus,
+            (Some(VisitTransition::RedirectPermanent), _) => self.permanent_redirect_visit_bonus,
+            (Some(VisitTransition::RedirectTemporary), _) => self.temporary_redirect_visit_bonus,
             (Some(VisitTransition::Download), _) => self.download_visit_bonus,
             (Some(VisitTransition::Reload), _) => self.reload_visit_bonus,
             (Some(VisitTransition::Typed), true) => self.typed_visit_bonus,
@@ -113,6 +119,49 @@ impl FrecencySettings {
     }
 }
 
+/// How `FrecencyDetails::frecency` was calculated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrecencyMethod {
+    /// From a sample of the page's most recent visits.
+    Sampled,
+    /// The page is an unvisited bookmark, which gets a fixed bonus.
+    UnvisitedBookmark,
+    /// The page has no visits, and isn't bookmarked (or is a query).
+    Unvisited,
+}
+
+/// One of the visits sampled to calculate a page's frecency.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FrecencySample {
+    /// The type of the visit, or of the visit that redirected to it.
+    pub visit_type: Option<VisitTransition>,
+    pub age_in_days: i32,
+    /// Whether the visit was scored as the source of a redirect.
+    pub used_redirect_bonus: bool,
+    pub transition_bonus: i32,
+    pub bookmark_bonus: i32,
+    /// The weight of the age bucket the visit falls into.
+    pub weight: i32,
+    /// `weight * (transition_bonus + bookmark_bonus) / 100`.
+    pub points: f32,
+}
+
+/// Everything that went into a page's frecency. See `calculate_frecency_detailed`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FrecencyDetails {
+    pub page_id: i64,
+    pub visit_count: i32,
+    pub typed: i32,
+    pub has_bookmark: bool,
+    pub is_query: bool,
+    pub samples: Vec<FrecencySample>,
+    /// The sum of the samples' points.
+    pub sample_score: f32,
+    pub method: FrecencyMethod,
+    pub frecency: i32,
+}
+
 struct FrecencyComputation<'db, 's> {
     conn: &'db Connection,
     settings: &'s FrecencySettings,
@@ -161,7 +210,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
         self.foreign_count > 0
     }
 
-    fn score_recent_visits(&self) -> Result<(usize, f32)> {
+    fn sample_recent_visits(&self) -> Result<Vec<FrecencySample>> {
         // Get a sample of the last visits to the page, to calculate its weight.
         // In case the visit is a redirect target, calculate the frecency
         // as if the original page was visited.
@@ -195,8 +244,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
              age_in_days as i32)
         })?;
 
-        let mut num_sampled_visits = 0;
-        let mut points_for_sampled_visits = 0.0f32;
+        let mut samples = Vec::new();
 
         for row_result in row_iter {
             let (visit_type, target_visit_type, age_in_days) = row_result?;
@@ -205,7 +253,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
             // database, because we only store redirect targets.
             // For older visits we extract the value from the database.
             let use_redirect_bonus =
-                if self.most_recent_redirect_bonus == RedirectBonus::Unknown || num_sampled_visits > 0 {
+                if self.most_recent_redirect_bonus == RedirectBonus::Unknown || !samples.is_empty() {
                     target_visit_type == Some(VisitTransition::RedirectPermanent) ||
                     (target_visit_type == Some(VisitTransition::RedirectTemporary) &&
                      visit_type != Some(VisitTransition::Typed))
@@ -213,19 +261,32 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
                     self.most_recent_redirect_bonus == RedirectBonus::Redirect
                 };
 
-            let mut bonus = self.settings.get_transition_bonus(visit_type, true, use_redirect_bonus);
-
-            if self.has_bookmark() {
-                bonus += self.settings.get_transition_bonus(Some(VisitTransition::Bookmark), true, false);
-            }
-            if bonus != 0 {
-                let weight = self.settings.get_frecency_aged_weight(age_in_days) as f32;
-                points_for_sampled_visits += weight * (bonus as f32 / 100.0)
-            }
-            num_sampled_visits += 1;
+            let transition_bonus = self.settings.get_transition_bonus(visit_type, true, use_redirect_bonus);
+
+            let bookmark_bonus = if self.has_bookmark() {
+                self.settings.get_transition_bonus(Some(VisitTransition::Bookmark), true, false)
+            } else {
+                0
+            };
+            let bonus = transition_bonus + bookmark_bonus;
+            let weight = self.settings.get_frecency_aged_weight(age_in_days);
+            let points = if bonus != 0 {
+                weight as f32 * (bonus as f32 / 100.0)
+            } else {
+                0.0
+            };
+            samples.push(FrecencySample {
+                visit_type,
+                age_in_days,
+                used_redirect_bonus: use_redirect_bonus,
+                transition_bonus,
+                bookmark_bonus,
+                weight,
+                points,
+            });
         }
 
-        Ok((num_sampled_visits, points_for_sampled_visits))
+        Ok(samples)
     }
 
     fn get_frecency_for_sample(&self, num_sampled: usize, score: f32) -> i32 {
@@ -261,6 +322,18 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
 }
 
 pub fn calculate_frecency(db: &Connection, settings: &FrecencySettings, page_id: i64, is_redirect: Option<bool>) -> Result<i32> {
+    Ok(compute_frecency(db, settings, page_id, is_redirect)?.frecency)
+}
+
+/// Like `calculate_frecency`, but returns how the score was arrived at, for
+/// debugging and ranking experiments. This doesn't know whether the most
+/// recent visit was a redirect, so it's the same as passing `None` for
+/// `is_redirect`.
+pub fn calculate_frecency_detailed(db: &Connection, settings: &FrecencySettings, page_id: i64) -> Result<FrecencyDetails> {
+    compute_frecency(db, settings, page_id, None)
+}
+
+fn compute_frecency(db: &Connection, settings: &FrecencySettings, page_id: i64, is_redirect: Option<bool>) -> Result<FrecencyDetails> {
     assert!(page_id > 0, "calculate_frecency given invalid page_id");
 
     let most_recent_redirect_bonus = match is_redirect {
@@ -271,23 +344,67 @@ pub fn calculate_frecency(db: &Connection, settings: &FrecencySettings, page_id:
 
     let fc = FrecencyComputation::new(db, settings, page_id, most_recent_redirect_bonus)?;
 
-    let (num_sampled_visits, sample_score) = if fc.visit_count > 0 {
-        fc.score_recent_visits()?
+    let samples = if fc.visit_count > 0 {
+        fc.sample_recent_visits()?
     } else {
-        (0, 0.0f32)
+        Vec::new()
     };
+    let sample_score: f32 = samples.iter().map(|s| s.points).sum();
 
-    Ok(if num_sampled_visits > 0 {
+    let (method, frecency) = if !samples.is_empty() {
         // If we sampled some visits for this page, use the calculated weight.
-        fc.get_frecency_for_sample(num_sampled_visits, sample_score)
+        (FrecencyMethod::Sampled, fc.get_frecency_for_sample(samples.len(), sample_score))
     } else if !fc.has_bookmark() || fc.is_query {
         // Otherwise, this page has no visits, it may be bookmarked.
-        0
+        (FrecencyMethod::Unvisited, 0)
     } else {
         // For unvisited bookmarks, produce a non-zero frecency, so that they show
         // up in URL bar autocomplete.
-        fc.compute_unvisited_bookmark_frecency()
+        (FrecencyMethod::UnvisitedBookmark, fc.compute_unvisited_bookmark_frecency())
+    };
+
+    Ok(FrecencyDetails {
+        page_id,
+        visit_count: fc.visit_count,
+        typed: fc.typed,
+        has_bookmark: fc.has_bookmark(),
+        is_query: fc.is_query,
+        samples,
+        sample_score,
+        method,
+        frecency,
     })
 }
 
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::PlacesDb;
+    use observation::VisitObservation;
+    use storage;
+    use types::Timestamp;
+    use url::Url;
+
+    #[test]
+    fn test_frecency_detailed() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        for &visit_type in &[VisitTransition::Typed, VisitTransition::Link] {
+            storage::apply_observation(&conn, VisitObservation::new(url.clone())
+                .with_visit_type(visit_type)
+                .with_at(Timestamp::now())).unwrap();
+        }
+        let page_id: i64 = conn.query_row(
+            "SELECT id FROM moz_places WHERE url = ?", &[&url.as_str()], |row| row.get(0)).unwrap();
+
+        let details = calculate_frecency_detailed(&conn, &DEFAULT_FRECENCY_SETTINGS, page_id).unwrap();
+        assert_eq!(details.method, FrecencyMethod::Sampled);
+        assert_eq!(details.visit_count, 2);
+        assert_eq!(details.samples.len(), 2);
+        let bonuses = details.samples.iter().map(|s| s.transition_bonus).collect::<Vec<_>>();
+        assert!(bonuses.contains(&DEFAULT_FRECENCY_SETTINGS.typed_visit_bonus));
+        assert!(bonuses.contains(&DEFAULT_FRECENCY_SETTINGS.link_visit_bonus));
+        assert!(details.samples.iter().all(|s| s.weight == DEFAULT_FRECENCY_SETTINGS.first_bucket_weight));
+        assert_eq!(details.frecency, calculate_frecency(&conn, &DEFAULT_FRECENCY_SETTINGS, page_id, None).unwrap());
+    }
+}
@@ -56,6 +56,18 @@ pub fn hash_url_prefix(spec_prefix: &str, mode: PrefixMode) -> u64 {
     }
 }
 
+/// Returns the inclusive range of `url_hash` values that urls with the given
+/// scheme (e.g. `"https"`, without the `:`) can have. Only the scheme
+/// contributes to the upper bits of a url's hash, so this can't narrow things
+/// down by host - queries for a host should use this range to take advantage
+/// of the `url_hash` index, and then check the `url` itself.
+///
+/// This is the same range as `hash(scheme, 'prefix_lo')` to
+/// `hash(scheme, 'prefix_hi')` in SQL.
+pub fn url_hash_range_for_scheme(scheme: &str) -> (u64, u64) {
+    (hash_url_prefix(scheme, PrefixMode::Lo), hash_url_prefix(scheme, PrefixMode::Hi))
+}
+
 // mozilla::kGoldenRatioU32
 const GOLDEN_RATIO: u32 = 0x9E3779B9;
 
@@ -100,6 +112,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_url_hash_range_for_scheme() {
+        let (lo, hi) = url_hash_range_for_scheme("https");
+        let in_range = &[
+            "https://www.example.com",
+            "https://github.com/mozilla/application-services/",
+        ];
+        for url in in_range {
+            let hash = hash_url(url);
+            assert!(lo <= hash && hash <= hi, "{:?} should be in the range", url);
+        }
+        let hash = hash_url("http://www.example.com");
+        assert!(hash < lo || hash > hi);
+    }
+
     #[test]
     fn test_hash_url() {
         // not actually a valid png, but whatever.
@@ -0,0 +1,399 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! How the user engages with the pages they visit: how long they spend
+//! viewing them, and whether they're regular pages or media, like a video.
+//! Front-ends note what they see with `note_history_metadata_observation`,
+//! and use the query functions for "recently watched" lists and for ranking
+//! suggestions by engagement.
+//!
+//! Observations are aggregated per page, in windows: an observation made
+//! within `AGGREGATION_WINDOW_MS` of the page's last one is added to it, and
+//! a later one starts a new window. The metadata goes away with its page, and
+//! `storage::delete_visits_for` removes the windows it covers.
+
+use rusqlite::Row;
+use rusqlite::types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, FromSqlError, ValueRef};
+use rusqlite::Result as RusqliteResult;
+use serde;
+use url::Url;
+use url_serde;
+
+use db::PlacesDb;
+use error::*;
+use sql_support::ConnExt;
+use storage;
+use types::Timestamp;
+
+/// Observations of a page made within this long of the last one are added
+/// to the same window.
+pub const AGGREGATION_WINDOW_MS: u64 = 30 * 60 * 1000;
+
+/// What kind of page the user was looking at. These values are stored in
+/// `moz_places_metadata`, and duplicated in the android lib.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DocumentType {
+    Regular = 0,
+    // Video or audio, which the user is likely to come back to.
+    Media = 1,
+}
+
+impl DocumentType {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(DocumentType::Regular),
+            1 => Some(DocumentType::Media),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for DocumentType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for DocumentType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        DocumentType::from_primitive(u8::column_result(value)?)
+            .ok_or_else(|| FromSqlError::InvalidType)
+    }
+}
+
+impl serde::Serialize for DocumentType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DocumentType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let value = <u8 as serde::Deserialize>::deserialize(deserializer)?;
+        DocumentType::from_primitive(value).ok_or_else(||
+            serde::de::Error::custom(format!("unknown DocumentType value: {}", value)))
+    }
+}
+
+/// Something the front-end saw about a page the user is viewing. Like
+/// `VisitObservation`, fields which are `None` record nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryMetadataObservation {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+
+    /// How long the user spent viewing the page since the last observation,
+    /// in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub view_time: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub document_type: Option<DocumentType>,
+
+    /// When the observation was made. Defaults to now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub at: Option<Timestamp>,
+}
+
+impl HistoryMetadataObservation {
+    pub fn new(url: Url) -> Self {
+        HistoryMetadataObservation {
+            url,
+            view_time: None,
+            document_type: None,
+            at: None,
+        }
+    }
+
+    pub fn with_view_time(mut self, v: impl Into<Option<u32>>) -> Self {
+        self.view_time = v.into();
+        self
+    }
+
+    pub fn with_document_type(mut self, v: impl Into<Option<DocumentType>>) -> Self {
+        self.document_type = v.into();
+        self
+    }
+
+    pub fn with_at(mut self, v: impl Into<Option<Timestamp>>) -> Self {
+        self.at = v.into();
+        self
+    }
+}
+
+/// The metadata for a page, either for a single window, or combined over
+/// several (see `query_history_metadata`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryMetadata {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+    /// In milliseconds.
+    pub total_view_time: u64,
+    pub document_type: DocumentType,
+}
+
+impl HistoryMetadata {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked("title")?,
+            created_at: row.get_checked("created_at")?,
+            updated_at: row.get_checked("updated_at")?,
+            total_view_time: row.get_checked::<_, i64>("total_view_time")?.max(0) as u64,
+            document_type: row.get_checked("document_type")?,
+        })
+    }
+}
+
+/// Adds `observation` to the current window for its page, or starts a new
+/// one. Observations for pages which aren't in history, or which the URL
+/// policy rejects, are ignored, since the visit should be noted first.
+pub fn note_history_metadata_observation(
+    db: &PlacesDb,
+    observation: HistoryMetadataObservation,
+) -> Result<()> {
+    if !db.url_policy().can_add_url(&observation.url) {
+        debug!("Ignoring metadata for a URL rejected by the URL policy");
+        return Ok(());
+    }
+    let now = Timestamp::now();
+    let at = observation.at.map_or(now, |at| at.clamp_future(now));
+    let view_time = observation.view_time.unwrap_or(0);
+    let tx = db.begin_transaction()?;
+    let page_id = match storage::find_page_id(&tx, &observation.url)? {
+        Some(id) => id,
+        None => {
+            debug!("Ignoring metadata for a page that isn't in history");
+            return Ok(());
+        }
+    };
+    let window_start = Timestamp(at.0.saturating_sub(AGGREGATION_WINDOW_MS));
+    let window_id: Option<i64> = tx.try_query_row("
+        SELECT id FROM moz_places_metadata
+        WHERE place_id = :page_id AND updated_at >= :window_start
+        ORDER BY updated_at DESC
+        LIMIT 1",
+        &[(":page_id", &page_id), (":window_start", &window_start)],
+        |row| row.get_checked(0),
+        true)?;
+    match window_id {
+        Some(id) => {
+            tx.execute_named_cached("
+                UPDATE moz_places_metadata
+                SET updated_at = MAX(updated_at, :at),
+                    total_view_time = total_view_time + :view_time,
+                    document_type = IFNULL(:document_type, document_type)
+                WHERE id = :id",
+                &[
+                    (":at", &at),
+                    (":view_time", &view_time),
+                    (":document_type", &observation.document_type),
+                    (":id", &id),
+                ])?;
+        }
+        None => {
+            tx.execute_named_cached("
+                INSERT INTO moz_places_metadata(place_id, created_at, updated_at,
+                                                total_view_time, document_type)
+                VALUES (:page_id, :at, :at, :view_time, :document_type)",
+                &[
+                    (":page_id", &page_id),
+                    (":at", &at),
+                    (":view_time", &view_time),
+                    (":document_type", &observation.document_type.unwrap_or(DocumentType::Regular)),
+                ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns the most recent window for `url`, or `None` if we don't have any
+/// metadata for it.
+pub fn get_latest_history_metadata_for_url(db: &impl ConnExt, url: &Url) -> Result<Option<HistoryMetadata>> {
+    let page_id = match storage::find_page_id(db, url)? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    Ok(db.try_query_row("
+        SELECT h.url, h.title, m.created_at, m.updated_at, m.total_view_time, m.document_type
+        FROM moz_places_metadata m
+        JOIN moz_places h ON h.id = m.place_id
+        WHERE m.place_id = :page_id
+        ORDER BY m.updated_at DESC
+        LIMIT 1",
+        &[(":page_id", &page_id)],
+        HistoryMetadata::from_row,
+        true)?)
+}
+
+/// Returns up to `limit` windows updated at or after `since`, most recently
+/// updated first. Pass `Some(DocumentType::Media)` for a "recently watched"
+/// list.
+pub fn get_history_metadata_since(
+    db: &impl ConnExt,
+    since: Timestamp,
+    document_type: Option<DocumentType>,
+    limit: u32,
+) -> Result<Vec<HistoryMetadata>> {
+    let mut stmt = db.conn().prepare_cached("
+        SELECT h.url, h.title, m.created_at, m.updated_at, m.total_view_time, m.document_type
+        FROM moz_places_metadata m
+        JOIN moz_places h ON h.id = m.place_id
+        WHERE m.updated_at >= :since
+          AND (:document_type IS NULL OR m.document_type = :document_type)
+        ORDER BY m.updated_at DESC, m.id DESC
+        LIMIT :limit")?;
+    let results = stmt
+        .query_and_then_named(&[
+            (":since", &since),
+            (":document_type", &document_type),
+            (":limit", &limit),
+        ], HistoryMetadata::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(results)
+}
+
+/// Returns up to `limit` pages whose url or title contains `query`, the ones
+/// the user has spent longest viewing first, for engagement-ranked
+/// suggestions. Each page's windows are combined into one result, which is a
+/// media page if any of them was. Matching is case-insensitive for ASCII
+/// only, and an empty query matches nothing.
+pub fn query_history_metadata(db: &impl ConnExt, query: &str, limit: u32) -> Result<Vec<HistoryMetadata>> {
+    let query = query.trim().to_ascii_lowercase();
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut stmt = db.conn().prepare_cached("
+        SELECT h.url, h.title,
+               MIN(m.created_at) AS created_at,
+               MAX(m.updated_at) AS updated_at,
+               SUM(m.total_view_time) AS total_view_time,
+               MAX(m.document_type) AS document_type
+        FROM moz_places_metadata m
+        JOIN moz_places h ON h.id = m.place_id
+        WHERE instr(lower(h.url), :query) > 0
+           OR instr(lower(IFNULL(h.title, '')), :query) > 0
+        GROUP BY m.place_id
+        ORDER BY total_view_time DESC, updated_at DESC
+        LIMIT :limit")?;
+    let results = stmt
+        .query_and_then_named(&[(":query", &query), (":limit", &limit)], HistoryMetadata::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use storage::{apply_observation, delete_visits_for, delete_visits_for_origin};
+    use types::VisitTransition;
+
+    fn visit(conn: &PlacesDb, url: &Url, title: &str, at: u64) {
+        apply_observation(conn, VisitObservation::new(url.clone())
+            .with_title(title.to_string())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(at))).expect("should apply visit");
+    }
+
+    fn note(conn: &PlacesDb, url: &Url, view_time: u32, document_type: Option<DocumentType>, at: u64) {
+        note_history_metadata_observation(conn, HistoryMetadataObservation::new(url.clone())
+            .with_view_time(view_time)
+            .with_document_type(document_type)
+            .with_at(Timestamp(at))).expect("should note metadata");
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/video").unwrap();
+
+        // Nothing is recorded for pages which aren't in history.
+        note(&conn, &url, 1000, None, 10_000);
+        assert!(get_latest_history_metadata_for_url(&conn, &url).unwrap().is_none());
+
+        visit(&conn, &url, "A video", 10_000);
+        note(&conn, &url, 1000, None, 10_000);
+        note(&conn, &url, 2000, Some(DocumentType::Media), 20_000);
+        let latest = get_latest_history_metadata_for_url(&conn, &url).unwrap().unwrap();
+        assert_eq!(latest, HistoryMetadata {
+            url: url.clone(),
+            title: Some("A video".into()),
+            created_at: Timestamp(10_000),
+            updated_at: Timestamp(20_000),
+            total_view_time: 3000,
+            document_type: DocumentType::Media,
+        });
+
+        // An observation after the window starts a new one.
+        let later = 20_000 + AGGREGATION_WINDOW_MS + 1;
+        note(&conn, &url, 500, None, later);
+        let latest = get_latest_history_metadata_for_url(&conn, &url).unwrap().unwrap();
+        assert_eq!((latest.created_at, latest.total_view_time, latest.document_type),
+                   (Timestamp(later), 500, DocumentType::Regular));
+        assert_eq!(get_history_metadata_since(&conn, Timestamp(0), None, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_queries() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let video = Url::parse("https://videos.example.com/cats").unwrap();
+        let article = Url::parse("https://news.example.com/dogs").unwrap();
+        let other = Url::parse("https://www.mozilla.org/").unwrap();
+        visit(&conn, &video, "Cat video", 1000);
+        visit(&conn, &article, "Dogs and CATS", 1000);
+        visit(&conn, &other, "Mozilla", 1000);
+        note(&conn, &video, 5000, Some(DocumentType::Media), 1000);
+        note(&conn, &video, 5000, None, 2000 + AGGREGATION_WINDOW_MS);
+        note(&conn, &article, 8000, None, 3000);
+        note(&conn, &other, 60_000, None, 4000);
+
+        let watched = get_history_metadata_since(&conn, Timestamp(0), Some(DocumentType::Media), 10).unwrap();
+        assert_eq!(watched.iter().map(|m| m.url.clone()).collect::<Vec<_>>(), vec![video.clone()]);
+        let recent = get_history_metadata_since(&conn, Timestamp(3000), None, 2).unwrap();
+        assert_eq!(recent.iter().map(|m| m.url.clone()).collect::<Vec<_>>(), vec![video.clone(), other.clone()]);
+
+        let results = query_history_metadata(&conn, "Cat", 10).unwrap();
+        assert_eq!(results.iter().map(|m| m.url.clone()).collect::<Vec<_>>(), vec![video.clone(), article.clone()]);
+        assert_eq!(results[0].total_view_time, 10_000);
+        assert_eq!(results[0].document_type, DocumentType::Media);
+        assert!(query_history_metadata(&conn, " ", 10).unwrap().is_empty());
+
+        // Non-ASCII letters only match in the same case, as SQLite's
+        // `lower()` leaves them alone.
+        let umlaut = Url::parse("https://www.example.com/umlaut").unwrap();
+        visit(&conn, &umlaut, "Über uns", 1000);
+        note(&conn, &umlaut, 1000, None, 5000);
+        let results = query_history_metadata(&conn, "ÜBER", 10).unwrap();
+        assert_eq!(results.iter().map(|m| m.url.clone()).collect::<Vec<_>>(), vec![umlaut.clone()]);
+        assert!(query_history_metadata(&conn, "über", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deletion() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        visit(&conn, &url, "Example", 1000);
+        visit(&conn, &url, "Example", 2000 + AGGREGATION_WINDOW_MS);
+        note(&conn, &url, 1000, None, 1000);
+        note(&conn, &url, 1000, None, 2000 + AGGREGATION_WINDOW_MS);
+
+        delete_visits_for(&conn, &url, Timestamp(2000)).unwrap();
+        let remaining = get_history_metadata_since(&conn, Timestamp(0), None, 10).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].created_at, Timestamp(1000));
+
+        delete_visits_for_origin(&conn, &url).unwrap();
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places_metadata").unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,98 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Canonicalizing the hosts of the URLs we store, so that the same page
+//! always ends up with the same row in `moz_places`.
+//!
+//! The `url` crate already lowercases and punycodes hosts for "special"
+//! schemes (http, https, ftp, etc), but leaves the hosts of other schemes
+//! as-is (percent-encoded, if they aren't ASCII), so without this
+//! `foo://EXAMPLE.com` and `foo://example.com` would be different pages.
+
+use std::borrow::Cow;
+
+use url::{Host, Url};
+use url::percent_encoding::percent_decode;
+
+use error::*;
+
+/// Returns `url` with its host lowercased and punycoded. Borrows `url` if
+/// it's already canonical, which is almost always the case.
+pub fn canonicalize_url(url: &Url) -> Cow<Url> {
+    let canonical_host = match url.host_str() {
+        Some(host) => match canonicalize_host(host) {
+            Ok(ref canonical) if canonical == host => return Cow::Borrowed(url),
+            Ok(canonical) => canonical,
+            // A host we can't canonicalize is left alone. It can still be
+            // looked up, just not by a differently cased version.
+            Err(_) => return Cow::Borrowed(url),
+        },
+        None => return Cow::Borrowed(url),
+    };
+    let mut canonical = url.clone();
+    match canonical.set_host(Some(&canonical_host)) {
+        Ok(()) => Cow::Owned(canonical),
+        Err(_) => Cow::Borrowed(url),
+    }
+}
+
+/// Lowercases and punycodes `host`, which may be percent-encoded.
+pub fn canonicalize_host(host: &str) -> Result<String> {
+    let decoded = percent_decode(host.as_bytes()).decode_utf8()
+        .map_err(|_| ::url::ParseError::InvalidDomainCharacter)?;
+    Ok(Host::parse(&decoded)?.to_string())
+}
+
+/// Returns the reversed host of `url`, with a trailing period, as stored in
+/// `moz_places.rev_host` (for example, "moc.elpmaxe.www." for
+/// "https://www.example.com/"). URLs without a host have a `rev_host` of
+/// ".". This matches both desktop, and our `reverse_host` SQL function.
+pub fn rev_host(url: &Url) -> String {
+    reverse_host(url.host_str().unwrap_or(""))
+}
+
+/// Reverses a host that's already been canonicalized.
+pub fn reverse_host(host: &str) -> String {
+    // Hosts are ASCII once they've been punycoded, so reversing the bytes is
+    // the same as reversing the characters. We lowercase again in case the
+    // host was never canonicalized.
+    let mut rev_host: String = host.chars().rev().map(|c| c.to_ascii_lowercase()).collect();
+    rev_host.push('.');
+    rev_host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize_url() {
+        let url = Url::parse("https://www.example.com/Path").unwrap();
+        match canonicalize_url(&url) {
+            Cow::Borrowed(u) => assert_eq!(u, &url),
+            Cow::Owned(_) => panic!("Canonical urls shouldn't be copied"),
+        }
+
+        // The url crate handles these itself...
+        let url = Url::parse("https://WWW.Bücher.example/").unwrap();
+        assert_eq!(canonicalize_url(&url).as_str(), "https://www.xn--bcher-kva.example/");
+
+        // ...but not these.
+        let url = Url::parse("foo://WWW.Bücher.example/Path").unwrap();
+        assert_eq!(canonicalize_url(&url).as_str(), "foo://www.xn--bcher-kva.example/Path");
+
+        let url = Url::parse("about:blank").unwrap();
+        assert_eq!(canonicalize_url(&url).as_str(), "about:blank");
+    }
+
+    #[test]
+    fn test_rev_host() {
+        assert_eq!(reverse_host("foo.com"), "moc.oof.");
+        assert_eq!(reverse_host("FOO.com"), "moc.oof.");
+        assert_eq!(rev_host(&Url::parse("https://www.example.com:8080/a").unwrap()),
+                   "moc.elpmaxe.www.");
+        assert_eq!(rev_host(&Url::parse("about:blank").unwrap()), ".");
+        assert_eq!(canonicalize_host("Bücher.EXAMPLE").unwrap(), "xn--bcher-kva.example");
+    }
+}
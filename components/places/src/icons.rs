@@ -0,0 +1,272 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Favicons, stored like desktop's favicons.sqlite. An icon URL can have
+//! several payloads (one for each size), and is linked to the pages which
+//! use it. Icons at `/favicon.ico` are also "root" icons, which are used for
+//! any page of the same origin that doesn't have an icon of its own.
+
+use rusqlite::Row;
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use host;
+use sql_support::ConnExt;
+use storage::RowId;
+use types::Timestamp;
+
+/// A column with the URL of the largest icon for the page `h` (an alias for
+/// `moz_places`), falling back to the root icon of its origin, for
+/// autocomplete results.
+pub(crate) const PAGE_ICON_URL_SQL: &str = "
+    IFNULL(
+        (SELECT i.icon_url
+         FROM moz_pages_w_icons p
+         JOIN moz_icons_to_pages ip ON ip.page_id = p.id
+         JOIN moz_icons i ON i.id = ip.icon_id
+         WHERE p.page_url_hash = h.url_hash AND p.page_url = h.url
+         ORDER BY i.width DESC
+         LIMIT 1),
+        (SELECT i.icon_url
+         FROM moz_icons i
+         WHERE i.root
+           AND i.icon_url_hash = hash(get_prefix(h.url) || get_host_and_port(h.url) || '/favicon.ico')
+         LIMIT 1)
+    ) AS icon_url";
+
+/// An icon for a page, as returned by `get_icon_for_page`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconInfo {
+    pub url: Url,
+    /// The width of the icon in pixels, or 0 if it's not known.
+    pub width: u32,
+    /// The icon itself, if we've fetched it.
+    pub data: Option<Vec<u8>>,
+    /// When the icon should be fetched again. Expired icons are still
+    /// returned, since an old icon is better than none.
+    pub expires: Timestamp,
+}
+
+impl IconInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url: String = row.get_checked("icon_url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            width: row.get_checked::<_, i64>("width")? as u32,
+            data: row.get_checked("data")?,
+            expires: row.get_checked("expire_ms")?,
+        })
+    }
+}
+
+/// Stores `icon_url` (and its payload, if it's been fetched) as an icon for
+/// `page_url`, which doesn't need to be in history. There can be an icon for
+/// each `width` of the same URL; setting one that already exists replaces
+/// its payload and expiration. Pages which the URL policy rejects are
+/// ignored.
+pub fn set_icon_for_page(
+    db: &PlacesDb,
+    page_url: &Url,
+    icon_url: &Url,
+    width: u32,
+    data: Option<&[u8]>,
+    expires: Timestamp,
+) -> Result<()> {
+    if !db.url_policy().can_add_url(page_url) {
+        debug!("Ignoring icon for a URL rejected by the URL policy");
+        return Ok(());
+    }
+    let page_url = host::canonicalize_url(page_url);
+    let icon_url = host::canonicalize_url(icon_url);
+    let tx = db.begin_transaction()?;
+    let icon_id = upsert_icon(&tx, &icon_url, width, data, expires)?;
+    let page_id = get_or_insert_icon_page_id(&tx, &page_url)?;
+    tx.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_icons_to_pages(page_id, icon_id) VALUES (:page_id, :icon_id)",
+        &[(":page_id", &page_id), (":icon_id", &icon_id)])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns the best icon for `page_url` at `width` pixels: the smallest
+/// which is at least that wide, or the largest if they're all smaller. Pass
+/// 0 for the largest icon. If the page has no icons, its origin's root icon
+/// (if any) is returned instead.
+pub fn get_icon_for_page(db: &PlacesDb, page_url: &Url, width: u32) -> Result<Option<IconInfo>> {
+    let page_url = host::canonicalize_url(page_url);
+    let mut icons = {
+        let mut stmt = db.prepare_cached("
+            SELECT i.icon_url, i.width, i.data, i.expire_ms
+            FROM moz_pages_w_icons p
+            JOIN moz_icons_to_pages ip ON ip.page_id = p.id
+            JOIN moz_icons i ON i.id = ip.icon_id
+            WHERE p.page_url_hash = hash(:page_url) AND p.page_url = :page_url")?;
+        let rows = stmt.query_and_then_named(&[(":page_url", &page_url.as_str())], IconInfo::from_row)?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+    if icons.is_empty() {
+        if let Some(root_url) = root_icon_url(&page_url) {
+            let mut stmt = db.prepare_cached("
+                SELECT icon_url, width, data, expire_ms
+                FROM moz_icons
+                WHERE root AND icon_url_hash = hash(:icon_url) AND icon_url = :icon_url")?;
+            let rows = stmt.query_and_then_named(&[(":icon_url", &root_url.as_str())], IconInfo::from_row)?;
+            icons = rows.collect::<Result<Vec<_>>>()?;
+        }
+    }
+    Ok(pick_best_icon(icons, width))
+}
+
+/// Removes the icons for pages which are no longer in history or bookmarks,
+/// and any non-root icons which no page uses. Returns the number of icons
+/// removed.
+pub fn remove_orphan_icons(db: &PlacesDb) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    tx.execute_batch("
+        DELETE FROM moz_pages_w_icons
+        WHERE NOT EXISTS (SELECT 1 FROM moz_places h
+                          WHERE h.url_hash = page_url_hash AND h.url = page_url);
+        DELETE FROM moz_icons_to_pages
+        WHERE page_id NOT IN (SELECT id FROM moz_pages_w_icons);")?;
+    let removed = tx.execute("
+        DELETE FROM moz_icons
+        WHERE NOT root
+          AND id NOT IN (SELECT icon_id FROM moz_icons_to_pages)", &[])?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+fn upsert_icon(
+    db: &impl ConnExt,
+    icon_url: &Url,
+    width: u32,
+    data: Option<&[u8]>,
+    expires: Timestamp,
+) -> Result<RowId> {
+    let existing: Option<RowId> = db.try_query_row("
+        SELECT id FROM moz_icons
+        WHERE icon_url_hash = hash(:icon_url) AND icon_url = :icon_url AND width = :width",
+        &[(":icon_url", &icon_url.as_str()), (":width", &width)],
+        |row| row.get_checked(0),
+        true)?;
+    Ok(match existing {
+        Some(id) => {
+            db.execute_named_cached(
+                "UPDATE moz_icons SET data = :data, expire_ms = :expires WHERE id = :id",
+                &[(":data", &data), (":expires", &expires), (":id", &id)])?;
+            id
+        }
+        None => {
+            db.execute_named_cached("
+                INSERT INTO moz_icons(icon_url, icon_url_hash, width, root, expire_ms, data)
+                VALUES (:icon_url, hash(:icon_url), :width, :root, :expires, :data)",
+                &[
+                    (":icon_url", &icon_url.as_str()),
+                    (":width", &width),
+                    (":root", &is_root_icon(icon_url)),
+                    (":expires", &expires),
+                    (":data", &data),
+                ])?;
+            RowId(db.conn().last_insert_rowid())
+        }
+    })
+}
+
+fn get_or_insert_icon_page_id(db: &impl ConnExt, page_url: &Url) -> Result<RowId> {
+    let existing: Option<RowId> = db.try_query_row("
+        SELECT id FROM moz_pages_w_icons
+        WHERE page_url_hash = hash(:page_url) AND page_url = :page_url",
+        &[(":page_url", &page_url.as_str())],
+        |row| row.get_checked(0),
+        true)?;
+    Ok(match existing {
+        Some(id) => id,
+        None => {
+            db.execute_named_cached("
+                INSERT INTO moz_pages_w_icons(page_url, page_url_hash)
+                VALUES (:page_url, hash(:page_url))",
+                &[(":page_url", &page_url.as_str())])?;
+            RowId(db.conn().last_insert_rowid())
+        }
+    })
+}
+
+fn is_root_icon(icon_url: &Url) -> bool {
+    icon_url.has_host() && icon_url.path() == "/favicon.ico" && icon_url.query().is_none()
+}
+
+fn root_icon_url(page_url: &Url) -> Option<Url> {
+    if !page_url.has_host() {
+        return None;
+    }
+    page_url.join("/favicon.ico").ok()
+}
+
+fn pick_best_icon(icons: Vec<IconInfo>, width: u32) -> Option<IconInfo> {
+    if width == 0 {
+        return icons.into_iter().max_by_key(|icon| icon.width);
+    }
+    let (big_enough, too_small): (Vec<_>, Vec<_>) = icons.into_iter().partition(|icon| icon.width >= width);
+    match big_enough.into_iter().min_by_key(|icon| icon.width) {
+        Some(icon) => Some(icon),
+        None => too_small.into_iter().max_by_key(|icon| icon.width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn far_future() -> Timestamp {
+        Timestamp(Timestamp::now().0 + 7 * 24 * 60 * 60 * 1000)
+    }
+
+    #[test]
+    fn test_icons_for_page() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let page = Url::parse("https://www.example.com/page").unwrap();
+        let icon = Url::parse("https://www.example.com/icon.png").unwrap();
+        assert_eq!(get_icon_for_page(&conn, &page, 16).unwrap(), None);
+
+        set_icon_for_page(&conn, &page, &icon, 16, Some(&b"small"[..]), far_future()).unwrap();
+        set_icon_for_page(&conn, &page, &icon, 64, None, far_future()).unwrap();
+        set_icon_for_page(&conn, &page, &icon, 32, Some(&b"medium"[..]), far_future()).unwrap();
+
+        let best = |width| get_icon_for_page(&conn, &page, width).unwrap().expect("should have an icon");
+        assert_eq!(best(16).data, Some(b"small".to_vec()));
+        assert_eq!(best(20).width, 32);
+        assert_eq!(best(128).width, 64);
+        assert_eq!(best(0).width, 64);
+        assert_eq!(best(0).url, icon);
+
+        // Setting the same size again replaces it.
+        set_icon_for_page(&conn, &page, &icon, 16, Some(&b"new"[..]), far_future()).unwrap();
+        assert_eq!(best(16).data, Some(b"new".to_vec()));
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_icons").unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_root_icons() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let page = Url::parse("https://www.example.com/page").unwrap();
+        let other_page = Url::parse("https://www.example.com/other?q=1").unwrap();
+        let root = Url::parse("https://www.example.com/favicon.ico").unwrap();
+        set_icon_for_page(&conn, &page, &root, 0, None, far_future()).unwrap();
+
+        // Pages from the same origin use the root icon...
+        let icon = get_icon_for_page(&conn, &other_page, 16).unwrap().expect("should use the root icon");
+        assert_eq!(icon.url, root);
+        // ...but other origins don't.
+        let http_page = Url::parse("http://www.example.com/page").unwrap();
+        assert_eq!(get_icon_for_page(&conn, &http_page, 16).unwrap(), None);
+
+        // Root icons aren't removed when their pages are.
+        assert_eq!(remove_orphan_icons(&conn).unwrap(), 0);
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_pages_w_icons").unwrap();
+        assert_eq!(count, 0);
+        assert!(get_icon_for_page(&conn, &other_page, 16).unwrap().is_some());
+    }
+}
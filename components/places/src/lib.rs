@@ -9,6 +9,8 @@ extern crate log;
 
 #[cfg(test)]
 extern crate env_logger;
+#[cfg(test)]
+extern crate tempfile;
 
 extern crate failure;
 
@@ -30,9 +32,15 @@ extern crate serde_derive;
 extern crate caseless;
 extern crate unicode_normalization;
 extern crate sql_support;
+extern crate types_support;
+extern crate error_support;
 extern crate url_serde;
 #[macro_use]
 extern crate bitflags;
+extern crate bytes;
+extern crate prost;
+#[macro_use]
+extern crate prost_derive;
 
 #[cfg(feature = "ffi")]
 #[macro_use]
@@ -41,24 +49,54 @@ extern crate ffi_support;
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+
 pub mod api;
 pub mod error;
 pub mod types;
 // Making these all pub for now while we flesh out the API.
 pub mod db;
 pub mod storage;
+pub mod annotations;
+pub mod icons;
+pub mod maintenance;
+pub mod dedupe;
+pub mod tombstones;
+pub mod history_metadata;
+pub mod pinned_sites;
 pub mod hash;
 pub mod frecency;
 pub mod observation;
+pub mod url_policy;
+pub mod host;
+pub mod bookmark_sync;
+pub mod private_browsing;
 mod util;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 mod match_impl;
 
+/// Types generated from `places_msg_types.proto`, for results returned over
+/// the FFI as protocol buffers.
+pub mod msg_types {
+    include!(concat!(env!("OUT_DIR"), "/msg_types.rs"));
+}
+
+#[cfg(test)]
+mod proptests;
+
 pub use error::*;
 pub use types::*;
+pub use types_support::Guid;
 pub use observation::VisitObservation;
-pub use storage::{RowId, PageInfo};
-pub use db::PlacesDb;
+pub use url_policy::UrlPolicy;
+pub use storage::{RowId, PageInfo, DownloadInfo, VisitInfo, RedirectChainEntry, HistoryVisitInfo, HistoryVisitPage, Highlight};
+pub use db::{PlacesDb, ConnectionType, PragmaConfig};
 pub use api::apply_observation;
+pub use api::places_api::PlacesApi;
+pub use private_browsing::PrivateBrowsingStore;
+pub use history_metadata::{DocumentType, HistoryMetadata, HistoryMetadataObservation};
+pub use pinned_sites::TopSite;
 
@@ -0,0 +1,148 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Periodic maintenance for the places database. See
+//! `sql_support::maintenance` for how often to run it.
+
+use std::time::Duration;
+
+use failure;
+
+use annotations;
+use db::PlacesDb;
+use dedupe;
+use error::*;
+use icons;
+use sql_support;
+use sql_support::maintenance::Maintenance;
+use storage;
+use tombstones;
+use types::{SyncStatus, Timestamp};
+
+/// How long we keep tombstones for deleted history. Sync uploads them long
+/// before this, unless it's been disconnected, in which case the server has
+/// other things to worry about.
+const TOMBSTONE_RETENTION_MS: u64 = 60 * 24 * 60 * 60 * 1000;
+
+/// Merges duplicate pages, removes expired annotations and unused icons,
+/// prunes old tombstones, vacuums the database if it's fragmented, and
+/// checkpoints the WAL. `now` decides what's expired, and is only a parameter
+/// so that tests can control it.
+///
+/// If `history_retention` is given, visits older than that are expired too,
+/// along with pages that only have older visits, unless they're bookmarked.
+/// Otherwise, history is kept forever, since throwing it away is up to the
+/// app.
+pub fn run_maintenance(
+    db: &PlacesDb,
+    now: Timestamp,
+    history_retention: Option<Duration>,
+) -> Result<()> {
+    // Before removing icons, since it moves them to the pages it keeps.
+    let dupes = dedupe::dedupe_pages(db)?;
+    // Also before removing icons, since the pages it removes may have been
+    // the only ones using them.
+    let visits = match history_retention {
+        Some(retention) => {
+            let cutoff = now.checked_sub(retention).unwrap_or_default();
+            storage::expire_history(db, cutoff)?
+        }
+        None => 0,
+    };
+    let annos = annotations::expire_annotations(db, now)?;
+    let icons = icons::remove_orphan_icons(db)?;
+    let cutoff = Timestamp(now.0.saturating_sub(TOMBSTONE_RETENTION_MS));
+    let tombstones = tombstones::prune_tombstones(db, cutoff)?;
+    debug!("Maintenance removed {} duplicate pages, {} visits, {} annotations, {} icons and {} tombstones",
+           dupes, visits, annos, icons, tombstones);
+    sql_support::maintenance::vacuum_if_fragmented(db)?;
+    db.checkpoint()?;
+    Ok(())
+}
+
+impl Maintenance for PlacesDb {
+    fn run_maintenance(&self) -> ::std::result::Result<(), failure::Error> {
+        Ok(run_maintenance(self, Timestamp::now(), None)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use sql_support::ConnExt;
+    use storage::{apply_observation, get_page_info};
+    use types::VisitTransition;
+    use url::Url;
+
+    #[test]
+    fn test_prune_tombstones() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        conn.execute_batch("
+            INSERT INTO moz_places(guid, url, url_hash)
+            VALUES ('restoredAAAA', 'https://example.com/', hash('https://example.com/'))").unwrap();
+        conn.execute_named_cached("
+            INSERT INTO moz_places_tombstones(guid, time_deleted)
+            VALUES ('recentAAAAAA', :recent), ('oldAAAAAAAAA', :old), ('restoredAAAA', :recent)",
+            &[(":recent", &Timestamp(now.0 - 1000)), (":old", &Timestamp(now.0 - TOMBSTONE_RETENTION_MS - 1))])
+            .unwrap();
+
+        run_maintenance(&conn, now, None).expect("should run maintenance");
+        let guid: String = conn.query_one("SELECT guid FROM moz_places_tombstones").unwrap();
+        assert_eq!(guid, "recentAAAAAA");
+    }
+
+    #[test]
+    fn test_expire_history() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        let retention = Duration::from_secs(180 * 24 * 60 * 60);
+        let old = now.checked_sub(retention + Duration::from_secs(1)).unwrap();
+        let visit = |url: &str, visit_type: VisitTransition, at: Timestamp| {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(visit_type)
+                .with_at(at))
+                .expect("Should apply visit");
+        };
+        visit("https://example.com/old", VisitTransition::Typed, old);
+        visit("https://example.com/bookmarked", VisitTransition::Link, old);
+        visit("https://example.com/mixed", VisitTransition::Typed, old);
+        visit("https://example.com/mixed", VisitTransition::Link, Timestamp(now.0 - 1000));
+        conn.execute_batch(&format!("
+            UPDATE moz_places SET sync_status = {}, sync_change_counter = 0;
+            UPDATE moz_places SET foreign_count = 1 WHERE url = 'https://example.com/bookmarked'",
+            SyncStatus::Normal as u8)).unwrap();
+
+        // History is kept unless the app asks for it to be expired.
+        run_maintenance(&conn, now, None).expect("should run maintenance");
+        let visits: i64 = conn.query_one("SELECT COUNT(*) FROM moz_historyvisits").unwrap();
+        assert_eq!(visits, 4);
+
+        run_maintenance(&conn, now, Some(retention)).expect("should run maintenance");
+        let visits: i64 = conn.query_one("SELECT COUNT(*) FROM moz_historyvisits").unwrap();
+        assert_eq!(visits, 1);
+        assert!(get_page_info(&conn, &Url::parse("https://example.com/old").unwrap())
+            .unwrap().is_none());
+
+        // Bookmarked pages are kept, even without visits.
+        let bookmarked = get_page_info(&conn, &Url::parse("https://example.com/bookmarked").unwrap())
+            .unwrap().expect("should keep bookmarked page");
+        assert_eq!(bookmarked.visit_count_local, 0);
+
+        let mixed = get_page_info(&conn, &Url::parse("https://example.com/mixed").unwrap())
+            .unwrap().expect("should keep page with recent visits");
+        assert_eq!(mixed.visit_count_local, 1);
+        assert_eq!(mixed.typed, 0);
+        assert_eq!(mixed.sync_status, SyncStatus::Normal);
+
+        // Expiry isn't a deletion, so none of this should be synced.
+        let changed: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places WHERE sync_change_counter > 0").unwrap();
+        assert_eq!(changed, 0);
+        let tombstones: i64 = conn.query_one("
+            SELECT (SELECT COUNT(*) FROM moz_places_tombstones) +
+                   (SELECT COUNT(*) FROM moz_historyvisit_tombstones)").unwrap();
+        assert_eq!(tombstones, 0);
+    }
+}
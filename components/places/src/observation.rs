@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use types::*;
+use types_support::Guid;
 use url::{Url};
 use url_serde;
 
@@ -56,6 +57,18 @@ pub struct VisitObservation {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub is_remote: Option<bool>,
+
+    /// For `VisitTransition::Download` visits, where the file was saved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub download_path: Option<String>,
+
+    /// The GUID to give the page if it doesn't exist yet, so that migrated
+    /// pages keep the GUIDs that Sync already knows them by. Ignored for
+    /// pages that already exist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub guid: Option<Guid>,
 }
 
 impl VisitObservation {
@@ -69,7 +82,9 @@ impl VisitObservation {
             is_permanent_redirect_source: None,
             at: None,
             referrer: None,
-            is_remote: None
+            is_remote: None,
+            download_path: None,
+            guid: None,
         }
     }
 
@@ -116,9 +131,19 @@ impl VisitObservation {
         self
     }
 
+    pub fn with_download_path(mut self, v: impl Into<Option<String>>) -> Self {
+        self.download_path = v.into();
+        self
+    }
+
+    pub fn with_guid(mut self, v: impl Into<Option<Guid>>) -> Self {
+        self.guid = v.into();
+        self
+    }
+
     // Other helpers which can be derived.
     pub fn get_redirect_frecency_boost(&self) -> bool {
-        self.is_redirect_source.is_some() &&
+        self.is_redirect_source == Some(true) &&
         match self.visit_type {
             Some(t) => t != VisitTransition::Typed,
             _ => true,
@@ -129,7 +154,9 @@ impl VisitObservation {
     pub fn get_is_hidden(&self) -> bool {
         match self.visit_type {
             Some(visit_type) =>
-                self.is_redirect_source.is_some() ||
+                // Redirect sources are hidden, since the user never
+                // actually saw them - only the page they redirected to.
+                self.is_redirect_source == Some(true) ||
                 visit_type == VisitTransition::FramedLink ||
                 visit_type == VisitTransition::Embed,
             None => false,
@@ -0,0 +1,180 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Top sites for the new tab page, and the sites the user pinned there.
+//! Pinned sites always come first, in the order they were pinned, followed
+//! by the most frecent pages. Pins are kept by URL, so a pinned site stays
+//! pinned even if its history is deleted.
+
+use rusqlite::Row;
+use url::Url;
+use url_serde;
+
+use db::PlacesDb;
+use error::*;
+use host;
+use sql_support::ConnExt;
+use types::Timestamp;
+
+/// A site returned by `get_top_sites` or `get_pinned_sites`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopSite {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    /// The title of the page, or `None` if it isn't in history.
+    pub title: Option<String>,
+    pub pinned: bool,
+}
+
+impl TopSite {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked("title")?,
+            pinned: row.get_checked("pinned")?,
+        })
+    }
+}
+
+/// Pins `url` after the sites already pinned. Returns `false` if it was
+/// already pinned, in which case it keeps its position.
+pub fn pin_site(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let url = host::canonicalize_url(url);
+    let changes = db.execute_named_cached("
+        INSERT OR IGNORE INTO moz_pinned_sites(url, url_hash, position, pinned_at)
+        VALUES (:url, hash(:url),
+                (SELECT IFNULL(MAX(position) + 1, 0) FROM moz_pinned_sites),
+                :now)",
+        &[(":url", &url.as_str()), (":now", &Timestamp::now())])?;
+    Ok(changes > 0)
+}
+
+/// Unpins `url`. Returns `false` if it wasn't pinned.
+pub fn unpin_site(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let url = host::canonicalize_url(url);
+    let changes = db.execute_named_cached("
+        DELETE FROM moz_pinned_sites
+        WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())])?;
+    Ok(changes > 0)
+}
+
+/// Returns every pinned site, in the order they were pinned.
+pub fn get_pinned_sites(db: &impl ConnExt) -> Result<Vec<TopSite>> {
+    let mut stmt = db.conn().prepare_cached("
+        SELECT s.url, h.title, 1 AS pinned
+        FROM moz_pinned_sites s
+        LEFT JOIN moz_places h ON h.url_hash = s.url_hash AND h.url = s.url
+        ORDER BY s.position")?;
+    let results = stmt
+        .query_and_then_named(&[], TopSite::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(results)
+}
+
+/// Returns up to `limit` sites for the new tab page: the pinned sites, then
+/// the most frecent visible pages that aren't pinned. Pinned sites are
+/// included regardless of their frecency, and take up the first slots, so
+/// if there are more than `limit` of them, only pinned sites are returned.
+pub fn get_top_sites(db: &impl ConnExt, limit: u32) -> Result<Vec<TopSite>> {
+    let mut results = get_pinned_sites(db)?;
+    results.truncate(limit as usize);
+    let remaining = limit - results.len() as u32;
+    if remaining == 0 {
+        return Ok(results);
+    }
+    let mut stmt = db.conn().prepare_cached("
+        SELECT h.url, h.title, 0 AS pinned
+        FROM moz_places h
+        WHERE h.frecency > 0
+          AND h.hidden = 0
+          AND NOT EXISTS(
+              SELECT 1 FROM moz_pinned_sites s
+              WHERE s.url_hash = h.url_hash AND s.url = h.url)
+        ORDER BY h.frecency DESC, h.id DESC
+        LIMIT :limit")?;
+    let frecent = stmt
+        .query_and_then_named(&[(":limit", &remaining)], TopSite::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    results.extend(frecent);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use storage::{apply_observation, delete_visits_for};
+    use types::VisitTransition;
+
+    fn visit(conn: &PlacesDb, url: &str, times: usize) -> Url {
+        let url = Url::parse(url).unwrap();
+        for _ in 0..times {
+            apply_observation(conn, VisitObservation::new(url.clone())
+                .with_title(format!("Title of {}", url))
+                .with_visit_type(VisitTransition::Typed)).expect("should apply visit");
+        }
+        url
+    }
+
+    fn urls(sites: &[TopSite]) -> Vec<(&str, bool)> {
+        sites.iter().map(|s| (s.url.as_str(), s.pinned)).collect()
+    }
+
+    #[test]
+    fn test_pin_and_unpin() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let a = visit(&conn, "https://a.example.com/", 1);
+        let b = Url::parse("https://b.example.com/").unwrap();
+
+        assert!(pin_site(&conn, &b).unwrap());
+        assert!(pin_site(&conn, &a).unwrap());
+        assert!(!pin_site(&conn, &b).unwrap());
+
+        let pinned = get_pinned_sites(&conn).unwrap();
+        assert_eq!(urls(&pinned), vec![("https://b.example.com/", true), ("https://a.example.com/", true)]);
+        assert_eq!(pinned[0].title, None);
+        assert_eq!(pinned[1].title, Some("Title of https://a.example.com/".to_string()));
+
+        assert!(unpin_site(&conn, &b).unwrap());
+        assert!(!unpin_site(&conn, &b).unwrap());
+        assert_eq!(urls(&get_pinned_sites(&conn).unwrap()), vec![("https://a.example.com/", true)]);
+
+        // Pins outlive history.
+        delete_visits_for(&conn, &a, Timestamp(0)).unwrap();
+        assert_eq!(urls(&get_pinned_sites(&conn).unwrap()), vec![("https://a.example.com/", true)]);
+    }
+
+    #[test]
+    fn test_top_sites() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        visit(&conn, "https://often.example.com/", 5);
+        let sometimes = visit(&conn, "https://sometimes.example.com/", 3);
+        visit(&conn, "https://rarely.example.com/", 1);
+        let never = Url::parse("https://never.example.com/").unwrap();
+
+        assert_eq!(urls(&get_top_sites(&conn, 10).unwrap()), vec![
+            ("https://often.example.com/", false),
+            ("https://sometimes.example.com/", false),
+            ("https://rarely.example.com/", false),
+        ]);
+
+        pin_site(&conn, &never).unwrap();
+        pin_site(&conn, &sometimes).unwrap();
+        assert_eq!(urls(&get_top_sites(&conn, 10).unwrap()), vec![
+            ("https://never.example.com/", true),
+            ("https://sometimes.example.com/", true),
+            ("https://often.example.com/", false),
+            ("https://rarely.example.com/", false),
+        ]);
+        assert_eq!(urls(&get_top_sites(&conn, 3).unwrap()), vec![
+            ("https://never.example.com/", true),
+            ("https://sometimes.example.com/", true),
+            ("https://often.example.com/", false),
+        ]);
+        assert_eq!(urls(&get_top_sites(&conn, 1).unwrap()), vec![
+            ("https://never.example.com/", true),
+        ]);
+    }
+}
@@ -0,0 +1,133 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pages visited in private tabs must never be written to disk, but it's
+//! still useful for them to show up in autocomplete for the rest of the
+//! private browsing session. We keep them in a separate in-memory database,
+//! which is searched alongside the real one, and thrown away when the
+//! session ends.
+
+use std::collections::HashSet;
+
+use api::matcher::{self, SearchParams, SearchResult};
+use db::PlacesDb;
+use error::*;
+use observation::VisitObservation;
+use storage;
+use url_policy::UrlPolicy;
+
+pub struct PrivateBrowsingStore {
+    db: PlacesDb,
+}
+
+impl PrivateBrowsingStore {
+    pub fn new() -> Result<Self> {
+        Ok(Self { db: PlacesDb::open_in_memory(None)? })
+    }
+
+    /// Use the same policy as the disk database, so that private browsing
+    /// doesn't record pages that normal browsing wouldn't.
+    pub fn set_url_policy(&mut self, policy: UrlPolicy) {
+        self.db.set_url_policy(policy);
+    }
+
+    /// Records a visit or page update from a private tab.
+    pub fn note_observation(&self, visit: VisitObservation) -> Result<()> {
+        storage::apply_observation(&self.db, visit)?;
+        Ok(())
+    }
+
+    /// Searches both the private pages and `disk`, returning the private
+    /// matches first, since they're the most recent. URLs which match in
+    /// both are only returned once, and there are at most `params.limit`
+    /// results in total.
+    pub fn search_frecent(&self, disk: &PlacesDb, params: SearchParams) -> Result<Vec<SearchResult>> {
+        let limit = params.limit as usize;
+        let private_matches = matcher::search_frecent(&self.db, params.clone())?;
+        let disk_matches = matcher::search_frecent(disk, params)?;
+        let mut seen = HashSet::new();
+        Ok(private_matches.into_iter()
+            .chain(disk_matches)
+            .filter(|m| seen.insert(m.url.to_string()))
+            .take(limit)
+            .collect())
+    }
+
+    /// Forgets everything from the current session.
+    pub fn clear(&mut self) -> Result<()> {
+        let mut db = PlacesDb::open_in_memory(None)?;
+        db.set_url_policy(self.db.url_policy().clone());
+        // Dropping the old connection frees its memory.
+        self.db = db;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{Timestamp, VisitTransition};
+    use url::Url;
+
+    fn visit(db: &PlacesDb, url: &str) {
+        let obs = VisitObservation::new(Url::parse(url).unwrap())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now());
+        storage::apply_observation(db, obs).unwrap();
+    }
+
+    fn search(store: &PrivateBrowsingStore, disk: &PlacesDb, s: &str) -> Vec<String> {
+        search_with_limit(store, disk, s, 10)
+    }
+
+    fn search_with_limit(store: &PrivateBrowsingStore, disk: &PlacesDb, s: &str, limit: u32) -> Vec<String> {
+        store.search_frecent(disk, SearchParams { search_string: s.into(), limit })
+            .unwrap()
+            .into_iter()
+            .map(|m| m.url.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_private_browsing() {
+        let disk = PlacesDb::open_in_memory(None).unwrap();
+        let mut store = PrivateBrowsingStore::new().unwrap();
+        visit(&disk, "https://example.com/");
+        store.note_observation(VisitObservation::new(Url::parse("https://private.example.org/").unwrap())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now())).unwrap();
+
+        let results = search(&store, &disk, "private.example.org");
+        assert!(results.contains(&"https://private.example.org/".to_string()));
+        let results = search(&store, &disk, "example.com");
+        assert!(results.contains(&"https://example.com/".to_string()));
+        // Nothing was written to disk.
+        let private_url = Url::parse("https://private.example.org/").unwrap();
+        assert_eq!(storage::get_visited(&disk, &[private_url]).unwrap(), vec![false]);
+
+        // Visiting a page in both shouldn't return it twice.
+        visit(&store.db, "https://example.com/");
+        let results = search(&store, &disk, "example.com");
+        assert_eq!(results.iter().filter(|url| *url == "https://example.com/").count(), 1);
+
+        store.clear().unwrap();
+        assert!(search(&store, &disk, "private.example.org").is_empty());
+    }
+
+    #[test]
+    fn test_private_browsing_limit() {
+        let disk = PlacesDb::open_in_memory(None).unwrap();
+        let store = PrivateBrowsingStore::new().unwrap();
+        for i in 0..3 {
+            visit(&disk, &format!("https://example.com/disk{}", i));
+            visit(&store.db, &format!("https://example.com/private{}", i));
+        }
+        // Each database has enough matches to fill the limit on its own, but
+        // we should only get `limit` between them, with the private ones
+        // first.
+        let results = search_with_limit(&store, &disk, "example.com", 4);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.iter().filter(|url| url.contains("private")).count(), 3);
+    }
+}
@@ -0,0 +1,170 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Property tests: we apply random sequences of observations and deletions,
+// and check that the denormalized columns of `moz_places` still agree with
+// the visits. The urls are drawn from a small set, so that operations often
+// hit the same pages.
+
+use std::collections::{HashMap, HashSet};
+
+use proptest::prelude::*;
+use url::Url;
+
+use api::apply_observation;
+use db::PlacesDb;
+use observation::VisitObservation;
+use storage::{delete_visits_for, delete_visits_for_origin};
+use types::{Timestamp, VisitTransition};
+use types_support::Guid;
+
+// Somewhere in 2017, so that the dates are plausible.
+const BASE_DATE: u64 = 1_500_000_000_000;
+
+const HOSTS: &[&str] = &["example.com", "www.example.com", "EXAMPLE.org:8080", "bücher.example", "127.0.0.1"];
+
+#[derive(Debug, Clone)]
+enum Op {
+    Visit(Url, VisitTransition, Timestamp, bool, bool, Option<Guid>),
+    SetTitle(Url, String),
+    DeleteVisitsFor(Url, Timestamp),
+    DeleteVisitsForOrigin(Url),
+}
+
+fn url_strategy() -> impl Strategy<Value = Url> {
+    (0..HOSTS.len(), prop::bool::ANY, "[a-zA-Z0-9._~-]{0,8}")
+        .prop_map(|(host, https, path)| {
+            let scheme = if https { "https" } else { "http" };
+            Url::parse(&format!("{}://{}/{}", scheme, HOSTS[host], path)).unwrap()
+        })
+}
+
+fn timestamp_strategy() -> impl Strategy<Value = Timestamp> {
+    (0u64..1_000_000).prop_map(|offset| Timestamp(BASE_DATE + offset))
+}
+
+fn visit_type_strategy() -> impl Strategy<Value = VisitTransition> {
+    (1u8..10).prop_map(|t| VisitTransition::from_primitive(t).unwrap())
+}
+
+// Mostly valid GUIDs, but some that aren't, which we should replace.
+fn guid_strategy() -> impl Strategy<Value = Option<Guid>> {
+    prop_oneof![
+        Just(None),
+        "[a-zA-Z0-9_-]{12}".prop_map(|s| Some(Guid::new(&s))),
+        ".{0,16}".prop_map(|s| Some(Guid::new(&s))),
+    ]
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (url_strategy(), visit_type_strategy(), timestamp_strategy(),
+              prop::bool::ANY, prop::bool::ANY, guid_strategy())
+            .prop_map(|(url, visit_type, at, is_remote, is_redirect_source, guid)|
+                Op::Visit(url, visit_type, at, is_remote, is_redirect_source, guid)),
+        1 => (url_strategy(), ".{0,20}").prop_map(|(url, title)| Op::SetTitle(url, title)),
+        1 => (url_strategy(), timestamp_strategy()).prop_map(|(url, since)| Op::DeleteVisitsFor(url, since)),
+        1 => url_strategy().prop_map(Op::DeleteVisitsForOrigin),
+    ]
+}
+
+fn apply(db: &PlacesDb, op: Op) {
+    match op {
+        Op::Visit(url, visit_type, at, is_remote, is_redirect_source, guid) => {
+            apply_observation(db, VisitObservation::new(url)
+                .with_visit_type(visit_type)
+                .with_at(at)
+                .with_is_remote(is_remote)
+                .with_is_redirect_source(is_redirect_source)
+                .with_guid(guid)).expect("should apply observation");
+        }
+        Op::SetTitle(url, title) => {
+            apply_observation(db, VisitObservation::new(url).with_title(title))
+                .expect("should set title");
+        }
+        Op::DeleteVisitsFor(url, since) => {
+            delete_visits_for(db, &url, since).expect("should delete visits");
+        }
+        Op::DeleteVisitsForOrigin(url) => {
+            delete_visits_for_origin(db, &url).expect("should delete origin");
+        }
+    }
+}
+
+// Visits of these types don't count towards the visit counts.
+fn is_excluded(visit_type: u8) -> bool {
+    match VisitTransition::from_primitive(visit_type) {
+        Some(VisitTransition::Embed) | Some(VisitTransition::Download) |
+        Some(VisitTransition::FramedLink) | Some(VisitTransition::Reload) | None => true,
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Expected {
+    visit_count_local: i64,
+    visit_count_remote: i64,
+    last_visit_date_local: i64,
+    last_visit_date_remote: i64,
+    has_visible_visit: bool,
+}
+
+fn check_invariants(db: &PlacesDb) -> Result<(), TestCaseError> {
+    let mut expected: HashMap<i64, Expected> = HashMap::new();
+    let mut stmt = db.prepare("SELECT place_id, visit_type, is_local, visit_date FROM moz_historyvisits").unwrap();
+    let visits = stmt.query_map(&[], |row| {
+        (row.get::<_, i64>(0), row.get::<_, u8>(1), row.get::<_, bool>(2), row.get::<_, i64>(3))
+    }).unwrap();
+    for visit in visits {
+        let (place_id, visit_type, is_local, visit_date) = visit.unwrap();
+        let e = expected.entry(place_id).or_insert_with(Expected::default);
+        let counts = !is_excluded(visit_type);
+        if is_local {
+            e.visit_count_local += counts as i64;
+            e.last_visit_date_local = e.last_visit_date_local.max(visit_date);
+        } else {
+            e.visit_count_remote += counts as i64;
+            e.last_visit_date_remote = e.last_visit_date_remote.max(visit_date);
+        }
+        e.has_visible_visit |= visit_type != VisitTransition::FramedLink as u8 &&
+                               visit_type != VisitTransition::Embed as u8;
+    }
+
+    let mut stmt = db.prepare("
+        SELECT id, guid, frecency, hidden, visit_count_local, visit_count_remote,
+               last_visit_date_local, last_visit_date_remote
+        FROM moz_places").unwrap();
+    let pages = stmt.query_map(&[], |row| {
+        (row.get::<_, i64>(0), row.get::<_, Guid>(1), row.get::<_, i64>(2), row.get::<_, bool>(3),
+         row.get::<_, i64>(4), row.get::<_, i64>(5), row.get::<_, i64>(6), row.get::<_, i64>(7))
+    }).unwrap();
+    let mut guids = HashSet::new();
+    for page in pages {
+        let (id, guid, frecency, hidden, count_local, count_remote, last_local, last_remote) = page.unwrap();
+        let e = expected.remove(&id).unwrap_or_default();
+        prop_assert!(guid.is_valid_for_places(), "invalid guid {:?}", guid);
+        prop_assert!(guids.insert(guid.clone()), "duplicate guid {:?}", guid);
+        prop_assert!(frecency >= 0 || frecency == -1, "frecency {} for {:?}", frecency, guid);
+        prop_assert!(hidden || e.has_visible_visit, "{:?} is visible without a visible visit", guid);
+        prop_assert_eq!(count_local, e.visit_count_local);
+        prop_assert_eq!(count_remote, e.visit_count_remote);
+        prop_assert_eq!(last_local, e.last_visit_date_local);
+        prop_assert_eq!(last_remote, e.last_visit_date_remote);
+    }
+    prop_assert!(expected.is_empty(), "visits for missing pages {:?}", expected.keys().collect::<Vec<_>>());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_random_operations(ops in prop::collection::vec(op_strategy(), 1..40)) {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        for op in ops {
+            apply(&db, op);
+            check_invariants(&db)?;
+        }
+    }
+}
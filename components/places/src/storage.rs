@@ -7,11 +7,17 @@
 // This should probably be a sub-directory
 
 use std::{fmt};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use url::{Url};
-use types::{SyncGuid, Timestamp, VisitTransition};
-use error::{Result};
+use types::{SyncStatus, Timestamp, VisitTransition, VisitTransitionSet};
+use types_support::Guid;
+use error::{InvalidPlaceInfo, Result};
 use observation::{VisitObservation};
-use frecency;
+use frecency::{self, FrecencySettings};
+use url_policy::UrlPolicy;
+use annotations::{self, AnnoExpiration, AnnoValue};
 
 use rusqlite::{Row, Connection};
 use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, ValueRef}};
@@ -19,10 +25,19 @@ use rusqlite::Result as RusqliteResult;
 
 use db::PlacesDb;
 use hash;
+use host;
 use sql_support::{self, ConnExt};
+use url_serde;
+
+/// The name of the annotation storing where a download was saved. This is the
+/// same name desktop uses.
+const DOWNLOAD_DESTINATION_ANNO: &str = "downloads/destinationFileURI";
+
+/// The longest redirect chain `fetch_visit_redirect_chain` will walk.
+const MAX_REDIRECT_CHAIN_DEPTH: u32 = 50;
 
 // Typesafe way to manage RowIds. Does it make sense? A better way?
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Deserialize, Serialize, Default)]
 pub struct RowId(pub i64);
 
 impl From<RowId> for i64 { // XXX - ToSql!
@@ -49,10 +64,15 @@ impl FromSql for RowId {
     }
 }
 
-#[derive(Debug)]
+/// Everything we know about a page, as returned by `get_page_info`. Pages
+/// which are only bookmarked have no visits, so their visit counts and dates
+/// are 0.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct PageInfo {
+    #[serde(with = "url_serde")]
     pub url: Url,
-    pub guid: SyncGuid,
+    pub guid: Guid,
+    #[serde(skip)]
     pub row_id: RowId,
     pub title: String,
     pub hidden: bool,
@@ -62,13 +82,18 @@ pub struct PageInfo {
     pub visit_count_remote: i32,
     pub last_visit_date_local: Timestamp,
     pub last_visit_date_remote: Timestamp,
+    #[serde(skip)]
+    pub sync_status: SyncStatus,
+    /// The number of local changes since the page was last uploaded.
+    #[serde(skip)]
+    pub sync_change_counter: u32,
 }
 
 impl PageInfo {
     pub fn from_row(row: &Row) -> Result<Self> {
         Ok(Self {
             url: Url::parse(&row.get_checked::<_, Option<String>>("url")?.expect("non null column"))?,
-            guid: SyncGuid(row.get_checked::<_, Option<String>>("guid")?.expect("non null column")),
+            guid: row.get_checked::<_, Option<Guid>>("guid")?.expect("non null column"),
             row_id: row.get_checked("id")?,
             title: row.get_checked::<_, Option<String>>("title")?.unwrap_or_default(),
             hidden: row.get_checked("hidden")?,
@@ -82,6 +107,9 @@ impl PageInfo {
                 "last_visit_date_local")?.unwrap_or_default(),
             last_visit_date_remote: row.get_checked::<_, Option<Timestamp>>(
                 "last_visit_date_remote")?.unwrap_or_default(),
+
+            sync_status: row.get_checked("sync_status")?,
+            sync_change_counter: row.get_checked("sync_change_counter")?,
         })
     }
 }
@@ -91,15 +119,16 @@ impl PageInfo {
 struct FetchedPageInfo {
     page: PageInfo,
     // XXX - not clear what this is used for yet, and whether it should be local, remote or either?
-    // The sql below isn't quite sure either :)
-    last_visit_id: RowId,
+    // The sql below isn't quite sure either :) `None` if the page has no
+    // visits, which is the case for bookmarks which were never visited.
+    last_visit_id: Option<RowId>,
 }
 
 impl FetchedPageInfo {
     pub fn from_row(row: &Row) -> Result<Self> {
         Ok(Self {
             page: PageInfo::from_row(row)?,
-            last_visit_id: row.get_checked::<_, Option<RowId>>("last_visit_id")?.expect("No visit id!"),
+            last_visit_id: row.get_checked("last_visit_id")?,
         })
     }
 }
@@ -110,32 +139,119 @@ fn fetch_page_info(db: &impl ConnExt, url: &Url) -> Result<Option<FetchedPageInf
       SELECT guid, url, id, title, hidden, typed, frecency,
              visit_count_local, visit_count_remote,
              last_visit_date_local, last_visit_date_remote,
+             sync_status, sync_change_counter,
       (SELECT id FROM moz_historyvisits
        WHERE place_id = h.id
          AND (visit_date = h.last_visit_date_local OR
               visit_date = h.last_visit_date_remote)) AS last_visit_id
       FROM moz_places h
       WHERE url_hash = hash(:page_url) AND url = :page_url";
-    Ok(db.try_query_row(sql, &[(":page_url", &url.clone().into_string())], FetchedPageInfo::from_row, true)?)
+    let url = host::canonicalize_url(url);
+    Ok(db.try_query_row(sql, &[(":page_url", &url.as_str())], FetchedPageInfo::from_row, true)?)
+}
+
+/// Returns the page for `url`, with its title, visit counts and last visit
+/// dates, or `None` if it's not in history or bookmarks. This is what the
+/// "page info" sheet shows.
+pub fn get_page_info(db: &impl ConnExt, url: &Url) -> Result<Option<PageInfo>> {
+    Ok(fetch_page_info(db, url)?.map(|info| info.page))
+}
+
+/// Returns the number of visits (local and remote) to `url`, not counting
+/// visits with any of the transition types in `exclude_types`. Unknown urls
+/// have zero visits.
+pub fn get_visit_count(db: &impl ConnExt, url: &Url, exclude_types: VisitTransitionSet) -> Result<u32> {
+    let excluded = sql_transition_list(exclude_types);
+    let sql = format!("
+        SELECT COUNT(*)
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE h.url_hash = hash(:url) AND h.url = :url
+          AND v.visit_type NOT IN ({})", excluded);
+    let url = host::canonicalize_url(url);
+    Ok(db.query_row_and_then_named(&sql, &[(":url", &url.as_str())],
+                                   |row| row.get_checked(0), false)?)
+}
+
+// The values of the transitions in `types`, separated by commas, for use
+// with `IN (...)`.
+fn sql_transition_list(types: VisitTransitionSet) -> String {
+    let types = types.transitions().collect::<Vec<_>>();
+    sql_support::repeat_display(types.len(), ",", |i, f| write!(f, "{}", types[i] as u8)).to_string()
+}
+
+/// Information about a single visit to a page.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisitInfo {
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+    pub is_local: bool,
+}
+
+impl VisitInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            visit_date: row.get_checked("visit_date")?,
+            visit_type: row.get_checked("visit_type")?,
+            is_local: row.get_checked("is_local")?,
+        })
+    }
+}
+
+/// Returns the most recent visit to `url` (local or remote), or None if it
+/// has never been visited.
+pub fn get_latest_visit(db: &impl ConnExt, url: &Url) -> Result<Option<VisitInfo>> {
+    let sql = "
+        SELECT v.visit_date, v.visit_type, v.is_local
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE h.url_hash = hash(:url) AND h.url = :url
+        ORDER BY v.visit_date DESC
+        LIMIT 1";
+    let url = host::canonicalize_url(url);
+    Ok(db.try_query_row(sql, &[(":url", &url.as_str())], VisitInfo::from_row, true)?)
 }
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
-pub fn apply_observation(db: &mut PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
-    let tx = db.db.transaction()?;
-    let result = apply_observation_direct(tx.conn(), visit_ob)?;
+pub fn apply_observation(db: &PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
+    let tx = db.begin_transaction()?;
+    let result = apply_observation_direct(tx.conn(), db.url_policy(), db.frecency_settings(), visit_ob)?;
     tx.commit()?;
     Ok(result)
 }
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
-pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) -> Result<Option<RowId>> {
+/// Observations of URLs which `policy` rejects are ignored.
+pub fn apply_observation_direct(
+    db: &Connection,
+    policy: &UrlPolicy,
+    settings: &FrecencySettings,
+    visit_ob: VisitObservation,
+) -> Result<Option<RowId>> {
+    if !policy.can_add_url(&visit_ob.url) {
+        debug!("Ignoring observation of a URL rejected by the URL policy");
+        return Ok(None);
+    }
+    // Title changes are much more common than other non-visit observations,
+    // and don't need anything but the page itself.
+    if visit_ob.visit_type.is_none() && visit_ob.download_path.is_none() {
+        if let Some(ref title) = visit_ob.title {
+            set_page_title_direct(db, policy, &visit_ob.url, policy.truncate_title(title), visit_ob.guid.clone())?;
+            return Ok(None);
+        }
+    }
     let mut page_info = match fetch_page_info(db, &visit_ob.url)? {
         Some(info) => info.page,
-        None => new_page_info(db, &visit_ob.url)?,
+        None => new_page_info(db, policy, &visit_ob.url, visit_ob.guid.clone())?,
     };
     let mut updates: Vec<(&str, &str, &ToSql)> = Vec::new();
+    // Whether this observation changes something sync uploads: the title,
+    // or a new local visit.
+    let mut is_sync_change = false;
     if let Some(ref title) = visit_ob.title {
-        page_info.title = title.clone();
+        let title = policy.truncate_title(title);
+        is_sync_change |= page_info.title != title;
+        page_info.title = title.to_owned();
         updates.push(("title", ":title", &page_info.title));
     }
 
@@ -149,14 +265,26 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
             if !visit_ob.get_is_hidden() {
                 updates.push(("hidden", ":hidden", &false));
             }
-            if visit_type == VisitTransition::Typed {
+
+            // A visit from a device whose clock is ahead would otherwise
+            // stay at the top of history until the real time caught up.
+            let now = Timestamp::now();
+            let at = visit_ob.at.map_or(now, |at| at.clamp_future(now));
+            let is_remote = visit_ob.is_remote.unwrap_or(false);
+            // Link the visit to the latest visit of the referrer, which for a
+            // redirect is the visit to the redirect source. This is what lets
+            // frecency score a redirect target as if its source was visited,
+            // and what `fetch_visit_redirect_chain` walks.
+            let from_visit = match visit_ob.referrer {
+                Some(ref referrer) => find_latest_visit_id(db, referrer)?,
+                None => None,
+            };
+            if counts_as_typed(db, settings, &visit_ob, visit_type, from_visit)? {
                 page_info.typed += 1;
                 updates.push(("typed", ":typed", &page_info.typed));
             }
-
-            let at = visit_ob.at.unwrap_or_else(|| Timestamp::now());
-            let is_remote = visit_ob.is_remote.unwrap_or(false);
-            let row_id = add_visit(db, &page_info.row_id, &None, &at, &visit_type, &!is_remote)?;
+            let row_id = add_visit(db, &page_info.row_id, &from_visit, &at, &visit_type, &!is_remote)?;
+            is_sync_change |= !is_remote;
             // a new visit implies new frecency except in error cases.
             if !visit_ob.is_error.unwrap_or(false) {
                 update_frecency = true;
@@ -166,6 +294,16 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
         None => None,
     };
 
+    if let Some(ref path) = visit_ob.download_path {
+        annotations::set_page_annotation_by_id(db, page_info.row_id, DOWNLOAD_DESTINATION_ANNO,
+                                               &AnnoValue::Text(path.clone()), AnnoExpiration::Never)?;
+    }
+
+    if is_sync_change {
+        page_info.sync_change_counter += 1;
+        updates.push(("sync_change_counter", ":sync_change_counter", &page_info.sync_change_counter));
+    }
+
     if updates.len() != 0 {
         let mut params: Vec<(&str, &ToSql)> = Vec::with_capacity(updates.len() + 1);
         let mut sets: Vec<String> = Vec::with_capacity(updates.len());
@@ -182,7 +320,7 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
     // This needs to happen after the other updates.
     if update_frecency {
         page_info.frecency = frecency::calculate_frecency(db,
-            &frecency::DEFAULT_FRECENCY_SETTINGS,
+            settings,
             page_info.row_id.0, // TODO: calculate_frecency should take a RowId here.
             Some(visit_ob.get_redirect_frecency_boost()))?;
         let sql = "
@@ -198,17 +336,153 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
     Ok(visit_row_id)
 }
 
-fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
-    let guid = super::sync::util::random_guid().expect("according to logins-sql, this is fine :)");
-    let sql = "INSERT INTO moz_places (guid, url, url_hash)
-               VALUES (:guid, :url, hash(:url))";
+// Whether a visit should increment the `typed` count of its page. Usually
+// that's just whether it was typed, but if `settings` says so, typing a URL
+// which redirects counts towards the page the redirects end at instead: the
+// sources are hidden, so they'd never be suggested anyway.
+fn counts_as_typed(
+    db: &Connection,
+    settings: &FrecencySettings,
+    visit_ob: &VisitObservation,
+    visit_type: VisitTransition,
+    from_visit: Option<RowId>,
+) -> Result<bool> {
+    if !settings.typed_redirect_boosts_target {
+        return Ok(visit_type == VisitTransition::Typed);
+    }
+    if visit_ob.is_redirect_source == Some(true) {
+        // Either the start or the middle of a chain, so the typed visit
+        // (if any) is counted when we see the target.
+        return Ok(false);
+    }
+    match (visit_type, from_visit) {
+        (VisitTransition::RedirectPermanent, Some(from_visit)) |
+        (VisitTransition::RedirectTemporary, Some(from_visit)) => {
+            let chain = fetch_visit_redirect_chain(db, from_visit)?;
+            Ok(chain.first().map(|entry| entry.visit_type) == Some(VisitTransition::Typed))
+        }
+        _ => Ok(visit_type == VisitTransition::Typed),
+    }
+}
+
+// Counts the visits to `page_id` which `counts_as_typed` would have counted,
+// so that removing some visits leaves the typed count the page would have had
+// if they'd never been added.
+fn count_typed_visits(db: &impl ConnExt, settings: &FrecencySettings, page_id: RowId) -> Result<u32> {
+    if !settings.typed_redirect_boosts_target {
+        return Ok(db.conn().query_row_named(
+            "SELECT COUNT(*) FROM moz_historyvisits WHERE place_id = :page_id AND visit_type = :typed",
+            &[(":page_id", &page_id), (":typed", &VisitTransition::Typed)],
+            |row| row.get(0))?);
+    }
+    // Visits which redirected somewhere else are redirect sources, which
+    // never count.
+    let mut stmt = db.conn().prepare_cached("
+        SELECT v.visit_type, v.from_visit
+        FROM moz_historyvisits v
+        WHERE v.place_id = :page_id
+          AND NOT EXISTS(SELECT 1 FROM moz_historyvisits r
+                         WHERE r.from_visit = v.id
+                           AND r.visit_type IN (:redirect_permanent, :redirect_temporary))")?;
+    let visits = stmt.query_and_then_named(&[
+        (":page_id", &page_id),
+        (":redirect_permanent", &VisitTransition::RedirectPermanent),
+        (":redirect_temporary", &VisitTransition::RedirectTemporary),
+    ], |row| -> Result<(VisitTransition, Option<RowId>)> {
+        Ok((row.get_checked("visit_type")?, row.get_checked("from_visit")?))
+    })?;
+    let mut typed = 0;
+    for visit in visits {
+        let counts = match visit? {
+            (VisitTransition::RedirectPermanent, Some(from_visit)) |
+            (VisitTransition::RedirectTemporary, Some(from_visit)) => {
+                let chain = fetch_visit_redirect_chain(db, from_visit)?;
+                chain.first().map(|entry| entry.visit_type) == Some(VisitTransition::Typed)
+            }
+            (visit_type, _) => visit_type == VisitTransition::Typed,
+        };
+        if counts {
+            typed += 1;
+        }
+    }
+    Ok(typed)
+}
+
+/// Sets the title of the page for `url`, adding the page (without any visits)
+/// if it doesn't exist. Unlike `apply_observation`, this never adds visits or
+/// recalculates frecency. URLs which the URL policy rejects are ignored.
+pub fn set_page_title(db: &PlacesDb, url: &Url, title: &str) -> Result<()> {
+    if !db.url_policy().can_add_url(url) {
+        debug!("Ignoring title for a URL rejected by the URL policy");
+        return Ok(());
+    }
+    let tx = db.begin_transaction()?;
+    set_page_title_direct(tx.conn(), db.url_policy(), url, db.url_policy().truncate_title(title), None)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn set_page_title_direct(
+    db: &impl ConnExt,
+    policy: &UrlPolicy,
+    url: &Url,
+    title: &str,
+    new_guid: Option<Guid>,
+) -> Result<()> {
+    let url = host::canonicalize_url(url);
+    let changed = db.execute_named_cached("
+        UPDATE moz_places
+        SET title = :title,
+            sync_change_counter = sync_change_counter + (IFNULL(title, '') != :title)
+        WHERE url_hash = hash(:url) AND url = :url",
+        &[(":title", &title), (":url", &url.as_str())])?;
+    if changed == 0 {
+        let page_id = new_page_info(db, policy, &url, new_guid)?.row_id;
+        db.execute_named_cached(
+            "UPDATE moz_places SET title = :title WHERE id = :page_id",
+            &[(":title", &title), (":page_id", &page_id)])?;
+    }
+    Ok(())
+}
+
+/// Adds a page for `url`, with the given GUID if it's valid and not already
+/// used by another page, or a new one otherwise. Bookmarks and annotations
+/// add pages here too, so this only rejects URLs which are too long, and
+/// leaves blocked schemes to the history callers.
+fn new_page_info(db: &impl ConnExt, policy: &UrlPolicy, url: &Url, new_guid: Option<Guid>) -> Result<PageInfo> {
+    if policy.is_url_too_long(url) {
+        return Err(InvalidPlaceInfo::UrlTooLong.into());
+    }
+    let guid = match new_guid {
+        Some(guid) => {
+            if !guid.is_valid_for_places() {
+                warn!("Ignoring invalid GUID for new page");
+                Guid::random()
+            } else if db.query_row_and_then_named(
+                "SELECT EXISTS(SELECT 1 FROM moz_places WHERE guid = :guid)",
+                &[(":guid", &guid)],
+                |row| row.get_checked::<_, bool>(0),
+                true,
+            )? {
+                warn!("Ignoring GUID for new page, since another page already has it");
+                Guid::random()
+            } else {
+                guid
+            }
+        }
+        None => Guid::random(),
+    };
+    let url = host::canonicalize_url(url).into_owned();
+    let sql = "INSERT INTO moz_places (guid, url, url_hash, rev_host)
+               VALUES (:guid, :url, hash(:url), :rev_host)";
     db.execute_named_cached(sql, &[
         (":guid", &guid),
-        (":url", &url.clone().into_string()),
+        (":url", &url.as_str()),
+        (":rev_host", &host::rev_host(&url)),
     ])?;
     Ok(PageInfo {
-        url: url.clone(),
-        guid: SyncGuid(guid),
+        url,
+        guid,
         row_id: RowId(db.conn().last_insert_rowid()),
         title: "".into(),
         hidden: true, // will be set to false as soon as a non-hidden visit appears.
@@ -218,9 +492,84 @@ fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
         visit_count_remote: 0,
         last_visit_date_local: Timestamp(0),
         last_visit_date_remote: Timestamp(0),
+        sync_status: SyncStatus::New,
+        sync_change_counter: 1,
     })
 }
 
+fn find_latest_visit_id(db: &impl ConnExt, url: &Url) -> Result<Option<RowId>> {
+    let sql = "
+        SELECT v.id
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE h.url_hash = hash(:url) AND h.url = :url
+        ORDER BY v.visit_date DESC
+        LIMIT 1";
+    let url = host::canonicalize_url(url);
+    Ok(db.try_query_row(sql, &[(":url", &url.as_str())], |row| -> Result<_> {
+        Ok(RowId(row.get_checked(0)?))
+    }, true)?)
+}
+
+/// A single visit in a redirect chain, as returned by
+/// `fetch_visit_redirect_chain`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RedirectChainEntry {
+    pub visit_id: RowId,
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+}
+
+impl RedirectChainEntry {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url: String = row.get_checked("url")?;
+        Ok(Self {
+            visit_id: RowId(row.get_checked("id")?),
+            url: Url::parse(&url)?,
+            visit_date: row.get_checked("visit_date")?,
+            visit_type: row.get_checked("visit_type")?,
+        })
+    }
+}
+
+/// Returns the redirect chain which ended in the visit `visit_id`, ordered
+/// from the first redirect source to `visit_id` itself. We follow `from_visit`
+/// for as long as the visit is a redirect target, so a visit which wasn't
+/// the result of a redirect is returned alone. Returns an empty Vec if there's
+/// no such visit.
+pub fn fetch_visit_redirect_chain(db: &impl ConnExt, visit_id: RowId) -> Result<Vec<RedirectChainEntry>> {
+    // The depth limit protects us against cycles, which shouldn't exist, but
+    // `from_visit` isn't a real foreign key so we can't rule them out.
+    let sql = format!("
+        WITH RECURSIVE chain(id, from_visit, visit_type, depth) AS (
+            SELECT id, from_visit, visit_type, 0
+            FROM moz_historyvisits
+            WHERE id = :visit_id
+            UNION ALL
+            SELECT v.id, v.from_visit, v.visit_type, c.depth + 1
+            FROM moz_historyvisits v
+            JOIN chain c ON v.id = c.from_visit
+            WHERE c.visit_type IN ({redirect_permanent}, {redirect_temporary})
+              AND c.depth < {max_depth}
+        )
+        SELECT v.id, h.url, v.visit_date, v.visit_type
+        FROM chain c
+        JOIN moz_historyvisits v ON v.id = c.id
+        JOIN moz_places h ON h.id = v.place_id
+        ORDER BY c.depth DESC",
+        redirect_permanent = VisitTransition::RedirectPermanent as u8,
+        redirect_temporary = VisitTransition::RedirectTemporary as u8,
+        max_depth = MAX_REDIRECT_CHAIN_DEPTH,
+    );
+    let mut stmt = db.conn().prepare_cached(&sql)?;
+    let chain = stmt
+        .query_and_then_named(&[(":visit_id", &visit_id)], RedirectChainEntry::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(chain)
+}
+
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with the new visit count, frecency, etc.
@@ -245,10 +594,196 @@ fn add_visit(db: &impl ConnExt,
     Ok(RowId(rid))
 }
 
+/// A page to insert with `insert_pages_bulk`: its url, title, and visits. Each
+/// visit is given as `(visit_date, visit_type, is_local)`.
+pub type BulkPage = (Url, Option<String>, Vec<(Timestamp, VisitTransition, bool)>);
+
+/// Inserts many pages and visits at once, for example when importing history
+/// from another browser. This is much faster than applying an observation
+/// for each visit, as we insert using multi-row INSERTs, and only calculate
+/// the frecency of each page once, after all visits have been added. Note
+/// that, unlike observations, this doesn't support redirect information.
+/// Pages with URLs which the URL policy rejects are skipped.
+pub fn insert_pages_bulk(db: &PlacesDb, pages: &[BulkPage]) -> Result<()> {
+    let policy = db.url_policy();
+    // The same page may be given more than once, so we merge them by their
+    // canonical url first, keeping the last title.
+    let mut by_url: HashMap<String, (String, Option<&str>, Vec<(Timestamp, VisitTransition, bool)>)> = HashMap::new();
+    for (url, title, visits) in pages {
+        if !policy.can_add_url(url) {
+            debug!("Skipping imported page with a URL rejected by the URL policy");
+            continue;
+        }
+        let url = host::canonicalize_url(url);
+        let page = by_url.entry(url.as_str().to_owned())
+                         .or_insert_with(|| (host::rev_host(&url), None, Vec::new()));
+        if let Some(title) = title {
+            page.1 = Some(title.as_str());
+        }
+        page.2.extend_from_slice(visits);
+    }
+    let tx = db.begin_transaction()?;
+
+    // Insert the pages we don't already have. Like other new pages, they're
+    // hidden until we add a visible visit. Each row uses 3 variables.
+    let urls: Vec<&str> = by_url.keys().map(|url| url.as_str()).collect();
+    let mut page_ids = find_page_ids(&tx, &urls)?;
+    let new_pages: Vec<(Guid, &str, &str)> = by_url.iter()
+        .filter(|(url, _)| !page_ids.contains_key(url.as_str()))
+        .map(|(url, (rev_host, _, _))| (Guid::random(), url.as_str(), rev_host.as_str()))
+        .collect();
+    for chunk in new_pages.chunks(sql_support::default_max_variable_number() / 3) {
+        let values = sql_support::repeat_display(chunk.len(), ",", |i, f|
+            write!(f, "(?{g}, ?{u}, hash(?{u}), ?{r}, 1)", g = i * 3 + 1, u = i * 3 + 2, r = i * 3 + 3));
+        let mut params: Vec<&ToSql> = Vec::with_capacity(chunk.len() * 3);
+        for (guid, url, rev_host) in chunk {
+            params.push(guid);
+            params.push(url);
+            params.push(rev_host);
+        }
+        tx.execute(&format!("INSERT INTO moz_places (guid, url, url_hash, rev_host, hidden) VALUES {}", values),
+                   &params)?;
+    }
+    let new_urls: Vec<&str> = new_pages.iter().map(|(_, url, _)| *url).collect();
+    page_ids.extend(find_page_ids(&tx, &new_urls)?);
+
+    let titles: Vec<(RowId, &str)> = by_url.iter()
+        .filter_map(|(url, (_, title, _))| title.map(|title| (page_ids[url.as_str()], policy.truncate_title(title))))
+        .collect();
+    for chunk in titles.chunks(sql_support::default_max_variable_number() / 2) {
+        let mut values = sql_support::NamedValues::with_capacity(&["id", "title"], chunk.len());
+        for &(page_id, title) in chunk {
+            values.push(page_id).push(title);
+        }
+        tx.execute_named(&format!("
+            WITH new_titles(id, title) AS (VALUES {})
+            UPDATE moz_places
+            SET title = (SELECT title FROM new_titles WHERE new_titles.id = moz_places.id)
+            WHERE id IN (SELECT id FROM new_titles)", values),
+            &values.params())?;
+    }
+
+    // Now every page exists, so we can insert the visits. Each row uses 4
+    // variables.
+    let mut visits: Vec<(RowId, Timestamp, VisitTransition, bool)> = Vec::new();
+    // For each page, the number of typed visits, whether any visits are
+    // visible, and whether any are local, which sync needs to upload.
+    let mut touched: Vec<(RowId, u32, bool, bool)> = Vec::with_capacity(by_url.len());
+    let now = Timestamp::now();
+    for (url, (_, _, page_visits)) in &by_url {
+        let page_id = page_ids[url.as_str()];
+        let mut stats = (page_id, 0, false, false);
+        for &(date, visit_type, is_local) in page_visits {
+            if visit_type == VisitTransition::Typed {
+                stats.1 += 1;
+            }
+            if visit_type != VisitTransition::FramedLink && visit_type != VisitTransition::Embed {
+                stats.2 = true;
+            }
+            stats.3 |= is_local;
+            visits.push((page_id, date.clamp_future(now), visit_type, is_local));
+        }
+        touched.push(stats);
+    }
+    for chunk in visits.chunks(sql_support::default_max_variable_number() / 4) {
+        let mut params: Vec<&ToSql> = Vec::with_capacity(chunk.len() * 4);
+        for (page_id, date, visit_type, is_local) in chunk {
+            params.push(page_id);
+            params.push(date);
+            params.push(visit_type);
+            params.push(is_local);
+        }
+        tx.execute(&format!("
+            INSERT INTO moz_historyvisits (place_id, visit_date, visit_type, is_local)
+            VALUES {}", sql_support::repeat_multi_values(chunk.len(), 4)),
+            &params)?;
+    }
+
+    // Finally, fix up each page we touched. Frecency depends on the typed
+    // count, so it's calculated after that's updated.
+    for chunk in touched.chunks(sql_support::default_max_variable_number() / 4) {
+        let mut values = sql_support::NamedValues::with_capacity(&["id", "typed", "visible", "has_local"], chunk.len());
+        for &(page_id, typed, visible, has_local) in chunk {
+            values.push(page_id).push(typed).push(visible).push(has_local);
+        }
+        tx.execute_named(&format!("
+            WITH stats(id, typed, visible, has_local) AS (VALUES {})
+            UPDATE moz_places
+            SET typed = typed + (SELECT typed FROM stats WHERE stats.id = moz_places.id),
+                hidden = hidden AND NOT (SELECT visible FROM stats WHERE stats.id = moz_places.id),
+                sync_change_counter = sync_change_counter +
+                    (SELECT has_local FROM stats WHERE stats.id = moz_places.id)
+            WHERE id IN (SELECT id FROM stats)", values),
+            &values.params())?;
+    }
+    let mut frecencies: Vec<(RowId, i32)> = Vec::with_capacity(touched.len());
+    for (page_id, _, _, _) in &touched {
+        frecencies.push((*page_id, frecency::calculate_frecency(&tx,
+            db.frecency_settings(),
+            page_id.0,
+            None)?));
+    }
+    for chunk in frecencies.chunks(sql_support::default_max_variable_number() / 2) {
+        let mut values = sql_support::NamedValues::with_capacity(&["id", "frecency"], chunk.len());
+        for &(page_id, frecency) in chunk {
+            values.push(page_id).push(frecency);
+        }
+        tx.execute_named(&format!("
+            WITH frecencies(id, frecency) AS (VALUES {})
+            UPDATE moz_places
+            SET frecency = (SELECT frecency FROM frecencies WHERE frecencies.id = moz_places.id)
+            WHERE id IN (SELECT id FROM frecencies)", values),
+            &values.params())?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Looks up the ids of the pages for `urls`, which must be canonical, in
+// batches. Urls we don't have a page for are left out.
+fn find_page_ids(db: &impl ConnExt, urls: &[&str]) -> Result<HashMap<String, RowId>> {
+    let mut ids = HashMap::with_capacity(urls.len());
+    sql_support::each_chunk_mapped(urls, |url| url as &dyn ToSql, |chunk, offset| -> Result<()> {
+        let values = sql_support::repeat_display(chunk.len(), ",", |i, f|
+            write!(f, "({},?)", hash::hash_url(urls[i + offset])));
+        let mut stmt = db.conn().prepare(&format!("
+            WITH to_find(url_hash, url) AS (VALUES {})
+            SELECT h.id, h.url
+            FROM moz_places h
+            JOIN to_find f
+            ON h.url_hash = f.url_hash
+              AND h.url = f.url", values))?;
+        for row in stmt.query_map(chunk, |row| (row.get::<_, String>(1), row.get::<_, RowId>(0)))? {
+            let (url, id) = row?;
+            ids.insert(url, id);
+        }
+        Ok(())
+    })?;
+    Ok(ids)
+}
+
+pub(crate) fn find_page_id(db: &impl ConnExt, url: &Url) -> Result<Option<RowId>> {
+    let url = host::canonicalize_url(url);
+    Ok(db.try_query_row(
+        "SELECT id FROM moz_places WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())],
+        |row| row.get_checked(0),
+        true)?)
+}
+
+/// Returns the id of the page for `url`, adding it (without any visits) if
+/// it doesn't exist. Fails if `url` is longer than `policy` allows.
+pub(crate) fn get_or_insert_page_id(db: &impl ConnExt, policy: &UrlPolicy, url: &Url) -> Result<RowId> {
+    Ok(match find_page_id(db, url)? {
+        Some(id) => id,
+        None => new_page_info(db, policy, url, None)?.row_id,
+    })
+}
+
 // Currently not used - we update the frecency as we update the page info.
-pub fn update_frecency(db: &mut PlacesDb, id: RowId, redirect: Option<bool>) -> Result<()> {
+pub fn update_frecency(db: &PlacesDb, id: RowId, redirect: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(db.conn(),
-        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        db.frecency_settings(),
         id.0, // TODO: calculate_frecency should take a RowId here.
         redirect)?;
 
@@ -261,21 +796,39 @@ pub fn update_frecency(db: &mut PlacesDb, id: RowId, redirect: Option<bool>) ->
     Ok(())
 }
 
+/// Explains how the frecency of the page for `url` is calculated, or returns
+/// `None` if there's no such page. See `frecency::calculate_frecency_detailed`.
+pub fn get_frecency_details(db: &PlacesDb, url: &Url) -> Result<Option<frecency::FrecencyDetails>> {
+    Ok(match find_page_id(db, url)? {
+        Some(id) => Some(frecency::calculate_frecency_detailed(
+            db.conn(), db.frecency_settings(), id.0)?),
+        None => None,
+    })
+}
+
+// The query for `get_visited`, which looks up the `(index, url_hash, url)`
+// rows in `values`.
+fn get_visited_sql(values: &impl fmt::Display) -> String {
+    format!("
+        WITH to_fetch(fetch_url_index, url_hash, url) AS (VALUES {})
+        SELECT fetch_url_index
+        FROM moz_places h
+        JOIN to_fetch f
+        ON h.url_hash = f.url_hash
+          AND h.url = f.url
+    ", values)
+}
+
 pub fn get_visited(db: &PlacesDb, urls: &[Url]) -> Result<Vec<bool>> {
     let mut result = vec![false; urls.len()];
+    let canonical_urls: Vec<Cow<Url>> = urls.iter().map(|url| host::canonicalize_url(url)).collect();
     // Note: this Vec is avoidable in the next rusqlite.
-    let url_strs: Vec<&str> = urls.iter().map(|v| v.as_ref()).collect();
+    let url_strs: Vec<&str> = canonical_urls.iter().map(|v| v.as_str()).collect();
     sql_support::each_chunk_mapped(&url_strs, |url| url as &dyn ToSql, |chunk, offset| -> Result<()> {
         let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f|
             write!(f, "({},{},?)", i + offset, hash::hash_url(url_strs[i + offset])));
-        let sql = format!("
-            WITH to_fetch(fetch_url_index, url_hash, url) AS (VALUES {})
-            SELECT fetch_url_index
-            FROM moz_places h
-            JOIN to_fetch f
-            ON h.url_hash = f.url_hash
-              AND h.url = f.url
-        ", values_with_idx);
+        let sql = get_visited_sql(&values_with_idx);
+        sql_support::maybe_log_plan(db, &sql);
         let mut stmt = db.prepare(&sql)?;
         for idx_r in stmt.query_map(chunk, |row| row.get::<_, i64>(0) as usize)? {
             let idx = idx_r?;
@@ -286,6 +839,35 @@ pub fn get_visited(db: &PlacesDb, urls: &[Url]) -> Result<Vec<bool>> {
     Ok(result)
 }
 
+/// Like `get_visited`, but takes URL strings, and returns a bitset where bit
+/// `i % 8` of byte `i / 8` is set if `urls[i]` was visited. URLs which don't
+/// parse are reported as unvisited, rather than failing the whole batch.
+///
+/// This is for marking visited links over the FFI, where a page can have
+/// hundreds of links, and encoding a JSON bool for each one takes longer than
+/// the query.
+pub fn get_visited_bitset(db: &PlacesDb, urls: &[&str]) -> Result<Vec<u8>> {
+    let mut indices = Vec::with_capacity(urls.len());
+    let mut parsed = Vec::with_capacity(urls.len());
+    for (i, url) in urls.iter().enumerate() {
+        match Url::parse(url) {
+            Ok(url) => {
+                indices.push(i);
+                parsed.push(url);
+            }
+            Err(e) => debug!("Treating unparseable URL as unvisited: {}", e),
+        }
+    }
+    let visited = get_visited(db, &parsed)?;
+    let mut bits = vec![0u8; (urls.len() + 7) / 8];
+    for (i, was_visited) in indices.into_iter().zip(visited) {
+        if was_visited {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    Ok(bits)
+}
+
 /// Get the set of urls that were visited between `start` and `end`. Only considers local visits
 /// unless you pass in `include_remote`.
 pub fn get_visited_urls(db: &PlacesDb, start: Timestamp, end: Timestamp, include_remote: bool) -> Result<Vec<String>> {
@@ -312,46 +894,562 @@ pub fn get_visited_urls(db: &PlacesDb, start: Timestamp, end: Timestamp, include
     Ok(iter.collect::<RusqliteResult<Vec<_>>>()?)
 }
 
-// Mini experiment with an "Origin" object that knows how to rev_host() itself,
-// that I don't want to throw away yet :) I'm really not sure exactly how
-// moz_origins fits in TBH :/
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::{Duration, SystemTime};
+/// Like `get_visited_urls`, but only returns urls whose host is `host`.
+/// `host` is canonicalized the same way as the hosts we store, so it may
+/// be mixed-case, or an IDN.
+pub fn get_visited_urls_for_host(
+    db: &PlacesDb,
+    host: &str,
+    start: Timestamp,
+    end: Timestamp,
+    include_remote: bool,
+) -> Result<Vec<String>> {
+    let rev_host = host::reverse_host(&host::canonicalize_host(host)?);
+    let mut stmt = db.prepare(&format!("
+        SELECT h.url
+        FROM moz_places h
+        WHERE h.rev_host = :rev_host
+          AND EXISTS (
+            SELECT 1 FROM moz_historyvisits v
+            WHERE place_id = h.id
+                AND visit_date BETWEEN :start AND :end
+                {and_is_local}
+            LIMIT 1
+        )
+    ", and_is_local = if include_remote { "" } else { "AND is_local" }))?;
+
+    let iter = stmt.query_map_named(&[
+        (":rev_host", &rev_host),
+        (":start", &start),
+        (":end", &end),
+    ], |row| row.get::<_, String>(0))?;
 
-    struct Origin {
-        prefix: String,
-        host: String,
-        frecency: i64,
+    Ok(iter.collect::<RusqliteResult<Vec<_>>>()?)
+}
+
+/// A single visit in the history timeline, as returned by
+/// `get_visits_paginated`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryVisitInfo {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+    pub is_hidden: bool,
+}
+
+impl HistoryVisitInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url: String = row.get_checked("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            title: row.get_checked("title")?,
+            visit_date: row.get_checked("visit_date")?,
+            visit_type: row.get_checked("visit_type")?,
+            is_hidden: row.get_checked("hidden")?,
+        })
     }
-    impl Origin {
-        pub fn rev_host(&self) -> String {
-            // Note: this is consistent with how places handles hosts, and our `reverse_host`
-            // function. We explictly don't want to use unicode_segmentation because it's not
-            // stable across unicode versions, and valid hosts are expected to be strings.
-            // (The `url` crate will punycode them for us).
-            String::from_utf8(self.host.bytes().rev().map(|b|
-                b.to_ascii_lowercase()).collect::<Vec<_>>())
-                .unwrap() // TODO: We should return a Result, or punycode on construction if needed.
+}
+
+/// A page of the history timeline. Pass `bound` and `offset` back to
+/// `get_visits_paginated` to fetch the next page.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryVisitPage {
+    pub visits: Vec<HistoryVisitInfo>,
+    pub bound: Timestamp,
+    pub offset: u32,
+}
+
+/// Fetches up to `count` visits, most recent first, for an infinitely
+/// scrolling history view. The first page should be fetched with a `bound`
+/// of now and an `offset` of 0, and later pages with the `bound` and
+/// `offset` returned in the previous page.
+///
+/// Pages are keyed by visit date rather than by a plain offset, so visits
+/// added while the user is scrolling (which will almost always be more recent
+/// than `bound`) don't cause visits to be repeated or skipped. `offset` is
+/// only used to skip the visits at exactly `bound` we've already returned.
+///
+/// If `dedupe` is true, consecutive visits to the same url within the page
+/// are collapsed into the most recent one, so a page may contain fewer than
+/// `count` visits even if there are more to fetch. Callers should keep going
+/// until they get an empty page.
+pub fn get_visits_paginated(
+    db: &impl ConnExt,
+    bound: Timestamp,
+    offset: u32,
+    count: u32,
+    exclude_types: VisitTransitionSet,
+    dedupe: bool,
+) -> Result<HistoryVisitPage> {
+    let excluded = sql_transition_list(exclude_types);
+    // Within a single date, we order by id ascending so that visits added
+    // with exactly the same date end up after the ones we've already seen.
+    let sql = format!("
+        SELECT h.url, h.title, h.hidden, v.visit_date, v.visit_type
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE v.visit_date <= :bound
+          AND v.visit_type NOT IN ({})
+        ORDER BY v.visit_date DESC, v.id ASC
+        LIMIT :count OFFSET :offset", excluded);
+    let mut stmt = db.conn().prepare_cached(&sql)?;
+    let mut visits = stmt
+        .query_and_then_named(&[
+            (":bound", &bound),
+            (":count", &count),
+            (":offset", &offset),
+        ], HistoryVisitInfo::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut next_bound = bound;
+    let mut next_offset = offset;
+    for visit in &visits {
+        if visit.visit_date == next_bound {
+            next_offset += 1;
+        } else {
+            next_bound = visit.visit_date;
+            next_offset = 1;
         }
     }
+    if dedupe {
+        visits.dedup_by(|later, earlier| later.url == earlier.url);
+    }
+    Ok(HistoryVisitPage { visits, bound: next_bound, offset: next_offset })
+}
 
-    #[test]
-    fn test_reverse() {
-        let o = Origin {prefix: "http".to_string(),
-                        host: "foo.com".to_string(),
-                        frecency: 0 };
-        assert_eq!(o.prefix, "http");
-        assert_eq!(o.frecency, 0);
-        assert_eq!(o.rev_host(), "moc.oof");
+/// A page matching a `search_history` query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistorySearchResult {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    pub last_visit_date: Timestamp,
+    pub visit_count: u32,
+    /// How well the page matched. Higher is better.
+    pub score: u32,
+}
+
+impl HistorySearchResult {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url: String = row.get_checked("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            title: row.get_checked("title")?,
+            last_visit_date: row.get_checked("last_visit_date")?,
+            visit_count: row.get_checked("visit_count")?,
+            score: row.get_checked("score")?,
+        })
     }
+}
+
+/// The most words of a query that `search_history` matches on.
+const SEARCH_HISTORY_MAX_WORDS: usize = 16;
+
+/// Searches the titles and urls of visited pages for every word in `query`,
+/// for the history screen's search box. Unlike the awesomebar matcher, this
+/// ignores frecency: results are ordered by how well they match (a word in
+/// the title counts for more than a word in the url), and then by when they
+/// were last visited. Matching is case-insensitive for ASCII only, like
+/// SQLite's `lower()`. Only the first `SEARCH_HISTORY_MAX_WORDS` words of the
+/// query are used, since each word is a separate SQL variable.
+///
+/// Use `offset` to fetch more results for the same query. An empty query
+/// matches nothing.
+pub fn search_history(db: &impl ConnExt, query: &str, limit: u32, offset: u32) -> Result<Vec<HistorySearchResult>> {
+    let words: Vec<String> = query.split_whitespace()
+        .take(SEARCH_HISTORY_MAX_WORDS)
+        .map(|w| w.to_ascii_lowercase())
+        .collect();
+    if words.is_empty() {
+        return Ok(Vec::new());
+    }
+    let names: Vec<String> = (0..words.len()).map(|i| format!(":word{}", i)).collect();
+    let title_matches = |name: &str| format!("instr(lower(IFNULL(h.title, '')), {}) > 0", name);
+    let url_matches = |name: &str| format!("instr(lower(h.url), {}) > 0", name);
+    let score = names.iter()
+        .map(|name| format!("(CASE WHEN {} THEN 2 ELSE 0 END) + (CASE WHEN {} THEN 1 ELSE 0 END)",
+                            title_matches(name), url_matches(name)))
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let all_match = names.iter()
+        .map(|name| format!("({} OR {})", title_matches(name), url_matches(name)))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let sql = format!("
+        SELECT h.url, h.title,
+               MAX(h.last_visit_date_local, h.last_visit_date_remote) AS last_visit_date,
+               h.visit_count_local + h.visit_count_remote AS visit_count,
+               {} AS score
+        FROM moz_places h
+        WHERE h.hidden = 0
+          AND h.visit_count_local + h.visit_count_remote > 0
+          AND {}
+        ORDER BY score DESC, last_visit_date DESC, h.id ASC
+        LIMIT :limit OFFSET :offset", score, all_match);
+    let mut params: Vec<(&str, &ToSql)> = Vec::with_capacity(words.len() + 2);
+    for (name, word) in names.iter().zip(words.iter()) {
+        params.push((name.as_str(), word));
+    }
+    params.push((":limit", &limit));
+    params.push((":offset", &offset));
+    let mut stmt = db.conn().prepare_cached(&sql)?;
+    let results = stmt
+        .query_and_then_named(&params, HistorySearchResult::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(results)
+}
+
+/// A page suggested by `get_highlights`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Highlight {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    pub last_visit_date: Timestamp,
+    pub visit_count: u32,
+    /// Higher is better. Only meaningful compared to other highlights.
+    pub score: f64,
+}
+
+// Highlights come from the pages visited in the last `HIGHLIGHTS_MAX_AGE_MS`,
+// and we score at most `HIGHLIGHTS_MAX_CANDIDATES` of the most recent ones.
+const HIGHLIGHTS_MAX_AGE_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+const HIGHLIGHTS_MAX_CANDIDATES: u32 = 500;
+// A visit counts for half as much after this long.
+const HIGHLIGHTS_HALF_LIFE_MS: f64 = 3.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// Returns up to `limit` recently visited pages for the home screen's
+/// highlights, like desktop's Activity Stream. Each page's score is the log of
+/// its visit count, halved for every three days since its last visit, so a
+/// page visited often last week can beat one visited once today. Hidden
+/// pages are skipped, as are pages we've only seen once, as the target of a
+/// redirect. Only the best page for each host is included.
+pub fn get_highlights(db: &PlacesDb, limit: u32) -> Result<Vec<Highlight>> {
+    get_highlights_at(db, limit, Timestamp::now())
+}
+
+fn get_highlights_at(db: &PlacesDb, limit: u32, now: Timestamp) -> Result<Vec<Highlight>> {
+    let since = Timestamp(now.0.saturating_sub(HIGHLIGHTS_MAX_AGE_MS));
+    let mut stmt = db.prepare("
+        SELECT h.url, h.title, h.rev_host,
+               MAX(h.last_visit_date_local, h.last_visit_date_remote) AS last_visit_date,
+               h.visit_count_local + h.visit_count_remote AS visit_count
+        FROM moz_places h
+        WHERE h.hidden = 0
+          AND (h.last_visit_date_local >= :since OR h.last_visit_date_remote >= :since)
+          AND h.visit_count_local + h.visit_count_remote > 0
+          AND NOT (h.visit_count_local + h.visit_count_remote = 1 AND EXISTS(
+              SELECT 1 FROM moz_historyvisits v
+              WHERE v.place_id = h.id
+                AND v.visit_type IN (:redirect_permanent, :redirect_temporary)))
+        ORDER BY last_visit_date DESC
+        LIMIT :candidates
+    ")?;
+    let rows = stmt.query_and_then_named(&[
+        (":since", &since),
+        (":redirect_permanent", &VisitTransition::RedirectPermanent),
+        (":redirect_temporary", &VisitTransition::RedirectTemporary),
+        (":candidates", &HIGHLIGHTS_MAX_CANDIDATES),
+    ], |row| -> Result<(Highlight, Option<String>)> {
+        let url: String = row.get_checked("url")?;
+        let last_visit_date: Timestamp = row.get_checked("last_visit_date")?;
+        let visit_count: u32 = row.get_checked("visit_count")?;
+        let age = now.0.saturating_sub(last_visit_date.0) as f64;
+        let score = f64::from(visit_count + 1).ln() * 0.5f64.powf(age / HIGHLIGHTS_HALF_LIFE_MS);
+        Ok((Highlight {
+            url: Url::parse(&url)?,
+            title: row.get_checked("title")?,
+            last_visit_date,
+            visit_count,
+            score,
+        }, row.get_checked("rev_host")?))
+    })?;
+    let mut candidates = rows.collect::<Result<Vec<_>>>()?;
+    // Sorting is stable, so ties go to the most recent page.
+    candidates.sort_by(|(a, _), (b, _)| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    let mut seen_hosts = HashSet::new();
+    Ok(candidates
+        .into_iter()
+        .filter(|(_, rev_host)| match rev_host {
+            Some(rev_host) => seen_hosts.insert(rev_host.clone()),
+            None => true,
+        })
+        .map(|(highlight, _)| highlight)
+        .take(limit as usize)
+        .collect())
+}
+
+/// Deletes every page whose url has the same scheme, host and port as
+/// `origin`, along with their visits, and returns how many pages were
+/// removed. Pages which are bookmarked (that is, with a non-zero
+/// `foreign_count`) are kept, but their visits are still removed.
+pub fn delete_visits_for_origin(db: &PlacesDb, origin: &Url) -> Result<usize> {
+    let prefix = match origin.host_str() {
+        Some(host) => match origin.port() {
+            Some(port) => format!("{}://{}:{}/", origin.scheme(), host, port),
+            None => format!("{}://{}/", origin.scheme(), host),
+        },
+        None => return Ok(0),
+    };
+    let (hash_lo, hash_hi) = hash::url_hash_range_for_scheme(origin.scheme());
+    let (hash_lo, hash_hi) = (hash_lo as i64, hash_hi as i64);
+    // We compare with `substr` rather than `LIKE` so that we don't need to
+    // escape `%` and `_` in the prefix.
+    let params: &[(&str, &ToSql)] = &[
+        (":hash_lo", &hash_lo),
+        (":hash_hi", &hash_hi),
+        (":prefix", &prefix),
+    ];
+    let tx = db.begin_transaction()?;
+    tx.execute_named_cached("
+        DELETE FROM moz_historyvisits
+        WHERE place_id IN (
+            SELECT id FROM moz_places
+            WHERE url_hash BETWEEN :hash_lo AND :hash_hi
+              AND substr(url, 1, length(:prefix)) = :prefix
+        )", params)?;
+    // Bookmarked pages are kept, but not their metadata.
+    tx.execute_named_cached("
+        DELETE FROM moz_places_metadata
+        WHERE place_id IN (
+            SELECT id FROM moz_places
+            WHERE url_hash BETWEEN :hash_lo AND :hash_hi
+              AND substr(url, 1, length(:prefix)) = :prefix
+        )", params)?;
+    let deleted = tx.execute_named_cached("
+        DELETE FROM moz_places
+        WHERE url_hash BETWEEN :hash_lo AND :hash_hi
+          AND substr(url, 1, length(:prefix)) = :prefix
+          AND foreign_count = 0", params)?;
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// Removes the visits to `url` made at or after `since`, for example to
+/// forget the last hour of a site's history, and returns how many visits
+/// were removed. The page's typed count, hidden flag and frecency are
+/// updated to reflect the visits that are left. If there are none, and the
+/// page isn't bookmarked, the page itself is removed. See the `tombstones`
+/// module for how the deletion reaches other devices.
+pub fn delete_visits_for(db: &PlacesDb, url: &Url, since: Timestamp) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let page_id = match find_page_id(&tx, url)? {
+        Some(id) => id,
+        None => return Ok(0),
+    };
+    let deleted = tx.execute_named_cached(
+        "DELETE FROM moz_historyvisits WHERE place_id = :page_id AND visit_date >= :since",
+        &[(":page_id", &page_id), (":since", &since)])?;
+    // So does the time spent on the page since then.
+    tx.execute_named_cached(
+        "DELETE FROM moz_places_metadata WHERE place_id = :page_id AND updated_at >= :since",
+        &[(":page_id", &page_id), (":since", &since)])?;
+    if deleted == 0 {
+        tx.commit()?;
+        return Ok(0);
+    }
+    let has_visits: bool = tx.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = :page_id)",
+        &[(":page_id", &page_id)],
+        |row| row.get(0))?;
+    if has_visits {
+        // A page is only visible if one of its visits is, so removing the
+        // visible ones hides it again.
+        let typed = count_typed_visits(&tx, db.frecency_settings(), page_id)?;
+        tx.execute_named_cached("
+            UPDATE moz_places
+            SET typed = :typed,
+                hidden = hidden OR NOT EXISTS(
+                    SELECT 1 FROM moz_historyvisits
+                    WHERE place_id = :page_id AND visit_type NOT IN (:framed_link, :embed))
+            WHERE id = :page_id",
+            &[
+                (":typed", &typed),
+                (":framed_link", &VisitTransition::FramedLink),
+                (":embed", &VisitTransition::Embed),
+                (":page_id", &page_id),
+            ])?;
+    } else {
+        let removed_page = tx.execute_named_cached(
+            "DELETE FROM moz_places WHERE id = :page_id AND foreign_count = 0",
+            &[(":page_id", &page_id)])?;
+        if removed_page != 0 {
+            tx.commit()?;
+            return Ok(deleted);
+        }
+        // Bookmarked pages are kept, but there are no typed visits left.
+        tx.execute_named_cached(
+            "UPDATE moz_places SET typed = 0 WHERE id = :page_id",
+            &[(":page_id", &page_id)])?;
+    }
+    let frecency = frecency::calculate_frecency(&tx,
+        db.frecency_settings(),
+        page_id.0,
+        None)?;
+    tx.execute_named_cached(
+        "UPDATE moz_places SET frecency = :frecency WHERE id = :page_id",
+        &[(":frecency", &frecency), (":page_id", &page_id)])?;
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// Removes the visits made before `cutoff`, and the pages left without any
+/// visits that aren't bookmarked, and returns how many visits were removed.
+/// Unlike `delete_visits_for`, this is housekeeping rather than something
+/// the user asked for, so it doesn't write tombstones or flag the pages it
+/// keeps for upload: other devices keep their own history for as long as
+/// they like.
+pub fn expire_history(db: &PlacesDb, cutoff: Timestamp) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let pages = {
+        let mut stmt = tx.prepare("
+            SELECT id, sync_status, sync_change_counter FROM moz_places
+            WHERE id IN (SELECT place_id FROM moz_historyvisits WHERE visit_date < :cutoff)")?;
+        let rows = stmt.query_and_then_named(&[(":cutoff", &cutoff)], |row| -> Result<_> {
+            Ok((row.get_checked::<_, RowId>(0)?,
+                row.get_checked::<_, SyncStatus>(1)?,
+                row.get_checked::<_, i64>(2)?))
+        })?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+    if pages.is_empty() {
+        tx.commit()?;
+        return Ok(0);
+    }
+    // The delete triggers only write tombstones for synced pages, so we
+    // pretend they aren't while we expire them, and put everything back
+    // after.
+    tx.execute_named_cached("
+        UPDATE moz_places SET sync_status = :new
+        WHERE id IN (SELECT place_id FROM moz_historyvisits WHERE visit_date < :cutoff)",
+        &[(":new", &SyncStatus::New), (":cutoff", &cutoff)])?;
+    let deleted = tx.execute_named_cached(
+        "DELETE FROM moz_historyvisits WHERE visit_date < :cutoff",
+        &[(":cutoff", &cutoff)])?;
+    for (page_id, sync_status, sync_change_counter) in pages {
+        let removed_page = tx.execute_named_cached("
+            DELETE FROM moz_places
+            WHERE id = :page_id
+              AND foreign_count = 0
+              AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = :page_id)",
+            &[(":page_id", &page_id)])?;
+        if removed_page != 0 {
+            continue;
+        }
+        let typed = count_typed_visits(&tx, db.frecency_settings(), page_id)?;
+        tx.execute_named_cached("
+            UPDATE moz_places
+            SET typed = :typed,
+                hidden = hidden OR NOT EXISTS(
+                    SELECT 1 FROM moz_historyvisits
+                    WHERE place_id = :page_id AND visit_type NOT IN (:framed_link, :embed)),
+                sync_status = :sync_status,
+                sync_change_counter = :sync_change_counter
+            WHERE id = :page_id",
+            &[
+                (":typed", &typed),
+                (":framed_link", &VisitTransition::FramedLink),
+                (":embed", &VisitTransition::Embed),
+                (":sync_status", &sync_status),
+                (":sync_change_counter", &sync_change_counter),
+                (":page_id", &page_id),
+            ])?;
+        let frecency = frecency::calculate_frecency(&tx,
+            db.frecency_settings(),
+            page_id.0,
+            None)?;
+        tx.execute_named_cached(
+            "UPDATE moz_places SET frecency = :frecency WHERE id = :page_id",
+            &[(":frecency", &frecency), (":page_id", &page_id)])?;
+    }
+    tx.commit()?;
+    Ok(deleted)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DownloadInfo {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    /// Where the download was saved, if known.
+    pub download_path: Option<String>,
+    /// The date of the most recent download of this url.
+    pub date: Timestamp,
+}
+
+impl DownloadInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked("title")?,
+            download_path: row.get_checked("download_path")?,
+            date: row.get_checked("date")?,
+        })
+    }
+}
+
+/// Get up to `limit` of the most recently downloaded urls, most recent first.
+pub fn get_recent_downloads(db: &PlacesDb, limit: u32) -> Result<Vec<DownloadInfo>> {
+    let mut stmt = db.prepare("
+        SELECT h.url, h.title, MAX(v.visit_date) AS date,
+               (SELECT a.content FROM moz_annos a
+                JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+                WHERE a.place_id = h.id AND n.name = :anno) AS download_path
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE v.visit_type = :download
+        GROUP BY h.id
+        ORDER BY date DESC
+        LIMIT :limit
+    ")?;
+    let rows = stmt.query_and_then_named(&[
+        (":anno", &DOWNLOAD_DESTINATION_ANNO),
+        (":download", &VisitTransition::Download),
+        (":limit", &limit),
+    ], DownloadInfo::from_row)?;
+    rows.collect()
+}
+
+/// Forget that `url` was downloaded: removes its download visits and the
+/// download destination. If the page has no other visits, it's removed
+/// entirely. Returns false if `url` had never been downloaded.
+pub fn delete_download(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let tx = db.begin_transaction()?;
+    let page_id = match find_page_id(&tx, url)? {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    let deleted = tx.execute_named_cached(
+        "DELETE FROM moz_historyvisits WHERE place_id = :page_id AND visit_type = :download",
+        &[(":page_id", &page_id), (":download", &VisitTransition::Download)])?;
+    tx.execute_named_cached("
+        DELETE FROM moz_annos
+        WHERE place_id = :page_id
+          AND anno_attribute_id = (SELECT id FROM moz_anno_attributes WHERE name = :anno)",
+        &[(":page_id", &page_id), (":anno", &DOWNLOAD_DESTINATION_ANNO)])?;
+    tx.execute_named_cached("
+        DELETE FROM moz_places
+        WHERE id = :page_id
+          AND foreign_count = 0
+          AND NOT EXISTS (SELECT 1 FROM moz_historyvisits WHERE place_id = :page_id)",
+        &[(":page_id", &page_id)])?;
+    tx.commit()?;
+    Ok(deleted != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::ErrorKind;
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn test_get_visited_urls() {
         use std::time::SystemTime;
         use std::collections::HashSet;
-        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
         let now: Timestamp = SystemTime::now().into();
         let now_u64 = now.0;
         // (url, when, is_remote, (expected_always, expected_only_local)
@@ -365,7 +1463,7 @@ mod tests {
 
         for &(url, when, remote, _) in &to_add {
             apply_observation(
-                &mut conn,
+                &conn,
                 VisitObservation::new(Url::parse(url).unwrap())
                     .with_at(Timestamp(when))
                     .with_is_remote(remote)
@@ -397,20 +1495,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_visited_urls_for_host() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        for url in &["https://www.example.com/1", "https://WWW.Example.com/2",
+                     "https://example.com/3", "foo://Bücher.example/4"] {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_at(now)
+                .with_visit_type(VisitTransition::Link)).expect("Should apply visit");
+        }
+        let get = |host: &str| {
+            let mut urls = get_visited_urls_for_host(&conn, host, Timestamp(0), now, true).unwrap();
+            urls.sort();
+            urls
+        };
+        assert_eq!(get("www.example.com"), vec!["https://www.example.com/1", "https://www.example.com/2"]);
+        assert_eq!(get("EXAMPLE.com"), vec!["https://example.com/3"]);
+        assert_eq!(get("bücher.example"), vec!["foo://xn--bcher-kva.example/4"]);
+        assert!(get("mozilla.org").is_empty());
+
+        // The mixed-case url should have been stored as the same page.
+        let url = Url::parse("foo://BÜCHER.example/4").unwrap();
+        assert_eq!(get_visit_count(&conn, &url, VisitTransitionSet::empty()).unwrap(), 1);
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)).expect("Should apply visit");
+        assert_eq!(get_visit_count(&conn, &url, VisitTransitionSet::empty()).unwrap(), 2);
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places").unwrap();
+        assert_eq!(count, 4);
+    }
+
     #[test]
     fn test_visit_counts() {
-        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
         let url = Url::parse("https://www.example.com").expect("it's a valid url");
         let early_time = SystemTime::now() - Duration::new(60, 0);
         let late_time = SystemTime::now();
 
         // add 2 local visits - add latest first
-        let rid1 = apply_observation(&mut conn, VisitObservation::new(url.clone())
+        let rid1 = apply_observation(&conn, VisitObservation::new(url.clone())
                     .with_visit_type(VisitTransition::Link)
                     .with_at(Some(late_time.into())))
                     .expect("Should apply visit").expect("should get a rowid");
 
-        let _rid2 = apply_observation(&mut conn, VisitObservation::new(url.clone())
+        let _rid2 = apply_observation(&conn, VisitObservation::new(url.clone())
                     .with_visit_type(VisitTransition::Link)
                     .with_at(Some(early_time.into())))
                     .expect("Should apply visit").expect("should get a rowid");
@@ -422,13 +1550,13 @@ mod tests {
         assert_eq!(pi.page.last_visit_date_remote.0, 0);
 
         // 2 remote visits, earliest first.
-        let rid3 = apply_observation(&mut conn, VisitObservation::new(url.clone())
+        let rid3 = apply_observation(&conn, VisitObservation::new(url.clone())
                     .with_visit_type(VisitTransition::Link)
                     .with_at(Some(early_time.into()))
                     .with_is_remote(true))
                     .expect("Should apply visit").expect("should get a rowid");
 
-        let _rid4 = apply_observation(&mut conn, VisitObservation::new(url.clone())
+        let _rid4 = apply_observation(&conn, VisitObservation::new(url.clone())
                     .with_visit_type(VisitTransition::Link)
                     .with_at(Some(late_time.into()))
                     .with_is_remote(true))
@@ -463,7 +1591,7 @@ mod tests {
 
     #[test]
     fn test_get_visited() {
-        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
 
         let to_add = [
             "https://www.example.com/1",
@@ -475,7 +1603,7 @@ mod tests {
         ];
 
         for item in &to_add {
-            apply_observation(&mut conn, VisitObservation::new(Url::parse(item).unwrap())
+            apply_observation(&conn, VisitObservation::new(Url::parse(item).unwrap())
                 .with_visit_type(VisitTransition::Link))
                 .expect("Should apply visit");
         }
@@ -502,6 +1630,8 @@ mod tests {
         let visited = get_visited(&conn, &urls).unwrap();
 
         assert_eq!(visited.len(), to_search.len());
+        // Each url is looked up by its hash, not by scanning every page.
+        sql_support::assert_uses_index(&conn, &get_visited_sql(&"(0,0,?)"), "url_hashindex");
 
         for (i, &did_see) in visited.iter().enumerate() {
             assert_eq!(did_see, to_search[i].1,
@@ -509,5 +1639,784 @@ mod tests {
                 to_search[i].0, i, // idx is logged because some things are repeated
                 to_search[i].1, did_see);
         }
+
+        let mut strs = to_search.iter().map(|(url, _)| *url).collect::<Vec<_>>();
+        strs.push("not a url");
+        strs.push("https://www.mozilla.com");
+        let bits = get_visited_bitset(&conn, &strs).unwrap();
+        assert_eq!(bits.len(), 2);
+        for (i, &(url, expect)) in to_search.iter().enumerate() {
+            assert_eq!(bits[i / 8] & (1 << (i % 8)) != 0, expect, "Wrong bit for '{}' (idx {})", url, i);
+        }
+        // The unparseable URL is unvisited, but doesn't affect those after it.
+        assert_eq!(bits[1], 0b0001_0010);
+        assert!(get_visited_bitset(&conn, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_downloads() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now: Timestamp = SystemTime::now().into();
+        let file1 = Url::parse("https://www.example.com/file1.pdf").unwrap();
+        let file2 = Url::parse("https://www.example.com/file2.zip").unwrap();
+        let page = Url::parse("https://www.example.com/").unwrap();
+
+        apply_observation(&conn, VisitObservation::new(page.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(now.0 - 3000)))
+            .expect("Should apply visit");
+        apply_observation(&conn, VisitObservation::new(file1.clone())
+            .with_visit_type(VisitTransition::Download)
+            .with_at(Timestamp(now.0 - 2000))
+            .with_download_path("/sdcard/Download/file1.pdf".to_string()))
+            .expect("Should apply visit");
+        apply_observation(&conn, VisitObservation::new(file2.clone())
+            .with_visit_type(VisitTransition::Download)
+            .with_at(Timestamp(now.0 - 1000))
+            .with_title("File 2".to_string()))
+            .expect("Should apply visit");
+
+        let downloads = get_recent_downloads(&conn, 10).expect("should work");
+        assert_eq!(downloads, vec![
+            DownloadInfo {
+                url: file2.clone(),
+                title: Some("File 2".into()),
+                download_path: None,
+                date: Timestamp(now.0 - 1000),
+            },
+            DownloadInfo {
+                url: file1.clone(),
+                title: None,
+                download_path: Some("/sdcard/Download/file1.pdf".into()),
+                date: Timestamp(now.0 - 2000),
+            },
+        ]);
+        assert_eq!(get_recent_downloads(&conn, 1).unwrap().len(), 1);
+
+        assert!(delete_download(&conn, &file1).expect("should work"));
+        assert!(!delete_download(&conn, &file1).expect("should work"));
+        assert!(!delete_download(&conn, &page).expect("should work"));
+        assert!(fetch_page_info(&conn, &file1).unwrap().is_none());
+        assert!(fetch_page_info(&conn, &page).unwrap().is_some());
+
+        let downloads = get_recent_downloads(&conn, 10).expect("should work");
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].url, file2);
+    }
+
+    #[test]
+    fn test_visit_count_and_latest_visit() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        assert_eq!(get_visit_count(&conn, &url, VisitTransitionSet::empty()).unwrap(), 0);
+        assert_eq!(get_latest_visit(&conn, &url).unwrap(), None);
+
+        let now: Timestamp = SystemTime::now().into();
+        let visits = [
+            (VisitTransition::Link, now.0 - 3000, false),
+            (VisitTransition::Reload, now.0 - 2000, false),
+            (VisitTransition::Typed, now.0 - 1000, true),
+        ];
+        for &(visit_type, when, is_remote) in &visits {
+            apply_observation(&conn, VisitObservation::new(url.clone())
+                .with_visit_type(visit_type)
+                .with_at(Timestamp(when))
+                .with_is_remote(is_remote))
+                .expect("Should apply visit");
+        }
+
+        assert_eq!(get_visit_count(&conn, &url, VisitTransitionSet::empty()).unwrap(), 3);
+        assert_eq!(get_visit_count(&conn, &url, VisitTransitionSet::RELOAD).unwrap(), 2);
+        assert_eq!(get_visit_count(&conn, &url,
+                                   VisitTransitionSet::RELOAD | VisitTransitionSet::TYPED).unwrap(), 1);
+
+        assert_eq!(get_latest_visit(&conn, &url).unwrap(), Some(VisitInfo {
+            visit_date: Timestamp(now.0 - 1000),
+            visit_type: VisitTransition::Typed,
+            is_local: false,
+        }));
+    }
+
+    #[test]
+    fn test_future_visit_clamped() {
+        use types_support::MAX_FUTURE_SKEW_MS;
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let before = Timestamp::now();
+        let in_a_day = before.checked_add(Duration::from_secs(24 * 60 * 60)).unwrap();
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(in_a_day))
+            .expect("Should apply visit");
+        let after = Timestamp::now();
+
+        let visit_date = get_latest_visit(&conn, &url).unwrap().expect("should have a visit").visit_date;
+        assert!(visit_date < in_a_day);
+        assert!(visit_date >= before.checked_add(Duration::from_millis(MAX_FUTURE_SKEW_MS)).unwrap());
+        assert!(visit_date <= after.checked_add(Duration::from_millis(MAX_FUTURE_SKEW_MS)).unwrap());
+    }
+
+    #[test]
+    fn test_insert_pages_bulk() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now: Timestamp = SystemTime::now().into();
+        let existing = Url::parse("https://www.example.com/existing").unwrap();
+        apply_observation(&conn, VisitObservation::new(existing.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(now.0 - 5000)))
+            .expect("Should apply visit");
+
+        let new_url = Url::parse("https://www.example.com/new").unwrap();
+        let framed = Url::parse("https://www.example.com/framed").unwrap();
+        insert_pages_bulk(&conn, &[
+            (existing.clone(), Some("Existing".into()), vec![
+                (Timestamp(now.0 - 4000), VisitTransition::Typed, true),
+            ]),
+            (new_url.clone(), Some("New".into()), vec![
+                (Timestamp(now.0 - 3000), VisitTransition::Link, true),
+                (Timestamp(now.0 - 2000), VisitTransition::Typed, false),
+            ]),
+            // The same url again should add to the same page.
+            (new_url.clone(), None, vec![
+                (Timestamp(now.0 - 1000), VisitTransition::Link, true),
+            ]),
+            (framed.clone(), None, vec![
+                (Timestamp(now.0 - 1000), VisitTransition::FramedLink, true),
+            ]),
+        ]).expect("should insert");
+
+        let pi = fetch_page_info(&conn, &existing).unwrap().expect("should exist").page;
+        assert_eq!(pi.title, "Existing");
+        assert_eq!(pi.visit_count_local, 2);
+        assert_eq!(pi.typed, 1);
+        assert!(pi.frecency > 0);
+
+        let pi = fetch_page_info(&conn, &new_url).unwrap().expect("should exist").page;
+        assert_eq!(pi.title, "New");
+        assert_eq!(pi.visit_count_local, 2);
+        assert_eq!(pi.visit_count_remote, 1);
+        assert_eq!(pi.last_visit_date_local, Timestamp(now.0 - 1000));
+        assert_eq!(pi.last_visit_date_remote, Timestamp(now.0 - 2000));
+        assert_eq!(pi.typed, 1);
+        assert!(!pi.hidden);
+        assert!(pi.frecency > 0);
+
+        // A page with only framed visits stays hidden, as it would if we'd
+        // applied an observation for it.
+        let pi = fetch_page_info(&conn, &framed).unwrap().expect("should exist").page;
+        assert_eq!(pi.visit_count_local, 0);
+        assert_eq!(pi.last_visit_date_local, Timestamp(now.0 - 1000));
+        assert!(pi.hidden);
+
+        let num_pages: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places").unwrap();
+        assert_eq!(num_pages, 3);
+    }
+
+    #[test]
+    fn test_insert_pages_bulk_url_policy() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        conn.set_url_policy(UrlPolicy::new().with_max_url_length(40));
+        let now: Timestamp = SystemTime::now().into();
+        let allowed = Url::parse("https://www.example.com/").unwrap();
+        let blocked = Url::parse("about:config").unwrap();
+        let too_long = Url::parse(&format!("data:text/plain,{}", "a".repeat(40))).unwrap();
+        insert_pages_bulk(&conn, &[
+            (allowed.clone(), None, vec![(now, VisitTransition::Link, true)]),
+            (blocked.clone(), None, vec![(now, VisitTransition::Link, true)]),
+            (too_long.clone(), None, vec![(now, VisitTransition::Link, true)]),
+        ]).expect("should insert");
+
+        assert!(fetch_page_info(&conn, &allowed).unwrap().is_some());
+        assert!(fetch_page_info(&conn, &blocked).unwrap().is_none());
+        assert!(fetch_page_info(&conn, &too_long).unwrap().is_none());
+        let num_visits: i64 = conn.query_one("SELECT COUNT(*) FROM moz_historyvisits").unwrap();
+        assert_eq!(num_visits, 1);
+
+        // Pages for bookmarks can't be too long either.
+        match get_or_insert_page_id(&conn, conn.url_policy(), &too_long) {
+            Err(ref e) => match e.kind() {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::UrlTooLong) => {}
+                _ => panic!("Unexpected error {:?}", e),
+            },
+            Ok(_) => panic!("Shouldn't add a page for a URL that's too long"),
+        }
+    }
+
+    #[test]
+    fn test_delete_visits_for_origin() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let urls = [
+            ("https://www.example.com/", true),
+            ("https://www.example.com/foo?bar", true),
+            ("https://www.example.com:8080/", false),
+            ("http://www.example.com/", false),
+            ("https://www.example.com.au/", false),
+            ("https://mozilla.org/", false),
+        ];
+        for &(url, _) in &urls {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(VisitTransition::Link))
+                .expect("Should apply visit");
+        }
+        let origin = Url::parse("https://www.example.com/some/page").unwrap();
+        assert_eq!(delete_visits_for_origin(&conn, &origin).expect("should work"), 2);
+        for &(url, deleted) in &urls {
+            let url = Url::parse(url).unwrap();
+            assert_eq!(fetch_page_info(&conn, &url).unwrap().is_none(), deleted,
+                       "wrong result for {}", url);
+        }
+        let num_visits: i64 = conn.query_one("SELECT COUNT(*) FROM moz_historyvisits").unwrap();
+        assert_eq!(num_visits, 4);
+    }
+
+    #[test]
+    fn test_delete_with_foreign_keys() {
+        use db::{ConnectionType, PragmaConfig};
+        use tempfile;
+        let dir = tempfile::tempdir().unwrap();
+        let conn = PlacesDb::open_with_config(
+            dir.path().join("places.sqlite"),
+            None,
+            ConnectionType::ReadWrite,
+            ConnectionType::ReadWrite.open_flags(),
+            &PragmaConfig { foreign_keys: true, ..PragmaConfig::default() },
+        ).unwrap();
+        let now = Timestamp::now();
+        let visit = |url: &str, referrer: Option<&str>, at: Timestamp| {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(VisitTransition::Link)
+                .with_referrer(referrer.map(|r| Url::parse(r).unwrap()))
+                .with_at(at))
+                .expect("Should apply visit");
+        };
+        // Each visit came from the one before, so deleting any but the last
+        // would leave a `from_visit` pointing at it.
+        visit("https://old.example.com/", None, Timestamp(now.0 - 20_000));
+        visit("https://www.example.com/", Some("https://old.example.com/"), Timestamp(now.0 - 10_000));
+        visit("https://mozilla.org/", Some("https://www.example.com/"), now);
+
+        assert_eq!(expire_history(&conn, Timestamp(now.0 - 15_000)).expect("should expire"), 1);
+        let origin = Url::parse("https://www.example.com/").unwrap();
+        assert_eq!(delete_visits_for_origin(&conn, &origin).expect("should delete origin"), 1);
+
+        let from_visits = conn.prepare("SELECT from_visit FROM moz_historyvisits").unwrap()
+            .query_map(&[], |row| row.get::<_, Option<RowId>>(0)).unwrap()
+            .collect::<RusqliteResult<Vec<_>>>().unwrap();
+        assert_eq!(from_visits, vec![None]);
+        let places: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places").unwrap();
+        assert_eq!(places, 1);
+    }
+
+    #[test]
+    fn test_get_page_info() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        assert_eq!(get_page_info(&conn, &url).unwrap(), None);
+
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Typed)
+            .with_title("Example".to_string())
+            .with_at(Timestamp(1000)))
+            .expect("Should apply visit");
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(2000))
+            .with_is_remote(true))
+            .expect("Should apply visit");
+        let info = get_page_info(&conn, &url).unwrap().expect("should have the page");
+        assert_eq!(info.title, "Example");
+        assert_eq!(info.typed, 1);
+        assert_eq!((info.visit_count_local, info.visit_count_remote), (1, 1));
+        assert_eq!(info.last_visit_date_local, Timestamp(1000));
+        assert_eq!(info.last_visit_date_remote, Timestamp(2000));
+
+        // Pages without visits, like unvisited bookmarks, are still returned.
+        let bookmarked = Url::parse("https://www.example.com/bookmarked").unwrap();
+        get_or_insert_page_id(&conn, conn.url_policy(), &bookmarked).unwrap();
+        let info = get_page_info(&conn, &bookmarked).unwrap().expect("should have the page");
+        assert_eq!(info.visit_count_local, 0);
+        assert_eq!(info.last_visit_date_local, Timestamp(0));
+    }
+
+    #[test]
+    fn test_delete_visits_for() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now: Timestamp = SystemTime::now().into();
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let visit = |url: &Url, visit_type: VisitTransition, ago: u64| {
+            apply_observation(&conn, VisitObservation::new(url.clone())
+                .with_visit_type(visit_type)
+                .with_at(Timestamp(now.0 - ago)))
+                .expect("Should apply visit");
+        };
+        visit(&url, VisitTransition::Embed, 3000);
+        visit(&url, VisitTransition::Typed, 2000);
+        visit(&url, VisitTransition::Link, 1000);
+        let page = fetch_page_info(&conn, &url).unwrap().unwrap().page;
+        assert_eq!(page.typed, 1);
+        assert!(!page.hidden);
+
+        // Removing the recent visits leaves the hidden one.
+        assert_eq!(delete_visits_for(&conn, &url, Timestamp(now.0 - 2000)).unwrap(), 2);
+        let page = fetch_page_info(&conn, &url).unwrap().unwrap().page;
+        assert_eq!(page.typed, 0);
+        assert!(page.hidden);
+        assert_eq!(page.visit_count_local, 0);
+        assert_eq!(page.last_visit_date_local, Timestamp(now.0 - 3000));
+        assert_eq!(delete_visits_for(&conn, &url, Timestamp(now.0 - 2000)).unwrap(), 0);
+
+        // Removing the last one removes the page, with a tombstone since it
+        // was synced.
+        conn.execute_batch("UPDATE moz_places SET sync_status = 2").unwrap();
+        assert_eq!(delete_visits_for(&conn, &url, Timestamp(0)).unwrap(), 1);
+        assert!(fetch_page_info(&conn, &url).unwrap().is_none());
+        let guid: Guid = conn.query_one("SELECT guid FROM moz_places_tombstones").unwrap();
+        assert_eq!(guid, page.guid);
+
+        // Bookmarked pages are kept, and new pages don't need tombstones.
+        let bookmarked = Url::parse("https://www.example.com/bookmarked").unwrap();
+        let other = Url::parse("https://www.example.com/other").unwrap();
+        visit(&bookmarked, VisitTransition::Typed, 1000);
+        visit(&other, VisitTransition::Link, 1000);
+        conn.execute_named_cached(
+            "UPDATE moz_places SET foreign_count = 1 WHERE url = :url",
+            &[(":url", &bookmarked.as_str())]).unwrap();
+        assert_eq!(delete_visits_for(&conn, &bookmarked, Timestamp(0)).unwrap(), 1);
+        assert_eq!(delete_visits_for(&conn, &other, Timestamp(0)).unwrap(), 1);
+        let (typed, last_visit_date): (u32, Timestamp) = conn.query_row(
+            "SELECT typed, last_visit_date_local FROM moz_places WHERE foreign_count = 1",
+            &[], |row| (row.get(0), row.get(1))).unwrap();
+        assert_eq!(typed, 0);
+        assert_eq!(last_visit_date, Timestamp(0));
+        assert!(fetch_page_info(&conn, &other).unwrap().is_none());
+        let count: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places_tombstones").unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_delete_visits_for_typed_redirects() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now: Timestamp = SystemTime::now().into();
+        let source = Url::parse("http://example.com/").unwrap();
+        let target = Url::parse("https://example.com/").unwrap();
+        // Type `source`, which redirects to `target`, twice.
+        for &ago in &[3000, 1000] {
+            apply_observation(&conn, VisitObservation::new(source.clone())
+                .with_visit_type(VisitTransition::Typed)
+                .with_is_redirect_source(true)
+                .with_at(Timestamp(now.0 - ago)))
+                .expect("Should apply visit");
+            apply_observation(&conn, VisitObservation::new(target.clone())
+                .with_visit_type(VisitTransition::RedirectPermanent)
+                .with_referrer(source.clone())
+                .with_at(Timestamp(now.0 - ago + 1)))
+                .expect("Should apply visit");
+        }
+        let typed = |url: &Url| fetch_page_info(&conn, url).unwrap().unwrap().page.typed;
+        assert_eq!((typed(&source), typed(&target)), (0, 2));
+
+        // None of the deleted visits were typed, but one of them was the end
+        // of a typed redirect, so it counted.
+        assert_eq!(delete_visits_for(&conn, &target, Timestamp(now.0 - 2000)).unwrap(), 1);
+        assert_eq!((typed(&source), typed(&target)), (0, 1));
+    }
+
+    #[test]
+    fn test_redirect_chain() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let source = Url::parse("http://example.com/").unwrap();
+        let middle = Url::parse("https://example.com/").unwrap();
+        let target = Url::parse("https://www.example.com/").unwrap();
+
+        let source_visit = apply_observation(&conn, VisitObservation::new(source.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_is_redirect_source(true)
+            .with_is_permanent_redirect_source(true))
+            .expect("Should apply visit").expect("should get a rowid");
+        let middle_visit = apply_observation(&conn, VisitObservation::new(middle.clone())
+            .with_visit_type(VisitTransition::RedirectPermanent)
+            .with_referrer(source.clone())
+            .with_is_redirect_source(true))
+            .expect("Should apply visit").expect("should get a rowid");
+        let target_visit = apply_observation(&conn, VisitObservation::new(target.clone())
+            .with_visit_type(VisitTransition::RedirectTemporary)
+            .with_referrer(middle.clone()))
+            .expect("Should apply visit").expect("should get a rowid");
+
+        // Only the final target should be visible.
+        let source_info = fetch_page_info(&conn, &source).unwrap().expect("should exist").page;
+        let middle_info = fetch_page_info(&conn, &middle).unwrap().expect("should exist").page;
+        let target_info = fetch_page_info(&conn, &target).unwrap().expect("should exist").page;
+        assert!(source_info.hidden);
+        assert!(middle_info.hidden);
+        assert!(!target_info.hidden);
+
+        let chain = fetch_visit_redirect_chain(&conn, target_visit).expect("should work");
+        assert_eq!(chain.iter().map(|e| e.visit_id).collect::<Vec<_>>(),
+                   vec![source_visit, middle_visit, target_visit]);
+        assert_eq!(chain[0].url, source);
+        assert_eq!(chain[0].visit_type, VisitTransition::Link);
+        assert_eq!(chain[2].url, target);
+
+        // A visit which isn't a redirect target is its own chain, even if it
+        // has a referrer.
+        let link_visit = apply_observation(&conn, VisitObservation::new(Url::parse("https://www.example.com/page").unwrap())
+            .with_visit_type(VisitTransition::Link)
+            .with_referrer(target.clone()))
+            .expect("Should apply visit").expect("should get a rowid");
+        let chain = fetch_visit_redirect_chain(&conn, link_visit).expect("should work");
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].visit_id, link_visit);
+
+        assert!(fetch_visit_redirect_chain(&conn, RowId(9999)).expect("should work").is_empty());
+
+        // Redirect visits get no bonus of their own, so a redirect target only
+        // has a positive frecency because it's scored as a visit to its source.
+        let landing = Url::parse("https://landing.example.com/").unwrap();
+        apply_observation(&conn, VisitObservation::new(Url::parse("http://redirect.example.com/").unwrap())
+            .with_visit_type(VisitTransition::Typed)
+            .with_is_redirect_source(true))
+            .expect("Should apply visit");
+        apply_observation(&conn, VisitObservation::new(landing.clone())
+            .with_visit_type(VisitTransition::RedirectTemporary)
+            .with_referrer(Url::parse("http://redirect.example.com/").unwrap()))
+            .expect("Should apply visit");
+        let landing_info = fetch_page_info(&conn, &landing).unwrap().expect("should exist").page;
+        assert!(landing_info.frecency > 0);
+    }
+
+    #[test]
+    fn test_typed_redirects() {
+        // Types `start`, which redirects through `hops`, and returns the
+        // typed counts of every page in the chain.
+        fn type_redirected(conn: &PlacesDb, start: &str, hops: &[&str]) -> Vec<u32> {
+            let mut urls = vec![Url::parse(start).unwrap()];
+            urls.extend(hops.iter().map(|u| Url::parse(u).unwrap()));
+            apply_observation(conn, VisitObservation::new(urls[0].clone())
+                .with_visit_type(VisitTransition::Typed)
+                .with_is_redirect_source(true))
+                .expect("Should apply visit");
+            for i in 1..urls.len() {
+                apply_observation(conn, VisitObservation::new(urls[i].clone())
+                    .with_visit_type(VisitTransition::RedirectTemporary)
+                    .with_referrer(urls[i - 1].clone())
+                    .with_is_redirect_source(i < urls.len() - 1))
+                    .expect("Should apply visit");
+            }
+            urls.iter()
+                .map(|url| fetch_page_info(conn, url).unwrap().expect("should exist").page.typed)
+                .collect()
+        }
+
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        assert_eq!(type_redirected(&conn, "http://a.example.com/", &["https://a.example.com/"]),
+                   vec![0, 1]);
+        assert_eq!(type_redirected(&conn, "http://b.example.com/",
+                                   &["https://b.example.com/", "https://www.b.example.com/"]),
+                   vec![0, 0, 1]);
+
+        // Following a link which redirects isn't typing the target.
+        let link_source = Url::parse("http://c.example.com/").unwrap();
+        let link_target = Url::parse("https://c.example.com/").unwrap();
+        apply_observation(&conn, VisitObservation::new(link_source.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_is_redirect_source(true))
+            .expect("Should apply visit");
+        apply_observation(&conn, VisitObservation::new(link_target.clone())
+            .with_visit_type(VisitTransition::RedirectPermanent)
+            .with_referrer(link_source.clone()))
+            .expect("Should apply visit");
+        let pi = fetch_page_info(&conn, &link_target).unwrap().expect("should exist").page;
+        assert_eq!(pi.typed, 0);
+
+        // Without the setting, the page that was typed gets the credit.
+        conn.set_frecency_settings(FrecencySettings {
+            typed_redirect_boosts_target: false,
+            ..FrecencySettings::default()
+        });
+        assert_eq!(type_redirected(&conn, "http://d.example.com/",
+                                   &["https://d.example.com/", "https://www.d.example.com/"]),
+                   vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn test_url_policy() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let about = Url::parse("about:config").unwrap();
+        let internal = Url::parse("my-browser://settings").unwrap();
+        for url in &[&about, &internal] {
+            apply_observation(&conn, VisitObservation::new((*url).clone())
+                .with_visit_type(VisitTransition::Link))
+                .expect("Should apply visit");
+        }
+        assert!(fetch_page_info(&conn, &about).unwrap().is_none());
+        assert!(fetch_page_info(&conn, &internal).unwrap().is_some());
+
+        conn.set_url_policy(UrlPolicy::new().block_scheme("my-browser"));
+        let rid = apply_observation(&conn, VisitObservation::new(internal.clone())
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit");
+        assert!(rid.is_none());
+        assert_eq!(get_visit_count(&conn, &internal, VisitTransitionSet::empty()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_long_titles_truncated() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        conn.set_url_policy(UrlPolicy::new().with_max_title_length(10));
+        let visited = Url::parse("https://www.example.com/visited").unwrap();
+        let titled = Url::parse("https://www.example.com/titled").unwrap();
+
+        apply_observation(&conn, VisitObservation::new(visited.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_title("A title that's too long".to_string()))
+            .expect("Should apply visit");
+        assert_eq!(fetch_page_info(&conn, &visited).unwrap().unwrap().page.title, "A title th");
+
+        set_page_title(&conn, &titled, "Ünïcödé title").unwrap();
+        assert_eq!(fetch_page_info(&conn, &titled).unwrap().unwrap().page.title, "Ünïcöd");
+
+        // Noting the same long title again isn't a change.
+        let counter = |url: &Url| -> i64 {
+            conn.query_row_named("SELECT sync_change_counter FROM moz_places WHERE url = :url",
+                                 &[(":url", &url.as_str())], |row| row.get(0)).unwrap()
+        };
+        let before = counter(&titled);
+        set_page_title(&conn, &titled, "Ünïcödé title, again").unwrap();
+        assert_eq!(counter(&titled), before);
+    }
+
+    #[test]
+    fn test_observation_guid() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_guid(Guid::new("pageAAAAAAAA")))
+            .expect("Should apply visit");
+        assert_eq!(fetch_page_info(&conn, &url).unwrap().unwrap().page.guid, "pageAAAAAAAA");
+
+        // The GUID of an existing page isn't changed...
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_guid(Guid::new("pageBBBBBBBB")))
+            .expect("Should apply visit");
+        assert_eq!(fetch_page_info(&conn, &url).unwrap().unwrap().page.guid, "pageAAAAAAAA");
+
+        // ...and new pages can't steal it, or use invalid ones.
+        for (url, guid) in &[("https://www.example.com/a", "pageAAAAAAAA"),
+                             ("https://www.example.com/b", "invalid")] {
+            let url = Url::parse(url).unwrap();
+            apply_observation(&conn, VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_guid(Guid::new(guid)))
+                .expect("Should apply visit");
+            let page = fetch_page_info(&conn, &url).unwrap().unwrap().page;
+            assert_ne!(page.guid, *guid);
+            assert!(page.guid.is_valid_for_places());
+        }
+    }
+
+    #[test]
+    fn test_set_page_title() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit");
+        let before = fetch_page_info(&conn, &url).unwrap().unwrap();
+
+        // A title-only observation changes the title, and nothing else.
+        assert_eq!(apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_title("Example".to_string()))
+            .expect("Should apply title"), None);
+        let after = fetch_page_info(&conn, &url).unwrap().unwrap();
+        assert_eq!(after.page.title, "Example");
+        assert_eq!(after.page.frecency, before.page.frecency);
+        assert_eq!(after.page.visit_count_local, 1);
+
+        set_page_title(&conn, &url, "Renamed").expect("Should set title");
+        assert_eq!(fetch_page_info(&conn, &url).unwrap().unwrap().page.title, "Renamed");
+
+        // Titles for new pages add the page, without a visit.
+        let new_url = Url::parse("https://www.example.com/new").unwrap();
+        set_page_title(&conn, &new_url, "New").expect("Should set title");
+        let page = fetch_page_info(&conn, &new_url).unwrap().unwrap().page;
+        assert_eq!(page.title, "New");
+        assert_eq!(page.visit_count_local, 0);
+        assert_eq!(get_visited(&conn, &[new_url]).unwrap(), vec![false]);
+
+        // `about:` URLs are rejected by the default policy.
+        let rejected = Url::parse("about:blank").unwrap();
+        set_page_title(&conn, &rejected, "Blank").expect("Should ignore title");
+        assert!(fetch_page_info(&conn, &rejected).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sync_change_counter() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let page = |conn: &PlacesDb| fetch_page_info(conn, &url).unwrap().unwrap().page;
+        let visit = |conn: &PlacesDb, visit_type: VisitTransition, is_remote: bool| {
+            apply_observation(conn, VisitObservation::new(url.clone())
+                .with_visit_type(visit_type)
+                .with_is_remote(is_remote))
+                .expect("Should apply visit");
+        };
+
+        // New pages need uploading, and so does the local visit.
+        visit(&conn, VisitTransition::Link, false);
+        assert_eq!(page(&conn).sync_status, SyncStatus::New);
+        assert_eq!(page(&conn).sync_change_counter, 2);
+
+        // Pretend we synced.
+        conn.execute_batch("UPDATE moz_places SET sync_status = 2, sync_change_counter = 0").unwrap();
+        assert_eq!(page(&conn).sync_status, SyncStatus::Normal);
+
+        // Remote visits and unchanged titles aren't changes...
+        visit(&conn, VisitTransition::Link, true);
+        set_page_title(&conn, &url, "").expect("Should set title");
+        assert_eq!(page(&conn).sync_change_counter, 0);
+
+        // ...but new titles, local visits, and removing visits are.
+        set_page_title(&conn, &url, "Example").expect("Should set title");
+        assert_eq!(page(&conn).sync_change_counter, 1);
+        apply_observation(&conn, VisitObservation::new(url.clone())
+            .with_title("Example".to_string()))
+            .expect("Should apply title");
+        assert_eq!(page(&conn).sync_change_counter, 1);
+        visit(&conn, VisitTransition::Download, false);
+        assert_eq!(page(&conn).sync_change_counter, 2);
+        assert!(delete_download(&conn, &url).expect("Should delete download"));
+        assert_eq!(page(&conn).sync_change_counter, 3);
+        assert_eq!(page(&conn).sync_status, SyncStatus::Normal);
+    }
+
+    #[test]
+    fn test_get_highlights() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        let day = 24 * 60 * 60 * 1000;
+        let visit = |url: &str, visit_type: VisitTransition, at: u64| {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(visit_type)
+                .with_at(Timestamp(at)))
+                .expect("Should apply visit");
+        };
+        for i in 0..10 {
+            visit("https://a.example.com/often", VisitTransition::Link, now.0 - 4 * day - i * 1000);
+        }
+        visit("https://b.example.com/once", VisitTransition::Link, now.0 - 1000);
+        // Loses to the page on the same host that's visited more often.
+        visit("https://a.example.com/recent", VisitTransition::Link, now.0 - 1000);
+        // Only seen as a redirect target, hidden, or too old.
+        visit("https://c.example.com/target", VisitTransition::RedirectTemporary, now.0 - 1000);
+        visit("https://d.example.com/frame", VisitTransition::FramedLink, now.0 - 1000);
+        visit("https://e.example.com/old", VisitTransition::Typed, now.0 - 40 * day);
+
+        let urls = |limit| -> Vec<String> {
+            get_highlights_at(&conn, limit, now)
+                .expect("Should get highlights")
+                .into_iter()
+                .map(|h| h.url.into_string())
+                .collect()
+        };
+        assert_eq!(urls(10), vec!["https://a.example.com/often", "https://b.example.com/once"]);
+        assert_eq!(urls(1), vec!["https://a.example.com/often"]);
+    }
+
+    #[test]
+    fn test_search_history() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let now = Timestamp::now();
+        for &(url, title, age) in &[
+            ("https://www.example.com/rust", "Learning Rust", 3),
+            ("https://rust.example.org/", "Something else", 1),
+            ("https://www.example.com/other", "Rust and other metals", 2),
+            ("https://www.example.com/unrelated", "Unrelated", 0),
+            ("https://www.example.com/aerger", "Ärger im Büro", 4),
+        ] {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(VisitTransition::Link)
+                .with_title(title.to_string())
+                .with_at(Timestamp(now.0 - age * 1000)))
+                .expect("Should apply visit");
+        }
+        // A page without visits shouldn't be found.
+        set_page_title(&conn, &Url::parse("https://unvisited.example.com/").unwrap(), "Rust")
+            .expect("Should set title");
+
+        let search = |query: &str, limit: u32, offset: u32| -> Vec<String> {
+            search_history(&conn, query, limit, offset)
+                .expect("Should search")
+                .into_iter()
+                .map(|r| r.url.into_string())
+                .collect()
+        };
+        // Matches in both the title and url come first, then title matches,
+        // then url matches, with ties broken by recency.
+        assert_eq!(search("RUST", 10, 0), vec![
+            "https://www.example.com/rust",
+            "https://www.example.com/other",
+            "https://rust.example.org/",
+        ]);
+        assert_eq!(search("rust", 1, 1), vec!["https://www.example.com/other"]);
+        // Every word has to match.
+        assert_eq!(search("rust metals", 10, 0), vec!["https://www.example.com/other"]);
+        assert!(search("rust nope", 10, 0).is_empty());
+        assert!(search("   ", 10, 0).is_empty());
+        // Only ASCII is case-insensitive, the same as SQLite's `lower()`.
+        assert_eq!(search("ÄRGER im", 10, 0), vec!["https://www.example.com/aerger"]);
+        assert!(search("ärger", 10, 0).is_empty());
+        // Long queries don't run out of SQL variables.
+        let long_query = vec!["rust"; 2000].join(" ");
+        assert_eq!(search(&long_query, 1, 0), vec!["https://www.example.com/rust"]);
+    }
+
+    #[test]
+    fn test_get_visits_paginated() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let visits = [
+            ("https://www.example.com/1", 1000, VisitTransition::Link),
+            ("https://www.example.com/2", 2000, VisitTransition::Link),
+            ("https://www.example.com/2", 3000, VisitTransition::Reload),
+            ("https://www.example.com/3", 4000, VisitTransition::Typed),
+            ("https://www.example.com/4", 4000, VisitTransition::Embed),
+            ("https://www.example.com/5", 4000, VisitTransition::Link),
+        ];
+        for &(url, at, visit_type) in &visits {
+            apply_observation(&conn, VisitObservation::new(Url::parse(url).unwrap())
+                .with_at(Timestamp(at))
+                .with_visit_type(visit_type))
+                .expect("Should apply visit");
+        }
+        let urls = |page: &HistoryVisitPage| -> Vec<String> {
+            page.visits.iter().map(|v| v.url.path().to_string()).collect()
+        };
+
+        let exclude = VisitTransitionSet::EMBED;
+        let page = get_visits_paginated(&conn, Timestamp(5000), 0, 2, exclude, false).unwrap();
+        assert_eq!(urls(&page), vec!["/3", "/5"]);
+        assert_eq!((page.bound, page.offset), (Timestamp(4000), 2));
+
+        // Visits newer than the bound don't affect the next page.
+        apply_observation(&conn, VisitObservation::new(Url::parse("https://www.example.com/6").unwrap())
+            .with_at(Timestamp(6000))
+            .with_visit_type(VisitTransition::Link))
+            .expect("Should apply visit");
+
+        let page = get_visits_paginated(&conn, page.bound, page.offset, 2, exclude, false).unwrap();
+        assert_eq!(urls(&page), vec!["/2", "/2"]);
+        assert_eq!((page.bound, page.offset), (Timestamp(2000), 1));
+
+        let page = get_visits_paginated(&conn, page.bound, page.offset, 2, exclude, false).unwrap();
+        assert_eq!(urls(&page), vec!["/1"]);
+        let page = get_visits_paginated(&conn, page.bound, page.offset, 2, exclude, false).unwrap();
+        assert!(page.visits.is_empty());
+
+        // Deduping collapses the two adjacent visits to /2.
+        let page = get_visits_paginated(&conn, Timestamp(4000), 2, 10, exclude, true).unwrap();
+        assert_eq!(urls(&page), vec!["/2", "/1"]);
+        assert_eq!((page.bound, page.offset), (Timestamp(1000), 1));
     }
 }
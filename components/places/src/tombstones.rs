@@ -0,0 +1,162 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Tombstones for deleted history, which the history sync engine uploads so
+//! that other devices delete it too. Without them, the next sync would see
+//! the deleted pages and visits on the server and bring them back.
+//!
+//! Tombstones are recorded by triggers, so every way of deleting history
+//! (`storage::delete_visits_for`, `storage::delete_visits_for_origin`,
+//! `storage::delete_download`, and so on) leaves them:
+//!
+//! - Deleting a page which has been synced leaves a page tombstone, with its
+//!   GUID, in `moz_places_tombstones`.
+//! - Deleting some of the visits of a synced page which we keep (because it
+//!   has other visits, or is bookmarked) leaves a visit tombstone, with its
+//!   page and date, in `moz_historyvisit_tombstones`. The page's change
+//!   counter is bumped too, so the engine uploads it without those visits.
+//!
+//! Pages and visits which haven't been synced can't be on the server, so
+//! they don't get tombstones. Adding a page or visit again removes its
+//! tombstone, and the engine should skip incoming pages and visits which
+//! have one. Once it's uploaded them it can remove them; until then, they're
+//! kept until maintenance prunes them (see `maintenance::run_maintenance`).
+
+use rusqlite::Row;
+
+use db::PlacesDb;
+use error::*;
+use sql_support::ConnExt;
+use types::Timestamp;
+use types_support::Guid;
+
+/// A deleted page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageTombstone {
+    pub guid: Guid,
+    pub time_deleted: Timestamp,
+}
+
+impl PageTombstone {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            guid: row.get_checked("guid")?,
+            time_deleted: row.get_checked("time_deleted")?,
+        })
+    }
+}
+
+/// A deleted visit to a page which still exists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisitTombstone {
+    pub page_guid: Guid,
+    pub visit_date: Timestamp,
+}
+
+impl VisitTombstone {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            page_guid: row.get_checked("guid")?,
+            visit_date: row.get_checked("visit_date")?,
+        })
+    }
+}
+
+/// Returns every page tombstone, oldest first.
+pub fn get_page_tombstones(db: &impl ConnExt) -> Result<Vec<PageTombstone>> {
+    let mut stmt = db.conn().prepare_cached("
+        SELECT guid, time_deleted FROM moz_places_tombstones
+        ORDER BY time_deleted")?;
+    let rows = stmt.query_and_then_named(&[], PageTombstone::from_row)?;
+    rows.collect()
+}
+
+/// Returns every visit tombstone, grouped by page.
+pub fn get_visit_tombstones(db: &impl ConnExt) -> Result<Vec<VisitTombstone>> {
+    let mut stmt = db.conn().prepare_cached("
+        SELECT h.guid, t.visit_date
+        FROM moz_historyvisit_tombstones t
+        JOIN moz_places h ON h.id = t.place_id
+        ORDER BY t.place_id, t.visit_date")?;
+    let rows = stmt.query_and_then_named(&[], VisitTombstone::from_row)?;
+    rows.collect()
+}
+
+/// Removes page tombstones recorded before `older_than`, and visit
+/// tombstones for visits made before it, and returns how many were removed.
+/// Visit tombstones don't record when the visit was deleted, but visits are
+/// usually deleted not long after they're made, and old visits are the
+/// least useful anyway.
+pub fn prune_tombstones(db: &PlacesDb, older_than: Timestamp) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let pages = tx.execute_named_cached("
+        DELETE FROM moz_places_tombstones
+        WHERE time_deleted < :older_than
+           OR guid IN (SELECT guid FROM moz_places)",
+        &[(":older_than", &older_than)])?;
+    let visits = tx.execute_named_cached(
+        "DELETE FROM moz_historyvisit_tombstones WHERE visit_date < :older_than",
+        &[(":older_than", &older_than)])?;
+    tx.commit()?;
+    Ok(pages + visits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observation::VisitObservation;
+    use storage::{apply_observation, delete_visits_for, delete_visits_for_origin};
+    use types::VisitTransition;
+    use url::Url;
+
+    fn visit(conn: &PlacesDb, url: &Url, date: Timestamp) {
+        apply_observation(conn, VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(date)).expect("should apply");
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let synced = Url::parse("https://example.com/synced").unwrap();
+        let unsynced = Url::parse("https://example.com/unsynced").unwrap();
+        let now = Timestamp::now();
+        let recent = Timestamp(now.0 - 1000);
+        visit(&conn, &synced, Timestamp(now.0 - 2000));
+        visit(&conn, &synced, recent);
+        conn.execute_batch("UPDATE moz_places SET sync_status = 2, sync_change_counter = 0").unwrap();
+        visit(&conn, &unsynced, now);
+        let guid: Guid = conn.query_one(
+            "SELECT guid FROM moz_places WHERE url = 'https://example.com/synced'").unwrap();
+        let change_counter = || -> u32 {
+            conn.query_one("SELECT sync_change_counter FROM moz_places WHERE url = 'https://example.com/synced'")
+                .unwrap()
+        };
+
+        // Removing a synced page's recent visit leaves a visit tombstone,
+        // which goes away if the visit comes back. The page is changed, so
+        // that it's uploaded without the visit.
+        assert_eq!(delete_visits_for(&conn, &synced, recent).unwrap(), 1);
+        assert_eq!(change_counter(), 1);
+        assert_eq!(get_visit_tombstones(&conn).unwrap(),
+                   vec![VisitTombstone { page_guid: guid.clone(), visit_date: recent }]);
+        assert!(get_page_tombstones(&conn).unwrap().is_empty());
+        visit(&conn, &synced, recent);
+        assert!(get_visit_tombstones(&conn).unwrap().is_empty());
+
+        // Visit tombstones are replaced by a page tombstone once the page
+        // goes. The unsynced page doesn't need one.
+        assert_eq!(delete_visits_for(&conn, &synced, recent).unwrap(), 1);
+        assert_eq!(delete_visits_for_origin(&conn, &synced).unwrap(), 2);
+        assert!(get_visit_tombstones(&conn).unwrap().is_empty());
+        let tombstones = get_page_tombstones(&conn).unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].guid, guid);
+        assert!(tombstones[0].time_deleted >= now);
+
+        assert_eq!(prune_tombstones(&conn, recent).unwrap(), 0);
+        assert_eq!(prune_tombstones(&conn, Timestamp(Timestamp::now().0 + 1)).unwrap(), 1);
+        assert!(get_page_tombstones(&conn).unwrap().is_empty());
+    }
+}
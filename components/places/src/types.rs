@@ -3,92 +3,14 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::{fmt};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::iter::FromIterator;
 
-use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, ValueRef}};
+use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, FromSqlError, ValueRef}};
 use rusqlite::Result as RusqliteResult;
 
 use serde;
 
-// XXX - copied from logins - surprised it's not in `sync`
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
-pub struct SyncGuid(pub String);
-
-impl AsRef<str> for SyncGuid {
-    fn as_ref(&self) -> &str {
-        self.0.as_ref()
-    }
-}
-
-impl<T> From<T> for SyncGuid where T: Into<String> {
-    fn from(x: T) -> SyncGuid {
-        SyncGuid(x.into())
-    }
-}
-
-impl ToSql for SyncGuid {
-    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
-        Ok(ToSqlOutput::from(self.0.clone())) // cloning seems wrong?
-    }
-}
-
-impl FromSql for SyncGuid {
-    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
-        value.as_str().map(|v| SyncGuid(v.to_string()))
-    }
-}
-
-// Typesafe way to manage timestamps.
-// We should probably work out how to share this too?
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize, Default)]
-pub struct Timestamp(pub u64);
-
-impl Timestamp {
-    pub fn now() -> Self {
-        SystemTime::now().into()
-    }
-}
-
-impl From<Timestamp> for u64 {
-    #[inline]
-    fn from(ts: Timestamp) -> Self { ts.0 }
-}
-
-impl From<SystemTime> for Timestamp {
-    #[inline]
-    fn from(st: SystemTime) -> Self {
-        let d = st.duration_since(UNIX_EPOCH).unwrap(); // hrmph - unwrap doesn't seem ideal
-        Timestamp((d.as_secs() as u64) * 1000 + ((d.subsec_nanos() as u64) / 1_000_000))
-    }
-}
-
-impl From<u64> for Timestamp {
-    #[inline]
-    fn from(ts: u64) -> Self {
-        assert!(ts != 0);
-        Timestamp(ts)
-    }
-}
-
-impl fmt::Display for Timestamp {
-    #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl ToSql for Timestamp {
-    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
-        Ok(ToSqlOutput::from(self.0 as i64)) // hrm - no u64 in rusqlite
-    }
-}
-
-impl FromSql for Timestamp {
-    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
-        value.as_i64().map(|v| Timestamp(v as u64)) // hrm - no u64
-    }
-}
-
+pub use types_support::Timestamp;
 
 // NOTE: These discriminator values are the same as those used by Desktop
 // Firefox and are what is written to the database. We also duplicate them
@@ -119,6 +41,13 @@ impl ToSql for VisitTransition {
     }
 }
 
+impl FromSql for VisitTransition {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        VisitTransition::from_primitive(u8::column_result(value)?)
+            .ok_or_else(|| FromSqlError::InvalidType)
+    }
+}
+
 impl VisitTransition {
     pub fn from_primitive(p: u8) -> Option<Self> {
         match p {
@@ -136,6 +65,58 @@ impl VisitTransition {
     }
 }
 
+bitflags! {
+    /// A set of visit transition types, for APIs which filter visits by
+    /// type. Bit `n` is set if the set contains the transition with the
+    /// value `n`, which is also how sets are passed over the FFI, as a single
+    /// `i32`.
+    pub struct VisitTransitionSet: u32 {
+        const LINK = 1 << (VisitTransition::Link as u32);
+        const TYPED = 1 << (VisitTransition::Typed as u32);
+        const BOOKMARK = 1 << (VisitTransition::Bookmark as u32);
+        const EMBED = 1 << (VisitTransition::Embed as u32);
+        const REDIRECT_PERMANENT = 1 << (VisitTransition::RedirectPermanent as u32);
+        const REDIRECT_TEMPORARY = 1 << (VisitTransition::RedirectTemporary as u32);
+        const DOWNLOAD = 1 << (VisitTransition::Download as u32);
+        const FRAMED_LINK = 1 << (VisitTransition::FramedLink as u32);
+        const RELOAD = 1 << (VisitTransition::Reload as u32);
+    }
+}
+
+impl VisitTransitionSet {
+    /// Makes a set from a bitmask passed over the FFI. Bits which aren't for
+    /// a known transition are ignored.
+    #[inline]
+    pub fn from_ffi(bits: i32) -> Self {
+        VisitTransitionSet::from_bits_truncate(bits as u32)
+    }
+
+    #[inline]
+    pub fn contains_transition(&self, t: VisitTransition) -> bool {
+        self.contains(t.into())
+    }
+
+    /// Returns the transitions in the set, in order of their values.
+    pub fn transitions(&self) -> impl Iterator<Item = VisitTransition> {
+        let bits = self.bits();
+        (0u8..32).filter(move |&v| bits & (1 << v) != 0)
+                 .filter_map(VisitTransition::from_primitive)
+    }
+}
+
+impl From<VisitTransition> for VisitTransitionSet {
+    #[inline]
+    fn from(t: VisitTransition) -> Self {
+        VisitTransitionSet::from_bits_truncate(1 << (t as u32))
+    }
+}
+
+impl FromIterator<VisitTransition> for VisitTransitionSet {
+    fn from_iter<I: IntoIterator<Item = VisitTransition>>(iter: I) -> Self {
+        iter.into_iter().fold(VisitTransitionSet::empty(), |set, t| set | t.into())
+    }
+}
+
 struct VisitTransitionSerdeVisitor;
 
 impl<'de> serde::de::Visitor<'de> for VisitTransitionSerdeVisitor {
@@ -169,6 +150,46 @@ impl<'de> serde::Deserialize<'de> for VisitTransition {
     }
 }
 
+/// Whether a page or bookmark has been synced, stored in the `sync_status`
+/// column of `moz_places` and the `syncStatus` column of `moz_bookmarks`.
+/// These are the same values desktop uses.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncStatus {
+    // We don't know whether the item has been synced; for example, it was
+    // added before we tracked this.
+    Unknown = 0,
+    // The item has never been uploaded.
+    New = 1,
+    // The item has been synced, and only needs to be uploaded if its change
+    // counter is non-zero.
+    Normal = 2,
+}
+
+impl SyncStatus {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(SyncStatus::Unknown),
+            1 => Some(SyncStatus::New),
+            2 => Some(SyncStatus::Normal),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for SyncStatus {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for SyncStatus {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        SyncStatus::from_primitive(u8::column_result(value)?)
+            .ok_or_else(|| FromSqlError::InvalidType)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,5 +198,22 @@ mod tests {
     fn test_primitive() {
         assert_eq!(Some(VisitTransition::Link), VisitTransition::from_primitive(1));
         assert_eq!(None, VisitTransition::from_primitive(99));
+        assert_eq!(Some(SyncStatus::Normal), SyncStatus::from_primitive(2));
+        assert_eq!(None, SyncStatus::from_primitive(3));
+    }
+
+    #[test]
+    fn test_transition_set() {
+        let set: VisitTransitionSet = vec![VisitTransition::Reload, VisitTransition::Link].into_iter().collect();
+        assert_eq!(set, VisitTransitionSet::LINK | VisitTransitionSet::RELOAD);
+        assert!(set.contains_transition(VisitTransition::Link));
+        assert!(!set.contains_transition(VisitTransition::Typed));
+        assert_eq!(set.transitions().collect::<Vec<_>>(),
+                   vec![VisitTransition::Link, VisitTransition::Reload]);
+        // Bit 0 and anything above `Reload` aren't transitions.
+        assert_eq!(VisitTransitionSet::from_ffi((1 << 0) | (1 << 2) | (1 << 20) | ::std::i32::MIN),
+                   VisitTransitionSet::TYPED);
+        assert_eq!(VisitTransitionSet::from_ffi(set.bits() as i32), set);
+        assert!(VisitTransitionSet::empty().transitions().next().is_none());
     }
 }
@@ -0,0 +1,161 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+use url::Url;
+
+use util;
+
+/// The longest URL we'll store, the same as desktop's
+/// `places.history.maxUrlLength` default.
+pub const DEFAULT_MAX_URL_LENGTH: usize = 65536;
+
+/// The longest title we'll store, in bytes. Desktop's `TITLE_LENGTH_MAX` is
+/// the same number of UTF-16 code units.
+pub const DEFAULT_MAX_TITLE_LENGTH: usize = 4096;
+
+// Schemes which desktop refuses to add to history (see `nsNavHistory::CanAddURI`
+// and `BaseHistory::CanStore`). These are either internal to the browser, or
+// would be unsafe or pointless to store (e.g. `javascript:` and `data:`).
+// Note that `place:` isn't here - those are bookmark queries, which belong in
+// moz_places even though they aren't real URLs.
+const DEFAULT_BLOCKED_SCHEMES: &[&str] = &[
+    "about",
+    "blob",
+    "chrome",
+    "data",
+    "imap",
+    "javascript",
+    "mailbox",
+    "moz-anno",
+    "news",
+    "resource",
+    "view-source",
+    "wyciwyg",
+];
+
+/// Decides which URLs we're willing to store, and how much of their titles.
+/// Observations of URLs the policy rejects are ignored, and longer titles are
+/// truncated, so that a page can't bloat the database, or make a sync record
+/// too big to upload. URLs which are too long are rejected rather than
+/// truncated, since a truncated URL is a different page.
+///
+/// The default matches desktop, but embedders can adjust it (for example, to
+/// block their own internal schemes) with `PlacesApi::new_with_url_policy`,
+/// or `PlacesDb::set_url_policy` for a single connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlPolicy {
+    blocked_schemes: HashSet<String>,
+    max_url_length: usize,
+    max_title_length: usize,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            blocked_schemes: DEFAULT_BLOCKED_SCHEMES.iter().map(|s| s.to_string()).collect(),
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
+            max_title_length: DEFAULT_MAX_TITLE_LENGTH,
+        }
+    }
+}
+
+impl UrlPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject URLs with `scheme` (which shouldn't include the trailing `:`).
+    pub fn block_scheme(mut self, scheme: &str) -> Self {
+        self.blocked_schemes.insert(scheme.to_ascii_lowercase());
+        self
+    }
+
+    /// Accept URLs with `scheme`, even if it's blocked by default.
+    pub fn allow_scheme(mut self, scheme: &str) -> Self {
+        self.blocked_schemes.remove(&scheme.to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_max_url_length(mut self, max_url_length: usize) -> Self {
+        self.max_url_length = max_url_length;
+        self
+    }
+
+    /// The longest title to store, in bytes.
+    pub fn with_max_title_length(mut self, max_title_length: usize) -> Self {
+        self.max_title_length = max_title_length;
+        self
+    }
+
+    pub fn can_add_url(&self, url: &Url) -> bool {
+        // `Url` always lowercases the scheme for us.
+        !self.is_url_too_long(url) && !self.blocked_schemes.contains(url.scheme())
+    }
+
+    /// Returns true if `url` is longer than we'll store, whatever its scheme.
+    /// Unlike blocked schemes, this applies to bookmarks too.
+    pub fn is_url_too_long(&self, url: &Url) -> bool {
+        url.as_str().len() > self.max_url_length
+    }
+
+    /// Returns as much of `title` as we'll store: at most the maximum title
+    /// length, cut at a character boundary.
+    pub fn truncate_title<'a>(&self, title: &'a str) -> &'a str {
+        util::slice_up_to(title, self.max_title_length)
+    }
+}
+
+/// Returns true if the default policy allows storing `url`.
+pub fn can_add_url(url: &Url) -> bool {
+    UrlPolicy::default().can_add_url(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Url {
+        Url::parse(s).expect("valid url")
+    }
+
+    #[test]
+    fn test_default_policy() {
+        assert!(can_add_url(&parse("https://www.example.com/")));
+        assert!(can_add_url(&parse("file:///etc/passwd")));
+        assert!(can_add_url(&parse("place:sort=8&maxResults=10")));
+        assert!(!can_add_url(&parse("about:config")));
+        assert!(!can_add_url(&parse("JavaScript:alert(1)")));
+        assert!(!can_add_url(&parse("data:text/plain,hi")));
+        assert!(!can_add_url(&parse("view-source:https://www.example.com/")));
+
+        let long = format!("https://www.example.com/{}", "a".repeat(DEFAULT_MAX_URL_LENGTH));
+        assert!(!can_add_url(&parse(&long)));
+    }
+
+    #[test]
+    fn test_custom_policy() {
+        let policy = UrlPolicy::new()
+            .block_scheme("Moz-Extension")
+            .allow_scheme("about")
+            .with_max_url_length(30);
+        assert!(policy.can_add_url(&parse("about:config")));
+        assert!(!policy.can_add_url(&parse("moz-extension://1234/page.html")));
+        assert!(!policy.can_add_url(&parse("https://www.example.com/a/long/path")));
+        assert!(policy.can_add_url(&parse("https://www.example.com/")));
+    }
+
+    #[test]
+    fn test_truncate_title() {
+        let long = "a".repeat(DEFAULT_MAX_TITLE_LENGTH + 1);
+        assert_eq!(UrlPolicy::default().truncate_title(&long).len(), DEFAULT_MAX_TITLE_LENGTH);
+
+        let policy = UrlPolicy::new().with_max_title_length(6);
+        assert_eq!(policy.truncate_title("Short"), "Short");
+        assert_eq!(policy.truncate_title("Longer title"), "Longer");
+        // "é" is two bytes, so we can't cut after the "a" and three of them.
+        assert_eq!(policy.truncate_title("éééé"), "ééé");
+        assert_eq!(policy.truncate_title("aééé"), "aéé");
+    }
+}
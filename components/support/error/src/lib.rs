@@ -0,0 +1,89 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Error codes shared by every component's FFI, so that consuming apps can
+//! handle the same kind of failure (the user needing to reauthenticate, the
+//! device being offline, etc) the same way no matter which component it came
+//! from.
+//!
+//! Codes from 1 to 99 mean the same thing in every component. Codes specific
+//! to one component are allocated from that component's range, which starts
+//! at its `*_BASE` and holds 100 codes. 0 (success) and -1 (panic) are
+//! reserved by `ffi_support`.
+//!
+//! Components map their errors to these codes by implementing
+//! `GetErrorCode`, and pass the result to `ffi_support::ErrorCode::new`.
+
+/// An unexpected error occurred which likely cannot be meaningfully handled
+/// by the application.
+pub const UNEXPECTED: i32 = -2;
+
+/// The FxA credentials are invalid, and should be refreshed.
+pub const AUTH_INVALID: i32 = 1;
+
+/// A network request failed, most likely because the device is offline.
+pub const NETWORK: i32 = 2;
+
+/// The operation was interrupted (for example, an autocomplete query that
+/// was superseded by a newer one), and can be retried.
+pub const INTERRUPTED: i32 = 3;
+
+/// The server asked us to back off. Callers should not try again until
+/// after the time given in the error message.
+pub const BACKOFF: i32 = 4;
+
+/// Either the file is not a database, or it is not encrypted with the
+/// provided encryption key.
+pub const INVALID_KEY: i32 = 5;
+
+/// The start of the range for errors specific to `sync15-adapter`.
+pub const SYNC15_BASE: i32 = 100;
+
+/// The start of the range for errors specific to `logins-sql`.
+pub const LOGINS_BASE: i32 = 200;
+
+/// The start of the range for errors specific to `places`.
+pub const PLACES_BASE: i32 = 300;
+
+/// The start of the range for errors specific to `fxa-client`.
+pub const FXA_BASE: i32 = 400;
+
+/// The start of the range for errors specific to `webext-storage`.
+pub const WEBEXT_STORAGE_BASE: i32 = 500;
+
+const RANGE_SIZE: i32 = 100;
+
+/// Implemented by each component's error type, to map it to one of the
+/// shared codes above, or to one from the component's own range.
+pub trait GetErrorCode {
+    fn error_code(&self) -> i32;
+}
+
+/// Returns true if `code` has the same meaning in every component.
+pub fn is_shared_code(code: i32) -> bool {
+    code == UNEXPECTED || (code > 0 && code < RANGE_SIZE)
+}
+
+/// Returns true if `code` was allocated from the range starting at `base`.
+pub fn is_in_range(code: i32, base: i32) -> bool {
+    code >= base && code < base + RANGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        assert!(is_shared_code(UNEXPECTED));
+        assert!(is_shared_code(INVALID_KEY));
+        assert!(!is_shared_code(0));
+        assert!(!is_shared_code(-1));
+        assert!(!is_shared_code(SYNC15_BASE + 1));
+
+        assert!(is_in_range(SYNC15_BASE + 1, SYNC15_BASE));
+        assert!(!is_in_range(LOGINS_BASE, SYNC15_BASE));
+        assert!(!is_in_range(NETWORK, PLACES_BASE));
+    }
+}
@@ -0,0 +1,111 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use into_ffi::IntoFfi;
+use std::{mem, ptr, slice};
+
+/// A buffer of bytes allocated by Rust, which can be returned over the FFI by value. This is
+/// useful for returning binary data (or data we've serialized to something more compact than
+/// JSON) without the copying and escaping that returning it as a string would require.
+///
+/// The caller (on the other side of the FFI) owns the buffer, and must free it by passing it back
+/// to a destructor defined with [`define_bytebuffer_destructor!`]. It's `#[repr(C)]`, so on that
+/// side it's a struct containing an `int64_t` length followed by a `uint8_t *` pointer.
+///
+/// ## Example
+///
+/// ```rust
+/// # #[macro_use] extern crate ffi_support;
+/// # use ffi_support::{ByteBuffer, ExternError, call_with_output};
+/// #[no_mangle]
+/// pub extern "C" fn mylib_get_bytes(error: &mut ExternError) -> ByteBuffer {
+///     call_with_output(error, || ByteBuffer::from_vec(vec![1, 2, 3]))
+/// }
+///
+/// define_bytebuffer_destructor!(mylib_destroy_bytebuffer);
+/// # fn main() {}
+/// ```
+#[repr(C)]
+#[derive(Debug)]
+pub struct ByteBuffer {
+    len: i64,
+    data: *mut u8,
+}
+
+impl ByteBuffer {
+    /// Take ownership of `bytes`, so that they may be passed over the FFI.
+    pub fn from_vec(bytes: Vec<u8>) -> Self {
+        let mut buf = bytes.into_boxed_slice();
+        let data = buf.as_mut_ptr();
+        let len = buf.len() as i64;
+        mem::forget(buf);
+        ByteBuffer { len, data }
+    }
+
+    /// The number of bytes in the buffer.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Convert the buffer back into a `Vec<u8>`, taking back ownership of the data.
+    ///
+    /// ## Safety
+    ///
+    /// The buffer must have been created with `ByteBuffer::from_vec` (or be the default,
+    /// empty buffer), and not have been modified or freed by the other side of the FFI.
+    pub unsafe fn into_vec(self) -> Vec<u8> {
+        if self.data.is_null() {
+            return Vec::new();
+        }
+        let slice = slice::from_raw_parts_mut(self.data, self.len());
+        Box::from_raw(slice).into_vec()
+    }
+
+    /// Free the buffer. Has the same safety requirements as `into_vec`.
+    pub unsafe fn destroy(self) {
+        drop(self.into_vec())
+    }
+}
+
+impl Default for ByteBuffer {
+    #[inline]
+    fn default() -> Self {
+        ByteBuffer { len: 0, data: ptr::null_mut() }
+    }
+}
+
+impl From<Vec<u8>> for ByteBuffer {
+    #[inline]
+    fn from(bytes: Vec<u8>) -> Self {
+        ByteBuffer::from_vec(bytes)
+    }
+}
+
+unsafe impl IntoFfi for ByteBuffer {
+    type Value = ByteBuffer;
+    #[inline] fn ffi_default() -> Self::Value { ByteBuffer::default() }
+    #[inline] fn into_ffi_value(self) -> Self::Value { self }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let buf = ByteBuffer::from_vec(vec![1, 2, 3, 4]);
+        assert_eq!(buf.len(), 4);
+        assert_eq!(unsafe { buf.into_vec() }, vec![1, 2, 3, 4]);
+
+        let empty = ByteBuffer::ffi_default();
+        assert!(empty.is_empty());
+        assert_eq!(unsafe { empty.into_vec() }, Vec::<u8>::new());
+    }
+}
@@ -50,6 +50,9 @@ use serde_json;
 ///   automatically with [`implement_into_ffi_by_json!`]), allowing `Vec<T>` to be passed back as
 ///   JSON if `T` could be.
 ///     - In the future, we may do this for `serde_json::Value` and `HashMap<String, T>` as well.
+///     - `String` is also tagged, so a `Vec<String>` is returned as a JSON array of strings.
+///
+/// - [`ByteBuffer`], which is returned by value, for binary data.
 ///
 /// None of these are directly helpful for user types though, so macros are provided for the
 /// following cases:
@@ -203,6 +206,10 @@ unsafe impl IntoFfi for String {
     }
 }
 
+// Lets us return lists of strings (e.g. lists of ids) as a JSON array. Note that a lone `String` is
+// still returned as-is, and not JSON encoded.
+impl IntoFfiJsonTag for String {}
+
 // Implement IntoFfi for Option<T> by falling back to ffi_default for None.
 unsafe impl<T: IntoFfi> IntoFfi for Option<T> {
     type Value = <T as IntoFfi>::Value;
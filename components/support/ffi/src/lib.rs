@@ -104,11 +104,13 @@ mod macros;
 mod string;
 mod error;
 mod into_ffi;
+mod bytebuffer;
 
 pub use macros::*;
 pub use string::*;
 pub use error::*;
 pub use into_ffi::*;
+pub use bytebuffer::*;
 
 /// Call a callback that returns a `Result<T, E>` while:
 ///
@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Forwards messages logged with the `log` crate's macros to a callback
+//! provided by the FFI consumer, so that they end up wherever the platform
+//! expects logs to go (logcat on Android, os_log on iOS, etc).
+//!
+//! FFI crates expose this with `define_log_adapter_ffi!`. Each FFI library
+//! has its own copy of the `log` crate's global logger, so each needs its own
+//! adapter.
+//!
+//! The callback is always called from a single background thread owned by
+//! the adapter, rather than from whichever thread logged the message. This
+//! means the callback doesn't need to be reentrant, and that JNA doesn't need
+//! to attach every thread we log from to the JVM.
+
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate ffi_support;
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+// Re-exported for `define_log_adapter_ffi!`.
+#[doc(hidden)]
+pub use ffi_support::{call_with_output, call_with_result, ExternError};
+
+/// The levels passed to the callback. These are the same values as
+/// `android.util.Log`'s priority constants, so that Android can pass them
+/// through unchanged.
+pub mod levels {
+    pub const TRACE: i32 = 2;
+    pub const DEBUG: i32 = 3;
+    pub const INFO: i32 = 4;
+    pub const WARN: i32 = 5;
+    pub const ERROR: i32 = 6;
+}
+
+/// The callback the FFI consumer provides. `tag` is the module path of the
+/// code that logged the message, and may be null. Neither string is valid
+/// once the callback returns.
+pub type LogCallback = extern "C" fn(level: i32, tag: *const c_char, message: *const c_char);
+
+enum LogMessage {
+    Record {
+        level: i32,
+        tag: Option<CString>,
+        message: CString,
+    },
+    Stop,
+}
+
+fn level_to_i32(level: log::Level) -> i32 {
+    match level {
+        log::Level::Trace => levels::TRACE,
+        log::Level::Debug => levels::DEBUG,
+        log::Level::Info => levels::INFO,
+        log::Level::Warn => levels::WARN,
+        log::Level::Error => levels::ERROR,
+    }
+}
+
+fn level_filter_from_i32(level: i32) -> log::LevelFilter {
+    match level {
+        l if l <= levels::TRACE => log::LevelFilter::Trace,
+        levels::DEBUG => log::LevelFilter::Debug,
+        levels::INFO => log::LevelFilter::Info,
+        levels::WARN => log::LevelFilter::Warn,
+        levels::ERROR => log::LevelFilter::Error,
+        _ => log::LevelFilter::Off,
+    }
+}
+
+fn to_cstring(s: &str) -> CString {
+    // Interior nul bytes would make `CString::new` fail, and are vanishingly
+    // unlikely to be important, so we just drop them.
+    CString::new(s.replace('\0', "")).expect("nul bytes were removed")
+}
+
+lazy_static! {
+    // Where the logger sends messages, if an adapter exists.
+    static ref SINK: Mutex<Option<mpsc::Sender<LogMessage>>> = Mutex::new(None);
+    // `log::set_logger` may only succeed once, so we remember whether it did.
+    static ref LOGGER_INSTALLED: bool = log::set_logger(&LOGGER).is_ok();
+}
+
+struct ForwardingLogger;
+
+static LOGGER: ForwardingLogger = ForwardingLogger;
+
+impl log::Log for ForwardingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        // Filtering is done with `log::set_max_level`.
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let sink = match SINK.lock() {
+            Ok(sink) => sink,
+            // Nothing sensible we can do, and we can't log about it...
+            Err(_) => return,
+        };
+        if let Some(tx) = sink.as_ref() {
+            let msg = LogMessage::Record {
+                level: level_to_i32(record.level()),
+                tag: record.module_path().map(to_cstring),
+                message: to_cstring(&record.args().to_string()),
+            };
+            // Only fails if the thread has gone away, in which case the
+            // adapter is being destroyed anyway.
+            let _ = tx.send(msg);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Owns the thread which calls the callback. Logging stops when this is
+/// dropped (or destroyed over the FFI).
+pub struct LogAdapterState {
+    tx: mpsc::Sender<LogMessage>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl LogAdapterState {
+    /// Start forwarding log messages to `callback`. Fails if an adapter
+    /// already exists, or if something other than this crate has already
+    /// installed a global logger.
+    pub fn new(callback: LogCallback) -> Result<Self, ExternError> {
+        if !*LOGGER_INSTALLED {
+            return Err(adapter_error("A different logger has already been installed"));
+        }
+        let mut sink = SINK.lock().map_err(|_| adapter_error("Log sink mutex poisoned"))?;
+        if sink.is_some() {
+            return Err(adapter_error("A log adapter already exists"));
+        }
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("rc_log".into())
+            .spawn(move || {
+                for msg in rx {
+                    match msg {
+                        LogMessage::Stop => break,
+                        LogMessage::Record { level, tag, message } => {
+                            let tag_ptr = tag.as_ref().map_or(ptr::null(), |t| t.as_ptr());
+                            callback(level, tag_ptr, message.as_ptr());
+                        }
+                    }
+                }
+            })
+            .map_err(|e| adapter_error(format!("Failed to start log thread: {}", e)))?;
+        *sink = Some(tx.clone());
+        // Until told otherwise, forward everything but trace logs.
+        log::set_max_level(log::LevelFilter::Debug);
+        Ok(Self { tx, handle: Some(handle) })
+    }
+}
+
+impl Drop for LogAdapterState {
+    fn drop(&mut self) {
+        if let Ok(mut sink) = SINK.lock() {
+            *sink = None;
+        }
+        let _ = self.tx.send(LogMessage::Stop);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+implement_into_ffi_by_pointer!(LogAdapterState);
+
+fn adapter_error(msg: impl Into<String>) -> ExternError {
+    // Error codes are per-library, but this can only fail in ways which are
+    // bugs in the caller, so we don't bother giving it a specific code.
+    ExternError::new_error(ffi_support::ErrorCode::new(1), msg)
+}
+
+/// Only forward messages at `level` (one of the values in `levels`) or more
+/// severe. Levels above `levels::ERROR` turn logging off.
+pub fn set_max_level(level: i32) {
+    log::set_max_level(level_filter_from_i32(level));
+}
+
+/// Define the FFI functions for creating, configuring, and destroying a log
+/// adapter. Names should be unique to your library.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// #[macro_use] extern crate rc_log;
+/// define_log_adapter_ffi!(
+///     mylib_log_adapter_create,
+///     mylib_log_adapter_set_max_level,
+///     mylib_log_adapter_destroy
+/// );
+/// ```
+#[macro_export]
+macro_rules! define_log_adapter_ffi {
+    ($create:ident, $set_max_level:ident, $destroy:ident) => {
+        /// Start forwarding log messages to `callback`. The returned adapter
+        /// must be destroyed with the matching destroy function.
+        #[no_mangle]
+        pub extern "C" fn $create(
+            callback: $crate::LogCallback,
+            error: &mut $crate::ExternError,
+        ) -> *mut $crate::LogAdapterState {
+            $crate::call_with_result(error, || $crate::LogAdapterState::new(callback))
+        }
+
+        #[no_mangle]
+        pub extern "C" fn $set_max_level(level: i32, error: &mut $crate::ExternError) {
+            $crate::call_with_output(error, || $crate::set_max_level(level))
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn $destroy(state: *mut $crate::LogAdapterState) {
+            if !state.is_null() {
+                drop(::std::boxed::Box::from_raw(state))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        assert_eq!(level_filter_from_i32(levels::TRACE), log::LevelFilter::Trace);
+        assert_eq!(level_filter_from_i32(0), log::LevelFilter::Trace);
+        assert_eq!(level_filter_from_i32(levels::WARN), log::LevelFilter::Warn);
+        assert_eq!(level_filter_from_i32(levels::ERROR + 1), log::LevelFilter::Off);
+        for &level in &[log::Level::Trace, log::Level::Info, log::Level::Error] {
+            assert_eq!(level_filter_from_i32(level_to_i32(level)), level.to_level_filter());
+        }
+    }
+
+    #[test]
+    fn test_to_cstring() {
+        assert_eq!(to_cstring("a\0b").to_str().unwrap(), "ab");
+    }
+}
@@ -7,6 +7,8 @@ extern crate log;
 
 extern crate rusqlite;
 
+extern crate failure;
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -14,11 +16,16 @@ mod each_chunk;
 mod repeat;
 mod conn_ext;
 mod maybe_cached;
+mod named_values;
+mod query_plan;
+pub mod maintenance;
 
 pub use repeat::*;
 pub use each_chunk::*;
 pub use conn_ext::*;
 pub use maybe_cached::*;
+pub use named_values::*;
+pub use query_plan::*;
 
 /// In PRAGMA foo='bar', `'bar'` must be a constant string (it cannot be a
 /// bound parameter), so we need to escape manually. According to
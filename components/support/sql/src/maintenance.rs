@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Periodic database maintenance. Each component's database removes data
+//! it no longer needs (expired annotations, old tombstones, and so on) and
+//! reclaims the space in its `run_maintenance` method, and `run_maintenance`
+//! here runs all of them.
+//!
+//! We recommend running maintenance about once a day
+//! (`MAINTENANCE_INTERVAL_SECS`), while the app is idle, for example from a
+//! `JobScheduler` job which requires the device to be idle and charging on
+//! Android, or a `BGProcessingTask` on iOS. It can take a while (vacuuming
+//! rewrites the whole database), so it shouldn't run on the main thread, or
+//! while the user is waiting on a query. Running it more often doesn't do
+//! any harm, but doesn't usually do much good either.
+
+use failure;
+use rusqlite::Connection;
+
+/// How often we recommend running maintenance: once a day.
+pub const MAINTENANCE_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// We vacuum once this percentage of the database's pages are free...
+const VACUUM_FREE_PERCENT: i64 = 20;
+/// ...as long as that's at least this many pages. Tiny databases aren't
+/// worth rewriting.
+const VACUUM_MIN_FREE_PAGES: i64 = 256;
+
+/// A component with a database which needs looking after.
+pub trait Maintenance {
+    /// Runs the component's maintenance tasks. See the module docs for how
+    /// often this should be called.
+    fn run_maintenance(&self) -> Result<(), failure::Error>;
+}
+
+/// Runs maintenance for each of `components`. A component failing doesn't
+/// stop the others from running; the first error is returned once they're
+/// all done.
+pub fn run_maintenance(components: &[&Maintenance]) -> Result<(), failure::Error> {
+    let mut first_error = None;
+    for component in components {
+        if let Err(e) = component.run_maintenance() {
+            warn!("Maintenance failed: {}", e);
+            first_error = first_error.or(Some(e));
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Returns true if enough of the database is free pages (left behind by
+/// deleted rows) that it's worth vacuuming.
+pub fn is_fragmented(conn: &Connection) -> rusqlite::Result<bool> {
+    let page_count: i64 = conn.query_row("PRAGMA page_count", &[], |row| row.get(0))?;
+    let free_pages: i64 = conn.query_row("PRAGMA freelist_count", &[], |row| row.get(0))?;
+    Ok(free_pages >= VACUUM_MIN_FREE_PAGES && free_pages * 100 >= page_count * VACUUM_FREE_PERCENT)
+}
+
+/// Vacuums the database if `is_fragmented` says it's worth it, and returns
+/// whether it did.
+pub fn vacuum_if_fragmented(conn: &Connection) -> rusqlite::Result<bool> {
+    if !is_fragmented(conn)? {
+        return Ok(false);
+    }
+    info!("Vacuuming fragmented database");
+    conn.execute_batch("VACUUM")?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    struct Component {
+        fail: bool,
+        runs: Cell<u32>,
+    }
+
+    impl Maintenance for Component {
+        fn run_maintenance(&self) -> Result<(), failure::Error> {
+            self.runs.set(self.runs.get() + 1);
+            if self.fail {
+                Err(failure::err_msg("failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_maintenance() {
+        let failing = Component { fail: true, runs: Cell::new(0) };
+        let working = Component { fail: false, runs: Cell::new(0) };
+        assert!(run_maintenance(&[&working]).is_ok());
+        assert!(run_maintenance(&[&failing, &working]).is_err());
+        assert_eq!(failing.runs.get(), 1);
+        assert_eq!(working.runs.get(), 2);
+    }
+
+    #[test]
+    fn test_vacuum_if_fragmented() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE t(x);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+            INSERT INTO t SELECT zeroblob(1000) FROM n;").unwrap();
+        assert!(!vacuum_if_fragmented(&conn).unwrap());
+
+        conn.execute_batch("DELETE FROM t").unwrap();
+        assert!(is_fragmented(&conn).unwrap());
+        assert!(vacuum_if_fragmented(&conn).unwrap());
+        assert!(!is_fragmented(&conn).unwrap());
+    }
+}
@@ -0,0 +1,131 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use rusqlite::types::ToSql;
+
+/// Builds the rows of a `VALUES` clause, binding every value as a named parameter. This is
+/// intended for chunked queries which join against a list of values (usually with an index
+/// column, so that results can be matched back up with the input), and saves numbering `?`s
+/// by hand, or splicing values into the SQL.
+///
+/// Each parameter is named after its column and row, so `:url_2` is the `url` of the third row.
+/// Since every value is bound, each row uses one variable per column; callers should make sure
+/// that `num_rows * columns.len()` stays below `default_max_variable_number()`.
+///
+/// # Example
+///
+/// ```rust
+/// # use sql_support::NamedValues;
+/// let mut values = NamedValues::new(&["idx", "url"]);
+/// values.push(0).push("https://example.com");
+/// values.push(1).push("https://example.org");
+/// assert_eq!(values.to_string(), "(:idx_0,:url_0),(:idx_1,:url_1)");
+/// assert_eq!(values.num_rows(), 2);
+/// ```
+pub struct NamedValues<'a> {
+    columns: &'a [&'a str],
+    names: Vec<String>,
+    values: Vec<Box<dyn ToSql + 'a>>,
+}
+
+impl<'a> NamedValues<'a> {
+    /// Panics if `columns` is empty.
+    pub fn new(columns: &'a [&'a str]) -> Self {
+        Self::with_capacity(columns, 0)
+    }
+
+    pub fn with_capacity(columns: &'a [&'a str], num_rows: usize) -> Self {
+        assert!(!columns.is_empty(), "A VALUES row needs at least one column");
+        NamedValues {
+            columns,
+            names: Vec::with_capacity(num_rows * columns.len()),
+            values: Vec::with_capacity(num_rows * columns.len()),
+        }
+    }
+
+    /// Adds the value of the next column, starting a new row once the last one is full.
+    pub fn push(&mut self, value: impl ToSql + 'a) -> &mut Self {
+        let n = self.values.len();
+        let column = self.columns[n % self.columns.len()];
+        self.names.push(format!(":{}_{}", column, n / self.columns.len()));
+        self.values.push(Box::new(value));
+        self
+    }
+
+    /// The number of rows, including the last one if it isn't full yet.
+    pub fn num_rows(&self) -> usize {
+        (self.values.len() + self.columns.len() - 1) / self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the parameters for a statement that uses these values, to pass to
+    /// `execute_named`, `query_map_named`, etc.
+    pub fn params(&self) -> Vec<(&str, &dyn ToSql)> {
+        self.names.iter()
+            .map(|name| name.as_str())
+            .zip(self.values.iter().map(|value| &**value as &dyn ToSql))
+            .collect()
+    }
+}
+
+/// Formats as the rows, ready to go after `VALUES`. Panics if the last row isn't full.
+impl<'a> fmt::Display for NamedValues<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        assert_eq!(self.values.len() % self.columns.len(), 0,
+                   "The last row of a VALUES clause is missing values");
+        for (i, row) in self.names.chunks(self.columns.len()).enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "({})", row.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_named_values() {
+        let conn = Connection::open_in_memory().unwrap();
+        let words = ["one", "two", "three"];
+        let mut values = NamedValues::with_capacity(&["idx", "word"], words.len());
+        assert!(values.is_empty());
+        for (i, word) in words.iter().enumerate() {
+            values.push(i as i64).push(*word);
+        }
+        assert_eq!(values.num_rows(), 3);
+
+        let sql = format!("
+            WITH v(idx, word) AS (VALUES {})
+            SELECT idx, word FROM v ORDER BY idx DESC", values);
+        let params = values.params();
+        let mut stmt = conn.prepare(&sql).unwrap();
+        let rows: Vec<(i64, String)> = stmt.query_map_named(&params, |row| (row.get(0), row.get(1)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![
+            (2, "three".to_string()),
+            (1, "two".to_string()),
+            (0, "one".to_string()),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_incomplete_row() {
+        let mut values = NamedValues::new(&["idx", "word"]);
+        values.push(0).push("zero").push(1);
+        assert_eq!(values.num_rows(), 2);
+        values.to_string();
+    }
+}
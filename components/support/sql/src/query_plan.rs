@@ -0,0 +1,70 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use rusqlite::{Connection, Result as SqlResult};
+
+/// Returns the `detail` column of each row of `EXPLAIN QUERY PLAN` for `sql`, for example
+/// `SEARCH TABLE moz_places USING INDEX url_hashindex (url_hash=?)`. Parameters don't need to be
+/// bound to explain a query, so any are left as NULL.
+pub fn query_plan(conn: &Connection, sql: &str) -> SqlResult<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    let rows = stmt.query_map(&[], |row| row.get::<_, String>(3))?;
+    rows.collect()
+}
+
+/// Logs the query plan for `sql` at debug level, if the `log_query_plans` feature is enabled.
+/// Otherwise, this does nothing, so it's fine to leave calls to it in production code.
+#[cfg(feature = "log_query_plans")]
+pub fn maybe_log_plan(conn: &Connection, sql: &str) {
+    match query_plan(conn, sql) {
+        Ok(plan) => debug!("Query plan for:\n{}\n{}", sql, plan.join("\n")),
+        Err(e) => warn!("Failed to explain query: {}", e),
+    }
+}
+
+#[cfg(not(feature = "log_query_plans"))]
+#[inline]
+pub fn maybe_log_plan(_conn: &Connection, _sql: &str) {}
+
+/// Panics if the plan for `sql` doesn't use the index named `index`. This is meant for tests, so
+/// that a schema or query change that turns a lookup into a table scan doesn't go unnoticed.
+pub fn assert_uses_index(conn: &Connection, sql: &str, index: &str) {
+    let plan = query_plan(conn, sql).expect("Failed to explain query");
+    let uses_index = plan.iter().any(|detail| {
+        let words: Vec<&str> = detail.split_whitespace().collect();
+        words.windows(2).any(|w| w[0] == "INDEX" && w[1] == index)
+    });
+    assert!(uses_index, "Expected the query to use {}, but its plan was:\n{}\nfor:\n{}",
+            index, plan.join("\n"), sql);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT, b TEXT);
+            CREATE INDEX t_a ON t(a);
+        ").unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_query_plan() {
+        let conn = setup();
+        assert!(!query_plan(&conn, "SELECT * FROM t WHERE a = :a").unwrap().is_empty());
+        assert!(query_plan(&conn, "SELECT * FROM not_a_table").is_err());
+        assert_uses_index(&conn, "SELECT * FROM t WHERE a = :a", "t_a");
+        maybe_log_plan(&conn, "SELECT * FROM t WHERE a = :a");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_uses_index_scan() {
+        let conn = setup();
+        assert_uses_index(&conn, "SELECT * FROM t WHERE b = :b", "t_a");
+    }
+}
@@ -0,0 +1,197 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::{fmt, ops};
+
+use base64;
+use rand::{self, RngCore};
+use serde;
+
+/// The length of the guids we generate, and that places requires.
+const GUID_LEN: usize = 12;
+
+/// The sync server only accepts ids up to this many bytes.
+const MAX_SYNC_ID_LEN: usize = 64;
+
+/// A globally unique identifier for a record, as used by sync (and by places
+/// and logins as the primary identifier for a record).
+///
+/// Any string can be stored in a `Guid`, since records we receive from other
+/// clients (or that we've stored in the past) might not follow the same rules
+/// we do. Use `is_valid_for_sync_server` or `is_valid_for_places` to check.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Guid(String);
+
+impl Guid {
+    /// Create a guid from a string, without checking its validity.
+    #[inline]
+    pub fn new(s: &str) -> Self {
+        Guid(s.into())
+    }
+
+    /// Generate a new random guid. These are 12 characters of the base64url
+    /// alphabet (which encode 9 random bytes), like the guids desktop
+    /// generates.
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 9];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Guid(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Returns true if the sync server will accept this as a record id: it
+    /// must be non-empty, no more than 64 bytes, and only contain printable
+    /// ASCII characters other than `,` (which the server uses as a separator
+    /// for lists of ids).
+    pub fn is_valid_for_sync_server(&self) -> bool {
+        !self.0.is_empty()
+            && self.0.len() <= MAX_SYNC_ID_LEN
+            && self.0.bytes().all(|b| b >= b' ' && b <= b'~' && b != b',')
+    }
+
+    /// Returns true if this guid has the form places requires: exactly 12
+    /// characters from the base64url alphabet. Guids generated with
+    /// `Guid::random()` always satisfy this.
+    pub fn is_valid_for_places(&self) -> bool {
+        self.0.len() == GUID_LEN && self.0.bytes().all(is_base64url_byte)
+    }
+}
+
+#[inline]
+fn is_base64url_byte(b: u8) -> bool {
+    (b'a' <= b && b <= b'z')
+        || (b'A' <= b && b <= b'Z')
+        || (b'0' <= b && b <= b'9')
+        || b == b'-'
+        || b == b'_'
+}
+
+impl<'a> From<&'a str> for Guid {
+    #[inline]
+    fn from(s: &'a str) -> Self {
+        Guid::new(s)
+    }
+}
+
+impl From<String> for Guid {
+    #[inline]
+    fn from(s: String) -> Self {
+        Guid(s)
+    }
+}
+
+impl From<Guid> for String {
+    #[inline]
+    fn from(guid: Guid) -> Self {
+        guid.0
+    }
+}
+
+impl AsRef<str> for Guid {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ops::Deref for Guid {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Guid {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Guid {
+    #[inline]
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl serde::Serialize for Guid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Guid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Guid)
+    }
+}
+
+#[cfg(feature = "rusqlite_support")]
+mod sql {
+    use super::Guid;
+    use rusqlite::{self, types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef}};
+
+    impl ToSql for Guid {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+            Ok(ToSqlOutput::from(self.as_str()))
+        }
+    }
+
+    impl FromSql for Guid {
+        fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+            value.as_str().map(Guid::new)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random() {
+        let a = Guid::random();
+        let b = Guid::random();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), GUID_LEN);
+        assert!(a.is_valid_for_places());
+        assert!(a.is_valid_for_sync_server());
+    }
+
+    #[test]
+    fn test_validity() {
+        assert!(Guid::new("bookmarkAAAA").is_valid_for_places());
+        assert!(Guid::new("a-_0123456Zz").is_valid_for_places());
+        assert!(!Guid::new("bookmarkAAA").is_valid_for_places());
+        assert!(!Guid::new("bookmarkAAA=").is_valid_for_places());
+
+        assert!(Guid::new("{5cd6b3d5-9c2e-4b25-bd46-d9e4b4cb0d9e}").is_valid_for_sync_server());
+        assert!(!Guid::new("").is_valid_for_sync_server());
+        assert!(!Guid::new("a,b").is_valid_for_sync_server());
+        assert!(!Guid::new("tab\t").is_valid_for_sync_server());
+        assert!(!Guid::new(&"x".repeat(65)).is_valid_for_sync_server());
+        assert!(Guid::new(&"x".repeat(64)).is_valid_for_sync_server());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let g = Guid::from("abcdefghijkl");
+        assert_eq!(g, "abcdefghijkl");
+        assert_eq!(g.as_str(), "abcdefghijkl");
+        assert_eq!(format!("{}", g), "abcdefghijkl");
+        assert_eq!(String::from(g.clone()), "abcdefghijkl");
+        assert_eq!(Guid::from("abcdefghijkl".to_string()), g);
+    }
+}
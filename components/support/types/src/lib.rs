@@ -0,0 +1,19 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Types shared between the various Rust components, which don't belong in
+//! any one of them.
+
+extern crate base64;
+extern crate rand;
+extern crate serde;
+
+#[cfg(feature = "rusqlite_support")]
+extern crate rusqlite;
+
+mod guid;
+mod timestamp;
+
+pub use guid::Guid;
+pub use timestamp::{Timestamp, MAX_FUTURE_SKEW_MS};
@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde;
+
+/// How far in the future `Timestamp::clamp_future` lets a timestamp be.
+/// Clocks on different devices (or on a device and a server) are rarely
+/// more than a few seconds apart, unless one of them is badly wrong.
+pub const MAX_FUTURE_SKEW_MS: u64 = 60 * 1000;
+
+/// A time, in milliseconds since the unix epoch. Serialized as a number.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    /// Returns the time `d` after this one, or `None` if that overflows.
+    #[inline]
+    pub fn checked_add(self, d: Duration) -> Option<Timestamp> {
+        self.0.checked_add(duration_ms(d)?).map(Timestamp)
+    }
+
+    /// Returns the time `d` before this one, or `None` if that's before the
+    /// epoch.
+    #[inline]
+    pub fn checked_sub(self, d: Duration) -> Option<Timestamp> {
+        self.0.checked_sub(duration_ms(d)?).map(Timestamp)
+    }
+
+    /// Returns how long after `earlier` this is, or `None` if it's before.
+    #[inline]
+    pub fn duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_millis)
+    }
+
+    /// Returns this timestamp, or `now` plus `MAX_FUTURE_SKEW_MS` if it's
+    /// later than that. Timestamps from a device whose clock is ahead would
+    /// otherwise sort before everything else, indefinitely.
+    #[inline]
+    pub fn clamp_future(self, now: Timestamp) -> Timestamp {
+        let latest = Timestamp(now.0.saturating_add(MAX_FUTURE_SKEW_MS));
+        if self > latest {
+            latest
+        } else {
+            self
+        }
+    }
+}
+
+// `None` if `d` has more milliseconds than fit in a `u64`.
+fn duration_ms(d: Duration) -> Option<u64> {
+    d.as_secs()
+        .checked_mul(1000)?
+        .checked_add(u64::from(d.subsec_millis()))
+}
+
+impl From<Timestamp> for u64 {
+    #[inline]
+    fn from(ts: Timestamp) -> Self { ts.0 }
+}
+
+impl From<SystemTime> for Timestamp {
+    #[inline]
+    fn from(st: SystemTime) -> Self {
+        // A clock set before the epoch is wrong, but that's no reason to
+        // panic.
+        let d = st.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Timestamp(d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    #[inline]
+    fn from(ts: Timestamp) -> Self {
+        UNIX_EPOCH + Duration::from_millis(ts.0)
+    }
+}
+
+impl From<u64> for Timestamp {
+    #[inline]
+    fn from(ts: u64) -> Self {
+        assert!(ts != 0);
+        Timestamp(ts)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl serde::Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Timestamp)
+    }
+}
+
+#[cfg(feature = "rusqlite_support")]
+mod sql {
+    use super::Timestamp;
+    use rusqlite::{self, types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef}};
+
+    impl ToSql for Timestamp {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+            Ok(ToSqlOutput::from(self.0 as i64)) // hrm - no u64 in rusqlite
+        }
+    }
+
+    impl FromSql for Timestamp {
+        fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+            value.as_i64().map(|v| Timestamp(v as u64)) // hrm - no u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        let ts = Timestamp(10_000);
+        assert_eq!(ts.checked_add(Duration::from_millis(1500)), Some(Timestamp(11_500)));
+        assert_eq!(ts.checked_sub(Duration::from_secs(10)), Some(Timestamp(0)));
+        assert_eq!(ts.checked_sub(Duration::from_secs(11)), None);
+        assert_eq!(Timestamp(u64::max_value()).checked_add(Duration::from_millis(1)), None);
+        assert_eq!(ts.checked_add(Duration::from_secs(u64::max_value())), None);
+        assert_eq!(ts.duration_since(Timestamp(4000)), Some(Duration::from_secs(6)));
+        assert_eq!(Timestamp(4000).duration_since(ts), None);
+        assert_eq!(Timestamp::from(SystemTime::from(ts)), ts);
+    }
+
+    #[test]
+    fn test_clamp_future() {
+        let now = Timestamp(1_500_000_000_000);
+        assert_eq!(now.clamp_future(now), now);
+        assert_eq!(Timestamp(1000).clamp_future(now), Timestamp(1000));
+        let skewed = Timestamp(now.0 + MAX_FUTURE_SKEW_MS + 1);
+        assert_eq!(skewed.clamp_future(now), Timestamp(now.0 + MAX_FUTURE_SKEW_MS));
+    }
+}
@@ -0,0 +1,32 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncing open tabs with other devices, using the Sync `tabs` collection.
+//! Each client uploads one record, keyed by its client ID, holding every tab
+//! it has open.
+//!
+//! Tabs change constantly, and are only useful while they're fresh, so
+//! nothing here is persisted: the app calls `TabsStore::set_local_tabs`
+//! before each sync, and reads `TabsStore::get_remote_tabs` after it.
+//! `TabsStore` implements `sync15_adapter::Store`, so it can be synced along
+//! with other stores using `sync15_adapter::sync_multiple`.
+
+extern crate sync15_adapter as sync;
+
+#[macro_use]
+extern crate log;
+
+extern crate failure;
+
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+#[macro_use]
+extern crate serde_derive;
+
+mod record;
+mod store;
+
+pub use store::{ClientRemoteTabs, RemoteTab, TabsStore};
@@ -0,0 +1,27 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The format of records in the `tabs` collection, which is shared with
+//! desktop and iOS.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecordTab {
+    pub title: String,
+    /// The most recent URL first.
+    pub url_history: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Seconds since the epoch.
+    pub last_used: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecord {
+    /// The client's ID, from the `clients` collection.
+    pub id: String,
+    pub client_name: String,
+    pub tabs: Vec<TabsRecordTab>,
+}
@@ -0,0 +1,284 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure;
+use sync::{
+    CollectionRequest,
+    IncomingChangeset,
+    OutgoingChangeset,
+    Payload,
+    ServerTimestamp,
+    Store,
+};
+use sync::telemetry;
+
+use record::{TabsRecord, TabsRecordTab};
+
+const COLLECTION_NAME: &str = "tabs";
+
+// How long a device's tabs are kept after it last uploaded them. This matches
+// the TTL desktop uploads its record with, so the server deletes records from
+// devices that stop syncing, and we forget them after the same time, even if
+// we haven't synced since.
+const TABS_TTL_SECS: u64 = 21 * 24 * 60 * 60;
+
+/// A tab open on a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteTab {
+    pub title: String,
+    /// The URLs the tab has visited, most recent (that is, the current URL)
+    /// first.
+    pub url_history: Vec<String>,
+    pub icon: Option<String>,
+    /// Milliseconds since the epoch.
+    pub last_used: u64,
+}
+
+impl RemoteTab {
+    fn from_record(tab: TabsRecordTab) -> Self {
+        Self {
+            title: tab.title,
+            url_history: tab.url_history,
+            icon: tab.icon,
+            last_used: tab.last_used.saturating_mul(1000),
+        }
+    }
+
+    fn to_record(&self) -> TabsRecordTab {
+        TabsRecordTab {
+            title: self.title.clone(),
+            url_history: self.url_history.clone(),
+            icon: self.icon.clone(),
+            last_used: self.last_used / 1000,
+        }
+    }
+}
+
+/// The tabs open on another device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientRemoteTabs {
+    pub client_id: String,
+    pub client_name: String,
+    pub remote_tabs: Vec<RemoteTab>,
+    /// When the device last uploaded its tabs, in milliseconds since the
+    /// epoch.
+    pub last_modified: u64,
+}
+
+pub struct TabsStore {
+    client_id: String,
+    client_name: String,
+    local_tabs: RefCell<Option<Vec<RemoteTab>>>,
+    remote_clients: RefCell<HashMap<String, ClientRemoteTabs>>,
+}
+
+impl TabsStore {
+    /// `client_id` must be this device's ID in the `clients` collection,
+    /// since other devices use it to find our name and send us commands.
+    pub fn new(client_id: impl Into<String>, client_name: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_name: client_name.into(),
+            local_tabs: RefCell::new(None),
+            remote_clients: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the tabs to upload on the next sync. Until this is called, we
+    /// don't upload anything, so that we don't replace the tabs on the server
+    /// with an empty list just because the app hasn't told us about them yet.
+    pub fn set_local_tabs(&self, tabs: Vec<RemoteTab>) {
+        *self.local_tabs.borrow_mut() = Some(tabs);
+    }
+
+    /// Returns the tabs of every other device, as of the last sync, with the
+    /// most recently synced device first. Devices which haven't uploaded
+    /// their tabs for longer than the TTL are left out.
+    pub fn get_remote_tabs(&self) -> Vec<ClientRemoteTabs> {
+        self.get_remote_tabs_at(SystemTime::now())
+    }
+
+    fn get_remote_tabs_at(&self, now: SystemTime) -> Vec<ClientRemoteTabs> {
+        let expired_before = now.checked_sub(Duration::from_secs(TABS_TTL_SECS))
+            .map_or(0, millis_since_epoch);
+        let mut remote_clients = self.remote_clients.borrow_mut();
+        remote_clients.retain(|_, client| client.last_modified >= expired_before);
+        let mut clients = remote_clients.values().cloned().collect::<Vec<_>>();
+        clients.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+        clients
+    }
+}
+
+impl Store for TabsStore {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        // We always fetch every record, so anything we don't see has expired.
+        let mut remote_clients = HashMap::new();
+        for (payload, modified) in inbound.changes {
+            if payload.is_tombstone() || payload.id() == self.client_id {
+                continue;
+            }
+            // The server may not have deleted expired records yet.
+            let age = inbound.timestamp.duration_since(modified);
+            if age.map_or(false, |age| age.as_secs() >= TABS_TTL_SECS) {
+                continue;
+            }
+            let record: TabsRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring invalid tabs record: {}", e);
+                    telem.failed += 1;
+                    continue;
+                }
+            };
+            remote_clients.insert(record.id.clone(), ClientRemoteTabs {
+                client_id: record.id,
+                client_name: record.client_name,
+                remote_tabs: record.tabs.into_iter().map(RemoteTab::from_record).collect(),
+                last_modified: modified.as_millis(),
+            });
+        }
+        *self.remote_clients.borrow_mut() = remote_clients;
+
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), inbound.timestamp);
+        if let Some(ref tabs) = *self.local_tabs.borrow() {
+            let record = TabsRecord {
+                id: self.client_id.clone(),
+                client_name: self.client_name.clone(),
+                tabs: tabs.iter().map(RemoteTab::to_record).collect(),
+            };
+            let mut payload = Payload::from_record(record)?;
+            payload.data.insert("ttl".into(), TABS_TTL_SECS.into());
+            outgoing.changes.push(payload);
+        }
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &self,
+        _new_timestamp: ServerTimestamp,
+        _records_synced: &[String],
+    ) -> result::Result<(), failure::Error> {
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        Ok(CollectionRequest::new(COLLECTION_NAME).full())
+    }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        self.remote_clients.borrow_mut().clear();
+        Ok(())
+    }
+
+    // Our own tabs come from the app, so there's nothing else to wipe.
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        self.reset()
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    // When the tests sync, in seconds since the epoch.
+    const NOW: f64 = 2_000_000.0;
+
+    fn at(secs: f64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    }
+
+    fn incoming(records: Vec<(serde_json::Value, f64)>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(NOW));
+        changeset.changes = records.into_iter()
+            .map(|(json, ts)| (Payload::from_json(json).unwrap(), ServerTimestamp(ts)))
+            .collect();
+        changeset
+    }
+
+    #[test]
+    fn test_sync() {
+        let store = TabsStore::new("local-client", "My Phone");
+        // Nothing's uploaded until we know our tabs.
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut telemetry::EngineIncoming::default())
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+
+        store.set_local_tabs(vec![RemoteTab {
+            title: "Example".into(),
+            url_history: vec!["https://example.com/".into()],
+            icon: None,
+            last_used: 1_500_000_000_123,
+        }]);
+        let mut telem = telemetry::EngineIncoming::default();
+        let outgoing = store.apply_incoming(incoming(vec![
+            (json!({
+                "id": "laptop",
+                "clientName": "My Laptop",
+                "tabs": [{
+                    "title": "Mozilla",
+                    "urlHistory": ["https://www.mozilla.org/", "https://mozilla.org/"],
+                    "icon": "https://www.mozilla.org/favicon.ico",
+                    "lastUsed": 1_500_000_000,
+                }],
+            }), NOW - 1000.0),
+            (json!({"id": "desktop", "clientName": "My Desktop", "tabs": []}), NOW - 500.0),
+            // Our own record is ignored.
+            (json!({"id": "local-client", "clientName": "My Phone", "tabs": []}), NOW - 800.0),
+            (json!({"id": "invalid"}), NOW - 1000.0),
+            // So are records the server should have expired.
+            (json!({"id": "old", "clientName": "My Old Phone", "tabs": []}),
+             NOW - TABS_TTL_SECS as f64),
+        ]), &mut telem).unwrap();
+        assert_eq!(telem.failed, 1);
+
+        let remote = store.get_remote_tabs_at(at(NOW));
+        assert_eq!(remote.iter().map(|c| c.client_id.as_str()).collect::<Vec<_>>(),
+                   vec!["desktop", "laptop"]);
+        assert_eq!(remote[1].client_name, "My Laptop");
+        assert_eq!(remote[1].last_modified, 1_999_000_000);
+        assert_eq!(remote[1].remote_tabs, vec![RemoteTab {
+            title: "Mozilla".into(),
+            url_history: vec!["https://www.mozilla.org/".into(), "https://mozilla.org/".into()],
+            icon: Some("https://www.mozilla.org/favicon.ico".into()),
+            last_used: 1_500_000_000_000,
+        }]);
+
+        assert_eq!(outgoing.changes.len(), 1);
+        let record: TabsRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(record.id, "local-client");
+        assert_eq!(record.client_name, "My Phone");
+        assert_eq!(record.tabs[0].last_used, 1_500_000_000);
+        assert_eq!(outgoing.changes[0].data["ttl"], TABS_TTL_SECS);
+        let bso = outgoing.changes[0].clone().into_bso(COLLECTION_NAME.into());
+        assert_eq!(bso.ttl, Some(TABS_TTL_SECS as u32));
+
+        // Devices are forgotten once their tabs expire, even without syncing.
+        let remote = store.get_remote_tabs_at(at(NOW - 999.0 + TABS_TTL_SECS as f64));
+        assert_eq!(remote.iter().map(|c| c.client_id.as_str()).collect::<Vec<_>>(),
+                   vec!["desktop"]);
+
+        // Clients which disappear from the server are forgotten.
+        store.apply_incoming(incoming(vec![]), &mut telemetry::EngineIncoming::default()).unwrap();
+        assert!(store.get_remote_tabs().is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `storage.sync` API that extensions see. Keys and values are JSON, and
+//! the arguments accept the same shapes the WebExtension API does.
+
+use serde_json::{self, Map, Value as JsonValue};
+use sql_support::ConnExt;
+
+use db::StorageDb;
+use error::*;
+
+/// The most data, in bytes, an extension can store. Like desktop, this is
+/// the length of each key plus the length of its value as JSON.
+pub const QUOTA_BYTES: usize = 102_400;
+
+/// The most data, in bytes, a single item can use.
+pub const QUOTA_BYTES_PER_ITEM: usize = 8_192;
+
+/// The most items an extension can store.
+pub const MAX_ITEMS: usize = 512;
+
+pub(crate) fn get_from_db(conn: &impl ConnExt, ext_id: &str) -> Result<Option<Map<String, JsonValue>>> {
+    let data = conn.try_query_row(
+        "SELECT data FROM storage_sync_data WHERE ext_id = :ext_id",
+        &[(":ext_id", &ext_id)],
+        |row| row.get_checked::<_, Option<String>>(0),
+        true,
+    )?;
+    Ok(match data {
+        Some(Some(s)) => match serde_json::from_str(&s)? {
+            JsonValue::Object(map) => Some(map),
+            _ => {
+                warn!("Stored data for an extension isn't an object; ignoring it");
+                None
+            }
+        },
+        _ => None,
+    })
+}
+
+// An extension with no data is stored as NULL, so that clearing it syncs as
+// a tombstone.
+fn save_to_db(conn: &impl ConnExt, ext_id: &str, data: &Map<String, JsonValue>) -> Result<()> {
+    let data = if data.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(data)?)
+    };
+    let changed = conn.execute_named_cached("
+        UPDATE storage_sync_data
+        SET data = :data,
+            sync_change_counter = sync_change_counter + 1
+        WHERE ext_id = :ext_id",
+        &[(":ext_id", &ext_id), (":data", &data)])?;
+    if changed == 0 {
+        conn.execute_named_cached("
+            INSERT INTO storage_sync_data(ext_id, data, sync_change_counter)
+            VALUES (:ext_id, :data, 1)",
+            &[(":ext_id", &ext_id), (":data", &data)])?;
+    }
+    Ok(())
+}
+
+fn check_quota(data: &Map<String, JsonValue>) -> Result<()> {
+    if data.len() > MAX_ITEMS {
+        return Err(QuotaReason::MaxItems.into());
+    }
+    let mut total = 0;
+    for (key, value) in data {
+        let item_bytes = key.len() + serde_json::to_string(value)?.len();
+        if item_bytes > QUOTA_BYTES_PER_ITEM {
+            return Err(QuotaReason::ItemBytes.into());
+        }
+        total += item_bytes;
+    }
+    if total > QUOTA_BYTES {
+        return Err(QuotaReason::TotalBytes.into());
+    }
+    Ok(())
+}
+
+/// Returns the keys in `keys` to look up, or remove.
+fn key_names(keys: &JsonValue) -> Result<Vec<&str>> {
+    match keys {
+        JsonValue::String(key) => Ok(vec![key.as_str()]),
+        JsonValue::Array(keys) => keys.iter()
+            .map(|key| key.as_str().ok_or_else(|| {
+                ErrorKind::InvalidArgument("Keys must be strings").into()
+            }))
+            .collect(),
+        _ => Err(ErrorKind::InvalidArgument("Expected a key, or an array of keys").into()),
+    }
+}
+
+/// Fetches an extension's items, as an object. `keys` may be null, to fetch
+/// every item; a key or array of keys; or an object whose values are used
+/// as defaults for missing items.
+pub fn get(db: &StorageDb, ext_id: &str, keys: JsonValue) -> Result<JsonValue> {
+    let mut data = get_from_db(db, ext_id)?.unwrap_or_default();
+    let result = match keys {
+        JsonValue::Null => data,
+        JsonValue::Object(defaults) => defaults.into_iter()
+            .map(|(key, default)| {
+                let value = data.remove(&key).unwrap_or(default);
+                (key, value)
+            })
+            .collect(),
+        keys => key_names(&keys)?
+            .into_iter()
+            .filter_map(|key| data.remove(key).map(|value| (key.to_string(), value)))
+            .collect(),
+    };
+    Ok(JsonValue::Object(result))
+}
+
+/// Adds or replaces the items in `items`, which must be an object. Fails
+/// without changing anything if that would exceed one of the quotas.
+pub fn set(db: &StorageDb, ext_id: &str, items: JsonValue) -> Result<()> {
+    let items = match items {
+        JsonValue::Object(items) => items,
+        _ => return Err(ErrorKind::InvalidArgument("Expected an object").into()),
+    };
+    let tx = db.unchecked_transaction()?;
+    let mut data = get_from_db(&tx, ext_id)?.unwrap_or_default();
+    for (key, value) in items {
+        data.insert(key, value);
+    }
+    check_quota(&data)?;
+    save_to_db(&tx, ext_id, &data)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes the items named by `keys`, a key or array of keys.
+pub fn remove(db: &StorageDb, ext_id: &str, keys: JsonValue) -> Result<()> {
+    let keys = key_names(&keys)?;
+    let tx = db.unchecked_transaction()?;
+    if let Some(mut data) = get_from_db(&tx, ext_id)? {
+        let count = data.len();
+        for key in keys {
+            data.remove(key);
+        }
+        if data.len() != count {
+            save_to_db(&tx, ext_id, &data)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes all of an extension's items.
+pub fn clear(db: &StorageDb, ext_id: &str) -> Result<()> {
+    let tx = db.unchecked_transaction()?;
+    if get_from_db(&tx, ext_id)?.is_some() {
+        save_to_db(&tx, ext_id, &Map::new())?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set_remove() {
+        let db = StorageDb::open_in_memory().unwrap();
+        let ext_id = "ext@example.com";
+        assert_eq!(get(&db, ext_id, JsonValue::Null).unwrap(), json!({}));
+
+        set(&db, ext_id, json!({"a": 1, "b": {"c": [true]}})).unwrap();
+        set(&db, ext_id, json!({"a": "two"})).unwrap();
+        assert_eq!(get(&db, ext_id, JsonValue::Null).unwrap(), json!({"a": "two", "b": {"c": [true]}}));
+        assert_eq!(get(&db, ext_id, json!("a")).unwrap(), json!({"a": "two"}));
+        assert_eq!(get(&db, ext_id, json!(["b", "missing"])).unwrap(), json!({"b": {"c": [true]}}));
+        assert_eq!(get(&db, ext_id, json!({"a": 0, "missing": 3})).unwrap(), json!({"a": "two", "missing": 3}));
+        // Other extensions can't see it.
+        assert_eq!(get(&db, "other@example.com", JsonValue::Null).unwrap(), json!({}));
+
+        remove(&db, ext_id, json!("a")).unwrap();
+        assert_eq!(get(&db, ext_id, JsonValue::Null).unwrap(), json!({"b": {"c": [true]}}));
+        clear(&db, ext_id).unwrap();
+        assert_eq!(get(&db, ext_id, JsonValue::Null).unwrap(), json!({}));
+
+        assert!(set(&db, ext_id, json!([1])).is_err());
+        assert!(get(&db, ext_id, json!(3)).is_err());
+        assert!(remove(&db, ext_id, json!([1])).is_err());
+    }
+
+    #[test]
+    fn test_quota() {
+        let db = StorageDb::open_in_memory().unwrap();
+        let ext_id = "ext@example.com";
+        let big = "x".repeat(QUOTA_BYTES_PER_ITEM);
+        match set(&db, ext_id, json!({"big": big})).unwrap_err().kind() {
+            ErrorKind::QuotaError(QuotaReason::ItemBytes) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        let mut items = Map::new();
+        for i in 0..=MAX_ITEMS {
+            items.insert(format!("key{}", i), json!(i));
+        }
+        match set(&db, ext_id, JsonValue::Object(items)).unwrap_err().kind() {
+            ErrorKind::QuotaError(QuotaReason::MaxItems) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        // Nothing was written.
+        assert_eq!(get(&db, ext_id, JsonValue::Null).unwrap(), json!({}));
+    }
+}
@@ -0,0 +1,75 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::ops::Deref;
+use std::path::Path;
+
+use rusqlite::{Connection, types::{ToSql, FromSql}};
+use sql_support::ConnExt;
+
+use error::*;
+use schema;
+
+pub struct StorageDb {
+    pub db: Connection,
+}
+
+impl StorageDb {
+    pub fn with_connection(db: Connection) -> Result<Self> {
+        // `temp_store = 2` keeps temp tables in memory, which is required on
+        // Android. See the comment in `LoginDb::with_connection`.
+        db.execute_batch("PRAGMA temp_store = 2;")?;
+        let storage = Self { db };
+        schema::init(&storage)?;
+        Ok(storage)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::with_connection(Connection::open(path)?)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Ok(Self::with_connection(Connection::open_in_memory()?)?)
+    }
+
+    pub(crate) fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
+        self.execute_named_cached(
+            "REPLACE INTO meta (key, value) VALUES (:key, :value)",
+            &[(":key", &key as &ToSql), (":value", value)]
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.try_query_row(
+            "SELECT value FROM meta WHERE key = :key",
+            &[(":key", &key as &ToSql)],
+            |row| Ok::<_, Error>(row.get_checked(0)?),
+            true
+        )?)
+    }
+
+    pub fn set_global_state(&self, global_state: &str) -> Result<()> {
+        self.put_meta(schema::GLOBAL_STATE_META_KEY, &global_state)
+    }
+
+    pub fn get_global_state(&self) -> Result<Option<String>> {
+        self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
+    }
+}
+
+impl ConnExt for StorageDb {
+    #[inline]
+    fn conn(&self) -> &Connection {
+        &self.db
+    }
+}
+
+impl Deref for StorageDb {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        &self.db
+    }
+}
@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use failure::{Fail, Context, Backtrace};
+use std::{self, fmt};
+use std::boxed::Box;
+use rusqlite;
+use serde_json;
+use sync;
+use error_support::{self, GetErrorCode};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Codes for errors specific to extension storage, allocated from
+/// `error_support::WEBEXT_STORAGE_BASE`. See `GetErrorCode`.
+pub mod error_codes {
+    use error_support::WEBEXT_STORAGE_BASE;
+
+    /// A `set` would have exceeded one of the storage quotas.
+    pub const QUOTA_EXCEEDED: i32 = WEBEXT_STORAGE_BASE + 1;
+
+    /// The values or keys passed to `set`, `get` or `remove` weren't of a
+    /// type those functions accept.
+    pub const INVALID_ARGUMENT: i32 = WEBEXT_STORAGE_BASE + 2;
+}
+
+#[derive(Debug)]
+pub struct Error(Box<Context<ErrorKind>>);
+
+impl Fail for Error {
+    #[inline]
+    fn cause(&self) -> Option<&Fail> {
+        self.0.cause()
+    }
+
+    #[inline]
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.0.backtrace()
+    }
+}
+
+impl fmt::Display for Error {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+impl Error {
+    #[inline]
+    pub fn kind(&self) -> &ErrorKind {
+        &*self.0.get_context()
+    }
+}
+
+impl GetErrorCode for Error {
+    fn error_code(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::QuotaError(_) => error_codes::QUOTA_EXCEEDED,
+            ErrorKind::InvalidArgument(_) => error_codes::INVALID_ARGUMENT,
+            ErrorKind::SyncAdapterError(e) => e.error_code(),
+            // We can't destructure `err` without bringing in the libsqlite3_sys crate
+            // (and I'd really rather not) so we can't put this in the match.
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::OperationInterrupted => {
+                error_support::INTERRUPTED
+            }
+            _ => error_support::UNEXPECTED,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    #[inline]
+    fn from(kind: ErrorKind) -> Error {
+        Error(Box::new(Context::new(kind)))
+    }
+}
+
+impl From<Context<ErrorKind>> for Error {
+    #[inline]
+    fn from(inner: Context<ErrorKind>) -> Error {
+        Error(Box::new(inner))
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Quota exceeded: {}", _0)]
+    QuotaError(QuotaReason),
+
+    #[fail(display = "Invalid argument: {}", _0)]
+    InvalidArgument(&'static str),
+
+    #[fail(display = "Error synchronizing: {}", _0)]
+    SyncAdapterError(#[fail(cause)] sync::Error),
+
+    #[fail(display = "Error parsing JSON data: {}", _0)]
+    JsonError(#[fail(cause)] serde_json::Error),
+
+    #[fail(display = "Error executing SQL: {}", _0)]
+    SqlError(#[fail(cause)] rusqlite::Error),
+}
+
+macro_rules! impl_from_error {
+    ($(($variant:ident, $type:ty)),+) => ($(
+        impl From<$type> for ErrorKind {
+            #[inline]
+            fn from(e: $type) -> ErrorKind {
+                ErrorKind::$variant(e)
+            }
+        }
+
+        impl From<$type> for Error {
+            #[inline]
+            fn from(e: $type) -> Error {
+                ErrorKind::from(e).into()
+            }
+        }
+    )*);
+}
+
+impl_from_error! {
+    (SyncAdapterError, sync::Error),
+    (JsonError, serde_json::Error),
+    (SqlError, rusqlite::Error),
+    (QuotaError, QuotaReason)
+}
+
+/// Which of the limits in `api` a `set` would have exceeded.
+#[derive(Debug, Fail)]
+pub enum QuotaReason {
+    #[fail(display = "The total size of an extension's data is limited")]
+    TotalBytes,
+    #[fail(display = "The size of a single item is limited")]
+    ItemBytes,
+    #[fail(display = "The number of items an extension can store is limited")]
+    MaxItems,
+}
@@ -0,0 +1,44 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A synced key-value store for extensions, implementing the
+//! `storage.sync` WebExtension API. Each extension gets its own namespace of
+//! JSON values, which is synced as a single record, using `sync15-adapter`.
+//!
+//! `StorageDb` implements `sync15_adapter::Store`, so it can be synced along
+//! with other stores using `sync15_adapter::sync_multiple`, persisting the
+//! global state with `StorageDb::set_global_state`.
+
+extern crate sync15_adapter as sync;
+
+#[macro_use]
+extern crate log;
+
+extern crate failure;
+
+#[macro_use]
+extern crate failure_derive;
+
+extern crate rusqlite;
+
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate sql_support;
+extern crate types_support;
+extern crate error_support;
+
+mod error;
+mod schema;
+mod db;
+pub mod api;
+mod store;
+
+pub use error::{error_codes, Error, ErrorKind, QuotaReason, Result};
+pub use db::StorageDb;
+pub use store::COLLECTION_NAME;
@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Extension Storage Schema v1
+//! ===========================
+//!
+//! Each extension's data is a single JSON object, which is stored (and
+//! synced) as a whole. There are three tables:
+//!
+//! - `storage_sync_data`: The local data for each extension.
+//! - `storage_sync_mirror`: What we last saw on the server for each
+//!   extension.
+//! - `meta`: Sync metadata, the same as `loginsSyncMeta` in logins.
+//!
+//! ## `storage_sync_data`
+//!
+//! - `ext_id`: The extension's ID.
+//!
+//! - `data`: The extension's data, as a JSON object, or NULL if the extension
+//!   has cleared its data and that hasn't been synced yet.
+//!
+//! - `sync_change_counter`: The number of local changes made since the data
+//!   was last uploaded. Data with a counter of 0 is the same as the mirror.
+//!
+//! ## `storage_sync_mirror`
+//!
+//! - `guid`: The ID of the record on the server. Records are found by their
+//!   `ext_id`, since two devices may both upload a record for the same
+//!   extension before seeing each other's.
+//!
+//! - `ext_id`: The extension's ID.
+//!
+//! - `data`: The extension's data on the server, or NULL if the record was a
+//!   tombstone.
+
+use error::*;
+use sql_support::ConnExt;
+use db::StorageDb;
+
+pub const VERSION: i64 = 1;
+
+const CREATE_DATA_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS storage_sync_data (
+        ext_id TEXT NOT NULL PRIMARY KEY,
+        data TEXT,
+        sync_change_counter INTEGER NOT NULL DEFAULT 1
+    )
+";
+
+const CREATE_MIRROR_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS storage_sync_mirror (
+        guid TEXT NOT NULL PRIMARY KEY,
+        ext_id TEXT NOT NULL UNIQUE,
+        data TEXT
+    )
+";
+
+const CREATE_META_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value NOT NULL
+    )
+";
+
+// The records we're uploading, with their data and counters as of when we
+// fetched them, so that changes made during the upload aren't marked as
+// synced.
+const CREATE_OUTGOING_TABLE_SQL: &'static str = "
+    CREATE TEMP TABLE IF NOT EXISTS storage_sync_outgoing (
+        guid TEXT NOT NULL PRIMARY KEY,
+        ext_id TEXT NOT NULL UNIQUE,
+        data TEXT,
+        sync_change_counter INTEGER NOT NULL
+    )
+";
+
+pub(crate) static LAST_SYNC_META_KEY:    &'static str = "last_sync_time";
+pub(crate) static GLOBAL_STATE_META_KEY: &'static str = "global_state";
+
+pub(crate) fn init(db: &StorageDb) -> Result<()> {
+    let user_version = db.query_one::<i64>("PRAGMA user_version")?;
+    if user_version == 0 {
+        create(db)?;
+    } else if user_version > VERSION {
+        warn!("Loaded future schema version {} (we only understand version {}). \
+               Optimistically continuing", user_version, VERSION);
+    }
+    // Temp tables only live as long as the connection.
+    db.execute_all(&[CREATE_OUTGOING_TABLE_SQL])?;
+    Ok(())
+}
+
+fn create(db: &StorageDb) -> Result<()> {
+    debug!("Creating schema");
+    db.execute_all(&[
+        CREATE_DATA_TABLE_SQL,
+        CREATE_MIRROR_TABLE_SQL,
+        CREATE_META_TABLE_SQL,
+        &format!("PRAGMA user_version = {}", VERSION),
+    ])?;
+    Ok(())
+}
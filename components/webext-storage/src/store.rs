@@ -0,0 +1,432 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncing extension storage. Each extension's data is one record, which we
+//! merge key by key with any local changes, using the mirror as the common
+//! ancestor.
+
+use std::result;
+
+use failure;
+use serde_json::{self, Map, Value as JsonValue};
+use sql_support::ConnExt;
+use sync::{
+    CollectionRequest,
+    IncomingChangeset,
+    OutgoingChangeset,
+    Payload,
+    ServerTimestamp,
+    Store,
+};
+use sync::telemetry;
+use types_support::Guid;
+
+use api::get_from_db;
+use db::StorageDb;
+use error::*;
+use schema;
+
+pub const COLLECTION_NAME: &str = "storage-sync-2";
+
+type JsonMap = Map<String, JsonValue>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    id: String,
+    ext_id: String,
+    // The extension's data as a JSON string, like desktop.
+    data: String,
+}
+
+/// Merges an extension's data, keeping local changes to each key unless the
+/// key was deleted locally and changed remotely.
+fn merge(local: JsonMap, remote: Option<JsonMap>, parent: Option<JsonMap>) -> JsonMap {
+    let parent = parent.unwrap_or_default();
+    let mut merged = remote.unwrap_or_default();
+    for key in parent.keys() {
+        if !local.contains_key(key) && merged.get(key) == parent.get(key) {
+            merged.remove(key);
+        }
+    }
+    for (key, value) in local {
+        if parent.get(&key) != Some(&value) {
+            merged.insert(key, value);
+        }
+    }
+    merged
+}
+
+fn parse_data(data: Option<String>) -> Result<Option<JsonMap>> {
+    Ok(match data {
+        Some(s) => match serde_json::from_str(&s)? {
+            JsonValue::Object(map) => Some(map),
+            _ => None,
+        },
+        None => None,
+    })
+}
+
+fn to_json_string(data: &Option<JsonMap>) -> Result<Option<String>> {
+    Ok(match data {
+        Some(data) if !data.is_empty() => Some(serde_json::to_string(data)?),
+        _ => None,
+    })
+}
+
+fn get_mirror(conn: &impl ConnExt, ext_id: &str) -> Result<Option<(String, Option<JsonMap>)>> {
+    let row = conn.try_query_row(
+        "SELECT guid, data FROM storage_sync_mirror WHERE ext_id = :ext_id",
+        &[(":ext_id", &ext_id)],
+        |row| Ok::<_, Error>((row.get_checked(0)?, row.get_checked(1)?)),
+        true,
+    )?;
+    Ok(match row {
+        Some((guid, data)) => Some((guid, parse_data(data)?)),
+        None => None,
+    })
+}
+
+fn get_local_change_counter(conn: &impl ConnExt, ext_id: &str) -> Result<Option<i64>> {
+    Ok(conn.try_query_row(
+        "SELECT sync_change_counter FROM storage_sync_data WHERE ext_id = :ext_id",
+        &[(":ext_id", &ext_id)],
+        |row| row.get_checked(0),
+        true,
+    )?)
+}
+
+fn set_mirror(conn: &impl ConnExt, guid: &str, ext_id: &str, data: Option<String>) -> Result<()> {
+    conn.execute_named_cached(
+        "DELETE FROM storage_sync_mirror WHERE guid = :guid OR ext_id = :ext_id",
+        &[(":guid", &guid), (":ext_id", &ext_id)])?;
+    conn.execute_named_cached(
+        "INSERT INTO storage_sync_mirror(guid, ext_id, data) VALUES (:guid, :ext_id, :data)",
+        &[(":guid", &guid), (":ext_id", &ext_id), (":data", &data)])?;
+    Ok(())
+}
+
+fn set_local(conn: &impl ConnExt, ext_id: &str, data: Option<String>, counter: i64) -> Result<()> {
+    conn.execute_named_cached(
+        "REPLACE INTO storage_sync_data(ext_id, data, sync_change_counter)
+         VALUES (:ext_id, :data, :counter)",
+        &[(":ext_id", &ext_id), (":data", &data), (":counter", &counter)])?;
+    Ok(())
+}
+
+impl StorageDb {
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.unchecked_transaction()?;
+        // Records for extensions that another device uploaded first, under
+        // a different ID.
+        let mut stale_guids = Vec::new();
+        for (payload, _) in inbound.changes {
+            let guid = payload.id().to_string();
+            let (ext_id, remote) = if payload.is_tombstone() {
+                let ext_id = tx.try_query_row(
+                    "SELECT ext_id FROM storage_sync_mirror WHERE guid = :guid",
+                    &[(":guid", &guid)],
+                    |row| row.get_checked::<_, String>(0),
+                    true,
+                )?;
+                match ext_id {
+                    Some(ext_id) => (ext_id, None),
+                    None => continue,
+                }
+            } else {
+                let record: Record = payload.into_record()?;
+                match parse_data(Some(record.data))? {
+                    Some(data) => (record.ext_id, Some(data)),
+                    None => {
+                        warn!("Ignoring incoming record whose data isn't an object");
+                        telem.failed += 1;
+                        continue;
+                    }
+                }
+            };
+            let parent = match get_mirror(&tx, &ext_id)? {
+                Some((mirror_guid, data)) => {
+                    if mirror_guid != guid && remote.is_some() {
+                        stale_guids.push(mirror_guid);
+                    }
+                    data
+                }
+                None => None,
+            };
+            match get_local_change_counter(&tx, &ext_id)? {
+                Some(counter) if counter > 0 => {
+                    let local = get_from_db(&tx, &ext_id)?.unwrap_or_default();
+                    let merged = Some(merge(local, remote.clone(), parent));
+                    let remote_json = to_json_string(&remote)?;
+                    let merged_json = to_json_string(&merged)?;
+                    // If the merge didn't change anything, there's nothing
+                    // to upload.
+                    let counter = if merged_json == remote_json { 0 } else { counter };
+                    set_local(&tx, &ext_id, merged_json, counter)?;
+                }
+                _ => match to_json_string(&remote)? {
+                    Some(data) => set_local(&tx, &ext_id, Some(data), 0)?,
+                    None => {
+                        tx.execute_named_cached(
+                            "DELETE FROM storage_sync_data WHERE ext_id = :ext_id",
+                            &[(":ext_id", &ext_id)])?;
+                    }
+                },
+            }
+            set_mirror(&tx, &guid, &ext_id, to_json_string(&remote)?)?;
+        }
+        tx.commit()?;
+        let mut outgoing = self.fetch_outgoing(inbound.timestamp)?;
+        for guid in stale_guids {
+            outgoing.changes.push(Payload::new_tombstone(guid));
+        }
+        Ok(outgoing)
+    }
+
+    fn fetch_outgoing(&self, st: ServerTimestamp) -> Result<OutgoingChangeset> {
+        let tx = self.unchecked_transaction()?;
+        // Clears that never reached the server don't need to be uploaded.
+        tx.execute_all(&[
+            "DELETE FROM temp.storage_sync_outgoing",
+            "DELETE FROM storage_sync_data
+             WHERE data IS NULL
+               AND ext_id NOT IN (SELECT ext_id FROM storage_sync_mirror)",
+        ])?;
+        let changed = {
+            let mut stmt = tx.prepare("
+                SELECT l.ext_id, l.data, l.sync_change_counter, m.guid
+                FROM storage_sync_data l
+                LEFT JOIN storage_sync_mirror m ON m.ext_id = l.ext_id
+                WHERE l.sync_change_counter > 0")?;
+            let rows = stmt.query_and_then(&[], |row| -> Result<_> {
+                Ok((
+                    row.get_checked::<_, String>(0)?,
+                    row.get_checked::<_, Option<String>>(1)?,
+                    row.get_checked::<_, i64>(2)?,
+                    row.get_checked::<_, Option<String>>(3)?,
+                ))
+            })?;
+            let changed = rows.collect::<Result<Vec<_>>>()?;
+            changed
+        };
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), st);
+        for (ext_id, data, counter, guid) in changed {
+            let guid = guid.unwrap_or_else(|| Guid::random().into_string());
+            tx.execute_named_cached("
+                INSERT INTO temp.storage_sync_outgoing(guid, ext_id, data, sync_change_counter)
+                VALUES (:guid, :ext_id, :data, :counter)",
+                &[(":guid", &guid), (":ext_id", &ext_id), (":data", &data), (":counter", &counter)])?;
+            outgoing.changes.push(match data {
+                Some(data) => Payload::from_record(Record { id: guid, ext_id, data })?,
+                None => Payload::new_tombstone(guid),
+            });
+        }
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    fn mark_as_synchronized(&self, guids: &[String], ts: ServerTimestamp) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        for guid in guids {
+            let staged = tx.try_query_row(
+                "SELECT ext_id, data, sync_change_counter FROM temp.storage_sync_outgoing
+                 WHERE guid = :guid",
+                &[(":guid", guid)],
+                |row| Ok::<_, Error>((
+                    row.get_checked::<_, String>(0)?,
+                    row.get_checked::<_, Option<String>>(1)?,
+                    row.get_checked::<_, i64>(2)?,
+                )),
+                true,
+            )?;
+            // Tombstones for stale records aren't staged.
+            let (ext_id, data, counter) = match staged {
+                Some(staged) => staged,
+                None => continue,
+            };
+            set_mirror(&tx, guid, &ext_id, data)?;
+            // Changes made since we staged the upload still need uploading.
+            tx.execute_named_cached("
+                UPDATE storage_sync_data
+                SET sync_change_counter = MAX(sync_change_counter - :counter, 0)
+                WHERE ext_id = :ext_id",
+                &[(":ext_id", &ext_id), (":counter", &counter)])?;
+            tx.execute_named_cached("
+                DELETE FROM storage_sync_data
+                WHERE ext_id = :ext_id AND data IS NULL AND sync_change_counter = 0",
+                &[(":ext_id", &ext_id)])?;
+        }
+        tx.execute_all(&["DELETE FROM temp.storage_sync_outgoing"])?;
+        self.put_meta(schema::LAST_SYNC_META_KEY, &(ts.as_millis() as i64))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(self.get_meta::<i64>(schema::LAST_SYNC_META_KEY)?
+               .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
+    }
+
+    /// Forgets what we know about the server, so that the next sync merges
+    /// everything.
+    pub fn reset(&self) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        tx.execute_all(&[
+            "DELETE FROM storage_sync_mirror",
+            "UPDATE storage_sync_data SET sync_change_counter = 1",
+            &format!("DELETE FROM meta WHERE key = '{}'", schema::LAST_SYNC_META_KEY),
+        ])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes the data for every extension, without syncing the deletions,
+    /// and forgets what we know about the server, so that the next sync
+    /// replaces our data with the server's.
+    pub fn wipe(&self) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        tx.execute_all(&[
+            "DELETE FROM storage_sync_mirror",
+            "DELETE FROM storage_sync_data",
+            &format!("DELETE FROM meta WHERE key = '{}'", schema::LAST_SYNC_META_KEY),
+        ])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl Store for StorageDb {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self.do_apply_incoming(inbound, telem)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> result::Result<(), failure::Error> {
+        Ok(self.mark_as_synchronized(records_synced, new_timestamp)?)
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        let since = self.get_last_sync()?.unwrap_or_default();
+        Ok(CollectionRequest::new(COLLECTION_NAME).full().newer_than(since))
+    }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        Ok(StorageDb::reset(self)?)
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        Ok(StorageDb::wipe(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api;
+
+    fn incoming(records: Vec<Payload>) -> IncomingChangeset {
+        let ts = ServerTimestamp(1000.0);
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ts);
+        changeset.changes = records.into_iter().map(|r| (r, ts)).collect();
+        changeset
+    }
+
+    fn record(guid: &str, ext_id: &str, data: JsonValue) -> Payload {
+        Payload::from_record(Record {
+            id: guid.into(),
+            ext_id: ext_id.into(),
+            data: data.to_string(),
+        }).unwrap()
+    }
+
+    fn outgoing_data(outgoing: &OutgoingChangeset) -> Vec<(String, JsonValue)> {
+        outgoing.changes.iter().map(|p| {
+            let record: Record = p.clone().into_record().unwrap();
+            (record.ext_id, serde_json::from_str(&record.data).unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let map = |v: JsonValue| match v { JsonValue::Object(m) => m, _ => unreachable!() };
+        let parent = map(json!({"same": 1, "changed": 1, "deleted": 1, "remote_deleted": 1}));
+        let local = map(json!({"same": 1, "changed": 2, "remote_deleted": 1, "added": 1}));
+        let remote = map(json!({"same": 1, "changed": 3, "deleted": 1, "remote_added": 1}));
+        assert_eq!(JsonValue::Object(merge(local, Some(remote), Some(parent))),
+                   json!({"same": 1, "changed": 2, "added": 1, "remote_added": 1}));
+    }
+
+    #[test]
+    fn test_sync() {
+        let db = StorageDb::open_in_memory().unwrap();
+        api::set(&db, "ext1", json!({"a": 1})).unwrap();
+
+        let outgoing = db.do_apply_incoming(incoming(vec![
+            record("guidAAAAAAAA", "ext2", json!({"b": 2})),
+        ]), &mut telemetry::EngineIncoming::default()).unwrap();
+        assert_eq!(outgoing_data(&outgoing), vec![("ext1".to_string(), json!({"a": 1}))]);
+        assert_eq!(api::get(&db, "ext2", JsonValue::Null).unwrap(), json!({"b": 2}));
+
+        let uploaded = outgoing.changes.iter().map(|p| p.id().to_string()).collect::<Vec<_>>();
+        // A change made during the upload is still uploaded next time.
+        api::set(&db, "ext1", json!({"c": 3})).unwrap();
+        db.mark_as_synchronized(&uploaded, ServerTimestamp(1001.0)).unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(1001.0)).unwrap();
+        assert_eq!(outgoing_data(&outgoing), vec![("ext1".to_string(), json!({"a": 1, "c": 3}))]);
+        // ...with the same ID as before.
+        assert_eq!(outgoing.changes[0].id(), uploaded[0]);
+        db.mark_as_synchronized(&uploaded, ServerTimestamp(1002.0)).unwrap();
+        assert!(db.fetch_outgoing(ServerTimestamp(1002.0)).unwrap().changes.is_empty());
+
+        // Remote and local changes to different keys are merged.
+        api::set(&db, "ext2", json!({"local": true})).unwrap();
+        let outgoing = db.do_apply_incoming(incoming(vec![
+            record("guidAAAAAAAA", "ext2", json!({"b": 2, "remote": true})),
+        ]), &mut telemetry::EngineIncoming::default()).unwrap();
+        let expected = json!({"b": 2, "local": true, "remote": true});
+        assert_eq!(api::get(&db, "ext2", JsonValue::Null).unwrap(), expected);
+        assert_eq!(outgoing_data(&outgoing), vec![("ext2".to_string(), expected)]);
+
+        // Clearing uploads a tombstone.
+        db.mark_as_synchronized(&["guidAAAAAAAA".to_string()], ServerTimestamp(1003.0)).unwrap();
+        api::clear(&db, "ext2").unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(1003.0)).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert!(outgoing.changes[0].is_tombstone());
+    }
+
+    #[test]
+    fn test_duplicate_records() {
+        let db = StorageDb::open_in_memory().unwrap();
+        api::set(&db, "ext1", json!({"a": 1})).unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(1000.0)).unwrap();
+        let our_guid = outgoing.changes[0].id().to_string();
+        db.mark_as_synchronized(&[our_guid.clone()], ServerTimestamp(1000.0)).unwrap();
+
+        // Another device uploaded a record for the same extension, so we use
+        // theirs, and delete ours.
+        let outgoing = db.do_apply_incoming(incoming(vec![
+            record("guidBBBBBBBB", "ext1", json!({"a": 1, "b": 2})),
+        ]), &mut telemetry::EngineIncoming::default()).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id(), our_guid);
+        assert!(outgoing.changes[0].is_tombstone());
+        assert_eq!(api::get(&db, "ext1", JsonValue::Null).unwrap(), json!({"a": 1, "b": 2}));
+    }
+}
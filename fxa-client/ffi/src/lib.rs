@@ -3,12 +3,14 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 extern crate fxa_client;
+extern crate sync15_adapter;
 
 #[macro_use]
 extern crate ffi_support;
 
 use std::ffi::CString;
 use std::os::raw::c_char;
+use std::sync::Arc;
 
 use ffi_support::{
     rust_str_from_c,
@@ -20,6 +22,19 @@ use ffi_support::{
 use fxa_client::{Config, FirefoxAccount, PersistCallback};
 use fxa_client::ffi::*;
 
+/// Sends all our requests, including the ones to fetch a [Config], with `callback` instead of our
+/// own networking stack, so that they go through the application's. See
+/// `sync15_adapter::ForeignSendCallback` for the request and response format.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_set_http_callback(
+    callback: sync15_adapter::ForeignSendCallback,
+    err: &mut ExternError,
+) {
+    call_with_output(err, || {
+        fxa_client::set_http_backend(Arc::new(sync15_adapter::ForeignBackend::new(callback)));
+    })
+}
+
 /// Convenience function over [fxa_get_custom_config] that provides a pointer to a [Config] that
 /// points to the production FxA servers.
 #[no_mangle]
@@ -240,6 +255,26 @@ pub extern "C" fn fxa_get_sync_keys(
     })
 }
 
+/// Gets the credentials needed to sync, from the cached token for the
+/// `https://identity.mozilla.com/apps/oldsync` scope (refreshing it if it's expired).
+///
+/// The token must have been obtained with keys, using [fxa_begin_oauth_flow] with `wants_keys`
+/// set, or using [fxa_begin_pairing_flow].
+///
+/// # Safety
+///
+/// A destructor [fxa_sync_credentials_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub extern "C" fn fxa_get_sync_credentials(
+    fxa: &mut FirefoxAccount,
+    error: &mut ExternError,
+) -> *mut SyncCredentialsC {
+    call_with_result(error, || {
+        fxa.get_sync_credentials()
+    })
+}
+
 /// Request a OAuth token by starting a new pairing flow, by calling the content server pairing endpoint.
 ///
 /// This function returns a URL string that the caller should open in a webview.
@@ -342,6 +377,112 @@ pub unsafe extern "C" fn fxa_get_oauth_token(
     })
 }
 
+/// Registers this device (or updates its registration) with the given name and type (e.g.
+/// "mobile"), and advertises that it can receive tabs. The account must have a token for the
+/// `https://identity.mozilla.com/apps/oldsync` scope, with keys.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_register_device(
+    fxa: &mut FirefoxAccount,
+    name: *const c_char,
+    device_type: *const c_char,
+    error: &mut ExternError,
+) {
+    call_with_result(error, || {
+        let name = rust_str_from_c(name);
+        let device_type = rust_str_from_c(device_type);
+        fxa.register_device(name, device_type)
+    })
+}
+
+/// Sets the push subscription for this device, so that it's notified when it receives commands.
+/// The messages are encrypted with keys that the account generates and keeps, so pass them to
+/// [fxa_handle_push_message] as they arrive, without decrypting them.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_set_push_subscription(
+    fxa: &mut FirefoxAccount,
+    endpoint: *const c_char,
+    error: &mut ExternError,
+) {
+    call_with_result(error, || {
+        let endpoint = rust_str_from_c(endpoint);
+        fxa.set_push_subscription(endpoint)
+    })
+}
+
+/// Returns the devices connected to the account, as a JSON array.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub extern "C" fn fxa_get_devices(
+    fxa: &mut FirefoxAccount,
+    error: &mut ExternError,
+) -> *mut c_char {
+    call_with_result(error, || {
+        fxa.get_devices()
+    })
+}
+
+/// Sends a tab to another device.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_send_tab(
+    fxa: &mut FirefoxAccount,
+    target_device_id: *const c_char,
+    title: *const c_char,
+    url: *const c_char,
+    error: &mut ExternError,
+) {
+    call_with_result(error, || {
+        let target_device_id = rust_str_from_c(target_device_id);
+        let title = rust_str_from_c(title);
+        let url = rust_str_from_c(url);
+        fxa.send_tab(target_device_id, title, url)
+    })
+}
+
+/// Fetches the commands sent to this device, and returns the resulting events (e.g. received
+/// tabs) as a JSON array.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub extern "C" fn fxa_poll_device_commands(
+    fxa: &mut FirefoxAccount,
+    error: &mut ExternError,
+) -> *mut c_char {
+    call_with_result(error, || {
+        fxa.poll_device_commands()
+    })
+}
+
+/// Handles a push message, and returns the resulting events as a JSON array. `payload` is the
+/// encrypted body of the message, base64url-encoded.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_handle_push_message(
+    fxa: &mut FirefoxAccount,
+    payload: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    call_with_result(error, || {
+        let payload = rust_str_from_c(payload);
+        fxa.handle_push_message(payload)
+    })
+}
+
 define_string_destructor!(fxa_str_free);
 
 define_box_destructor!(FirefoxAccount, fxa_free);
@@ -349,3 +490,4 @@ define_box_destructor!(Config, fxa_config_free);
 define_box_destructor!(OAuthInfoC, fxa_oauth_info_free);
 define_box_destructor!(ProfileC, fxa_profile_free);
 define_box_destructor!(SyncKeysC, fxa_sync_keys_free);
+define_box_destructor!(SyncCredentialsC, fxa_sync_credentials_free);
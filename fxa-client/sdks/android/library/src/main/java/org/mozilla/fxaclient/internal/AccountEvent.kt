@@ -0,0 +1,38 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+package org.mozilla.fxaclient.internal
+
+import org.json.JSONArray
+import org.json.JSONObject
+
+data class TabHistoryEntry(val title: String, val url: String)
+
+sealed class AccountEvent {
+    data class TabReceived(val from: Device?, val entries: List<TabHistoryEntry>) : AccountEvent()
+
+    companion object {
+        fun fromJSON(obj: JSONObject): AccountEvent? {
+            return when (obj.getString("type")) {
+                "TabReceived" -> {
+                    val entries = obj.getJSONArray("entries")
+                    TabReceived(
+                        from = if (obj.isNull("from")) null else Device.fromJSON(obj.getJSONObject("from")),
+                        entries = (0 until entries.length()).map {
+                            val entry = entries.getJSONObject(it)
+                            TabHistoryEntry(entry.getString("title"), entry.getString("url"))
+                        }
+                    )
+                }
+                // Newer versions of the library may know about more events.
+                else -> null
+            }
+        }
+
+        fun fromJSONArray(json: String): List<AccountEvent> {
+            val array = JSONArray(json)
+            return (0 until array.length()).mapNotNull { fromJSON(array.getJSONObject(it)) }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+package org.mozilla.fxaclient.internal
+
+import org.json.JSONArray
+import org.json.JSONObject
+
+data class Device(
+    val id: String,
+    val name: String,
+    val deviceType: String,
+    val isCurrentDevice: Boolean,
+    val lastAccessTime: Long?,
+    val availableCommands: List<String>
+) {
+    companion object {
+        fun fromJSON(obj: JSONObject): Device {
+            val commands = obj.getJSONObject("availableCommands")
+            return Device(
+                id = obj.getString("id"),
+                name = obj.getString("name"),
+                deviceType = obj.getString("type"),
+                isCurrentDevice = obj.getBoolean("isCurrentDevice"),
+                lastAccessTime = if (obj.isNull("lastAccessTime")) null else obj.getLong("lastAccessTime"),
+                availableCommands = commands.keys().asSequence().toList()
+            )
+        }
+
+        fun fromJSONArray(json: String): List<Device> {
+            val array = JSONArray(json)
+            return (0 until array.length()).map { fromJSON(array.getJSONObject(it)) }
+        }
+    }
+}
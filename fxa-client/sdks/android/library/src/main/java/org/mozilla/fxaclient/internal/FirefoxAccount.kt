@@ -126,6 +126,100 @@ class FirefoxAccount : RustObject<RawFxAccount> {
         }?.let { OAuthInfo(it) }
     }
 
+    /**
+     * Fetches the credentials needed to sync, using the cached token for the
+     * `https://identity.mozilla.com/apps/oldsync` scope, which must have been obtained with keys.
+     *
+     * If the token is close to expiration, we may refresh it.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     *
+     * @return [SyncCredentials] for the token server and for decrypting sync records
+     */
+    fun getSyncCredentials(): SyncCredentials {
+        return SyncCredentials(rustCall { e ->
+            FxaClient.INSTANCE.fxa_get_sync_credentials(validPointer(), e)
+        })
+    }
+
+    /**
+     * Registers this device, or updates its registration, so that other devices can send it tabs.
+     * Requires a token for the sync scope, with keys.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     *
+     * @param name The name of this device, shown to the user on their other devices
+     * @param deviceType The kind of device, e.g. "mobile" or "tablet"
+     */
+    fun registerDevice(name: String, deviceType: String) {
+        rustCall { e ->
+            FxaClient.INSTANCE.fxa_register_device(validPointer(), name, deviceType, e)
+        }
+    }
+
+    /**
+     * Sets the push subscription for this device. Push messages received for it are encrypted
+     * with keys that the account keeps, and should be passed to [handlePushMessage] as they
+     * arrive, without decrypting them.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     */
+    fun setPushSubscription(endpoint: String) {
+        rustCall { e ->
+            FxaClient.INSTANCE.fxa_set_push_subscription(validPointer(), endpoint, e)
+        }
+    }
+
+    /**
+     * Fetches the devices connected to the account, including this one.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     */
+    fun getDevices(): List<Device> {
+        val json = rustCall { e ->
+            FxaClient.INSTANCE.fxa_get_devices(validPointer(), e)
+        }.getAndConsumeString()
+        return Device.fromJSONArray(json)
+    }
+
+    /**
+     * Sends a tab to another device.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     */
+    fun sendTab(targetDeviceId: String, title: String, url: String) {
+        rustCall { e ->
+            FxaClient.INSTANCE.fxa_send_tab(validPointer(), targetDeviceId, title, url, e)
+        }
+    }
+
+    /**
+     * Fetches the commands sent to this device since we last checked, for when push
+     * messages may have been missed.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     */
+    fun pollDeviceCommands(): List<AccountEvent> {
+        val json = rustCall { e ->
+            FxaClient.INSTANCE.fxa_poll_device_commands(validPointer(), e)
+        }.getAndConsumeString()
+        return AccountEvent.fromJSONArray(json)
+    }
+
+    /**
+     * Handles a push message for this device.
+     *
+     * This performs network requests, and should not be used on the main thread.
+     *
+     * @param payload The encrypted body of the push message, base64url-encoded
+     */
+    fun handlePushMessage(payload: String): List<AccountEvent> {
+        val json = rustCall { e ->
+            FxaClient.INSTANCE.fxa_handle_push_message(validPointer(), payload, e)
+        }.getAndConsumeString()
+        return AccountEvent.fromJSONArray(json)
+    }
+
     /**
      * Saves the current account's authentication state as a JSON string, for persistence in
      * the Android KeyStore/shared preferences. The authentication state can be restored using
@@ -142,6 +236,28 @@ class FirefoxAccount : RustObject<RawFxAccount> {
     }
 
     companion object {
+        // Held so that the callback isn't garbage collected while Rust uses it.
+        private var httpCallback: RawHttpCallback? = null
+
+        /**
+         * Sends all Firefox Accounts requests with [send], instead of the library's own networking
+         * stack, so that they use the application's. [send] is called on the thread that made the
+         * request, with the request as a JSON object like
+         * `{"method": "GET", "url": "...", "headers": {"Name": "value"}, "body": "<base64>"}`,
+         * and returns the response as a JSON object like
+         * `{"status": 200, "url": "...", "headers": {"Name": "value"}, "body": "<base64>"}`, or
+         * `{"error": "message"}` if it couldn't get one. Requests are sent one at a time, so
+         * [send] is never called again until it has returned.
+         */
+        fun setHttpCallback(send: (String) -> String?) {
+            val callback = object : RawHttpCallback {
+                override fun invoke(requestJson: String): String? = send(requestJson)
+            }
+            unlockedRustCall { e ->
+                FxaClient.INSTANCE.fxa_set_http_callback(callback, e)
+            }
+            httpCallback = callback
+        }
 
         /**
          * Restores the account's authentication state from a JSON string produced by
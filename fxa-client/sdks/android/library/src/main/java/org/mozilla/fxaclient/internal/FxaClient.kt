@@ -4,6 +4,7 @@
 
 package org.mozilla.fxaclient.internal
 
+import com.sun.jna.Callback
 import com.sun.jna.Library
 import com.sun.jna.Native
 import com.sun.jna.Pointer
@@ -32,6 +33,8 @@ internal interface FxaClient : Library {
         }
     }
 
+    fun fxa_set_http_callback(callback: RawHttpCallback, e: Error.ByReference)
+
     fun fxa_get_release_config(e: Error.ByReference): RawConfig?
     fun fxa_get_custom_config(content_base: String, e: Error.ByReference): RawConfig?
 
@@ -67,6 +70,14 @@ internal interface FxaClient : Library {
 
     fun fxa_complete_oauth_flow(fxa: RawFxAccount, code: String, state: String, e: Error.ByReference): OAuthInfo.Raw?
     fun fxa_get_oauth_token(fxa: RawFxAccount, scope: String, e: Error.ByReference): OAuthInfo.Raw?
+    fun fxa_get_sync_credentials(fxa: RawFxAccount, e: Error.ByReference): SyncCredentials.Raw?
+
+    fun fxa_register_device(fxa: RawFxAccount, name: String, deviceType: String, e: Error.ByReference)
+    fun fxa_set_push_subscription(fxa: RawFxAccount, endpoint: String, e: Error.ByReference)
+    fun fxa_get_devices(fxa: RawFxAccount, e: Error.ByReference): Pointer?
+    fun fxa_send_tab(fxa: RawFxAccount, targetDeviceId: String, title: String, url: String, e: Error.ByReference)
+    fun fxa_poll_device_commands(fxa: RawFxAccount, e: Error.ByReference): Pointer?
+    fun fxa_handle_push_message(fxa: RawFxAccount, payload: String, e: Error.ByReference): Pointer?
 
     fun fxa_config_free(config: RawConfig)
     fun fxa_str_free(string: Pointer)
@@ -80,7 +91,18 @@ internal interface FxaClient : Library {
 
     fun fxa_profile_free(ptr: Pointer)
     fun fxa_sync_keys_free(ptr: Pointer)
+    fun fxa_sync_credentials_free(ptr: Pointer)
 }
 
 class RawFxAccount : PointerType()
 class RawConfig : PointerType()
+
+/**
+ * Sends a request, described by a JSON object, and returns the response as a JSON object. See
+ * `sync15_adapter::ForeignSendCallback` for the format. JNA only keeps the returned string alive
+ * until the callback is called again, so Rust calls it for one request at a time, and a callback
+ * mustn't be in use by two Rust objects at once.
+ */
+internal interface RawHttpCallback : Callback {
+    fun invoke(requestJson: String): String?
+}
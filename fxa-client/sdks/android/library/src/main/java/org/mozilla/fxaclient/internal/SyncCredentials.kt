@@ -0,0 +1,44 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+package org.mozilla.fxaclient.internal
+
+import com.sun.jna.Pointer
+import com.sun.jna.Structure
+
+import java.util.Arrays
+
+class SyncCredentials internal constructor(raw: Raw) {
+
+    val keyId: String?
+    val accessToken: String?
+    val syncKey: String?
+    val tokenServerURL: String?
+
+    class Raw(p: Pointer) : Structure(p) {
+        @JvmField var keyId: Pointer? = null
+        @JvmField var accessToken: Pointer? = null
+        @JvmField var syncKey: Pointer? = null
+        @JvmField var tokenServerURL: Pointer? = null
+
+        init {
+            read()
+        }
+
+        override fun getFieldOrder(): List<String> {
+            return Arrays.asList("keyId", "accessToken", "syncKey", "tokenServerURL")
+        }
+    }
+
+    init {
+        try {
+            this.keyId = raw.keyId?.getRustString()
+            this.accessToken = raw.accessToken?.getRustString()
+            this.syncKey = raw.syncKey?.getRustString()
+            this.tokenServerURL = raw.tokenServerURL?.getRustString()
+        } finally {
+            FxaClient.INSTANCE.fxa_sync_credentials_free(raw.pointer)
+        }
+    }
+}
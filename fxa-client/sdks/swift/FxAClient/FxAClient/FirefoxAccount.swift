@@ -153,6 +153,14 @@ open class FirefoxAccount: RustOpaquePointer {
     }
     #endif
 
+    open func getSyncCredentials() throws -> SyncCredentials {
+        return try queue.sync(execute: {
+            return SyncCredentials(raw: try FxAError.unwrap({err in
+                fxa_get_sync_credentials(self.raw, err)
+            }))
+        })
+    }
+
     open func getTokenServerEndpointURL() throws -> URL {
         return try queue.sync(execute: {
             return URL(string: String(freeingFxaString: try FxAError.unwrap({err in
@@ -336,3 +344,34 @@ open class SyncKeys: RustStructPointer<SyncKeysC> {
     }
 }
 
+open class SyncCredentials: RustStructPointer<SyncCredentialsC> {
+    open var keyId: String {
+        get {
+            return String(cString: raw.pointee.key_id)
+        }
+    }
+
+    open var accessToken: String {
+        get {
+            return String(cString: raw.pointee.access_token)
+        }
+    }
+
+    open var syncKey: String {
+        get {
+            return String(cString: raw.pointee.sync_key)
+        }
+    }
+
+    open var tokenServerURL: String {
+        get {
+            return String(cString: raw.pointee.tokenserver_url)
+        }
+    }
+
+    override func cleanup(pointer: UnsafeMutablePointer<SyncCredentialsC>) {
+        queue.sync {
+            fxa_sync_credentials_free(raw)
+        }
+    }
+}
@@ -45,6 +45,13 @@ typedef struct SyncKeysC {
     const char *const _Nonnull xcs;
 } SyncKeysC;
 
+typedef struct SyncCredentialsC {
+    const char *const _Nonnull key_id;
+    const char *const _Nonnull access_token;
+    const char *const _Nonnull sync_key;
+    const char *const _Nonnull tokenserver_url;
+} SyncCredentialsC;
+
 typedef struct ProfileC {
     const char *const _Nonnull uid;
     const char *const _Nonnull email;
@@ -112,11 +119,40 @@ char *_Nullable fxa_get_token_server_endpoint_url(FirefoxAccount *_Nonnull fxa,
 SyncKeysC *_Nullable fxa_get_sync_keys(FirefoxAccount *_Nonnull fxa,
                                        FxAErrorC *_Nonnull out);
 
+SyncCredentialsC *_Nullable fxa_get_sync_credentials(FirefoxAccount *_Nonnull fxa,
+                                                     FxAErrorC *_Nonnull out);
+
+void fxa_register_device(FirefoxAccount *_Nonnull fxa,
+                         const char *_Nonnull name,
+                         const char *_Nonnull device_type,
+                         FxAErrorC *_Nonnull out);
+
+void fxa_set_push_subscription(FirefoxAccount *_Nonnull fxa,
+                               const char *_Nonnull endpoint,
+                               FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_get_devices(FirefoxAccount *_Nonnull fxa,
+                                FxAErrorC *_Nonnull out);
+
+void fxa_send_tab(FirefoxAccount *_Nonnull fxa,
+                  const char *_Nonnull target_device_id,
+                  const char *_Nonnull title,
+                  const char *_Nonnull url,
+                  FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_poll_device_commands(FirefoxAccount *_Nonnull fxa,
+                                         FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_handle_push_message(FirefoxAccount *_Nonnull fxa,
+                                        const char *_Nonnull payload,
+                                        FxAErrorC *_Nonnull out);
+
 void fxa_str_free(char* _Nullable ptr);
 void fxa_free(FirefoxAccount* _Nullable ptr);
 void fxa_oauth_info_free(OAuthInfoC* _Nullable ptr);
 void fxa_profile_free(ProfileC* _Nullable ptr);
 void fxa_config_free(Config* _Nullable ptr);
 void fxa_sync_keys_free(SyncKeysC* _Nullable ptr);
+void fxa_sync_credentials_free(SyncCredentialsC* _Nullable ptr);
 
 #endif /* fxa_h */
@@ -3,7 +3,9 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use super::errors::*;
-use reqwest;
+use http_client;
+use reqwest::Method;
+use sync15_adapter::HttpRequest;
 use url::Url;
 
 #[derive(Deserialize)]
@@ -52,10 +54,11 @@ impl Config {
 
     pub fn import_from(content_url: &str) -> Result<Config> {
         let config_url = Url::parse(content_url)?.join(".well-known/fxa-client-configuration")?;
-        let resp: ClientConfigurationResponse = reqwest::get(config_url)?.json()?;
+        let resp: ClientConfigurationResponse = http_client::send_json(HttpRequest::new(Method::GET, config_url))?;
 
         let openid_config_url = Url::parse(content_url)?.join(".well-known/openid-configuration")?;
-        let openid_resp: OpenIdConfigurationResponse = reqwest::get(openid_config_url)?.json()?;
+        let openid_resp: OpenIdConfigurationResponse =
+            http_client::send_json(HttpRequest::new(Method::GET, openid_config_url))?;
 
         Ok(Config {
             content_url: content_url.to_string(),
@@ -0,0 +1,413 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encrypted Content-Encoding for HTTP (RFC 8188), using the key derivation from Web Push
+//! (RFC 8291). This is how device commands are encrypted for the device that receives them:
+//! the sender only needs the receiver's public key and auth secret.
+
+use base64;
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(test)]
+use openssl::bn::BigNum;
+use openssl::bn::BigNumContext;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use ring::rand::SecureRandom;
+use ring::{digest, hkdf, hmac};
+use std::cmp;
+
+use errors::*;
+use RNG;
+
+const AUTH_SECRET_LENGTH: usize = 16;
+const SALT_LENGTH: usize = 16;
+const TAG_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+// The salt, the record size, and the length of the key id.
+const HEADER_LENGTH: usize = SALT_LENGTH + 4 + 1;
+const DEFAULT_RECORD_SIZE: usize = 4096;
+
+/// A P-256 key pair that payloads can be encrypted for.
+pub struct LocalKeyPair {
+    key: EcKey<Private>,
+}
+
+impl LocalKeyPair {
+    pub fn generate() -> Result<LocalKeyPair> {
+        let group = p256()?;
+        Ok(LocalKeyPair {
+            key: EcKey::generate(&group)?,
+        })
+    }
+
+    pub fn from_der(der: &[u8]) -> Result<LocalKeyPair> {
+        Ok(LocalKeyPair {
+            key: EcKey::private_key_from_der(der)?,
+        })
+    }
+
+    /// Builds a key pair from a raw private key and an uncompressed public key, like the ones in
+    /// the RFC examples.
+    #[cfg(test)]
+    fn from_raw(private_key: &[u8], public_key: &[u8]) -> Result<LocalKeyPair> {
+        let group = p256()?;
+        let mut ctx = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, public_key, &mut ctx)?;
+        let private_key = BigNum::from_slice(private_key)?;
+        Ok(LocalKeyPair {
+            key: EcKey::from_private_components(&group, &private_key, &point)?,
+        })
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>> {
+        Ok(self.key.private_key_to_der()?)
+    }
+
+    /// Returns the public key, in uncompressed form.
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let group = p256()?;
+        let mut ctx = BigNumContext::new()?;
+        Ok(self
+            .key
+            .public_key()
+            .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)?)
+    }
+
+    fn agree(&self, remote_public_key: &[u8]) -> Result<Vec<u8>> {
+        let group = p256()?;
+        let mut ctx = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, remote_public_key, &mut ctx)
+            .map_err(|_| ErrorKind::InvalidEncryptedPayload("Bad public key"))?;
+        let remote = PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?;
+        let local = PKey::from_ec_key(self.key.clone())?;
+        let mut deriver = Deriver::new(&local)?;
+        deriver.set_peer(&remote)?;
+        let mut secret = vec![0u8; deriver.len()?];
+        let len = deriver.derive(&mut secret)?;
+        secret.truncate(len);
+        Ok(secret)
+    }
+}
+
+/// A key pair and an auth secret to receive payloads with, kept base64url-encoded so that they
+/// can be stored with the account.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrivateKeys {
+    /// A DER-encoded P-256 private key.
+    private_key: String,
+    auth_secret: String,
+}
+
+impl PrivateKeys {
+    pub fn from_random() -> Result<PrivateKeys> {
+        let key_pair = LocalKeyPair::generate()?;
+        let mut auth_secret = [0u8; AUTH_SECRET_LENGTH];
+        RNG.fill(&mut auth_secret).map_err(|_| ErrorKind::RngFailure)?;
+        Ok(PrivateKeys {
+            private_key: base64::encode_config(&key_pair.to_der()?, base64::URL_SAFE_NO_PAD),
+            auth_secret: base64::encode_config(&auth_secret, base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    fn key_pair(&self) -> Result<LocalKeyPair> {
+        let der = base64::decode_config(&self.private_key, base64::URL_SAFE_NO_PAD)?;
+        LocalKeyPair::from_der(&der)
+    }
+
+    /// Returns the public key that senders encrypt for, base64url-encoded.
+    pub fn public_key(&self) -> Result<String> {
+        Ok(base64::encode_config(
+            &self.key_pair()?.public_key()?,
+            base64::URL_SAFE_NO_PAD,
+        ))
+    }
+
+    /// Returns the auth secret that senders encrypt with, base64url-encoded.
+    pub fn auth_secret(&self) -> &str {
+        &self.auth_secret
+    }
+
+    pub fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let auth_secret = base64::decode_config(&self.auth_secret, base64::URL_SAFE_NO_PAD)?;
+        decrypt(&self.key_pair()?, &auth_secret, payload)
+    }
+}
+
+/// Encrypts `plaintext` for the owner of `remote_public_key` and `auth_secret`, as a single record.
+pub fn encrypt(remote_public_key: &[u8], auth_secret: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let local = LocalKeyPair::generate()?;
+    let mut salt = [0u8; SALT_LENGTH];
+    RNG.fill(&mut salt).map_err(|_| ErrorKind::RngFailure)?;
+    encrypt_with(&local, remote_public_key, auth_secret, &salt, plaintext)
+}
+
+// Like `encrypt`, but with a given sender key and salt, so that the output is reproducible.
+fn encrypt_with(
+    local: &LocalKeyPair,
+    remote_public_key: &[u8],
+    auth_secret: &[u8],
+    salt: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let local_public_key = local.public_key()?;
+    let ikm = derive_ikm(
+        &local.agree(remote_public_key)?,
+        auth_secret,
+        remote_public_key,
+        &local_public_key,
+    );
+    let (key, nonce) = derive_key_and_nonce(&ikm, salt);
+
+    // The only record is also the last, so it ends with the last record delimiter, and
+    // doesn't need any more padding.
+    let mut record = plaintext.to_vec();
+    record.push(2);
+    let record_size = cmp::max(DEFAULT_RECORD_SIZE, record.len() + TAG_LENGTH);
+    let mut tag = [0u8; TAG_LENGTH];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_128_gcm(),
+        &key,
+        Some(&nonce[..]),
+        &[],
+        &record,
+        &mut tag,
+    )?;
+
+    let mut payload = Vec::with_capacity(
+        HEADER_LENGTH + local_public_key.len() + ciphertext.len() + TAG_LENGTH,
+    );
+    payload.extend_from_slice(salt);
+    let mut record_size_bytes = [0u8; 4];
+    BigEndian::write_u32(&mut record_size_bytes, record_size as u32);
+    payload.extend_from_slice(&record_size_bytes);
+    payload.push(local_public_key.len() as u8);
+    payload.extend_from_slice(&local_public_key);
+    payload.extend_from_slice(&ciphertext);
+    payload.extend_from_slice(&tag);
+    Ok(payload)
+}
+
+/// Decrypts a payload encrypted for `local` and `auth_secret`.
+pub fn decrypt(local: &LocalKeyPair, auth_secret: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let header = Header::parse(payload)?;
+    // In Web Push, the key id is the sender's public key.
+    let ikm = derive_ikm(
+        &local.agree(header.key_id)?,
+        auth_secret,
+        &local.public_key()?,
+        header.key_id,
+    );
+    decrypt_records(&ikm, &header)
+}
+
+// The header that starts every payload (RFC 8188, section 2.1), and the records that follow it.
+struct Header<'a> {
+    salt: &'a [u8],
+    record_size: usize,
+    key_id: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    fn parse(payload: &'a [u8]) -> Result<Header<'a>> {
+        if payload.len() < HEADER_LENGTH {
+            return Err(ErrorKind::InvalidEncryptedPayload("Truncated header").into());
+        }
+        let record_size = BigEndian::read_u32(&payload[SALT_LENGTH..SALT_LENGTH + 4]) as usize;
+        let key_id_len = payload[HEADER_LENGTH - 1] as usize;
+        if payload.len() < HEADER_LENGTH + key_id_len {
+            return Err(ErrorKind::InvalidEncryptedPayload("Truncated header").into());
+        }
+        // Each record needs room for at least a delimiter and a tag.
+        if record_size <= TAG_LENGTH + 1 {
+            return Err(ErrorKind::InvalidEncryptedPayload("Record size too small").into());
+        }
+        let ciphertext = &payload[HEADER_LENGTH + key_id_len..];
+        if ciphertext.is_empty() {
+            return Err(ErrorKind::InvalidEncryptedPayload("No records").into());
+        }
+        Ok(Header {
+            salt: &payload[0..SALT_LENGTH],
+            record_size,
+            key_id: &payload[HEADER_LENGTH..HEADER_LENGTH + key_id_len],
+            ciphertext,
+        })
+    }
+}
+
+fn decrypt_records(ikm: &[u8], header: &Header) -> Result<Vec<u8>> {
+    let (key, nonce) = derive_key_and_nonce(ikm, header.salt);
+    let records: Vec<&[u8]> = header.ciphertext.chunks(header.record_size).collect();
+    let mut plaintext = Vec::with_capacity(header.ciphertext.len());
+    for (seq, record) in records.iter().enumerate() {
+        if record.len() <= TAG_LENGTH {
+            return Err(ErrorKind::InvalidEncryptedPayload("Truncated record").into());
+        }
+        let (data, tag) = record.split_at(record.len() - TAG_LENGTH);
+        let mut decrypted = decrypt_aead(
+            Cipher::aes_128_gcm(),
+            &key,
+            Some(&record_nonce(&nonce, seq)[..]),
+            &[],
+            data,
+            tag,
+        ).map_err(|_| ErrorKind::InvalidEncryptedPayload("Decryption failed"))?;
+        // Records end with a delimiter (2 for the last record, 1 for the others), which may be
+        // followed by any number of zeros.
+        let delimiter = match decrypted.iter().rposition(|&b| b != 0) {
+            Some(delimiter) => delimiter,
+            None => return Err(ErrorKind::InvalidEncryptedPayload("Missing delimiter").into()),
+        };
+        let expected = if seq == records.len() - 1 { 2 } else { 1 };
+        if decrypted[delimiter] != expected {
+            return Err(ErrorKind::InvalidEncryptedPayload("Bad delimiter").into());
+        }
+        decrypted.truncate(delimiter);
+        plaintext.extend_from_slice(&decrypted);
+    }
+    Ok(plaintext)
+}
+
+fn p256() -> Result<EcGroup> {
+    Ok(EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)
+}
+
+// The input keying material for `derive_key_and_nonce`, from the Web Push key exchange
+// (RFC 8291, section 3.4).
+fn derive_ikm(
+    ecdh_secret: &[u8],
+    auth_secret: &[u8],
+    receiver_public_key: &[u8],
+    sender_public_key: &[u8],
+) -> Vec<u8> {
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(receiver_public_key);
+    key_info.extend_from_slice(sender_public_key);
+    hkdf_sha256(auth_secret, ecdh_secret, &key_info, 32)
+}
+
+fn derive_key_and_nonce(ikm: &[u8], salt: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let key = hkdf_sha256(salt, ikm, b"Content-Encoding: aes128gcm\0", KEY_LENGTH);
+    let nonce = hkdf_sha256(salt, ikm, b"Content-Encoding: nonce\0", NONCE_LENGTH);
+    (key, nonce)
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+    let mut out = vec![0u8; len];
+    hkdf::extract_and_expand(&salt, ikm, info, &mut out);
+    out
+}
+
+// The nonce for each record is the derived nonce, XORed with the record's sequence number.
+fn record_nonce(nonce: &[u8], seq: usize) -> Vec<u8> {
+    let mut nonce = nonce.to_vec();
+    let mut seq_bytes = [0u8; 8];
+    BigEndian::write_u64(&mut seq_bytes, seq as u64);
+    for (n, s) in nonce[NONCE_LENGTH - 8..].iter_mut().zip(seq_bytes.iter()) {
+        *n ^= s;
+    }
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn b64(s: &str) -> Vec<u8> {
+        base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    // The example from RFC 8291, section 5.
+    const RFC8291_RECEIVER_PRIVATE_KEY: &str = "q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94";
+    const RFC8291_RECEIVER_PUBLIC_KEY: &str = "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4";
+    const RFC8291_SENDER_PRIVATE_KEY: &str = "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw";
+    const RFC8291_SENDER_PUBLIC_KEY: &str = "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8";
+    const RFC8291_AUTH_SECRET: &str = "BTBZMqHH6r4Tts7J_aSIgg";
+    const RFC8291_SALT: &str = "DGv6ra1nlYgDCS1FRnbzlw";
+    const RFC8291_PAYLOAD: &str = "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN";
+
+    #[test]
+    fn test_rfc8291_decrypt() {
+        let receiver = LocalKeyPair::from_raw(
+            &b64(RFC8291_RECEIVER_PRIVATE_KEY),
+            &b64(RFC8291_RECEIVER_PUBLIC_KEY),
+        ).unwrap();
+        assert_eq!(receiver.public_key().unwrap(), b64(RFC8291_RECEIVER_PUBLIC_KEY));
+        let plaintext = decrypt(&receiver, &b64(RFC8291_AUTH_SECRET), &b64(RFC8291_PAYLOAD)).unwrap();
+        assert_eq!(plaintext, b"When I grow up, I want to be a watermelon".to_vec());
+    }
+
+    #[test]
+    fn test_rfc8291_encrypt() {
+        let sender = LocalKeyPair::from_raw(
+            &b64(RFC8291_SENDER_PRIVATE_KEY),
+            &b64(RFC8291_SENDER_PUBLIC_KEY),
+        ).unwrap();
+        let payload = encrypt_with(
+            &sender,
+            &b64(RFC8291_RECEIVER_PUBLIC_KEY),
+            &b64(RFC8291_AUTH_SECRET),
+            &b64(RFC8291_SALT),
+            b"When I grow up, I want to be a watermelon",
+        ).unwrap();
+        assert_eq!(payload, b64(RFC8291_PAYLOAD));
+    }
+
+    #[test]
+    fn test_rfc8188_single_record() {
+        // RFC 8188, section 3.1.
+        let payload = b64("I1BsxtFttlv3u_Oo94xnmwAAEAAA-NAVub2qFgBEuQKRapoZu-IxkIva3MEB1PD-ly8Thjg");
+        let header = Header::parse(&payload).unwrap();
+        assert_eq!(header.record_size, 4096);
+        assert!(header.key_id.is_empty());
+        let plaintext = decrypt_records(&b64("yqdlZ-tYemfogSmv7Ws5PQ"), &header).unwrap();
+        assert_eq!(plaintext, b"I am the walrus".to_vec());
+    }
+
+    #[test]
+    fn test_rfc8188_multiple_records() {
+        // RFC 8188, section 3.2, which splits the plaintext across two records, and pads the
+        // first.
+        let payload = b64("uNCkWiNYzKTnBN9ji3-qWAAAABkCYTHOG8chz_gnvgOqdGYovxyjuqRyJFjEDyoF1Fvkj6hQPdPHI51OEUKEpgz3SsLWIqS_uA");
+        let header = Header::parse(&payload).unwrap();
+        assert_eq!(header.record_size, 25);
+        assert_eq!(header.key_id, b"a1");
+        let ikm = b64("BO3ZVPxUlnLORbVGMpbT1Q");
+        assert_eq!(decrypt_records(&ikm, &header).unwrap(), b"I am the walrus".to_vec());
+        assert!(decrypt_records(&b64("yqdlZ-tYemfogSmv7Ws5PQ"), &header).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let receiver = LocalKeyPair::generate().unwrap();
+        let auth_secret = [7u8; 16];
+        let plaintext = b"When I grow up, I want to be a watermelon";
+        let payload = encrypt(&receiver.public_key().unwrap(), &auth_secret, plaintext).unwrap();
+        assert_eq!(decrypt(&receiver, &auth_secret, &payload).unwrap(), plaintext.to_vec());
+
+        // The key survives being stored.
+        let restored = LocalKeyPair::from_der(&receiver.to_der().unwrap()).unwrap();
+        assert_eq!(restored.public_key().unwrap(), receiver.public_key().unwrap());
+        assert_eq!(decrypt(&restored, &auth_secret, &payload).unwrap(), plaintext.to_vec());
+
+        assert!(decrypt(&receiver, &[8u8; 16], &payload).is_err());
+        let other = LocalKeyPair::generate().unwrap();
+        assert!(decrypt(&other, &auth_secret, &payload).is_err());
+        assert!(decrypt(&receiver, &auth_secret, &payload[..HEADER_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_large_payload() {
+        let receiver = LocalKeyPair::generate().unwrap();
+        let auth_secret = [1u8; 16];
+        let plaintext = vec![b'x'; DEFAULT_RECORD_SIZE * 2];
+        let payload = encrypt(&receiver.public_key().unwrap(), &auth_secret, &plaintext).unwrap();
+        assert_eq!(decrypt(&receiver, &auth_secret, &payload).unwrap(), plaintext);
+    }
+}
@@ -16,6 +16,7 @@ use hex;
 use openssl;
 use reqwest;
 use serde_json;
+use sync15_adapter;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -70,6 +71,9 @@ pub enum ErrorKind {
     #[fail(display = "The client requested keys alongside the token but they were not included")]
     TokenWithoutKeys,
 
+    #[fail(display = "No key for scope {}", _0)]
+    NoScopedKey(String),
+
     #[fail(display = "Login state needs to be Married for the current operation")]
     NotMarried,
 
@@ -121,6 +125,21 @@ pub enum ErrorKind {
     #[fail(display = "HMAC verification failed")]
     HmacVerifyFail,
 
+    #[fail(display = "No refresh token to authenticate device requests with")]
+    NoRefreshToken,
+
+    #[fail(display = "This device hasn't been registered")]
+    DeviceNotRegistered,
+
+    #[fail(display = "Unknown device {}", _0)]
+    UnknownDevice(String),
+
+    #[fail(display = "Device {} doesn't support {}", _0, _1)]
+    UnsupportedCommand(String, &'static str),
+
+    #[fail(display = "Invalid encrypted payload: {}", _0)]
+    InvalidEncryptedPayload(&'static str),
+
     #[fail(
         display = "Remote server error: '{}' '{}' '{}' '{}' '{}'", code, errno, error, message, info
     )]
@@ -150,7 +169,10 @@ pub enum ErrorKind {
     UTF8DecodeError(#[fail(cause)] string::FromUtf8Error),
 
     #[fail(display = "Network error: {}", _0)]
-    RequestError(#[fail(cause)] reqwest::Error),
+    RequestError(#[fail(cause)] sync15_adapter::Error),
+
+    #[fail(display = "Unexpected HTTP status: {}", _0)]
+    UnexpectedStatus(u16),
 
     #[fail(display = "Malformed URL error: {}", _0)]
     MalformedUrl(#[fail(cause)] reqwest::UrlError),
@@ -189,7 +211,7 @@ impl_from_error! {
     (Base64Decode, ::base64::DecodeError),
     (JsonError, ::serde_json::Error),
     (UTF8DecodeError, ::std::string::FromUtf8Error),
-    (RequestError, ::reqwest::Error),
+    (RequestError, ::sync15_adapter::Error),
     (MalformedUrl, ::reqwest::UrlError),
     (HeaderParseError, ::reqwest::header::ToStrError),
     (MalformedHeader, ::reqwest::header::InvalidHeaderValue)
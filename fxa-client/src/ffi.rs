@@ -22,12 +22,16 @@ use ffi_support::{
     opt_rust_string_to_c,
     destroy_c_string,
 };
+#[cfg(feature = "browserid")]
+use AccountEvent;
 use {
+    Device,
     Error,
     ErrorKind,
     FirefoxAccount,
     Config,
     SyncKeys,
+    SyncCredentials,
     OAuthInfo,
     Profile,
 
@@ -68,7 +72,7 @@ impl From<Error> for ExternError {
     }
 }
 
-// `SyncKeysC`/`OAuthInfoC`/`ProfileC` are `#[repr(C)]` types which are heap allocated and returned
+// `SyncKeysC`/`SyncCredentialsC`/`OAuthInfoC`/`ProfileC` are `#[repr(C)]` types which are heap allocated and returned
 // by a boxed pointer.
 //
 // The fields of these are private for safety reasons (if they were pub, you could cause memory
@@ -99,6 +103,36 @@ impl From<SyncKeys> for SyncKeysC {
     }
 }
 
+#[repr(C)]
+pub struct SyncCredentialsC {
+    key_id: *mut c_char,
+    access_token: *mut c_char,
+    sync_key: *mut c_char,
+    tokenserver_url: *mut c_char,
+}
+
+impl Drop for SyncCredentialsC {
+    fn drop(&mut self) {
+        unsafe {
+            destroy_c_string(self.key_id);
+            destroy_c_string(self.access_token);
+            destroy_c_string(self.sync_key);
+            destroy_c_string(self.tokenserver_url);
+        }
+    }
+}
+
+impl From<SyncCredentials> for SyncCredentialsC {
+    fn from(creds: SyncCredentials) -> Self {
+        SyncCredentialsC {
+            key_id: rust_string_to_c(creds.key_id),
+            access_token: rust_string_to_c(creds.access_token),
+            sync_key: rust_string_to_c(creds.sync_key),
+            tokenserver_url: rust_string_to_c(creds.tokenserver_url),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct OAuthInfoC {
     access_token: *mut c_char,
@@ -177,8 +211,14 @@ macro_rules! implement_into_ffi_converting {
 }
 
 implement_into_ffi_converting!(SyncKeys, SyncKeysC);
+implement_into_ffi_converting!(SyncCredentials, SyncCredentialsC);
 implement_into_ffi_converting!(OAuthInfo, OAuthInfoC);
 implement_into_ffi_converting!(Profile, ProfileC);
 
 // More normal opaque tyeps
 implement_into_ffi_by_pointer!(FirefoxAccount, Config);
+
+// Newer types are returned as JSON, like the other FFIs.
+implement_into_ffi_by_json!(Device);
+#[cfg(feature = "browserid")]
+implement_into_ffi_by_json!(AccountEvent);
@@ -4,8 +4,9 @@
 
 use hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
 use hex;
-use reqwest::{header::{self, HeaderValue}, Client, Method, Request};
+use reqwest::{header::{self, HeaderValue}, Method};
 use serde_json;
+use sync15_adapter::HttpRequest;
 use url::Url;
 
 use errors::*;
@@ -36,7 +37,7 @@ impl<'a> HAWKRequestBuilder<'a> {
         self
     }
 
-    pub fn build(self) -> Result<Request> {
+    pub fn build(self) -> Result<HttpRequest> {
         let hawk_header;
         {
             // Make sure we de-allocate the hash after hawk_request_builder.
@@ -58,14 +59,14 @@ impl<'a> HAWKRequestBuilder<'a> {
             hawk_header = format!("Hawk {}", header);
         }
 
-        let mut request_builder = Client::new().request(self.method, self.url);
-        request_builder = request_builder.header(header::AUTHORIZATION, HeaderValue::from_str(&hawk_header)?);
+        let mut request = HttpRequest::new(self.method, self.url);
+        request.headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&hawk_header)?);
 
         if let Some(body) = self.body {
-            request_builder = request_builder.header(header::CONTENT_TYPE, "application/json");
-            request_builder = request_builder.body(body);
+            request.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            request.body = Some(body.into_bytes());
         }
 
-        request_builder.build().map_err(|e| e.into())
+        Ok(request)
     }
 }
@@ -4,12 +4,16 @@
 
 #[cfg(feature = "browserid")]
 use hex;
-use reqwest;
-use reqwest::{header, Client as ReqwestClient, Method, Request, Response, StatusCode};
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Method, StatusCode};
 #[cfg(feature = "browserid")]
 use ring::{digest, hkdf, hmac};
+use serde;
 use serde_json;
-use std;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use sync15_adapter::{HttpBackend, HttpRequest, HttpResponse, ReqwestBackend};
+use url::Url;
 #[cfg(feature = "browserid")]
 use util::Xorable;
 
@@ -34,6 +38,70 @@ const KEY_LENGTH: usize = 32;
 #[cfg(feature = "browserid")]
 const SIGN_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
 
+lazy_static! {
+    // The backend that all our requests go through. `None` until the first
+    // request, or until the application provides one, so that we don't make
+    // a reqwest client that we won't use.
+    static ref HTTP_BACKEND: RwLock<Option<Arc<HttpBackend>>> = RwLock::new(None);
+}
+
+/// Sends all our requests, for every account, with `backend`, instead of the
+/// default reqwest backend, so that they go through the application's
+/// networking stack.
+pub fn set_http_backend(backend: Arc<HttpBackend>) {
+    *HTTP_BACKEND.write().unwrap() = Some(backend);
+}
+
+fn http_backend() -> Result<Arc<HttpBackend>> {
+    let mut backend = HTTP_BACKEND.write().unwrap();
+    if let Some(ref backend) = *backend {
+        return Ok(Arc::clone(backend));
+    }
+    let default: Arc<HttpBackend> = Arc::new(ReqwestBackend::new()?);
+    *backend = Some(Arc::clone(&default));
+    Ok(default)
+}
+
+/// Sends `request`, and returns the response if it succeeded, or an error
+/// with the details the server sent otherwise.
+pub(crate) fn send(request: HttpRequest) -> Result<HttpResponse> {
+    let resp = http_backend()?.execute(request)?;
+    let status = resp.status;
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        return Ok(resp);
+    }
+    match serde_json::from_slice::<serde_json::Value>(&resp.body) {
+        Ok(json) => Err(ErrorKind::RemoteError {
+            code: json["code"].as_u64().unwrap_or(0),
+            errno: json["errno"].as_u64().unwrap_or(0),
+            error: json["error"].as_str().unwrap_or("").to_string(),
+            message: json["message"].as_str().unwrap_or("").to_string(),
+            info: json["info"].as_str().unwrap_or("").to_string(),
+        }.into()),
+        Err(_) => Err(ErrorKind::UnexpectedStatus(status.as_u16()).into()),
+    }
+}
+
+/// Sends `request`, and parses the response as JSON.
+pub(crate) fn send_json<T>(request: HttpRequest) -> Result<T>
+where
+    for<'a> T: serde::de::Deserialize<'a>,
+{
+    Ok(serde_json::from_slice(&send(request)?.body)?)
+}
+
+fn bearer_request(method: Method, url: Url, token: &str) -> Result<HttpRequest> {
+    let mut request = HttpRequest::new(method, url);
+    request.headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token))?);
+    Ok(request)
+}
+
+fn with_json_body(mut request: HttpRequest, body: String) -> HttpRequest {
+    request.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    request.body = Some(body.into_bytes());
+    request
+}
+
 pub struct Client<'a> {
     config: &'a Config,
 }
@@ -81,26 +149,20 @@ impl<'a> Client<'a> {
 
     #[cfg(feature = "browserid")]
     pub fn login(&self, email: &str, auth_pwd: &str, get_keys: bool) -> Result<LoginResponse> {
-        let url = self.config.auth_url_path("v1/account/login")?;
+        let mut url = self.config.auth_url_path("v1/account/login")?;
+        url.query_pairs_mut().append_pair("keys", &get_keys.to_string());
         let parameters = json!({
           "email": email,
           "authPW": auth_pwd
         });
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .query(&[("keys", get_keys)])
-            .body(parameters.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        send_json(with_json_body(HttpRequest::new(Method::POST, url), parameters.to_string()))
     }
 
     #[cfg(feature = "browserid")]
     pub fn account_status(&self, uid: &String) -> Result<AccountStatusResponse> {
-        let url = self.config.auth_url_path("v1/account/status")?;
-        let client = ReqwestClient::new();
-        let request = client.get(url).query(&[("uid", uid)]).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let mut url = self.config.auth_url_path("v1/account/status")?;
+        url.query_pairs_mut().append_pair("uid", uid);
+        send_json(HttpRequest::new(Method::GET, url))
     }
 
     #[cfg(feature = "browserid")]
@@ -115,7 +177,7 @@ impl<'a> Client<'a> {
         );
         let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
         let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
-        let json: serde_json::Value = Client::make_request(request)?.json()?;
+        let json: serde_json::Value = send_json(request)?;
         let bundle = match json["bundle"].as_str() {
             Some(bundle) => bundle,
             None => panic!("Invalid JSON"),
@@ -152,7 +214,7 @@ impl<'a> Client<'a> {
         let url = self.config.auth_url_path("v1/recovery_email/status")?;
         let key = Client::derive_key_from_session_token(session_token)?;
         let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        send_json(request)
     }
 
     pub fn profile(
@@ -161,21 +223,17 @@ impl<'a> Client<'a> {
         etag: Option<String>,
     ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
         let url = self.config.userinfo_endpoint()?;
-        let client = ReqwestClient::new();
-        let mut builder = client.request(Method::GET, url)
-            .header(header::AUTHORIZATION, format!("Bearer {}", profile_access_token));
+        let mut request = bearer_request(Method::GET, url, profile_access_token)?;
         if let Some(etag) = etag {
-            builder = builder.header(header::IF_NONE_MATCH, format!("\"{}\"", etag));
+            request.headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&format!("\"{}\"", etag))?);
         }
-        let request = builder.build()?;
-        let mut resp = Client::make_request(request)?;
-        if resp.status() == StatusCode::NOT_MODIFIED {
+        let resp = send(request)?;
+        if resp.status == StatusCode::NOT_MODIFIED {
             return Ok(None);
         }
-        let etag = resp.headers().get(header::ETAG).and_then(|v| v.to_str().ok()).map(|s| s.to_owned());
         Ok(Some(ResponseAndETag {
-            etag,
-            response: resp.json()?,
+            etag: resp.header("ETag").map(|s| s.to_owned()),
+            response: serde_json::from_slice(&resp.body)?,
         }))
     }
 
@@ -201,7 +259,7 @@ impl<'a> Client<'a> {
         let request = HAWKRequestBuilder::new(Method::POST, url, &key)
             .body(parameters)
             .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        send_json(request)
     }
 
     pub fn oauth_token_with_code(
@@ -235,13 +293,51 @@ impl<'a> Client<'a> {
 
     fn make_oauth_token_request(&self, body: serde_json::Value) -> Result<OAuthTokenResponse> {
         let url = self.config.token_endpoint()?;
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        send_json(with_json_body(HttpRequest::new(Method::POST, url), body.to_string()))
+    }
+
+    pub fn devices(&self, refresh_token: &str) -> Result<Vec<GetDeviceResponse>> {
+        let url = self.config.auth_url_path("v1/account/devices")?;
+        send_json(bearer_request(Method::GET, url, refresh_token)?)
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn update_device(&self, refresh_token: &str, update: &DeviceUpdateRequest) -> Result<()> {
+        let url = self.config.auth_url_path("v1/account/device")?;
+        let request = bearer_request(Method::POST, url, refresh_token)?;
+        send(with_json_body(request, serde_json::to_string(update)?))?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn invoke_command(
+        &self,
+        refresh_token: &str,
+        command: &str,
+        target: &str,
+        payload: &serde_json::Value,
+    ) -> Result<()> {
+        let url = self.config.auth_url_path("v1/account/devices/invoke_command")?;
+        let body = json!({
+            "command": command,
+            "target": target,
+            "payload": payload
+        });
+        let request = bearer_request(Method::POST, url, refresh_token)?;
+        send(with_json_body(request, body.to_string()))?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    /// Fetches the commands sent to this device, starting at `index`.
+    pub fn pending_commands(
+        &self,
+        refresh_token: &str,
+        index: u64,
+    ) -> Result<PendingCommandsResponse> {
+        let mut url = self.config.auth_url_path("v1/account/device/commands")?;
+        url.query_pairs_mut().append_pair("index", &index.to_string());
+        send_json(bearer_request(Method::GET, url, refresh_token)?)
     }
 
     #[cfg(feature = "browserid")]
@@ -256,7 +352,7 @@ impl<'a> Client<'a> {
         let request = HAWKRequestBuilder::new(Method::POST, url, &key)
             .body(parameters)
             .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        send_json(request)
     }
 
     #[cfg(feature = "browserid")]
@@ -289,28 +385,6 @@ impl<'a> Client<'a> {
         hkdf::extract_and_expand(&salt, ikm, info, &mut out);
         out.to_vec()
     }
-
-    fn make_request(request: Request) -> Result<Response> {
-        let client = ReqwestClient::new();
-        let mut resp = client.execute(request)?;
-        let status = resp.status();
-
-        if status.is_success() || status == StatusCode::NOT_MODIFIED {
-            Ok(resp)
-        } else {
-            let json: std::result::Result<serde_json::Value, reqwest::Error> = resp.json();
-            match json {
-                Ok(json) => Err(ErrorKind::RemoteError {
-                    code: json["code"].as_u64().unwrap_or(0),
-                    errno: json["errno"].as_u64().unwrap_or(0),
-                    error: json["error"].as_str().unwrap_or("").to_string(),
-                    message: json["message"].as_str().unwrap_or("").to_string(),
-                    info: json["info"].as_str().unwrap_or("").to_string(),
-                }.into()),
-                Err(_) => Err(resp.error_for_status().unwrap_err().into()),
-            }
-        }
-    }
 }
 
 pub struct ResponseAndETag<T> {
@@ -346,6 +420,61 @@ pub struct OAuthTokenResponse {
     pub access_token: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetDeviceResponse {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    #[serde(rename = "isCurrentDevice")]
+    pub is_current_device: bool,
+    #[serde(rename = "lastAccessTime")]
+    pub last_access_time: Option<u64>,
+    #[serde(rename = "availableCommands", default)]
+    pub available_commands: HashMap<String, String>,
+}
+
+#[cfg(feature = "browserid")]
+#[derive(Default, Serialize)]
+pub struct DeviceUpdateRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    #[serde(rename = "pushCallback", skip_serializing_if = "Option::is_none")]
+    pub push_callback: Option<String>,
+    #[serde(rename = "pushPublicKey", skip_serializing_if = "Option::is_none")]
+    pub push_public_key: Option<String>,
+    #[serde(rename = "pushAuthKey", skip_serializing_if = "Option::is_none")]
+    pub push_auth_key: Option<String>,
+    #[serde(rename = "availableCommands", skip_serializing_if = "Option::is_none")]
+    pub available_commands: Option<HashMap<String, String>>,
+}
+
+#[cfg(feature = "browserid")]
+#[derive(Deserialize)]
+pub struct PendingCommandsResponse {
+    pub index: u64,
+    // False if there are more commands to fetch.
+    pub last: Option<bool>,
+    pub messages: Vec<PendingCommand>,
+}
+
+#[cfg(feature = "browserid")]
+#[derive(Deserialize)]
+pub struct PendingCommand {
+    pub index: u64,
+    pub data: CommandData,
+}
+
+#[cfg(feature = "browserid")]
+#[derive(Deserialize)]
+pub struct CommandData {
+    pub command: String,
+    pub payload: serde_json::Value,
+    pub sender: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct SignResponse {
     #[serde(rename = "cert")]
@@ -24,6 +24,7 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate sync15_adapter;
 extern crate untrusted;
 extern crate url;
 #[cfg(feature = "ffi")]
@@ -43,26 +44,41 @@ use self::login_sm::*;
 use errors::*;
 #[cfg(feature = "browserid")]
 use http_client::browser_id::jwt_utils;
+#[cfg(feature = "browserid")]
+use http_client::{CommandData, DeviceUpdateRequest, PendingCommand};
 use http_client::{Client, OAuthTokenResponse, ProfileResponse};
 use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
-use scoped_keys::ScopedKeysFlow;
+use scoped_keys::{ScopedKey, ScopedKeysFlow};
+#[cfg(feature = "browserid")]
+use send_tab::{EncryptedSendTabPayload, PrivateSendTabKeys, PublicSendTabKeys, SendTabPayload};
 use url::Url;
 use util::now;
 
 mod config;
+#[cfg(feature = "browserid")]
+mod ece;
 pub mod errors;
 mod http_client;
 #[cfg(feature = "browserid")]
 mod login_sm;
 mod oauth;
 mod scoped_keys;
+#[cfg(feature = "browserid")]
+mod send_tab;
 mod util;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 
 pub use config::Config;
+pub use http_client::set_http_backend;
+pub use http_client::GetDeviceResponse as Device;
 pub use http_client::ProfileResponse as Profile;
+#[cfg(feature = "browserid")]
+pub use send_tab::TabHistoryEntry;
+
+/// The scope whose key is used to derive the Sync keybundle.
+pub const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
 
 // If a cached token has less than `OAUTH_MIN_TIME_LEFT` seconds left to live,
 // it will be considered already expired.
@@ -82,6 +98,18 @@ struct StateV1 {
     #[cfg(feature = "browserid")]
     login_state: LoginState,
     oauth_cache: HashMap<String, OAuthInfo>,
+    // Generated the first time the device is registered.
+    #[cfg(feature = "browserid")]
+    #[serde(default)]
+    send_tab_keys: Option<PrivateSendTabKeys>,
+    // Generated the first time we subscribe to push messages.
+    #[cfg(feature = "browserid")]
+    #[serde(default)]
+    push_keys: Option<ece::PrivateKeys>,
+    // The index of the first device command we haven't handled yet.
+    #[cfg(feature = "browserid")]
+    #[serde(default)]
+    next_command_index: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -126,6 +154,31 @@ pub struct FirefoxAccount {
 
 pub struct SyncKeys(pub String, pub String);
 
+/// Everything a Sync engine needs to get a token from the token server, and
+/// to decrypt the records it gets back.
+#[derive(Clone, Debug)]
+pub struct SyncCredentials {
+    pub key_id: String,
+    pub access_token: String,
+    /// The base64url-encoded 64-byte key for `SYNC_SCOPE`, which is split
+    /// into the encryption and HMAC keys of the root Sync keybundle (see
+    /// `KeyBundle::from_ksync_base64` in sync15-adapter).
+    pub sync_key: String,
+    pub tokenserver_url: String,
+}
+
+/// Something that happened to the account, which the application should
+/// know about.
+#[cfg(feature = "browserid")]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum AccountEvent {
+    TabReceived {
+        from: Option<Device>,
+        entries: Vec<TabHistoryEntry>,
+    },
+}
+
 pub struct PersistCallback {
     callback_fn: Box<Fn(&str) + Send + RefUnwindSafe>,
 }
@@ -163,6 +216,12 @@ impl FirefoxAccount {
             #[cfg(feature = "browserid")]
             login_state: Unknown,
             oauth_cache: HashMap::new(),
+            #[cfg(feature = "browserid")]
+            send_tab_keys: None,
+            #[cfg(feature = "browserid")]
+            push_keys: None,
+            #[cfg(feature = "browserid")]
+            next_command_index: 0,
         })
     }
 
@@ -197,6 +256,9 @@ impl FirefoxAccount {
             config,
             login_state,
             oauth_cache: HashMap::new(),
+            send_tab_keys: None,
+            push_keys: None,
+            next_command_index: 0,
         }))
     }
 
@@ -261,11 +323,13 @@ impl FirefoxAccount {
 
     pub fn get_oauth_token(&mut self, scopes: &[&str]) -> Result<Option<OAuthInfo>> {
         let mut refresh_token = None;
+        let mut cached_keys = None;
         if let Some(cached_oauth_info) = self.oauth_cache_find(scopes) {
             if cached_oauth_info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT {
                 return Ok(Some(cached_oauth_info.clone()));
             }
             refresh_token = cached_oauth_info.refresh_token.clone();
+            cached_keys = cached_oauth_info.keys.clone();
         }
         // This is a bit awkward, borrow checker weirdness.
         let resp;
@@ -299,7 +363,7 @@ impl FirefoxAccount {
                 }
             }
         }
-        Ok(Some(self.handle_oauth_token_response(resp, None)?))
+        Ok(Some(self.handle_oauth_token_response(resp, None, cached_keys)?))
     }
 
     pub fn begin_pairing_flow(&mut self, pairing_url: &str, scopes: &[&str]) -> Result<String> {
@@ -370,13 +434,14 @@ impl FirefoxAccount {
             Some(oauth_flow) => oauth_flow,
             None => return Err(ErrorKind::UnknownOAuthState.into()),
         };
-        self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow)
+        self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow, None)
     }
 
     fn handle_oauth_token_response(
         &mut self,
         resp: OAuthTokenResponse,
         scoped_keys_flow: Option<ScopedKeysFlow>,
+        cached_keys: Option<String>,
     ) -> Result<OAuthInfo> {
         let granted_scopes = resp.scope.split(" ").map(|s| s.to_string()).collect();
         // This assumes that if the server returns keys_jwe, the jwk argument is Some.
@@ -392,7 +457,9 @@ impl FirefoxAccount {
                     error!("Expected to get keys back alongside the token but the server didn't send them.");
                     return Err(ErrorKind::TokenWithoutKeys.into());
                 } else {
-                    None
+                    // Refreshed tokens don't come with keys, so we hold on
+                    // to the ones we got alongside the original token.
+                    cached_keys
                 }
             }
         };
@@ -496,24 +563,248 @@ impl FirefoxAccount {
         self.state.config.token_server_endpoint_url()
     }
 
-    pub fn handle_push_message(&self) {
-        panic!("Not implemented yet!")
+    /// Returns the credentials for syncing, using (and refreshing, if needed)
+    /// a cached token for `SYNC_SCOPE`. The token must have been obtained
+    /// with keys, by an OAuth or pairing flow with `wants_keys`.
+    pub fn get_sync_credentials(&mut self) -> Result<SyncCredentials> {
+        let (access_token, key) = self.get_sync_token_and_key()?;
+        Ok(SyncCredentials {
+            key_id: key.kid,
+            access_token,
+            sync_key: key.k,
+            tokenserver_url: self.get_token_server_endpoint_url()?.to_string(),
+        })
     }
 
-    pub fn register_device(&self) {
-        panic!("Not implemented yet!")
+    fn get_sync_token_and_key(&mut self) -> Result<(String, ScopedKey)> {
+        let token = match self.get_oauth_token(&[SYNC_SCOPE])? {
+            Some(token) => token,
+            None => return Err(ErrorKind::NoCachedToken(SYNC_SCOPE).into()),
+        };
+        let keys = match token.keys {
+            Some(keys) => keys,
+            None => return Err(ErrorKind::TokenWithoutKeys.into()),
+        };
+        let key = ScopedKey::from_keys_json(&keys, SYNC_SCOPE)?;
+        let key_len = key.key_bytes()?.len();
+        if key_len != 64 {
+            return Err(ErrorKind::BadKeyLength("kSync", key_len, 64).into());
+        }
+        Ok((token.access_token, key))
     }
 
-    pub fn get_devices_list(&self) {
-        panic!("Not implemented yet!")
+    // Device requests are authenticated with a refresh token, and any of
+    // ours will do.
+    fn get_refresh_token(&self) -> Result<String> {
+        for info in self.state.oauth_cache.values() {
+            if let Some(ref refresh_token) = info.refresh_token {
+                return Ok(refresh_token.clone());
+            }
+        }
+        Err(ErrorKind::NoRefreshToken.into())
     }
 
-    pub fn send_message(&self) {
-        panic!("Not implemented yet!")
+    /// Registers this device with the given name and type (for example,
+    /// "mobile"), or updates its registration, and advertises that it can
+    /// receive tabs. This needs the Sync key, to encrypt the keys for send
+    /// tab.
+    #[cfg(feature = "browserid")]
+    pub fn register_device(&mut self, name: &str, device_type: &str) -> Result<()> {
+        let send_tab_keys = match self.state.send_tab_keys.clone() {
+            Some(keys) => keys,
+            None => {
+                let keys = PrivateSendTabKeys::from_random()?;
+                self.state.send_tab_keys = Some(keys.clone());
+                self.maybe_call_persist_callback();
+                keys
+            }
+        };
+        let (_, sync_key) = self.get_sync_token_and_key()?;
+        let mut available_commands = HashMap::new();
+        available_commands.insert(
+            send_tab::COMMAND_NAME.to_string(),
+            send_tab_keys.public_keys()?.to_command_data(&sync_key)?,
+        );
+        let update = DeviceUpdateRequest {
+            name: Some(name.to_string()),
+            device_type: Some(device_type.to_string()),
+            available_commands: Some(available_commands),
+            ..DeviceUpdateRequest::default()
+        };
+        let refresh_token = self.get_refresh_token()?;
+        let client = Client::new(&self.state.config);
+        client.update_device(&refresh_token, &update)
     }
 
-    pub fn retrieve_messages(&self) {
-        panic!("Not implemented yet!")
+    /// Tells the server where to send push messages for this device. The
+    /// messages are encrypted with keys that we generate and keep, so pass
+    /// them to `handle_push_message` as they arrive.
+    #[cfg(feature = "browserid")]
+    pub fn set_push_subscription(&mut self, endpoint: &str) -> Result<()> {
+        let push_keys = match self.state.push_keys.clone() {
+            Some(keys) => keys,
+            None => {
+                let keys = ece::PrivateKeys::from_random()?;
+                self.state.push_keys = Some(keys.clone());
+                self.maybe_call_persist_callback();
+                keys
+            }
+        };
+        let update = DeviceUpdateRequest {
+            push_callback: Some(endpoint.to_string()),
+            push_public_key: Some(push_keys.public_key()?),
+            push_auth_key: Some(push_keys.auth_secret().to_string()),
+            ..DeviceUpdateRequest::default()
+        };
+        let refresh_token = self.get_refresh_token()?;
+        let client = Client::new(&self.state.config);
+        client.update_device(&refresh_token, &update)
+    }
+
+    /// Returns all the devices connected to the account, including this one.
+    pub fn get_devices(&self) -> Result<Vec<Device>> {
+        let refresh_token = self.get_refresh_token()?;
+        let client = Client::new(&self.state.config);
+        client.devices(&refresh_token)
+    }
+
+    /// Sends a tab to another device, which must have advertised that it
+    /// can receive tabs.
+    #[cfg(feature = "browserid")]
+    pub fn send_tab(&mut self, target_device_id: &str, title: &str, url: &str) -> Result<()> {
+        let devices = self.get_devices()?;
+        let target = match devices.into_iter().find(|d| d.id == target_device_id) {
+            Some(target) => target,
+            None => return Err(ErrorKind::UnknownDevice(target_device_id.to_string()).into()),
+        };
+        let command_data = match target.available_commands.get(send_tab::COMMAND_NAME) {
+            Some(command_data) => command_data,
+            None => {
+                return Err(ErrorKind::UnsupportedCommand(target.id.clone(), "send tab").into())
+            }
+        };
+        let (_, sync_key) = self.get_sync_token_and_key()?;
+        let public_keys = PublicSendTabKeys::from_command_data(command_data, &sync_key)?;
+        let payload = public_keys.encrypt(&SendTabPayload::single_tab(title, url))?;
+        let refresh_token = self.get_refresh_token()?;
+        let client = Client::new(&self.state.config);
+        client.invoke_command(
+            &refresh_token,
+            send_tab::COMMAND_NAME,
+            &target.id,
+            &serde_json::to_value(&payload)?,
+        )
+    }
+
+    /// Fetches and handles the commands sent to this device since we last
+    /// looked. If a command fails, we stop there, and try it again the next
+    /// time we poll, so that we don't lose it.
+    #[cfg(feature = "browserid")]
+    pub fn poll_device_commands(&mut self) -> Result<Vec<AccountEvent>> {
+        let refresh_token = self.get_refresh_token()?;
+        let mut index = self.state.next_command_index;
+        let mut commands = Vec::new();
+        {
+            let client = Client::new(&self.state.config);
+            loop {
+                let resp = client.pending_commands(&refresh_token, index)?;
+                let is_last = resp.last.unwrap_or(true) || resp.messages.is_empty();
+                for message in resp.messages {
+                    index = index.max(message.index + 1);
+                    commands.push(message);
+                }
+                if is_last {
+                    break;
+                }
+            }
+        }
+        if commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Only used to tell the application who sent each command.
+        let devices = self.get_devices().unwrap_or_else(|e| {
+            warn!("Couldn't fetch the senders of device commands: {}", e);
+            Vec::new()
+        });
+        Ok(self.handle_commands(commands, &devices))
+    }
+
+    // Handles `commands` in order, and moves `next_command_index` past each
+    // one we handle.
+    #[cfg(feature = "browserid")]
+    fn handle_commands(
+        &mut self,
+        mut commands: Vec<PendingCommand>,
+        devices: &[Device],
+    ) -> Vec<AccountEvent> {
+        commands.sort_by_key(|command| command.index);
+        let mut events = Vec::with_capacity(commands.len());
+        let next_command_index = self.state.next_command_index;
+        for command in commands {
+            let index = command.index;
+            match self.handle_command(command.data, devices) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error handling device command {}: {}", index, e);
+                    break;
+                }
+            }
+            self.state.next_command_index = index + 1;
+        }
+        if self.state.next_command_index != next_command_index {
+            self.maybe_call_persist_callback();
+        }
+        events
+    }
+
+    #[cfg(feature = "browserid")]
+    fn handle_command(
+        &self,
+        command: CommandData,
+        devices: &[Device],
+    ) -> Result<Option<AccountEvent>> {
+        if command.command != send_tab::COMMAND_NAME {
+            info!("Ignoring unknown device command {}", command.command);
+            return Ok(None);
+        }
+        let send_tab_keys = match self.state.send_tab_keys {
+            Some(ref keys) => keys,
+            None => return Err(ErrorKind::DeviceNotRegistered.into()),
+        };
+        let encrypted: EncryptedSendTabPayload = serde_json::from_value(command.payload)?;
+        let payload = send_tab_keys.decrypt(&encrypted)?;
+        let from = command
+            .sender
+            .and_then(|sender| devices.iter().find(|d| d.id == sender).cloned());
+        Ok(Some(AccountEvent::TabReceived {
+            from,
+            entries: payload.entries,
+        }))
+    }
+
+    /// Handles a push message sent to the subscription from
+    /// `set_push_subscription`. `payload` is the encrypted body of the
+    /// message, base64url-encoded, as the push service delivers it.
+    #[cfg(feature = "browserid")]
+    pub fn handle_push_message(&mut self, payload: &str) -> Result<Vec<AccountEvent>> {
+        let payload: serde_json::Value = {
+            let push_keys = match self.state.push_keys {
+                Some(ref keys) => keys,
+                None => return Err(ErrorKind::DeviceNotRegistered.into()),
+            };
+            let ciphertext = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)?;
+            serde_json::from_slice(&push_keys.decrypt(&ciphertext)?)?
+        };
+        match payload["command"].as_str() {
+            // The message tells us the index of the new command, but we
+            // might have missed others before it, so we fetch everything.
+            Some("fxaccounts:command_received") => self.poll_device_commands(),
+            command => {
+                info!("Ignoring push message {:?}", command);
+                Ok(Vec::new())
+            }
+        }
     }
 
     pub fn register_persist_callback(&mut self, persist_callback: PersistCallback) {
@@ -688,6 +979,135 @@ mod tests {
         fxa.oauth_cache_store(&oauth_info);
         fxa.oauth_cache_find(&["profile"]).unwrap();
     }
+
+    #[test]
+    fn test_get_sync_credentials() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let mut oauth_info = OAuthInfo {
+            access_token: "abcdef".to_string(),
+            keys: None,
+            refresh_token: None,
+            expires_at: util::now_secs() + 3600,
+            scopes: vec![SYNC_SCOPE.to_string()],
+        };
+        fxa.oauth_cache_store(&oauth_info);
+        // Tokens without keys can't be used for syncing.
+        assert!(fxa.get_sync_credentials().is_err());
+
+        oauth_info.keys = Some("{\"https://identity.mozilla.com/apps/oldsync\":{\"kty\":\"oct\",\"scope\":\"https://identity.mozilla.com/apps/oldsync\",\"k\":\"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA\",\"kid\":\"1526414944666-zgTjf5oXmPmBjxwXWFsDWg\"}}".to_string());
+        fxa.oauth_cache_store(&oauth_info);
+        let creds = fxa.get_sync_credentials().unwrap();
+        assert_eq!(creds.key_id, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(creds.access_token, "abcdef");
+        assert_eq!(creds.sync_key, "8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA");
+        assert_eq!(creds.tokenserver_url, fxa.get_token_server_endpoint_url().unwrap().to_string());
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_handle_send_tab_command() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let payload = SendTabPayload::single_tab("Example", "https://www.example.com/");
+        let encrypted = keys.public_keys().unwrap().encrypt(&payload).unwrap();
+        let command = || CommandData {
+            command: send_tab::COMMAND_NAME.to_string(),
+            payload: serde_json::to_value(&encrypted).unwrap(),
+            sender: Some("sender".to_string()),
+        };
+        let sender = Device {
+            id: "sender".to_string(),
+            name: "Sender".to_string(),
+            device_type: "desktop".to_string(),
+            is_current_device: false,
+            last_access_time: None,
+            available_commands: HashMap::new(),
+        };
+
+        // We can't read tabs until we've registered, and have keys.
+        assert!(fxa.handle_command(command(), &[]).is_err());
+
+        fxa.state.send_tab_keys = Some(keys);
+        match fxa.handle_command(command(), &[sender]).unwrap() {
+            Some(AccountEvent::TabReceived { from, entries }) => {
+                assert_eq!(from.unwrap().name, "Sender");
+                assert_eq!(entries, payload.entries);
+            }
+            None => panic!("Expected a received tab"),
+        }
+
+        let unknown = CommandData {
+            command: "https://identity.mozilla.com/cmd/unknown".to_string(),
+            payload: json!({}),
+            sender: None,
+        };
+        assert!(fxa.handle_command(unknown, &[]).unwrap().is_none());
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_handle_commands_stops_at_failure() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let payload = SendTabPayload::single_tab("Example", "https://www.example.com/");
+        let encrypted = keys.public_keys().unwrap().encrypt(&payload).unwrap();
+        fxa.state.send_tab_keys = Some(keys);
+        fxa.state.next_command_index = 3;
+        let command = |index, payload| PendingCommand {
+            index,
+            data: CommandData {
+                command: send_tab::COMMAND_NAME.to_string(),
+                payload,
+                sender: None,
+            },
+        };
+
+        let commands = vec![
+            command(5, json!({ "encrypted": "garbage" })),
+            command(3, serde_json::to_value(&encrypted).unwrap()),
+            command(4, serde_json::to_value(&encrypted).unwrap()),
+            command(6, serde_json::to_value(&encrypted).unwrap()),
+        ];
+        let events = fxa.handle_commands(commands, &[]);
+        // We handle the commands before the one we couldn't, and try it again
+        // next time.
+        assert_eq!(events.len(), 2);
+        assert_eq!(fxa.state.next_command_index, 5);
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_handle_push_message() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let message = |keys: &ece::PrivateKeys, plaintext: &str| {
+            let public_key =
+                base64::decode_config(&keys.public_key().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+            let auth_secret =
+                base64::decode_config(keys.auth_secret(), base64::URL_SAFE_NO_PAD).unwrap();
+            let ciphertext = ece::encrypt(&public_key, &auth_secret, plaintext.as_bytes()).unwrap();
+            base64::encode_config(&ciphertext, base64::URL_SAFE_NO_PAD)
+        };
+        let keys = ece::PrivateKeys::from_random().unwrap();
+        let profile_updated = message(&keys, r#"{"command": "fxaccounts:profile_updated"}"#);
+
+        // We can't read push messages until we've subscribed.
+        assert!(fxa.handle_push_message(&profile_updated).is_err());
+
+        fxa.state.push_keys = Some(keys.clone());
+        // Push messages other than new commands are ignored.
+        let events = fxa.handle_push_message(&profile_updated).unwrap();
+        assert!(events.is_empty());
+
+        // Messages must be encrypted for our subscription.
+        let other_keys = ece::PrivateKeys::from_random().unwrap();
+        let other = message(&other_keys, r#"{"command": "fxaccounts:profile_updated"}"#);
+        assert!(fxa.handle_push_message(&other).is_err());
+        assert!(fxa.handle_push_message(r#"{"command": "fxaccounts:profile_updated"}"#).is_err());
+    }
 }
 
 pub struct OAuthFlow {
@@ -6,6 +6,7 @@ use ring::agreement::EphemeralPrivateKey;
 use ring::rand::SecureRandom;
 use ring::{aead, agreement, digest};
 use serde_json;
+use std::collections::HashMap;
 use untrusted::Input;
 
 pub struct ScopedKeysFlow {
@@ -113,6 +114,31 @@ impl ScopedKeysFlow {
     }
 }
 
+/// A key for a single scope, as found in the JSON object (keyed by scope) that we get back from
+/// `ScopedKeysFlow::decrypt_keys_jwe`, and keep in `OAuthInfo::keys`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScopedKey {
+    pub kty: String,
+    pub scope: String,
+    /// The key material, base64url-encoded.
+    pub k: String,
+    pub kid: String,
+}
+
+impl ScopedKey {
+    pub fn from_keys_json(keys: &str, scope: &str) -> Result<ScopedKey> {
+        let mut keys: HashMap<String, ScopedKey> = serde_json::from_str(keys)?;
+        match keys.remove(scope) {
+            Some(key) => Ok(key),
+            None => Err(ErrorKind::NoScopedKey(scope.to_string()).into()),
+        }
+    }
+
+    pub fn key_bytes(&self) -> Result<Vec<u8>> {
+        Ok(base64::decode_config(&self.k, base64::URL_SAFE_NO_PAD)?)
+    }
+}
+
 fn to_32b_buf(n: u32) -> Vec<u8> {
     let mut buf = [0; 4];
     BigEndian::write_u32(&mut buf, n);
@@ -140,4 +166,13 @@ mod tests {
         let keys = flow.decrypt_keys_jwe(jwe).unwrap();
         assert_eq!(keys, "{\"https://identity.mozilla.com/apps/oldsync\":{\"kty\":\"oct\",\"scope\":\"https://identity.mozilla.com/apps/oldsync\",\"k\":\"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA\",\"kid\":\"1526414944666-zgTjf5oXmPmBjxwXWFsDWg\"}}");
     }
+
+    #[test]
+    fn test_scoped_key() {
+        let keys = "{\"https://identity.mozilla.com/apps/oldsync\":{\"kty\":\"oct\",\"scope\":\"https://identity.mozilla.com/apps/oldsync\",\"k\":\"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA\",\"kid\":\"1526414944666-zgTjf5oXmPmBjxwXWFsDWg\"}}";
+        let key = ScopedKey::from_keys_json(keys, "https://identity.mozilla.com/apps/oldsync").unwrap();
+        assert_eq!(key.kid, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(key.key_bytes().unwrap().len(), 64);
+        assert!(ScopedKey::from_keys_json(keys, "https://identity.mozilla.com/apps/lockbox").is_err());
+    }
 }
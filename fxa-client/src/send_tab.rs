@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Sending tabs to other devices, as device commands.
+//!
+//! A device that can receive tabs generates a key pair and an auth secret when it registers, and
+//! keeps them in its state. The public half is advertised in the `COMMAND_NAME` entry of the
+//! device's `availableCommands`, encrypted with the Sync key, so that only other devices connected
+//! to the same account can use it. Senders encrypt the tab for the receiver with `ece`.
+
+use base64;
+use hex;
+use openssl::symm::{self, Cipher};
+use ring::rand::SecureRandom;
+use ring::{digest, hmac};
+use serde_json;
+
+use ece;
+use errors::*;
+use scoped_keys::ScopedKey;
+use RNG;
+
+pub const COMMAND_NAME: &str = "https://identity.mozilla.com/cmd/open-uri";
+
+const IV_LENGTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TabHistoryEntry {
+    pub title: String,
+    pub url: String,
+}
+
+/// What gets sent: the tab's history, with the current page last.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SendTabPayload {
+    pub entries: Vec<TabHistoryEntry>,
+}
+
+impl SendTabPayload {
+    pub fn single_tab(title: &str, url: &str) -> SendTabPayload {
+        SendTabPayload {
+            entries: vec![TabHistoryEntry {
+                title: title.to_string(),
+                url: url.to_string(),
+            }],
+        }
+    }
+}
+
+/// The payload of a send tab command, as it's sent to the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedSendTabPayload {
+    /// The ECE-encrypted `SendTabPayload` JSON, base64url-encoded.
+    encrypted: String,
+}
+
+/// The keys for receiving tabs, which never leave this device.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrivateSendTabKeys {
+    #[serde(flatten)]
+    keys: ece::PrivateKeys,
+}
+
+impl PrivateSendTabKeys {
+    pub fn from_random() -> Result<PrivateSendTabKeys> {
+        Ok(PrivateSendTabKeys {
+            keys: ece::PrivateKeys::from_random()?,
+        })
+    }
+
+    pub fn public_keys(&self) -> Result<PublicSendTabKeys> {
+        Ok(PublicSendTabKeys {
+            public_key: self.keys.public_key()?,
+            auth_secret: self.keys.auth_secret().to_string(),
+        })
+    }
+
+    pub fn decrypt(&self, payload: &EncryptedSendTabPayload) -> Result<SendTabPayload> {
+        let ciphertext = base64::decode_config(&payload.encrypted, base64::URL_SAFE_NO_PAD)?;
+        let plaintext = self.keys.decrypt(&ciphertext)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// The keys other devices need to send us tabs.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicSendTabKeys {
+    public_key: String,
+    auth_secret: String,
+}
+
+impl PublicSendTabKeys {
+    pub fn encrypt(&self, payload: &SendTabPayload) -> Result<EncryptedSendTabPayload> {
+        let public_key = base64::decode_config(&self.public_key, base64::URL_SAFE_NO_PAD)?;
+        let auth_secret = base64::decode_config(&self.auth_secret, base64::URL_SAFE_NO_PAD)?;
+        let plaintext = serde_json::to_vec(payload)?;
+        let ciphertext = ece::encrypt(&public_key, &auth_secret, &plaintext)?;
+        Ok(EncryptedSendTabPayload {
+            encrypted: base64::encode_config(&ciphertext, base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    /// Returns our entry for `availableCommands`, encrypted with `sync_key`
+    /// the same way Sync encrypts its records.
+    pub fn to_command_data(&self, sync_key: &ScopedKey) -> Result<String> {
+        let (enc_key, mac_key) = split_sync_key(sync_key)?;
+        let mut iv = [0u8; IV_LENGTH];
+        RNG.fill(&mut iv).map_err(|_| ErrorKind::RngFailure)?;
+        let plaintext = serde_json::to_vec(self)?;
+        let ciphertext = symm::encrypt(Cipher::aes_256_cbc(), &enc_key, Some(&iv[..]), &plaintext)?;
+        let ciphertext = base64::encode(&ciphertext);
+        let hmac_key = hmac::SigningKey::new(&digest::SHA256, &mac_key);
+        let hmac = hex::encode(hmac::sign(&hmac_key, ciphertext.as_bytes()).as_ref());
+        Ok(serde_json::to_string(&EncryptedCommandData {
+            kid: sync_key.kid.clone(),
+            iv: base64::encode(&iv),
+            hmac,
+            ciphertext,
+        })?)
+    }
+
+    pub fn from_command_data(data: &str, sync_key: &ScopedKey) -> Result<PublicSendTabKeys> {
+        let data: EncryptedCommandData = serde_json::from_str(data)?;
+        // The device registered with a different Sync key, and needs to register again before it
+        // can receive tabs.
+        if data.kid != sync_key.kid {
+            return Err(ErrorKind::InvalidEncryptedPayload("Sync key mismatch").into());
+        }
+        let (enc_key, mac_key) = split_sync_key(sync_key)?;
+        let hmac_key = hmac::VerificationKey::new(&digest::SHA256, &mac_key);
+        let expected_hmac = hex::decode(&data.hmac)?;
+        hmac::verify(&hmac_key, data.ciphertext.as_bytes(), &expected_hmac)
+            .map_err(|_| ErrorKind::HmacVerifyFail)?;
+        let iv = base64::decode(&data.iv)?;
+        let ciphertext = base64::decode(&data.ciphertext)?;
+        let plaintext = symm::decrypt(Cipher::aes_256_cbc(), &enc_key, Some(&iv[..]), &ciphertext)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedCommandData {
+    /// The `kid` of the Sync key, so that we can tell when it's changed.
+    kid: String,
+    #[serde(rename = "IV")]
+    iv: String,
+    hmac: String,
+    ciphertext: String,
+}
+
+// Splits kSync into the encryption and HMAC keys, like Sync's root key bundle.
+fn split_sync_key(sync_key: &ScopedKey) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut enc_key = sync_key.key_bytes()?;
+    if enc_key.len() != 64 {
+        return Err(ErrorKind::BadKeyLength("kSync", enc_key.len(), 64).into());
+    }
+    let mac_key = enc_key.split_off(32);
+    Ok((enc_key, mac_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_key(kid: &str) -> ScopedKey {
+        ScopedKey {
+            kty: "oct".to_string(),
+            scope: "https://identity.mozilla.com/apps/oldsync".to_string(),
+            k: "8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA".to_string(),
+            kid: kid.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_command_data_roundtrip() {
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let public_keys = keys.public_keys().unwrap();
+        let data = public_keys.to_command_data(&sync_key("1-abc")).unwrap();
+        assert_eq!(
+            PublicSendTabKeys::from_command_data(&data, &sync_key("1-abc")).unwrap(),
+            public_keys
+        );
+        assert!(PublicSendTabKeys::from_command_data(&data, &sync_key("2-def")).is_err());
+    }
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let payload = SendTabPayload::single_tab("Example", "https://www.example.com/");
+        let encrypted = keys.public_keys().unwrap().encrypt(&payload).unwrap();
+        assert_eq!(keys.decrypt(&encrypted).unwrap(), payload);
+
+        let other_keys = PrivateSendTabKeys::from_random().unwrap();
+        assert!(other_keys.decrypt(&encrypted).is_err());
+    }
+}
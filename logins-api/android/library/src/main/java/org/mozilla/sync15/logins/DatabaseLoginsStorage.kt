@@ -12,9 +12,12 @@ import android.util.Log
 import com.sun.jna.Pointer
 import kotlinx.coroutines.experimental.launch
 import org.mozilla.sync15.logins.rust.PasswordSyncAdapter
+import org.mozilla.sync15.logins.rust.RawHttpCallback
+import org.mozilla.sync15.logins.rust.RawLoginsInterruptHandle
 import org.mozilla.sync15.logins.rust.RawLoginSyncState
 import org.mozilla.sync15.logins.rust.RustError
 import java.io.Closeable
+import java.nio.ByteBuffer
 
 /**
  * LoginsStorage implementation backed by a database.
@@ -23,6 +26,16 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
 
     private var raw: RawLoginSyncState? = null;
 
+    // Used by `interrupt()`, which can't wait for the lock on
+    // `PasswordSyncAdapter.INSTANCE` since the sync holds it. Guarded by
+    // `interruptLock` instead.
+    private var interruptHandle: RawLoginsInterruptHandle? = null
+    private val interruptLock = Any()
+
+    // Set by `setHttpCallback()`, and held so that it isn't garbage collected
+    // while Rust uses it. It's applied to the sync state whenever we unlock.
+    private var httpCallback: RawHttpCallback? = null
+
     override fun isLocked(): SyncResult<Boolean> {
         return safeAsync {
             // Run inside a safeAsync block to be sure that all pending operations have finished.
@@ -43,6 +56,7 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
                 throw MismatchedLockException("Lock called when we are already locked")
             }
             // Free the sync state object
+            destroyInterruptHandle()
             var raw = this.raw;
             this.raw = null;
             if (raw != null) {
@@ -62,11 +76,45 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
                     encryptionKey,
                     error
             )
+            if (error.isSuccess()) {
+                val handle = PasswordSyncAdapter.INSTANCE.sync15_passwords_new_interrupt_handle(raw!!, error)
+                synchronized(interruptLock) {
+                    interruptHandle = handle
+                }
+            }
+            val callback = httpCallback
+            if (callback != null && error.isSuccess()) {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_set_http_callback(raw!!, callback, error)
+            }
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    /**
+     * Sends sync requests with [send], instead of the library's own networking stack, so that
+     * they use the application's. [send] is called on the syncing thread with the request as a
+     * JSON object like
+     * `{"method": "GET", "url": "...", "headers": {"Name": "value"}, "body": "<base64>"}`,
+     * and returns the response as a JSON object like
+     * `{"status": 200, "url": "...", "headers": {"Name": "value"}, "body": "<base64>"}`, or
+     * `{"error": "message"}` if it couldn't get one. Requests are sent one at a time, so [send]
+     * is never called again until it has returned. It stays in effect across locking and
+     * unlocking.
+     */
+    fun setHttpCallback(send: (String) -> String?): SyncResult<Unit> {
         return safeAsync { error ->
+            val callback = object : RawHttpCallback {
+                override fun invoke(requestJson: String): String? = send(requestJson)
+            }
+            httpCallback = callback
+            val raw = this.raw
+            if (raw != null) {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_set_http_callback(raw, callback, error)
+            }
+        }
+    }
+
+    override fun sync(syncInfo: SyncUnlockInfo, commandsJson: String?): SyncResult<String> {
+        return safeAsyncString { error ->
             Log.d("LoginsAPI", "sync")
             checkUnlocked()
             PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(this.raw!!,
@@ -74,10 +122,57 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    commandsJson,
+                    syncInfo.configJson(),
+                    error)
+        }.then {
+            SyncResult.fromValue(it!!)
+        }
+    }
+
+    override fun hasPendingSync(): SyncResult<Boolean> {
+        return safeAsync { error ->
+            checkUnlocked()
+            val pending = PasswordSyncAdapter.INSTANCE.sync15_passwords_has_pending_sync(this.raw!!, error)
+            pending.toInt() != 0
+        }
+    }
+
+    override fun retryPendingSync(syncInfo: SyncUnlockInfo): SyncResult<String?> {
+        return safeAsyncString { error ->
+            Log.d("LoginsAPI", "retryPendingSync")
+            checkUnlocked()
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_retry_pending(this.raw!!,
+                    syncInfo.kid,
+                    syncInfo.fxaAccessToken,
+                    syncInfo.syncKey,
+                    syncInfo.tokenserverURL,
+                    syncInfo.configJson(),
                     error)
         }
     }
 
+    override fun interrupt() {
+        synchronized(interruptLock) {
+            val handle = interruptHandle ?: return
+            val error = RustError.ByReference()
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_interrupt(handle, error)
+            if (error.isFailure()) {
+                Log.e("LoginsAPI", "Failed to interrupt: ${error.consumeErrorMessage()}")
+            }
+        }
+    }
+
+    private fun destroyInterruptHandle() {
+        synchronized(interruptLock) {
+            val handle = interruptHandle
+            interruptHandle = null
+            if (handle != null) {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_interrupt_handle_destroy(handle)
+            }
+        }
+    }
+
     override fun reset(): SyncResult<Unit> {
         return safeAsync { error ->
             Log.d("LoginsAPI", "reset")
@@ -138,6 +233,26 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
         }
     }
 
+    override fun listIds(): SyncResult<List<String>> {
+        return safeAsync { error ->
+            Log.d("LoginsAPI", "list ids")
+            checkUnlocked()
+            val buf = PasswordSyncAdapter.INSTANCE.sync15_passwords_get_all_ids(this.raw!!, error)
+            try {
+                val bytes = ByteBuffer.wrap(buf.getByteArray())
+                val ids = ArrayList<String>()
+                while (bytes.hasRemaining()) {
+                    val id = ByteArray(bytes.getInt())
+                    bytes.get(id)
+                    ids.add(String(id, Charsets.UTF_8))
+                }
+                ids
+            } finally {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_destroy_bytebuffer(buf)
+            }
+        }
+    }
+
     override fun add(login: ServerPassword): SyncResult<String> {
         return safeAsyncString {
             val s = login.toJSON().toString()
@@ -158,6 +273,7 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
 
     override fun close() {
         synchronized(PasswordSyncAdapter.INSTANCE) {
+            destroyInterruptHandle()
             var raw = this.raw;
             this.raw = null;
             if (raw != null) {
@@ -8,14 +8,40 @@
  * specific language governing permissions and limitations under the License. */
 package org.mozilla.sync15.logins
 
+import android.util.Base64
+import org.json.JSONArray
+import org.json.JSONObject
 import java.io.Closeable
 
+/**
+ * The credentials and settings for a sync.
+ *
+ * @param storageURL the storage server to use instead of the one the tokenserver returns, for
+ *  servers behind a proxy. If null, uses the tokenserver's.
+ * @param allowInsecureHttp whether to allow plain `http` tokenserver and storage URLs. Only
+ *  useful for local test servers.
+ * @param extraRootCertificates DER-encoded certificates to trust as roots, in addition to the
+ *  system's, for self-hosted servers.
+ */
 class SyncUnlockInfo (
         val kid: String,
         val fxaAccessToken: String,
         val syncKey: String,
-        val tokenserverURL: String
-)
+        val tokenserverURL: String,
+        val storageURL: String? = null,
+        val allowInsecureHttp: Boolean = false,
+        val extraRootCertificates: List<ByteArray> = listOf()
+) {
+    internal fun configJson(): String {
+        val config = JSONObject()
+        storageURL?.let { config.put("storageUrl", it) }
+        config.put("allowInsecureHttp", allowInsecureHttp)
+        val certs = JSONArray()
+        extraRootCertificates.forEach { certs.put(Base64.encodeToString(it, Base64.NO_WRAP)) }
+        config.put("extraRootCertificates", certs)
+        return config.toString()
+    }
+}
 
 interface LoginsStorage : Closeable {
 
@@ -27,8 +53,42 @@ interface LoginsStorage : Closeable {
 
     /**
      * Synchronize the logins storage layer with a remote layer.
+     *
+     * `commandsJson` is a JSON array of the commands other clients sent us
+     * through the clients collection, like
+     * `[{"command": "wipeEngine", "args": ["passwords"]}]`. Wipe and reset
+     * commands for passwords are applied before syncing.
+     *
+     * The result is a JSON object, like
+     * `{"telemetry": {...}, "requiresLocalReset": [], "requiresLocalWipe": []}`.
+     * `telemetry` is the sync telemetry ping, which the app should submit to
+     * the telemetry pipeline. `requiresLocalReset` and `requiresLocalWipe`
+     * are the other engines which the app should reset or wipe locally.
+     */
+    fun sync(syncInfo: SyncUnlockInfo, commandsJson: String? = null): SyncResult<String>
+
+    /**
+     * Returns true if the last sync failed because the server couldn't be
+     * reached, or was interrupted, and it hasn't been retried successfully
+     * since.
      */
-    fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit>
+    fun hasPendingSync(): SyncResult<Boolean>
+
+    /**
+     * Sync again if a sync is pending (see [hasPendingSync]), for example
+     * when the device comes back online. Changes made since the failed sync
+     * are uploaded too. The result is the same as [sync]'s, or null if no
+     * sync was pending, in which case we don't sync at all.
+     */
+    fun retryPendingSync(syncInfo: SyncUnlockInfo): SyncResult<String?>
+
+    /**
+     * Cancel the sync in progress, if any, for example because the app is
+     * going to the background. The sync's result fails with an
+     * [OperationInterruptedException]. Unlike the other methods, this
+     * returns immediately, without waiting for pending operations.
+     */
+    fun interrupt()
 
     /**
      * Delete all locally stored login sync metadata.
@@ -60,6 +120,12 @@ interface LoginsStorage : Closeable {
      */
     fun list(): SyncResult<List<ServerPassword>>
 
+    /**
+     * Fetch the ids of every password, which is much cheaper than [list]
+     * for callers that only need to know which logins exist.
+     */
+    fun listIds(): SyncResult<List<String>>
+
     /**
      * Insert the provided login into the database.
      *
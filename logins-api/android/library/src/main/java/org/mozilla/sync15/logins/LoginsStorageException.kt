@@ -56,4 +56,21 @@ class InvalidKeyException(msg: String): LoginsStorageException(msg)
  */
 class RequestFailedException(msg: String): LoginsStorageException(msg)
 
+/**
+ * This error is emitted if a sync was cancelled with `interrupt()`. The sync
+ * is retried the next time `sync()` is called.
+ */
+class OperationInterruptedException(msg: String): LoginsStorageException(msg)
 
+/**
+ * This error is emitted if the sync server asked us to back off (for example
+ * because it is overloaded). Syncing should not be retried until later.
+ */
+class SyncBackoffException(msg: String): LoginsStorageException(msg)
+
+/**
+ * This error is emitted if another client changed the sync server's keys
+ * while we were syncing, and we weren't able to recover by starting over.
+ * Syncing again later should succeed.
+ */
+class SyncKeysChangedException(msg: String): LoginsStorageException(msg)
@@ -62,14 +62,33 @@ class MemoryLoginsStorage(private var list: List<ServerPassword>) : Closeable, L
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    override fun sync(syncInfo: SyncUnlockInfo, commandsJson: String?): SyncResult<String> {
         return asyncResult {
             checkUnlocked()
             Log.w("MemoryLoginsStorage", "Not syncing because this implementation can not sync")
-            Unit
+            // A ping with no syncs in it.
+            """{"telemetry":{"version":1,"syncs":[]},"requiresLocalReset":[],"requiresLocalWipe":[]}"""
+        }
+    }
+
+    override fun hasPendingSync(): SyncResult<Boolean> {
+        return asyncResult {
+            checkUnlocked()
+            false
+        }
+    }
+
+    override fun retryPendingSync(syncInfo: SyncUnlockInfo): SyncResult<String?> {
+        return asyncResult {
+            checkUnlocked()
+            null
         }
     }
 
+    override fun interrupt() {
+        // Nothing to do, since we never sync.
+    }
+
     override fun reset(): SyncResult<Unit> {
         return asyncResult {
             checkUnlocked()
@@ -181,6 +200,13 @@ class MemoryLoginsStorage(private var list: List<ServerPassword>) : Closeable, L
         }
     }
 
+    override fun listIds(): SyncResult<List<String>> {
+        return asyncResult {
+            checkUnlocked()
+            list.map { it.id }
+        }
+    }
+
     private fun checkNotClosed() {
         if (state == LoginsStorageState.Closed) {
             throw LoginsStorageException("Using MemoryLoginsStorage after close!");
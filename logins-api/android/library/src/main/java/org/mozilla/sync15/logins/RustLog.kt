@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+package org.mozilla.sync15.logins
+
+import android.util.Log
+import org.mozilla.sync15.logins.rust.PasswordSyncAdapter
+import org.mozilla.sync15.logins.rust.RawLogAdapter
+import org.mozilla.sync15.logins.rust.RawLogCallback
+import org.mozilla.sync15.logins.rust.RustError
+
+/**
+ * Forwards log messages from the logins rust code to logcat.
+ */
+object RustLog {
+    private var adapter: RawLogAdapter? = null
+
+    // Held on to so that it isn't garbage collected while rust still has it.
+    private val callback = object : RawLogCallback {
+        override fun invoke(level: Int, tag: String?, message: String) {
+            Log.println(level, tag ?: "libloginsapi_ffi", message)
+        }
+    }
+
+    /**
+     * Start forwarding logs. Does nothing if we're already doing so.
+     */
+    @Synchronized
+    fun enable() {
+        if (adapter != null) {
+            return
+        }
+        adapter = rustCall { error ->
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_log_adapter_create(callback, error)
+        }
+    }
+
+    /**
+     * Only forward messages with at least `level` priority (one of the
+     * `android.util.Log` constants, e.g. `Log.WARN`).
+     */
+    @Synchronized
+    fun setMaxLevel(level: Int) {
+        rustCall { error ->
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_log_adapter_set_max_level(level, error)
+        }
+    }
+
+    /**
+     * Stop forwarding logs.
+     */
+    @Synchronized
+    fun disable() {
+        adapter?.let { PasswordSyncAdapter.INSTANCE.sync15_passwords_log_adapter_destroy(it) }
+        adapter = null
+    }
+
+    private inline fun <U> rustCall(callback: (RustError.ByReference) -> U): U {
+        val e = RustError.ByReference()
+        val ret: U = callback(e)
+        if (e.isFailure()) {
+            throw e.intoException()
+        }
+        return ret
+    }
+}
@@ -7,10 +7,12 @@
  * CONDITIONS OF ANY KIND, either express or implied. See the License for the
  * specific language governing permissions and limitations under the License. */
 package org.mozilla.sync15.logins.rust
+import com.sun.jna.Callback
 import com.sun.jna.Library
 import com.sun.jna.Native
 import com.sun.jna.Pointer
 import com.sun.jna.PointerType
+import com.sun.jna.Structure
 
 
 @Suppress("FunctionNaming", "TooManyFunctions", "TooGenericExceptionThrown")
@@ -30,6 +32,22 @@ internal interface PasswordSyncAdapter : Library {
             error: RustError.ByReference
     ): RawLoginSyncState
 
+    // Like sync15_passwords_state_new, but derives the encryption key from `secret`
+    fun sync15_passwords_state_new_with_secret(
+            mentat_db_path: String,
+            secret: String,
+            error: RustError.ByReference
+    ): RawLoginSyncState
+
+    // Re-encrypts a database opened with `old_key` so it can be opened with
+    // sync15_passwords_state_new_with_secret. The database must not be open.
+    fun sync15_passwords_migrate_from_raw_key(
+            mentat_db_path: String,
+            old_key: String,
+            secret: String,
+            error: RustError.ByReference
+    )
+
     fun sync15_passwords_state_destroy(p: RawLoginSyncState)
 
     // Important: strings returned from rust as *char must be Pointers on this end, returning a
@@ -42,12 +60,55 @@ internal interface PasswordSyncAdapter : Library {
     // return json array
     fun sync15_passwords_get_all(state: RawLoginSyncState, error: RustError.ByReference): Pointer
 
+    // return ids as length-prefixed UTF-8 strings, free with sync15_passwords_destroy_bytebuffer
+    fun sync15_passwords_get_all_ids(state: RawLoginSyncState, error: RustError.ByReference): RustBuffer.ByValue
+
+    // return json array, `since` is in milliseconds since the unix epoch
+    fun sync15_passwords_get_modified_since(state: RawLoginSyncState, since: Long, error: RustError.ByReference): Pointer
+
+    // return json array of ids, `since` is in milliseconds since the unix epoch
+    fun sync15_passwords_get_deleted_since(state: RawLoginSyncState, since: Long, error: RustError.ByReference): Pointer
+
+    // `commands_json` is a json array of the commands other clients sent us, or null.
+    // `config_json` is a json object with the `storageUrl`, `allowInsecureHttp` and
+    // `extraRootCertificates` (base64 DER) settings, or null.
+    // return json object, with the sync telemetry ping and the engines needing a local
+    // reset or wipe
     fun sync15_passwords_sync(state: RawLoginSyncState,
                               key_id: String,
                               access_token: String,
                               sync_key: String,
                               token_server_url: String,
-                              error: RustError.ByReference)
+                              commands_json: String?,
+                              config_json: String?,
+                              error: RustError.ByReference): Pointer?
+
+    // Returns a handle which cancels a sync in progress on `state`. It may be
+    // used while another thread holds `state`. Free with
+    // sync15_passwords_interrupt_handle_destroy.
+    fun sync15_passwords_new_interrupt_handle(state: RawLoginSyncState, error: RustError.ByReference): RawLoginsInterruptHandle?
+    fun sync15_passwords_interrupt(handle: RawLoginsInterruptHandle, error: RustError.ByReference)
+    fun sync15_passwords_interrupt_handle_destroy(handle: RawLoginsInterruptHandle)
+
+    // Sends the sync requests for `state` with `callback`. See
+    // `sync15_adapter::ForeignSendCallback` for the request and response format.
+    fun sync15_passwords_set_http_callback(state: RawLoginSyncState, callback: RawHttpCallback, error: RustError.ByReference)
+
+    fun sync15_passwords_has_pending_sync(state: RawLoginSyncState, error: RustError.ByReference): Byte
+
+    // Returns the same JSON as sync15_passwords_sync, or null if no sync was
+    // pending.
+    fun sync15_passwords_retry_pending(state: RawLoginSyncState,
+                                       key_id: String,
+                                       access_token: String,
+                                       sync_key: String,
+                                       token_server_url: String,
+                                       config_json: String?,
+                                       error: RustError.ByReference): Pointer?
+
+    // return json object with numLogins, numTombstones, numMirrorRecords,
+    // dbSizeBytes and lastSync (milliseconds since the unix epoch, or null)
+    fun sync15_passwords_get_db_stats(state: RawLoginSyncState, error: RustError.ByReference): Pointer
 
     fun sync15_passwords_wipe(state: RawLoginSyncState, error: RustError.ByReference)
     fun sync15_passwords_reset(state: RawLoginSyncState, error: RustError.ByReference)
@@ -60,7 +121,51 @@ internal interface PasswordSyncAdapter : Library {
     fun sync15_passwords_add(state: RawLoginSyncState, new_login_json: String, error: RustError.ByReference): Pointer
     fun sync15_passwords_update(state: RawLoginSyncState, existing_login_json: String, error: RustError.ByReference)
 
+    // Start forwarding rust logs to `callback`. Free with sync15_passwords_log_adapter_destroy.
+    fun sync15_passwords_log_adapter_create(callback: RawLogCallback, error: RustError.ByReference): RawLogAdapter?
+    // `level` is an android.util.Log priority.
+    fun sync15_passwords_log_adapter_set_max_level(level: Int, error: RustError.ByReference)
+    fun sync15_passwords_log_adapter_destroy(adapter: RawLogAdapter)
+
     fun sync15_passwords_destroy_string(p: Pointer)
+    fun sync15_passwords_destroy_bytebuffer(b: RustBuffer.ByValue)
 }
 
 class RawLoginSyncState : PointerType()
+
+class RawLoginsInterruptHandle : PointerType()
+
+class RawLogAdapter : PointerType()
+
+/**
+ * A `ffi_support::ByteBuffer`, which is returned by value. It must be freed with
+ * `sync15_passwords_destroy_bytebuffer`.
+ */
+open class RustBuffer : Structure() {
+    @JvmField var len: Long = 0
+    @JvmField var data: Pointer? = null
+
+    class ByValue : RustBuffer(), Structure.ByValue
+
+    override fun getFieldOrder(): List<String> {
+        return listOf("len", "data")
+    }
+
+    fun getByteArray(): ByteArray {
+        return this.data?.getByteArray(0, this.len.toInt()) ?: ByteArray(0)
+    }
+}
+
+internal interface RawLogCallback : Callback {
+    fun invoke(level: Int, tag: String?, message: String)
+}
+
+/**
+ * Sends a request, described by a JSON object, and returns the response as a JSON object. See
+ * `sync15_adapter::ForeignSendCallback` for the format. JNA only keeps the returned string alive
+ * until the callback is called again, so Rust calls it for one request at a time, and a callback
+ * mustn't be in use by two Rust objects at once.
+ */
+internal interface RawHttpCallback : Callback {
+    fun invoke(requestJson: String): String?
+}
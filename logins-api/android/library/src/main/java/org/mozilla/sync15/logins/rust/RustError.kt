@@ -49,12 +49,18 @@ open class RustError : Structure() {
         }
         val message = this.consumeErrorMessage();
         when (code) {
+            // Codes shared between components.
             1 -> return SyncAuthInvalidException(message)
-            2 -> return NoSuchRecordException(message)
-            3 -> return IdCollisionException(message)
-            4 -> return InvalidRecordException(message)
+            2 -> return RequestFailedException(message)
+            3 -> return OperationInterruptedException(message)
+            4 -> return SyncBackoffException(message)
             5 -> return InvalidKeyException(message)
-            6 -> return RequestFailedException(message)
+            // Codes specific to sync.
+            101 -> return SyncKeysChangedException(message)
+            // Codes specific to logins.
+            201 -> return NoSuchRecordException(message)
+            202 -> return IdCollisionException(message)
+            203 -> return InvalidRecordException(message)
             else -> return LoginsStorageException(message)
         }
     }
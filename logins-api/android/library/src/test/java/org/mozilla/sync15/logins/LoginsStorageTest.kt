@@ -98,6 +98,7 @@ abstract class LoginsStorageTest {
         // Note that waitForException fails the test if it successfully resolves
         waitForException(test.get("aaaaaaaaaaaa"))
         waitForException(test.list())
+        waitForException(test.listIds())
         waitForException(test.delete("aaaaaaaaaaaa"))
         waitForException(test.touch("bbbbbbbbbbbb"))
         waitForException(test.wipe())
@@ -156,8 +157,11 @@ abstract class LoginsStorageTest {
         waitForResult(test.unlock(encryptionKey))
         assertEquals(2, waitForResult(test.list()).size)
 
+        assertEquals(listOf("aaaaaaaaaaaa", "bbbbbbbbbbbb"), waitForResult(test.listIds()).sorted())
+
         waitForResult(test.wipe())
         assertEquals(0, waitForResult(test.list()).size)
+        assertEquals(0, waitForResult(test.listIds()).size)
 
         assertNull(waitForResult(test.get("aaaaaaaaaaaa")))
         assertNull(waitForResult(test.get("bbbbbbbbbbbb")))
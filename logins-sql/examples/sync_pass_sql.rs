@@ -289,7 +289,7 @@ fn show_all(engine: &PasswordEngine) -> Result<Vec<String>> {
                 timestamp_to_string(rec.time_last_used)
             }
         ]);
-        v.push(rec.id.clone());
+        v.push(rec.id.to_string());
     }
     table.printstd();
     Ok(v)
@@ -381,6 +381,9 @@ fn main() -> Result<()> {
         key_id: key.kid.clone(),
         access_token: token.access_token.clone(),
         tokenserver_url,
+        storage_url: None,
+        allow_insecure_http: false,
+        extra_root_certificates: Vec::new(),
     };
     let root_sync_key = KeyBundle::from_ksync_base64(&key.k)?;
 
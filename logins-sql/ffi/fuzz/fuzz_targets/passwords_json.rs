@@ -0,0 +1,49 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Feeds arbitrary JSON to `sync15_passwords_add` and `sync15_passwords_update`,
+// which parse whatever the app gives them. Bad input should come back as an
+// error, never as a panic. Run with `cargo fuzz run passwords_json` from
+// `logins-sql/ffi`.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate ffi_support;
+extern crate logins_sql;
+extern crate loginsapi_ffi;
+
+use std::ffi::CString;
+
+use ffi_support::{destroy_c_string, ErrorCode, ExternError};
+use logins_sql::PasswordEngine;
+use loginsapi_ffi::{sync15_passwords_add, sync15_passwords_update};
+
+thread_local! {
+    // Opening a database for each input would make fuzzing much slower.
+    static ENGINE: PasswordEngine = PasswordEngine::new_in_memory(None).unwrap();
+}
+
+fn check(error: ExternError) {
+    assert_ne!(error.get_code(), ErrorCode::PANIC, "{:?}", unsafe { error.get_message() });
+    unsafe { error.manually_release() };
+}
+
+fuzz_target!(|data: &[u8]| {
+    // C strings can't have NULs in them, so the app can't send us these.
+    let json = match CString::new(data) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    ENGINE.with(|engine| {
+        let mut error = ExternError::default();
+        let guid = unsafe { sync15_passwords_add(engine, json.as_ptr(), &mut error) };
+        check(error);
+        unsafe { destroy_c_string(guid) };
+
+        let mut error = ExternError::default();
+        unsafe { sync15_passwords_update(engine, json.as_ptr(), &mut error) };
+        check(error);
+    });
+});
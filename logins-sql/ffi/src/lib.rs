@@ -2,6 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+extern crate base64;
 extern crate serde_json;
 extern crate rusqlite;
 extern crate logins_sql;
@@ -10,34 +11,34 @@ extern crate url;
 
 #[macro_use] extern crate ffi_support;
 #[macro_use] extern crate log;
-
-#[cfg(target_os = "android")]
-extern crate android_logger;
+#[macro_use] extern crate rc_log;
 
 use std::os::raw::c_char;
+use std::sync::Arc;
 
 use ffi_support::{
+    opt_rust_str_from_c,
     rust_str_from_c,
     rust_string_from_c,
     call_with_result,
+    call_with_output,
+    ByteBuffer,
     ExternError,
 };
 
 use logins_sql::{
     Result,
     Login,
+    LoginsInterruptHandle,
     PasswordEngine,
+    SyncOutcome,
 };
 
-fn logging_init() {
-    #[cfg(target_os = "android")]
-    {
-        android_logger::init_once(
-            android_logger::Filter::default().with_min_level(log::Level::Trace),
-            Some("libloginsapi_ffi"));
-        debug!("Android logging should be hooked up!")
-    }
-}
+define_log_adapter_ffi!(
+    sync15_passwords_log_adapter_create,
+    sync15_passwords_log_adapter_set_max_level,
+    sync15_passwords_log_adapter_destroy
+);
 
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_state_new(
@@ -45,7 +46,6 @@ pub unsafe extern "C" fn sync15_passwords_state_new(
     encryption_key: *const c_char,
     error: &mut ExternError,
 ) -> *mut PasswordEngine {
-    logging_init();
     trace!("sync15_passwords_state_new");
     call_with_result(error, || {
         let path = rust_str_from_c(db_path);
@@ -54,11 +54,115 @@ pub unsafe extern "C" fn sync15_passwords_state_new(
     })
 }
 
+/// Like `sync15_passwords_state_new`, but derives the key from `secret`. See
+/// `logins_sql::key`.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_state_new_with_secret(
+    db_path: *const c_char,
+    secret: *const c_char,
+    error: &mut ExternError,
+) -> *mut PasswordEngine {
+    trace!("sync15_passwords_state_new_with_secret");
+    call_with_result(error, || {
+        PasswordEngine::new_with_secret(rust_str_from_c(db_path), rust_str_from_c(secret))
+    })
+}
+
+/// Re-encrypts a database opened with `sync15_passwords_state_new` and
+/// `old_key` so that it can be opened with
+/// `sync15_passwords_state_new_with_secret` and `secret` instead. The
+/// database must not be open.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_migrate_from_raw_key(
+    db_path: *const c_char,
+    old_key: *const c_char,
+    secret: *const c_char,
+    error: &mut ExternError,
+) {
+    trace!("sync15_passwords_migrate_from_raw_key");
+    call_with_result(error, || -> Result<()> {
+        logins_sql::key::migrate_from_raw_key(
+            rust_str_from_c(db_path),
+            rust_str_from_c(old_key),
+            rust_str_from_c(secret),
+        )?;
+        Ok(())
+    })
+}
+
 // indirection to help `?` figure out the target error type
 fn parse_url(url: &str) -> sync15_adapter::Result<url::Url> {
     Ok(url::Url::parse(url)?)
 }
 
+// indirection to help `?` figure out the target error type
+fn decode_certificate(cert: &str) -> sync15_adapter::Result<Vec<u8>> {
+    Ok(base64::decode(cert)?)
+}
+
+// Builds the storage client's settings from our arguments, and the optional
+// `config_json`, like
+// `{"storageUrl": "https://...", "allowInsecureHttp": false, "extraRootCertificates": ["<base64 DER>"]}`.
+// Missing fields keep their defaults.
+fn storage_init(
+    key_id: String,
+    access_token: String,
+    tokenserver_url: &str,
+    config_json: Option<&str>,
+) -> Result<sync15_adapter::Sync15StorageClientInit> {
+    let config: serde_json::Value = match config_json {
+        Some(json) => serde_json::from_str(json)?,
+        None => serde_json::Value::Null,
+    };
+    let storage_url = match config["storageUrl"].as_str() {
+        Some(url) => Some(parse_url(url)?),
+        None => None,
+    };
+    let extra_root_certificates = match config["extraRootCertificates"].as_array() {
+        Some(certs) => certs.iter()
+            .filter_map(|cert| cert.as_str())
+            .map(decode_certificate)
+            .collect::<sync15_adapter::Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    Ok(sync15_adapter::Sync15StorageClientInit {
+        key_id,
+        access_token,
+        tokenserver_url: parse_url(tokenserver_url)?,
+        storage_url,
+        allow_insecure_http: config["allowInsecureHttp"].as_bool().unwrap_or(false),
+        extra_root_certificates,
+    })
+}
+
+// Parses the commands from our record in the `clients` collection, like
+// `[{"command": "wipeEngine", "args": ["passwords"]}]`, skipping the ones that
+// aren't about engines.
+fn parse_commands(json: &str) -> Result<Vec<sync15_adapter::Command>> {
+    let commands: Vec<serde_json::Value> = serde_json::from_str(json)?;
+    Ok(commands.iter().filter_map(|command| {
+        let name = command["command"].as_str()?;
+        let args = command["args"].as_array().map(|args| {
+            args.iter().filter_map(|arg| arg.as_str().map(String::from)).collect::<Vec<_>>()
+        }).unwrap_or_default();
+        sync15_adapter::Command::from_client_command(name, &args)
+    }).collect())
+}
+
+/// Syncs the passwords collection, after applying the `wipeEngine`,
+/// `resetEngine`, `wipeAll` and `resetAll` commands in `commands_json`, a
+/// JSON array of the commands other clients sent us, which may be null.
+///
+/// `config_json`, which may also be null, is a JSON object with the storage
+/// URL to use instead of the one from the tokenserver (`storageUrl`), whether
+/// to allow plain `http` URLs (`allowInsecureHttp`), and base64-encoded DER
+/// certificates to trust as roots (`extraRootCertificates`). See
+/// `sync15_adapter::Sync15StorageClientInit`.
+///
+/// Returns a JSON object with the sync's telemetry ping, for the app to
+/// submit (see `sync15_adapter::telemetry` for the format), and the other
+/// engines the app needs to reset or wipe locally. See
+/// `logins_sql::SyncOutcome`.
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_sync(
     state: &mut PasswordEngine,
@@ -66,21 +170,106 @@ pub unsafe extern "C" fn sync15_passwords_sync(
     access_token: *const c_char,
     sync_key: *const c_char,
     tokenserver_url: *const c_char,
+    commands_json: *const c_char,
+    config_json: *const c_char,
     error: &mut ExternError
-) {
+) -> *mut c_char {
     trace!("sync15_passwords_sync");
     // TODO: Is there any way to convince rust that some `&mut T` is unwind safe?
-    call_with_result(error, || {
-        state.sync(
-            &sync15_adapter::Sync15StorageClientInit {
-                key_id: rust_string_from_c(key_id),
-                access_token: rust_string_from_c(access_token),
-                tokenserver_url: parse_url(rust_str_from_c(tokenserver_url))?,
-            },
+    call_with_result(error, || -> Result<String> {
+        let commands = match opt_rust_str_from_c(commands_json) {
+            Some(json) => parse_commands(json)?,
+            None => Vec::new(),
+        };
+        let mut outcome = SyncOutcome::default();
+        state.sync_with_commands(
+            &storage_init(
+                rust_string_from_c(key_id),
+                rust_string_from_c(access_token),
+                rust_str_from_c(tokenserver_url),
+                opt_rust_str_from_c(config_json),
+            )?,
             &sync15_adapter::KeyBundle::from_ksync_base64(
                 rust_str_from_c(sync_key)
-            )?
-        )
+            )?,
+            &commands,
+            &mut outcome,
+        )?;
+        Ok(serde_json::to_string(&outcome)?)
+    })
+}
+
+/// Sends sync requests for `state` with `callback`, instead of our own
+/// networking stack, so that they go through the app's. See
+/// `sync15_adapter::ForeignSendCallback` for the request and response format.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_set_http_callback(
+    state: &PasswordEngine,
+    callback: sync15_adapter::ForeignSendCallback,
+    error: &mut ExternError,
+) {
+    trace!("sync15_passwords_set_http_callback");
+    call_with_output(error, || {
+        state.set_http_backend(Arc::new(sync15_adapter::ForeignBackend::new(callback)));
+    })
+}
+
+/// Syncs again if the last sync failed because the server couldn't be
+/// reached, or was interrupted, and returns the same JSON object as
+/// `sync15_passwords_sync`. Returns null without syncing if no sync is
+/// pending. Takes the same arguments as `sync15_passwords_sync`, except for
+/// the commands, which were applied by the sync that failed.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_retry_pending(
+    state: &mut PasswordEngine,
+    key_id: *const c_char,
+    access_token: *const c_char,
+    sync_key: *const c_char,
+    tokenserver_url: *const c_char,
+    config_json: *const c_char,
+    error: &mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_retry_pending");
+    call_with_result(error, || -> Result<Option<String>> {
+        let outcome = state.retry_pending(
+            &storage_init(
+                rust_string_from_c(key_id),
+                rust_string_from_c(access_token),
+                rust_str_from_c(tokenserver_url),
+                opt_rust_str_from_c(config_json),
+            )?,
+            &sync15_adapter::KeyBundle::from_ksync_base64(
+                rust_str_from_c(sync_key)
+            )?,
+        )?;
+        Ok(match outcome {
+            Some(outcome) => Some(serde_json::to_string(&outcome)?),
+            None => None,
+        })
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn sync15_passwords_has_pending_sync(
+    state: &PasswordEngine,
+    error: &mut ExternError
+) -> u8 {
+    trace!("sync15_passwords_has_pending_sync");
+    call_with_result(error, || {
+        state.has_pending_sync()
+    })
+}
+
+/// Returns a JSON object with the number of logins, tombstones and mirror
+/// records, the size of the database, and the time of the last sync.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_db_stats(
+    state: &PasswordEngine,
+    error: &mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_db_stats");
+    call_with_result(error, || {
+        state.get_db_stats()
     })
 }
 
@@ -119,6 +308,17 @@ pub unsafe extern "C" fn sync15_passwords_wipe(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn sync15_passwords_wipe_local(
+    state: &PasswordEngine,
+    error: &mut ExternError
+) {
+    trace!("sync15_passwords_wipe_local");
+    call_with_result(error, || {
+        state.wipe_local()
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sync15_passwords_reset(
     state: &PasswordEngine,
@@ -143,6 +343,63 @@ pub extern "C" fn sync15_passwords_get_all(
     })
 }
 
+// Encodes `strings` as a big-endian `i32` byte length followed by that many
+// bytes of UTF-8 for each, which is what a `java.nio.ByteBuffer` reads with
+// `getInt` and `get`.
+fn strings_to_buffer(strings: &[String]) -> ByteBuffer {
+    let mut buf = Vec::with_capacity(strings.iter().map(|s| s.len() + 4).sum());
+    for s in strings {
+        let len = s.len() as u32;
+        buf.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        buf.extend_from_slice(s.as_bytes());
+    }
+    ByteBuffer::from_vec(buf)
+}
+
+/// Returns the ids of every login, as length-prefixed strings (see
+/// `strings_to_buffer`), which are much cheaper to build and parse than a
+/// JSON array when there are lots of logins. The buffer must be freed with
+/// `sync15_passwords_destroy_bytebuffer`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_all_ids(
+    state: &PasswordEngine,
+    error: &mut ExternError
+) -> ByteBuffer {
+    trace!("sync15_passwords_get_all_ids");
+    call_with_result(error, || -> Result<ByteBuffer> {
+        Ok(strings_to_buffer(&state.list_ids()?))
+    })
+}
+
+/// Returns a JSON array of the logins added or changed, locally or by a sync,
+/// at or after `since` (in milliseconds since the unix epoch).
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_modified_since(
+    state: &PasswordEngine,
+    since: i64,
+    error: &mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_modified_since");
+    call_with_result(error, || -> Result<String> {
+        let modified = state.get_modified_since(since)?;
+        Ok(serde_json::to_string(&modified)?)
+    })
+}
+
+/// Returns a JSON array of the ids of the logins deleted at or after `since`
+/// (in milliseconds since the unix epoch).
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_deleted_since(
+    state: &PasswordEngine,
+    since: i64,
+    error: &mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_deleted_since");
+    call_with_result(error, || {
+        state.get_deleted_since(since)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_get_by_id(
     state: &PasswordEngine,
@@ -186,5 +443,52 @@ pub unsafe extern "C" fn sync15_passwords_update(
     });
 }
 
+/// Returns a handle which cancels a sync in progress on `state` when passed
+/// to `sync15_passwords_interrupt`. Unlike `state`, it may be used while
+/// another thread is syncing. It must be freed with
+/// `sync15_passwords_interrupt_handle_destroy`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_new_interrupt_handle(
+    state: &PasswordEngine,
+    error: &mut ExternError
+) -> *mut LoginsInterruptHandle {
+    trace!("sync15_passwords_new_interrupt_handle");
+    call_with_result(error, || -> Result<_> {
+        Ok(state.new_interrupt_handle())
+    })
+}
+
+/// Cancels the sync in progress, which fails with the `INTERRUPTED` error
+/// code. Does nothing if there isn't one.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_interrupt(
+    handle: &LoginsInterruptHandle,
+    error: &mut ExternError
+) {
+    trace!("sync15_passwords_interrupt");
+    call_with_result(error, || -> Result<()> {
+        handle.interrupt();
+        Ok(())
+    })
+}
+
 define_string_destructor!(sync15_passwords_destroy_string);
+define_bytebuffer_destructor!(sync15_passwords_destroy_bytebuffer);
 define_box_destructor!(PasswordEngine, sync15_passwords_state_destroy);
+define_box_destructor!(LoginsInterruptHandle, sync15_passwords_interrupt_handle_destroy);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strings_to_buffer() {
+        let buf = strings_to_buffer(&["abc".into(), "".into(), "d".into()]);
+        assert_eq!(unsafe { buf.into_vec() }, vec![
+            0, 0, 0, 3, b'a', b'b', b'c',
+            0, 0, 0, 0,
+            0, 0, 0, 1, b'd',
+        ]);
+        assert!(unsafe { strings_to_buffer(&[]).into_vec() }.is_empty());
+    }
+}
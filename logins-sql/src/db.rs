@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use rusqlite::{Connection, types::{ToSql, FromSql}};
+use std::fmt;
 use std::time::SystemTime;
 use std::path::Path;
 use std::collections::HashSet;
@@ -11,6 +12,7 @@ use std::result;
 use failure;
 use schema;
 use login::{LocalLogin, MirrorLogin, Login, SyncStatus, SyncLoginData};
+use query::LoginQuery;
 use sync::{
     self,
     CollectionRequest,
@@ -20,13 +22,78 @@ use sync::{
     ServerTimestamp,
     Store,
 };
+use sync::telemetry;
 use update_plan::UpdatePlan;
 use sql_support::{self, ConnExt};
+use types_support::Guid;
 use util;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How long `run_maintenance` keeps entries in `loginsDeleted`, which is as
+/// far back as `get_deleted_since` can see.
+const DELETED_LOG_RETENTION_MS: i64 = 60 * 24 * 60 * 60 * 1000;
+
+/// Counts and sizes describing the state of the database, for health
+/// dashboards and support diagnostics. See `PasswordEngine::get_db_stats`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginsDbStats {
+    /// The number of logins, as returned by `PasswordEngine::list`.
+    pub num_logins: u32,
+    /// The number of logins which were deleted locally after being synced,
+    /// and whose deletions haven't been uploaded yet.
+    pub num_tombstones: u32,
+    /// The number of records in the mirror, which holds the last synced
+    /// version of every login on the server.
+    pub num_mirror_records: u32,
+    /// The size of the database in bytes, not counting the write-ahead log.
+    pub db_size_bytes: u64,
+    /// The server time of the last successful sync, in milliseconds since
+    /// the unix epoch, or `None` if we haven't synced since the last reset.
+    pub last_sync: Option<i64>,
+}
 
 pub struct LoginDb {
     pub db: Connection,
+    interrupt_counter: Arc<AtomicUsize>,
+}
+
+/// A handle which cancels a sync in progress, from any thread. The sync
+/// stops at the next point where it's safe to, and fails with
+/// `ErrorKind::Interrupted`. Whatever query is running on the connection is
+/// interrupted as well.
+pub struct LoginsInterruptHandle {
+    db_handle: rusqlite::InterruptHandle,
+    interrupt_counter: Arc<AtomicUsize>,
+}
+
+impl LoginsInterruptHandle {
+    pub fn interrupt(&self) {
+        self.interrupt_counter.fetch_add(1, Ordering::SeqCst);
+        self.db_handle.interrupt();
+    }
+}
+
+/// Notes how many times the handles were interrupted when it was made, so
+/// that interrupting a previous sync doesn't cancel this one.
+pub(crate) struct InterruptScope {
+    start_value: usize,
+    interrupt_counter: Arc<AtomicUsize>,
+}
+
+impl InterruptScope {
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupt_counter.load(Ordering::SeqCst) != self.start_value
+    }
+
+    pub fn err_if_interrupted(&self) -> Result<()> {
+        if self.was_interrupted() {
+            throw!(ErrorKind::Interrupted);
+        }
+        Ok(())
+    }
 }
 
 impl LoginDb {
@@ -57,7 +124,7 @@ impl LoginDb {
 
         db.execute_batch(&initial_pragmas)?;
 
-        let mut logins = Self { db };
+        let mut logins = Self { db, interrupt_counter: Arc::new(AtomicUsize::new(0)) };
         schema::init(&mut logins)?;
         Ok(logins)
     }
@@ -69,6 +136,30 @@ impl LoginDb {
     pub fn open_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         Ok(Self::with_connection(Connection::open_in_memory()?, encryption_key)?)
     }
+
+    pub fn new_interrupt_handle(&self) -> LoginsInterruptHandle {
+        LoginsInterruptHandle {
+            db_handle: self.db.get_interrupt_handle(),
+            interrupt_counter: self.interrupt_counter.clone(),
+        }
+    }
+
+    pub(crate) fn begin_interrupt_scope(&self) -> InterruptScope {
+        InterruptScope {
+            start_value: self.interrupt_counter.load(Ordering::SeqCst),
+            interrupt_counter: self.interrupt_counter.clone(),
+        }
+    }
+
+    /// Re-encrypts the database with `new_key`. The database must already be
+    /// encrypted, since SQLCipher can't encrypt or decrypt one in place.
+    pub fn rekey(&self, new_key: &str) -> Result<()> {
+        self.db.execute_batch(&format!(
+            "PRAGMA rekey = '{}';",
+            sql_support::escape_string_for_pragma(new_key),
+        ))?;
+        Ok(())
+    }
 }
 
 impl ConnExt for LoginDb {
@@ -78,6 +169,44 @@ impl ConnExt for LoginDb {
     }
 }
 
+// The query for `fetch_login_data`, which looks up the mirror and local
+// records for the `(index, guid)` rows in `vals`.
+fn fetch_login_data_sql(vals: &impl fmt::Display) -> String {
+    format!("
+        WITH to_fetch(guid_idx, fetch_guid) AS (VALUES {vals})
+        SELECT
+            {common_cols},
+            is_overridden,
+            server_modified,
+            NULL as local_modified,
+            NULL as is_deleted,
+            NULL as sync_status,
+            1 as is_mirror,
+            to_fetch.guid_idx as guid_idx
+        FROM loginsM
+        JOIN to_fetch
+          ON loginsM.guid = to_fetch.fetch_guid
+
+        UNION ALL
+
+        SELECT
+            {common_cols},
+            NULL as is_overridden,
+            NULL as server_modified,
+            local_modified,
+            is_deleted,
+            sync_status,
+            0 as is_mirror,
+            to_fetch.guid_idx as guid_idx
+        FROM loginsL
+        JOIN to_fetch
+          ON loginsL.guid = to_fetch.fetch_guid",
+        // give each VALUES item 2 entries, an index and the parameter.
+        vals = vals,
+        common_cols = schema::COMMON_COLS,
+    )
+}
+
 impl Deref for LoginDb {
     type Target = Connection;
     #[inline]
@@ -93,9 +222,13 @@ impl LoginDb {
 
     fn mark_as_synchronized(&self, guids: &[&str], ts: ServerTimestamp) -> Result<()> {
         sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            // Records which changed again after we fetched them for upload
+            // are left in `loginsL` (see below), and everything else moves
+            // into the mirror.
             self.db.execute(
-                &format!("DELETE FROM loginsM WHERE guid IN ({vars})",
-                         vars = sql_support::repeat_sql_vars(chunk.len())),
+                &format!("DELETE FROM loginsM WHERE guid IN ({vars}) AND guid NOT IN ({changed})",
+                         vars = sql_support::repeat_sql_vars(chunk.len()),
+                         changed = CHANGED_SINCE_UPLOAD_SQL),
                 chunk
             )?;
 
@@ -106,20 +239,39 @@ impl LoginDb {
                     )
                     SELECT {common_cols}, 0, {modified_ms_i64}
                     FROM loginsL
-                    WHERE is_deleted = 0 AND guid IN ({vars})",
+                    WHERE is_deleted = 0 AND guid IN ({vars}) AND guid NOT IN ({changed})",
                     common_cols = schema::COMMON_COLS,
                     modified_ms_i64 = ts.as_millis() as i64,
-                    vars = sql_support::repeat_sql_vars(chunk.len())),
+                    vars = sql_support::repeat_sql_vars(chunk.len()),
+                    changed = CHANGED_SINCE_UPLOAD_SQL),
+                chunk
+            )?;
+
+            self.db.execute(
+                &format!("DELETE FROM loginsL WHERE guid IN ({vars}) AND guid NOT IN ({changed})",
+                         vars = sql_support::repeat_sql_vars(chunk.len()),
+                         changed = CHANGED_SINCE_UPLOAD_SQL),
                 chunk
             )?;
 
+            // What's left changed during the sync, so still needs to be
+            // uploaded. It's on the server now, so it's no longer new.
             self.db.execute(
-                &format!("DELETE FROM loginsL WHERE guid IN ({vars})",
-                         vars = sql_support::repeat_sql_vars(chunk.len())),
+                &format!("
+                    UPDATE loginsL
+                    SET sync_status = {changed},
+                        sync_change_counter = sync_change_counter - (
+                            SELECT o.sync_change_counter FROM temp.loginsOutgoing o
+                            WHERE o.guid = loginsL.guid
+                        )
+                    WHERE guid IN ({vars})",
+                    changed = SyncStatus::Changed as u8,
+                    vars = sql_support::repeat_sql_vars(chunk.len())),
                 chunk
             )?;
             Ok(())
         })?;
+        self.execute_all(&["DELETE FROM temp.loginsOutgoing"])?;
         self.set_last_sync(ts)?;
         Ok(())
     }
@@ -127,56 +279,38 @@ impl LoginDb {
     // Fetch all the data for the provided IDs.
     // TODO: Might be better taking a fn instead of returning all of it... But that func will likely
     // want to insert stuff while we're doing this so ugh.
-    fn fetch_login_data(&self, records: &[(sync::Payload, ServerTimestamp)]) -> Result<Vec<SyncLoginData>> {
+    // Records that aren't valid logins are skipped, and counted in `telem`.
+    fn fetch_login_data(
+        &self,
+        records: &[(sync::Payload, ServerTimestamp)],
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<Vec<SyncLoginData>> {
         let mut sync_data = Vec::with_capacity(records.len());
         {
-            let mut seen_ids: HashSet<String> = HashSet::with_capacity(records.len());
+            let mut seen_ids: HashSet<Guid> = HashSet::with_capacity(records.len());
             for incoming in records.iter() {
-                if seen_ids.contains(&incoming.0.id) {
-                    throw!(ErrorKind::DuplicateGuid(incoming.0.id.to_string()))
+                let id = Guid::new(&incoming.0.id);
+                if seen_ids.contains(&id) {
+                    throw!(ErrorKind::DuplicateGuid(id.into_string()))
+                }
+                seen_ids.insert(id);
+                match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
+                    Ok(data) => sync_data.push(data),
+                    Err(e) => {
+                        warn!("Skipping invalid incoming login {}: {}", incoming.0.id, e);
+                        telem.failed += 1;
+                    }
                 }
-                seen_ids.insert(incoming.0.id.clone());
-                sync_data.push(SyncLoginData::from_payload(incoming.0.clone(), incoming.1)?);
             }
         }
+        let guids = sync_data.iter().map(|data| data.guid.clone()).collect::<Vec<_>>();
 
-        sql_support::each_chunk_mapped(&records, |r| &r.0.id as &ToSql, |chunk, offset| -> Result<()> {
+        sql_support::each_chunk_mapped(&guids, |guid| guid as &ToSql, |chunk, offset| -> Result<()> {
             // pairs the bound parameter for the guid with an integer index.
             let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| write!(f, "({},?)", i + offset));
-            let query = format!("
-                WITH to_fetch(guid_idx, fetch_guid) AS (VALUES {vals})
-                SELECT
-                    {common_cols},
-                    is_overridden,
-                    server_modified,
-                    NULL as local_modified,
-                    NULL as is_deleted,
-                    NULL as sync_status,
-                    1 as is_mirror,
-                    to_fetch.guid_idx as guid_idx
-                FROM loginsM
-                JOIN to_fetch
-                  ON loginsM.guid = to_fetch.fetch_guid
-
-                UNION ALL
-
-                SELECT
-                    {common_cols},
-                    NULL as is_overridden,
-                    NULL as server_modified,
-                    local_modified,
-                    is_deleted,
-                    sync_status,
-                    0 as is_mirror,
-                    to_fetch.guid_idx as guid_idx
-                FROM loginsL
-                JOIN to_fetch
-                  ON loginsL.guid = to_fetch.fetch_guid",
-                // give each VALUES item 2 entries, an index and the parameter.
-                vals = values_with_idx,
-                common_cols = schema::COMMON_COLS,
-            );
+            let query = fetch_login_data_sql(&values_with_idx);
 
+            sql_support::maybe_log_plan(&self.db, &query);
             let mut stmt = self.db.prepare(&query)?;
 
             let rows = stmt.query_and_then(chunk, |row| {
@@ -227,12 +361,105 @@ impl LoginDb {
         Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
     }
 
+    /// Returns the logins which `l` would be a duplicate of if it weren't for
+    /// the username, i.e. those with the same hostname, http realm, and form
+    /// submit host. Used so the save prompt can offer to update an existing
+    /// login instead of saving a new one.
+    pub fn potential_dupes_ignoring_username(&self, l: &Login) -> Result<Vec<Login>> {
+        let form_submit_host_port = l.form_submit_url.as_ref().and_then(|s| util::url_host_port(&s));
+        let args = &[
+            (":hostname", &l.hostname as &ToSql),
+            (":http_realm", &l.http_realm as &ToSql),
+            (":form_submit", &form_submit_host_port as &ToSql),
+        ];
+        let mut query = format!("
+            SELECT {common}
+            FROM ({get_all})
+            WHERE hostname IS :hostname
+              AND httpRealm IS :http_realm",
+            common = schema::COMMON_COLS,
+            get_all = &*GET_ALL_SQL,
+        );
+        // Same as in `find_dupe`.
+        if form_submit_host_port.is_some() {
+            query += " AND (formSubmitURL = '' OR (instr(formSubmitURL, :form_submit) > 0))";
+        } else {
+            query += " AND formSubmitURL IS :form_submit"
+        }
+        let mut stmt = self.db.prepare_cached(&query)?;
+        let rows = stmt.query_and_then_named(args, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
     pub fn get_all(&self) -> Result<Vec<Login>> {
         let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
         let rows = stmt.query_and_then(&[], Login::from_row)?;
         rows.collect::<Result<_>>()
     }
 
+    pub fn get_all_ids(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached("
+            SELECT guid FROM loginsL WHERE is_deleted = 0
+            UNION ALL
+            SELECT guid FROM loginsM WHERE is_overridden = 0
+        ")?;
+        let rows = stmt.query_and_then(&[], |row| row.get_checked(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn query(&self, q: &LoginQuery) -> Result<Vec<Login>> {
+        let mut sql = format!("
+            SELECT {common_cols} FROM ({get_all}) WHERE 1",
+            common_cols = schema::COMMON_COLS,
+            get_all = &*GET_ALL_SQL,
+        );
+        // A negative LIMIT means there isn't one.
+        let limit = q.limit.map(i64::from).unwrap_or(-1);
+        let mut args: Vec<(&str, &ToSql)> = Vec::new();
+        if let Some(hostname) = &q.hostname {
+            sql += " AND instr(hostname, :hostname) > 0";
+            args.push((":hostname", hostname));
+        }
+        if let Some(username) = &q.username {
+            sql += " AND instr(lower(username), lower(:username)) > 0";
+            args.push((":username", username));
+        }
+        if let Some(since) = &q.modified_since {
+            sql += " AND timePasswordChanged >= :modified_since";
+            args.push((":modified_since", since));
+        }
+        sql += &format!(" ORDER BY {} LIMIT :limit OFFSET :offset", q.sort.order_by());
+        args.push((":limit", &limit));
+        args.push((":offset", &q.offset));
+
+        let mut stmt = self.db.prepare_cached(&sql)?;
+        let rows = stmt.query_and_then_named(&args, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins which were added or changed at or after `since`
+    /// (in milliseconds since the unix epoch), either locally or by a sync.
+    pub fn get_modified_since(&self, since: i64) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_MODIFIED_SINCE_SQL)?;
+        let rows = stmt.query_and_then_named(&[(":since", &since as &ToSql)], Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the ids of the logins which were deleted at or after `since`
+    /// (in milliseconds since the unix epoch), either locally or by a sync,
+    /// and which haven't been added again since.
+    pub fn get_deleted_since(&self, since: i64) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached("
+            SELECT guid FROM loginsDeleted
+            WHERE time_deleted >= :since
+              AND guid NOT IN (SELECT guid FROM loginsL WHERE is_deleted = 0)
+              AND guid NOT IN (SELECT guid FROM loginsM WHERE is_overridden = 0)
+            ORDER BY time_deleted ASC, guid ASC
+        ")?;
+        let rows = stmt.query_and_then_named(&[(":since", &since as &ToSql)], |row| row.get_checked(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
     pub fn get_by_id(&self, id: &str) -> Result<Option<Login>> {
         self.try_query_row(&GET_BY_GUID_SQL,
                            &[(":guid", &id as &ToSql)],
@@ -268,13 +495,7 @@ impl LoginDb {
         // one. (Note that the FFI, does not require that the `id` field be
         // present in the JSON, and replaces it with an empty string if missing).
         if login.id.is_empty() {
-            // Our FFI handles panics so this is fine. In practice there's not
-            // much we can do here. Using a CSPRNG for this is probably
-            // unnecessary, so we likely could fall back to something less
-            // fallible eventually, but it's unlikely very much else will work
-            // if this fails, so it doesn't matter much.
-            login.id = sync::util::random_guid()
-                .expect("Failed to generate failed to generate random bytes for GUID");
+            login.id = Guid::random();
         }
 
         // Fill in default metadata.
@@ -284,6 +505,18 @@ impl LoginDb {
         login.time_last_used = now_ms;
         login.times_used = 1;
 
+        let rows_changed = self.insert_new_login(&login, now_ms)?;
+        if rows_changed == 0 {
+            error!("Record {:?} already exists (use `update` to update records, not add)",
+                   login.id);
+            throw!(ErrorKind::DuplicateGuid(login.id.into_string()));
+        }
+        Ok(login)
+    }
+
+    // Inserts `login` into the local table, returning the number of rows
+    // changed (0 if a record with its GUID already exists).
+    fn insert_new_login(&self, login: &Login, now_ms: i64) -> Result<usize> {
         let sql = format!("
             INSERT OR IGNORE INTO loginsL (
                 hostname,
@@ -300,7 +533,8 @@ impl LoginDb {
                 timePasswordChanged,
                 local_modified,
                 is_deleted,
-                sync_status
+                sync_status,
+                sync_change_counter
             ) VALUES (
                 :hostname,
                 :http_realm,
@@ -316,10 +550,11 @@ impl LoginDb {
                 :time_password_changed,
                 :local_modified,
                 0, -- is_deleted
-                {new} -- sync_status
+                {new}, -- sync_status
+                1 -- sync_change_counter
             )", new = SyncStatus::New as u8);
 
-        let rows_changed = self.execute_named(&sql, &[
+        Ok(self.execute_named(&sql, &[
             (":hostname", &login.hostname as &ToSql),
             (":http_realm", &login.http_realm as &ToSql),
             (":form_submit_url", &login.form_submit_url as &ToSql),
@@ -333,13 +568,43 @@ impl LoginDb {
             (":time_last_used", &login.time_last_used as &ToSql),
             (":time_password_changed", &login.time_password_changed as &ToSql),
             (":local_modified", &now_ms as &ToSql)
-        ])?;
-        if rows_changed == 0 {
-            error!("Record {:?} already exists (use `update` to update records, not add)",
-                   login.id);
-            throw!(ErrorKind::DuplicateGuid(login.id));
+        ])?)
+    }
+
+    /// Adds logins exported from another profile (see `export`), keeping
+    /// their metadata, and skipping any that are invalid or already exist.
+    /// Returns the number of logins added.
+    pub fn import_multiple(&self, logins: &[Login]) -> Result<usize> {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let tx = self.db.unchecked_transaction()?;
+        let mut num_added = 0;
+        for login in logins {
+            if let Err(e) = login.check_valid() {
+                warn!("Skipping invalid imported login {:?}: {}", login.id, e);
+                continue;
+            }
+            let mut login = login.clone();
+            if login.id.is_empty() {
+                login.id = Guid::random();
+            } else if self.exists(&login.id)? {
+                info!("Skipping imported login {:?}, which already exists", login.id);
+                continue;
+            }
+            // Hand-written (or other products') exports may not have metadata.
+            if login.time_created == 0 {
+                login.time_created = now_ms;
+            }
+            if login.time_password_changed == 0 {
+                login.time_password_changed = login.time_created;
+            }
+            if login.time_last_used == 0 {
+                login.time_last_used = login.time_created;
+            }
+            login.times_used = login.times_used.max(1);
+            num_added += self.insert_new_login(&login, now_ms)?;
         }
-        Ok(login)
+        tx.commit()?;
+        Ok(num_added)
     }
 
     pub fn update(&self, login: Login) -> Result<()> {
@@ -369,7 +634,8 @@ impl LoginDb {
                 password            = :password,
                 hostname            = :hostname,
                 -- leave New records as they are, otherwise update them to `changed`
-                sync_status         = max(sync_status, {changed})
+                sync_status         = max(sync_status, {changed}),
+                sync_change_counter = sync_change_counter + 1
             WHERE guid = :guid",
             changed = SyncStatus::Changed as u8
         );
@@ -408,6 +674,12 @@ impl LoginDb {
         let exists = self.exists(id)?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
+        if exists {
+            self.execute_named_cached(
+                "INSERT OR REPLACE INTO loginsDeleted(guid, time_deleted) VALUES (:guid, :now_ms)",
+                &[(":guid", &id as &ToSql), (":now_ms", &now_ms as &ToSql)])?;
+        }
+
         // Directly delete IDs that have not yet been synced to the server
         self.execute_named(&format!("
             DELETE FROM loginsL
@@ -422,6 +694,7 @@ impl LoginDb {
             UPDATE loginsL
             SET local_modified = :now_ms,
                 sync_status = {status_changed},
+                sync_change_counter = sync_change_counter + 1,
                 is_deleted = 1,
                 password = '',
                 hostname = '',
@@ -438,8 +711,8 @@ impl LoginDb {
         // insert a tombstone.
         self.execute_named(&format!("
             INSERT OR IGNORE INTO loginsL
-                    (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
-            SELECT   guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,                   '',       ''
+                    (guid, local_modified, is_deleted, sync_status, sync_change_counter, hostname, timeCreated, timePasswordChanged, password, username)
+            SELECT   guid, :now_ms,        1,          {changed},   1,                   '',       timeCreated, :now_ms,                   '',       ''
             FROM loginsM
             WHERE guid = :guid",
             changed = SyncStatus::Changed as u8),
@@ -490,23 +763,34 @@ impl LoginDb {
         self.execute_all(&[
             &*CLONE_ENTIRE_MIRROR_SQL,
             "DELETE FROM loginsM",
-            &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
+            &format!("UPDATE loginsL SET sync_status = {}, sync_change_counter = sync_change_counter + 1",
+                     SyncStatus::New as u8),
         ])?;
         self.set_last_sync(ServerTimestamp(0.0))?;
         // TODO: Should we clear global_state?
         Ok(())
     }
 
+    /// Deletes every login, and records the deletions so that they're synced.
+    /// See `wipe_local` for discarding local data without syncing anything.
     pub fn wipe(&self) -> Result<()> {
-        info!("Executing reset on password store!");
+        info!("Executing wipe on password store!");
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
+        self.execute_named("
+            INSERT OR REPLACE INTO loginsDeleted(guid, time_deleted)
+            SELECT guid, :now_ms FROM loginsL WHERE is_deleted = 0
+            UNION
+            SELECT guid, :now_ms FROM loginsM WHERE is_overridden = 0",
+            &[(":now_ms", &now_ms as &ToSql)])?;
+
         self.execute(&format!("DELETE FROM loginsL WHERE sync_status = {new}", new = SyncStatus::New as u8), &[])?;
         self.execute_named(
             &format!("
                 UPDATE loginsL
                 SET local_modified = :now_ms,
                     sync_status = {changed},
+                    sync_change_counter = sync_change_counter + 1,
                     is_deleted = 1,
                     password = '',
                     hostname = '',
@@ -520,8 +804,8 @@ impl LoginDb {
         self.execute_named(
             &format!("
                 INSERT OR IGNORE INTO loginsL
-                      (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
-                SELECT guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,             '',       ''
+                      (guid, local_modified, is_deleted, sync_status, sync_change_counter, hostname, timeCreated, timePasswordChanged, password, username)
+                SELECT guid, :now_ms,        1,          {changed},   1,                   '',       timeCreated, :now_ms,             '',       ''
                 FROM loginsM",
                 changed = SyncStatus::Changed as u8),
             &[(":now_ms", &now_ms as &ToSql)])?;
@@ -529,6 +813,40 @@ impl LoginDb {
         Ok(())
     }
 
+    /// Discards every login and all sync metadata, without recording anything
+    /// to upload, so that the next sync replaces our logins with the
+    /// server's. This is what another client's `wipeEngine` command asks for.
+    pub fn wipe_local(&self) -> Result<()> {
+        info!("Executing local wipe on password store!");
+        let tx = self.db.unchecked_transaction()?;
+        self.execute_all(&[
+            "DELETE FROM loginsL",
+            "DELETE FROM loginsM",
+        ])?;
+        self.set_last_sync(ServerTimestamp(0.0))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes mirror records which are overridden but have no local record
+    /// to take their place (so they can never be seen again), and deletion
+    /// log entries older than `DELETED_LOG_RETENTION_MS`, then vacuums the
+    /// database if that left it fragmented. See `sql_support::maintenance`.
+    pub fn run_maintenance(&self, now_ms: i64) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        let mirror = self.execute("
+            DELETE FROM loginsM
+            WHERE is_overridden = 1
+              AND guid NOT IN (SELECT guid FROM loginsL)", &[])?;
+        let deleted = self.execute_named(
+            "DELETE FROM loginsDeleted WHERE time_deleted < :cutoff",
+            &[(":cutoff", &(now_ms - DELETED_LOG_RETENTION_MS) as &ToSql)])?;
+        tx.commit()?;
+        debug!("Maintenance removed {} mirror records and {} deletion log entries", mirror, deleted);
+        sql_support::maintenance::vacuum_if_fragmented(&self.db)?;
+        Ok(())
+    }
+
     fn reconcile(&self, records: Vec<SyncLoginData>, server_now: ServerTimestamp) -> Result<UpdatePlan> {
         let mut plan = UpdatePlan::default();
 
@@ -582,6 +900,17 @@ impl LoginDb {
 
     pub fn fetch_outgoing(&self, st: ServerTimestamp) -> Result<OutgoingChangeset> {
         let mut outgoing = OutgoingChangeset::new("passwords".into(), st);
+        // Remember the counters of what we're uploading, so that
+        // `mark_as_synchronized` can tell if they change again before the
+        // upload finishes.
+        self.execute_all(&[
+            "DELETE FROM temp.loginsOutgoing",
+            &format!("
+                INSERT INTO temp.loginsOutgoing(guid, sync_change_counter)
+                SELECT guid, sync_change_counter FROM loginsL
+                WHERE sync_status IS NOT {synced}",
+                synced = SyncStatus::Synced as u8),
+        ])?;
         let mut stmt = self.db.prepare_cached(&format!("
             SELECT * FROM loginsL
             WHERE sync_status IS NOT {synced}",
@@ -600,12 +929,17 @@ impl LoginDb {
         Ok(outgoing)
     }
 
-    fn do_apply_incoming(
+    pub(crate) fn do_apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+        scope: &InterruptScope,
     ) -> Result<OutgoingChangeset> {
-        let data = self.fetch_login_data(&inbound.changes)?;
+        let data = self.fetch_login_data(&inbound.changes, telem)?;
         let plan = self.reconcile(data, inbound.timestamp)?;
+        // Nothing has been written yet, so this is the last chance to stop
+        // without leaving the incoming records half-applied.
+        scope.err_if_interrupted()?;
         self.execute_plan(plan)?;
         Ok(self.fetch_outgoing(inbound.timestamp)?)
     }
@@ -638,6 +972,23 @@ impl LoginDb {
                .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
     }
 
+    pub fn get_stats(&self) -> Result<LoginsDbStats> {
+        let num_logins: i64 = self.query_one("
+            SELECT (SELECT COUNT(*) FROM loginsL WHERE is_deleted = 0) +
+                   (SELECT COUNT(*) FROM loginsM WHERE is_overridden = 0)")?;
+        let num_tombstones: i64 = self.query_one("SELECT COUNT(*) FROM loginsL WHERE is_deleted = 1")?;
+        let num_mirror_records: i64 = self.query_one("SELECT COUNT(*) FROM loginsM")?;
+        let page_count: i64 = self.query_one("PRAGMA page_count")?;
+        let page_size: i64 = self.query_one("PRAGMA page_size")?;
+        Ok(LoginsDbStats {
+            num_logins: num_logins as u32,
+            num_tombstones: num_tombstones as u32,
+            num_mirror_records: num_mirror_records as u32,
+            db_size_bytes: (page_count * page_size) as u64,
+            last_sync: self.get_meta::<i64>(schema::LAST_SYNC_META_KEY)?.filter(|&millis| millis > 0),
+        })
+    }
+
     pub fn set_global_state(&self, global_state: &str) -> Result<()> {
         self.put_meta(schema::GLOBAL_STATE_META_KEY, &global_state)
     }
@@ -645,14 +996,27 @@ impl LoginDb {
     pub fn get_global_state(&self) -> Result<Option<String>> {
         self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
     }
+
+    pub fn set_sync_pending(&self, pending: bool) -> Result<()> {
+        self.put_meta(schema::PENDING_SYNC_META_KEY, &pending)
+    }
+
+    pub fn is_sync_pending(&self) -> Result<bool> {
+        Ok(self.get_meta::<bool>(schema::PENDING_SYNC_META_KEY)?.unwrap_or(false))
+    }
 }
 
 impl Store for LoginDb {
+    fn collection_name(&self) -> &'static str {
+        "passwords"
+    }
+
     fn apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
     ) -> result::Result<OutgoingChangeset, failure::Error> {
-        Ok(self.do_apply_incoming(inbound)?)
+        Ok(self.do_apply_incoming(inbound, telem, &self.begin_interrupt_scope())?)
     }
 
     fn sync_finished(
@@ -670,8 +1034,24 @@ impl Store for LoginDb {
         let since = self.get_last_sync()?.unwrap_or_default();
         Ok(CollectionRequest::new("passwords").full().newer_than(since))
     }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        Ok(LoginDb::reset(self)?)
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        Ok(LoginDb::wipe_local(self)?)
+    }
 }
 
+// The GUIDs of records in `loginsL` which have changed since
+// `fetch_outgoing` staged them for upload.
+const CHANGED_SINCE_UPLOAD_SQL: &'static str = "
+    SELECT l.guid FROM loginsL l
+    JOIN temp.loginsOutgoing o ON o.guid = l.guid
+    WHERE l.sync_change_counter > o.sync_change_counter
+";
+
 lazy_static! {
 
     static ref GET_ALL_SQL: String = format!("
@@ -682,6 +1062,18 @@ lazy_static! {
         common_cols = schema::COMMON_COLS,
     );
 
+    // `local_modified` is NULL for records cloned from the mirror which
+    // haven't changed locally, so they never match.
+    static ref GET_MODIFIED_SINCE_SQL: String = format!("
+        SELECT {common_cols} FROM loginsL
+        WHERE is_deleted = 0 AND local_modified >= :since
+        UNION ALL
+        SELECT {common_cols} FROM loginsM
+        WHERE is_overridden = 0 AND server_modified >= :since
+    ",
+        common_cols = schema::COMMON_COLS,
+    );
+
     static ref GET_BY_GUID_SQL: String = format!("
         SELECT {common_cols}
         FROM loginsL
@@ -713,3 +1105,17 @@ lazy_static! {
         &*CLONE_ENTIRE_MIRROR_SQL,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_login_data_uses_guid_indexes() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let sql = fetch_login_data_sql(&"(0,?)");
+        // The indexes SQLite makes for the `UNIQUE` guid columns.
+        sql_support::assert_uses_index(&db, &sql, "sqlite_autoindex_loginsM_1");
+        sql_support::assert_uses_index(&db, &sql, "sqlite_autoindex_loginsL_1");
+    }
+}
@@ -1,29 +1,44 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use std::result;
 use login::Login;
+use query::LoginQuery;
 use error::*;
-use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle};
-use db::LoginDb;
+use sync::{self, Sync15StorageClientInit, KeyBundle};
+use db::{LoginDb, LoginsDbStats, LoginsInterruptHandle, InterruptScope};
+use export;
+use key;
 use std::path::Path;
 use std::cell::Cell;
-use serde_json;
+use std::result;
+use std::sync::Arc;
+use std::time::SystemTime;
+use failure;
 use rusqlite;
-
-#[derive(Debug)]
-pub(crate) struct SyncInfo {
-    pub state: GlobalState,
-    pub client: Sync15StorageClient,
-    // Used so that we know whether or not we need to re-initialize `client`
-    pub last_client_init: Sync15StorageClientInit,
+use sql_support::maintenance::Maintenance;
+use util;
+
+/// What the app needs to know after syncing passwords.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncOutcome {
+    /// The sync's telemetry ping, which the app should submit.
+    pub telemetry: sync::SyncTelemetryPing,
+    /// Other engines which need a local reset, because their sync ID changed,
+    /// they were enabled or disabled in `meta/global`, or another client sent
+    /// a `resetEngine` command. The app should reset them before they next
+    /// sync, since we won't report them again.
+    pub requires_local_reset: Vec<String>,
+    /// Other engines which another client sent a `wipeEngine` command for,
+    /// which the app should wipe.
+    pub requires_local_wipe: Vec<String>,
 }
 
 // This isn't really an engine in the firefox sync15 desktop sense -- it's
 // really a bundle of state that contains the sync storage client, the sync
 // state, and the login DB.
 pub struct PasswordEngine {
-    sync: Cell<Option<SyncInfo>>,
+    mem_cached_state: Cell<sync::MemoryCachedState>,
     db: LoginDb,
 }
 
@@ -31,22 +46,73 @@ impl PasswordEngine {
 
     pub fn new(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
         let db = LoginDb::open(path, encryption_key)?;
-        Ok(Self { db, sync: Cell::new(None) })
+        Ok(Self { db, mem_cached_state: Cell::default() })
+    }
+
+    /// Opens the database at `path` with a key derived from `secret`. See the
+    /// `key` module for how the key is derived, and how to move a database
+    /// opened with `new` over to a derived key.
+    pub fn new_with_secret(path: impl AsRef<Path>, secret: &str) -> Result<Self> {
+        let key = key::derive_key_for_db(path.as_ref(), secret)?;
+        Self::new(path, Some(&key.sqlcipher_key()))
     }
 
     pub fn new_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         let db = LoginDb::open_in_memory(encryption_key)?;
-        Ok(Self { db, sync: Cell::new(None) })
+        Ok(Self { db, mem_cached_state: Cell::default() })
+    }
+
+    /// Send sync requests with `backend` instead of the default reqwest
+    /// backend, for apps that need to use their own networking stack (and
+    /// for tests, which use a mock server). Forgets the cached storage client
+    /// and global state, which the next sync rebuilds.
+    pub fn set_http_backend(&self, backend: Arc<sync::HttpBackend>) {
+        self.mem_cached_state.replace(sync::MemoryCachedState::with_http_backend(backend));
     }
 
     pub fn list(&self) -> Result<Vec<Login>> {
         self.db.get_all()
     }
 
+    /// Returns the ids of all logins, for callers which only need to know
+    /// what exists (or has been removed) without fetching every record.
+    pub fn list_ids(&self) -> Result<Vec<String>> {
+        self.db.get_all_ids()
+    }
+
+    /// Returns the logins added or changed, locally or by a sync, at or after
+    /// `since` (in milliseconds since the unix epoch), so that backup agents
+    /// can extract logins incrementally. Together with `get_deleted_since`,
+    /// this covers every change since `since`.
+    pub fn get_modified_since(&self, since: i64) -> Result<Vec<Login>> {
+        self.db.get_modified_since(since)
+    }
+
+    /// Returns the ids of the logins deleted, locally or by a sync, at or
+    /// after `since` (in milliseconds since the unix epoch). Logins which
+    /// were added again since are returned by `get_modified_since` instead.
+    /// `wipe_local` isn't counted as deleting anything, and deletions are
+    /// forgotten by `run_maintenance` after 60 days.
+    pub fn get_deleted_since(&self, since: i64) -> Result<Vec<String>> {
+        self.db.get_deleted_since(since)
+    }
+
+    /// Fetch the logins matching `q`. See `LoginQuery` for details.
+    pub fn query(&self, q: &LoginQuery) -> Result<Vec<Login>> {
+        self.db.query(q)
+    }
+
     pub fn get(&self, id: &str) -> Result<Option<Login>> {
         self.db.get_by_id(id)
     }
 
+    /// Returns the existing logins which match `login` on everything but the
+    /// username, so that the app can choose between prompting to update an
+    /// existing login or to save a new one.
+    pub fn potential_dupes_ignoring_username(&self, login: &Login) -> Result<Vec<Login>> {
+        self.db.potential_dupes_ignoring_username(login)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         self.db.touch(id)
     }
@@ -59,6 +125,13 @@ impl PasswordEngine {
         self.db.wipe()
     }
 
+    /// Deletes every login without syncing the deletions, so that the next
+    /// sync downloads the server's logins again. Unlike `wipe`, this doesn't
+    /// affect other devices.
+    pub fn wipe_local(&self) -> Result<()> {
+        self.db.wipe_local()
+    }
+
     pub fn reset(&self) -> Result<()> {
         self.db.reset()
     }
@@ -69,7 +142,44 @@ impl PasswordEngine {
 
     pub fn add(&self, login: Login) -> Result<String> {
         // Just return the record's ID (which we may have generated).
-        self.db.add(login).map(|record| record.id)
+        self.db.add(login).map(|record| record.id.into_string())
+    }
+
+    /// Write every login to `path` in the portable format described in the
+    /// `export` module, so that they can be imported into another profile.
+    /// The file is only encrypted if `key` is provided, so callers must take
+    /// care of a plaintext export, since it contains every password.
+    pub fn export_to_json(&self, path: impl AsRef<Path>, key: Option<&KeyBundle>) -> Result<()> {
+        export::write_to_file(path.as_ref(), self.db.get_all()?, key)
+    }
+
+    /// Add the logins from a file written by `export_to_json`, decrypting it
+    /// with `key` if it was encrypted. Logins which already exist are
+    /// skipped. Returns the number of logins added.
+    pub fn import_from_json(&self, path: impl AsRef<Path>, key: Option<&KeyBundle>) -> Result<usize> {
+        let logins = export::read_from_file(path.as_ref(), key)?;
+        self.db.import_multiple(&logins)
+    }
+
+    /// Removes sync data we no longer need, and old deletions from the log
+    /// `get_deleted_since` reads. See `sql_support::maintenance` for how
+    /// often to run this.
+    pub fn run_maintenance(&self) -> Result<()> {
+        self.db.run_maintenance(util::system_time_ms_i64(SystemTime::now()))
+    }
+
+    /// Returns the number of logins, tombstones and mirror records, the size
+    /// of the database, and when we last synced. See `LoginsDbStats`.
+    pub fn get_db_stats(&self) -> Result<LoginsDbStats> {
+        self.db.get_stats()
+    }
+
+    /// Returns a handle which can cancel a sync in progress from another
+    /// thread, for example when the app goes to the background. The
+    /// interrupted sync fails with an `Interrupted` error, and is marked as
+    /// pending so that `retry_pending` picks it up again.
+    pub fn new_interrupt_handle(&self) -> LoginsInterruptHandle {
+        self.db.new_interrupt_handle()
     }
 
     // This is basiclaly exposed just for sync_pass_sql, but it doesn't seem
@@ -78,107 +188,209 @@ impl PasswordEngine {
         &self.db.db
     }
 
+    /// Sync the passwords collection. If this fails because the sync server
+    /// couldn't be reached, or was cancelled with an interrupt handle (see
+    /// `new_interrupt_handle`), we remember that a sync is pending, so that
+    /// the app can call `retry_pending` later.
+    ///
+    /// Returns the sync's telemetry, and the other engines the app needs to
+    /// reset or wipe. See `SyncOutcome`.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle
-    ) -> result::Result<(), Error> {
-
-        // Note: If `to_ready` (or anything else with a ?) fails below, this
-        // `replace()` means we end up with `state.sync.is_none()`, which means the
-        // next sync will redownload meta/global, crypto/keys, etc. without
-        // needing to. Apparently this is both okay and by design.
-        let maybe_sync_info = self.sync.replace(None).map(Ok);
-
-        // `maybe_sync_info` is None if we haven't called `sync` since
-        // restarting the browser.
-        //
-        // If this is the case we may or may not have a persisted version of
-        // GlobalState stored in the DB (we will iff we've synced before, unless
-        // we've `reset()`, which clears it out).
-        let mut sync_info = maybe_sync_info.unwrap_or_else(|| -> Result<SyncInfo> {
-            info!("First time through since unlock. Trying to load persisted global state.");
-            let state = if let Some(persisted_global_state) = self.db.get_global_state()? {
-                serde_json::from_str::<GlobalState>(&persisted_global_state)
-                .unwrap_or_else(|_| {
-                    // Don't log the error since it might contain sensitive
-                    // info like keys (the JSON does, after all).
-                    error!("Failed to parse GlobalState from JSON! Falling back to default");
-                    // Unstick ourselves by using the default state.
-                    GlobalState::default()
-                })
-            } else {
-                info!("No previously persisted global state, using default");
-                GlobalState::default()
-            };
-            let client = Sync15StorageClient::new(storage_init.clone())?;
-            Ok(SyncInfo {
-                state,
-                client,
-                last_client_init: storage_init.clone(),
-            })
-        })?;
-
-        // If the options passed for initialization of the storage client aren't
-        // the same as the ones we used last time, reinitialize it. (Note that
-        // we could avoid the comparison in the case where we had `None` in
-        // `state.sync` before, but this probably doesn't matter).
-        //
-        // It's a little confusing that we do things this way (transparently
-        // re-initialize the client), but it reduces the size of the API surface
-        // exposed over the FFI, and simplifies the states that the client code
-        // has to consider (as far as it's concerned it just has to pass
-        // `current` values for these things, and not worry about having to
-        // re-initialize the sync state).
-        if storage_init != &sync_info.last_client_init {
-            info!("Detected change in storage client init, updating");
-            sync_info.client = Sync15StorageClient::new(storage_init.clone())?;
-            sync_info.last_client_init = storage_init.clone();
-        }
+    ) -> Result<SyncOutcome> {
+        let mut outcome = SyncOutcome::default();
+        self.sync_with_commands(storage_init, root_sync_key, &[], &mut outcome)?;
+        Ok(outcome)
+    }
 
-        // Advance the state machine to the point where it can perform a full
-        // sync. This may involve uploading meta/global, crypto/keys etc.
-        {
-            // Scope borrow of `sync_info.client`
-            let mut state_machine =
-                sync::SetupStateMachine::for_full_sync(&sync_info.client, &root_sync_key);
-            info!("Advancing state machine to ready (full)");
-            let next_sync_state = state_machine.to_ready(sync_info.state)?;
-            sync_info.state = next_sync_state;
+    /// Like `sync`, but first applies the `wipeEngine` and `resetEngine`
+    /// commands that other clients sent us, and fills in `outcome` whether
+    /// or not the sync succeeds, so that the telemetry for failed syncs
+    /// (including why they failed) can be reported too. Commands for other
+    /// engines end up in `outcome`, for the app to handle.
+    pub fn sync_with_commands(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        commands: &[sync::Command],
+        outcome: &mut SyncOutcome,
+    ) -> Result<()> {
+        let result = self.do_sync(storage_init, root_sync_key, commands, outcome);
+        match &result {
+            Ok(_) => self.db.set_sync_pending(false)?,
+            Err(e) if e.is_network_error() => {
+                info!("Sync failed due to a network error, marking sync as pending");
+                self.db.set_sync_pending(true)?;
+            }
+            Err(e) if e.is_interrupted() => {
+                info!("Sync was interrupted, marking sync as pending");
+                self.db.set_sync_pending(true)?;
+            }
+            Err(_) => {}
         }
+        result
+    }
 
-        // Reset our local state if necessary.
-        if sync_info.state.engines_that_need_local_reset().contains("passwords") {
-            info!("Passwords sync ID changed; engine needs local reset");
-            self.db.reset()?;
-        }
+    /// Returns true if the last sync failed due to a network error or was
+    /// interrupted, and hasn't been successfully retried yet.
+    pub fn has_pending_sync(&self) -> Result<bool> {
+        self.db.is_sync_pending()
+    }
 
-        // Persist the current sync state in the DB.
-        info!("Updating persisted global state");
-        let s = sync_info.state.to_persistable_string();
-        self.db.set_global_state(&s)?;
+    /// Sync again if the previous sync failed due to a network error. Returns
+    /// `None` without syncing if no sync is pending. Local changes made since
+    /// the failed sync are uploaded as well, as they're tracked the same way.
+    pub fn retry_pending(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle
+    ) -> Result<Option<SyncOutcome>> {
+        if !self.has_pending_sync()? {
+            return Ok(None);
+        }
+        info!("Retrying pending sync");
+        self.sync(storage_init, root_sync_key).map(Some)
+    }
 
+    fn do_sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        commands: &[sync::Command],
+        outcome: &mut SyncOutcome,
+    ) -> Result<()> {
+        // Interruptions from before now were meant for an earlier sync.
+        let store = InterruptibleStore { db: &self.db, scope: self.db.begin_interrupt_scope() };
+
+        // `mem_cached_state` is empty if we haven't synced since restarting
+        // the browser (or since `reset()`), in which case we fall back to the
+        // global state persisted in the DB, if any.
+        let mut mem_cached_state = self.mem_cached_state.replace(sync::MemoryCachedState::default());
+        let mut persisted_global_state = self.db.get_global_state()?;
+
+        // The storage client is transparently re-initialized if
+        // `storage_init` differs from last time. This reduces the size of the
+        // API surface exposed over the FFI, and simplifies the states that the
+        // client code has to consider (as far as it's concerned it just has to
+        // pass `current` values for these things).
         info!("Syncing passwords engine!");
-
-        // We don't use `?` here so that we can restore the value of of
-        // `self.sync` even if sync fails.
-        let result = sync::synchronize(
-            &sync_info.client,
-            &sync_info.state,
-            &self.db,
-            "passwords".into(),
-            true
+        let mut telemetry = sync::telemetry::SyncTelemetry::new();
+        let result = sync::sync_multiple(
+            &[&store],
+            commands,
+            &mut persisted_global_state,
+            &mut mem_cached_state,
+            storage_init,
+            root_sync_key,
+            &mut telemetry,
         );
+        outcome.telemetry.sync(telemetry);
 
-        match &result {
-            Ok(()) => info!("Sync was successful!"),
-            Err(e) => warn!("Sync failed! {:?}", e),
+        // Restore our cached state even if the sync failed.
+        self.mem_cached_state.replace(mem_cached_state);
+
+        // Persist the current sync state in the DB, even if syncing the
+        // passwords collection itself failed.
+        if let Some(s) = persisted_global_state {
+            info!("Updating persisted global state");
+            self.db.set_global_state(&s)?;
         }
 
-        // Restore our value of `sync_info` even if the sync failed.
-        self.sync.replace(Some(sync_info));
+        // If the sync failed, and the app interrupted us, that's all it
+        // cares about. A sync that finished before we noticed the interrupt
+        // still succeeded, so we only check when something went wrong.
+        let mut sync_result = match result {
+            Ok(sync_result) => sync_result,
+            Err(e) => {
+                store.scope.err_if_interrupted()?;
+                return Err(e.into());
+            }
+        };
+        match sync_result.engine_results.remove("passwords") {
+            Some(Ok(())) => info!("Sync was successful!"),
+            Some(Err(e)) => {
+                warn!("Sync failed! {:?}", e);
+                store.scope.err_if_interrupted()?;
+                return Err(e.into());
+            }
+            None => info!("Passwords engine is declined, not syncing"),
+        }
+        if sync_result.node_reassigned {
+            info!("Moved to a new storage node; passwords were reset and uploaded again");
+        }
+        // Passwords only show up here if resetting them failed while they
+        // were declined, so we try again. We only sync passwords, so the
+        // other engines are for the app to handle.
+        for name in sync_result.requires_local_reset {
+            if name == "passwords" {
+                self.db.reset()?;
+            } else {
+                info!("{} needs a local reset", name);
+                outcome.requires_local_reset.push(name);
+            }
+        }
+        for name in sync_result.requires_local_wipe {
+            if name == "passwords" {
+                self.db.wipe_local()?;
+            } else {
+                info!("{} needs a local wipe", name);
+                outcome.requires_local_wipe.push(name);
+            }
+        }
+        Ok(())
+    }
+}
 
-        Ok(result?)
+// Wraps the DB for `sync_multiple`, so that a sync stops once it's
+// interrupted. The network requests happen between the calls into the
+// store, so this is where we notice interruptions made during them.
+struct InterruptibleStore<'a> {
+    db: &'a LoginDb,
+    scope: InterruptScope,
+}
+
+impl<'a> sync::Store for InterruptibleStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        sync::Store::collection_name(self.db)
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: sync::IncomingChangeset,
+        telem: &mut sync::telemetry::EngineIncoming,
+    ) -> result::Result<sync::OutgoingChangeset, failure::Error> {
+        self.scope.err_if_interrupted()?;
+        Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: sync::ServerTimestamp,
+        records_synced: &[String],
+    ) -> result::Result<(), failure::Error> {
+        self.scope.err_if_interrupted()?;
+        sync::Store::sync_finished(self.db, new_timestamp, records_synced)
+    }
+
+    fn get_collection_request(&self) -> result::Result<sync::CollectionRequest, failure::Error> {
+        self.scope.err_if_interrupted()?;
+        sync::Store::get_collection_request(self.db)
+    }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        sync::Store::reset(self.db)
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        sync::Store::wipe(self.db)
+    }
+}
+
+impl Maintenance for PasswordEngine {
+    fn run_maintenance(&self) -> result::Result<(), failure::Error> {
+        Ok(PasswordEngine::run_maintenance(self)?)
     }
 }
 
@@ -186,6 +398,7 @@ impl PasswordEngine {
 mod test {
     use super::*;
     use std::time::SystemTime;
+    use tempfile;
     use util;
     // Doesn't check metadata fields
     fn assert_logins_equiv(a: &Login, b: &Login) {
@@ -231,9 +444,9 @@ mod test {
         let a_id = engine.add(a.clone()).expect("added a");
         let b_id = engine.add(b.clone()).expect("added b");
 
-        assert_eq!(a_id, a.id);
+        assert_eq!(a.id, a_id.as_str());
 
-        assert_ne!(b_id, b.id, "Should generate guid when none provided");
+        assert_ne!(b.id, b_id.as_str(), "Should generate guid when none provided");
 
         let a_from_db = engine.get(&a_id)
             .expect("Not to error getting a")
@@ -250,7 +463,7 @@ mod test {
             .expect("b to exist");
 
         assert_logins_equiv(&b_from_db, &Login {
-            id: b_id.clone(),
+            id: b_id.clone().into(),
             .. b.clone()
         });
         assert_ge!(b_from_db.time_created, start_us);
@@ -276,7 +489,7 @@ mod test {
         assert_eq!(list[0], b_from_db);
 
         let now_us = util::system_time_ms_i64(SystemTime::now());
-        let b2 = Login { password: "newpass".into(), id: b_id.clone(), .. b.clone() };
+        let b2 = Login { password: "newpass".into(), id: b_id.clone().into(), .. b.clone() };
 
         engine.update(b2.clone()).expect("update b should work");
 
@@ -292,4 +505,406 @@ mod test {
         // Should be two even though we updated twice
         assert_eq!(b_after_update.times_used, 2);
     }
+
+    #[test]
+    fn test_query() {
+        let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let logins = [
+            ("aaaaaaaaaaaa", "https://www.example.com", "Alice", 3000, 100),
+            ("bbbbbbbbbbbb", "https://accounts.example.com", "bob", 1000, 300),
+            ("cccccccccccc", "https://www.mozilla.org", "alice.smith", 2000, 200),
+        ];
+        for &(id, hostname, username, last_used, changed) in &logins {
+            engine.add(Login {
+                id: id.into(),
+                hostname: hostname.into(),
+                http_realm: Some("realm".into()),
+                username: username.into(),
+                password: "hunter2".into(),
+                .. Login::default()
+            }).expect("add should work");
+            // `add` sets the timestamps to now, so we override them here.
+            engine.conn().execute_named("
+                UPDATE loginsL
+                SET timeLastUsed = :last_used, timePasswordChanged = :changed
+                WHERE guid = :guid",
+                &[(":last_used", &last_used), (":changed", &changed), (":guid", &id)],
+            ).expect("update should work");
+        }
+        let ids = |q: LoginQuery| -> Vec<String> {
+            engine.query(&q).expect("query should work").into_iter().map(|l| l.id.into_string()).collect()
+        };
+
+        assert_eq!(ids(LoginQuery::new()),
+                   vec!["bbbbbbbbbbbb", "aaaaaaaaaaaa", "cccccccccccc"]);
+        assert_eq!(ids(LoginQuery::new().with_hostname("example.com".to_string())),
+                   vec!["bbbbbbbbbbbb", "aaaaaaaaaaaa"]);
+        assert_eq!(ids(LoginQuery::new().with_username("ALICE".to_string())),
+                   vec!["aaaaaaaaaaaa", "cccccccccccc"]);
+        assert_eq!(ids(LoginQuery::new().with_modified_since(200).with_sort(LoginSort::LastModified)),
+                   vec!["bbbbbbbbbbbb", "cccccccccccc"]);
+        assert_eq!(ids(LoginQuery::new().with_sort(LoginSort::LastUsed)),
+                   vec!["aaaaaaaaaaaa", "cccccccccccc", "bbbbbbbbbbbb"]);
+        assert_eq!(ids(LoginQuery::new().with_sort(LoginSort::LastUsed).with_limit(1).with_offset(1)),
+                   vec!["cccccccccccc"]);
+        assert_eq!(ids(LoginQuery::new().with_offset(3)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_potential_dupes_ignoring_username() {
+        let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let login = Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "alice".into(),
+            password: "hunter2".into(),
+            .. Login::default()
+        };
+        let id = engine.add(login.clone()).unwrap();
+        engine.add(Login {
+            form_submit_url: None,
+            http_realm: Some("realm".into()),
+            .. login.clone()
+        }).unwrap();
+        engine.add(Login {
+            hostname: "https://www.mozilla.org".into(),
+            .. login.clone()
+        }).unwrap();
+
+        let dupes = engine.potential_dupes_ignoring_username(&Login {
+            username: "bob".into(),
+            password: "different".into(),
+            form_submit_url: Some("https://www.example.com/other-form".into()),
+            .. login.clone()
+        }).unwrap();
+        assert_eq!(dupes.len(), 1);
+        assert_eq!(dupes[0].id, id.as_str());
+
+        engine.delete(&id).unwrap();
+        assert!(engine.potential_dupes_ignoring_username(&login).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retry_pending() {
+        let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let init = Sync15StorageClientInit {
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            // Never contacted, since no sync is pending.
+            tokenserver_url: "https://token.example.com".parse().unwrap(),
+            storage_url: None,
+            allow_insecure_http: false,
+            extra_root_certificates: Vec::new(),
+        };
+        let key = KeyBundle::new_random().unwrap();
+
+        assert!(!engine.has_pending_sync().unwrap());
+        assert!(engine.retry_pending(&init, &key).unwrap().is_none());
+
+        engine.db.set_sync_pending(true).unwrap();
+        assert!(engine.has_pending_sync().unwrap());
+        engine.db.set_sync_pending(false).unwrap();
+        assert!(!engine.has_pending_sync().unwrap());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let login = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        source.add(login.clone()).unwrap();
+        let exported = source.get(&login.id).unwrap().unwrap();
+
+        let key = KeyBundle::new_random().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.json");
+        source.export_to_json(&path, Some(&key)).unwrap();
+
+        let dest = PasswordEngine::new_in_memory(Some("other-secret")).unwrap();
+        assert!(dest.import_from_json(&path, None).is_err());
+        assert_eq!(dest.import_from_json(&path, Some(&key)).unwrap(), 1);
+        let imported = dest.get(&login.id).unwrap().unwrap();
+        assert_logins_equiv(&imported, &exported);
+        assert_eq!(imported.time_created, exported.time_created);
+
+        // Importing again shouldn't duplicate anything.
+        assert_eq!(dest.import_from_json(&path, Some(&key)).unwrap(), 0);
+        assert_eq!(dest.list().unwrap().len(), 1);
+    }
+
+    fn incoming(logins: Vec<Login>, ts: f64) -> sync::IncomingChangeset {
+        let mut inbound = sync::IncomingChangeset::new("passwords".into(), sync::ServerTimestamp(ts));
+        for login in logins {
+            inbound.changes.push((sync::Payload::from_record(login).unwrap(), sync::ServerTimestamp(ts)));
+        }
+        inbound
+    }
+
+    #[test]
+    fn test_sync_merges_fields() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let login = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            time_created: 1000,
+            time_password_changed: 1000,
+            time_last_used: 1000,
+            times_used: 1,
+            .. Login::default()
+        };
+        let outgoing = engine.db.apply_incoming(
+            incoming(vec![login.clone()], 1.0), &mut Default::default()).unwrap();
+        assert!(outgoing.changes.is_empty());
+
+        // We change the username, while another device changes the password.
+        engine.update(Login { username: "coolperson22".into(), .. login.clone() }).unwrap();
+        let upstream = Login {
+            password: "n3wp4ssw0rd".into(),
+            time_password_changed: 2000,
+            .. login.clone()
+        };
+        let outgoing = engine.db.apply_incoming(incoming(vec![upstream], 2.0), &mut Default::default()).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        let merged: Login = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(merged.username, "coolperson22");
+        assert_eq!(merged.password, "n3wp4ssw0rd");
+        assert_eq!(merged.time_password_changed, 2000);
+
+        // Changes made while we're uploading aren't lost when the upload finishes...
+        engine.update(Login {
+            username: "coolperson23".into(),
+            password: "n3wp4ssw0rd".into(),
+            .. login.clone()
+        }).unwrap();
+        engine.db.sync_finished(sync::ServerTimestamp(3.0), &[login.id.to_string()]).unwrap();
+        let outgoing = engine.db.fetch_outgoing(sync::ServerTimestamp(3.0)).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(engine.get(&login.id).unwrap().unwrap().username, "coolperson23");
+
+        // ...and are uploaded next time.
+        engine.db.sync_finished(sync::ServerTimestamp(4.0), &[login.id.to_string()]).unwrap();
+        assert!(engine.db.fetch_outgoing(sync::ServerTimestamp(4.0)).unwrap().changes.is_empty());
+        let synced = engine.get(&login.id).unwrap().unwrap();
+        assert_eq!(synced.username, "coolperson23");
+        assert_eq!(synced.password, "n3wp4ssw0rd");
+    }
+
+    #[test]
+    fn test_modified_and_deleted_since() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let start_ms = util::system_time_ms_i64(SystemTime::now());
+        let local = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        engine.add(local.clone()).unwrap();
+        // Synced records use the server's timestamp.
+        let synced = Login {
+            id: "bbbbbbbbbbbb".into(),
+            hostname: "https://www.example.org".into(),
+            time_created: 1000,
+            time_password_changed: 1000,
+            .. local.clone()
+        };
+        engine.db.apply_incoming(incoming(vec![synced.clone()], 1.0), &mut Default::default()).unwrap();
+
+        let ids = |logins: Vec<Login>| logins.into_iter().map(|l| l.id).collect::<Vec<_>>();
+        assert_eq!(ids(engine.get_modified_since(start_ms).unwrap()), vec![local.id.clone()]);
+        assert_eq!(engine.get_modified_since(0).unwrap().len(), 2);
+        assert!(engine.get_deleted_since(0).unwrap().is_empty());
+
+        // Deleting a login that was never synced doesn't leave a tombstone,
+        // but we should still report it, and incoming deletions too.
+        assert!(engine.delete(&local.id).unwrap());
+        assert!(!engine.delete("cccccccccccc").unwrap());
+        let mut tombstone = sync::IncomingChangeset::new("passwords".into(), sync::ServerTimestamp(2.0));
+        tombstone.changes.push((sync::Payload::new_tombstone(synced.id.to_string()), sync::ServerTimestamp(2.0)));
+        engine.db.apply_incoming(tombstone, &mut Default::default()).unwrap();
+        assert!(engine.list().unwrap().is_empty());
+        assert!(engine.get_modified_since(0).unwrap().is_empty());
+        assert_eq!(engine.get_deleted_since(start_ms).unwrap(), vec![local.id.to_string(), synced.id.to_string()]);
+        assert!(engine.get_deleted_since(start_ms + 60 * 60 * 1000).unwrap().is_empty());
+
+        // Logins added again aren't deleted anymore.
+        engine.add(local.clone()).unwrap();
+        assert_eq!(engine.get_deleted_since(start_ms).unwrap(), vec![synced.id.to_string()]);
+    }
+
+    #[test]
+    fn test_list_ids() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let local = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        engine.add(local.clone()).unwrap();
+        engine.add(Login { id: "bbbbbbbbbbbb".into(), .. local.clone() }).unwrap();
+        let synced = Login {
+            id: "cccccccccccc".into(),
+            hostname: "https://www.example.org".into(),
+            time_created: 1000,
+            time_password_changed: 1000,
+            .. local.clone()
+        };
+        engine.db.apply_incoming(incoming(vec![synced.clone()], 1.0), &mut Default::default()).unwrap();
+
+        let sorted_ids = || {
+            let mut ids = engine.list_ids().unwrap();
+            ids.sort();
+            ids
+        };
+        assert_eq!(sorted_ids(), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+
+        // Changing a synced login shouldn't list it twice, and deleted
+        // logins aren't listed at all.
+        engine.update(Login { username: "coolperson22".into(), .. synced.clone() }).unwrap();
+        assert!(engine.delete("bbbbbbbbbbbb").unwrap());
+        assert_eq!(sorted_ids(), vec!["aaaaaaaaaaaa", "cccccccccccc"]);
+        assert!(engine.delete("cccccccccccc").unwrap());
+        assert_eq!(sorted_ids(), vec!["aaaaaaaaaaaa"]);
+    }
+
+    #[test]
+    fn test_run_maintenance() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let orphan = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        let changed = Login {
+            id: "bbbbbbbbbbbb".into(),
+            hostname: "https://www.example.org".into(),
+            .. orphan.clone()
+        };
+        engine.add(orphan.clone()).unwrap();
+        engine.add(changed.clone()).unwrap();
+        engine.db.sync_finished(sync::ServerTimestamp(1.0), &[orphan.id.to_string(), changed.id.to_string()]).unwrap();
+        engine.update(Login { password: "n3wp4ssw0rd".into(), .. changed.clone() }).unwrap();
+        engine.conn().execute("UPDATE loginsM SET is_overridden = 1 WHERE guid = 'aaaaaaaaaaaa'", &[]).unwrap();
+        engine.conn().execute_named(
+            "INSERT INTO loginsDeleted(guid, time_deleted) VALUES ('cccccccccccc', :old), ('dddddddddddd', :now)",
+            &[(":old", &(now_ms - 90 * 24 * 60 * 60 * 1000)), (":now", &now_ms)]).unwrap();
+
+        engine.db.run_maintenance(now_ms).unwrap();
+        let mirror: i64 = engine.conn().query_row("SELECT COUNT(*) FROM loginsM", &[], |row| row.get(0)).unwrap();
+        assert_eq!(mirror, 1);
+        assert_eq!(engine.get(&changed.id).unwrap().unwrap().password, "n3wp4ssw0rd");
+        assert_eq!(engine.get_deleted_since(0).unwrap(), vec!["dddddddddddd".to_string()]);
+    }
+
+    #[test]
+    fn test_db_stats() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let stats = engine.get_db_stats().unwrap();
+        assert_eq!(stats.num_logins, 0);
+        assert_eq!(stats.last_sync, None);
+        assert!(stats.db_size_bytes > 0);
+
+        let synced = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        let local = Login {
+            id: "bbbbbbbbbbbb".into(),
+            hostname: "https://www.example.org".into(),
+            .. synced.clone()
+        };
+        engine.add(synced.clone()).unwrap();
+        engine.add(local.clone()).unwrap();
+        engine.db.sync_finished(sync::ServerTimestamp(1.5), &[synced.id.to_string()]).unwrap();
+        assert!(engine.delete(&synced.id).unwrap());
+
+        let stats = engine.get_db_stats().unwrap();
+        assert_eq!(stats.num_logins, 1);
+        assert_eq!(stats.num_tombstones, 1);
+        assert_eq!(stats.num_mirror_records, 1);
+        assert_eq!(stats.last_sync, Some(1500));
+    }
+
+    #[test]
+    fn test_wipe_local() {
+        use sync::Store;
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let synced = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        engine.add(synced.clone()).unwrap();
+        engine.db.sync_finished(sync::ServerTimestamp(1.0), &[synced.id.to_string()]).unwrap();
+        engine.add(Login {
+            id: "bbbbbbbbbbbb".into(),
+            hostname: "https://www.example.org".into(),
+            .. synced.clone()
+        }).unwrap();
+
+        // Unlike `wipe`, a local wipe doesn't leave anything to upload.
+        engine.db.wipe().unwrap();
+        assert_eq!(engine.db.fetch_outgoing(sync::ServerTimestamp(2.0)).unwrap().changes.len(), 1);
+        Store::wipe(&engine.db).unwrap();
+        assert!(engine.list().unwrap().is_empty());
+        assert!(engine.db.fetch_outgoing(sync::ServerTimestamp(2.0)).unwrap().changes.is_empty());
+        assert_eq!(engine.db.get_collection_request().unwrap(),
+                   sync::CollectionRequest::new("passwords").full().newer_than(sync::ServerTimestamp(0.0)));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use sync::Store;
+        use error_support::{self, GetErrorCode};
+        let engine = PasswordEngine::new_in_memory(None).unwrap();
+        let login = Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        let handle = engine.new_interrupt_handle();
+
+        // Interrupting before a sync starts doesn't affect it.
+        handle.interrupt();
+        let store = InterruptibleStore { db: &engine.db, scope: engine.db.begin_interrupt_scope() };
+        assert!(!store.scope.was_interrupted());
+        store.get_collection_request().unwrap();
+
+        // Once it's interrupted, the sync stops before applying anything.
+        handle.interrupt();
+        assert!(store.get_collection_request().is_err());
+        let err = engine.db.do_apply_incoming(
+            incoming(vec![login.clone()], 1.0), &mut Default::default(), &store.scope).unwrap_err();
+        assert!(err.is_interrupted());
+        assert!(engine.list().unwrap().is_empty());
+        assert!(store.sync_finished(sync::ServerTimestamp(1.0), &[]).is_err());
+        assert_eq!(store.scope.err_if_interrupted().unwrap_err().error_code(), error_support::INTERRUPTED);
+    }
 }
@@ -5,13 +5,31 @@
 use failure::{Fail, Context, Backtrace};
 use std::{self, fmt};
 use std::boxed::Box;
+use openssl;
 use rusqlite;
 use serde_json;
 use sync;
 use url;
+use error_support::{self, GetErrorCode};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Codes for errors specific to logins, allocated from
+/// `error_support::LOGINS_BASE`. See `GetErrorCode`.
+pub mod error_codes {
+    use error_support::LOGINS_BASE;
+
+    /// Returned from an `update()` call where the record ID did not exist.
+    pub const NO_SUCH_RECORD: i32 = LOGINS_BASE + 1;
+
+    /// Returned from an `add()` call that was provided an ID, where the ID
+    /// already existed.
+    pub const DUPLICATE_GUID: i32 = LOGINS_BASE + 2;
+
+    /// Attempted to insert or update a record so that it is invalid
+    pub const INVALID_LOGIN: i32 = LOGINS_BASE + 3;
+}
+
 // Backported part of the (someday real) failure 1.x API, basically equivalent
 // to error_chain's `bail!` (We don't call it that because `failure` has a
 // `bail` macro with different semantics)
@@ -48,6 +66,48 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         &*self.0.get_context()
     }
+
+    /// Returns true if this error means we couldn't reach the sync server.
+    pub fn is_network_error(&self) -> bool {
+        match self.kind() {
+            ErrorKind::SyncAdapterError(e) => e.is_network_error(),
+            _ => false,
+        }
+    }
+
+    /// Returns true if this error means the operation was cancelled with a
+    /// `LoginsInterruptHandle`.
+    pub fn is_interrupted(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Interrupted => true,
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) =>
+                err.code == rusqlite::ErrorCode::OperationInterrupted,
+            _ => false,
+        }
+    }
+}
+
+impl GetErrorCode for Error {
+    fn error_code(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::SyncAdapterError(e) => e.error_code(),
+            ErrorKind::DuplicateGuid(_) => error_codes::DUPLICATE_GUID,
+            ErrorKind::NoSuchRecord(_) => error_codes::NO_SUCH_RECORD,
+            ErrorKind::InvalidLogin(_) => error_codes::INVALID_LOGIN,
+            ErrorKind::Interrupted => error_support::INTERRUPTED,
+            // We can't destructure `err` without bringing in the libsqlite3_sys crate
+            // (and I'd really rather not) so we can't put this in the match.
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::NotADatabase => {
+                error_support::INVALID_KEY
+            }
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == rusqlite::ErrorCode::OperationInterrupted => {
+                error_support::INTERRUPTED
+            }
+            _ => error_support::UNEXPECTED,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -90,6 +150,21 @@ pub enum ErrorKind {
 
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
+
+    #[fail(display = "Invalid export file: {}", _0)]
+    InvalidExportFile(String),
+
+    #[fail(display = "Invalid key salt file: {}", _0)]
+    InvalidKeyFile(String),
+
+    #[fail(display = "Crypto error: {}", _0)]
+    OpensslError(#[fail(cause)] openssl::error::ErrorStack),
+
+    #[fail(display = "The operation was interrupted")]
+    Interrupted,
 }
 
 macro_rules! impl_from_error {
@@ -115,6 +190,8 @@ impl_from_error! {
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
+    (IoError, std::io::Error),
+    (OpensslError, openssl::error::ErrorStack),
     (InvalidLogin, InvalidLogin)
 }
 
@@ -128,5 +205,7 @@ pub enum InvalidLogin {
     BothTargets,
     #[fail(display = "Neither `formSubmitUrl` and `httpRealm` are present")]
     NoTarget,
+    #[fail(display = "The login's id is not a valid sync guid")]
+    InvalidGuid,
 }
 
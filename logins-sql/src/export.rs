@@ -0,0 +1,215 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A portable format for moving logins between profiles (or out of the
+//! product entirely).
+//!
+//! An export file is a JSON object, with a `version` (currently always 1),
+//! and a `logins` array. Each login has the same fields as in the FFI (see
+//! `Login`), including its metadata:
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "logins": [{
+//!         "id": "aaaaaaaaaaaa",
+//!         "hostname": "https://www.example.com",
+//!         "formSubmitURL": "https://www.example.com/login",
+//!         "username": "coolperson21",
+//!         "password": "p4ssw0rd",
+//!         "usernameField": "user_input",
+//!         "passwordField": "pass_input",
+//!         "timeCreated": 1538000000000,
+//!         "timePasswordChanged": 1538000000000,
+//!         "timeLastUsed": 1538000000000,
+//!         "timesUsed": 1
+//!     }]
+//! }
+//! ```
+//!
+//! The export can optionally be encrypted with a `KeyBundle`, in which case
+//! `logins` is replaced by `encrypted`, which holds the `logins` array
+//! encrypted the same way as a Sync record's payload (that is, an object with
+//! `IV`, `hmac`, and `ciphertext` fields).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde_json;
+use sync::{EncryptedBso, KeyBundle, Payload, ServerTimestamp};
+use sync::bso_record::EncryptedPayload;
+
+use error::*;
+use login::Login;
+
+const EXPORT_VERSION: u32 = 1;
+
+// The ID and collection of the BSO we wrap encrypted exports in, which are
+// never seen outside this file.
+const EXPORT_BSO_ID: &str = "logins";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportFile {
+    version: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logins: Option<Vec<Login>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted: Option<EncryptedPayload>,
+}
+
+// What we encrypt. The `id` is required to make a `Payload`.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedLogins {
+    id: String,
+    logins: Vec<Login>,
+}
+
+pub(crate) fn to_json_string(logins: Vec<Login>, key: Option<&KeyBundle>) -> Result<String> {
+    let file = match key {
+        Some(key) => {
+            let record = EncryptedLogins { id: EXPORT_BSO_ID.into(), logins };
+            let bso = Payload::from_record(record)?.into_bso(EXPORT_BSO_ID.into()).encrypt(key)?;
+            ExportFile { version: EXPORT_VERSION, logins: None, encrypted: Some(bso.payload) }
+        }
+        None => ExportFile { version: EXPORT_VERSION, logins: Some(logins), encrypted: None },
+    };
+    Ok(serde_json::to_string(&file)?)
+}
+
+pub(crate) fn from_json_str(data: &str, key: Option<&KeyBundle>) -> Result<Vec<Login>> {
+    let file: ExportFile = serde_json::from_str(data)?;
+    if file.version != EXPORT_VERSION {
+        throw!(ErrorKind::InvalidExportFile(format!("Unsupported version {}", file.version)));
+    }
+    match (file.logins, file.encrypted, key) {
+        (Some(logins), None, _) => Ok(logins),
+        (None, Some(payload), Some(key)) => {
+            let bso = EncryptedBso {
+                id: EXPORT_BSO_ID.into(),
+                collection: EXPORT_BSO_ID.into(),
+                modified: ServerTimestamp::default(),
+                sortindex: None,
+                ttl: None,
+                payload,
+            };
+            Ok(bso.decrypt_as::<EncryptedLogins>(key)?.payload.logins)
+        }
+        (None, Some(_), None) => Err(ErrorKind::InvalidExportFile(
+            "The file is encrypted, but no key was provided".into()).into()),
+        _ => Err(ErrorKind::InvalidExportFile(
+            "Expected exactly one of `logins` or `encrypted`".into()).into()),
+    }
+}
+
+// Opens `path` for writing, replacing what's there, so that only the user can
+// read it. Even encrypted exports are only as strong as the key they're
+// encrypted with.
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<File> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // The mode only applies to new files, so an existing file might still be
+    // readable by others.
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+pub(crate) fn write_to_file(path: &Path, logins: Vec<Login>, key: Option<&KeyBundle>) -> Result<()> {
+    let data = to_json_string(logins, key)?;
+    let mut file = create_private_file(path)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub(crate) fn read_from_file(path: &Path, key: Option<&KeyBundle>) -> Result<Vec<Login>> {
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+    from_json_str(&data, key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile;
+
+    fn logins() -> Vec<Login> {
+        vec![Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "coolperson21".into(),
+            password: "p4ssw0rd".into(),
+            time_created: 1000,
+            times_used: 3,
+            .. Login::default()
+        }]
+    }
+
+    #[test]
+    fn test_plaintext_roundtrip() {
+        let data = to_json_string(logins(), None).unwrap();
+        assert!(data.contains("p4ssw0rd"));
+        assert_eq!(from_json_str(&data, None).unwrap(), logins());
+        // Providing a key for a plaintext file is fine.
+        let key = KeyBundle::new_random().unwrap();
+        assert_eq!(from_json_str(&data, Some(&key)).unwrap(), logins());
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let key = KeyBundle::new_random().unwrap();
+        let data = to_json_string(logins(), Some(&key)).unwrap();
+        assert!(!data.contains("p4ssw0rd"));
+        assert_eq!(from_json_str(&data, Some(&key)).unwrap(), logins());
+
+        assert!(from_json_str(&data, None).is_err());
+        let wrong_key = KeyBundle::new_random().unwrap();
+        assert!(from_json_str(&data, Some(&wrong_key)).is_err());
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.json");
+        write_to_file(&path, logins(), None).unwrap();
+        assert_eq!(read_from_file(&path, None).unwrap(), logins());
+
+        // Writing again replaces the file, rather than appending to it.
+        write_to_file(&path, Vec::new(), None).unwrap();
+        assert!(read_from_file(&path, None).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_permissions() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.json");
+        write_to_file(&path, logins(), None).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Existing files are made private, too.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_to_file(&path, logins(), None).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(from_json_str(r#"{"version": 2, "logins": []}"#, None).is_err());
+        assert!(from_json_str(r#"{"version": 1}"#, None).is_err());
+        assert!(from_json_str("[]", None).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Deriving the SQLCipher key for a logins database from a user secret (a
+//! passphrase, or a secret kept in the platform's keystore), so that apps
+//! don't each need to come up with their own scheme.
+//!
+//! We use scrypt, with a random salt for each database. The salt and the
+//! scrypt parameters aren't secret, and are stored next to the database in
+//! a small JSON file (the database's path with `.salt` appended):
+//!
+//! ```json
+//! {"version": 1, "salt": [12, 34, ...], "log_n": 14, "r": 8, "p": 1}
+//! ```
+//!
+//! The database can't be opened without this file, so apps which back up
+//! the database need to back it up as well. Storing the parameters means we
+//! can make them stronger for new databases without breaking existing ones.
+//!
+//! Databases which were encrypted with the secret itself, as `PRAGMA key`
+//! does with a string (which SQLCipher runs through its own PBKDF2), can be
+//! moved over to a derived key with `migrate_from_raw_key`.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use openssl;
+use serde_json;
+
+use db::LoginDb;
+use error::*;
+
+const SALT_FILE_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Parameters for scrypt. See `KeyParams::default` for the ones we use.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KeyParams {
+    /// The base 2 log of the CPU/memory cost `N`.
+    pub log_n: u8,
+    /// The block size.
+    pub r: u32,
+    /// The parallelization factor.
+    pub p: u32,
+}
+
+impl Default for KeyParams {
+    /// `N = 2^14, r = 8, p = 1`, the parameters recommended for interactive
+    /// logins, which take 16 MiB of memory and about 100ms on a mid-range
+    /// phone. Deriving the key is only done when opening the database, so
+    /// this is the time it takes to unlock the user's logins.
+    fn default() -> Self {
+        KeyParams { log_n: 14, r: 8, p: 1 }
+    }
+}
+
+impl KeyParams {
+    // The memory scrypt needs, with room to spare. OpenSSL refuses to use
+    // more than 32 MiB unless we tell it otherwise.
+    fn max_memory(&self) -> u64 {
+        2 * 128 * u64::from(self.r) * (u64::from(self.p) + (1u64 << self.log_n))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SaltFile {
+    version: u32,
+    salt: Vec<u8>,
+    #[serde(flatten)]
+    params: KeyParams,
+}
+
+/// A key derived by `derive_key`. It's only printed as `DerivedKey(..)`, so
+/// that it doesn't end up in logs.
+#[derive(Clone, PartialEq)]
+pub struct DerivedKey([u8; KEY_LEN]);
+
+impl DerivedKey {
+    /// Returns the key in the form `PRAGMA key` expects for a raw key
+    /// (`x'...'`), which is what `PasswordEngine::new` and `LoginDb::open`
+    /// should be given. SQLCipher uses raw keys as they are, rather than
+    /// running them through PBKDF2 again.
+    pub fn sqlcipher_key(&self) -> String {
+        let hex = self.0.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        format!("x'{}'", hex)
+    }
+}
+
+impl fmt::Debug for DerivedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DerivedKey(..)")
+    }
+}
+
+/// Derives a key from `secret` and `salt`, using scrypt with `params`.
+pub fn derive_key(secret: &str, salt: &[u8], params: &KeyParams) -> Result<DerivedKey> {
+    let mut key = [0u8; KEY_LEN];
+    openssl::pkcs5::scrypt(
+        secret.as_bytes(),
+        salt,
+        1u64 << params.log_n,
+        u64::from(params.r),
+        u64::from(params.p),
+        params.max_memory(),
+        &mut key,
+    )?;
+    Ok(DerivedKey(key))
+}
+
+/// Derives the key for the database at `db_path` from `secret`, using the
+/// salt and parameters stored next to it, which are created (with the
+/// default parameters) if this is a new database.
+pub fn derive_key_for_db(db_path: impl AsRef<Path>, secret: &str) -> Result<DerivedKey> {
+    derive_key_for_db_with_params(db_path.as_ref(), secret, &KeyParams::default())
+}
+
+/// Re-encrypts the database at `db_path`, which was opened with `old_key`
+/// until now, with a key derived from `secret`, and returns the new key.
+/// `old_key` is usually the same as `secret`, for apps which passed their
+/// secret straight to `PasswordEngine::new`. Fails without changing anything
+/// if `old_key` is wrong.
+pub fn migrate_from_raw_key(db_path: impl AsRef<Path>, old_key: &str, secret: &str) -> Result<DerivedKey> {
+    migrate_from_raw_key_with_params(db_path.as_ref(), old_key, secret, &KeyParams::default())
+}
+
+fn derive_key_for_db_with_params(db_path: &Path, secret: &str, params: &KeyParams) -> Result<DerivedKey> {
+    let file = load_or_create_salt_file(&salt_path(db_path), params)?;
+    derive_key(secret, &file.salt, &file.params)
+}
+
+fn migrate_from_raw_key_with_params(
+    db_path: &Path,
+    old_key: &str,
+    secret: &str,
+    params: &KeyParams,
+) -> Result<DerivedKey> {
+    // Opening the database checks `old_key` before we create the salt file,
+    // so that a failed migration doesn't leave one behind.
+    let db = LoginDb::open(db_path, Some(old_key))?;
+    let key = derive_key_for_db_with_params(db_path, secret, params)?;
+    db.rekey(&key.sqlcipher_key())?;
+    info!("Migrated logins database to a derived key");
+    Ok(key)
+}
+
+fn salt_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".salt");
+    PathBuf::from(path)
+}
+
+fn load_or_create_salt_file(path: &Path, params: &KeyParams) -> Result<SaltFile> {
+    if let Some(salt_file) = load_salt_file(path)? {
+        return Ok(salt_file);
+    }
+    let mut salt = vec![0u8; SALT_LEN];
+    openssl::rand::rand_bytes(&mut salt)?;
+    let salt_file = SaltFile { version: SALT_FILE_VERSION, salt, params: *params };
+    match write_salt_file(path, serde_json::to_string(&salt_file)?.as_bytes()) {
+        Ok(()) => Ok(salt_file),
+        // Someone else created it since we looked, so we use theirs.
+        Err(ref e) if e.kind() == IoErrorKind::AlreadyExists => {
+            load_salt_file(path)?.ok_or_else(|| ErrorKind::InvalidKeyFile(
+                "Salt file disappeared after it was created".into()).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn load_salt_file(path: &Path) -> Result<Option<SaltFile>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = String::new();
+    file.read_to_string(&mut data)?;
+    let salt_file: SaltFile = serde_json::from_str(&data)?;
+    if salt_file.version != SALT_FILE_VERSION || salt_file.salt.len() != SALT_LEN {
+        throw!(ErrorKind::InvalidKeyFile(format!(
+            "Unsupported version {} or salt length {}",
+            salt_file.version, salt_file.salt.len())));
+    }
+    Ok(Some(salt_file))
+}
+
+// Writes `data` to `path`, failing with `AlreadyExists` if there's already a
+// file there, so that we never replace a salt which is in use. The data is
+// written and synced to a temporary file first, then linked into place, so
+// that a crash can't leave a truncated salt file behind.
+fn write_salt_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut suffix = [0u8; 8];
+    openssl::rand::rand_bytes(&mut suffix).map_err(|e| io::Error::new(IoErrorKind::Other, e))?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp-{}", suffix.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = OpenOptions::new().write(true).create_new(true).open(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        // Unlike renaming, linking fails if `path` exists.
+        .and_then(|()| fs::hard_link(&tmp_path, path));
+    if let Err(e) = fs::remove_file(&tmp_path) {
+        if e.kind() != IoErrorKind::NotFound {
+            warn!("Failed to remove temporary salt file {:?}: {}", tmp_path, e);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    // Much weaker than the defaults, so the tests are quick.
+    const TEST_PARAMS: KeyParams = KeyParams { log_n: 4, r: 8, p: 1 };
+
+    #[test]
+    fn test_derive_key() {
+        let key = derive_key("secret", &[1; SALT_LEN], &TEST_PARAMS).unwrap();
+        assert_eq!(key, derive_key("secret", &[1; SALT_LEN], &TEST_PARAMS).unwrap());
+        assert_ne!(key, derive_key("secret", &[2; SALT_LEN], &TEST_PARAMS).unwrap());
+        assert_ne!(key, derive_key("other", &[1; SALT_LEN], &TEST_PARAMS).unwrap());
+        let sqlcipher_key = key.sqlcipher_key();
+        assert!(sqlcipher_key.starts_with("x'") && sqlcipher_key.ends_with("'"));
+        assert_eq!(sqlcipher_key.len(), KEY_LEN * 2 + 3);
+        assert_eq!(format!("{:?}", key), "DerivedKey(..)");
+    }
+
+    #[test]
+    fn test_derive_key_for_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let key = derive_key_for_db_with_params(&path, "secret", &TEST_PARAMS).unwrap();
+        assert!(salt_path(&path).exists());
+        // The stored parameters win over the ones we're given.
+        let again = derive_key_for_db_with_params(&path, "secret", &KeyParams::default()).unwrap();
+        assert_eq!(key, again);
+
+        let db = LoginDb::open(&path, Some(&key.sqlcipher_key())).unwrap();
+        drop(db);
+        assert!(LoginDb::open(&path, Some("secret")).is_err());
+    }
+
+    #[test]
+    fn test_write_salt_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.sqlite.salt");
+        write_salt_file(&path, b"first").unwrap();
+        let err = write_salt_file(&path, b"second").unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        // The temporary files are gone, whether or not we linked them.
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![path.file_name().unwrap().to_owned()]);
+    }
+
+    #[test]
+    fn test_migrate_from_raw_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open(&path, Some("secret")).unwrap();
+
+        assert!(migrate_from_raw_key_with_params(&path, "wrong", "secret", &TEST_PARAMS).is_err());
+        assert!(!salt_path(&path).exists());
+
+        let key = migrate_from_raw_key_with_params(&path, "secret", "secret", &TEST_PARAMS).unwrap();
+        assert!(LoginDb::open(&path, Some("secret")).is_err());
+        LoginDb::open(&path, Some(&key.sqlcipher_key())).unwrap();
+        assert_eq!(derive_key_for_db(&path, "secret").unwrap(), key);
+    }
+}
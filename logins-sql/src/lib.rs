@@ -9,6 +9,8 @@ extern crate log;
 
 #[cfg(test)]
 extern crate env_logger;
+#[cfg(test)]
+extern crate tempfile;
 
 #[macro_use]
 extern crate lazy_static;
@@ -24,6 +26,8 @@ extern crate more_asserts;
 
 extern crate url;
 
+extern crate openssl;
+
 extern crate rusqlite;
 
 extern crate serde;
@@ -33,6 +37,8 @@ extern crate serde_json;
 extern crate serde_derive;
 
 extern crate sql_support;
+extern crate types_support;
+extern crate error_support;
 
 #[cfg(feature = "ffi")]
 #[macro_use]
@@ -41,11 +47,14 @@ extern crate ffi_support;
 #[macro_use]
 mod error;
 mod login;
+mod query;
 
 pub mod schema;
 mod util;
 mod db;
 mod engine;
+mod export;
+pub mod key;
 mod update_plan;
 
 #[cfg(feature = "ffi")]
@@ -53,7 +62,10 @@ mod ffi;
 
 pub use error::*;
 pub use login::*;
+pub use query::*;
 pub use engine::*;
+pub use db::{LoginsInterruptHandle, LoginsDbStats};
+pub use types_support::Guid;
 
 
 
@@ -7,12 +7,13 @@ use rusqlite::Row;
 use util;
 use std::time::{self, SystemTime};
 use error::*;
+use types_support::Guid;
 
 #[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Login {
-    // TODO: consider `#[serde(rename = "id")] pub guid: String` to avoid confusion
-    pub id: String,
+    // TODO: consider `#[serde(rename = "id")] pub guid: Guid` to avoid confusion
+    pub id: Guid,
 
     pub hostname: String,
 
@@ -58,7 +59,7 @@ fn string_or_default(row: &Row, col: &str) -> Result<String> {
 
 impl Login {
     #[inline]
-    pub fn guid(&self) -> &String {
+    pub fn guid(&self) -> &Guid {
         &self.id
     }
 
@@ -83,6 +84,12 @@ impl Login {
         if self.form_submit_url.is_none() && self.http_realm.is_none() {
             throw!(InvalidLogin::NoTarget);
         }
+
+        // An empty id means one will be generated for us when the login is
+        // added, but anything else must be something the server will accept.
+        if !self.id.is_empty() && !self.id.is_valid_for_sync_server() {
+            throw!(InvalidLogin::InvalidGuid);
+        }
         Ok(())
     }
 
@@ -223,7 +230,7 @@ impl_login!(MirrorLogin {
 
 // Stores data needed to do a 3-way merge
 pub(crate) struct SyncLoginData {
-    pub guid: String,
+    pub guid: Guid,
     pub local: Option<LocalLogin>,
     pub mirror: Option<MirrorLogin>,
     // None means it's a deletion
@@ -233,17 +240,17 @@ pub(crate) struct SyncLoginData {
 impl SyncLoginData {
     #[inline]
     pub fn guid_str(&self) -> &str {
-        &self.guid[..]
+        self.guid.as_str()
     }
 
     #[inline]
-    pub fn guid(&self) -> &String {
+    pub fn guid(&self) -> &Guid {
         &self.guid
     }
 
     #[inline]
     pub fn from_payload(payload: sync::Payload, ts: ServerTimestamp) -> Result<Self> {
-        let guid = payload.id.clone();
+        let guid = Guid::new(&payload.id);
         let login: Option<Login> =
             if payload.is_tombstone() {
                 None
@@ -310,7 +317,9 @@ pub(crate) struct LoginDelta {
 macro_rules! merge_field {
     ($merged:ident, $b:ident, $prefer_b:expr, $field:ident) => {
         if let Some($field) = $b.$field.take() {
-            if $merged.$field.is_some() {
+            // Both sides making the same change isn't a conflict.
+            let same = $merged.$field.as_ref() == Some(&$field);
+            if $merged.$field.is_some() && !same {
                 warn!("Collision merging login field {}", stringify!($field));
                 if $prefer_b {
                     $merged.$field = Some($field);
@@ -323,17 +332,25 @@ macro_rules! merge_field {
 }
 
 impl LoginDelta {
+    /// Merges two sets of changes made to the same parent. Fields changed on
+    /// only one side are kept, and for fields changed on both, we prefer `b`
+    /// if `b_is_newer`. The exception is the password, which we take from
+    /// whichever side changed it most recently, if we know.
     pub fn merge(self, mut b: LoginDelta, b_is_newer: bool) -> LoginDelta {
+        let b_password_is_newer = match (self.time_password_changed, b.time_password_changed) {
+            (Some(a_changed), Some(b_changed)) => b_changed > a_changed,
+            _ => b_is_newer,
+        };
         let mut merged = self;
         merge_field!(merged, b, b_is_newer, hostname);
-        merge_field!(merged, b, b_is_newer, password);
+        merge_field!(merged, b, b_password_is_newer, password);
         merge_field!(merged, b, b_is_newer, username);
         merge_field!(merged, b, b_is_newer, http_realm);
         merge_field!(merged, b, b_is_newer, form_submit_url);
 
         merge_field!(merged, b, b_is_newer, time_created);
         merge_field!(merged, b, b_is_newer, time_last_used);
-        merge_field!(merged, b, b_is_newer, time_password_changed);
+        merge_field!(merged, b, b_password_is_newer, time_password_changed);
 
         merge_field!(merged, b, b_is_newer, password_field);
         merge_field!(merged, b, b_is_newer, username_field);
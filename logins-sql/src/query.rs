@@ -0,0 +1,100 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// How the results of a `LoginQuery` are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LoginSort {
+    /// By hostname, then username, ascending.
+    Hostname,
+    /// Most recently used first.
+    LastUsed,
+    /// Most recently modified (that is, most recent `timePasswordChanged`)
+    /// first.
+    LastModified,
+}
+
+impl Default for LoginSort {
+    fn default() -> Self {
+        LoginSort::Hostname
+    }
+}
+
+impl LoginSort {
+    pub(crate) fn order_by(self) -> &'static str {
+        // `guid` is included so that the order (and thus pagination using
+        // `limit` and `offset`) is stable.
+        match self {
+            LoginSort::Hostname => "hostname ASC, username ASC, guid ASC",
+            LoginSort::LastUsed => "timeLastUsed DESC, guid ASC",
+            LoginSort::LastModified => "timePasswordChanged DESC, guid ASC",
+        }
+    }
+}
+
+/// A filter for fetching a subset of the stored logins, for UI which doesn't
+/// want to fetch every record (e.g. autofill, or a searchable list).
+///
+/// Filters which aren't provided match everything, so `LoginQuery::default()`
+/// returns the same logins as `PasswordEngine::list`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LoginQuery {
+    /// Only return logins whose hostname contains this string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+
+    /// Only return logins whose username contains this string. Unlike the
+    /// hostname, this ignores (ASCII) case.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Only return logins whose `timePasswordChanged` is at least this (in
+    /// milliseconds since the unix epoch).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_since: Option<i64>,
+
+    pub sort: LoginSort,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+
+    pub offset: u32,
+}
+
+impl LoginQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_hostname(mut self, v: impl Into<Option<String>>) -> Self {
+        self.hostname = v.into();
+        self
+    }
+
+    pub fn with_username(mut self, v: impl Into<Option<String>>) -> Self {
+        self.username = v.into();
+        self
+    }
+
+    pub fn with_modified_since(mut self, v: impl Into<Option<i64>>) -> Self {
+        self.modified_since = v.into();
+        self
+    }
+
+    pub fn with_sort(mut self, sort: LoginSort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    pub fn with_limit(mut self, v: impl Into<Option<u32>>) -> Self {
+        self.limit = v.into();
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+}
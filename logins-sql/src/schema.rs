@@ -2,15 +2,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
+//! Logins Schema v6
 //! ================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are four tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsDeleted`: A log of deleted logins.
 //!
 //! ## `loginsL`
 //!
@@ -46,6 +47,12 @@
 //!     - `2` (`SyncStatus::New`): Indicating that the record has never been
 //!       synced, or we have been reset since the last time it synced.
 //!
+//! - `sync_change_counter`: The number of local changes made to the record
+//!   since it was last uploaded (added in v5). When a sync finishes, records
+//!   whose counter changed after we fetched them for upload are kept in
+//!   `loginsL` (with the uploaded changes subtracted), rather than being
+//!   folded into the mirror, so that changes made during a sync aren't lost.
+//!
 //! ## `loginsM`
 //!
 //! This stores server-side login information, also known as the "mirror".
@@ -78,7 +85,7 @@
 //! This table was added (by this rust crate) in version 4, and so is not
 //! present in firefox-ios.
 //!
-//! Currently it is used to store two items:
+//! Currently it is used to store three items:
 //!
 //! 1. The last sync timestamp is stored under [LAST_SYNC_META_KEY], a
 //!    `sync15_adapter::ServerTimestamp` stored in integer milliseconds.
@@ -87,14 +94,31 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15_adapter::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. Whether or not a sync failed due to network problems and should be
+//!    retried is stored under [PENDING_SYNC_META_KEY], as an integer 0 or 1.
+//!    The changes that weren't uploaded don't need to be stored separately,
+//!    as they're still flagged as changed in `loginsL`.
+//!
+//! ## `loginsDeleted`
+//!
+//! This records the `guid` and the millisecond `time_deleted` of every login
+//! which was deleted, either locally or by an incoming tombstone, so that
+//! `PasswordEngine::get_deleted_since` can report deletions even after the
+//! tombstones are gone from `loginsL`. It was added in version 6. Logins
+//! which are added again with the same `guid` are ignored, rather than
+//! removed from this table. Entries are pruned by `LoginDb::run_maintenance`
+//! after 60 days.
+//!
 
 use error::*;
 use sql_support::ConnExt;
 use db;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 adds a metadata
+/// table and changes timestamps to be in milliseconds, version 5 adds
+/// `sync_change_counter` to `loginsL`, and version 6 (this version) adds
+/// `loginsDeleted`.
+pub const VERSION: i64 = 6;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -154,7 +178,8 @@ lazy_static! {
             local_modified INTEGER,
 
             is_deleted     TINYINT NOT NULL DEFAULT 0,
-            sync_status    TINYINT NOT NULL DEFAULT 0
+            sync_status    TINYINT NOT NULL DEFAULT 0,
+            sync_change_counter INTEGER NOT NULL DEFAULT 0
         )",
         common_sql = COMMON_SQL
     );
@@ -183,6 +208,13 @@ const CREATE_META_TABLE_SQL: &'static str = "
     )
 ";
 
+const CREATE_DELETED_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS loginsDeleted (
+        guid TEXT PRIMARY KEY,
+        time_deleted INTEGER NOT NULL
+    ) WITHOUT ROWID
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &'static str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -209,8 +241,28 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &'static str = "
         timePasswordChanged = timePasswordChanged / 1000
 ";
 
+// The counters of the records we're uploading, as of when we fetched them.
+// See `sync_change_counter` above.
+const CREATE_OUTGOING_TABLE_SQL: &'static str = "
+    CREATE TEMP TABLE IF NOT EXISTS loginsOutgoing (
+        guid TEXT PRIMARY KEY,
+        sync_change_counter INTEGER NOT NULL
+    )
+";
+
+// Records which weren't synced when we upgraded to v5 have changed at least
+// once.
+const ADD_CHANGE_COUNTER_SQL: &'static str = "
+    ALTER TABLE loginsL ADD COLUMN sync_change_counter INTEGER NOT NULL DEFAULT 0
+";
+
+const INIT_CHANGE_COUNTER_SQL: &'static str = "
+    UPDATE loginsL SET sync_change_counter = 1 WHERE sync_status != 0
+";
+
 pub(crate) static LAST_SYNC_META_KEY:    &'static str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &'static str = "global_state";
+pub(crate) static PENDING_SYNC_META_KEY: &'static str = "pending_sync";
 
 pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
@@ -236,9 +288,8 @@ pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
         if table_list_exists {
             drop(db)?;
         }
-        return create(db);
-    }
-    if user_version != VERSION {
+        create(db)?;
+    } else if user_version != VERSION {
         if user_version < VERSION {
             upgrade(db, user_version)?;
         } else {
@@ -247,6 +298,8 @@ pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
                   user_version, VERSION)
         }
     }
+    // Temp tables only live as long as the connection.
+    db.execute_all(&[CREATE_OUTGOING_TABLE_SQL])?;
     Ok(())
 }
 
@@ -274,9 +327,18 @@ fn upgrade(db: &db::LoginDb, from: i64) -> Result<()> {
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
         ])?;
     }
+    if from < 5 {
+        db.execute_all(&[
+            ADD_CHANGE_COUNTER_SQL,
+            INIT_CHANGE_COUNTER_SQL,
+        ])?;
+    }
+    if from < 6 {
+        db.execute_all(&[CREATE_DELETED_TABLE_SQL])?;
+    }
+    db.execute_all(&[&*SET_VERSION_SQL])?;
     Ok(())
 }
 
@@ -288,6 +350,7 @@ pub(crate) fn create(db: &db::LoginDb) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_DELETED_TABLE_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
@@ -299,6 +362,7 @@ pub(crate) fn drop(db: &db::LoginDb) -> Result<()> {
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsDeleted",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
@@ -8,16 +8,17 @@ use error::*;
 use login::{LocalLogin, MirrorLogin, Login, SyncStatus};
 use sync::ServerTimestamp;
 use sql_support;
+use types_support::Guid;
 use util;
 
 #[derive(Default, Debug, Clone)]
 pub(crate) struct UpdatePlan {
-    pub delete_mirror: Vec<String>,
-    pub delete_local: Vec<String>,
+    pub delete_mirror: Vec<Guid>,
+    pub delete_local: Vec<Guid>,
     pub local_updates: Vec<MirrorLogin>,
     // the bool is the `is_overridden` flag, the i64 is ServerTimestamp in millis
     pub mirror_inserts: Vec<(Login, i64, bool)>,
-    pub mirror_updates: Vec<(Login, i64)>,
+    pub mirror_updates: Vec<(Login, i64, bool)>,
 }
 
 impl UpdatePlan {
@@ -25,7 +26,7 @@ impl UpdatePlan {
         let is_override = local.time_password_changed > upstream.0.time_password_changed;
         self.mirror_inserts.push((upstream.0, upstream.1.as_millis() as i64, is_override));
         if !is_override {
-            self.delete_local.push(local.id.to_string());
+            self.delete_local.push(local.id.clone());
         }
     }
 
@@ -37,30 +38,46 @@ impl UpdatePlan {
         upstream_time: ServerTimestamp,
         server_now: ServerTimestamp
     ) {
+        let upstream_time_ms = upstream_time.as_millis() as i64;
+        if local.is_deleted {
+            // The user deleted the record locally, so we keep the tombstone
+            // (which will be uploaded) instead of resurrecting it.
+            self.mirror_updates.push((upstream, upstream_time_ms, true));
+            return;
+        }
+
         let local_age = SystemTime::now().duration_since(local.local_modified).unwrap_or_default();
         let remote_age = server_now.duration_since(upstream_time).unwrap_or_default();
 
+        // Diff both sides against the version they share, so that changes to
+        // different fields are both kept.
         let local_delta = local.login.delta(&shared.login);
         let upstream_delta = upstream.delta(&shared.login);
 
         let merged_delta = local_delta.merge(upstream_delta, remote_age < local_age);
 
-        // Update mirror to upstream
-        self.mirror_updates.push((upstream, upstream_time.as_millis() as i64));
         let mut new = shared;
-
         new.login.apply_delta(merged_delta);
         new.server_modified = upstream_time;
-        self.local_updates.push(new);
+
+        if new.login == upstream {
+            // Everything we changed locally was also changed upstream, so
+            // there's nothing to upload, and the mirror is up to date.
+            self.delete_local.push(upstream.id.clone());
+            self.mirror_updates.push((upstream, upstream_time_ms, false));
+        } else {
+            self.mirror_updates.push((upstream, upstream_time_ms, true));
+            self.local_updates.push(new);
+        }
     }
 
-    pub fn plan_delete(&mut self, id: String) {
-        self.delete_local.push(id.to_string());
-        self.delete_mirror.push(id.to_string());
+    pub fn plan_delete(&mut self, id: Guid) {
+        self.delete_local.push(id.clone());
+        self.delete_mirror.push(id);
     }
 
     pub fn plan_mirror_update(&mut self, login: Login, time: ServerTimestamp) {
-        self.mirror_updates.push((login, time.as_millis() as i64));
+        self.mirror_updates.push((login, time.as_millis() as i64, false));
     }
 
     pub fn plan_mirror_insert(&mut self, login: Login, time: ServerTimestamp, is_override: bool) {
@@ -68,6 +85,19 @@ impl UpdatePlan {
     }
 
     fn perform_deletes(&self, conn: &Connection) -> Result<()> {
+        // Only incoming tombstones delete from the mirror. Remember the ones
+        // for logins we had, for `get_deleted_since`.
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        sql_support::each_chunk(&self.delete_mirror, |chunk, _| -> Result<()> {
+            conn.execute(&format!("
+                INSERT OR REPLACE INTO loginsDeleted(guid, time_deleted)
+                SELECT guid, {now_ms} FROM loginsM WHERE guid IN ({vars})",
+                now_ms = now_ms,
+                vars = sql_support::repeat_sql_vars(chunk.len())),
+                chunk)?;
+            Ok(())
+        })?;
+
         sql_support::each_chunk(&self.delete_local, |chunk, _| -> Result<()> {
             conn.execute(&format!("DELETE FROM loginsL WHERE guid IN ({vars})",
                                   vars = sql_support::repeat_sql_vars(chunk.len())),
@@ -88,6 +118,7 @@ impl UpdatePlan {
         let sql = "
             UPDATE loginsM
             SET server_modified = :server_modified,
+                is_overridden   = :is_overridden,
                 httpRealm       = :http_realm,
                 formSubmitURL   = :form_submit_url,
                 usernameField   = :username_field,
@@ -103,10 +134,11 @@ impl UpdatePlan {
             WHERE guid = :guid
         ";
         let mut stmt = conn.prepare_cached(sql)?;
-        for (login, timestamp) in &self.mirror_updates {
+        for (login, timestamp, is_overridden) in &self.mirror_updates {
             trace!("Updating mirror {:?}", login.guid_str());
             stmt.execute_named(&[
                (":server_modified", timestamp as &ToSql),
+               (":is_overridden",   is_overridden as &ToSql),
                (":http_realm",      &login.http_realm as &ToSql),
                (":form_submit_url", &login.form_submit_url as &ToSql),
                (":username_field",  &login.username_field as &ToSql),
@@ -206,7 +238,8 @@ impl UpdatePlan {
                 password            = :password,
                 hostname            = :hostname,
                 username            = :username,
-                sync_status         = {changed}
+                sync_status         = {changed},
+                sync_change_counter = sync_change_counter + 1
             WHERE guid = :guid",
             changed = SyncStatus::Changed as u8);
         let mut stmt = conn.prepare_cached(&sql)?;
@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::fmt;
+
 use bso_record::{EncryptedBso, Payload};
 use client::Sync15StorageClient;
 use error::{self, ErrorKind, Result};
@@ -51,6 +53,52 @@ impl OutgoingChangeset {
             .collect()
     }
 
+    /// Like `encrypt`, but checks each record first, and returns the ones
+    /// the server would reject separately instead of failing. Records with
+    /// an encrypted payload of `max_payload_bytes` or more are rejected.
+    pub fn encrypt_valid(
+        self,
+        key: &KeyBundle,
+        max_payload_bytes: usize,
+    ) -> Result<(Vec<EncryptedBso>, Vec<InvalidRecord>)> {
+        let RecordChangeset {
+            changes,
+            collection,
+            ..
+        } = self;
+        let mut valid = Vec::with_capacity(changes.len());
+        let mut invalid = vec![];
+        for change in changes {
+            let id = change.id.clone();
+            let reason = match check_payload(&change) {
+                Some(reason) => reason,
+                None => match change.into_bso(collection.clone()).encrypt(key) {
+                    Ok(bso) => {
+                        let size = bso.payload.serialized_len();
+                        if size < max_payload_bytes {
+                            valid.push(bso);
+                            continue;
+                        }
+                        InvalidRecordReason::TooLarge { size, max: max_payload_bytes }
+                    }
+                    Err(e) => {
+                        let message = match e.kind() {
+                            ErrorKind::JsonError(json_error) => Some(json_error.to_string()),
+                            _ => None,
+                        };
+                        match message {
+                            Some(message) => InvalidRecordReason::Unserializable(message),
+                            None => return Err(e),
+                        }
+                    }
+                },
+            };
+            warn!("Skipping outgoing record {} in {}: {}", id, collection, reason);
+            invalid.push(InvalidRecord { id, reason });
+        }
+        Ok((valid, invalid))
+    }
+
     pub fn post(
         self,
         client: &Sync15StorageClient,
@@ -82,6 +130,70 @@ impl IncomingChangeset {
     }
 }
 
+/// Why an outgoing record was skipped instead of uploaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidRecordReason {
+    /// The ID isn't 1 to 64 printable ASCII characters.
+    InvalidId,
+    /// An automatic field (`sortindex` or `ttl`) has the wrong type.
+    InvalidAutoField(&'static str),
+    /// The cleartext couldn't be serialized as JSON.
+    Unserializable(String),
+    /// The encrypted payload is larger than the server allows.
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for InvalidRecordReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidRecordReason::InvalidId => write!(f, "invalid id"),
+            InvalidRecordReason::InvalidAutoField(name) => write!(f, "invalid {}", name),
+            InvalidRecordReason::Unserializable(e) => write!(f, "can't be serialized: {}", e),
+            InvalidRecordReason::TooLarge { size, max } => {
+                write!(f, "payload is {} bytes, the limit is {}", size, max)
+            }
+        }
+    }
+}
+
+/// An outgoing record which the server would reject. Uploading it in a
+/// batch would fail the whole POST, with an error that doesn't say which
+/// record was the problem, so we skip it and report it as failed instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidRecord {
+    pub id: String,
+    pub reason: InvalidRecordReason,
+}
+
+const MAX_ID_LEN: usize = 64;
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b >= b' ' && b <= b'~')
+}
+
+// Checks the things we can without encrypting the record. `into_bso` drops
+// automatic fields it can't deserialize (with an error in the log), which
+// would quietly upload the record without them.
+fn check_payload(payload: &Payload) -> Option<InvalidRecordReason> {
+    if !is_valid_id(&payload.id) {
+        return Some(InvalidRecordReason::InvalidId);
+    }
+    if let Some(v) = payload.data.get("sortindex") {
+        let in_range = v.as_i64().map_or(false, |n| {
+            n >= i64::from(i32::min_value()) && n <= i64::from(i32::max_value())
+        });
+        if !in_range {
+            return Some(InvalidRecordReason::InvalidAutoField("sortindex"));
+        }
+    }
+    if let Some(v) = payload.data.get("ttl") {
+        if v.as_u64().map_or(true, |n| n > u64::from(u32::max_value())) {
+            return Some(InvalidRecordReason::InvalidAutoField("ttl"));
+        }
+    }
+    None
+}
+
 #[derive(Debug, Clone)]
 pub struct CollectionUpdate<'a, 'b> {
     client: &'a Sync15StorageClient,
@@ -89,6 +201,7 @@ pub struct CollectionUpdate<'a, 'b> {
     collection: String,
     xius: ServerTimestamp,
     to_update: Vec<EncryptedBso>,
+    invalid: Vec<InvalidRecord>,
     fully_atomic: bool,
 }
 
@@ -107,6 +220,7 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             collection,
             xius,
             to_update: records,
+            invalid: vec![],
             fully_atomic,
         }
     }
@@ -124,21 +238,38 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             // Not actually interrupted, but we know we'd fail the XIUS check.
             return Err(ErrorKind::BatchInterrupted.into());
         }
-        let to_update = changeset.encrypt(&key_bundle)?;
-        Ok(CollectionUpdate::new(
+        let (to_update, invalid) =
+            changeset.encrypt_valid(&key_bundle, state.config.max_record_payload_bytes)?;
+        if fully_atomic {
+            if let Some(record) = invalid.first() {
+                return Err(match record.reason {
+                    InvalidRecordReason::TooLarge { .. } => ErrorKind::RecordTooLargeError,
+                    ref reason => ErrorKind::InvalidRecord(reason.to_string()),
+                }.into());
+            }
+        }
+        let mut update = CollectionUpdate::new(
             client,
             state,
             collection,
             xius,
             to_update,
             fully_atomic,
-        ))
+        );
+        update.invalid = invalid;
+        Ok(update)
+    }
+
+    /// Records which `new_from_changeset` skipped. They're included in the
+    /// `failed_ids` returned by `upload`.
+    pub fn invalid_records(&self) -> &[InvalidRecord] {
+        &self.invalid
     }
 
     /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
     /// returns an empty vec.
     pub fn upload(self) -> error::Result<UploadInfo> {
-        let mut failed = vec![];
+        let mut failed: Vec<String> = self.invalid.into_iter().map(|record| record.id).collect();
         let mut q = self.client.new_post_queue(
             &self.collection,
             &self.state.config,
@@ -148,8 +279,11 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
 
         for record in self.to_update.into_iter() {
             let enqueued = q.enqueue(&record)?;
-            if !enqueued && self.fully_atomic {
-                return Err(ErrorKind::RecordTooLargeError.into());
+            if !enqueued {
+                if self.fully_atomic {
+                    return Err(ErrorKind::RecordTooLargeError.into());
+                }
+                failed.push(record.id);
             }
         }
 
@@ -163,3 +297,49 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
         Ok(info)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(value: ::serde_json::Value) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn test_check_payload() {
+        assert_eq!(check_payload(&payload(json!({"id": "aaaaaaaaaaaa", "sortindex": 100}))), None);
+        assert_eq!(check_payload(&payload(json!({"id": ""}))),
+                   Some(InvalidRecordReason::InvalidId));
+        assert_eq!(check_payload(&payload(json!({"id": "a".repeat(MAX_ID_LEN + 1)}))),
+                   Some(InvalidRecordReason::InvalidId));
+        assert_eq!(check_payload(&payload(json!({"id": "tab\there"}))),
+                   Some(InvalidRecordReason::InvalidId));
+        assert_eq!(check_payload(&payload(json!({"id": "aaaaaaaaaaaa", "sortindex": "high"}))),
+                   Some(InvalidRecordReason::InvalidAutoField("sortindex")));
+        assert_eq!(check_payload(&payload(json!({"id": "aaaaaaaaaaaa", "sortindex": 1u64 << 40}))),
+                   Some(InvalidRecordReason::InvalidAutoField("sortindex")));
+        assert_eq!(check_payload(&payload(json!({"id": "aaaaaaaaaaaa", "ttl": -1}))),
+                   Some(InvalidRecordReason::InvalidAutoField("ttl")));
+    }
+
+    #[test]
+    fn test_encrypt_valid() {
+        let key = KeyBundle::new_random().unwrap();
+        let mut changeset = OutgoingChangeset::new("tabs".into(), ServerTimestamp(0.0));
+        changeset.changes.push(payload(json!({"id": "smallAAAAAAA", "title": "small"})));
+        changeset.changes.push(payload(json!({"id": "largeAAAAAAA", "title": "x".repeat(1000)})));
+        changeset.changes.push(payload(json!({"id": ""})));
+
+        let (valid, invalid) = changeset.encrypt_valid(&key, 1000).unwrap();
+        assert_eq!(valid.len(), 1);
+        assert_eq!(valid[0].id, "smallAAAAAAA");
+        assert_eq!(invalid.len(), 2);
+        assert_eq!(invalid[0].id, "largeAAAAAAA");
+        match invalid[0].reason {
+            InvalidRecordReason::TooLarge { size, max } => assert!(size >= max && max == 1000),
+            ref reason => panic!("Unexpected reason {:?}", reason),
+        }
+        assert_eq!(invalid[1].reason, InvalidRecordReason::InvalidId);
+    }
+}
@@ -3,15 +3,17 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::cell::Cell;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use hyper::{Method};
-use reqwest::{Client, Request, Response, Url, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
+use hyper::{Method, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
 use serde;
 use serde_json;
+use url::Url;
 
 use bso_record::{BsoRecord, EncryptedBso};
 use error::{self, ErrorKind};
+use http::{HttpBackend, HttpRequest, HttpResponse, ReqwestBackend};
 use record_types::MetaGlobalRecord;
 use request::{BatchPoster, CollectionRequest, InfoConfiguration, PostQueue, PostResponse,
               PostResponseHandler, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP, InfoCollections};
@@ -19,11 +21,36 @@ use std::str::FromStr;
 use token;
 use util::ServerTimestamp;
 
+const X_WEAVE_BACKOFF: &str = "X-Weave-Backoff";
+const X_BACKOFF: &str = "X-Backoff";
+const RETRY_AFTER: &str = "Retry-After";
+
+/// How long we back off for if the server returns a 429 or 503 without
+/// telling us how long to wait.
+const DEFAULT_BACKOFF_SECS: u64 = 5 * 60;
+
+/// The parameters for setting up a `Sync15StorageClient`. Besides the
+/// credentials, these let an application point the client at a self-hosted
+/// tokenserver and storage server.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Sync15StorageClientInit {
     pub key_id: String,
     pub access_token: String,
     pub tokenserver_url: Url,
+    /// If set, storage requests go here instead of to the `api_endpoint` in
+    /// the token, for servers behind a proxy that rewrites the storage URL.
+    /// We still need the tokenserver for the credentials.
+    pub storage_url: Option<Url>,
+    /// Allows plain `http` tokenserver and storage URLs. Only useful for
+    /// local test servers; otherwise, we refuse to send credentials and
+    /// records without TLS.
+    pub allow_insecure_http: bool,
+    /// DER-encoded certificates which the default backend trusts as roots,
+    /// in addition to the system's, for servers with certificates issued by
+    /// an enterprise CA. These aren't used by a custom `HttpBackend`, which
+    /// is where an application that needs to pin certificates should check
+    /// them.
+    pub extra_root_certificates: Vec<Vec<u8>>,
 }
 
 /// A trait containing the methods required to run through the setup state
@@ -41,10 +68,15 @@ pub trait SetupStorageClient {
 
 #[derive(Debug)]
 pub struct Sync15StorageClient {
-    http_client: Client,
+    http_client: Arc<HttpBackend>,
     // We update this when we make requests
     timestamp: Cell<ServerTimestamp>,
+    // The latest time the server has asked us to back off until, if any.
+    backoff: Cell<Option<SystemTime>>,
     tsc: token::TokenProvider,
+    tokenserver_url: Url,
+    storage_url: Option<Url>,
+    allow_insecure_http: bool,
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -59,7 +91,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
-        let mut resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
+        let resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
             Ok(r) => Ok(r),
             Err(ref e) if e.is_not_found() => Err(ErrorKind::NoMetaGlobal.into()),
             Err(e) => Err(e)
@@ -75,7 +107,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
-        let mut keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
+        let keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
         let keys: EncryptedBso = keys_resp.json()?;
         Ok(keys)
     }
@@ -85,9 +117,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn wipe_all_remote(&self) -> error::Result<()> {
-        let s = self.tsc.api_endpoint(&self.http_client)?;
-        let url = Url::parse(&s)?;
-
+        let url = self.api_endpoint()?;
         let req = self.build_request(Method::DELETE, url)?;
         match self.exec_request(req, true) {
             Ok(_) => Ok(()),
@@ -98,19 +128,57 @@ impl SetupStorageClient for Sync15StorageClient {
 }
 
 impl Sync15StorageClient {
+    /// Creates a client which makes requests using the default backend.
     pub fn new(init_params: Sync15StorageClientInit) -> error::Result<Sync15StorageClient> {
-        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+        let backend = ReqwestBackend::with_root_certificates(&init_params.extra_root_certificates)?;
+        Ok(Sync15StorageClient::with_backend(init_params, Arc::new(backend)))
+    }
+
+    /// Creates a client which makes its tokenserver and storage requests
+    /// using `backend`.
+    pub fn with_backend(
+        init_params: Sync15StorageClientInit,
+        backend: Arc<HttpBackend>,
+    ) -> Sync15StorageClient {
         let tsc = token::TokenProvider::new(
-            init_params.tokenserver_url,
+            init_params.tokenserver_url.clone(),
             init_params.access_token,
             init_params.key_id,
         );
         let timestamp = ServerTimestamp(0f64);
-        Ok(Sync15StorageClient {
-            http_client: client,
+        Sync15StorageClient {
+            http_client: backend,
             timestamp: Cell::new(timestamp),
+            backoff: Cell::new(None),
             tsc,
-        })
+            tokenserver_url: init_params.tokenserver_url,
+            storage_url: init_params.storage_url,
+            allow_insecure_http: init_params.allow_insecure_http,
+        }
+    }
+
+    /// Returns the URL of the storage server, which is either the one we
+    /// were configured with, or the one in our token. Every storage request
+    /// starts here, so this is also where we refuse to talk to insecure
+    /// servers (including the tokenserver, which we'd otherwise contact for
+    /// the token).
+    pub(crate) fn api_endpoint(&self) -> error::Result<Url> {
+        check_url_scheme(&self.tokenserver_url, self.allow_insecure_http)?;
+        let url = match self.storage_url {
+            Some(ref url) => url.clone(),
+            None => Url::parse(&self.tsc.api_endpoint(&*self.http_client)?)?,
+        };
+        check_url_scheme(&url, self.allow_insecure_http)?;
+        Ok(url)
+    }
+
+    fn relative_storage_url(&self, relative_path: &str) -> error::Result<Url> {
+        let mut s = self.api_endpoint()?.into_string();
+        // A configured storage URL might already end with a slash.
+        if !s.ends_with('/') {
+            s.push('/');
+        }
+        Ok(Url::parse(&s)?.join(relative_path)?)
     }
 
     #[inline]
@@ -118,11 +186,19 @@ impl Sync15StorageClient {
         return self.timestamp.get();
     }
 
+    /// Returns the time the server asked us not to make requests before, if
+    /// that time hasn't yet passed. Note that the server may ask us to back
+    /// off via a header on a successful response, in which case the request
+    /// that received it still succeeds.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        self.backoff.get().filter(|when| *when > SystemTime::now())
+    }
+
     pub fn get_encrypted_records(
         &self,
         collection_request: &CollectionRequest,
     ) -> error::Result<Vec<EncryptedBso>> {
-        let mut resp = self.collection_request(
+        let resp = self.collection_request(
             Method::GET,
             collection_request,
         )?;
@@ -130,71 +206,92 @@ impl Sync15StorageClient {
     }
 
     #[inline]
-    fn authorized(&self, mut req: Request) -> error::Result<Request> {
-        let hawk_header_value = self.tsc.authorization(&self.http_client, &req)?;
-        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
+    fn authorized(&self, mut req: HttpRequest) -> error::Result<HttpRequest> {
+        let hawk_header_value = self.tsc.authorization(&*self.http_client, &req)?;
+        req.headers.insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
         Ok(req)
     }
 
     // TODO: probably want a builder-like API to do collection requests (e.g. something
     // that occupies roughly the same conceptual role as the Collection class in desktop)
-    fn build_request(&self, method: Method, url: Url) -> error::Result<Request> {
-        self.authorized(self.http_client
-            .request(method, url)
-            .header(ACCEPT, "application/json")
-            .build()?)
+    fn build_request(&self, method: Method, url: Url) -> error::Result<HttpRequest> {
+        let mut req = HttpRequest::new(method, url);
+        req.headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        self.authorized(req)
     }
 
     fn relative_storage_request<T>(
         &self,
         method: Method,
         relative_path: T,
-    ) -> error::Result<Response>
+    ) -> error::Result<HttpResponse>
     where
         T: AsRef<str>,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
-        let url = Url::parse(&s)?.join(relative_path.as_ref())?;
+        let url = self.relative_storage_url(relative_path.as_ref())?;
         Ok(self.make_storage_request(method, url)?)
     }
 
-    fn make_storage_request(&self, method: Method, url: Url) -> error::Result<Response> {
+    fn make_storage_request(&self, method: Method, url: Url) -> error::Result<HttpResponse> {
         // I'm shocked that method isn't Copy...
         Ok(self.exec_request(self.build_request(method.clone(), url)?, true)?)
     }
 
-    fn exec_request(&self, req: Request, require_success: bool) -> error::Result<Response> {
-        trace!("request: {} {}", req.method(), req.url().path());
+    fn exec_request(&self, req: HttpRequest, require_success: bool) -> error::Result<HttpResponse> {
+        trace!("request: {} {}", req.method, req.url.path());
         let resp = self.http_client.execute(req)?;
-        trace!("response: {}", resp.status());
+        trace!("response: {}", resp.status);
+
+        self.update_timestamp(&resp.headers);
+        let requested_backoff = backoff_from_headers(&resp.headers);
+        if let Some(when) = requested_backoff {
+            warn!("Server requested backoff until {:?}", when);
+            self.note_backoff(when);
+        }
 
-        self.update_timestamp(resp.headers());
+        let status = resp.status;
+        if status.as_u16() == 429 || status.as_u16() == 503
+            || (status.is_server_error() && requested_backoff.is_some())
+        {
+            let retry_at = requested_backoff.unwrap_or_else(|| {
+                SystemTime::now() + Duration::from_secs(DEFAULT_BACKOFF_SECS)
+            });
+            error!("HTTP error {} during storage request, backing off", status.as_u16());
+            self.note_backoff(retry_at);
+            return Err(ErrorKind::BackoffError { retry_at }.into());
+        }
 
-        if require_success && !resp.status().is_success() {
+        // The storage server rejects our token if it's expired early, or if
+        // we've been moved to another node, so the next request needs a new
+        // one.
+        if status.as_u16() == 401 {
+            self.tsc.drop_token();
+        }
+
+        if require_success && !status.is_success() {
             error!(
                 "HTTP error {} ({}) during storage request to {}",
-                resp.status().as_u16(),
-                resp.status(),
-                resp.url().path()
+                status.as_u16(),
+                status,
+                resp.url.path()
             );
             return Err(ErrorKind::StorageHttpError {
-                code: resp.status().as_u16(),
-                route: resp.url().path().into(),
+                code: status.as_u16(),
+                route: resp.url.path().into(),
             }.into());
         }
 
         // TODO:
-        // - handle backoff
         // - x-weave-quota?
         // - ... almost certainly other things too...
 
         Ok(resp)
     }
 
-    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<Response> {
+    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<HttpResponse> {
         self.make_storage_request(
             method.clone(),
-            r.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?,
+            r.build_url(self.api_endpoint()?)?,
         )
     }
 
@@ -202,7 +299,7 @@ impl Sync15StorageClient {
     where
         for<'a> T: serde::de::Deserialize<'a>,
     {
-        let mut resp = self.relative_storage_request(Method::GET, path)?;
+        let resp = self.relative_storage_request(Method::GET, path)?;
         let result: T = resp.json()?;
         Ok(result)
     }
@@ -216,6 +313,15 @@ impl Sync15StorageClient {
         }
     }
 
+    fn note_backoff(&self, when: SystemTime) {
+        // Never shorten a backoff we were already asked for.
+        let later = match self.backoff.get() {
+            Some(prev) if prev > when => prev,
+            _ => when,
+        };
+        self.backoff.set(Some(later));
+    }
+
     pub fn new_post_queue<'a, F: PostResponseHandler>(
         &'a self,
         coll: &str,
@@ -240,23 +346,50 @@ impl Sync15StorageClient {
         P: AsRef<str>,
         B: serde::ser::Serialize,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
-        let url = Url::parse(&s)?.join(relative_path.as_ref())?;
+        let url = self.relative_storage_url(relative_path.as_ref())?;
 
         let bytes = serde_json::to_vec(body)?;
 
         let mut req = self.build_request(Method::PUT, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        req.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
         if let Some(ts) = xius {
-            req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+            req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
         }
-        *req.body_mut() = Some(bytes.into());
+        req.body = Some(bytes);
         let _ = self.exec_request(req, true)?;
 
         Ok(())
     }
 }
 
+/// Fails unless `url` is `https`, or `http` if we're allowed to use it.
+fn check_url_scheme(url: &Url, allow_insecure_http: bool) -> error::Result<()> {
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if allow_insecure_http => Ok(()),
+        "http" => Err(ErrorKind::UnacceptableUrl(
+            format!("{} doesn't use TLS, and insecure HTTP isn't allowed", url)).into()),
+        scheme => Err(ErrorKind::UnacceptableUrl(
+            format!("{} has unsupported scheme {}", url, scheme)).into()),
+    }
+}
+
+/// Returns the latest time requested by any of the backoff headers the server
+/// may send. All of them are specified in (possibly fractional) seconds. Note
+/// that `Retry-After` may also be an HTTP date, which the sync servers don't
+/// send, so we ignore it in that case.
+fn backoff_from_headers(hm: &header::HeaderMap) -> Option<SystemTime> {
+    let now = SystemTime::now();
+    [X_WEAVE_BACKOFF, X_BACKOFF, RETRY_AFTER]
+        .iter()
+        .filter_map(|name| hm.get(*name))
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|s| s.trim().parse::<f64>().ok())
+        .filter(|secs| *secs >= 0f64)
+        .map(|secs| now + Duration::from_millis((secs * 1000f64) as u64))
+        .max()
+}
+
 pub struct PostWrapper<'a> {
     client: &'a Sync15StorageClient,
     coll: String,
@@ -274,17 +407,163 @@ impl<'a> BatchPoster for PostWrapper<'a> {
         let url = CollectionRequest::new(self.coll.clone())
             .batch(batch)
             .commit(commit)
-            .build_url(Url::parse(&self.client
-                .tsc
-                .api_endpoint(&self.client.http_client)?)?)?;
+            .build_url(self.client.api_endpoint()?)?;
 
         let mut req = self.client.build_request(Method::POST, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
+        req.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        req.headers.insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
         // It's very annoying that we need to copy the body here, the request
         // shouldn't need to take ownership of it...
-        *req.body_mut() = Some(Vec::from(bytes).into());
-        let mut resp = self.client.exec_request(req, false)?;
-        Ok(PostResponse::from_response(&mut resp)?)
+        req.body = Some(Vec::from(bytes));
+        let resp = self.client.exec_request(req, false)?;
+        Ok(PostResponse::from_response(&resp)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use hyper::StatusCode;
+
+    // Answers tokenserver requests with a token for `https://storage.example.com/1.5/1`,
+    // and storage requests with `storage_status`, remembering each request.
+    #[derive(Debug)]
+    struct MockBackend {
+        storage_status: StatusCode,
+        requests: Mutex<Vec<HttpRequest>>,
+    }
+
+    impl HttpBackend for MockBackend {
+        fn execute(&self, request: HttpRequest) -> error::Result<HttpResponse> {
+            let mut headers = header::HeaderMap::new();
+            let (status, body) = if request.url.host_str() == Some("token.example.com") {
+                headers.insert("X-Timestamp", HeaderValue::from_static("1000"));
+                (StatusCode::OK, json!({
+                    "id": "id",
+                    "key": "key",
+                    "api_endpoint": "https://storage.example.com/1.5/1",
+                    "uid": 1,
+                    "duration": 3600,
+                    "hashed_fxa_uid": "hash",
+                }))
+            } else {
+                headers.insert(X_WEAVE_TIMESTAMP, HeaderValue::from_static("1234.5"));
+                headers.insert(X_WEAVE_BACKOFF, HeaderValue::from_static("60"));
+                (self.storage_status, json!({"passwords": 1234.5}))
+            };
+            let url = request.url.clone();
+            self.requests.lock().unwrap().push(request);
+            Ok(HttpResponse { status, url, headers, body: serde_json::to_vec(&body)? })
+        }
+    }
+
+    fn make_init(tokenserver_url: &str) -> Sync15StorageClientInit {
+        Sync15StorageClientInit {
+            key_id: "key_id".into(),
+            access_token: "access_token".into(),
+            tokenserver_url: Url::parse(tokenserver_url).unwrap(),
+            storage_url: None,
+            allow_insecure_http: false,
+            extra_root_certificates: Vec::new(),
+        }
+    }
+
+    fn make_client_with_init(
+        storage_status: StatusCode,
+        init: Sync15StorageClientInit,
+    ) -> (Arc<MockBackend>, Sync15StorageClient) {
+        let backend = Arc::new(MockBackend {
+            storage_status,
+            requests: Mutex::new(Vec::new()),
+        });
+        let client = Sync15StorageClient::with_backend(init, backend.clone());
+        (backend, client)
+    }
+
+    fn make_client(storage_status: StatusCode) -> (Arc<MockBackend>, Sync15StorageClient) {
+        make_client_with_init(storage_status, make_init("https://token.example.com/1.0/sync/1.5"))
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let (backend, client) = make_client(StatusCode::OK);
+        let collections = client.fetch_info_collections().expect("should fetch info/collections");
+        assert_eq!(collections.get("passwords"), Some(&ServerTimestamp(1234.5)));
+        assert_eq!(client.last_server_time(), ServerTimestamp(1234.5));
+        // The request succeeded, but we should still honor the backoff.
+        assert!(client.backoff_until().is_some());
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers.get(AUTHORIZATION).unwrap(), "Bearer access_token");
+        assert_eq!(requests[1].url.as_str(), "https://storage.example.com/1.5/1/info/collections");
+        let auth = requests[1].headers.get(AUTHORIZATION).unwrap().to_str().unwrap();
+        assert!(auth.starts_with("Hawk "));
+    }
+
+    #[test]
+    fn test_custom_backend_unavailable() {
+        let (_, client) = make_client(StatusCode::SERVICE_UNAVAILABLE);
+        let err = client.fetch_info_collections().expect_err("should back off");
+        assert!(err.retry_at().is_some());
+        assert_eq!(err.retry_at(), client.backoff_until());
+    }
+
+    #[test]
+    fn test_storage_url() {
+        let mut init = make_init("https://token.example.com/1.0/sync/1.5");
+        init.storage_url = Some(Url::parse("https://sync.example.org/storage/1.5/1/").unwrap());
+        let (backend, client) = make_client_with_init(StatusCode::OK, init);
+        client.fetch_info_collections().expect("should fetch info/collections");
+
+        // We still need a token, but we ignore its endpoint.
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].url.host_str(), Some("token.example.com"));
+        assert_eq!(requests[1].url.as_str(), "https://sync.example.org/storage/1.5/1/info/collections");
+        let url = client.api_endpoint().unwrap();
+        assert_eq!(CollectionRequest::new("passwords").build_url(url).unwrap().as_str(),
+                   "https://sync.example.org/storage/1.5/1/storage/passwords");
+    }
+
+    #[test]
+    fn test_insecure_http() {
+        let mut init = make_init("http://token.example.com/1.0/sync/1.5");
+        let (backend, client) = make_client_with_init(StatusCode::OK, init.clone());
+        let err = client.fetch_info_collections().expect_err("should refuse http");
+        match err.kind() {
+            ErrorKind::UnacceptableUrl(_) => {}
+            _ => panic!("Unexpected error {:?}", err),
+        }
+        assert!(backend.requests.lock().unwrap().is_empty());
+
+        // The token points at an https storage server, so only the
+        // tokenserver is insecure here.
+        init.allow_insecure_http = true;
+        let (backend, client) = make_client_with_init(StatusCode::OK, init.clone());
+        client.fetch_info_collections().expect("should allow http when asked");
+        assert_eq!(backend.requests.lock().unwrap()[0].url.scheme(), "http");
+
+        init.storage_url = Some(Url::parse("ftp://sync.example.org/1.5/1").unwrap());
+        let (_, client) = make_client_with_init(StatusCode::OK, init);
+        client.fetch_info_collections().expect_err("should refuse other schemes");
+    }
+
+    #[test]
+    fn test_backoff_from_headers() {
+        let mut hm = header::HeaderMap::new();
+        assert_eq!(backoff_from_headers(&hm), None);
+
+        hm.insert(X_WEAVE_BACKOFF, HeaderValue::from_static("not a number"));
+        assert_eq!(backoff_from_headers(&hm), None);
+
+        let before = SystemTime::now();
+        hm.insert(X_WEAVE_BACKOFF, HeaderValue::from_static("10"));
+        hm.insert(RETRY_AFTER, HeaderValue::from_static("1800"));
+        let when = backoff_from_headers(&hm).expect("should have backoff");
+        let waited = when.duration_since(before).unwrap();
+        assert!(waited >= Duration::from_secs(1800));
+        assert!(waited < Duration::from_secs(1810));
     }
 }
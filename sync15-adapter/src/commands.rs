@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// A command that another client sent us, via our record in the `clients`
+/// collection, asking us to wipe or reset some of our engines.
+///
+/// Resetting an engine only discards what we know about the server (the
+/// mirror, timestamps and sync status flags), so that the next sync merges
+/// everything again. Wiping an engine also discards all of its local data,
+/// without uploading deletions, so that the next sync replaces it with
+/// what's on the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Wipe(String),
+    WipeAll,
+    Reset(String),
+    ResetAll,
+}
+
+impl Command {
+    /// Parses a command from a `clients` record, like
+    /// `{"command": "wipeEngine", "args": ["passwords"]}`. Returns `None` for
+    /// commands which aren't about engines, like `displayURI`, and for
+    /// commands with missing arguments.
+    pub fn from_client_command(command: &str, args: &[String]) -> Option<Command> {
+        match command {
+            "wipeEngine" => args.first().map(|name| Command::Wipe(name.clone())),
+            "wipeAll" => Some(Command::WipeAll),
+            "resetEngine" => args.first().map(|name| Command::Reset(name.clone())),
+            "resetAll" => Some(Command::ResetAll),
+            _ => None,
+        }
+    }
+
+    /// Returns true if this command applies to the engine that syncs
+    /// `collection`.
+    pub fn applies_to(&self, collection: &str) -> bool {
+        match self {
+            Command::Wipe(name) | Command::Reset(name) => name == collection,
+            Command::WipeAll | Command::ResetAll => true,
+        }
+    }
+
+    /// Returns true if this command discards local data, rather than just
+    /// sync metadata.
+    pub fn is_wipe(&self) -> bool {
+        match self {
+            Command::Wipe(_) | Command::WipeAll => true,
+            Command::Reset(_) | Command::ResetAll => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_client_command() {
+        let args = vec!["passwords".to_string()];
+        let wipe = Command::from_client_command("wipeEngine", &args).unwrap();
+        assert_eq!(wipe, Command::Wipe("passwords".into()));
+        assert!(wipe.is_wipe());
+        assert!(wipe.applies_to("passwords"));
+        assert!(!wipe.applies_to("history"));
+
+        let reset = Command::from_client_command("resetEngine", &args).unwrap();
+        assert!(!reset.is_wipe());
+        assert!(reset.applies_to("passwords"));
+
+        assert!(Command::from_client_command("resetAll", &[]).unwrap().applies_to("history"));
+        assert!(Command::from_client_command("wipeEngine", &[]).is_none());
+        assert!(Command::from_client_command("displayURI", &args).is_none());
+    }
+}
@@ -11,9 +11,21 @@ use openssl;
 use base64;
 use serde_json;
 use hawk;
+use error_support::{self, GetErrorCode};
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Codes for errors specific to syncing, allocated from
+/// `error_support::SYNC15_BASE`. See `GetErrorCode`.
+pub mod error_codes {
+    use error_support::SYNC15_BASE;
+
+    /// Another client changed the sync server's keys or `meta/global` while
+    /// we were syncing, and they changed again when we retried. Syncing again
+    /// later should succeed.
+    pub const KEYS_CHANGED: i32 = SYNC15_BASE + 1;
+}
+
 #[derive(Debug)]
 pub struct Error(Box<Context<ErrorKind>>);
 
@@ -48,6 +60,56 @@ impl Error {
             _ => false
         }
     }
+
+    /// Returns true if this error was caused by failing to reach the server at
+    /// all (as opposed to the server returning an error), which typically
+    /// means the device is offline.
+    pub fn is_network_error(&self) -> bool {
+        match self.kind() {
+            ErrorKind::RequestError(e) => e.is_http() || e.is_timeout(),
+            ErrorKind::HttpBackendError(_) => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if this error means that `crypto/keys` or `meta/global`
+    /// changed on the server while we were syncing, so our cached copies are
+    /// stale. This is the case if we fail to decrypt a record (it was
+    /// encrypted with keys we don't have), if the storage server rejects our
+    /// token, or if another client replaced either record while we were
+    /// uploading it.
+    pub fn is_keys_changed(&self) -> bool {
+        match self.kind() {
+            ErrorKind::HmacMismatch => true,
+            ErrorKind::StorageHttpError { code: 401, .. } => true,
+            ErrorKind::StorageHttpError { code: 412, route } => {
+                route.ends_with("/meta/global") || route.ends_with("/crypto/keys")
+            }
+            _ => false
+        }
+    }
+
+    /// If this error was caused by the server asking us to back off, returns
+    /// the time at which we may retry.
+    pub fn retry_at(&self) -> Option<SystemTime> {
+        match self.kind() {
+            ErrorKind::BackoffError { retry_at } => Some(*retry_at),
+            _ => None
+        }
+    }
+}
+
+impl GetErrorCode for Error {
+    fn error_code(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::TokenserverHttpError(401) => error_support::AUTH_INVALID,
+            ErrorKind::RequestError(_) => error_support::NETWORK,
+            ErrorKind::HttpBackendError(_) => error_support::NETWORK,
+            ErrorKind::BackoffError { .. } => error_support::BACKOFF,
+            _ if self.is_keys_changed() => error_codes::KEYS_CHANGED,
+            _ => error_support::UNEXPECTED,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -78,8 +140,10 @@ pub enum ErrorKind {
     #[fail(display = "HTTP status {} during a storage request to \"{}\"", code, route)]
     StorageHttpError { code: u16, route: String },
 
-    #[fail(display = "Server requested backoff. Retry after {:?}", _0)]
-    BackoffError(SystemTime),
+    /// The server asked us to back off, either via a `Retry-After`,
+    /// `X-Weave-Backoff` or `X-Backoff` header, or by returning a 429 or 503.
+    #[fail(display = "Server requested backoff. Retry after {:?}", retry_at)]
+    BackoffError { retry_at: SystemTime },
 
     #[fail(display = "No meta/global record is present on the server")]
     NoMetaGlobal,
@@ -90,6 +154,9 @@ pub enum ErrorKind {
     #[fail(display = "Outgoing record is too large to upload")]
     RecordTooLargeError,
 
+    #[fail(display = "Outgoing record can't be uploaded: {}", _0)]
+    InvalidRecord(String),
+
     #[fail(display = "The batch was not committed due to being interrupted")]
     BatchInterrupted,
 
@@ -140,6 +207,10 @@ pub enum ErrorKind {
     #[fail(display = "Network error: {}", _0)]
     RequestError(#[fail(cause)] reqwest::Error),
 
+    /// A custom `HttpBackend` couldn't get a response from the server.
+    #[fail(display = "HTTP backend error: {}", _0)]
+    HttpBackendError(String),
+
     #[fail(display = "HAWK error: {}", _0)]
     HawkError(#[fail(cause)] SyncFailure<hawk::Error>),
 
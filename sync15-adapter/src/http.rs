@@ -0,0 +1,358 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The HTTP requests we make to the tokenserver and the storage server all go
+//! through an `HttpBackend`, so that an application can send them using its
+//! own networking stack (for example, OkHttp or NSURLSession) and have them
+//! respect its proxy settings, certificate handling, and so on. If it doesn't
+//! provide one, we use `ReqwestBackend`. Applications on the other side of an
+//! FFI provide a callback instead, which `ForeignBackend` wraps.
+//!
+//! Applications which need to pin the certificates of their servers should
+//! provide a backend which checks them, since reqwest can't. The default
+//! backend can only be told to trust extra root certificates.
+//!
+//! Backends only need to send requests and return responses. Authorization,
+//! timestamps, and the backoff and rate limiting headers that the servers
+//! send are all handled by `Sync15StorageClient` and the token provider, so
+//! they behave the same way regardless of the backend.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::c_char;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, StatusCode};
+use reqwest::{self, Certificate, Client};
+use serde;
+use serde_json;
+use url::Url;
+
+use error::{ErrorKind, Result};
+
+/// How long the default backend waits for a response before giving up.
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// A request for an `HttpBackend` to send.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, url: Url) -> HttpRequest {
+        HttpRequest {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+}
+
+/// The response to an `HttpRequest`. Backends should return a response for
+/// any status the server sends, including errors; they should only fail if
+/// they couldn't get a response at all.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    /// The URL of the response, which differs from the request's if the
+    /// backend followed a redirect.
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns the value of the header `name`, if the response has one and
+    /// it's valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    pub fn json<T>(&self) -> Result<T>
+    where
+        for<'a> T: serde::de::Deserialize<'a>,
+    {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    /// Returns the body as a string, replacing any invalid UTF-8. Mainly
+    /// useful for logging.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Something that can send HTTP requests. Implementations should report
+/// failures to reach the server as `ErrorKind::HttpBackendError`, so that
+/// `Error::is_network_error` recognizes them.
+pub trait HttpBackend: fmt::Debug + Send + Sync {
+    fn execute(&self, request: HttpRequest) -> Result<HttpResponse>;
+}
+
+/// The default backend, which sends requests using reqwest.
+#[derive(Debug)]
+pub struct ReqwestBackend {
+    client: Client,
+}
+
+impl ReqwestBackend {
+    pub fn new() -> Result<ReqwestBackend> {
+        ReqwestBackend::with_root_certificates(&[])
+    }
+
+    /// Creates a backend which also trusts the DER-encoded certificates in
+    /// `der_certs` as roots, for servers whose certificates are issued by a
+    /// private CA.
+    pub fn with_root_certificates(der_certs: &[Vec<u8>]) -> Result<ReqwestBackend> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+        for der in der_certs {
+            builder = builder.add_root_certificate(Certificate::from_der(der)?);
+        }
+        let client = builder.build()?;
+        Ok(ReqwestBackend { client })
+    }
+}
+
+impl HttpBackend for ReqwestBackend {
+    fn execute(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut builder = self.client
+            .request(request.method, request.url)
+            .headers(request.headers);
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        let mut resp: reqwest::Response = builder.send()?;
+        let mut body = Vec::new();
+        resp.copy_to(&mut body)?;
+        Ok(HttpResponse {
+            status: resp.status(),
+            url: resp.url().clone(),
+            headers: resp.headers().clone(),
+            body,
+        })
+    }
+}
+
+/// The function an application provides to send requests for a
+/// `ForeignBackend`. It's called with the request as a JSON object, like
+/// `{"method": "GET", "url": "https://...", "headers": {"Name": "value"},
+/// "body": "<base64>"}`, where the body may be null. It returns the
+/// response, including error statuses, as a JSON object like
+/// `{"status": 200, "url": "https://...", "headers": {"Name": "value"},
+/// "body": "<base64>"}`, or `{"error": "message"}` if it couldn't get a
+/// response at all. The returned string is owned by the application, and
+/// only needs to stay valid until the callback is called again. A
+/// `ForeignBackend` never calls its callback again until it's copied the
+/// last response, even from another thread, so a callback mustn't be shared
+/// between backends.
+pub type ForeignSendCallback = extern "C" fn(request_json: *const c_char) -> *const c_char;
+
+/// A backend which sends requests using a callback from the application.
+/// Requests are sent one at a time, since each response is only valid until
+/// the next call.
+pub struct ForeignBackend {
+    // Held while the callback runs, and until we've copied its response.
+    send: Mutex<ForeignSendCallback>,
+}
+
+impl ForeignBackend {
+    /// Creates a backend which sends requests with `send`. This is unsafe
+    /// because we trust `send` to return null, or a valid string which lives
+    /// long enough for us to copy it.
+    pub unsafe fn new(send: ForeignSendCallback) -> ForeignBackend {
+        ForeignBackend { send: Mutex::new(send) }
+    }
+}
+
+impl fmt::Debug for ForeignBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForeignBackend").finish()
+    }
+}
+
+#[derive(Serialize)]
+struct ForeignRequest<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: HashMap<&'a str, &'a str>,
+    body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ForeignResponse {
+    status: Option<u16>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: Option<String>,
+    error: Option<String>,
+}
+
+fn foreign_backend_error(message: &str) -> ErrorKind {
+    ErrorKind::HttpBackendError(message.into())
+}
+
+impl HttpBackend for ForeignBackend {
+    fn execute(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let request_json = serde_json::to_string(&ForeignRequest {
+            method: request.method.as_str(),
+            url: request.url.as_str(),
+            // Our own requests never have headers that aren't strings.
+            headers: request.headers.iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+                .collect(),
+            body: request.body.as_ref().map(base64::encode),
+        })?;
+        // JSON never has embedded null bytes.
+        let request_json = CString::new(request_json).unwrap();
+        let response_json = {
+            // Nothing we do while holding the lock can leave the callback in
+            // a bad state if we panic.
+            let send = self.send.lock().unwrap_or_else(|e| e.into_inner());
+            let response_json = (*send)(request_json.as_ptr());
+            if response_json.is_null() {
+                return Err(foreign_backend_error("The application didn't return a response").into());
+            }
+            // Safe because `ForeignBackend::new` requires the callback to
+            // return a valid string, and the lock keeps other threads from
+            // calling it again until we've copied it.
+            unsafe { CStr::from_ptr(response_json) }.to_string_lossy().into_owned()
+        };
+        let response: ForeignResponse = serde_json::from_str(&response_json)?;
+        if let Some(error) = response.error {
+            return Err(ErrorKind::HttpBackendError(error).into());
+        }
+        let status = response.status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| foreign_backend_error("Invalid response status"))?;
+        let url = match response.url {
+            Some(url) => Url::parse(&url)?,
+            None => request.url,
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &response.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| foreign_backend_error("Invalid response header name"))?;
+            headers.insert(name, HeaderValue::from_str(value)?);
+        }
+        let body = match response.body {
+            Some(body) => base64::decode(&body)?,
+            None => Vec::new(),
+        };
+        Ok(HttpResponse { status, url, headers, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Weave-Timestamp", HeaderValue::from_static("1234.5"));
+        headers.insert("X-Binary", HeaderValue::from_bytes(b"\xff").unwrap());
+        let resp = HttpResponse {
+            status: StatusCode::OK,
+            url: Url::parse("https://example.com/1.5/123/info/collections").unwrap(),
+            headers,
+            body: br#"{"passwords": 1234.5}"#.to_vec(),
+        };
+        assert_eq!(resp.header("x-weave-timestamp"), Some("1234.5"));
+        assert_eq!(resp.header("X-Binary"), None);
+        assert_eq!(resp.header("X-Missing"), None);
+
+        let json: serde_json::Value = resp.json().unwrap();
+        assert_eq!(json["passwords"], 1234.5);
+        assert!(resp.json::<Vec<String>>().is_err());
+        assert_eq!(resp.text(), r#"{"passwords": 1234.5}"#);
+    }
+
+    extern "C" fn echo_callback(request_json: *const c_char) -> *const c_char {
+        let request: serde_json::Value = serde_json::from_str(
+            &unsafe { CStr::from_ptr(request_json) }.to_string_lossy()).unwrap();
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["headers"]["content-type"], "application/json");
+        let response = json!({
+            "status": 201,
+            "url": request["url"],
+            "headers": { "X-Weave-Timestamp": "1234.5" },
+            "body": request["body"],
+        });
+        // Leaked, but it's only a test.
+        CString::new(response.to_string()).unwrap().into_raw()
+    }
+
+    extern "C" fn failing_callback(_: *const c_char) -> *const c_char {
+        b"{\"error\": \"Offline\"}\0".as_ptr() as *const c_char
+    }
+
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static OVERLAPPED: AtomicBool = AtomicBool::new(false);
+
+    // Returns a response in a buffer that's overwritten by the next call, as
+    // JNA's do.
+    extern "C" fn reused_buffer_callback(_: *const c_char) -> *const c_char {
+        static mut RESPONSE: [u8; 32] = [0; 32];
+        if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) != 0 {
+            OVERLAPPED.store(true, Ordering::SeqCst);
+        }
+        thread::sleep(Duration::from_millis(5));
+        let response = b"{\"status\": 204}\0";
+        unsafe {
+            RESPONSE[..response.len()].copy_from_slice(response);
+        }
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        unsafe { RESPONSE.as_ptr() as *const c_char }
+    }
+
+    #[test]
+    fn test_foreign_backend_concurrent() {
+        let backend = Arc::new(unsafe { ForeignBackend::new(reused_buffer_callback) });
+        let threads = (0..4).map(|_| {
+            let backend = Arc::clone(&backend);
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    let req = HttpRequest::new(Method::GET, Url::parse("https://example.com/").unwrap());
+                    assert_eq!(backend.execute(req).unwrap().status, StatusCode::NO_CONTENT);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(!OVERLAPPED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_foreign_backend() {
+        let backend = unsafe { ForeignBackend::new(echo_callback) };
+        let mut req = HttpRequest::new(Method::POST, Url::parse("https://example.com/1.5/123/storage/passwords").unwrap());
+        req.headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+        req.body = Some(b"[]".to_vec());
+        let resp = backend.execute(req).unwrap();
+        assert_eq!(resp.status, StatusCode::CREATED);
+        assert_eq!(resp.url.as_str(), "https://example.com/1.5/123/storage/passwords");
+        assert_eq!(resp.header("X-Weave-Timestamp"), Some("1234.5"));
+        assert_eq!(resp.body, b"[]");
+
+        let backend = unsafe { ForeignBackend::new(failing_callback) };
+        let req = HttpRequest::new(Method::GET, Url::parse("https://example.com/").unwrap());
+        let err = backend.execute(req).unwrap_err();
+        assert!(err.is_network_error());
+    }
+}
@@ -28,6 +28,7 @@ extern crate serde_json;
 
 extern crate url;
 extern crate base16;
+extern crate error_support;
 
 // TODO: Some of these don't need to be pub...
 pub mod key_bundle;
@@ -40,16 +41,24 @@ pub mod util;
 pub mod request;
 pub mod changeset;
 pub mod sync;
+pub mod commands;
+pub mod sync_multiple;
+pub mod http;
 pub mod client;
 pub mod state;
+pub mod telemetry;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
-pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset};
+pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset, InvalidRecord, InvalidRecordReason};
 pub use error::{Result, Error, ErrorKind};
 pub use sync::{synchronize, Store};
+pub use commands::Command;
+pub use sync_multiple::{sync_multiple, MemoryCachedState, SyncResult};
 pub use util::{ServerTimestamp, SERVER_EPOCH};
 pub use key_bundle::KeyBundle;
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
+pub use http::{ForeignBackend, ForeignSendCallback, HttpBackend, HttpRequest, HttpResponse, ReqwestBackend};
 pub use state::{GlobalState, SetupStateMachine};
 pub use request::{CollectionRequest};
+pub use telemetry::SyncTelemetryPing;
@@ -14,7 +14,7 @@ use std::str::FromStr;
 use url::{Url, UrlQuery, form_urlencoded::Serializer};
 use error::{self, Result, ErrorKind};
 use hyper::{StatusCode};
-use reqwest::Response;
+use http::HttpResponse;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder { Oldest, Newest, Index }
@@ -142,6 +142,7 @@ impl CollectionRequest {
     pub fn build_url(&self, mut base_url: Url) -> Result<Url> {
         base_url.path_segments_mut()
                 .map_err(|_| ErrorKind::UnacceptableUrl("Storage server URL is not a base".into()))?
+                .pop_if_empty()
                 .extend(&["storage", &self.collection]);
         self.build_query(&mut base_url.query_pairs_mut());
         // This is strange but just accessing query_pairs_mut makes you have
@@ -285,12 +286,12 @@ pub struct PostResponse {
 }
 
 impl PostResponse {
-    pub fn from_response(r: &mut Response) -> Result<PostResponse> {
+    pub fn from_response(r: &HttpResponse) -> Result<PostResponse> {
         let result: UploadResult = r.json()?;
         // TODO Can this happen in error cases?
-        let last_modified = r.headers().get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
+        let last_modified = r.header(X_LAST_MODIFIED).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
             ErrorKind::MissingServerTimestamp)?;
-        let status = r.status();
+        let status = r.status;
         Ok(PostResponse { status, result, last_modified })
     }
 }
@@ -637,6 +638,9 @@ mod test {
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
 
+        let slash = CollectionRequest::new("foo")
+            .build_url(Url::parse("https://example.com/sync/").unwrap()).unwrap();
+        assert_eq!(slash.as_str(), "https://example.com/sync/storage/foo");
     }
 
     #[derive(Debug, Clone)]
@@ -56,6 +56,11 @@ pub struct GlobalState {
     pub global: Option<BsoRecord<MetaGlobalRecord>>,
     pub keys: Option<CollectionKeys>,
     pub engine_state_changes: Vec<EngineStateChange>,
+    /// The storage node that `collections`, `global` and `keys` came from,
+    /// so that we notice when we're reassigned to a different one. `None` if
+    /// we haven't synced since this was added.
+    #[serde(default)]
+    pub storage_endpoint: Option<String>,
 }
 
 impl GlobalState {
@@ -82,7 +87,11 @@ impl GlobalState {
         self.collections.get(coll).cloned().unwrap_or(SERVER_EPOCH)
     }
 
-    /// Returns a set of all engine names that should be reset locally.
+    /// Returns a set of all engine names that should be reset locally: those
+    /// whose sync IDs or keys changed, and those which another client enabled
+    /// or disabled in `meta/global`. A newly enabled engine has a new sync ID,
+    /// and a disabled one shouldn't keep stale sync metadata around in case
+    /// it's enabled again later.
     pub fn engines_that_need_local_reset(&self) -> HashSet<String> {
         let all_engines = self.global
             .as_ref()
@@ -97,7 +106,9 @@ impl GlobalState {
         let mut engines_to_reset = HashSet::new();
         for change in &self.engine_state_changes {
             match change {
-                EngineStateChange::Reset(name) => {
+                EngineStateChange::Reset(name)
+                | EngineStateChange::Enable(name)
+                | EngineStateChange::Disable(name) => {
                     engines_to_reset.insert(name.to_string());
                 }
                 EngineStateChange::ResetAll => {
@@ -111,7 +122,6 @@ impl GlobalState {
                         engines_to_reset.insert(name.to_string());
                     }
                 }
-                _ => {}
             }
         }
         engines_to_reset
@@ -146,6 +156,7 @@ fn resolve_global(
         global: Some(new_global),
         keys: previous_keys,
         engine_state_changes: changes,
+        storage_endpoint: previous_state.storage_endpoint,
     }
 }
 
@@ -225,6 +236,7 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
         global: previous_state.global,
         keys: Some(new_keys),
         engine_state_changes: changes,
+        storage_endpoint: previous_state.storage_endpoint,
     }
 }
 
@@ -365,6 +377,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: Vec::new(),
+                    storage_endpoint: state.storage_endpoint,
                 }))
             }
 
@@ -376,6 +389,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
+                    storage_endpoint: state.storage_endpoint,
                 }))
             }
 
@@ -407,6 +421,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        storage_endpoint: state.storage_endpoint,
                     }),
                 })
             }
@@ -463,6 +478,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        storage_endpoint: state.storage_endpoint,
                     }),
                 })
             }
@@ -513,6 +529,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: None,
                     keys: None,
                     engine_state_changes: vec![EngineStateChange::ResetAll],
+                    storage_endpoint: state.storage_endpoint,
                 }))
             }
         }
@@ -757,4 +774,49 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_engines_that_need_local_reset() {
+        let global = BsoRecord {
+            id: "global".into(),
+            modified: ServerTimestamp(999.0),
+            collection: "meta".into(),
+            sortindex: None,
+            ttl: None,
+            payload: MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version: 5usize,
+                engines: vec![
+                    ("passwords", "syncIDBBBBBB"),
+                    ("history", "syncIDCCCCCC"),
+                    ("bookmarks", "syncIDDDDDDD"),
+                ].into_iter()
+                    .map(|(name, sync_id)| (name.to_owned(), MetaGlobalEngine {
+                        version: 1usize,
+                        sync_id: sync_id.to_owned(),
+                    }))
+                    .collect(),
+                declined: vec!["forms".to_owned()],
+            },
+        };
+        let names = |names: &[&str]| -> HashSet<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        };
+        let mut state = GlobalState {
+            global: Some(global),
+            ..GlobalState::default()
+        };
+        assert!(state.engines_that_need_local_reset().is_empty());
+
+        state.engine_state_changes = vec![
+            EngineStateChange::Enable("passwords".into()),
+            EngineStateChange::Disable("forms".into()),
+        ];
+        assert_eq!(state.engines_that_need_local_reset(), names(&["passwords", "forms"]));
+
+        state.engine_state_changes = vec![
+            EngineStateChange::ResetAllExcept(names(&["history"])),
+        ];
+        assert_eq!(state.engines_that_need_local_reset(), names(&["passwords", "bookmarks"]));
+    }
 }
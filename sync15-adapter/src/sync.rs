@@ -8,16 +8,25 @@ use client::Sync15StorageClient;
 use error::Error;
 use failure;
 use state::GlobalState;
-use util::ServerTimestamp;
+use telemetry;
+use util::{ServerTimestamp, SERVER_EPOCH};
 
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
 /// Different stores will produce errors of different types.  To accommodate this, we force them
 /// all to return failure::Error, which we expose as ErrorKind::StoreError.
 pub trait Store {
+    /// The name of the collection this store syncs, e.g. "passwords".
+    fn collection_name(&self) -> &'static str;
+
+    /// Apply the records in `inbound`, and return the local changes to
+    /// upload. Records that can't be applied, for example because they're
+    /// malformed, should be skipped and counted in `telem.failed`, rather
+    /// than failing the whole sync. Everything else counts as applied.
     fn apply_incoming(
         &self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset, failure::Error>;
 
     fn sync_finished(
@@ -32,22 +41,51 @@ pub trait Store {
     /// engines might do something fancier. This could even later be extended
     /// to handle "backfills" etc
     fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error>;
+
+    /// Discard any local sync metadata (timestamps, sync status flags, etc),
+    /// so that the next sync behaves like a first sync. Called when the
+    /// collection's syncID changes on the server, and when the collection
+    /// was wiped on the server, or we were moved to a new storage node.
+    fn reset(&self) -> Result<(), failure::Error>;
+
+    /// Discard all local data for the collection, without recording anything
+    /// to upload, along with the sync metadata that `reset` discards, so
+    /// that the next sync replaces it with the server's. Called when another
+    /// client sends us a `wipeEngine` command.
+    fn wipe(&self) -> Result<(), failure::Error>;
 }
 
 pub fn synchronize(client: &Sync15StorageClient,
                    state: &GlobalState,
                    store: &Store,
                    collection: String,
-                   fully_atomic: bool) -> Result<(), Error>
+                   fully_atomic: bool,
+                   telem_engine: &mut telemetry::Engine) -> Result<(), Error>
 {
 
     info!("Syncing collection {}", collection);
-    let collection_request = store.get_collection_request()?;
+    let mut collection_request = store.get_collection_request()?;
+    if was_wiped_on_server(state, &collection_request) {
+        // Our sync metadata describes records that the server no longer has,
+        // so a reset makes us fetch everything that's there now, and upload
+        // everything we have.
+        warn!("Collection {} was wiped on the server; resetting", collection);
+        store.reset()?;
+        collection_request = store.get_collection_request()?;
+    }
     let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), &collection_request)?;
     let last_changed_remote = incoming_changes.timestamp;
 
-    info!("Downloaded {} remote changes", incoming_changes.changes.len());
-    let mut outgoing = store.apply_incoming(incoming_changes)?;
+    let num_incoming = incoming_changes.changes.len() as u32;
+
+    info!("Downloaded {} remote changes", num_incoming);
+    let mut telem_incoming = telemetry::EngineIncoming::default();
+    let mut outgoing = store.apply_incoming(incoming_changes, &mut telem_incoming)?;
+    telem_incoming.applied = num_incoming.saturating_sub(telem_incoming.failed);
+    if telem_incoming.failed > 0 {
+        warn!("Failed to apply {} of {} remote changes", telem_incoming.failed, num_incoming);
+    }
+    telem_engine.incoming(telem_incoming);
 
     outgoing.timestamp = last_changed_remote;
 
@@ -58,9 +96,53 @@ pub fn synchronize(client: &Sync15StorageClient,
     info!("Upload success ({} records success, {} records failed)",
           upload_info.successful_ids.len(),
           upload_info.failed_ids.len());
+    telem_engine.outgoing(telemetry::EngineOutgoing {
+        sent: upload_info.successful_ids.len() as u32,
+        failed: upload_info.failed_ids.len() as u32,
+    });
 
     store.sync_finished(upload_info.modified_timestamp, &upload_info.successful_ids)?;
 
     info!("Sync finished!");
     Ok(())
 }
+
+// Returns true if the server's last modified time for the collection is
+// older than the last sync that `request` fetches changes since, or it's
+// missing entirely. The collection's timestamp only ever moves forward, so
+// this means it was deleted, either by another client, or by a server-side
+// wipe or node reassignment that left `meta/global` alone.
+fn was_wiped_on_server(state: &GlobalState, request: &CollectionRequest) -> bool {
+    match request.newer {
+        Some(since) if since > SERVER_EPOCH => state.last_modified_or_zero(&request.collection) < since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::InfoCollections;
+
+    #[test]
+    fn test_was_wiped_on_server() {
+        let mut state = GlobalState::default();
+        state.collections = InfoCollections::new(
+            vec![("passwords".to_string(), ServerTimestamp(1000.0))].into_iter().collect());
+
+        let first_sync = CollectionRequest::new("passwords").full();
+        assert!(!was_wiped_on_server(&state, &first_sync));
+        let since_epoch = CollectionRequest::new("passwords").newer_than(SERVER_EPOCH);
+        assert!(!was_wiped_on_server(&state, &since_epoch));
+
+        let up_to_date = CollectionRequest::new("passwords").newer_than(ServerTimestamp(1000.0));
+        assert!(!was_wiped_on_server(&state, &up_to_date));
+        let behind = CollectionRequest::new("passwords").newer_than(ServerTimestamp(500.0));
+        assert!(!was_wiped_on_server(&state, &behind));
+
+        let ahead = CollectionRequest::new("passwords").newer_than(ServerTimestamp(1500.0));
+        assert!(was_wiped_on_server(&state, &ahead));
+        let missing = CollectionRequest::new("history").newer_than(ServerTimestamp(500.0));
+        assert!(was_wiped_on_server(&state, &missing));
+    }
+}
@@ -0,0 +1,560 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use client::{Sync15StorageClient, Sync15StorageClientInit};
+use commands::Command;
+use error::{Error, ErrorKind};
+use http::HttpBackend;
+use key_bundle::KeyBundle;
+use state::{GlobalState, SetupStateMachine};
+use sync::{self, Store};
+use telemetry;
+
+/// The result of syncing a set of stores with `sync_multiple`.
+#[derive(Debug, Default)]
+pub struct SyncResult {
+    /// The outcome of syncing each store that wasn't declined, keyed by
+    /// collection name. An error here doesn't prevent the other stores from
+    /// syncing.
+    pub engine_results: HashMap<String, Result<(), Error>>,
+    /// The collections we skipped because they're declined in `meta/global`.
+    pub declined: Vec<String>,
+    /// True if `crypto/keys` or `meta/global` changed on the server partway
+    /// through this sync, so we discarded our cached state and started over.
+    /// If they changed again during the retry, the affected stores' results
+    /// are errors for which `Error::is_keys_changed` returns true.
+    pub keys_changed: bool,
+    /// Engines which need a local reset, because their sync ID changed, they
+    /// were enabled or disabled in `meta/global`, or another client sent a
+    /// `resetEngine` command, but which we couldn't reset because they
+    /// weren't passed to `sync_multiple`. We won't report these again, so
+    /// the caller should reset them before they next sync.
+    pub requires_local_reset: Vec<String>,
+    /// Engines which another client sent a `wipeEngine` command for, but
+    /// which weren't passed to `sync_multiple`, so the caller should wipe
+    /// them itself.
+    pub requires_local_wipe: Vec<String>,
+    /// True if the tokenserver moved us to a different storage node since
+    /// the last sync. The new node starts out empty, so we discarded our
+    /// global state, and reset every store so that it uploads everything
+    /// again.
+    pub node_reassigned: bool,
+}
+
+impl SyncResult {
+    /// Returns true if every store we attempted to sync succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.engine_results.values().all(|r| r.is_ok())
+    }
+}
+
+#[derive(Debug)]
+struct ClientInfo {
+    client: Sync15StorageClient,
+    // Used so that we know whether or not we need to re-initialize `client`
+    last_client_init: Sync15StorageClientInit,
+}
+
+/// Sync state which is only cached in memory, between calls to
+/// `sync_multiple` in the same process: the storage client (and so our
+/// tokenserver token), and the `GlobalState` from the last sync.
+///
+/// Everything here can be rebuilt, so it's fine to drop this (or to pass a
+/// fresh one) at any time; the next sync will just need to fetch a new token,
+/// and reload the global state from the persisted string.
+#[derive(Debug, Default)]
+pub struct MemoryCachedState {
+    client_info: Option<ClientInfo>,
+    global_state: Option<GlobalState>,
+    http_backend: Option<Arc<HttpBackend>>,
+}
+
+impl MemoryCachedState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a state whose storage client sends its requests with
+    /// `backend`, instead of the default `ReqwestBackend`.
+    pub fn with_http_backend(backend: Arc<HttpBackend>) -> Self {
+        MemoryCachedState {
+            http_backend: Some(backend),
+            ..Self::default()
+        }
+    }
+
+    /// Forget everything we've cached. Should be called when the persisted
+    /// state is discarded, for example when the user signs out. The HTTP
+    /// backend, if any, is kept.
+    pub fn clear(&mut self) {
+        self.client_info = None;
+        self.global_state = None;
+    }
+}
+
+/// Syncs several stores in one go, sharing a single storage client (and so a
+/// single tokenserver token) and a single `GlobalState` between them.
+///
+/// `persisted_global_state` is the string this function last stored in it,
+/// which the caller is responsible for persisting between runs (it's
+/// updated even if a store fails to sync). It holds the cached `meta/global`,
+/// `crypto/keys`, and collection timestamps, so that we only need to refetch
+/// them from the server when they change. Note that it contains the
+/// collection keys, so it should be stored with the same care as the data
+/// it protects.
+///
+/// Errors encountered while setting up (fetching `meta/global`,
+/// `crypto/keys`, etc) are returned directly, whereas errors syncing an
+/// individual store are recorded in the `SyncResult`.
+///
+/// If another client changes `crypto/keys` or `meta/global` while we're
+/// syncing, we throw away both the cached and persisted global state, and
+/// try once more with a new token and freshly fetched keys. The storage
+/// server rejects our token with a 401 when we've been moved to another
+/// node, which we can't tell apart from a keys change until we have the new
+/// token, so we keep the node we last synced with for the retry.
+///
+/// Telemetry for the sync is recorded in `telem`, including why it failed if
+/// it did, so that failed syncs can be reported as well.
+///
+/// `commands` are the wipe and reset commands other clients sent us since
+/// the last sync, which are applied to `stores` before syncing them. Commands
+/// for engines that aren't in `stores` are returned in the `SyncResult`,
+/// except for `wipeAll` and `resetAll`, which the caller needs to apply to
+/// any other engines itself.
+pub fn sync_multiple(
+    stores: &[&Store],
+    commands: &[Command],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    telem: &mut telemetry::SyncTelemetry,
+) -> Result<SyncResult, Error> {
+    let result = sync_multiple_with_retry(
+        stores,
+        commands,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        telem,
+    );
+    if let Err(e) = &result {
+        telem.failure(e);
+    }
+    telem.finished();
+    result
+}
+
+fn sync_multiple_with_retry(
+    stores: &[&Store],
+    commands: &[Command],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    telem: &mut telemetry::SyncTelemetry,
+) -> Result<SyncResult, Error> {
+    let unhandled = apply_commands(stores, commands)?;
+    let result = sync_multiple_once(
+        stores,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        telem,
+    );
+    let mut result = if !keys_changed(&result) {
+        result?
+    } else {
+        warn!("crypto/keys or meta/global changed during sync; resetting state and retrying");
+        // A 401 from a node we've been moved away from looks the same as a
+        // keys change, so we keep the node we last synced with, to notice
+        // the move when we retry with a new token.
+        let storage_endpoint = previous_storage_endpoint(
+            mem_cached_state,
+            persisted_global_state.as_ref().map(|s| s.as_str()),
+        );
+        mem_cached_state.clear();
+        *persisted_global_state = Some(GlobalState {
+            storage_endpoint,
+            ..GlobalState::default()
+        }.to_persistable_string());
+        // Only report the engines from the attempt that counts.
+        telem.engines.clear();
+        let mut result = sync_multiple_once(
+            stores,
+            persisted_global_state,
+            mem_cached_state,
+            storage_init,
+            root_sync_key,
+            telem,
+        )?;
+        result.keys_changed = true;
+        result
+    };
+    for command in unhandled {
+        match command {
+            Command::Wipe(name) => result.requires_local_wipe.push(name),
+            Command::Reset(name) => {
+                if !result.requires_local_reset.contains(&name) {
+                    result.requires_local_reset.push(name);
+                }
+            }
+            Command::WipeAll | Command::ResetAll => {}
+        }
+    }
+    Ok(result)
+}
+
+// Wipes or resets the stores that `commands` apply to, and returns the
+// commands for engines that aren't in `stores`. A wipe includes a reset, so
+// we don't reset a store that we've already wiped.
+fn apply_commands(stores: &[&Store], commands: &[Command]) -> Result<Vec<Command>, Error> {
+    let mut wiped = Vec::new();
+    let mut reset = Vec::new();
+    let mut unhandled = Vec::new();
+    for command in commands {
+        let mut handled = false;
+        for store in stores {
+            let name = store.collection_name();
+            if !command.applies_to(name) {
+                continue;
+            }
+            handled = true;
+            if wiped.contains(&name) {
+                continue;
+            }
+            if command.is_wipe() {
+                info!("Wiping {} at another client's request", name);
+                store.wipe()?;
+                wiped.push(name);
+            } else if !reset.contains(&name) {
+                info!("Resetting {} at another client's request", name);
+                store.reset()?;
+                reset.push(name);
+            }
+        }
+        if !handled {
+            unhandled.push(command.clone());
+        }
+    }
+    Ok(unhandled)
+}
+
+fn sync_multiple_once(
+    stores: &[&Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    telem: &mut telemetry::SyncTelemetry,
+) -> Result<SyncResult, Error> {
+    // If the options passed for initialization of the storage client
+    // aren't the same as the ones we used last time, reinitialize it.
+    let needs_new_client = match &mem_cached_state.client_info {
+        Some(info) => &info.last_client_init != storage_init,
+        None => true,
+    };
+    if needs_new_client {
+        info!("Initializing storage client");
+        let client = match &mem_cached_state.http_backend {
+            Some(backend) => Sync15StorageClient::with_backend(storage_init.clone(), backend.clone()),
+            None => Sync15StorageClient::new(storage_init.clone())?,
+        };
+        mem_cached_state.client_info = Some(ClientInfo {
+            client,
+            last_client_init: storage_init.clone(),
+        });
+    }
+    let client = &mem_cached_state.client_info.as_ref().unwrap().client;
+
+    // Don't even try to sync if the server recently told us to back off.
+    if let Some(retry_at) = client.backoff_until() {
+        info!("Server requested backoff until {:?}, not syncing", retry_at);
+        return Err(ErrorKind::BackoffError { retry_at }.into());
+    }
+
+    // Prefer the state we cached in memory, since it's the same as the
+    // persisted state, without the cost of deserializing it. If advancing
+    // fails we don't cache anything, so the next sync falls back to the
+    // persisted state.
+    let prev_state = match mem_cached_state.global_state.take() {
+        Some(state) => state,
+        None => load_global_state(persisted_global_state.as_ref().map(|s| s.as_str())),
+    };
+    // Everything we cached came from the old node, and the new one doesn't
+    // have any of it. Starting from the default state makes the state
+    // machine fetch (or upload) a fresh `meta/global`, and reset every
+    // engine, which discards their sync metadata so they upload everything.
+    let storage_endpoint = client.api_endpoint()?.into_string();
+    let node_reassigned = is_node_reassigned(&prev_state, &storage_endpoint);
+    let prev_state = if node_reassigned {
+        warn!("Storage node changed since the last sync; starting over");
+        GlobalState::default()
+    } else {
+        prev_state
+    };
+    let state = {
+        let mut state_machine = SetupStateMachine::for_full_sync(client, root_sync_key);
+        info!("Advancing state machine to ready (full)");
+        let mut state = state_machine.to_ready(prev_state)?;
+        state.storage_endpoint = Some(storage_endpoint);
+        state
+    };
+    *persisted_global_state = Some(state.to_persistable_string());
+
+    let declined = state.global
+        .as_ref()
+        .map(|global| global.declined.clone())
+        .unwrap_or_default();
+    let needs_reset = state.engines_that_need_local_reset();
+
+    let mut result = SyncResult {
+        node_reassigned,
+        ..SyncResult::default()
+    };
+    for store in stores {
+        let name = store.collection_name();
+        if declined.iter().any(|d| d == name) {
+            info!("Skipping declined engine {}", name);
+            result.declined.push(name.to_string());
+            // Reset the store even though we aren't syncing it, since we
+            // won't be told again.
+            if needs_reset.contains(name) {
+                if let Err(e) = store.reset() {
+                    warn!("Failed to reset declined engine {}: {:?}", name, e);
+                    result.requires_local_reset.push(name.to_string());
+                }
+            }
+            continue;
+        }
+        let mut telem_engine = telemetry::Engine::new(name);
+        let engine_result = sync_one(
+            client,
+            &state,
+            *store,
+            needs_reset.contains(name),
+            &mut telem_engine,
+        );
+        if let Err(e) = &engine_result {
+            warn!("Sync of {} failed! {:?}", name, e);
+            telem_engine.failure(e);
+        }
+        telem_engine.finished();
+        telem.engine(telem_engine);
+        result.engine_results.insert(name.to_string(), engine_result);
+    }
+    for name in needs_reset {
+        if !stores.iter().any(|store| store.collection_name() == name) {
+            result.requires_local_reset.push(name);
+        }
+    }
+    mem_cached_state.global_state = Some(state);
+    Ok(result)
+}
+
+// Returns true if the setup, or syncing any of the stores, failed because
+// the keys or `meta/global` changed out from under us.
+fn keys_changed(result: &Result<SyncResult, Error>) -> bool {
+    match result {
+        Ok(sync_result) => sync_result.engine_results.values().any(|r| match r {
+            Ok(()) => false,
+            Err(e) => e.is_keys_changed(),
+        }),
+        Err(e) => e.is_keys_changed(),
+    }
+}
+
+// Returns true if `state` came from a different storage node than
+// `storage_endpoint`. States persisted before we recorded the node don't
+// count, since we can't tell.
+fn is_node_reassigned(state: &GlobalState, storage_endpoint: &str) -> bool {
+    match &state.storage_endpoint {
+        Some(previous) => previous != storage_endpoint,
+        None => false,
+    }
+}
+
+// Returns the storage node we last synced with, from the cached state if we
+// have it, or from the persisted state.
+fn previous_storage_endpoint(
+    mem_cached_state: &MemoryCachedState,
+    persisted: Option<&str>,
+) -> Option<String> {
+    match &mem_cached_state.global_state {
+        Some(state) => state.storage_endpoint.clone(),
+        None => load_global_state(persisted).storage_endpoint,
+    }
+}
+
+fn load_global_state(persisted: Option<&str>) -> GlobalState {
+    match persisted {
+        Some(data) => GlobalState::from_persisted_string(data).unwrap_or_else(|_| {
+            // Don't log the error, since it might contain sensitive info
+            // like keys (the string does, after all). The default state
+            // just means we'll refetch everything.
+            error!("Failed to parse persisted global state! Falling back to default");
+            GlobalState::default()
+        }),
+        None => {
+            info!("No previously persisted global state, using default");
+            GlobalState::default()
+        }
+    }
+}
+
+fn sync_one(
+    client: &Sync15StorageClient,
+    state: &GlobalState,
+    store: &Store,
+    needs_reset: bool,
+    telem_engine: &mut telemetry::Engine,
+) -> Result<(), Error> {
+    let name = store.collection_name();
+    if needs_reset {
+        info!("{} sync ID changed; engine needs local reset", name);
+        store.reset()?;
+    }
+    sync::synchronize(client, state, store, name.into(), true, telem_engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use changeset::{IncomingChangeset, OutgoingChangeset};
+    use collection_keys::CollectionKeys;
+    use failure;
+    use request::{CollectionRequest, InfoCollections};
+    use std::cell::RefCell;
+    use util::ServerTimestamp;
+
+    struct TestStore {
+        name: &'static str,
+        calls: RefCell<Vec<&'static str>>,
+    }
+
+    impl TestStore {
+        fn new(name: &'static str) -> Self {
+            TestStore { name, calls: RefCell::default() }
+        }
+    }
+
+    impl Store for TestStore {
+        fn collection_name(&self) -> &'static str {
+            self.name
+        }
+
+        fn apply_incoming(
+            &self,
+            inbound: IncomingChangeset,
+            _: &mut telemetry::EngineIncoming,
+        ) -> Result<OutgoingChangeset, failure::Error> {
+            Ok(OutgoingChangeset::new(self.name.into(), inbound.timestamp))
+        }
+
+        fn sync_finished(&self, _: ServerTimestamp, _: &[String]) -> Result<(), failure::Error> {
+            Ok(())
+        }
+
+        fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+            Ok(CollectionRequest::new(self.name))
+        }
+
+        fn reset(&self) -> Result<(), failure::Error> {
+            self.calls.borrow_mut().push("reset");
+            Ok(())
+        }
+
+        fn wipe(&self) -> Result<(), failure::Error> {
+            self.calls.borrow_mut().push("wipe");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_load_global_state() {
+        let mut state = GlobalState::default();
+        state.keys = Some(CollectionKeys::new_random().unwrap());
+        state.collections = InfoCollections::new(
+            vec![("crypto".to_string(), ServerTimestamp(123.0))].into_iter().collect());
+        let persisted = state.to_persistable_string();
+
+        let loaded = load_global_state(Some(&persisted));
+        assert_eq!(loaded.keys, state.keys);
+        assert_eq!(loaded.last_modified_or_zero("crypto"), ServerTimestamp(123.0));
+
+        assert!(load_global_state(Some("not json")).keys.is_none());
+        assert!(load_global_state(None).keys.is_none());
+    }
+
+    #[test]
+    fn test_is_node_reassigned() {
+        let mut state = GlobalState::default();
+        assert!(!is_node_reassigned(&state, "https://node1.example.com/1.5/123"));
+
+        state.storage_endpoint = Some("https://node1.example.com/1.5/123".into());
+        assert!(!is_node_reassigned(&state, "https://node1.example.com/1.5/123"));
+        assert!(is_node_reassigned(&state, "https://node2.example.com/1.5/123"));
+
+        // The node is persisted, but older states without it still load.
+        let loaded = load_global_state(Some(&state.to_persistable_string()));
+        assert_eq!(loaded.storage_endpoint, state.storage_endpoint);
+        let old_format = r#"{"schema_version":"V1","config":{},"collections":{},"global":null,"keys":null,"engine_state_changes":[]}"#;
+        assert_eq!(GlobalState::from_persisted_string(old_format).unwrap().storage_endpoint, None);
+    }
+
+    #[test]
+    fn test_keys_changed() {
+        let hmac: Result<SyncResult, Error> = Err(ErrorKind::HmacMismatch.into());
+        assert!(keys_changed(&hmac));
+        let unauthorized: Result<SyncResult, Error> = Err(ErrorKind::StorageHttpError {
+            code: 401,
+            route: "/1.5/123/info/collections".into(),
+        }.into());
+        assert!(keys_changed(&unauthorized));
+        let backoff: Result<SyncResult, Error> = Err(ErrorKind::BackoffError {
+            retry_at: ::std::time::SystemTime::now(),
+        }.into());
+        assert!(!keys_changed(&backoff));
+
+        let precondition_failed = |route: &str| -> Error {
+            ErrorKind::StorageHttpError { code: 412, route: route.into() }.into()
+        };
+        let mut result = SyncResult::default();
+        result.engine_results.insert("passwords".into(), Ok(()));
+        result.engine_results.insert(
+            "history".into(), Err(precondition_failed("/1.5/123/storage/history")));
+        let mut result = Ok(result);
+        assert!(!keys_changed(&result));
+        if let Ok(r) = &mut result {
+            r.engine_results.insert(
+                "bookmarks".into(), Err(precondition_failed("/1.5/123/storage/crypto/keys")));
+        }
+        assert!(keys_changed(&result));
+    }
+
+    #[test]
+    fn test_apply_commands() {
+        let passwords = TestStore::new("passwords");
+        let bookmarks = TestStore::new("bookmarks");
+        let unhandled = apply_commands(&[&passwords, &bookmarks], &[
+            Command::Reset("passwords".into()),
+            Command::Wipe("history".into()),
+            Command::WipeAll,
+            Command::ResetAll,
+            Command::Reset("history".into()),
+        ]).unwrap();
+        assert_eq!(unhandled, vec![
+            Command::Wipe("history".into()),
+            Command::Reset("history".into()),
+        ]);
+        assert_eq!(*passwords.calls.borrow(), vec!["reset", "wipe"]);
+        assert_eq!(*bookmarks.calls.borrow(), vec!["wipe"]);
+    }
+}
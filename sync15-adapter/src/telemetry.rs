@@ -0,0 +1,224 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Accumulates the data for the "sync" telemetry ping. The structures here
+//! serialize to (a subset of) the format desktop uses, which is documented at
+//! https://firefox-source-docs.mozilla.org/toolkit/components/telemetry/telemetry/data/sync-ping.html
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use error::{Error, ErrorKind};
+
+/// Returns the number of whole milliseconds since `start`.
+fn ms_since(start: Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+fn now_ms_since_epoch() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Why a sync (or the sync of a single engine) failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum SyncFailure {
+    /// The server returned an unexpected HTTP status.
+    HttpError { code: u16 },
+    /// We couldn't reach the server at all.
+    NetworkError,
+    /// The server asked us to back off.
+    Backoff,
+    /// Some other error; `error` is a short description.
+    OtherError { error: String },
+}
+
+impl<'a> From<&'a Error> for SyncFailure {
+    fn from(e: &'a Error) -> Self {
+        match e.kind() {
+            ErrorKind::TokenserverHttpError(code) => SyncFailure::HttpError { code: *code },
+            ErrorKind::StorageHttpError { code, .. } => SyncFailure::HttpError { code: *code },
+            ErrorKind::BackoffError { .. } => SyncFailure::Backoff,
+            ErrorKind::RequestError(_) => SyncFailure::NetworkError,
+            // Errors from stores may contain user data, so we only record
+            // that something went wrong in the store.
+            ErrorKind::StoreError(_) => SyncFailure::OtherError { error: "store error".into() },
+            kind => SyncFailure::OtherError { error: format!("{}", kind) },
+        }
+    }
+}
+
+/// Counts of records we downloaded and tried to apply.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineIncoming {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub applied: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failed: u32,
+}
+
+/// Counts of records we tried to upload in a single batch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineOutgoing {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub sent: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failed: u32,
+}
+
+fn is_zero(v: &u32) -> bool {
+    *v == 0
+}
+
+/// Telemetry for a single engine (collection).
+#[derive(Debug, Clone, Serialize)]
+pub struct Engine {
+    pub name: String,
+    /// How long syncing this engine took, in milliseconds.
+    pub took: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incoming: Option<EngineIncoming>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outgoing: Vec<EngineOutgoing>,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<SyncFailure>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl Engine {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            took: 0,
+            incoming: None,
+            outgoing: Vec::new(),
+            failure_reason: None,
+            started: Some(Instant::now()),
+        }
+    }
+
+    pub fn incoming(&mut self, incoming: EngineIncoming) {
+        self.incoming = Some(incoming);
+    }
+
+    pub fn outgoing(&mut self, outgoing: EngineOutgoing) {
+        self.outgoing.push(outgoing);
+    }
+
+    pub fn failure(&mut self, err: &Error) {
+        self.failure_reason = Some(err.into());
+    }
+
+    /// Record how long the engine took to sync. Called once syncing the
+    /// engine has completed (successfully or not).
+    pub fn finished(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.took = ms_since(started);
+        }
+    }
+}
+
+/// Telemetry for a single sync, which may include several engines.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncTelemetry {
+    /// When the sync started, in milliseconds since the epoch.
+    pub when: u64,
+    /// How long the sync took, in milliseconds.
+    pub took: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub engines: Vec<Engine>,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<SyncFailure>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl Default for SyncTelemetry {
+    fn default() -> Self {
+        Self {
+            when: now_ms_since_epoch(),
+            took: 0,
+            engines: Vec::new(),
+            failure_reason: None,
+            started: Some(Instant::now()),
+        }
+    }
+}
+
+impl SyncTelemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn engine(&mut self, engine: Engine) {
+        self.engines.push(engine);
+    }
+
+    pub fn failure(&mut self, err: &Error) {
+        self.failure_reason = Some(err.into());
+    }
+
+    pub fn finished(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.took = ms_since(started);
+        }
+    }
+}
+
+/// The top-level ping the application submits to the telemetry pipeline.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncTelemetryPing {
+    pub version: u32,
+    pub syncs: Vec<SyncTelemetry>,
+}
+
+impl Default for SyncTelemetryPing {
+    fn default() -> Self {
+        Self { version: 1, syncs: Vec::new() }
+    }
+}
+
+impl SyncTelemetryPing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sync(&mut self, sync: SyncTelemetry) {
+        self.syncs.push(sync);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure;
+    use serde_json;
+
+    #[test]
+    fn test_engine_serialization() {
+        let mut engine = Engine::new("passwords");
+        engine.incoming(EngineIncoming { applied: 3, failed: 0 });
+        engine.outgoing(EngineOutgoing { sent: 2, failed: 1 });
+        engine.failure(&ErrorKind::StorageHttpError { code: 500, route: "x".into() }.into());
+        engine.finished();
+        engine.took = 10;
+        assert_eq!(serde_json::to_value(&engine).unwrap(), json!({
+            "name": "passwords",
+            "took": 10,
+            "incoming": { "applied": 3 },
+            "outgoing": [{ "sent": 2, "failed": 1 }],
+            "failureReason": { "name": "http_error", "code": 500 },
+        }));
+    }
+
+    #[test]
+    fn test_failure_from_error() {
+        let err: Error = ErrorKind::StoreError(failure::err_msg("secret user data")).into();
+        assert_eq!(SyncFailure::from(&err), SyncFailure::OtherError { error: "store error".into() });
+        let err: Error = ErrorKind::BackoffError { retry_at: SystemTime::now() }.into();
+        assert_eq!(SyncFailure::from(&err), SyncFailure::Backoff);
+    }
+}
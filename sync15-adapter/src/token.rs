@@ -4,9 +4,11 @@
 
 use hawk;
 
-use reqwest::{Client, Request, Url};
-use hyper::header::AUTHORIZATION;
+use hyper::Method;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use url::Url;
 use error::{self, Result, ErrorKind};
+use http::{HttpBackend, HttpRequest};
 use std::fmt;
 use std::borrow::{Borrow, Cow};
 use std::str::FromStr;
@@ -47,7 +49,7 @@ struct TokenFetchResult {
 // The trait for fetching tokens - we'll provide a "real" implementation but
 // tests will re-implement it.
 trait TokenFetcher {
-    fn fetch_token(&self, request_client: &Client) -> super::Result<TokenFetchResult>;
+    fn fetch_token(&self, backend: &HttpBackend) -> super::Result<TokenFetchResult>;
     // We allow the trait to tell us what the time is so tests can get funky.
     fn now(&self) -> SystemTime;
 }
@@ -69,33 +71,31 @@ impl TokenServerFetcher {
 }
 
 impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self, request_client: &Client) -> Result<TokenFetchResult> {
-        let mut resp = request_client.get(self.server_url.clone())
-                                     .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
-                                     .header(X_KEY_ID, self.key_id.clone())
-                                     .send()?;
-
-        if !resp.status().is_success() {
-            warn!("Non-success status when fetching token: {}", resp.status());
+    fn fetch_token(&self, backend: &HttpBackend) -> Result<TokenFetchResult> {
+        let mut req = HttpRequest::new(Method::GET, self.server_url.clone());
+        req.headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", self.access_token))?);
+        req.headers.insert(X_KEY_ID, HeaderValue::from_str(&self.key_id)?);
+        let resp = backend.execute(req)?;
+
+        if !resp.status.is_success() {
+            warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
-            debug!("  Response body {}", resp.text().unwrap_or_else(|_| "???".into()));
+            debug!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(header) = resp.headers().get(RETRY_AFTER) {
+            if resp.headers.contains_key(RETRY_AFTER) {
                 // XXX - We are silently dropping parsing errors here.
-                let ms = header.to_str().ok().and_then(|s| s.parse::<f64>().ok())
+                let ms = resp.header(RETRY_AFTER).and_then(|s| s.parse::<f64>().ok())
                     .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
                 let when = self.now() + Duration::from_millis(ms);
-                return Err(ErrorKind::BackoffError(when).into());
+                return Err(ErrorKind::BackoffError { retry_at: when }.into());
             }
-            let status = resp.status().as_u16();
+            let status = resp.status.as_u16();
             return Err(ErrorKind::TokenserverHttpError(status).into());
         }
 
         let token: TokenserverToken = resp.json()?;
-        let server_timestamp = resp.headers()
-                    .get(X_TIMESTAMP)
-                    .and_then(|v| v.to_str().ok())
+        let server_timestamp = resp.header(X_TIMESTAMP)
                     .and_then(|s| ServerTimestamp::from_str(s).ok())
                     .ok_or_else(|| ErrorKind::MissingServerTimestamp)?;
         Ok(TokenFetchResult { token, server_timestamp })
@@ -143,8 +143,8 @@ impl TokenContext {
         now < self.valid_until
     }
 
-    fn authorization(&self, req: &Request) -> Result<String> {
-        let url = req.url();
+    fn authorization(&self, req: &HttpRequest) -> Result<String> {
+        let url = &req.url;
 
         let path_and_query = match url.query() {
             None => Cow::from(url.path()),
@@ -160,7 +160,7 @@ impl TokenContext {
                 "Storage URL has no port and no default port is known for the protocol".into()))?;
 
         let header = hawk::RequestBuilder::new(
-            req.method().as_ref(),
+            req.method.as_ref(),
             host,
             port,
             path_and_query.borrow()
@@ -185,6 +185,10 @@ enum TokenState {
     // elt is the api_endpoint we had before we hit the backoff error.
     // XXX - should we roll Backoff and Failed together?
     Backoff(SystemTime, Option<String>),
+    // The storage server rejected our token, so we need a new one even if
+    // it hasn't expired. The elt is the api_endpoint of the rejected token,
+    // so that we notice if the new one is for a different node.
+    Rejected(String),
     // api_endpoint changed - we are never going to get a token nor move out
     // of this state.
     NodeReassigned,
@@ -209,8 +213,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
 
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
-    fn fetch_context(&self, request_client: &Client) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token(request_client)?;
+    fn fetch_context(&self, backend: &HttpBackend) -> Result<TokenContext> {
+        let result = self.fetcher.fetch_token(backend)?;
         let token = result.token;
         let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
 
@@ -225,8 +229,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Attempt to fetch a new token and return a new state reflecting that
     // operation. If it worked a TokenState will be returned, but errors may
     // cause other states.
-    fn fetch_token(&self, request_client: &Client, previous_endpoint: Option<&str>) -> TokenState {
-        match self.fetch_context(request_client) {
+    fn fetch_token(&self, backend: &HttpBackend, previous_endpoint: Option<&str>) -> TokenState {
+        match self.fetch_context(backend) {
             Ok(tc) => {
                 // We got a new token - check that the endpoint is the same
                 // as a previous endpoint we saw (if any)
@@ -247,7 +251,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
             },
             Err(e) => {
                 // Early to avoid nll issues...
-                if let ErrorKind::BackoffError(be) = e.kind() {
+                if let ErrorKind::BackoffError { retry_at: be } = e.kind() {
                     return TokenState::Backoff(*be, previous_endpoint.map(|s| s.to_string()));
                 }
                 TokenState::Failed(Some(e), previous_endpoint.map(|s| s.to_string()))
@@ -259,19 +263,22 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Returns None if the current state should be used (eg, if we are
     // holding a token that remains valid) or Some() if the state has changed
     // (which may have changed to a state with a token or an error state)
-    fn advance_state(&self, request_client: &Client, state: &TokenState) -> Option<TokenState> {
+    fn advance_state(&self, backend: &HttpBackend, state: &TokenState) -> Option<TokenState> {
         match state {
             TokenState::NoToken => {
-                Some(self.fetch_token(request_client, None))
+                Some(self.fetch_token(backend, None))
             },
             TokenState::Failed(_, existing_endpoint) => {
-                Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                Some(self.fetch_token(backend, existing_endpoint.as_ref().map(|e| e.as_str())))
+            },
+            TokenState::Rejected(existing_endpoint) => {
+                Some(self.fetch_token(backend, Some(existing_endpoint.as_str())))
             },
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
                 } else {
-                    Some(self.fetch_token(request_client, Some(existing_context.token.api_endpoint.as_str())))
+                    Some(self.fetch_token(backend, Some(existing_context.token.api_endpoint.as_str())))
                 }
             },
             TokenState::Backoff(ref until, ref existing_endpoint) => {
@@ -280,7 +287,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     None
                 } else {
                     // backoff period is over
-                    Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                    Some(self.fetch_token(backend, existing_endpoint.as_ref().map(|e| e.as_str())))
                 }
             },
             TokenState::NodeReassigned => {
@@ -290,13 +297,13 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn with_token<T, F>(&self, request_client: &Client, func: F) -> Result<T>
+    fn with_token<T, F>(&self, backend: &HttpBackend, func: F) -> Result<T>
             where F: FnOnce(&TokenContext) -> Result<T> {
 
         // first get a mutable ref to our existing state, advance to the
         // state we will use, then re-stash that state for next time.
         let state: &mut TokenState = &mut self.current_state.borrow_mut();
-        match self.advance_state(request_client, state) {
+        match self.advance_state(backend, state) {
             Some(new_state) => *state = new_state,
             None => ()
         }
@@ -304,9 +311,9 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         // Now re-fetch the state we should use for this call - if it's
         // anything other than TokenState::Token we will fail.
         match state {
-            TokenState::NoToken => {
+            TokenState::NoToken | TokenState::Rejected(_) => {
                 // it should be impossible to get here.
-                panic!("Can't be in NoToken state after advancing");
+                panic!("Can't be in NoToken or Rejected state after advancing");
             }
             TokenState::Token(ref token_context) => {
                 // make the call.
@@ -321,21 +328,32 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                 return Err(ErrorKind::StorageResetError.into());
             }
             TokenState::Backoff(ref remaining, _) => {
-                return Err(ErrorKind::BackoffError(*remaining).into());
+                return Err(ErrorKind::BackoffError { retry_at: *remaining }.into());
             }
         }
     }
 
-    fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.with_token(http_client, |ctx| ctx.authorization(req))
+    fn authorization(&self, backend: &HttpBackend, req: &HttpRequest) -> Result<String> {
+        self.with_token(backend, |ctx| ctx.authorization(req))
     }
 
-    fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    fn api_endpoint(&self, backend: &HttpBackend) -> Result<String> {
+        self.with_token(backend, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    }
+
+    // Forgets our token after the storage server rejected it, so that the
+    // next request fetches a new one. If we've been moved to another node,
+    // the new token has a different endpoint, and we end up in
+    // `NodeReassigned`.
+    fn drop_token(&self) {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        let endpoint = match state {
+            TokenState::Token(ref token_context) => token_context.token.api_endpoint.clone(),
+            // We don't have a token to drop.
+            _ => return,
+        };
+        *state = TokenState::Rejected(endpoint);
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
 }
 
 // The public concrete object exposed by this module
@@ -352,12 +370,19 @@ impl TokenProvider {
         }
     }
 
-    pub fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.imp.authorization(http_client, req)
+    /// Returns the value of the `Authorization` header for `req`, fetching a
+    /// token with `backend` if we don't have a valid one.
+    pub fn authorization(&self, backend: &HttpBackend, req: &HttpRequest) -> Result<String> {
+        self.imp.authorization(backend, req)
+    }
+
+    pub fn api_endpoint(&self, backend: &HttpBackend) -> Result<String> {
+        self.imp.api_endpoint(backend)
     }
 
-    pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.imp.api_endpoint(http_client)
+    /// Forgets our token, because the storage server rejected it with a 401.
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
     }
 }
 
@@ -365,10 +390,19 @@ impl TokenProvider {
 mod tests {
     use super::*;
     use std::cell::Cell;
-    use reqwest::Client;
+    use http::HttpResponse;
+
+    // The fetchers below never make requests, so the backend is never used.
+    #[derive(Debug)]
+    struct UnusedBackend;
+    impl HttpBackend for UnusedBackend {
+        fn execute(&self, _: HttpRequest) -> Result<HttpResponse> {
+            panic!("Shouldn't make requests")
+        }
+    }
 
-    fn make_client() -> Client {
-        Client::builder().timeout(Duration::from_secs(30)).build().expect("can't build client")
+    fn make_client() -> UnusedBackend {
+        UnusedBackend
     }
 
     struct TestFetcher<FF, FN>
@@ -380,7 +414,7 @@ mod tests {
     impl<FF, FN> TokenFetcher for TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
               FN: Fn() -> SystemTime {
-        fn fetch_token(&self, _: &Client) -> Result<TokenFetchResult> {
+        fn fetch_token(&self, _: &HttpBackend) -> Result<TokenFetchResult> {
             (self.fetch)()
         }
         fn now(&self) -> SystemTime {
@@ -435,7 +469,7 @@ mod tests {
         let fetch = || {
             counter.set(counter.get() + 1);
             let when = SystemTime::now() + Duration::from_millis(10000);
-            return Err(error::Error::from(ErrorKind::BackoffError(when)));
+            return Err(error::Error::from(ErrorKind::BackoffError { retry_at: when }));
         };
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
@@ -492,4 +526,43 @@ mod tests {
         tsc.api_endpoint(&make_client()).expect("should re-fetch");
         assert_eq!(counter.get(), 2);
     }
+
+    #[test]
+    fn test_drop_token() {
+        let counter: Cell<u32> = Cell::new(0);
+        let api_endpoint = RefCell::new("api_endpoint".to_string());
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: api_endpoint.borrow().clone(),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let tsc = make_tsc(fetch, || {SystemTime::now()});
+
+        tsc.api_endpoint(&make_client()).expect("should get a token");
+        assert_eq!(counter.get(), 1);
+
+        // The token is still valid, but was rejected, so we fetch a new one.
+        tsc.drop_token();
+        tsc.api_endpoint(&make_client()).expect("should re-fetch");
+        assert_eq!(counter.get(), 2);
+
+        // A new token for a different node means we were reassigned.
+        *api_endpoint.borrow_mut() = "new_api_endpoint".to_string();
+        tsc.drop_token();
+        let err = tsc.api_endpoint(&make_client()).expect_err("should be reassigned");
+        match err.kind() {
+            ErrorKind::StorageResetError => {}
+            _ => panic!("Unexpected error {:?}", err),
+        }
+        assert_eq!(counter.get(), 3);
+    }
 }
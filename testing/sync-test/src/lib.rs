@@ -0,0 +1,135 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A harness for end-to-end sync tests, which sync components between
+//! several simulated devices signed in to the same account. The devices talk
+//! to a `MockSyncServer` instead of the real tokenserver and storage servers,
+//! so the tests run offline, and can inspect the server and make it fail.
+//! The tests themselves are in `tests/`.
+//!
+//! The server doesn't speak HTTP. It's installed as each store's
+//! `HttpBackend`, so it gets requests after `sync15_adapter` has built them,
+//! and hands back responses for it to parse. Everything above that, from
+//! the token and Hawk signing to the headers the server sends back, is
+//! tested. `ReqwestBackend`, which actually sends the requests, isn't, and
+//! neither is how it converts headers.
+//!
+//! The server can still fail in the ways that matter to clients. It can
+//! move the account to a new storage node, after which the old node answers
+//! with a 401 and the tokenserver hands out the new endpoint
+//! (`test_node_reassignment`). It can also go offline, so that requests fail
+//! as if the network was down (`test_retry_pending`), return 503s with
+//! `Retry-After`, and send `X-Weave-Backoff`.
+
+extern crate hyper;
+extern crate logins_sql;
+extern crate serde;
+extern crate sync15_adapter as sync;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate serde_json;
+
+extern crate url;
+
+mod server;
+
+pub use server::{MockSyncServer, STORAGE_URL, TOKENSERVER_URL};
+
+use std::sync::Arc;
+
+use logins_sql::{PasswordEngine, SyncOutcome};
+use serde_json::Value as JsonValue;
+use sync::collection_keys::CollectionKeys;
+use sync::{Command, EncryptedBso, KeyBundle, Payload, Sync15StorageClientInit};
+use url::Url;
+
+/// An account, which is a mock server to sync with, and the sync key that
+/// its data is encrypted with.
+#[derive(Debug)]
+pub struct TestAccount {
+    pub server: Arc<MockSyncServer>,
+    pub root_sync_key: KeyBundle,
+}
+
+impl TestAccount {
+    pub fn new() -> TestAccount {
+        TestAccount {
+            server: Arc::new(MockSyncServer::new()),
+            root_sync_key: KeyBundle::new_random().expect("Should generate a sync key"),
+        }
+    }
+
+    pub fn storage_init(&self) -> Sync15StorageClientInit {
+        Sync15StorageClientInit {
+            key_id: "key_id".into(),
+            access_token: "access_token".into(),
+            tokenserver_url: Url::parse(TOKENSERVER_URL).unwrap(),
+            storage_url: None,
+            allow_insecure_http: false,
+            extra_root_certificates: Vec::new(),
+        }
+    }
+
+    /// Encrypts `cleartext` with the account's keys, and uploads it to
+    /// `collection`, as another client would. This lets tests upload records
+    /// that our own stores wouldn't. A device must have synced first, so
+    /// that the server has keys.
+    pub fn upload_cleartext(&self, collection: &str, cleartext: JsonValue) {
+        let keys = self.server.record("crypto", "keys").expect("Server should have keys");
+        let keys = serde_json::from_value::<EncryptedBso>(keys).unwrap();
+        let keys = CollectionKeys::from_encrypted_bso(keys, &self.root_sync_key).unwrap();
+        let bso = Payload::from_json(cleartext).unwrap()
+            .into_bso(collection.into())
+            .encrypt(keys.key_for_collection(collection))
+            .unwrap();
+        self.server.insert_record(collection, serde_json::to_value(&bso).unwrap());
+    }
+
+    /// Signs in a new device with empty, in-memory stores.
+    pub fn new_device(&self) -> TestDevice {
+        let logins = PasswordEngine::new_in_memory(None).expect("Should open logins");
+        logins.set_http_backend(self.server.clone());
+        TestDevice {
+            logins,
+            storage_init: self.storage_init(),
+            root_sync_key: self.root_sync_key.clone(),
+        }
+    }
+}
+
+/// A device with its own stores, each of which syncs separately, as they
+/// would in an app. Tests change the stores directly, then sync them.
+pub struct TestDevice {
+    pub logins: PasswordEngine,
+    storage_init: Sync15StorageClientInit,
+    root_sync_key: KeyBundle,
+}
+
+impl TestDevice {
+    pub fn sync_logins(&self) -> logins_sql::Result<SyncOutcome> {
+        self.logins.sync(&self.storage_init, &self.root_sync_key)
+    }
+
+    /// Syncs logins again if the last sync failed because the device was
+    /// offline, or returns `None` without syncing if it didn't.
+    pub fn retry_pending_logins(&self) -> logins_sql::Result<Option<SyncOutcome>> {
+        self.logins.retry_pending(&self.storage_init, &self.root_sync_key)
+    }
+
+    /// Like `sync_logins`, but applies `commands` first, as if another
+    /// client sent them, and fills in `outcome` even if the sync fails.
+    pub fn sync_logins_with_commands(
+        &self,
+        commands: &[Command],
+        outcome: &mut SyncOutcome,
+    ) -> logins_sql::Result<()> {
+        self.logins.sync_with_commands(&self.storage_init, &self.root_sync_key, commands, outcome)
+    }
+}
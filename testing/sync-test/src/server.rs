@@ -0,0 +1,490 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A mock tokenserver and Sync 1.5 storage server, which devices reach
+//! through their `HttpBackend`, so requests never leave the process. It
+//! implements as much of the storage API as our clients use: `info/*`, single
+//! records (for `meta/global` and `crypto/keys`), collection GETs, batched
+//! POSTs with `X-If-Unmodified-Since`, and DELETEs. It checks that storage
+//! requests are signed, but not the signatures themselves. It can also move
+//! the account to a new storage node, after which the old node rejects every
+//! request with a 401.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use hyper::StatusCode;
+use serde_json::{self, Value as JsonValue};
+
+use sync::{ErrorKind, HttpBackend, HttpRequest, HttpResponse, Result, ServerTimestamp};
+
+/// The tokenserver URL to sign devices in with.
+pub const TOKENSERVER_URL: &str = "https://token.example.com/1.0/sync/1.5";
+
+/// The storage endpoint in the tokens we hand out, until the account is
+/// moved to another node.
+pub const STORAGE_URL: &str = "https://storage.example.com/1.5/1";
+
+const TOKENSERVER_HOST: &str = "token.example.com";
+const STORAGE_HOST: &str = "storage.example.com";
+const STORAGE_PATH: &str = "/1.5/1";
+
+const X_IF_UNMODIFIED_SINCE: &str = "X-If-Unmodified-Since";
+const X_LAST_MODIFIED: &str = "X-Last-Modified";
+const X_WEAVE_TIMESTAMP: &str = "X-Weave-Timestamp";
+
+// A record as the server sees it: the payload is an opaque (encrypted) string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServerBso {
+    id: String,
+    #[serde(default)]
+    modified: ServerTimestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sortindex: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+    payload: String,
+}
+
+#[derive(Debug, Default)]
+struct Collection {
+    modified: ServerTimestamp,
+    records: BTreeMap<String, ServerBso>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    collection: String,
+    records: Vec<ServerBso>,
+}
+
+#[derive(Debug, Default)]
+struct ServerState {
+    // The server's clock, in hundredths of a second, like the timestamps it
+    // sends. Every write moves it forward, so no two writes share a
+    // timestamp, even if they happen in the same hundredth of a second.
+    clock: u64,
+    collections: HashMap<String, Collection>,
+    batches: HashMap<String, Batch>,
+    next_batch_id: u64,
+    max_post_records: Option<usize>,
+    unavailable_retry_after: Option<u64>,
+    backoff: Option<u64>,
+    offline: bool,
+    request_count: usize,
+    // The storage node the account is on. Each move gets a new one.
+    node: u32,
+}
+
+// A response, before we attach the request's URL.
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn new(status: StatusCode, body: &JsonValue) -> Reply {
+        Reply {
+            status,
+            headers: HeaderMap::new(),
+            body: serde_json::to_vec(body).unwrap(),
+        }
+    }
+
+    fn empty(status: StatusCode) -> Reply {
+        Reply::new(status, &json!({}))
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Reply {
+        self.headers.insert(name, HeaderValue::from_str(&value.to_string()).unwrap());
+        self
+    }
+}
+
+/// The mock server. Devices share one by each holding an `Arc` to it as
+/// their `HttpBackend`. Tests can also use it to look at what was uploaded,
+/// and to simulate outages and server-side wipes.
+#[derive(Debug, Default)]
+pub struct MockSyncServer {
+    state: Mutex<ServerState>,
+}
+
+impl MockSyncServer {
+    pub fn new() -> MockSyncServer {
+        MockSyncServer::default()
+    }
+
+    /// Answer every storage request with a 503 and a `Retry-After` of
+    /// `retry_after` seconds, or stop doing so if `None`. The tokenserver
+    /// stays up.
+    pub fn set_unavailable(&self, retry_after: Option<u64>) {
+        self.state.lock().unwrap().unavailable_retry_after = retry_after;
+    }
+
+    /// Fail every request as if the device were offline, or stop doing so.
+    /// Unlike `set_unavailable`, the requests never reach either server.
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+
+    /// Ask clients to back off for `secs` seconds with an `X-Weave-Backoff`
+    /// header on successful storage responses, or stop asking if `None`.
+    pub fn set_backoff(&self, secs: Option<u64>) {
+        self.state.lock().unwrap().backoff = secs;
+    }
+
+    /// Limit the number of records in a POST, so that uploads take several
+    /// POSTs in a batch. Unlimited by default.
+    pub fn set_max_post_records(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max_post_records = max;
+    }
+
+    /// Deletes `collection`, as if another client (or an admin) wiped it.
+    pub fn wipe_collection(&self, collection: &str) {
+        self.state.lock().unwrap().collections.remove(collection);
+    }
+
+    /// Returns the ids of the records in `collection`, including tombstones,
+    /// in order.
+    pub fn record_ids(&self, collection: &str) -> Vec<String> {
+        self.state.lock().unwrap().collections
+            .get(collection)
+            .map(|c| c.records.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the record `id` in `collection` as the server stores it, with
+    /// its payload still encrypted, or `None` if there isn't one.
+    pub fn record(&self, collection: &str, id: &str) -> Option<JsonValue> {
+        self.state.lock().unwrap().collections
+            .get(collection)
+            .and_then(|c| c.records.get(id))
+            .map(|r| json!(r))
+    }
+
+    /// Writes `record`, an encrypted BSO, to `collection`, as if a client
+    /// had uploaded it.
+    pub fn insert_record(&self, collection: &str, record: JsonValue) {
+        let record = serde_json::from_value::<ServerBso>(record).expect("Should be a BSO");
+        self.state.lock().unwrap().write_records(collection, vec![record]);
+    }
+
+    /// Moves the account to a new, empty storage node, as the tokenserver
+    /// does when a node is decommissioned. New tokens are for the new node,
+    /// and the old node answers every request with a 401, so clients must
+    /// fetch a new token, notice the move, and upload everything again.
+    pub fn reassign_node(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.node += 1;
+        state.collections.clear();
+        state.batches.clear();
+    }
+
+    /// Returns the number of requests made so far, to either server.
+    pub fn request_count(&self) -> usize {
+        self.state.lock().unwrap().request_count
+    }
+}
+
+impl HttpBackend for MockSyncServer {
+    fn execute(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.request_count += 1;
+        if state.offline {
+            return Err(ErrorKind::HttpBackendError(
+                format!("Offline, couldn't reach {}", request.url)).into());
+        }
+        let storage_host = state.storage_host();
+        let reply = match request.url.host_str() {
+            Some(TOKENSERVER_HOST) => state.token_reply(&request),
+            Some(host) if host == storage_host => state.storage_reply(&request),
+            // A node that the account was moved away from.
+            Some(host) if host.ends_with(STORAGE_HOST) => Reply::empty(StatusCode::UNAUTHORIZED),
+            _ => return Err(ErrorKind::HttpBackendError(
+                format!("No route to {}", request.url)).into()),
+        };
+        trace!("{} {} => {}", request.method, request.url, reply.status);
+        Ok(HttpResponse {
+            status: reply.status,
+            url: request.url,
+            headers: reply.headers,
+            body: reply.body,
+        })
+    }
+}
+
+impl ServerState {
+    // Returns the current time, which is never before the latest write.
+    fn now(&mut self) -> ServerTimestamp {
+        self.clock = cmp::max(self.clock, wall_clock());
+        to_server_timestamp(self.clock)
+    }
+
+    // Returns the time for a write, which is after every earlier write.
+    fn next_write_time(&mut self) -> ServerTimestamp {
+        self.clock = cmp::max(self.clock + 1, wall_clock());
+        to_server_timestamp(self.clock)
+    }
+
+    // The host of the node the account is on now. The first node is the one
+    // in `STORAGE_URL`.
+    fn storage_host(&self) -> String {
+        match self.node {
+            0 => STORAGE_HOST.to_string(),
+            node => format!("node{}.{}", node, STORAGE_HOST),
+        }
+    }
+
+    fn last_modified(&self, collection: &str) -> ServerTimestamp {
+        self.collections.get(collection).map(|c| c.modified).unwrap_or_default()
+    }
+
+    // The endpoint changes when the account is moved with `reassign_node`.
+    // We only check that the request has an `Authorization` header, since
+    // there's no FxA server to verify the assertion with.
+    fn token_reply(&mut self, request: &HttpRequest) -> Reply {
+        if !request.headers.contains_key(AUTHORIZATION) {
+            return Reply::empty(StatusCode::UNAUTHORIZED);
+        }
+        let now = self.now();
+        Reply::new(StatusCode::OK, &json!({
+            "id": "id",
+            "key": "key",
+            "api_endpoint": format!("https://{}{}", self.storage_host(), STORAGE_PATH),
+            "uid": 1,
+            "duration": 3600,
+            "hashed_fxa_uid": "hash",
+        })).header("X-Timestamp", now.0 as u64)
+    }
+
+    fn storage_reply(&mut self, request: &HttpRequest) -> Reply {
+        let reply = self.route_storage_request(request);
+        let now = self.now();
+        let reply = reply.header(X_WEAVE_TIMESTAMP, now);
+        match self.backoff {
+            Some(secs) if reply.status.is_success() => reply.header("X-Weave-Backoff", secs),
+            _ => reply,
+        }
+    }
+
+    fn route_storage_request(&mut self, request: &HttpRequest) -> Reply {
+        let signed = request.headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.starts_with("Hawk "));
+        if !signed {
+            return Reply::empty(StatusCode::UNAUTHORIZED);
+        }
+        if let Some(retry_after) = self.unavailable_retry_after {
+            return Reply::empty(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", retry_after);
+        }
+        let path = request.url.path();
+        if !path.starts_with(STORAGE_PATH) {
+            return Reply::empty(StatusCode::NOT_FOUND);
+        }
+        let segments = path[STORAGE_PATH.len()..]
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["info", "configuration"]) => self.info_configuration(),
+            ("GET", ["info", "collections"]) => self.info_collections(),
+            ("GET", ["storage", collection]) => self.get_records(collection, request),
+            ("POST", ["storage", collection]) => self.post_records(collection, request),
+            ("DELETE", ["storage", collection]) => self.delete_collection(collection),
+            ("GET", ["storage", collection, id]) => self.get_record(collection, id),
+            ("PUT", ["storage", collection, id]) => self.put_record(collection, id, request),
+            ("DELETE", []) | ("DELETE", ["storage"]) => self.delete_all(),
+            _ => Reply::empty(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn info_configuration(&self) -> Reply {
+        let mut config = json!({});
+        if let Some(max) = self.max_post_records {
+            config["max_post_records"] = json!(max);
+        }
+        Reply::new(StatusCode::OK, &config)
+    }
+
+    fn info_collections(&self) -> Reply {
+        let collections = self.collections.iter()
+            .map(|(name, c)| (name.clone(), c.modified))
+            .collect::<HashMap<_, _>>();
+        Reply::new(StatusCode::OK, &json!(collections))
+    }
+
+    fn get_record(&self, collection: &str, id: &str) -> Reply {
+        match self.collections.get(collection).and_then(|c| c.records.get(id)) {
+            Some(record) => Reply::new(StatusCode::OK, &json!(record))
+                .header(X_LAST_MODIFIED, record.modified),
+            None => Reply::empty(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn put_record(&mut self, collection: &str, id: &str, request: &HttpRequest) -> Reply {
+        let mut record = match parse_body::<ServerBso>(request) {
+            Some(record) => record,
+            None => return Reply::empty(StatusCode::BAD_REQUEST),
+        };
+        let last_modified = self.last_modified(collection);
+        if is_modified_since(request, last_modified) {
+            return Reply::empty(StatusCode::PRECONDITION_FAILED)
+                .header(X_LAST_MODIFIED, last_modified);
+        }
+        record.id = id.to_string();
+        let modified = self.write_records(collection, vec![record]);
+        Reply::new(StatusCode::OK, &json!(modified)).header(X_LAST_MODIFIED, modified)
+    }
+
+    // Supports the `newer`, `older`, `ids`, `full`, `sort` and `limit`
+    // parameters, but not `offset`, so a limited GET can't be continued.
+    fn get_records(&self, collection: &str, request: &HttpRequest) -> Reply {
+        let params = request.url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        let newer = params.get("newer").and_then(|s| s.parse::<f64>().ok());
+        let older = params.get("older").and_then(|s| s.parse::<f64>().ok());
+        let ids = params.get("ids").map(|s| s.split(',').collect::<Vec<_>>());
+        let last_modified = self.last_modified(collection);
+        let mut records = self.collections.get(collection)
+            .map(|c| c.records.values().collect::<Vec<_>>())
+            .unwrap_or_default();
+        records.retain(|r| {
+            newer.map_or(true, |ts| r.modified.0 > ts) &&
+                older.map_or(true, |ts| r.modified.0 < ts) &&
+                ids.as_ref().map_or(true, |ids| ids.contains(&r.id.as_str()))
+        });
+        match params.get("sort").map(|s| s.as_str()) {
+            Some("newest") => records.sort_by(|a, b| b.modified.partial_cmp(&a.modified).unwrap()),
+            Some("oldest") => records.sort_by(|a, b| a.modified.partial_cmp(&b.modified).unwrap()),
+            Some("index") => records.sort_by_key(|r| cmp::Reverse(r.sortindex)),
+            _ => {}
+        }
+        if let Some(limit) = params.get("limit").and_then(|s| s.parse::<usize>().ok()) {
+            records.truncate(limit);
+        }
+        let body = if params.contains_key("full") {
+            json!(records)
+        } else {
+            json!(records.iter().map(|r| &r.id).collect::<Vec<_>>())
+        };
+        Reply::new(StatusCode::OK, &body).header(X_LAST_MODIFIED, last_modified)
+    }
+
+    // Records are only written when the batch is committed, or immediately
+    // if the POST isn't part of one.
+    fn post_records(&mut self, collection: &str, request: &HttpRequest) -> Reply {
+        let params = request.url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        let records = match parse_body::<Vec<ServerBso>>(request) {
+            Some(records) => records,
+            None => return Reply::empty(StatusCode::BAD_REQUEST),
+        };
+        if self.max_post_records.map_or(false, |max| records.len() > max) {
+            return Reply::empty(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let last_modified = self.last_modified(collection);
+        if is_modified_since(request, last_modified) {
+            return Reply::empty(StatusCode::PRECONDITION_FAILED)
+                .header(X_LAST_MODIFIED, last_modified);
+        }
+        let success = records.iter().map(|r| r.id.clone()).collect::<Vec<_>>();
+        let batch_id = match params.get("batch").map(|s| s.as_str()) {
+            None => {
+                let modified = self.write_records(collection, records);
+                return upload_reply(StatusCode::OK, None, success, modified);
+            }
+            Some("true") => {
+                self.next_batch_id += 1;
+                let batch_id = self.next_batch_id.to_string();
+                self.batches.insert(batch_id.clone(), Batch {
+                    collection: collection.to_string(),
+                    records: Vec::new(),
+                });
+                batch_id
+            }
+            Some(batch_id) => batch_id.to_string(),
+        };
+        match self.batches.get_mut(&batch_id) {
+            Some(ref mut batch) if batch.collection == collection => {
+                batch.records.extend(records);
+            }
+            _ => return Reply::empty(StatusCode::BAD_REQUEST),
+        }
+        if params.get("commit").map(|s| s.as_str()) != Some("true") {
+            return upload_reply(StatusCode::ACCEPTED, Some(batch_id), success, last_modified);
+        }
+        let batch = self.batches.remove(&batch_id).unwrap();
+        let modified = self.write_records(collection, batch.records);
+        upload_reply(StatusCode::OK, None, success, modified)
+    }
+
+    fn delete_collection(&mut self, collection: &str) -> Reply {
+        self.collections.remove(collection);
+        let modified = self.next_write_time();
+        Reply::new(StatusCode::OK, &json!({ "modified": modified }))
+    }
+
+    fn delete_all(&mut self) -> Reply {
+        self.collections.clear();
+        self.batches.clear();
+        let modified = self.next_write_time();
+        Reply::new(StatusCode::OK, &json!({ "modified": modified }))
+    }
+
+    // Writes `records` to `collection` in one go, and returns their modified
+    // time, which is also the collection's.
+    fn write_records(&mut self, collection: &str, records: Vec<ServerBso>) -> ServerTimestamp {
+        let modified = self.next_write_time();
+        let collection = self.collections.entry(collection.to_string()).or_insert_with(Collection::default);
+        for mut record in records {
+            record.modified = modified;
+            collection.records.insert(record.id.clone(), record);
+        }
+        collection.modified = modified;
+        modified
+    }
+}
+
+fn upload_reply(
+    status: StatusCode,
+    batch: Option<String>,
+    success: Vec<String>,
+    modified: ServerTimestamp,
+) -> Reply {
+    Reply::new(status, &json!({
+        "batch": batch,
+        "modified": modified,
+        "success": success,
+        "failed": {},
+    })).header(X_LAST_MODIFIED, modified)
+}
+
+fn parse_body<T>(request: &HttpRequest) -> Option<T>
+where
+    for<'a> T: ::serde::Deserialize<'a>,
+{
+    request.body.as_ref().and_then(|body| serde_json::from_slice(body).ok())
+}
+
+// Returns true if the request has an `X-If-Unmodified-Since` header, and
+// `last_modified` is after it.
+fn is_modified_since(request: &HttpRequest, last_modified: ServerTimestamp) -> bool {
+    request.headers.get(X_IF_UNMODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<f64>().ok())
+        .map_or(false, |since| last_modified.0 > since)
+}
+
+// The current time, in hundredths of a second since the epoch.
+fn wall_clock() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    d.as_secs() * 100 + u64::from(d.subsec_millis() / 10)
+}
+
+fn to_server_timestamp(hundredths: u64) -> ServerTimestamp {
+    ServerTimestamp(hundredths as f64 / 100.0)
+}
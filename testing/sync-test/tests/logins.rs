@@ -0,0 +1,324 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate logins_sql;
+extern crate sync15_adapter as sync;
+extern crate sync_test;
+
+#[macro_use]
+extern crate serde_json;
+
+use std::time::SystemTime;
+
+use logins_sql::{Error, ErrorKind, Login, SyncOutcome};
+use sync::Command;
+use sync_test::TestAccount;
+
+fn login(id: &str, hostname: &str) -> Login {
+    Login {
+        id: id.into(),
+        hostname: hostname.into(),
+        form_submit_url: Some(format!("{}/login", hostname)),
+        username: "alice".into(),
+        password: "hunter2".into(),
+        username_field: "user".into(),
+        password_field: "pass".into(),
+        ..Login::default()
+    }
+}
+
+fn retry_at(err: &Error) -> Option<SystemTime> {
+    match err.kind() {
+        ErrorKind::SyncAdapterError(e) => e.retry_at(),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().expect("first device should sync");
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa"]);
+
+    b.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+    b.sync_logins().expect("second device should sync");
+    a.sync_logins().expect("first device should sync again");
+
+    for device in &[&a, &b] {
+        let mut ids = device.logins.list_ids().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+    }
+    let synced = b.logins.get("aaaaaaaaaaaa").unwrap().expect("should have a's login");
+    assert_eq!(synced.password, "hunter2");
+}
+
+#[test]
+fn test_batched_upload() {
+    let account = TestAccount::new();
+    account.server.set_max_post_records(Some(2));
+    let a = account.new_device();
+    let b = account.new_device();
+
+    let ids = (0..5).map(|i| format!("login{:07}", i)).collect::<Vec<_>>();
+    for (i, id) in ids.iter().enumerate() {
+        a.logins.add(login(id, &format!("https://{}.example.com", i))).unwrap();
+    }
+    a.sync_logins().expect("should upload in several posts");
+    assert_eq!(account.server.record_ids("passwords"), ids);
+
+    b.sync_logins().expect("should download everything");
+    assert_eq!(b.logins.list().unwrap().len(), 5);
+}
+
+#[test]
+fn test_conflicting_changes_merge() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    b.sync_logins().unwrap();
+
+    // Both devices change the login before either syncs, but they change
+    // different fields, so we should keep both changes.
+    let mut on_a = a.logins.get("aaaaaaaaaaaa").unwrap().unwrap();
+    on_a.username = "alice@example.com".into();
+    a.logins.update(on_a).unwrap();
+    let mut on_b = b.logins.get("aaaaaaaaaaaa").unwrap().unwrap();
+    on_b.password = "correct horse battery staple".into();
+    b.logins.update(on_b).unwrap();
+
+    a.sync_logins().unwrap();
+    b.sync_logins().unwrap();
+    a.sync_logins().unwrap();
+
+    for device in &[&a, &b] {
+        let merged = device.logins.get("aaaaaaaaaaaa").unwrap().unwrap();
+        assert_eq!(merged.username, "alice@example.com");
+        assert_eq!(merged.password, "correct horse battery staple");
+    }
+}
+
+#[test]
+fn test_tombstones() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    b.sync_logins().unwrap();
+    assert_eq!(b.logins.list().unwrap().len(), 2);
+
+    assert!(b.logins.delete("aaaaaaaaaaaa").unwrap());
+    b.sync_logins().unwrap();
+    // The deletion is uploaded as a tombstone, which replaces the record.
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+    a.sync_logins().unwrap();
+    assert!(a.logins.get("aaaaaaaaaaaa").unwrap().is_none());
+    assert_eq!(a.logins.list_ids().unwrap(), vec!["bbbbbbbbbbbb"]);
+
+    // A device that signs in later never sees the deleted login.
+    let c = account.new_device();
+    c.sync_logins().unwrap();
+    assert_eq!(c.logins.list_ids().unwrap(), vec!["bbbbbbbbbbbb"]);
+}
+
+#[test]
+fn test_server_wipe() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    b.sync_logins().unwrap();
+
+    account.server.wipe_collection("passwords");
+    assert!(account.server.record_ids("passwords").is_empty());
+
+    // The next device to sync notices, and uploads everything again.
+    a.sync_logins().unwrap();
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa"]);
+    b.sync_logins().unwrap();
+    assert_eq!(b.logins.list_ids().unwrap(), vec!["aaaaaaaaaaaa"]);
+}
+
+#[test]
+fn test_node_reassignment() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    b.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+    b.sync_logins().unwrap();
+    a.sync_logins().unwrap();
+
+    // Both devices still have valid tokens for the old node, which rejects
+    // them. The first device to sync after the move starts over, and
+    // uploads everything it has to the new node.
+    account.server.reassign_node();
+    assert!(account.server.record_ids("passwords").is_empty());
+    a.sync_logins().expect("should sync with the new node");
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+    // The other device starts over too, so it uploads the login it added
+    // since, and downloads everything else.
+    b.logins.add(login("cccccccccccc", "https://c.example.com")).unwrap();
+    b.sync_logins().expect("should sync with the new node");
+    assert_eq!(
+        account.server.record_ids("passwords"),
+        vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]
+    );
+    a.sync_logins().unwrap();
+    for device in &[&a, &b] {
+        let mut ids = device.logins.list_ids().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+    }
+}
+
+#[test]
+fn test_unavailable_backoff() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+
+    account.server.set_unavailable(Some(60));
+    let err = a.sync_logins().expect_err("should fail while the server is down");
+    assert!(retry_at(&err).is_some(), "Unexpected error {:?}", err);
+
+    // The server is back, but we were asked to wait, so we shouldn't even
+    // try.
+    account.server.set_unavailable(None);
+    let requests = account.server.request_count();
+    let err = a.sync_logins().expect_err("should still be backing off");
+    assert!(retry_at(&err).is_some(), "Unexpected error {:?}", err);
+    assert_eq!(account.server.request_count(), requests);
+
+    // Backoff is per device, so a device that didn't see the 503 syncs.
+    let b = account.new_device();
+    b.sync_logins().unwrap();
+    assert_eq!(b.logins.list_ids().unwrap(), vec!["aaaaaaaaaaaa"]);
+}
+
+#[test]
+fn test_retry_pending() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    assert!(a.retry_pending_logins().unwrap().is_none(), "Nothing should be pending yet");
+
+    account.server.set_offline(true);
+    let err = a.sync_logins().expect_err("should fail while offline");
+    assert!(err.is_network_error(), "Unexpected error {:?}", err);
+    assert!(a.logins.has_pending_sync().unwrap());
+
+    // Changes made while offline are uploaded by the retry, too.
+    a.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+    account.server.set_offline(false);
+    a.retry_pending_logins().unwrap().expect("should retry the pending sync");
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+    assert!(!a.logins.has_pending_sync().unwrap());
+
+    // Once the retry succeeds, there's nothing left to retry.
+    let requests = account.server.request_count();
+    assert!(a.retry_pending_logins().unwrap().is_none());
+    assert_eq!(account.server.request_count(), requests);
+}
+
+#[test]
+fn test_backoff_header() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+
+    // A backoff header on a successful response doesn't fail the sync that
+    // got it, only the next one.
+    account.server.set_backoff(Some(60));
+    a.sync_logins().expect("should sync despite the backoff header");
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa"]);
+
+    account.server.set_backoff(None);
+    let requests = account.server.request_count();
+    let err = a.sync_logins().expect_err("should back off");
+    assert!(retry_at(&err).is_some(), "Unexpected error {:?}", err);
+    assert_eq!(account.server.request_count(), requests);
+}
+
+#[test]
+fn test_telemetry() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    // A login without a password, which we can't apply.
+    account.upload_cleartext("passwords", json!({
+        "id": "bbbbbbbbbbbb",
+        "hostname": "https://b.example.com",
+        "formSubmitURL": "https://b.example.com/login",
+    }));
+
+    let b = account.new_device();
+    let ping = serde_json::to_value(&b.sync_logins().unwrap().telemetry).unwrap();
+    assert_eq!(ping["syncs"].as_array().unwrap().len(), 1);
+    let engines = &ping["syncs"][0]["engines"];
+    assert_eq!(engines.as_array().unwrap().len(), 1);
+    assert_eq!(engines[0]["name"], "passwords");
+    assert_eq!(engines[0]["incoming"], json!({ "applied": 1, "failed": 1 }));
+    assert!(engines[0].get("failureReason").is_none());
+    assert!(ping["syncs"][0].get("failureReason").is_none());
+    assert_eq!(b.logins.list_ids().unwrap(), vec!["aaaaaaaaaaaa"]);
+
+    // Failed syncs are reported too, with the reason.
+    account.server.set_unavailable(Some(60));
+    let mut outcome = SyncOutcome::default();
+    b.sync_logins_with_commands(&[], &mut outcome).expect_err("should fail while the server is down");
+    let ping = serde_json::to_value(&outcome.telemetry).unwrap();
+    assert_eq!(ping["syncs"][0]["failureReason"], json!({ "name": "backoff" }));
+}
+
+#[test]
+fn test_wipe_and_reset_commands() {
+    let account = TestAccount::new();
+    let a = account.new_device();
+    let b = account.new_device();
+    a.logins.add(login("aaaaaaaaaaaa", "https://a.example.com")).unwrap();
+    a.sync_logins().unwrap();
+    b.sync_logins().unwrap();
+    b.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+
+    // Wiping passwords drops b's login without uploading a deletion, so b
+    // ends up with just what's on the server. The commands for other engines
+    // are for the app.
+    let commands = [
+        Command::Wipe("passwords".into()),
+        Command::Reset("history".into()),
+        Command::Wipe("bookmarks".into()),
+    ];
+    let mut outcome = SyncOutcome::default();
+    b.sync_logins_with_commands(&commands, &mut outcome).unwrap();
+    assert_eq!(b.logins.list_ids().unwrap(), vec!["aaaaaaaaaaaa"]);
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa"]);
+    assert_eq!(outcome.requires_local_reset, vec!["history"]);
+    assert_eq!(outcome.requires_local_wipe, vec!["bookmarks"]);
+
+    // Resetting passwords keeps b's logins, and merges them again.
+    b.logins.add(login("bbbbbbbbbbbb", "https://b.example.com")).unwrap();
+    let mut outcome = SyncOutcome::default();
+    b.sync_logins_with_commands(&[Command::ResetAll], &mut outcome).unwrap();
+    assert_eq!(account.server.record_ids("passwords"), vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+    assert!(outcome.requires_local_reset.is_empty());
+}